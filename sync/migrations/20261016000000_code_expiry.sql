@@ -0,0 +1 @@
+ALTER TABLE codes ADD COLUMN expires_at INTEGER;
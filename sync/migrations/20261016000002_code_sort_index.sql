@@ -0,0 +1,7 @@
+ALTER TABLE codes ADD COLUMN sort_index INTEGER NOT NULL DEFAULT 0;
+
+-- Keep the current (insertion) order of existing codes
+UPDATE codes SET sort_index = (
+	SELECT COUNT(*) FROM codes AS previous
+	WHERE previous.owner_id = codes.owner_id AND previous.rowid < codes.rowid
+);
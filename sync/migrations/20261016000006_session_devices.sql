@@ -0,0 +1 @@
+ALTER TABLE sessions ADD COLUMN device TEXT NOT NULL DEFAULT '';
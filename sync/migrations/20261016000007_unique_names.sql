@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN enforce_unique_names BOOLEAN NOT NULL DEFAULT FALSE;
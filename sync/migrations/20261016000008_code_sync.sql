@@ -0,0 +1,12 @@
+ALTER TABLE codes ADD COLUMN updated_at INTEGER NOT NULL DEFAULT 0;
+-- Deleted codes are kept as tombstones, so syncing clients learn about the deletion
+ALTER TABLE codes ADD COLUMN deleted_at INTEGER;
+ALTER TABLE codes ADD COLUMN revision INTEGER NOT NULL DEFAULT 0;
+
+-- Every change to a code claims the next revision, which clients use as their sync cursor
+CREATE TABLE IF NOT EXISTS sync_revision (
+  value INTEGER NOT NULL
+);
+INSERT INTO sync_revision (value) VALUES (0);
+
+CREATE INDEX IF NOT EXISTS codes_owner_revision ON codes (owner_id, revision);
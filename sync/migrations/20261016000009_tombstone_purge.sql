@@ -0,0 +1,2 @@
+-- Highest revision of the purged tombstones. Clients with an older cursor missed their deletion
+ALTER TABLE sync_revision ADD COLUMN purged INTEGER NOT NULL DEFAULT 0;
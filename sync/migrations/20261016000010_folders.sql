@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS folders (
+  id TEXT PRIMARY KEY NOT NULL,
+  owner_id TEXT NOT NULL,
+  name TEXT NOT NULL,
+  -- Folders without a parent are at the top level
+  parent_id TEXT,
+  FOREIGN KEY (owner_id) REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE,
+  FOREIGN KEY (parent_id) REFERENCES folders(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS folders_owner ON folders (owner_id);
+
+-- Codes without a folder are at the top level. Deleting a folder moves its codes up beforehand,
+-- so syncing clients notice, leaving the constraint as a fallback
+ALTER TABLE codes ADD COLUMN folder_id TEXT REFERENCES folders(id) ON DELETE SET NULL;
@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS tags (
+  id TEXT PRIMARY KEY NOT NULL,
+  owner_id TEXT NOT NULL,
+  name TEXT NOT NULL,
+  UNIQUE (owner_id, name),
+  FOREIGN KEY (owner_id) REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS code_tags (
+  code_id TEXT NOT NULL,
+  tag_id TEXT NOT NULL,
+  PRIMARY KEY (code_id, tag_id),
+  FOREIGN KEY (code_id) REFERENCES codes(id) ON DELETE CASCADE,
+  FOREIGN KEY (tag_id) REFERENCES tags(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS code_tags_tag ON code_tags (tag_id);
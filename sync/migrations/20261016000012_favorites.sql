@@ -0,0 +1 @@
+ALTER TABLE codes ADD COLUMN favorite BOOLEAN NOT NULL DEFAULT FALSE;
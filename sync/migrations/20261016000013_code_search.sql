@@ -0,0 +1,22 @@
+-- Full text index over the names and websites (issuers) of codes, kept in sync by triggers
+CREATE VIRTUAL TABLE IF NOT EXISTS codes_search USING fts5(
+  display_name,
+  website_url,
+  content = 'codes',
+  content_rowid = 'rowid'
+);
+
+CREATE TRIGGER IF NOT EXISTS codes_search_insert AFTER INSERT ON codes BEGIN
+  INSERT INTO codes_search (rowid, display_name, website_url) VALUES (new.rowid, new.display_name, new.website_url);
+END;
+
+CREATE TRIGGER IF NOT EXISTS codes_search_delete AFTER DELETE ON codes BEGIN
+  INSERT INTO codes_search (codes_search, rowid, display_name, website_url) VALUES ('delete', old.rowid, old.display_name, old.website_url);
+END;
+
+CREATE TRIGGER IF NOT EXISTS codes_search_update AFTER UPDATE OF display_name, website_url ON codes BEGIN
+  INSERT INTO codes_search (codes_search, rowid, display_name, website_url) VALUES ('delete', old.rowid, old.display_name, old.website_url);
+  INSERT INTO codes_search (rowid, display_name, website_url) VALUES (new.rowid, new.display_name, new.website_url);
+END;
+
+INSERT INTO codes_search (codes_search) VALUES ('rebuild');
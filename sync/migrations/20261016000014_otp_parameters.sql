@@ -0,0 +1,6 @@
+-- Parsed from otpauth:// URIs. Codes added without one use the defaults of RFC 6238
+ALTER TABLE codes ADD COLUMN issuer TEXT;
+ALTER TABLE codes ADD COLUMN account TEXT;
+ALTER TABLE codes ADD COLUMN digits INTEGER NOT NULL DEFAULT 6;
+ALTER TABLE codes ADD COLUMN period INTEGER NOT NULL DEFAULT 30;
+ALTER TABLE codes ADD COLUMN algorithm TEXT NOT NULL DEFAULT 'SHA1';
@@ -0,0 +1,4 @@
+-- Users with end-to-end encryption only store code contents encrypted by their clients. The key
+-- check is an opaque value derived from their key, letting new devices verify theirs
+ALTER TABLE users ADD COLUMN encryption_enabled BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE users ADD COLUMN encryption_key_check TEXT;
@@ -0,0 +1,3 @@
+ALTER TABLE codes ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+-- The creation of existing codes is unknown, their last change is the closest guess
+UPDATE codes SET created_at = updated_at;
@@ -0,0 +1,2 @@
+-- `totp`, or `steam` for Steam Guard codes
+ALTER TABLE codes ADD COLUMN kind TEXT NOT NULL DEFAULT 'totp';
@@ -0,0 +1,17 @@
+-- Previous versions of codes, recorded when they are edited so they can be rolled back
+CREATE TABLE IF NOT EXISTS code_revisions (
+  code_id TEXT NOT NULL,
+  revision INTEGER NOT NULL,
+  content TEXT NOT NULL,
+  display_name TEXT NOT NULL,
+  icon_url TEXT,
+  website_url TEXT,
+  kind TEXT NOT NULL,
+  digits INTEGER NOT NULL,
+  period INTEGER NOT NULL,
+  algorithm TEXT NOT NULL,
+  updated_at INTEGER NOT NULL,
+  replaced_at INTEGER NOT NULL,
+  PRIMARY KEY (code_id, revision),
+  FOREIGN KEY (code_id) REFERENCES codes(id) ON DELETE CASCADE
+);
@@ -0,0 +1,3 @@
+-- SHA-256 hashes of the secret of the session's refresh token, and of the one it replaced
+ALTER TABLE sessions ADD COLUMN refresh_token_hash TEXT;
+ALTER TABLE sessions ADD COLUMN previous_refresh_token_hash TEXT;
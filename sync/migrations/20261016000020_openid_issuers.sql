@@ -0,0 +1,5 @@
+-- Upstream identities are keyed by the issuer of the OpenID provider next to the subject. Users from
+-- before multiple providers were supported have an empty issuer until they log in with the default one.
+ALTER TABLE users ADD COLUMN upstream_issuer TEXT NOT NULL DEFAULT '';
+CREATE UNIQUE INDEX users_upstream_identity ON users (upstream_issuer, upstream_userid);
+UPDATE users SET upstream_issuer = 'webauthn' WHERE upstream_userid LIKE 'webauthn:%';
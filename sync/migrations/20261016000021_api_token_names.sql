@@ -0,0 +1,3 @@
+-- Names tell a user's API tokens apart, and the last use shows which are still needed
+ALTER TABLE api_tokens ADD COLUMN name TEXT NOT NULL DEFAULT '';
+ALTER TABLE api_tokens ADD COLUMN last_used_at INTEGER;
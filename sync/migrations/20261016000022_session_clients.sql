@@ -0,0 +1,3 @@
+-- User agent the session logged in with, and the address it was last used from
+ALTER TABLE sessions ADD COLUMN user_agent TEXT NOT NULL DEFAULT '';
+ALTER TABLE sessions ADD COLUMN ip TEXT;
@@ -0,0 +1,2 @@
+-- Set by admins. Suspended users can't log in or use their sessions and tokens
+ALTER TABLE users ADD COLUMN suspended_at INTEGER;
@@ -0,0 +1,11 @@
+-- Identities at further OpenID providers users linked to their account, next to the one it was
+-- created with in users.upstream_issuer and users.upstream_userid
+CREATE TABLE IF NOT EXISTS identities (
+  id TEXT PRIMARY KEY NOT NULL,
+  user_id TEXT NOT NULL,
+  issuer TEXT NOT NULL,
+  subject TEXT NOT NULL,
+  created_at INTEGER NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
+CREATE UNIQUE INDEX identities_subject ON identities (issuer, subject);
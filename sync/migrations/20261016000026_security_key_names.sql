@@ -0,0 +1,2 @@
+-- Label telling the security keys and passkeys of a user apart, e.g. `YubiKey`
+ALTER TABLE webauthn_credentials ADD COLUMN name TEXT NOT NULL DEFAULT '';
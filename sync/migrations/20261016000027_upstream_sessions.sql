@@ -0,0 +1,5 @@
+-- OpenID provider a session logged in at, and the provider's session id (`sid` of the ID token),
+-- so the provider can end it with a back-channel logout
+ALTER TABLE sessions ADD COLUMN upstream_issuer TEXT;
+ALTER TABLE sessions ADD COLUMN upstream_sid TEXT;
+CREATE INDEX IF NOT EXISTS sessions_upstream ON sessions (upstream_issuer, upstream_sid);
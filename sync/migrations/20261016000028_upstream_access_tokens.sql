@@ -0,0 +1,3 @@
+-- Access token the OpenID provider handed out at the login of a session, so the user's profile can be
+-- fetched again from its userinfo endpoint. Only valid for the `openid profile` scopes
+ALTER TABLE sessions ADD COLUMN upstream_access_token TEXT;
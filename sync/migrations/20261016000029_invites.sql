@@ -0,0 +1,13 @@
+-- Single use invites to create an account, minted by admins when registration is invite-only. Only a
+-- SHA-256 hash of the token is stored. `used_by` has no foreign key, as the invite is redeemed right
+-- before the account is created
+CREATE TABLE IF NOT EXISTS invites (
+  id TEXT PRIMARY KEY NOT NULL,
+  token_hash TEXT NOT NULL UNIQUE,
+  created_by TEXT NOT NULL,
+  created_at INTEGER NOT NULL,
+  expires_at INTEGER NOT NULL,
+  used_by TEXT,
+  used_at INTEGER,
+  FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,3 @@
+-- Accounts are deleted after a grace period, during which the user can still cancel the deletion.
+-- Set to the time the account is purged at, NULL if no deletion is pending
+ALTER TABLE users ADD COLUMN delete_at INTEGER;
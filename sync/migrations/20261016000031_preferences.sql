@@ -0,0 +1,10 @@
+-- Preferences of the clients, like their theme, synced between the devices of the user. Values are
+-- JSON, and opaque to the server
+CREATE TABLE IF NOT EXISTS preferences (
+  user_id TEXT NOT NULL,
+  key TEXT NOT NULL,
+  value TEXT NOT NULL,
+  updated_at INTEGER NOT NULL,
+  PRIMARY KEY (user_id, key),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,15 @@
+-- Apps and browsers which registered themselves as a device of the user. Revoking a device revokes
+-- the sessions it logged in with
+CREATE TABLE IF NOT EXISTS devices (
+  id TEXT PRIMARY KEY NOT NULL,
+  user_id TEXT NOT NULL,
+  name TEXT NOT NULL,
+  platform TEXT NOT NULL,
+  app_version TEXT NOT NULL,
+  created_at INTEGER NOT NULL,
+  last_push_at INTEGER,
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
+CREATE INDEX devices_user ON devices (user_id);
+ALTER TABLE sessions ADD COLUMN device_id TEXT REFERENCES devices(id) ON DELETE CASCADE;
+CREATE INDEX sessions_device ON sessions (device_id);
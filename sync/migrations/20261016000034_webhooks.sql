@@ -0,0 +1,30 @@
+-- URLs receiving signed events. Webhooks without a user are registered by admins, and receive the
+-- events of every user
+CREATE TABLE IF NOT EXISTS webhooks (
+  id TEXT PRIMARY KEY NOT NULL,
+  user_id TEXT,
+  url TEXT NOT NULL,
+  secret TEXT NOT NULL,
+  -- Space separated event kinds, empty for every kind
+  events TEXT NOT NULL,
+  created_at INTEGER NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
+CREATE INDEX webhooks_user ON webhooks (user_id);
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+  id TEXT PRIMARY KEY NOT NULL,
+  webhook_id TEXT NOT NULL,
+  event TEXT NOT NULL,
+  payload TEXT NOT NULL,
+  attempts INTEGER NOT NULL DEFAULT 0,
+  -- HTTP status of the last attempt, if the receiver responded
+  response_status INTEGER,
+  error TEXT,
+  created_at INTEGER NOT NULL,
+  -- Cleared once delivered, or after the last attempt failed
+  next_attempt_at INTEGER,
+  delivered_at INTEGER,
+  FOREIGN KEY (webhook_id) REFERENCES webhooks(id) ON DELETE CASCADE
+);
+CREATE INDEX webhook_deliveries_webhook ON webhook_deliveries (webhook_id, created_at);
+CREATE INDEX webhook_deliveries_due ON webhook_deliveries (next_attempt_at);
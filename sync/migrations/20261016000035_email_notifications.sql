@@ -0,0 +1,6 @@
+-- Address security notifications are emailed to, from the OpenID provider or the local
+-- registration. NULL if the user has none
+ALTER TABLE users ADD COLUMN email TEXT;
+-- Users can opt out of the emails
+ALTER TABLE users ADD COLUMN email_notifications BOOLEAN NOT NULL DEFAULT TRUE;
+UPDATE users SET email = (SELECT email FROM passwords WHERE passwords.user_id = users.id);
@@ -0,0 +1,40 @@
+-- Icons stored once by the SHA-256 hash of their bytes, whether fetched for websites or embedded in
+-- codes. The bytes are kept in the icon directory, named by the hash
+CREATE TABLE IF NOT EXISTS icons (
+  hash TEXT PRIMARY KEY NOT NULL,
+  content_type TEXT NOT NULL,
+  size INTEGER NOT NULL,
+  -- Codes showing the icon, kept up to date by the triggers below. Unused icons get removed
+  refs INTEGER NOT NULL DEFAULT 0,
+  -- Last time the icon was stored, so icons about to be linked aren't removed
+  stored_at INTEGER NOT NULL
+);
+CREATE INDEX icons_unused ON icons (refs, stored_at);
+
+-- Icon every code is shown with
+CREATE TABLE IF NOT EXISTS code_icons (
+  code_id TEXT PRIMARY KEY NOT NULL,
+  hash TEXT NOT NULL,
+  FOREIGN KEY (code_id) REFERENCES codes(id) ON DELETE CASCADE ON UPDATE CASCADE,
+  FOREIGN KEY (hash) REFERENCES icons(hash)
+);
+CREATE INDEX code_icons_hash ON code_icons (hash);
+
+CREATE TRIGGER IF NOT EXISTS code_icons_insert AFTER INSERT ON code_icons BEGIN
+  UPDATE icons SET refs = refs + 1 WHERE hash = new.hash;
+END;
+
+CREATE TRIGGER IF NOT EXISTS code_icons_delete AFTER DELETE ON code_icons BEGIN
+  UPDATE icons SET refs = refs - 1 WHERE hash = old.hash;
+END;
+
+CREATE TRIGGER IF NOT EXISTS code_icons_update AFTER UPDATE OF hash ON code_icons BEGIN
+  UPDATE icons SET refs = refs - 1 WHERE hash = old.hash;
+  UPDATE icons SET refs = refs + 1 WHERE hash = new.hash;
+END;
+
+-- Changing the icon or website of a code unlinks the icon it was shown with
+CREATE TRIGGER IF NOT EXISTS code_icons_reset AFTER UPDATE OF icon_url, website_url ON codes
+WHEN old.icon_url IS NOT new.icon_url OR old.website_url IS NOT new.website_url BEGIN
+  DELETE FROM code_icons WHERE code_id = new.id;
+END;
@@ -0,0 +1,4 @@
+-- Tokens created before hashing with Argon2 have no id to be found by. Once they're rehashed, the
+-- start of their SHA-256 hash finds them instead
+ALTER TABLE api_tokens ADD COLUMN legacy_lookup TEXT;
+CREATE INDEX api_tokens_legacy_lookup ON api_tokens (legacy_lookup);
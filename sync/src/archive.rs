@@ -0,0 +1,273 @@
+use crate::models::{
+    codes::Code,
+    credentials::WebauthnCredential,
+    devices::Device,
+    folders::Folder,
+    identities::Identity,
+    preferences,
+    revisions::{self, CodeRevision},
+    sessions::Session,
+    tags::{self, TagUsage},
+    tokens::ApiToken,
+    user::User,
+    webhooks::Webhook,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, Write},
+};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+/// Version of the layout of archives, bumped when files are renamed or change their structure.
+pub const FORMAT_VERSION: u32 = 1;
+
+const README: &str = "\
+Everything Iceblink Sync stores about your account, as of the export.
+
+profile.json    Your profile, settings and preferences, linked identities and security keys
+codes.json      Your codes with their tags and previous versions, including those in the trash
+folders.json    Your folders
+tags.json       Your tags, with the codes carrying them
+sessions.json   Logins to your account
+devices.json    Apps and browsers you registered to sync with
+tokens.json     Your API tokens. The tokens themselves are only stored as hashes, and left out
+webhooks.json   URLs receiving your events, without the secrets they're signed with
+icons/          Icons embedded in your codes, named by the id of their code
+
+Icons fetched from websites are shared by every user of the instance, and not included.
+This archive holds your secrets. Keep it somewhere safe.
+";
+
+#[derive(Serialize)]
+struct ArchivedProfile {
+    format_version: u32,
+    /// Unix timestamp (seconds) at which the archive was made.
+    exported_at: i64,
+    #[serde(flatten)]
+    user: User,
+    /// Preferences of the clients, see [`crate::routes::v1::users::UserSettings`].
+    preferences: BTreeMap<String, serde_json::Value>,
+    identities: Vec<Identity>,
+    security_keys: Vec<ArchivedSecurityKey>,
+}
+
+#[derive(Serialize)]
+struct ArchivedSecurityKey {
+    id: String,
+    name: String,
+    created_at: i64,
+}
+
+#[derive(Serialize)]
+struct ArchivedCode {
+    #[serde(flatten)]
+    code: Code,
+    tags: Vec<String>,
+    /// Unix timestamp (seconds) at which the code was moved to the trash.
+    deleted_at: Option<i64>,
+    /// Previous versions of the code, most recent first.
+    history: Vec<CodeRevision>,
+}
+
+#[derive(Serialize)]
+struct ArchivedSession {
+    id: String,
+    device: String,
+    user_agent: String,
+    ip: Option<String>,
+    upstream_issuer: Option<String>,
+    created_at: i64,
+    last_activity: i64,
+    device_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ArchivedWebhook {
+    id: String,
+    url: String,
+    /// Space separated kinds of events the webhook receives, empty for every kind.
+    events: String,
+    created_at: i64,
+}
+
+#[derive(Serialize)]
+struct ArchivedToken {
+    id: String,
+    name: String,
+    scope: String,
+    created_at: i64,
+    last_used_at: Option<i64>,
+}
+
+/// Everything stored about a user, for them to take out of the instance. Unlike exports, archives
+/// aren't meant to be imported again, but read by people and other programs.
+pub struct UserArchive {
+    profile: ArchivedProfile,
+    codes: Vec<ArchivedCode>,
+    folders: Vec<Folder>,
+    tags: Vec<TagUsage>,
+    sessions: Vec<ArchivedSession>,
+    devices: Vec<Device>,
+    tokens: Vec<ArchivedToken>,
+    webhooks: Vec<ArchivedWebhook>,
+}
+
+impl UserArchive {
+    /// Gathers the archive of the user. Codes in the trash are included until their content is
+    /// cleared.
+    pub async fn collect(pool: &SqlitePool, user: User) -> Result<Self, sqlx::Error> {
+        let mut codes = vec![];
+        let live = Code::get_many(pool, user.id.clone()).await?;
+        let trash = Code::get_trash(pool, user.id.clone(), 0).await?;
+        for code in live.into_iter().chain(trash) {
+            codes.push(ArchivedCode {
+                tags: tags::of_code(pool, &code.id).await?,
+                history: revisions::of_code(pool, &code.id).await?,
+                deleted_at: code.deleted_at,
+                code,
+            });
+        }
+
+        let security_keys = WebauthnCredential::get_for_user(pool, user.id.clone())
+            .await?
+            .into_iter()
+            .map(|key| ArchivedSecurityKey {
+                id: key.id,
+                name: key.name,
+                created_at: key.created_at,
+            })
+            .collect();
+        let sessions = Session::get_for_user(pool, &user.id)
+            .await?
+            .into_iter()
+            .map(|session| ArchivedSession {
+                id: session.id,
+                device: session.device,
+                user_agent: session.user_agent,
+                ip: session.ip,
+                upstream_issuer: session.upstream_issuer,
+                created_at: session.created_at,
+                last_activity: session.last_activity,
+                device_id: session.device_id,
+            })
+            .collect();
+        let tokens = ApiToken::get_for_user(pool, &user.id)
+            .await?
+            .into_iter()
+            .map(|api_token| ArchivedToken {
+                id: api_token.id,
+                name: api_token.name,
+                scope: api_token.scope,
+                created_at: api_token.created_at,
+                last_used_at: api_token.last_used_at,
+            })
+            .collect();
+        let webhooks = Webhook::list(pool, Some(&user.id))
+            .await?
+            .into_iter()
+            .map(|webhook| ArchivedWebhook {
+                id: webhook.id,
+                url: webhook.url,
+                events: webhook.events,
+                created_at: webhook.created_at,
+            })
+            .collect();
+
+        Ok(UserArchive {
+            codes,
+            folders: Folder::get_many(pool, &user.id).await?,
+            tags: tags::usage(pool, &user.id).await?,
+            sessions,
+            devices: Device::get_for_user(pool, &user.id).await?,
+            tokens,
+            webhooks,
+            profile: ArchivedProfile {
+                format_version: FORMAT_VERSION,
+                exported_at: chrono::Utc::now().timestamp(),
+                preferences: preferences::of_user(pool, &user.id).await?,
+                identities: Identity::get_for_user(pool, &user.id).await?,
+                security_keys,
+                user,
+            },
+        })
+    }
+
+    /// Number of codes in the archive, including those in the trash.
+    pub fn codes(&self) -> usize {
+        self.codes.len()
+    }
+
+    /// Writes the archive as a zip file.
+    pub fn into_zip(self) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        let mut add = |name: &str, contents: &[u8]| {
+            zip.start_file(name, options)
+                .expect("Unable to write archive");
+            zip.write_all(contents).expect("Unable to write archive");
+        };
+        add("README.txt", README.as_bytes());
+        add("profile.json", &to_json(&self.profile));
+        add("codes.json", &to_json(&self.codes));
+        add("folders.json", &to_json(&self.folders));
+        add("tags.json", &to_json(&self.tags));
+        add("sessions.json", &to_json(&self.sessions));
+        add("devices.json", &to_json(&self.devices));
+        add("tokens.json", &to_json(&self.tokens));
+        add("webhooks.json", &to_json(&self.webhooks));
+        for archived in &self.codes {
+            if let Some((extension, icon)) = embedded_icon(archived.code.icon_url.as_deref()) {
+                add(&format!("icons/{}.{extension}", archived.code.id), &icon);
+            }
+        }
+
+        zip.finish().expect("Unable to write archive").into_inner()
+    }
+}
+
+fn to_json(value: &impl Serialize) -> Vec<u8> {
+    serde_json::to_vec_pretty(value).expect("Unable to serialize archive")
+}
+
+/// The file extension and image of an icon embedded as a `data:` URI. Linked icons are left out.
+fn embedded_icon(icon_url: Option<&str>) -> Option<(&'static str, Vec<u8>)> {
+    let (mime, data) = icon_url?.strip_prefix("data:")?.split_once(";base64,")?;
+    let extension = match mime {
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/svg+xml" => "svg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/x-icon" | "image/vnd.microsoft.icon" => "ico",
+        _ => "bin",
+    };
+    Some((extension, STANDARD.decode(data).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    #[gtest]
+    fn embedded_icons() {
+        expect_that!(
+            embedded_icon(Some("data:image/svg+xml;base64,PHN2Zy8+")),
+            some(eq(&("svg", b"<svg/>".to_vec())))
+        );
+        expect_that!(
+            embedded_icon(Some("data:image/avif;base64,AAAA")),
+            some(eq(&("bin", vec![0, 0, 0])))
+        );
+        expect_that!(
+            embedded_icon(Some("data:image/png;base64,not base64!")),
+            none()
+        );
+        expect_that!(embedded_icon(Some("https://example.com/icon.png")), none());
+        expect_that!(embedded_icon(None), none());
+    }
+}
@@ -1,19 +1,39 @@
 use crate::{
-    models::{self, user::User},
+    connections,
+    jwt::JwtKeys,
+    lockout,
+    models::{
+        self,
+        sessions::Session,
+        tokens::{ApiToken, TokenScope, TokenScopes},
+        user::User,
+    },
     routes::v1::ApiError,
-    AppState,
+    utils, AppState,
 };
 use axum::{
-    extract::{Request, State},
-    http::header,
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
     middleware::Next,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
 use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{DecodingKey, Validation};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use reqwest::header::USER_AGENT;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use subtle::ConstantTimeEq;
+use tracing::warn;
 
 #[derive(Serialize, Deserialize)]
 pub struct TokenClaims {
@@ -23,34 +43,296 @@ pub struct TokenClaims {
     pub username: String,
     pub display_name: String,
     pub avatar_url: String,
+    /// Id of the [`Session`] the token belongs to.
+    pub sid: String,
+}
+
+/// Creation time (unix seconds) of the session authenticating the request. Absent for API tokens.
+#[derive(Clone, Copy, Debug)]
+pub struct IssuedAt(pub i64);
+
+/// Seconds since logging in, during which sensitive actions are allowed.
+pub const REAUTHENTICATION_WINDOW: i64 = 5 * 60;
+
+/// Only updating the last activity of a session or API token this often, to avoid a write on every
+/// request.
+const SESSION_ACTIVITY_PRECISION: i64 = 60;
+
+/// Coarse label of the browser and operating system sending the user agent, e.g. `Firefox on Linux`.
+/// Anything finer would only make it easier to fingerprint users.
+pub fn device_label(headers: &HeaderMap) -> String {
+    let Some(user_agent) = headers
+        .get(USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+    else {
+        return "Unknown device".into();
+    };
+
+    // Order matters, as e.g. Edge claims to be Chrome and Chrome claims to be Safari
+    let browser = [
+        ("Iceblink", "Iceblink"),
+        ("Firefox/", "Firefox"),
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+    ]
+    .into_iter()
+    .find(|(needle, _)| user_agent.contains(needle))
+    .map(|(_, name)| name);
+
+    let os = [
+        ("Android", "Android"),
+        ("iPhone", "iOS"),
+        ("iPad", "iPadOS"),
+        ("Windows", "Windows"),
+        ("Mac OS X", "macOS"),
+        ("CrOS", "ChromeOS"),
+        ("Linux", "Linux"),
+    ]
+    .into_iter()
+    .find(|(needle, _)| user_agent.contains(needle))
+    .map(|(_, name)| name);
+
+    match (browser, os) {
+        (Some(browser), Some(os)) => format!("{browser} on {os}"),
+        (Some(name), None) | (None, Some(name)) => name.to_string(),
+        (None, None) => "Unknown device".into(),
+    }
 }
 
-pub async fn create_jwt(user: &User, secret: String) -> (String, Cookie) {
-    let now = chrono::Utc::now();
+/// Tokens of a session, handed out when logging in and when refreshing the session.
+pub struct SessionTokens {
+    /// Id of the [`Session`].
+    pub session_id: String,
+    pub jwt: String,
+    /// Exchanged for new tokens with `POST /v1/oauth/refresh`, see [`refresh_session`].
+    pub refresh_token: String,
+    /// Unix timestamp (seconds) at which the JWT expires.
+    pub expires_at: i64,
+}
+
+impl SessionTokens {
+    /// Cookies carrying the tokens for browsers. The refresh token is only sent to the endpoint
+    /// refreshing the session.
+    pub fn cookies(&self) -> [Cookie<'static>; 2] {
+        [
+            jwt_cookie(&self.jwt),
+            Cookie::build((REFRESH_COOKIE, self.refresh_token.clone()))
+                .path("/v1/oauth/refresh")
+                .same_site(SameSite::Strict)
+                .secure(true)
+                .http_only(true)
+                .build(),
+        ]
+    }
 
+    /// `Set-Cookie` headers of [`SessionTokens::cookies`].
+    pub fn cookie_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for cookie in self.cookies() {
+            headers.append(header::SET_COOKIE, cookie.to_string().parse().unwrap());
+        }
+        headers
+    }
+}
+
+fn jwt_cookie(jwt: &str) -> Cookie<'static> {
+    Cookie::build(("iceblink_jwt", jwt.to_string()))
+        .same_site(SameSite::Strict)
+        .secure(true)
+        .http_only(true)
+        .build()
+}
+
+/// Header carrying a renewed JWT, sent when the one authenticating the request expires within
+/// `ServerOptions::jwt_renewal_window`. Clients should use it from then on.
+pub const RENEWED_TOKEN_HEADER: &str = "X-Renewed-Token";
+
+/// JWT issued in place of the one authenticating the request, see [`RENEWED_TOKEN_HEADER`].
+#[derive(Clone)]
+struct RenewedJwt {
+    jwt: String,
+    /// The request came with the JWT cookie, which is replaced as well.
+    cookie: bool,
+}
+
+impl RenewedJwt {
+    fn apply(self, response: &mut Response) {
+        let headers = response.headers_mut();
+        if self.cookie {
+            headers.append(
+                header::SET_COOKIE,
+                jwt_cookie(&self.jwt).to_string().parse().unwrap(),
+            );
+        }
+        headers.insert(RENEWED_TOKEN_HEADER, self.jwt.parse().unwrap());
+    }
+}
+
+/// `Set-Cookie` headers removing the cookies of [`SessionTokens::cookies`], logging browsers out.
+pub fn removal_cookie_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for cookie in [
+        Cookie::build(("iceblink_jwt", "")).removal().build(),
+        Cookie::build((REFRESH_COOKIE, ""))
+            .path("/v1/oauth/refresh")
+            .removal()
+            .build(),
+    ] {
+        headers.append(header::SET_COOKIE, cookie.to_string().parse().unwrap());
+    }
+    headers
+}
+
+/// Name of the cookie carrying the refresh token.
+pub const REFRESH_COOKIE: &str = "iceblink_refresh";
+
+/// Prefix of every refresh token, followed by the id of its session and a secret.
+const REFRESH_TOKEN_PREFIX: &str = "ibr_";
+
+/// Generates a refresh token for the session, returning it next to the hash of its secret.
+fn generate_refresh_token(session_id: &str) -> (String, String) {
+    let secret = utils::generate_id(40);
+    (
+        format!("{REFRESH_TOKEN_PREFIX}{session_id}_{secret}"),
+        Session::hash_refresh_secret(&secret),
+    )
+}
+
+/// Signs a JWT for the session, bundling it with the refresh token.
+fn session_tokens(
+    user: &User,
+    session: &Session,
+    keys: &JwtKeys,
+    refresh_token: String,
+    now: i64,
+) -> SessionTokens {
+    let (jwt, expires_at) = sign_jwt(user, session, keys, now);
+    SessionTokens {
+        session_id: session.id.clone(),
+        jwt,
+        refresh_token,
+        expires_at,
+    }
+}
+
+/// Signs a JWT for the session, valid for the lifetime of the keys. Returns it with its expiry.
+fn sign_jwt(user: &User, session: &Session, keys: &JwtKeys, now: i64) -> (String, i64) {
+    let expires_at = now + keys.lifetime().as_secs() as i64;
     let claims = TokenClaims {
-        iat: now.timestamp() as usize,
-        exp: (now + chrono::Duration::days(90)).timestamp() as usize,
+        iat: now as usize,
+        exp: expires_at as usize,
         sub: user.id.clone(),
         username: user.username.clone(),
         display_name: user.display_name.clone(),
         avatar_url: user.avatar_url.clone(),
+        sid: session.id.clone(),
     };
 
-    let jwt = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret.as_ref()),
-    )
-    .unwrap();
+    (keys.encode(&claims), expires_at)
+}
 
-    let cookie = Cookie::build(("iceblink_jwt", jwt.clone()))
-        .same_site(SameSite::Strict)
-        .secure(true)
-        .http_only(true)
-        .build();
+/// User agent of the request, cut off at [`Session::MAX_USER_AGENT_LENGTH`] bytes.
+fn user_agent(headers: &HeaderMap) -> String {
+    let mut user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    // Header values which are valid strings are ASCII, so any byte is a char boundary
+    user_agent.truncate(Session::MAX_USER_AGENT_LENGTH);
+    user_agent
+}
+
+/// Starts a session for the user, labelled with the device from the login request's headers.
+/// `ip` is the address of the client logging in, if known.
+pub async fn create_jwt(
+    pool: &SqlitePool,
+    user: &User,
+    keys: &JwtKeys,
+    request_headers: &HeaderMap,
+    ip: Option<IpAddr>,
+) -> Result<SessionTokens, sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+    let id = utils::generate_id(24);
+    let (refresh_token, refresh_token_hash) = generate_refresh_token(&id);
+
+    let session = Session {
+        id,
+        user_id: user.id.clone(),
+        created_at: now,
+        last_activity: now,
+        device: device_label(request_headers),
+        refresh_token_hash: Some(refresh_token_hash),
+        previous_refresh_token_hash: None,
+        user_agent: user_agent(request_headers),
+        ip: ip.map(|ip| ip.to_string()),
+        upstream_issuer: None,
+        upstream_sid: None,
+        upstream_access_token: None,
+        device_id: None,
+    };
+    session.insert(pool).await?;
+
+    Ok(session_tokens(user, &session, keys, refresh_token, now))
+}
+
+/// Exchanges a refresh token for a new JWT of the same session, and a new refresh token. Every
+/// refresh token only works once: using a replaced one again revokes the session, as it was
+/// probably stolen.
+pub async fn refresh_session(
+    pool: &SqlitePool,
+    keys: &JwtKeys,
+    refresh_token: &str,
+    idle_timeout: Duration,
+) -> Result<SessionTokens, ApiError> {
+    let (session_id, secret) = refresh_token
+        .strip_prefix(REFRESH_TOKEN_PREFIX)
+        .and_then(|token| token.split_once('_'))
+        .ok_or(ApiError::InvalidAuthentication)?;
+    let mut session = Session::get(pool, session_id)
+        .await?
+        .ok_or(ApiError::SessionExpired)?;
+    let hash = Session::hash_refresh_secret(secret);
+    let matches = |stored: &Option<String>| -> bool {
+        stored
+            .as_ref()
+            .is_some_and(|stored| stored.as_bytes().ct_eq(hash.as_bytes()).into())
+    };
+
+    if matches(&session.previous_refresh_token_hash) {
+        warn!("A replaced refresh token was used again, revoking its session");
+        session.delete(pool).await?;
+        return Err(ApiError::SessionExpired);
+    }
+    if !matches(&session.refresh_token_hash) {
+        return Err(ApiError::InvalidAuthentication);
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let idle_timeout = idle_timeout.as_secs() as i64;
+    if idle_timeout != 0 && now - session.last_activity > idle_timeout {
+        session.delete(pool).await?;
+        return Err(ApiError::SessionExpired);
+    }
+    let user = User::get_by_id(pool, session.user_id.clone())
+        .await?
+        .ok_or(ApiError::JwtUserGone)?;
+    require_active(&user)?;
+
+    let (refresh_token, refresh_token_hash) = generate_refresh_token(&session.id);
+    // Losing a race against a concurrent refresh with the same token
+    session
+        .rotate_refresh_token(pool, refresh_token_hash, now)
+        .await
+        .map_err(|err| match err {
+            sqlx::Error::RowNotFound => ApiError::InvalidAuthentication,
+            err => err.into(),
+        })?;
 
-    (jwt, cookie)
+    Ok(session_tokens(&user, &session, keys, refresh_token, now))
 }
 
 pub async fn jwt_middleware(
@@ -58,7 +340,50 @@ pub async fn jwt_middleware(
     State(data): State<Arc<AppState>>,
     mut req: Request,
     next: Next,
-) -> Result<impl IntoResponse, ApiError> {
+) -> Response {
+    let wants_html = accepts_html(req.headers());
+
+    match authenticate(&cookie_jar, &data, &mut req).await {
+        Ok(()) => {
+            let renewed = req.extensions_mut().remove::<RenewedJwt>();
+            let mut response = next.run(req).await;
+            if let Some(renewed) = renewed {
+                renewed.apply(&mut response);
+            }
+            response
+        }
+        // Browsers are better served by being sent to login, than by a JSON error
+        Err(
+            ApiError::MissingAuthentication
+            | ApiError::InvalidAuthentication
+            | ApiError::InvalidJwtSignature
+            | ApiError::JwtUserGone
+            | ApiError::SessionExpired,
+        ) if wants_html => (
+            StatusCode::FOUND,
+            [(
+                header::LOCATION,
+                data.settings.unauthenticated_redirect.clone(),
+            )],
+        )
+            .into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+fn accepts_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+async fn authenticate(
+    cookie_jar: &CookieJar,
+    data: &AppState,
+    req: &mut Request,
+) -> Result<(), ApiError> {
+    let from_cookie = cookie_jar.get("iceblink_jwt").is_some();
     let token = cookie_jar
         .get("iceblink_jwt")
         .map(|cookie| cookie.value().to_string())
@@ -74,25 +399,192 @@ pub async fn jwt_middleware(
 
     let token = token.ok_or(ApiError::MissingAuthentication)?;
 
-    let claims = decode::<TokenClaims>(
-        &token,
-        &DecodingKey::from_secret(data.settings.jwt_secret.as_ref()),
-        &Validation::default(),
-    )?
-    .claims;
+    if token.starts_with(ApiToken::PREFIX) {
+        // API tokens can't be guessed, but whoever tries to is cut off regardless
+        let lockout_keys: Vec<String> = request_ip(req, &data.settings.trusted_proxies)
+            .map(lockout::address_key)
+            .into_iter()
+            .collect();
+        data.lockouts.check(&lockout_keys)?;
+        let Some(mut api_token) =
+            ApiToken::get_by_token(&data.db, &token, &data.verified_tokens).await?
+        else {
+            data.lockouts.record_failure("api_token", &lockout_keys);
+            return Err(ApiError::InvalidAuthentication);
+        };
+        let scopes = api_token.scopes().ok_or(ApiError::InvalidAuthentication)?;
+        let user = models::user::User::get_by_id(&data.db, api_token.user_id.clone())
+            .await?
+            .ok_or(ApiError::JwtUserGone)?;
+        require_active(&user)?;
+
+        let now = chrono::Utc::now().timestamp();
+        if !api_token
+            .last_used_at
+            .is_some_and(|last_used_at| now - last_used_at < SESSION_ACTIVITY_PRECISION)
+        {
+            api_token.touch(&data.db, now).await?;
+        }
+
+        req.extensions_mut().insert(user);
+        req.extensions_mut().insert(scopes);
+        req.extensions_mut().insert(api_token);
+        return Ok(());
+    }
+
+    let claims = data.jwt_keys.decode::<TokenClaims>(&token)?;
+
+    let mut session = Session::get(&data.db, &claims.sid)
+        .await?
+        .filter(|session| session.user_id == claims.sub)
+        .ok_or(ApiError::SessionExpired)?;
+    let now = chrono::Utc::now().timestamp();
+    let idle_timeout = data.settings.session_idle_timeout.as_secs() as i64;
+
+    if idle_timeout != 0 && now - session.last_activity > idle_timeout {
+        session.delete(&data.db).await?;
+        return Err(ApiError::SessionExpired);
+    }
+    if now - session.last_activity >= SESSION_ACTIVITY_PRECISION {
+        let ip = request_ip(req, &data.settings.trusted_proxies);
+        session
+            .touch(&data.db, now, ip.map(|ip| ip.to_string()))
+            .await?;
+    }
 
     let user = models::user::User::get_by_id(&data.db, claims.sub).await?;
     let user = user.ok_or(ApiError::JwtUserGone)?;
+    require_active(&user)?;
+
+    // Active users get a fresh JWT before theirs runs out, rather than being logged out mid-session
+    let renewal_window = data.settings.jwt_renewal_window.as_secs() as i64;
+    if renewal_window != 0 && claims.exp as i64 - now < renewal_window {
+        let (jwt, _) = sign_jwt(&user, &session, &data.jwt_keys, now);
+        req.extensions_mut().insert(RenewedJwt {
+            jwt,
+            cookie: from_cookie,
+        });
+    }
 
     req.extensions_mut().insert(user);
-    Ok(next.run(req).await)
+    req.extensions_mut().insert(TokenScopes::full());
+    // Refreshing issues new JWTs, which don't make for a recent login
+    req.extensions_mut().insert(IssuedAt(session.created_at));
+    req.extensions_mut().insert(session);
+    Ok(())
+}
+
+/// Address of the client sending the request, see [`connections::client_ip`].
+fn request_ip(req: &Request, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    connections::client_ip(
+        req.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(peer)| peer.ip()),
+        req.headers(),
+        trusted_proxies,
+    )
+}
+
+/// The user authenticating the request, if any, for endpoints which don't require logging in.
+pub async fn optional_user(
+    cookie_jar: &CookieJar,
+    data: &AppState,
+    req: &mut Request,
+) -> Option<User> {
+    authenticate(cookie_jar, data, req).await.ok()?;
+    req.extensions_mut().remove::<User>()
+}
+
+/// Rejects users an admin suspended, when logging in and on every request.
+pub fn require_active(user: &User) -> Result<(), ApiError> {
+    match user.suspended_at {
+        Some(_) => Err(ApiError::AccountSuspended),
+        None => Ok(()),
+    }
+}
+
+/// Only lets admins through. Has to run after [`jwt_middleware`].
+pub async fn admin_middleware(req: Request, next: Next) -> Response {
+    match req.extensions().get::<User>() {
+        Some(user) if user.is_admin => next.run(req).await,
+        _ => ApiError::AdminRequired.into_response(),
+    }
+}
+
+/// Rejects requests not authenticated by a login within the [`REAUTHENTICATION_WINDOW`].
+pub fn require_recent_login(issued_at: Option<IssuedAt>) -> Result<(), ApiError> {
+    match issued_at {
+        Some(IssuedAt(iat)) if chrono::Utc::now().timestamp() - iat <= REAUTHENTICATION_WINDOW => {
+            Ok(())
+        }
+        _ => Err(ApiError::ReauthenticationRequired),
+    }
+}
+
+/// Header carrying a step-up token, proving a security key was used for the request. Handed out by
+/// `POST /v1/user/step-up/finish`.
+pub const STEP_UP_HEADER: &str = "X-Step-Up-Token";
+
+/// Guards actions which would let a stolen token wipe or walk off with the whole vault. Lets through
+/// requests of a recent login, see [`require_recent_login`], and those with a step-up token of the
+/// user in the [`STEP_UP_HEADER`], which is used up.
+pub fn require_step_up(
+    state: &AppState,
+    user: &User,
+    issued_at: Option<IssuedAt>,
+    headers: &HeaderMap,
+) -> Result<(), ApiError> {
+    if require_recent_login(issued_at).is_ok() {
+        return Ok(());
+    }
+
+    #[cfg(feature = "webauthn")]
+    if let (Some(passkeys), Some(token)) = (
+        state.passkeys.as_deref(),
+        headers
+            .get(STEP_UP_HEADER)
+            .and_then(|token| token.to_str().ok()),
+    ) {
+        if passkeys.take_step_up(token, &user.id) {
+            return Ok(());
+        }
+    }
+    #[cfg(not(feature = "webauthn"))]
+    let _ = (state, user, headers);
+
+    Err(ApiError::ReauthenticationRequired)
+}
+
+/// Rejects requests authenticated with a token which wasn't granted the scope.
+pub fn require_scope(scopes: &TokenScopes, scope: TokenScope) -> Result<(), ApiError> {
+    if scopes.allows(scope) {
+        Ok(())
+    } else {
+        Err(ApiError::InsufficientScope)
+    }
 }
 
 #[derive(Deserialize, Clone)]
 pub struct OpenIdDiscovery {
+    /// Required by OpenID Connect, but not every OAuth server sends it.
+    pub issuer: Option<String>,
     pub authorization_endpoint: String,
     pub token_endpoint: String,
     pub userinfo_endpoint: String,
+    /// Keys ID tokens are signed with. Plain OAuth servers don't have any.
+    pub jwks_uri: Option<String>,
+}
+
+impl OpenIdDiscovery {
+    pub async fn fetch(client: &reqwest::Client, server: &str) -> Result<Self, reqwest::Error> {
+        client
+            .get(format!("{server}/.well-known/openid-configuration"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<OpenIdDiscovery>()
+            .await
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -105,76 +597,526 @@ pub struct OpenIdUserInfo {
     pub username: String,
     #[serde(rename = "picture")]
     pub avatar: String,
+    /// Only sent by providers which were granted the `email` scope.
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+}
+
+impl OpenIdUserInfo {
+    /// Name to show for the user, falling back to the username if the provider has none.
+    pub fn display_name_or_username(&self) -> String {
+        self.display_name
+            .clone()
+            .unwrap_or_else(|| self.username.clone())
+    }
+
+    /// Address to email notifications to, unless the provider says it isn't verified.
+    pub fn verified_email(&self) -> Option<String> {
+        self.email
+            .clone()
+            .filter(|email| !email.is_empty() && self.email_verified != Some(false))
+    }
 }
 
+/// Tokens the OpenID provider hands out for an authorization code.
 #[derive(Deserialize, Debug)]
-struct TokenExchangeResponse {
-    access_token: String,
+pub struct OpenIdTokens {
+    pub access_token: String,
+    /// Only sent by OpenID Connect providers, when requested with the `openid` scope.
+    pub id_token: Option<String>,
 }
 
+/// Name of the provider configured with `--oauth-server`, used when logging in without naming one.
+pub const DEFAULT_OPENID_PROVIDER: &str = "default";
+
 #[derive(Clone)]
 pub struct OpenId {
+    /// Clients pick the provider to log in with by this name.
+    pub name: String,
+    /// Identifies the provider in users' upstream identities, next to their subject.
+    pub issuer: String,
     pub authorization: String,
     pub token: String,
     pub userinfo: String,
     pub client_id: String,
     pub client_secret: String,
+    /// Proxy requests to the OAuth server go through.
+    pub proxy: Option<reqwest::Proxy>,
+    /// ID tokens are only verified against the provider's keys if it publishes them.
+    pub jwks_uri: Option<String>,
+    pub keys: OpenIdKeys,
+}
+
+/// Keys of an OpenID provider, fetched from its JWKS when first needed and again whenever a token
+/// names a key which isn't known yet, as providers publish new keys before signing with them. Anyone
+/// can send such tokens, so the JWKS is fetched by one request at a time, and at most once per
+/// refresh interval.
+#[derive(Clone)]
+pub struct OpenIdKeys(Arc<KeysInner>);
+
+struct KeysInner {
+    keys: RwLock<Option<jsonwebtoken::jwk::JwkSet>>,
+    /// When the JWKS was last fetched, held while fetching it.
+    fetched_at: tokio::sync::Mutex<Option<Instant>>,
+    refresh_interval: Duration,
+}
+
+/// How often the JWKS of a provider is fetched at most.
+const KEYS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long fetching the JWKS of a provider may take.
+const KEYS_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Default for OpenIdKeys {
+    fn default() -> Self {
+        Self::with_refresh_interval(KEYS_REFRESH_INTERVAL)
+    }
+}
+
+impl OpenIdKeys {
+    pub fn with_refresh_interval(refresh_interval: Duration) -> Self {
+        Self(Arc::new(KeysInner {
+            keys: RwLock::default(),
+            fetched_at: tokio::sync::Mutex::default(),
+            refresh_interval,
+        }))
+    }
+
+    fn find(&self, kid: &str) -> Option<jsonwebtoken::jwk::Jwk> {
+        self.0.keys.read().unwrap().as_ref()?.find(kid).cloned()
+    }
+
+    /// Key with the ID, fetching the keys with `fetch` if it isn't known and they weren't fetched
+    /// within the refresh interval. Requests waiting for another one to fetch them use its keys.
+    async fn find_or_fetch<F>(
+        &self,
+        kid: &str,
+        fetch: impl FnOnce() -> F,
+    ) -> Result<Option<jsonwebtoken::jwk::Jwk>, reqwest::Error>
+    where
+        F: Future<Output = Result<jsonwebtoken::jwk::JwkSet, reqwest::Error>>,
+    {
+        if let Some(jwk) = self.find(kid) {
+            return Ok(Some(jwk));
+        }
+
+        let mut fetched_at = self.0.fetched_at.lock().await;
+        if let Some(jwk) = self.find(kid) {
+            return Ok(Some(jwk));
+        }
+        if fetched_at.is_some_and(|at| at.elapsed() < self.0.refresh_interval) {
+            return Ok(None);
+        }
+        // Failed fetches count too, so an unreachable provider isn't asked again by every token
+        *fetched_at = Some(Instant::now());
+        let keys = fetch().await?;
+        *self.0.keys.write().unwrap() = Some(keys);
+        Ok(self.find(kid))
+    }
+}
+
+/// Claims of ID tokens checked on login, besides the issuer, audience and expiry.
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    nonce: Option<String>,
+    /// Session at the provider, for providers supporting back-channel logout.
+    sid: Option<String>,
+}
+
+/// Event a logout token has to carry, telling it apart from other JWTs of the provider.
+const BACKCHANNEL_LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+
+/// Claims of a back-channel logout token, see [`OpenId::verify_logout_token`].
+#[derive(Deserialize)]
+struct LogoutTokenClaims {
+    sub: Option<String>,
+    sid: Option<String>,
+    #[serde(default)]
+    events: HashMap<String, serde_json::Value>,
+    /// Logout tokens must not have one, so ID tokens can't be passed off as them.
+    nonce: Option<String>,
+}
+
+/// Sessions a provider ended, by the user's subject at the provider, the provider's session id, or
+/// both.
+#[derive(Debug)]
+pub struct UpstreamLogout {
+    pub subject: Option<String>,
+    pub sid: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
 struct TokenExchangeRequest {
     client_id: String,
+    /// Left out for clients registered as public, which rely on PKCE instead.
+    #[serde(skip_serializing_if = "String::is_empty")]
     client_secret: String,
     code: String,
+    /// The redirect URI the code was requested with, which the provider compares.
+    redirect_uri: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code_verifier: Option<String>,
+}
+
+/// Name of the cookie binding a login started at `/v1/oauth/authorize` to the browser.
+pub const OAUTH_STATE_COOKIE: &str = "iceblink_oauth_state";
+
+/// Cookie carrying the state of a login, sent back when the provider redirects to the instance.
+/// `Lax`, as that redirect is a navigation from the provider's site. The state itself expires on
+/// the server, see [`crate::oauth_state::LIFETIME`]. Sent to `/v1/user/identities` as well, which
+/// finishes logins linking another identity.
+pub fn oauth_state_cookie(state: &str) -> Cookie<'static> {
+    Cookie::build((OAUTH_STATE_COOKIE, state.to_string()))
+        .path("/v1")
+        .same_site(SameSite::Lax)
+        .secure(true)
+        .http_only(true)
+        .build()
+}
+
+/// `Set-Cookie` header removing the cookie of [`oauth_state_cookie`], once its login finished.
+pub fn oauth_state_removal_header() -> HeaderValue {
+    Cookie::build((OAUTH_STATE_COOKIE, ""))
+        .path("/v1")
+        .removal()
+        .build()
+        .to_string()
+        .parse()
+        .unwrap()
+}
+
+/// Rejects ID tokens without the nonce of the login, as they were issued for another login and
+/// replayed.
+fn verify_nonce(claims: &IdTokenClaims, nonce: &str) -> Result<(), ApiError> {
+    match &claims.nonce {
+        Some(claimed) if bool::from(claimed.as_bytes().ct_eq(nonce.as_bytes())) => Ok(()),
+        _ => Err(ApiError::InvalidIdToken),
+    }
+}
+
+/// Claims of the ID token, without checking its signature.
+fn unverified_claims(id_token: &str) -> Result<IdTokenClaims, ApiError> {
+    id_token
+        .split('.')
+        .nth(1)
+        .and_then(|payload| URL_SAFE_NO_PAD.decode(payload).ok())
+        .and_then(|payload| serde_json::from_slice(&payload).ok())
+        .ok_or(ApiError::InvalidIdToken)
+}
+
+/// PKCE code challenge methods clients may use when logging in.
+pub const CODE_CHALLENGE_METHODS: [&str; 1] = ["S256"];
+
+/// Whether the PKCE code verifier has the length and characters RFC 7636 requires.
+pub fn valid_code_verifier(verifier: &str) -> bool {
+    (43..=128).contains(&verifier.len())
+        && verifier
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || "-._~".contains(char))
+}
+
+/// Whether the scheme can be one of native apps' private-use URI schemes, like `iceblink`. Web
+/// schemes are left out, as only loopback addresses may be redirected to over HTTP.
+pub fn valid_native_scheme(scheme: &str) -> bool {
+    scheme.starts_with(|char: char| char.is_ascii_lowercase())
+        && scheme
+            .chars()
+            .all(|char| char.is_ascii_lowercase() || char.is_ascii_digit() || "+-.".contains(char))
+        && !["http", "https"].contains(&scheme)
+}
+
+/// Whether a native app may finish logins at the redirect URI, following RFC 8252: either at one of
+/// the private-use schemes, or over HTTP at a loopback address with any port. Hostnames like
+/// `localhost` are rejected, as they might not resolve to the loopback interface.
+pub fn native_redirect_uri(uri: &str, schemes: &[String]) -> bool {
+    let Ok(url) = reqwest::Url::parse(uri) else {
+        return false;
+    };
+    if url.fragment().is_some() || !url.username().is_empty() || url.password().is_some() {
+        return false;
+    }
+
+    match url.scheme() {
+        "http" => url
+            .host_str()
+            .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+            .and_then(|host| host.parse::<IpAddr>().ok())
+            .is_some_and(|ip| ip.is_loopback()),
+        scheme => schemes.iter().any(|allowed| allowed == scheme),
+    }
+}
+
+/// S256 code challenge of the PKCE code verifier, which clients send to the authorization endpoint.
+/// The provider only hands out tokens for the code to whoever knows the verifier.
+pub fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
 }
 
 #[bon::bon]
 impl OpenId {
     #[builder]
     pub async fn discover(
+        #[builder(default = DEFAULT_OPENID_PROVIDER.to_string())] name: String,
         client_id: String,
         client_secret: String,
         server: String,
+        proxy: Option<reqwest::Proxy>,
     ) -> Result<Self, reqwest::Error> {
-        let config = reqwest::get(format!("{server}/.well-known/openid-configuration"))
-            .await?
-            .json::<OpenIdDiscovery>()
-            .await?;
+        let client = utils::client_builder(proxy.as_ref()).build()?;
+        let config = OpenIdDiscovery::fetch(&client, &server).await?;
 
         Ok(OpenId {
+            name,
+            issuer: config.issuer.unwrap_or(server),
             client_id,
             client_secret,
             authorization: config.authorization_endpoint,
             token: config.token_endpoint,
             userinfo: config.userinfo_endpoint,
+            proxy,
+            jwks_uri: config.jwks_uri,
+            keys: OpenIdKeys::default(),
         })
     }
 
-    pub async fn exchange(self, code: String) -> Result<String, reqwest::Error> {
-        let request = reqwest::Client::new()
+    pub fn with_proxy(mut self, proxy: reqwest::Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Redirect URI registered with the provider. Other providers than the default one get their
+    /// name appended, so logging in knows which provider to exchange the code with.
+    pub fn redirect_uri(&self, base: &str) -> String {
+        if self.name == DEFAULT_OPENID_PROVIDER {
+            return base.to_string();
+        }
+
+        let separator = if base.contains('?') { '&' } else { '?' };
+        format!("{base}{separator}provider={}", self.name)
+    }
+
+    fn client(&self) -> Result<reqwest::Client, reqwest::Error> {
+        utils::client_builder(self.proxy.as_ref()).build()
+    }
+
+    /// URL of the provider's authorization endpoint to send the user to, for logging in with the
+    /// state and nonce of [`crate::oauth_state::OauthStates::start`]. The provider sends the user
+    /// back to the redirect URI, see [`OpenId::redirect_uri`].
+    pub fn authorization_url(
+        &self,
+        redirect_uri: &str,
+        state: &str,
+        nonce: &str,
+        code_challenge: Option<&str>,
+    ) -> String {
+        let mut params = vec![
+            ("response_type", "code"),
+            ("client_id", self.client_id.as_str()),
+            ("redirect_uri", redirect_uri),
+            ("scope", "openid profile email"),
+            ("state", state),
+            ("nonce", nonce),
+        ];
+        if let Some(code_challenge) = code_challenge {
+            params.push(("code_challenge", code_challenge));
+            params.push(("code_challenge_method", CODE_CHALLENGE_METHODS[0]));
+        }
+
+        let query: Vec<String> = params
+            .into_iter()
+            .map(|(key, value)| format!("{key}={}", utf8_percent_encode(value, NON_ALPHANUMERIC)))
+            .collect();
+        let separator = if self.authorization.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        format!("{}{separator}{}", self.authorization, query.join("&"))
+    }
+
+    /// Exchanges the authorization code for tokens. Codes requested with a PKCE code challenge need
+    /// the verifier it was derived from, and every code the redirect URI it was requested with.
+    pub async fn exchange(
+        self,
+        code: String,
+        redirect_uri: String,
+        code_verifier: Option<String>,
+    ) -> Result<OpenIdTokens, reqwest::Error> {
+        let request = self
+            .client()?
             .post(self.token)
             .header(USER_AGENT, "Iceblink")
             .json(&TokenExchangeRequest {
                 client_id: self.client_id,
                 client_secret: self.client_secret,
                 code,
+                redirect_uri,
+                code_verifier,
             });
 
-        let response = request
+        request
             .send()
             .await?
-            .json::<TokenExchangeResponse>()
+            .error_for_status()?
+            .json::<OpenIdTokens>()
+            .await
+    }
+
+    /// Verifies the ID token was signed by the provider, for this client, and isn't expired, and
+    /// that it has the nonce of the login. Providers without a JWKS, like plain OAuth servers, only
+    /// get the nonce checked. Returns the provider's session id, if the token has one.
+    pub async fn verify_id_token(
+        &self,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<Option<String>, ApiError> {
+        let Some(jwks_uri) = &self.jwks_uri else {
+            let claims = unverified_claims(id_token)?;
+            verify_nonce(&claims, nonce)?;
+            return Ok(claims.sid);
+        };
+
+        let claims: IdTokenClaims = self
+            .decode_signed(jwks_uri, id_token, &["exp", "iss", "aud"], || {
+                ApiError::InvalidIdToken
+            })
             .await?;
 
-        Ok(response.access_token)
+        verify_nonce(&claims, nonce)?;
+        Ok(claims.sid)
+    }
+
+    /// Verifies a back-channel logout token the provider sent to end sessions of a user, following
+    /// OpenID Connect Back-Channel Logout 1.0. Providers without a JWKS can't send any.
+    pub async fn verify_logout_token(
+        &self,
+        logout_token: &str,
+    ) -> Result<UpstreamLogout, ApiError> {
+        let jwks_uri = self
+            .jwks_uri
+            .as_deref()
+            .ok_or(ApiError::InvalidLogoutToken)?;
+        let claims: LogoutTokenClaims = self
+            .decode_signed(jwks_uri, logout_token, &["iat", "iss", "aud"], || {
+                ApiError::InvalidLogoutToken
+            })
+            .await?;
+
+        if !claims.events.contains_key(BACKCHANNEL_LOGOUT_EVENT)
+            || claims.nonce.is_some()
+            || (claims.sub.is_none() && claims.sid.is_none())
+        {
+            return Err(ApiError::InvalidLogoutToken);
+        }
+        Ok(UpstreamLogout {
+            subject: claims.sub,
+            sid: claims.sid,
+        })
+    }
+
+    /// Decodes a JWT signed by one of the provider's keys and issued to this client, with the
+    /// claims which are required. Fails with the `invalid` error if the JWT is invalid.
+    async fn decode_signed<T: serde::de::DeserializeOwned>(
+        &self,
+        jwks_uri: &str,
+        token: &str,
+        required_claims: &[&str],
+        invalid: fn() -> ApiError,
+    ) -> Result<T, ApiError> {
+        let header = jsonwebtoken::decode_header(token).map_err(|_| invalid())?;
+        let kid = header.kid.ok_or_else(invalid)?;
+        let jwk = self
+            .keys
+            .find_or_fetch(&kid, || self.fetch_keys(jwks_uri))
+            .await
+            .map_err(ApiError::OpenIdKeysFail)?
+            .ok_or_else(invalid)?;
+
+        // Keys only verify the algorithms of their type, so an HMAC signature with the public key
+        // as secret is rejected
+        let key = DecodingKey::from_jwk(&jwk).map_err(|_| invalid())?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.client_id]);
+        validation.set_required_spec_claims(required_claims);
+        jsonwebtoken::decode::<T>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|_| invalid())
+    }
+
+    async fn fetch_keys(
+        &self,
+        jwks_uri: &str,
+    ) -> Result<jsonwebtoken::jwk::JwkSet, reqwest::Error> {
+        self.client()?
+            .get(jwks_uri)
+            .header(USER_AGENT, utils::USER_AGENT)
+            .timeout(KEYS_FETCH_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
     }
 
     pub async fn userinfo(self, token: String) -> Result<OpenIdUserInfo, reqwest::Error> {
-        let request = reqwest::Client::new()
+        let request = self
+            .client()?
             .get(self.userinfo)
             .header(USER_AGENT, "Iceblink")
             .bearer_auth(token);
 
-        request.send().await?.json::<OpenIdUserInfo>().await
+        request
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<OpenIdUserInfo>()
+            .await
+    }
+}
+
+/// OpenID providers users can log in with. The first one is the default.
+#[derive(Clone)]
+pub struct OpenIdProviders(Vec<OpenId>);
+
+impl OpenIdProviders {
+    pub fn new(default: OpenId, others: Vec<OpenId>) -> Self {
+        OpenIdProviders(std::iter::once(default).chain(others).collect())
+    }
+
+    pub fn default_provider(&self) -> &OpenId {
+        &self.0[0]
+    }
+
+    /// The provider with the name, or the default one without a name.
+    pub fn get(&self, name: Option<&str>) -> Option<&OpenId> {
+        match name {
+            Some(name) => self.0.iter().find(|provider| provider.name == name),
+            None => Some(self.default_provider()),
+        }
+    }
+
+    /// The provider identities with the issuer belong to.
+    pub fn by_issuer(&self, issuer: &str) -> Option<&OpenId> {
+        self.0.iter().find(|provider| provider.issuer == issuer)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &OpenId> {
+        self.0.iter()
+    }
+
+    pub fn with_proxy(self, proxy: reqwest::Proxy) -> Self {
+        OpenIdProviders(
+            self.0
+                .into_iter()
+                .map(|provider| provider.with_proxy(proxy.clone()))
+                .collect(),
+        )
+    }
+}
+
+impl From<OpenId> for OpenIdProviders {
+    fn from(openid: OpenId) -> Self {
+        OpenIdProviders::new(openid, vec![])
     }
 }
@@ -0,0 +1,110 @@
+use crate::utils;
+use axum::body::Bytes;
+use futures_util::Stream;
+use sqlx::SqlitePool;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
+use tracing::warn;
+
+#[derive(Debug)]
+pub enum BackupError {
+    FileSystemFailToWrite,
+    Database(sqlx::Error),
+}
+
+/// Writes a consistent snapshot of the database to a new file in the given directory.
+/// Uses `VACUUM INTO`, which reads within a single transaction, so concurrent writes can't tear the backup.
+///
+/// The backup runs on its own, so when the caller gives up on it, the file is still removed once
+/// it's written, rather than left behind.
+pub async fn create_backup(pool: &SqlitePool, directory: &Path) -> Result<BackupFile, BackupError> {
+    tokio::fs::create_dir_all(directory)
+        .await
+        .map_err(|_| BackupError::FileSystemFailToWrite)?;
+
+    // Backups made within the same millisecond don't clash, as `VACUUM INTO` fails for existing files
+    let file = BackupFile {
+        path: directory.join(format!(
+            "iceblink-{}-{}.db",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+            utils::generate_id(6)
+        )),
+        keep: false,
+    };
+
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        sqlx::query("VACUUM INTO ?")
+            .bind(file.path.to_string_lossy().to_string())
+            .execute(&pool)
+            .await
+            .map_err(BackupError::Database)?;
+        Ok(file)
+    })
+    .await
+    .expect("Unable to create backup")
+}
+
+/// File of a backup, which is removed when dropped unless it's kept.
+#[derive(Debug)]
+pub struct BackupFile {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl BackupFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Keeps the backup on the server, returning its path.
+    pub fn keep(mut self) -> PathBuf {
+        self.keep = true;
+        std::mem::take(&mut self.path)
+    }
+}
+
+impl Drop for BackupFile {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+        match std::fs::remove_file(&self.path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                warn!("Unable to remove backup {}: {err}", self.path.display())
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Chunks of a backup which is being downloaded. The backup only exists to be downloaded, so its file
+/// is removed once the download finishes or is aborted.
+pub struct BackupDownload {
+    // Closed before the file is removed, as open files can't be removed on every platform
+    chunks: ReaderStream<File>,
+    _file: BackupFile,
+}
+
+impl BackupDownload {
+    pub fn new(chunks: File, file: BackupFile) -> Self {
+        BackupDownload {
+            chunks: ReaderStream::new(chunks),
+            _file: file,
+        }
+    }
+}
+
+impl Stream for BackupDownload {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.chunks).poll_next(cx)
+    }
+}
@@ -0,0 +1,266 @@
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{self, HeaderMap},
+    Extension, Router,
+};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto::Builder, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv6Addr},
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::net::TcpListener;
+use tower::{Layer, ServiceExt};
+use tracing::{debug, error, trace};
+
+/// Keeps track of how many connections every peer has open at once.
+#[derive(Clone, Debug)]
+pub struct ConnectionLimiter {
+    max_per_ip: usize,
+    trusted_proxies: Arc<Vec<IpAddr>>,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// Releases the slot of a peer when dropped. Trusted proxies are not tracked, and hold no slot.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    slot: Option<(IpAddr, Arc<Mutex<HashMap<IpAddr, usize>>>)>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_per_ip: usize, trusted_proxies: Vec<IpAddr>) -> Self {
+        ConnectionLimiter {
+            max_per_ip,
+            trusted_proxies: Arc::new(trusted_proxies),
+            active: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Reserves a slot for the given peer. Returns `None` if it already is at the limit.
+    /// Trusted proxies are never limited.
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<ConnectionGuard> {
+        if self.trusted_proxies.contains(&ip) {
+            return Some(ConnectionGuard { slot: None });
+        }
+
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(ip).or_insert(0);
+
+        if *count >= self.max_per_ip {
+            return None;
+        }
+
+        *count += 1;
+        Some(ConnectionGuard {
+            slot: Some((ip, self.active.clone())),
+        })
+    }
+
+    pub fn active(&self, ip: IpAddr) -> usize {
+        self.active.lock().unwrap().get(&ip).copied().unwrap_or(0)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let Some((ip, active)) = &self.slot else {
+            return;
+        };
+        let mut active = active.lock().unwrap();
+
+        if let Some(count) = active.get_mut(ip) {
+            *count -= 1;
+
+            if *count == 0 {
+                active.remove(ip);
+            }
+        }
+    }
+}
+
+/// Address of the client. Behind a trusted proxy, that is the last address the proxy appended to
+/// `X-Forwarded-For`, as anything before it could be made up by the client.
+pub fn client_ip(
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let peer = peer?;
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+
+    headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok())
+        .or(Some(peer))
+}
+
+/// Network the client address is limited as. IPv6 addresses count as their /64, as that is what a
+/// single subscriber is usually handed, and rotating through it would otherwise evade any limit.
+pub fn client_network(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !(u128::MAX >> 64))),
+        ip => ip,
+    }
+}
+
+/// Serves the router, with the address of the peer as [`ConnectInfo`] like
+/// [`Router::into_make_service_with_connect_info`]. Connections of peers at the limit are closed as
+/// soon as they are accepted, before anything is read from them, and the others hold a slot until
+/// they close. Once `shutdown` completes, no more connections are accepted, and open connections are
+/// waited for.
+pub async fn serve(
+    listener: TcpListener,
+    router: Router,
+    limiter: ConnectionLimiter,
+    shutdown: impl Future<Output = ()>,
+) {
+    let graceful = GracefulShutdown::new();
+    let mut shutdown = pin!(shutdown);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    // Like axum, wait for file descriptors to free up rather than spinning
+                    if !matches!(
+                        err.kind(),
+                        io::ErrorKind::ConnectionRefused
+                            | io::ErrorKind::ConnectionAborted
+                            | io::ErrorKind::ConnectionReset
+                    ) {
+                        error!("Unable to accept connection: {err}");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let Some(guard) = limiter.try_acquire(peer.ip()) else {
+            debug!("Closing connection of {peer}, which has too many open already");
+            drop(stream);
+            continue;
+        };
+
+        let service = Extension(ConnectInfo(peer))
+            .layer(router.clone())
+            .map_request(|req: http::Request<Incoming>| req.map(Body::new));
+        let connection = Builder::new(TokioExecutor::new())
+            .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service))
+            .into_owned();
+        let connection = graceful.watch(connection);
+
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                trace!("Connection of {peer} failed: {err}");
+            }
+            drop(guard);
+        });
+    }
+
+    drop(listener);
+    graceful.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    #[gtest]
+    fn refuses_over_limit() {
+        let limiter = ConnectionLimiter::new(2, vec![]);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let first = limiter.try_acquire(ip);
+        let second = limiter.try_acquire(ip);
+        assert_that!(first, some(anything()));
+        assert_that!(second, some(anything()));
+        assert_that!(limiter.try_acquire(ip), none());
+        assert_that!(limiter.active(ip), eq(2));
+
+        // Other peers are unaffected
+        assert_that!(
+            limiter.try_acquire("192.0.2.2".parse().unwrap()),
+            some(anything())
+        );
+
+        drop(first);
+        assert_that!(limiter.active(ip), eq(1));
+        assert_that!(limiter.try_acquire(ip), some(anything()));
+    }
+
+    #[gtest]
+    fn releases_all_slots() {
+        let limiter = ConnectionLimiter::new(3, vec![]);
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+
+        let guards: Vec<_> = (0..3).map(|_| limiter.try_acquire(ip)).collect();
+        assert_that!(limiter.try_acquire(ip), none());
+
+        drop(guards);
+        assert_that!(limiter.active(ip), eq(0));
+    }
+
+    #[gtest]
+    fn client_ip_behind_trusted_proxy() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.9, 192.0.2.7".parse().unwrap());
+
+        expect_that!(client_ip(Some(proxy), &headers, &[proxy]), some(eq(client)));
+        // Untrusted peers can't claim to be someone else
+        expect_that!(
+            client_ip(Some(client), &headers, &[proxy]),
+            some(eq(client))
+        );
+        expect_that!(
+            client_ip(Some(proxy), &HeaderMap::new(), &[proxy]),
+            some(eq(proxy))
+        );
+        expect_that!(client_ip(None, &headers, &[proxy]), none());
+    }
+
+    #[gtest]
+    fn ipv6_clients_count_as_their_network() {
+        let parse = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        expect_that!(
+            client_network(parse("2001:db8:1:2:a:b:c:d")),
+            eq(parse("2001:db8:1:2::"))
+        );
+        expect_that!(client_network(parse("192.0.2.7")), eq(parse("192.0.2.7")));
+        // IPv4 clients of dual-stack sockets are still told apart
+        expect_that!(
+            client_network(parse("::ffff:192.0.2.7")),
+            eq(parse("192.0.2.7"))
+        );
+    }
+
+    #[gtest]
+    fn trusted_proxies_are_exempt() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let limiter = ConnectionLimiter::new(1, vec![proxy]);
+
+        let guards: Vec<_> = (0..10).map(|_| limiter.try_acquire(proxy)).collect();
+        assert_that!(guards, each(some(anything())));
+        assert_that!(limiter.active(proxy), eq(0));
+    }
+}
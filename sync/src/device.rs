@@ -0,0 +1,301 @@
+use crate::{connections, utils};
+use rand::Rng;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long a device has to get its user code approved.
+pub const LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// Devices polling again sooner than this are told to slow down.
+pub const POLLING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Pending devices at once, so unauthenticated requests can't grow the map forever.
+const MAX_PENDING: usize = 10_000;
+
+/// Pending devices of a single client network at once, see [`connections::client_network`], so one
+/// client can't take up all of [`MAX_PENDING`].
+pub const MAX_PENDING_PER_NETWORK: usize = 20;
+
+/// Letters of user codes. Without vowels, so they don't spell words, and without letters which are
+/// easily mistaken for others.
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+
+const USER_CODE_LENGTH: usize = 8;
+
+#[derive(Debug)]
+enum Decision {
+    Pending,
+    /// Approved by the user with the id.
+    Approved(String),
+    Denied,
+}
+
+#[derive(Debug)]
+struct PendingDevice {
+    /// Normalized, see [`normalize_user_code`].
+    user_code: String,
+    /// Network of the client which started authorizing the device, if known.
+    network: Option<IpAddr>,
+    created: Instant,
+    last_poll: Option<Instant>,
+    decision: Decision,
+}
+
+#[derive(Debug, Default)]
+struct Devices {
+    /// Pending devices by their device code.
+    pending: HashMap<String, PendingDevice>,
+    /// Device codes by the normalized user code of the device.
+    user_codes: HashMap<String, String>,
+    /// Pending devices per client network.
+    networks: HashMap<IpAddr, usize>,
+    /// Device codes in the order they were started, which is also the order they expire in.
+    started: VecDeque<(Instant, String)>,
+}
+
+impl Devices {
+    /// Forgets the devices which expired, without going through all of them.
+    fn expire(&mut self) {
+        while let Some((created, _)) = self.started.front() {
+            if created.elapsed() < LIFETIME {
+                break;
+            }
+            if let Some((_, device_code)) = self.started.pop_front() {
+                self.remove(&device_code);
+            }
+        }
+    }
+
+    fn remove(&mut self, device_code: &str) -> Option<PendingDevice> {
+        let device = self.pending.remove(device_code)?;
+        self.user_codes.remove(&device.user_code);
+        if let Some(network) = device.network {
+            if let Some(count) = self.networks.get_mut(&network) {
+                *count -= 1;
+                if *count == 0 {
+                    self.networks.remove(&network);
+                }
+            }
+        }
+        Some(device)
+    }
+}
+
+/// Outcome of a device polling for its authorization.
+#[derive(Debug, PartialEq)]
+pub enum DevicePoll {
+    /// Nobody entered the user code yet.
+    Pending,
+    /// Polled again within the [`POLLING_INTERVAL`].
+    SlowDown,
+    /// The user with the id approved the device, which can be logged in now. Only returned once.
+    Approved(String),
+    Denied,
+    /// The device code expired, was already used, or never existed.
+    Expired,
+}
+
+/// Devices without a browser, like CLIs and TVs, logging in with the OAuth 2.0 device authorization
+/// grant (RFC 8628). They show a user code, which the user approves on a device that is logged in,
+/// while polling with their device code until then.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceAuthorizations(Arc<Mutex<Devices>>);
+
+impl DeviceAuthorizations {
+    /// Starts authorizing a device for the client, returning its device code and the user code to
+    /// show. Returns `None` if too many devices are pending, in total or of the client's network.
+    pub fn start(&self, client: Option<IpAddr>) -> Option<(String, String)> {
+        let network = client.map(connections::client_network);
+        let mut devices = self.0.lock().unwrap();
+        devices.expire();
+        if devices.pending.len() >= MAX_PENDING {
+            return None;
+        }
+        if let Some(network) = network {
+            let count = devices.networks.entry(network).or_default();
+            if *count >= MAX_PENDING_PER_NETWORK {
+                return None;
+            }
+            *count += 1;
+        }
+
+        let user_code = loop {
+            let user_code = generate_user_code();
+            if !devices.user_codes.contains_key(&user_code) {
+                break user_code;
+            }
+        };
+        let device_code = utils::generate_id(40);
+        let created = Instant::now();
+        devices
+            .user_codes
+            .insert(user_code.clone(), device_code.clone());
+        devices.started.push_back((created, device_code.clone()));
+        devices.pending.insert(
+            device_code.clone(),
+            PendingDevice {
+                user_code: user_code.clone(),
+                network,
+                created,
+                last_poll: None,
+                decision: Decision::Pending,
+            },
+        );
+
+        Some((device_code, format_user_code(&user_code)))
+    }
+
+    /// Logs the device with the user code in as the user. Returns `false` if no device is waiting
+    /// for the code.
+    pub fn approve(&self, user_code: &str, user_id: String) -> bool {
+        self.decide(user_code, Decision::Approved(user_id))
+    }
+
+    /// Rejects the device with the user code. Returns `false` if no device is waiting for the code.
+    pub fn deny(&self, user_code: &str) -> bool {
+        self.decide(user_code, Decision::Denied)
+    }
+
+    fn decide(&self, user_code: &str, decision: Decision) -> bool {
+        let user_code = normalize_user_code(user_code);
+        let mut devices = self.0.lock().unwrap();
+        let devices = &mut *devices;
+
+        let Some(device) = devices
+            .user_codes
+            .get(&user_code)
+            .and_then(|device_code| devices.pending.get_mut(device_code))
+            .filter(|device| {
+                matches!(device.decision, Decision::Pending) && device.created.elapsed() < LIFETIME
+            })
+        else {
+            return false;
+        };
+
+        device.decision = decision;
+        true
+    }
+
+    pub fn poll(&self, device_code: &str) -> DevicePoll {
+        let mut devices = self.0.lock().unwrap();
+        let Some(device) = devices
+            .pending
+            .get_mut(device_code)
+            .filter(|device| device.created.elapsed() < LIFETIME)
+        else {
+            return DevicePoll::Expired;
+        };
+
+        if matches!(device.decision, Decision::Pending) {
+            let too_soon = device
+                .last_poll
+                .is_some_and(|last_poll| last_poll.elapsed() < POLLING_INTERVAL);
+            device.last_poll = Some(Instant::now());
+
+            return if too_soon {
+                DevicePoll::SlowDown
+            } else {
+                DevicePoll::Pending
+            };
+        }
+
+        // Decided devices are forgotten, so the device code only logs in once
+        match devices.remove(device_code).map(|device| device.decision) {
+            Some(Decision::Approved(user_id)) => DevicePoll::Approved(user_id),
+            _ => DevicePoll::Denied,
+        }
+    }
+}
+
+fn generate_user_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..USER_CODE_LENGTH)
+        .map(|_| USER_CODE_ALPHABET[rng.gen_range(0..USER_CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+/// Splits the user code in two halves, e.g. `BCDF-GHJK`, to make it easier to type.
+fn format_user_code(user_code: &str) -> String {
+    let (first, second) = user_code.split_at(USER_CODE_LENGTH / 2);
+    format!("{first}-{second}")
+}
+
+/// Users may type the code in lowercase, and with or without the dash.
+fn normalize_user_code(user_code: &str) -> String {
+    user_code
+        .chars()
+        .filter(|char| char.is_ascii_alphabetic())
+        .map(|char| char.to_ascii_uppercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    #[gtest]
+    fn approve_with_typed_code() {
+        let devices = DeviceAuthorizations::default();
+        let (device_code, user_code) = devices.start(None).unwrap();
+        assert_that!(user_code, matches_regex("^[B-Z]{4}-[B-Z]{4}$"));
+
+        expect_that!(devices.poll(&device_code), eq(&DevicePoll::Pending));
+        expect_that!(devices.poll(&device_code), eq(&DevicePoll::SlowDown));
+
+        let typed = user_code.replace('-', " ").to_lowercase();
+        assert_that!(
+            devices.approve(&typed, "k0d8WrkRjK6gkc3C".into()),
+            is_true()
+        );
+        // Approving twice, or a code nobody waits for, does nothing
+        expect_that!(devices.deny(&user_code), is_false());
+        expect_that!(
+            devices.approve("BCDF-GHJK", "k0d8WrkRjK6gkc3C".into()),
+            is_false()
+        );
+
+        expect_that!(
+            devices.poll(&device_code),
+            eq(&DevicePoll::Approved("k0d8WrkRjK6gkc3C".into()))
+        );
+        expect_that!(devices.poll(&device_code), eq(&DevicePoll::Expired));
+    }
+
+    #[gtest]
+    fn deny() {
+        let devices = DeviceAuthorizations::default();
+        let (device_code, user_code) = devices.start(None).unwrap();
+
+        assert_that!(devices.deny(&user_code), is_true());
+        expect_that!(devices.poll(&device_code), eq(&DevicePoll::Denied));
+        expect_that!(devices.poll(&device_code), eq(&DevicePoll::Expired));
+    }
+
+    #[gtest]
+    fn limits_pending_devices_per_network() {
+        let devices = DeviceAuthorizations::default();
+        let client: IpAddr = "2001:db8::1".parse().unwrap();
+        let neighbour: IpAddr = "2001:db8::2".parse().unwrap();
+
+        let started: Vec<_> = (0..MAX_PENDING_PER_NETWORK)
+            .map(|_| devices.start(Some(client)).unwrap())
+            .collect();
+        // Other addresses of the same /64 count towards the same limit
+        expect_that!(devices.start(Some(neighbour)), none());
+        expect_that!(
+            devices.start(Some("192.0.2.1".parse().unwrap())),
+            some(anything())
+        );
+
+        // Devices which are decided and polled make room again
+        let (device_code, user_code) = &started[0];
+        assert_that!(devices.deny(user_code), is_true());
+        expect_that!(devices.poll(device_code), eq(&DevicePoll::Denied));
+        expect_that!(devices.start(Some(neighbour)), some(anything()));
+    }
+}
@@ -0,0 +1,56 @@
+use crate::{models::user::User, routes::v1::ApiError};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+/// Prefix of values encrypted by a client, followed by the base64 encoded nonce and ciphertext.
+/// Clients of users with end-to-end encryption encrypt the content of codes before sending them,
+/// in whichever way they like, so the server only ever stores opaque values.
+pub const PREFIX: &str = "e2e:v1:";
+/// Longest key check accepted, which is plenty for a hash.
+pub const MAX_KEY_CHECK_LENGTH: usize = 512;
+
+/// Whether the value looks encrypted by a client. The server can't tell more than that.
+pub fn is_encrypted(value: &str) -> bool {
+    value
+        .strip_prefix(PREFIX)
+        .is_some_and(|encoded| !encoded.is_empty() && STANDARD.decode(encoded).is_ok())
+}
+
+/// Rejects storing a plaintext secret for users with end-to-end encryption.
+pub fn require_encrypted(user: &User, content: &str) -> Result<(), ApiError> {
+    if user.encryption_enabled && !is_encrypted(content) {
+        return Err(ApiError::EncryptionRequired);
+    }
+    Ok(())
+}
+
+/// Rejects storing secrets which are plaintext by nature, like those of backups of other apps, for
+/// users with end-to-end encryption.
+pub fn reject_plaintext(user: &User) -> Result<(), ApiError> {
+    if user.encryption_enabled {
+        return Err(ApiError::EncryptionRequired);
+    }
+    Ok(())
+}
+
+/// Rejects actions needing to read the secrets, like verifying codes, for users with end-to-end
+/// encryption.
+pub fn require_readable(user: &User) -> Result<(), ApiError> {
+    if user.encryption_enabled {
+        return Err(ApiError::EncryptedContent);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    #[gtest]
+    fn encrypted_values() {
+        expect_that!(is_encrypted("e2e:v1:bm9uY2VjaXBoZXJ0ZXh0"), is_true());
+        expect_that!(is_encrypted("e2e:v1:"), is_false());
+        expect_that!(is_encrypted("e2e:v1:not base64!"), is_false());
+        expect_that!(is_encrypted("JBSWY3DPEHPK3PXP"), is_false());
+    }
+}
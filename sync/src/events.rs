@@ -0,0 +1,248 @@
+use crate::{
+    models::{codes::Code, folders::Folder, sessions::Session},
+    routes::v1::ApiError,
+};
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use utoipa::ToSchema;
+
+/// Header clients use to identify themselves, so they can recognize events caused by their own requests.
+pub const CLIENT_ID_HEADER: &str = "X-Client-Id";
+const MAX_CLIENT_ID_LENGTH: usize = 64;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+pub enum EventKind {
+    #[serde(rename = "code.created")]
+    CodeCreated,
+    #[serde(rename = "code.updated")]
+    CodeUpdated,
+    #[serde(rename = "code.deleted")]
+    CodeDeleted,
+    #[serde(rename = "folder.created")]
+    FolderCreated,
+    #[serde(rename = "folder.updated")]
+    FolderUpdated,
+    #[serde(rename = "folder.deleted")]
+    FolderDeleted,
+    #[serde(rename = "settings.updated")]
+    SettingsUpdated,
+    #[serde(rename = "user.deleted")]
+    UserDeleted,
+    #[serde(rename = "user.deletion_scheduled")]
+    UserDeletionScheduled,
+    #[serde(rename = "user.deletion_cancelled")]
+    UserDeletionCancelled,
+}
+
+impl EventKind {
+    /// Name of the kind on the wire, e.g. `code.created`.
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::CodeCreated => "code.created",
+            EventKind::CodeUpdated => "code.updated",
+            EventKind::CodeDeleted => "code.deleted",
+            EventKind::FolderCreated => "folder.created",
+            EventKind::FolderUpdated => "folder.updated",
+            EventKind::FolderDeleted => "folder.deleted",
+            EventKind::SettingsUpdated => "settings.updated",
+            EventKind::UserDeleted => "user.deleted",
+            EventKind::UserDeletionScheduled => "user.deletion_scheduled",
+            EventKind::UserDeletionCancelled => "user.deletion_cancelled",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "code.created" => Some(EventKind::CodeCreated),
+            "code.updated" => Some(EventKind::CodeUpdated),
+            "code.deleted" => Some(EventKind::CodeDeleted),
+            "folder.created" => Some(EventKind::FolderCreated),
+            "folder.updated" => Some(EventKind::FolderUpdated),
+            "folder.deleted" => Some(EventKind::FolderDeleted),
+            "settings.updated" => Some(EventKind::SettingsUpdated),
+            "user.deleted" => Some(EventKind::UserDeleted),
+            "user.deletion_scheduled" => Some(EventKind::UserDeletionScheduled),
+            "user.deletion_cancelled" => Some(EventKind::UserDeletionCancelled),
+            _ => None,
+        }
+    }
+}
+
+/// A change to the data of a user.
+#[derive(Serialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct Event {
+    pub kind: EventKind,
+    #[serde(skip)]
+    pub user_id: String,
+    pub code_id: Option<String>,
+    /// Set for folder events only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub folder_id: Option<String>,
+    /// `X-Client-Id` of the request causing the change, if it had one.
+    pub client_id: Option<String>,
+    /// Registered device which made the change, see [`crate::models::devices::Device`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+}
+
+impl Event {
+    pub fn code(kind: EventKind, code: &Code, client: ClientId) -> Self {
+        Event {
+            kind,
+            user_id: code.owner_id.clone(),
+            code_id: Some(code.id.clone()),
+            folder_id: None,
+            client_id: client.id,
+            device_id: client.device_id,
+        }
+    }
+
+    pub fn folder(kind: EventKind, folder: &Folder, client: ClientId) -> Self {
+        Event {
+            kind,
+            user_id: folder.owner_id.clone(),
+            code_id: None,
+            folder_id: Some(folder.id.clone()),
+            client_id: client.id,
+            device_id: client.device_id,
+        }
+    }
+
+    pub fn user_deleted(user_id: String, client_id: ClientId) -> Self {
+        Event::user(EventKind::UserDeleted, user_id, client_id)
+    }
+
+    /// An event about the account itself, like its settings changing.
+    pub fn user(kind: EventKind, user_id: String, client: ClientId) -> Self {
+        Event {
+            kind,
+            user_id,
+            code_id: None,
+            folder_id: None,
+            client_id: client.id,
+            device_id: client.device_id,
+        }
+    }
+}
+
+/// Broadcasts events to every subscriber. Subscribers have to filter for the user they're interested in.
+#[derive(Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Events {
+    /// Subscribers lagging behind more than `capacity` events miss the oldest ones.
+    pub fn new(capacity: usize) -> Self {
+        Events {
+            sender: broadcast::channel(capacity).0,
+        }
+    }
+
+    pub fn publish(&self, event: Event) {
+        // Fails only if nobody is subscribed, in which case there's nobody to tell
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events::new(256)
+    }
+}
+
+/// The client making a request, to attribute the changes it causes to.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClientId {
+    /// Value of the optional `X-Client-Id` header.
+    pub id: Option<String>,
+    /// Device the session of the request is registered as, if any.
+    pub device_id: Option<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientId
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let device_id = parts
+            .extensions
+            .get::<Session>()
+            .and_then(|session| session.device_id.clone());
+        let Some(value) = parts.headers.get(CLIENT_ID_HEADER) else {
+            return Ok(ClientId {
+                id: None,
+                device_id,
+            });
+        };
+
+        match value.to_str() {
+            Ok(client_id) if !client_id.is_empty() && client_id.len() <= MAX_CLIENT_ID_LENGTH => {
+                Ok(ClientId {
+                    id: Some(client_id.to_string()),
+                    device_id,
+                })
+            }
+            _ => Err(ApiError::BadRequest(format!(
+                "The {CLIENT_ID_HEADER} header has to be between 1 and {MAX_CLIENT_ID_LENGTH} visible ASCII characters."
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    #[gtest]
+    fn event_serialization() {
+        let event = Event {
+            kind: EventKind::CodeUpdated,
+            user_id: "k0d8WrkRjK6gkc3C".into(),
+            code_id: Some("Ckpt4eFi1pw9fxI3".into()),
+            folder_id: None,
+            client_id: Some("phone".into()),
+            device_id: None,
+        };
+
+        assert_that!(
+            serde_json::to_value(event).unwrap(),
+            eq(&serde_json::json!({
+                "kind": "code.updated",
+                "code_id": "Ckpt4eFi1pw9fxI3",
+                "client_id": "phone"
+            }))
+        );
+    }
+
+    #[gtest]
+    fn kind_names_match_serialization() {
+        for kind in [
+            EventKind::CodeCreated,
+            EventKind::CodeUpdated,
+            EventKind::CodeDeleted,
+            EventKind::FolderCreated,
+            EventKind::FolderUpdated,
+            EventKind::FolderDeleted,
+            EventKind::SettingsUpdated,
+            EventKind::UserDeleted,
+            EventKind::UserDeletionScheduled,
+            EventKind::UserDeletionCancelled,
+        ] {
+            expect_that!(
+                serde_json::to_value(kind).unwrap(),
+                eq(&serde_json::json!(kind.name()))
+            );
+            expect_that!(EventKind::parse(kind.name()), some(eq(kind)));
+        }
+    }
+}
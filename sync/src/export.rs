@@ -0,0 +1,519 @@
+use crate::{
+    models::{codes::Code, folders::Folder, tags::TagUsage},
+    otpauth, totp,
+};
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{collections::HashMap, fmt};
+use utoipa::ToSchema;
+
+/// Highest Argon2 memory cost (in KiB) accepted when decrypting, to keep imports from exhausting memory.
+const MAX_MEMORY_COST: u32 = 64 * 1024;
+/// Highest Argon2 iteration count accepted when decrypting, to keep imports from hogging the CPU.
+const MAX_TIME_COST: u32 = 8;
+
+/// Version of the export format written by this version of Iceblink. When changing
+/// [`ExportedCode`], bump it and add a migration from the previous version to [`MIGRATIONS`], so
+/// older exports keep importing.
+pub const FORMAT_VERSION: u64 = 3;
+
+/// Upgrades the JSON of an export from a version to the next one, starting with version 1.
+type Migration = fn(&mut Value);
+
+const MIGRATIONS: [Migration; FORMAT_VERSION as usize - 1] = [
+    // 2: OTP parameters, favorites, folders and tags
+    |export| {
+        for code in export["codes"].as_array_mut().into_iter().flatten() {
+            let Some(code) = code.as_object_mut() else {
+                continue;
+            };
+            for (field, default) in [
+                ("issuer", Value::Null),
+                ("account", Value::Null),
+                ("digits", json!(totp::DIGITS)),
+                ("period", json!(totp::PERIOD)),
+                ("algorithm", json!(totp::Algorithm::default().as_str())),
+                ("icon_url", Value::Null),
+                ("favorite", json!(false)),
+                ("folder", Value::Null),
+                ("tags", json!([])),
+            ] {
+                code.entry(field).or_insert(default);
+            }
+        }
+    },
+    // 3: Steam Guard codes
+    |export| {
+        for code in export["codes"].as_array_mut().into_iter().flatten() {
+            let Some(code) = code.as_object_mut() else {
+                continue;
+            };
+            code.entry("kind")
+                .or_insert(json!(totp::Kind::default().as_str()));
+        }
+    },
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct ExportedCode {
+    pub content: String,
+    pub display_name: String,
+    pub website_url: Option<String>,
+    pub expires_at: Option<i64>,
+    pub issuer: Option<String>,
+    pub account: Option<String>,
+    /// `totp`, or `steam` for Steam Guard codes.
+    pub kind: String,
+    pub digits: i64,
+    pub period: i64,
+    pub algorithm: String,
+    pub icon_url: Option<String>,
+    pub favorite: bool,
+    /// Name of the folder the code is in. Imports put it in a top level folder of the same name.
+    pub folder: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// Plaintext backup of all codes owned by a user.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+#[serde(try_from = "Value")]
+pub struct Export {
+    /// Version of the format. Exports from before versioning have none, and are version 1.
+    pub format_version: u64,
+    pub codes: Vec<ExportedCode>,
+}
+
+/// Parameters used to derive the encryption key from the passphrase.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct KdfParams {
+    /// Always `argon2id`.
+    pub algorithm: String,
+    /// Base64 encoded salt.
+    pub salt: String,
+    /// Memory cost in KiB.
+    pub memory_cost: u32,
+    pub time_cost: u32,
+    pub parallelism: u32,
+}
+
+/// An [`Export`] encrypted with AES-256-GCM, using a key derived from a passphrase with Argon2id.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct EncryptedExport {
+    pub kdf: KdfParams,
+    /// Base64 encoded 96-bit nonce.
+    pub nonce: String,
+    /// Base64 encoded ciphertext of the JSON serialized export, including the authentication tag.
+    pub ciphertext: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+#[serde(untagged)]
+pub enum ExportFile {
+    Encrypted(EncryptedExport),
+    Plain(Export),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ExportError {
+    /// The file is encrypted, but no passphrase was given.
+    PassphraseRequired,
+    /// Decryption failed. Either the passphrase is wrong, or the file has been tampered with.
+    WrongPassphrase,
+    /// The file isn't a valid encrypted export, or uses unsupported parameters.
+    Malformed,
+    /// The export was made by a newer version of Iceblink, with a format this one doesn't know.
+    UnsupportedVersion,
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ExportError::PassphraseRequired => "the export is encrypted",
+            ExportError::WrongPassphrase => "the passphrase is wrong",
+            ExportError::Malformed => "the export is malformed",
+            ExportError::UnsupportedVersion => "the export has an unsupported format version",
+        })
+    }
+}
+
+impl TryFrom<Value> for Export {
+    type Error = ExportError;
+
+    /// Reads an export of any format version, migrating it to the current one.
+    fn try_from(mut value: Value) -> Result<Self, Self::Error> {
+        if !value.is_object() {
+            return Err(ExportError::Malformed);
+        }
+        let version = match value.get("format_version") {
+            Some(version) => version.as_u64().ok_or(ExportError::Malformed)?,
+            None => 1,
+        };
+        if version == 0 {
+            return Err(ExportError::Malformed);
+        }
+        if version > FORMAT_VERSION {
+            return Err(ExportError::UnsupportedVersion);
+        }
+
+        for migration in &MIGRATIONS[version as usize - 1..] {
+            migration(&mut value);
+        }
+
+        let codes: Vec<ExportedCode> =
+            serde_json::from_value(value["codes"].take()).map_err(|_| ExportError::Malformed)?;
+        if codes.iter().any(|code| code.params().is_none()) {
+            return Err(ExportError::Malformed);
+        }
+
+        Ok(Export {
+            format_version: FORMAT_VERSION,
+            codes,
+        })
+    }
+}
+
+impl ExportedCode {
+    /// Parameters of the code, or `None` if they are out of the supported range.
+    pub fn params(&self) -> Option<totp::Params> {
+        match totp::Kind::parse(&self.kind)? {
+            totp::Kind::Steam => return Some(totp::Params::STEAM),
+            totp::Kind::Totp => {}
+        }
+        let digits = u32::try_from(self.digits).ok()?;
+        if !otpauth::DIGITS.contains(&digits) || !otpauth::PERIODS.contains(&self.period) {
+            return None;
+        }
+
+        Some(totp::Params {
+            kind: totp::Kind::Totp,
+            algorithm: totp::Algorithm::parse(&self.algorithm)?,
+            digits,
+            period: self.period,
+        })
+    }
+}
+
+impl Export {
+    /// Export of the codes, with the names of their folders and tags.
+    pub fn new(codes: Vec<Code>, folders: &[Folder], tags: &[TagUsage]) -> Self {
+        let folders: HashMap<&str, &str> = folders
+            .iter()
+            .map(|folder| (folder.id.as_str(), folder.name.as_str()))
+            .collect();
+        let mut tags_of: HashMap<&str, Vec<String>> = HashMap::new();
+        for tag in tags {
+            for code_id in &tag.code_ids {
+                tags_of
+                    .entry(code_id.as_str())
+                    .or_default()
+                    .push(tag.name.clone());
+            }
+        }
+
+        let codes = codes
+            .into_iter()
+            .map(|code| ExportedCode {
+                folder: code
+                    .folder_id
+                    .as_deref()
+                    .and_then(|id| folders.get(id))
+                    .map(|name| name.to_string()),
+                tags: tags_of.remove(code.id.as_str()).unwrap_or_default(),
+                content: code.content,
+                display_name: code.display_name,
+                website_url: code.website_url,
+                expires_at: code.expires_at,
+                issuer: code.issuer,
+                account: code.account,
+                kind: code.kind,
+                digits: code.digits,
+                period: code.period,
+                algorithm: code.algorithm,
+                icon_url: code.icon_url,
+                favorite: code.favorite,
+            })
+            .collect();
+
+        Export {
+            format_version: FORMAT_VERSION,
+            codes,
+        }
+    }
+
+    pub fn encrypt(&self, passphrase: &str) -> EncryptedExport {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        let kdf = KdfParams {
+            algorithm: "argon2id".into(),
+            salt: STANDARD.encode(salt),
+            memory_cost: Params::DEFAULT_M_COST,
+            time_cost: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        };
+        let cipher = kdf
+            .cipher(passphrase)
+            .expect("Default key derivation parameters are valid");
+
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let plaintext = serde_json::to_vec(self).expect("Unable to serialize export");
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext.as_slice())
+            .expect("Unable to encrypt export");
+
+        EncryptedExport {
+            kdf,
+            nonce: STANDARD.encode(nonce),
+            ciphertext: STANDARD.encode(ciphertext),
+        }
+    }
+}
+
+impl KdfParams {
+    fn cipher(&self, passphrase: &str) -> Result<Aes256Gcm, ExportError> {
+        if self.algorithm != "argon2id"
+            || self.memory_cost > MAX_MEMORY_COST
+            || self.time_cost > MAX_TIME_COST
+        {
+            return Err(ExportError::Malformed);
+        }
+
+        let salt = STANDARD
+            .decode(&self.salt)
+            .map_err(|_| ExportError::Malformed)?;
+        let params = Params::new(self.memory_cost, self.time_cost, self.parallelism, Some(32))
+            .map_err(|_| ExportError::Malformed)?;
+
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|_| ExportError::Malformed)?;
+
+        Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+    }
+}
+
+impl EncryptedExport {
+    pub fn decrypt(&self, passphrase: &str) -> Result<Export, ExportError> {
+        let nonce = STANDARD
+            .decode(&self.nonce)
+            .map_err(|_| ExportError::Malformed)?;
+        if nonce.len() != 12 {
+            return Err(ExportError::Malformed);
+        }
+        let ciphertext = STANDARD
+            .decode(&self.ciphertext)
+            .map_err(|_| ExportError::Malformed)?;
+
+        let plaintext = self
+            .kdf
+            .cipher(passphrase)?
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| ExportError::WrongPassphrase)?;
+
+        serde_json::from_slice::<Value>(&plaintext)
+            .map_err(|_| ExportError::Malformed)?
+            .try_into()
+    }
+}
+
+impl ExportFile {
+    /// Reads an export file of any format version. Unlike deserializing it, tells newer format
+    /// versions apart from malformed files.
+    pub fn from_value(value: Value) -> Result<Self, ExportError> {
+        match value.get("ciphertext") {
+            Some(_) => serde_json::from_value(value)
+                .map(ExportFile::Encrypted)
+                .map_err(|_| ExportError::Malformed),
+            None => Export::try_from(value).map(ExportFile::Plain),
+        }
+    }
+
+    /// Returns the contained export, decrypting it if needed.
+    pub fn open(self, passphrase: Option<&str>) -> Result<Export, ExportError> {
+        match self {
+            ExportFile::Plain(export) => Ok(export),
+            ExportFile::Encrypted(encrypted) => {
+                encrypted.decrypt(passphrase.ok_or(ExportError::PassphraseRequired)?)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    fn example_export() -> Export {
+        Export {
+            format_version: FORMAT_VERSION,
+            codes: vec![ExportedCode {
+                content: "GK6ZFMqk18fuWnCw".into(),
+                display_name: "Google".into(),
+                website_url: Some("google.com".into()),
+                expires_at: None,
+                issuer: Some("Google".into()),
+                account: Some("alice@example.com".into()),
+                kind: "totp".into(),
+                digits: 8,
+                period: 60,
+                algorithm: "SHA256".into(),
+                icon_url: None,
+                favorite: true,
+                folder: Some("Work".into()),
+                tags: vec!["email".into()],
+            }],
+        }
+    }
+
+    #[gtest]
+    fn encrypted_round_trip() {
+        let export = example_export();
+        let encrypted = export.encrypt("correct horse battery staple");
+
+        expect_that!(
+            encrypted.ciphertext,
+            not(contains_substring("GK6ZFMqk18fuWnCw"))
+        );
+        assert_that!(
+            encrypted.decrypt("correct horse battery staple"),
+            ok(eq(&export))
+        );
+    }
+
+    #[gtest]
+    fn wrong_passphrase() {
+        let encrypted = example_export().encrypt("correct horse battery staple");
+
+        assert_that!(
+            encrypted.decrypt("incorrect horse battery staple"),
+            err(eq(&ExportError::WrongPassphrase))
+        );
+    }
+
+    #[gtest]
+    fn unreasonable_kdf_parameters() {
+        let encrypted = example_export().encrypt("correct horse battery staple");
+        let mut memory_hungry = encrypted.clone();
+        memory_hungry.kdf.memory_cost = MAX_MEMORY_COST + 1;
+        let mut slow = encrypted;
+        slow.kdf.time_cost = MAX_TIME_COST + 1;
+
+        for encrypted in [memory_hungry, slow] {
+            expect_that!(
+                encrypted.decrypt("correct horse battery staple"),
+                err(eq(&ExportError::Malformed))
+            );
+        }
+    }
+
+    #[gtest]
+    fn encrypted_file_requires_passphrase() {
+        let file = ExportFile::Encrypted(example_export().encrypt("hunter2"));
+
+        assert_that!(file.open(None), err(eq(&ExportError::PassphraseRequired)));
+    }
+
+    #[gtest]
+    fn plain_file_ignores_passphrase() {
+        let file = ExportFile::Plain(example_export());
+
+        assert_that!(file.open(Some("hunter2")), ok(eq(&example_export())));
+    }
+
+    #[gtest]
+    fn plain_round_trip() {
+        let export = example_export();
+        let json = serde_json::to_value(&export).unwrap();
+
+        expect_that!(json["format_version"], eq(&json!(FORMAT_VERSION)));
+        assert_that!(Export::try_from(json), ok(eq(&export)));
+    }
+
+    #[gtest]
+    fn version_1_export() {
+        let file = json!({
+            "codes": [{
+                "content": "GK6ZFMqk18fuWnCw",
+                "display_name": "Google",
+                "website_url": "google.com",
+                "expires_at": null,
+            }],
+        });
+
+        let export = ExportFile::from_value(file).unwrap().open(None).unwrap();
+
+        expect_that!(export.format_version, eq(FORMAT_VERSION));
+        expect_that!(
+            export.codes,
+            elements_are![eq(&ExportedCode {
+                content: "GK6ZFMqk18fuWnCw".into(),
+                display_name: "Google".into(),
+                website_url: Some("google.com".into()),
+                expires_at: None,
+                issuer: None,
+                account: None,
+                kind: "totp".into(),
+                digits: totp::DIGITS as i64,
+                period: totp::PERIOD,
+                algorithm: "SHA1".into(),
+                icon_url: None,
+                favorite: false,
+                folder: None,
+                tags: vec![],
+            })]
+        );
+    }
+
+    #[gtest]
+    fn newer_version() {
+        let mut json = serde_json::to_value(example_export()).unwrap();
+        json["format_version"] = json!(FORMAT_VERSION + 1);
+
+        expect_that!(
+            ExportFile::from_value(json.clone()),
+            err(eq(&ExportError::UnsupportedVersion))
+        );
+        expect_that!(
+            Export::try_from(json),
+            err(eq(&ExportError::UnsupportedVersion))
+        );
+    }
+
+    #[gtest]
+    fn unsupported_params() {
+        let mut json = serde_json::to_value(example_export()).unwrap();
+        json["codes"][0]["algorithm"] = json!("MD5");
+
+        expect_that!(
+            Export::try_from(json.clone()),
+            err(eq(&ExportError::Malformed))
+        );
+
+        json["codes"][0]["algorithm"] = json!("SHA1");
+        json["codes"][0]["kind"] = json!("hotp");
+        expect_that!(Export::try_from(json), err(eq(&ExportError::Malformed)));
+    }
+
+    #[gtest]
+    fn malformed_version() {
+        expect_that!(
+            Export::try_from(json!([])),
+            err(eq(&ExportError::Malformed))
+        );
+        for version in [json!(0), json!("2"), json!(-1)] {
+            let mut json = serde_json::to_value(example_export()).unwrap();
+            json["format_version"] = version;
+
+            expect_that!(Export::try_from(json), err(eq(&ExportError::Malformed)));
+        }
+    }
+}
@@ -1,81 +1,740 @@
-use crate::utils;
-use reqwest::header;
+use crate::{
+    models::{codes::Code, icons::Icon},
+    ssrf, utils,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::{header, redirect, ClientBuilder, Url};
+use sqlx::SqlitePool;
 use std::{
     io::{Cursor, ErrorKind},
     path::PathBuf,
+    time::Duration,
 };
 use tokio::io::copy;
-use tracing::debug;
+use tracing::{debug, warn};
 
 #[derive(Debug, Clone)]
 pub struct IconStore {
     base: PathBuf,
+    /// Fetch favicons from `{upstream}/{domain}/favicon.ico` instead of the website itself.
+    upstream: Option<String>,
+    /// Disables the SSRF guard, allowing requests to loopback and private addresses.
+    allow_private_networks: bool,
+    /// Proxy icons are fetched through. The SSRF guard still resolves and checks every host.
+    proxy: Option<reqwest::Proxy>,
+    /// Longest a single request to a website may take, including its body.
+    timeout: Duration,
 }
 
+/// Largest icon accepted from websites, in bytes.
+pub const MAX_ICON_SIZE: usize = 1024 * 1024;
+/// Bytes of a page read while looking for the icons it links to.
+const MAX_PAGE_SIZE: usize = 256 * 1024;
+/// Redirects followed before giving up on a URL.
+const MAX_REDIRECTS: usize = 5;
+/// Icons linked from a page which are tried, before falling back to `/favicon.ico`.
+const MAX_ICON_LINKS: usize = 3;
+/// Longest requests to websites may take by default. Icons are fetched one at a time, so slow
+/// websites would hold up the icons of everyone else.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug)]
 pub enum IconStoreError {
     FileSystemFailToWrite,
     UnableToSendRequest,
     UnableToParseResponse,
+    BlockedHost,
+    /// The response is larger than [`MAX_ICON_SIZE`].
+    TooLarge,
+    /// The response isn't an image.
+    NotAnImage,
+    /// Unable to record the icon, or link a code to it.
+    Database(sqlx::Error),
+}
+
+impl From<ssrf::GuardError> for IconStoreError {
+    fn from(err: ssrf::GuardError) -> Self {
+        match err {
+            ssrf::GuardError::Blocked => IconStoreError::BlockedHost,
+            ssrf::GuardError::Unresolvable => IconStoreError::UnableToSendRequest,
+        }
+    }
 }
 
 impl IconStore {
     pub fn new() -> Self {
+        IconStore::new_with_custom_base(
+            std::env::temp_dir().join("iceblink-".to_string() + &utils::generate_id(5)),
+        )
+    }
+
+    pub fn new_with_custom_base(base: PathBuf) -> Self {
         IconStore {
-            base: std::env::temp_dir().join("iceblink-".to_string() + &utils::generate_id(5)),
+            base,
+            upstream: None,
+            allow_private_networks: false,
+            proxy: None,
+            timeout: FETCH_TIMEOUT,
         }
     }
 
-    pub fn new_with_custom_base(base: PathBuf) -> Self {
-        IconStore { base }
+    pub fn with_upstream(mut self, upstream: String) -> Self {
+        self.upstream = Some(upstream);
+        self
+    }
+
+    pub fn allow_private_networks(mut self, allow: bool) -> Self {
+        self.allow_private_networks = allow;
+        self
+    }
+
+    pub fn with_proxy(mut self, proxy: reqwest::Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn get_path(&self, hash: &str) -> PathBuf {
+        self.base.join(hash)
     }
 
-    fn get_path(&self, domain: &str) -> PathBuf {
-        self.base
-            .join(PathBuf::from(utils::hash_domain(domain) + ".ico"))
+    fn favicon_url(&self, domain: &str) -> String {
+        match &self.upstream {
+            Some(upstream) => format!("{upstream}/{domain}/favicon.ico"),
+            None => format!("https://{domain}/favicon.ico"),
+        }
     }
 
     pub async fn init(&self) -> Result<&Self, IconStoreError> {
-        match tokio::fs::create_dir(&self.base).await {
+        match tokio::fs::create_dir_all(&self.base).await {
             Ok(_) => Ok(self),
             Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(self),
             Err(_) => Err(IconStoreError::FileSystemFailToWrite),
         }
     }
 
-    pub async fn find_or_gather(&self, domain: &str) -> Result<Vec<u8>, IconStoreError> {
-        match tokio::fs::read(self.get_path(domain)).await {
-            Ok(content) => Ok(content),
-            Err(_) => self.gather(domain).await,
+    /// Makes sure icons can be cached, and the upstream (if any) is reachable.
+    pub async fn check(&self, timeout: Duration) -> Result<(), IconStoreError> {
+        self.init().await?;
+        let probe = self.base.join(".probe");
+        tokio::fs::write(&probe, b"")
+            .await
+            .map_err(|_| IconStoreError::FileSystemFailToWrite)?;
+        let _ = tokio::fs::remove_file(&probe).await;
+
+        if let Some(upstream) = &self.upstream {
+            utils::client_builder(self.proxy.as_ref())
+                .user_agent(utils::USER_AGENT)
+                .timeout(timeout)
+                .build()
+                .unwrap()
+                .head(upstream)
+                .send()
+                .await
+                .map_err(|_| IconStoreError::UnableToSendRequest)?;
         }
+
+        Ok(())
     }
 
-    pub async fn gather(&self, domain: &str) -> Result<Vec<u8>, IconStoreError> {
-        debug!("Gathering icon for {}", domain);
-        let client = reqwest::Client::builder()
-            .user_agent(utils::USER_AGENT)
-            .build()
-            .unwrap();
+    /// Stores the bytes of an icon under its hash, unless they're stored already.
+    pub async fn put(&self, hash: &str, icon: &[u8]) -> Result<(), IconStoreError> {
+        let path = self.get_path(hash);
+        if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            return Ok(());
+        }
 
-        let req = client
-            .get(format!("https://{domain}/favicon.ico"))
-            .header(header::CONTENT_TYPE, "image/x-icon")
-            .send()
+        // Written aside first, so icons are never served partially written
+        let partial = self.base.join(format!(".{hash}-{}", utils::generate_id(8)));
+        let mut file = tokio::fs::File::create(&partial)
+            .await
+            .map_err(|_| IconStoreError::FileSystemFailToWrite)?;
+        let mut content = Cursor::new(icon);
+        copy(&mut content, &mut file)
             .await
-            .map_err(|_| IconStoreError::UnableToSendRequest)?
-            .bytes()
+            .map_err(|_| IconStoreError::FileSystemFailToWrite)?;
+        tokio::fs::rename(&partial, &path)
             .await
-            .map_err(|_| IconStoreError::UnableToParseResponse)?;
+            .map_err(|_| IconStoreError::FileSystemFailToWrite)
+    }
+
+    /// The bytes of the icon stored under the hash.
+    pub async fn get(&self, hash: &str) -> Option<Vec<u8>> {
+        tokio::fs::read(self.get_path(hash)).await.ok()
+    }
+
+    pub async fn remove(&self, hash: &str) {
+        if let Err(err) = tokio::fs::remove_file(self.get_path(hash)).await {
+            if err.kind() != ErrorKind::NotFound {
+                warn!("Unable to remove the icon {hash}: {err}");
+            }
+        }
+    }
+
+    /// Pins the client to the address the host of the URL resolves to, unless it is internal.
+    async fn guard(
+        &self,
+        url: &Url,
+        client: ClientBuilder,
+    ) -> Result<ClientBuilder, IconStoreError> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(IconStoreError::BlockedHost);
+        }
 
-        let mut file = tokio::fs::File::create(self.get_path(domain))
+        if self.allow_private_networks {
+            return Ok(client);
+        }
+
+        Ok(ssrf::pin(client, url).await?)
+    }
+
+    /// Sends a GET request for the URL, guarding against internal hosts. Redirects are followed
+    /// here rather than by reqwest, so every hop is guarded. Fails on responses which aren't
+    /// successful.
+    async fn send(&self, mut url: Url, accept: &str) -> Result<reqwest::Response, IconStoreError> {
+        for _ in 0..=MAX_REDIRECTS {
+            let client = utils::client_builder(self.proxy.as_ref())
+                .user_agent(utils::USER_AGENT)
+                .timeout(self.timeout)
+                .connect_timeout(CONNECT_TIMEOUT.min(self.timeout))
+                .redirect(redirect::Policy::none());
+
+            let response = self
+                .guard(&url, client)
+                .await?
+                .build()
+                .unwrap()
+                .get(url.clone())
+                .header(header::ACCEPT, accept)
+                .send()
+                .await
+                .map_err(|_| IconStoreError::UnableToSendRequest)?;
+
+            if !response.status().is_redirection() {
+                return response
+                    .error_for_status()
+                    .map_err(|_| IconStoreError::UnableToSendRequest);
+            }
+            url = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| url.join(location).ok())
+                .ok_or(IconStoreError::UnableToSendRequest)?;
+        }
+
+        Err(IconStoreError::UnableToSendRequest)
+    }
+
+    /// Downloads the URL, guarding against internal hosts, and responses over [`MAX_ICON_SIZE`].
+    /// Returns the content type of the response alongside its body.
+    async fn fetch(&self, url: Url) -> Result<(Option<String>, Vec<u8>), IconStoreError> {
+        let mut response = self.send(url, "image/*").await?;
+
+        if response
+            .content_length()
+            .is_some_and(|length| length > MAX_ICON_SIZE as u64)
+        {
+            return Err(IconStoreError::TooLarge);
+        }
+
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        let mut body = vec![];
+        while let Some(chunk) = response
+            .chunk()
             .await
-            .map_err(|_| IconStoreError::FileSystemFailToWrite)?;
+            .map_err(|_| IconStoreError::UnableToParseResponse)?
+        {
+            if body.len() + chunk.len() > MAX_ICON_SIZE {
+                return Err(IconStoreError::TooLarge);
+            }
+            body.extend_from_slice(&chunk);
+        }
 
-        let mut content = Cursor::new(&req);
-        copy(&mut content, &mut file)
+        Ok((content_type, body))
+    }
+
+    /// Downloads the start of the page, up to [`MAX_PAGE_SIZE`], which is enough to find the links
+    /// in its head. Returns the URL the page ended up at after redirects alongside it.
+    async fn fetch_page(&self, url: Url) -> Result<(Url, String), IconStoreError> {
+        let mut response = self.send(url, "text/html").await?;
+        let url = response.url().clone();
+
+        let mut body = vec![];
+        while let Some(chunk) = response
+            .chunk()
             .await
-            .map_err(|_| IconStoreError::FileSystemFailToWrite)?;
+            .map_err(|_| IconStoreError::UnableToParseResponse)?
+        {
+            body.extend_from_slice(&chunk);
+            if body.len() >= MAX_PAGE_SIZE {
+                body.truncate(MAX_PAGE_SIZE);
+                break;
+            }
+        }
+
+        Ok((url, String::from_utf8_lossy(&body).into_owned()))
+    }
+
+    /// Downloads an icon of the website, trying the icons linked from its page before
+    /// `/favicon.ico`. Responses which aren't images are skipped. Returns the URL of the icon and
+    /// its content type alongside it.
+    async fn discover(
+        &self,
+        website: &str,
+    ) -> Result<(Url, Option<String>, Vec<u8>), IconStoreError> {
+        let site = site_url(website).ok_or(IconStoreError::BlockedHost)?;
+        let (page, html) = match self.fetch_page(site.clone()).await {
+            Ok(page) => page,
+            // Sites without a usable page may still have a favicon
+            Err(IconStoreError::BlockedHost) => return Err(IconStoreError::BlockedHost),
+            Err(err) => {
+                debug!("Unable to fetch the page of {website}: {err:?}");
+                (site, String::new())
+            }
+        };
+
+        let mut candidates = icon_links(&html, &page);
+        candidates.truncate(MAX_ICON_LINKS);
+        if let Ok(fallback) = page.join("/favicon.ico") {
+            if !candidates.contains(&fallback) {
+                candidates.push(fallback);
+            }
+        }
+
+        let mut last_err = IconStoreError::NotAnImage;
+        for candidate in candidates {
+            match self.fetch(candidate.clone()).await {
+                Ok((content_type, icon))
+                    if content_type
+                        .as_deref()
+                        .is_some_and(|c| c.starts_with("image/"))
+                        || sniff_content_type(&icon).is_some() =>
+                {
+                    return Ok((candidate, content_type, icon));
+                }
+                Ok(_) => last_err = IconStoreError::NotAnImage,
+                Err(err) => last_err = err,
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// Downloads the icon of the website, without storing it.
+    pub async fn gather(&self, website: &str) -> Result<GatheredIcon, IconStoreError> {
+        debug!("Gathering icon for {}", website);
+        let (source, content_type, icon) = match &self.upstream {
+            Some(_) => {
+                let url = Url::parse(&self.favicon_url(website))
+                    .map_err(|_| IconStoreError::BlockedHost)?;
+                let (content_type, icon) = self.fetch(url.clone()).await?;
+                (url, content_type, icon)
+            }
+            None => self.discover(website).await?,
+        };
+
+        Ok(GatheredIcon {
+            source: source.into(),
+            content_type: icon_content_type(&icon, content_type.as_deref()),
+            icon,
+        })
+    }
+
+    /// Fetches an image from any public URL, without caching it.
+    /// Returns its content type and contents.
+    pub async fn preview(&self, url: &str) -> Result<(String, Vec<u8>), IconStoreError> {
+        debug!("Previewing icon at {}", url);
+        let url = Url::parse(url).map_err(|_| IconStoreError::BlockedHost)?;
+
+        match self.fetch(url).await? {
+            (Some(content_type), image) if content_type.starts_with("image/") => {
+                Ok((content_type, image))
+            }
+            _ => Err(IconStoreError::NotAnImage),
+        }
+    }
+}
+
+/// Icon downloaded from a website.
+#[derive(Debug)]
+pub struct GatheredIcon {
+    /// URL the icon was found at.
+    pub source: String,
+    pub content_type: String,
+    pub icon: Vec<u8>,
+}
+
+/// URL of the page of the website of a code, which is either a full URL or just a domain.
+fn site_url(website: &str) -> Option<Url> {
+    match Url::parse(website) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Some(url),
+        _ => Url::parse(&format!("https://{website}")).ok(),
+    }
+}
+
+/// Icons linked from the head of the page, resolved against its URL. Ordered by preference, so
+/// regular icons come before touch icons, and larger ones before smaller ones.
+fn icon_links(html: &str, page: &Url) -> Vec<Url> {
+    let lowercase = html.to_ascii_lowercase();
+    let head = lowercase
+        .find("</head")
+        .or_else(|| lowercase.find("<body"))
+        .unwrap_or(lowercase.len());
+
+    // Whether the link is a regular icon, its largest size and its URL
+    let mut links: Vec<(bool, u32, Url)> = vec![];
+    let mut rest = 0;
+    while let Some(start) = lowercase[rest..head].find("<link") {
+        let start = rest + start + "<link".len();
+        let end = lowercase[start..head]
+            .find('>')
+            .map_or(head, |end| start + end);
+        rest = end;
+
+        let attributes = parse_attributes(&html[start..end]);
+        let attribute = |name: &str| {
+            attributes
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        let rel = attribute("rel").unwrap_or_default().to_ascii_lowercase();
+        // Leaves out mask icons, which are single color outlines meant to be tinted
+        let rel: Vec<&str> = rel.split_ascii_whitespace().collect();
+        let touch = rel.contains(&"apple-touch-icon");
+        if !(rel.contains(&"icon") || touch) {
+            continue;
+        }
+        let Some(url) = attribute("href").and_then(|href| page.join(href.trim()).ok()) else {
+            continue;
+        };
+        let size = attribute("sizes")
+            .map(|sizes| {
+                sizes
+                    .split_ascii_whitespace()
+                    .filter_map(|size| match size {
+                        "any" => Some(u32::MAX),
+                        _ => size.split_once(['x', 'X'])?.0.parse().ok(),
+                    })
+                    .max()
+                    .unwrap_or(0)
+            })
+            .unwrap_or(0);
+
+        if !links.iter().any(|(_, _, link)| *link == url) {
+            links.push((!touch, size, url));
+        }
+    }
+
+    // Stable, so links of the same rank keep the order of the page
+    links.sort_by(|(a_icon, a_size, _), (b_icon, b_size, _)| {
+        b_icon.cmp(a_icon).then(b_size.cmp(a_size))
+    });
+    links.into_iter().map(|(_, _, url)| url).collect()
+}
+
+/// Attributes of an HTML tag, with lowercase names and the common entities in values decoded.
+fn parse_attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attributes = vec![];
+    let mut rest = tag.trim_start();
+
+    loop {
+        let end = rest
+            .find(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or(rest.len());
+        let name = rest[..end].trim_matches('/');
+        rest = rest[end..].trim_start();
+        if name.is_empty() && rest.is_empty() {
+            break;
+        }
+
+        let mut value = "";
+        if let Some(after) = rest.strip_prefix('=') {
+            let after = after.trim_start();
+            let (quoted, end) = match after.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let end = after[1..].find(quote).map_or(after.len(), |end| end + 1);
+                    (&after[1..end], (end + 1).min(after.len()))
+                }
+                _ => {
+                    let end = after.find(char::is_whitespace).unwrap_or(after.len());
+                    (&after[..end], end)
+                }
+            };
+            value = quoted;
+            rest = after[end..].trim_start();
+        }
+
+        if !name.is_empty() {
+            attributes.push((
+                name.to_ascii_lowercase(),
+                value
+                    .replace("&quot;", "\"")
+                    .replace("&#39;", "'")
+                    .replace("&lt;", "<")
+                    .replace("&gt;", ">")
+                    .replace("&amp;", "&"),
+            ));
+        }
+    }
+
+    attributes
+}
+
+/// Content type to serve the icon with: the one judged by its bytes, or else the declared one if
+/// it is an image.
+fn icon_content_type(icon: &[u8], declared: Option<&str>) -> String {
+    sniff_content_type(icon)
+        .or(declared.filter(|content_type| content_type.starts_with("image/")))
+        .unwrap_or("image/x-icon")
+        .to_string()
+}
+
+/// The content type and bytes of an icon embedded as a `data:` URI.
+fn embedded_icon(icon_url: &str) -> Option<(&str, Vec<u8>)> {
+    let (content_type, data) = icon_url.strip_prefix("data:")?.split_once(";base64,")?;
+    Some((content_type, STANDARD.decode(data).ok()?))
+}
+
+/// Content type of an icon, judged by its first bytes. Websites often serve icons as
+/// `application/octet-stream`, or pages as `/favicon.ico`.
+pub fn sniff_content_type(icon: &[u8]) -> Option<&'static str> {
+    if icon.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if icon.starts_with(b"\xff\xd8\xff") {
+        Some("image/jpeg")
+    } else if icon.starts_with(b"GIF87a") || icon.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if icon.len() >= 12 && icon.starts_with(b"RIFF") && &icon[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else if icon.starts_with(b"\0\0\x01\0") {
+        Some("image/x-icon")
+    } else {
+        let start = String::from_utf8_lossy(&icon[..icon.len().min(256)]).to_ascii_lowercase();
+        let start = start.trim_start_matches('\u{feff}').trim_start();
+        (start.starts_with("<svg") || (start.starts_with("<?xml") && start.contains("<svg")))
+            .then_some("image/svg+xml")
+    }
+}
+
+/// Icons no code is linked to are kept this long after they were last stored, so they aren't
+/// removed between being stored and linked.
+pub const UNUSED_ICON_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// Stores the icon once by the hash of its bytes, and records it. Returns the hash.
+pub async fn store_icon(
+    pool: &SqlitePool,
+    store: &IconStore,
+    icon: &[u8],
+    content_type: &str,
+) -> Result<String, IconStoreError> {
+    let hash = utils::content_hash(icon);
+    // Recorded first, so pruning doesn't remove the bytes while they're stored
+    Icon::record(
+        pool,
+        &hash,
+        content_type,
+        icon.len() as i64,
+        chrono::Utc::now().timestamp(),
+    )
+    .await
+    .map_err(IconStoreError::Database)?;
+    store.put(&hash, icon).await?;
+
+    Ok(hash)
+}
+
+/// Stores the icon of the code, and links the code to it, so it is served at
+/// `GET /v1/code/{id}/icon`. Icons embedded as `data:` URIs are decoded, and other icon URLs
+/// fetched. Codes without an icon get the favicon of their website, whose URL becomes their icon.
+/// Codes linked already are left alone, as are codes changed meanwhile. Returns whether the code
+/// was linked.
+pub async fn populate_icon(
+    pool: &SqlitePool,
+    store: &IconStore,
+    code: &mut Code,
+) -> Result<bool, IconStoreError> {
+    if code.icon_url.is_none() && code.website_url.is_none() {
+        return Ok(false);
+    }
+    if Icon::of_code(pool, &code.id)
+        .await
+        .map_err(IconStoreError::Database)?
+        .is_some()
+    {
+        return Ok(false);
+    }
+
+    let (content_type, icon) = match (code.icon_url.clone(), code.website_url.clone()) {
+        (Some(url), _) if url.starts_with("data:") => {
+            let (declared, icon) = embedded_icon(&url).ok_or(IconStoreError::NotAnImage)?;
+            if icon.len() > MAX_ICON_SIZE {
+                return Err(IconStoreError::TooLarge);
+            }
+            (icon_content_type(&icon, Some(declared)), icon)
+        }
+        (Some(url), _) => {
+            let (declared, icon) = store.preview(&url).await?;
+            (icon_content_type(&icon, Some(&declared)), icon)
+        }
+        (None, Some(website)) => {
+            let gathered = store.gather(&website).await?;
+            let expected_revision = code.revision;
+            match code
+                .edit()
+                .pool(pool)
+                .icon_url(Some(gathered.source))
+                .expected_revision(expected_revision)
+                .call()
+                .await
+            {
+                Ok(_) => {}
+                Err(sqlx::Error::RowNotFound) => return Ok(false),
+                Err(err) => return Err(IconStoreError::Database(err)),
+            }
+            (gathered.content_type, gathered.icon)
+        }
+        (None, None) => return Ok(false),
+    };
+
+    let hash = store_icon(pool, store, &icon, &content_type).await?;
+    Icon::link(pool, &code.id, code.icon_url.as_deref(), &hash)
+        .await
+        .map_err(IconStoreError::Database)
+}
+
+/// Removes the icons no code has been linked to since `before`. Returns how many were removed.
+pub async fn prune_icons(
+    pool: &SqlitePool,
+    store: &IconStore,
+    before: i64,
+) -> Result<u64, sqlx::Error> {
+    let hashes = Icon::prune(pool, before).await?;
+    for hash in &hashes {
+        store.remove(hash).await;
+    }
+
+    Ok(hashes.len() as u64)
+}
+
+#[derive(Debug, Default)]
+pub struct IconRefreshReport {
+    pub refreshed: Vec<String>,
+    pub failed: Vec<(String, IconStoreError)>,
+}
+
+/// Fetches the icon of every website used by a code again, optionally only for a single user, and
+/// shows the codes which use the icon of their website with it. Waits `delay` between every fetch,
+/// as to not hammer upstreams.
+pub async fn refresh_icons(
+    pool: &SqlitePool,
+    store: &IconStore,
+    owner_id: Option<String>,
+    delay: Duration,
+) -> Result<IconRefreshReport, sqlx::Error> {
+    let domains = Code::website_urls(pool, owner_id.clone()).await?;
+    let mut report = IconRefreshReport::default();
+
+    for (i, domain) in domains.into_iter().enumerate() {
+        if i != 0 {
+            tokio::time::sleep(delay).await;
+        }
+
+        match refresh_website(pool, store, &domain, owner_id.as_deref()).await {
+            Ok(()) => report.refreshed.push(domain),
+            Err(err) => report.failed.push((domain, err)),
+        }
+    }
+
+    Ok(report)
+}
+
+async fn refresh_website(
+    pool: &SqlitePool,
+    store: &IconStore,
+    website: &str,
+    owner_id: Option<&str>,
+) -> Result<(), IconStoreError> {
+    let gathered = store.gather(website).await?;
+    let hash = store_icon(pool, store, &gathered.icon, &gathered.content_type).await?;
+    Icon::link_website(pool, website, &gathered.source, owner_id, &hash)
+        .await
+        .map_err(IconStoreError::Database)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    #[gtest]
+    fn icon_links_by_preference() {
+        let page = Url::parse("https://example.com/login/").unwrap();
+        let html = r#"<!DOCTYPE html><html><head>
+            <link rel="stylesheet" href="/style.css">
+            <LINK REL="apple-touch-icon" HREF="/touch.png" sizes="180x180">
+            <link rel=icon href=small.png sizes=16x16>
+            <link rel="mask-icon" href="/mask.svg">
+            <link href='/large.png?v=1&amp;b=2' rel='shortcut icon' sizes="32x32 64x64" />
+            <link rel="icon" href="/small.png">
+            </head><body><link rel="icon" href="/body.png"></body></html>"#;
+
+        expect_that!(
+            icon_links(html, &page)
+                .iter()
+                .map(Url::as_str)
+                .collect::<Vec<_>>(),
+            elements_are![
+                eq("https://example.com/large.png?v=1&b=2"),
+                eq("https://example.com/login/small.png"),
+                eq("https://example.com/small.png"),
+                eq("https://example.com/touch.png"),
+            ]
+        );
+        expect_that!(icon_links("<html><body></body></html>", &page), empty());
+    }
+
+    #[gtest]
+    fn site_urls() {
+        expect_that!(
+            site_url("github.com").map(String::from),
+            some(eq("https://github.com/"))
+        );
+        expect_that!(
+            site_url("http://intranet.example.com/app").map(String::from),
+            some(eq("http://intranet.example.com/app"))
+        );
+    }
 
-        Ok(req.to_vec())
+    #[gtest]
+    fn sniffed_content_types() {
+        expect_that!(
+            sniff_content_type(b"\x89PNG\r\n\x1a\n..."),
+            some(eq("image/png"))
+        );
+        expect_that!(
+            sniff_content_type(b"\0\0\x01\0\x01\0"),
+            some(eq("image/x-icon"))
+        );
+        expect_that!(
+            sniff_content_type(
+                b"<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\">"
+            ),
+            some(eq("image/svg+xml"))
+        );
+        expect_that!(sniff_content_type(b"<!DOCTYPE html><html>"), none());
     }
 }
@@ -0,0 +1,667 @@
+use super::{Backup, ForeignCode};
+use crate::{
+    export::ExportError,
+    models::{codes::Code, folders::Folder},
+    otpauth,
+    totp::{self, Algorithm, Kind},
+};
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Slot type of a key encrypted with a password, as opposed to biometrics or raw keys.
+const PASSWORD_SLOT: u8 = 1;
+/// Highest scrypt memory use (in bytes) accepted when decrypting, to keep imports from exhausting
+/// memory. Aegis itself uses N = 2^15 and r = 8, which is 32 MiB.
+const MAX_SCRYPT_MEMORY: u64 = 32 * 1024 * 1024;
+/// Password slots tried at most when decrypting, as every one of them costs a key derivation.
+const MAX_PASSWORD_SLOTS: usize = 4;
+/// Longest base64 encoded icon kept when importing. Larger icons are dropped, leaving the code
+/// to the icon of its website.
+const MAX_ICON_LENGTH: usize = 256 * 1024;
+/// Icon types Aegis can display. Others, like the `.ico` files of websites, are left out of exports.
+const ICON_TYPES: [&str; 3] = ["image/png", "image/jpeg", "image/svg+xml"];
+/// Versions of the vault file and database written by exports.
+const VAULT_VERSION: u32 = 1;
+const DB_VERSION: u32 = 3;
+/// Scrypt cost of the password slot of exported vaults, matching Aegis itself.
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+/// A vault exported by the Aegis Authenticator app, plaintext or encrypted.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AegisVault {
+    #[serde(default = "vault_version")]
+    version: u32,
+    header: AegisHeader,
+    /// The database itself when plaintext, or its base64 encoded ciphertext when encrypted.
+    db: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct AegisHeader {
+    slots: Option<Vec<AegisSlot>>,
+    params: Option<AegisParams>,
+}
+
+/// A copy of the master key, encrypted with a key derived from a password.
+#[derive(Serialize, Deserialize, Debug)]
+struct AegisSlot {
+    #[serde(rename = "type")]
+    kind: u8,
+    uuid: Option<String>,
+    /// Hex encoded ciphertext of the master key.
+    key: String,
+    key_params: AegisParams,
+    n: Option<u64>,
+    r: Option<u32>,
+    p: Option<u32>,
+    /// Hex encoded salt.
+    salt: Option<String>,
+}
+
+/// Hex encoded AES-GCM nonce and authentication tag.
+#[derive(Serialize, Deserialize, Debug)]
+struct AegisParams {
+    nonce: String,
+    tag: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AegisDb {
+    #[serde(default = "db_version")]
+    pub version: u32,
+    pub entries: Vec<AegisEntry>,
+    /// Groups entries can be put in. Only present since version 3 of the database.
+    #[serde(default)]
+    pub groups: Vec<AegisGroup>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AegisGroup {
+    pub uuid: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AegisEntry {
+    /// `totp`, `hotp`, `steam`, ...
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub uuid: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub issuer: String,
+    #[serde(default)]
+    pub note: String,
+    #[serde(default)]
+    pub favorite: bool,
+    /// Base64 encoded image, shown in place of the icon of the website.
+    pub icon: Option<String>,
+    pub icon_mime: Option<String>,
+    /// UUIDs of the groups the entry is in.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Name of the group the entry is in, as stored before version 3 of the database.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    pub info: AegisEntryInfo,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AegisEntryInfo {
+    /// Base32 encoded secret.
+    pub secret: String,
+    #[serde(default = "default_algorithm")]
+    pub algo: String,
+    #[serde(default = "default_digits")]
+    pub digits: u32,
+    #[serde(default = "default_period")]
+    pub period: i64,
+}
+
+fn vault_version() -> u32 {
+    VAULT_VERSION
+}
+
+fn db_version() -> u32 {
+    DB_VERSION
+}
+
+fn default_algorithm() -> String {
+    Algorithm::default().as_str().into()
+}
+
+fn default_digits() -> u32 {
+    totp::DIGITS
+}
+
+fn default_period() -> i64 {
+    totp::PERIOD
+}
+
+impl AegisEntry {
+    /// Parameters of the code, or `None` if Iceblink can't generate it, like HOTP or MD5 codes.
+    pub fn params(&self) -> Option<totp::Params> {
+        if self.kind == "steam" {
+            return Some(totp::Params::STEAM);
+        }
+        if self.kind != "totp"
+            || !otpauth::DIGITS.contains(&self.info.digits)
+            || !otpauth::PERIODS.contains(&self.info.period)
+        {
+            return None;
+        }
+
+        Some(totp::Params {
+            kind: Kind::Totp,
+            algorithm: Algorithm::parse(&self.info.algo)?,
+            digits: self.info.digits,
+            period: self.info.period,
+        })
+    }
+
+    /// The icon of the entry as a `data:` URI, if it has a reasonably sized image.
+    pub fn icon_url(&self) -> Option<String> {
+        let icon = self.icon.as_deref().filter(|icon| !icon.is_empty())?;
+        let mime = self.icon_mime.as_deref().unwrap_or("image/png");
+        if icon.len() > MAX_ICON_LENGTH || !mime.starts_with("image/") {
+            return None;
+        }
+        STANDARD.decode(icon).ok()?;
+
+        Some(format!("data:{mime};base64,{icon}"))
+    }
+
+    /// Entry of an exported vault, in the group with the given UUID.
+    fn from_code(code: Code, group: Option<String>) -> Self {
+        // Only icons imported from Aegis, or set as `data:` URIs, can be embedded
+        let (icon_mime, icon) = code
+            .icon_url
+            .as_deref()
+            .and_then(|url| url.strip_prefix("data:"))
+            .and_then(|data| data.split_once(";base64,"))
+            .filter(|(mime, _)| ICON_TYPES.contains(mime))
+            .map(|(mime, icon)| (Some(mime.to_string()), Some(icon.to_string())))
+            .unwrap_or_default();
+
+        AegisEntry {
+            kind: code.kind,
+            uuid: Some(random_uuid()),
+            name: code.account.unwrap_or(code.display_name),
+            issuer: code.issuer.or(code.website_url).unwrap_or_default(),
+            note: String::new(),
+            favorite: code.favorite,
+            icon,
+            icon_mime,
+            groups: group.into_iter().collect(),
+            group: None,
+            info: AegisEntryInfo {
+                secret: code.content,
+                algo: code.algorithm,
+                digits: code.digits as u32,
+                period: code.period,
+            },
+        }
+    }
+}
+
+impl AegisDb {
+    /// Database of the given codes, with a group for every folder.
+    pub fn new(codes: Vec<Code>, folders: Vec<Folder>) -> Self {
+        let uuids: HashMap<String, String> = folders
+            .iter()
+            .map(|folder| (folder.id.clone(), random_uuid()))
+            .collect();
+        let groups = folders
+            .into_iter()
+            .map(|folder| AegisGroup {
+                uuid: uuids[&folder.id].clone(),
+                name: folder.name,
+            })
+            .collect();
+
+        let entries = codes
+            .into_iter()
+            .map(|code| {
+                let group = code
+                    .folder_id
+                    .as_ref()
+                    .and_then(|id| uuids.get(id))
+                    .cloned();
+                AegisEntry::from_code(code, group)
+            })
+            .collect();
+
+        AegisDb {
+            version: DB_VERSION,
+            entries,
+            groups,
+        }
+    }
+
+    /// Name of the first group the entry is in.
+    pub fn group_of<'a>(&'a self, entry: &'a AegisEntry) -> Option<&'a str> {
+        let name = match entry.groups.first() {
+            Some(uuid) => self
+                .groups
+                .iter()
+                .find(|group| &group.uuid == uuid)
+                .map(|group| group.name.as_str()),
+            None => entry.group.as_deref(),
+        };
+        name.map(str::trim).filter(|name| !name.is_empty())
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, ExportError> {
+    base16ct::mixed::decode_vec(hex).map_err(|_| ExportError::Malformed)
+}
+
+/// Decrypts AES-GCM ciphertext whose tag is stored separately, as Aegis does.
+fn decrypt(key: &[u8], params: &AegisParams, ciphertext: &[u8]) -> Option<Vec<u8>> {
+    let nonce = decode_hex(&params.nonce).ok()?;
+    let tag = decode_hex(&params.tag).ok()?;
+    if key.len() != 32 || nonce.len() != 12 || tag.len() != 16 {
+        return None;
+    }
+
+    let payload = [ciphertext, &tag].concat();
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))
+        .decrypt(Nonce::from_slice(&nonce), payload.as_slice())
+        .ok()
+}
+
+/// Encrypts with AES-GCM, storing the tag separately from the ciphertext, as Aegis does.
+fn encrypt(key: &[u8], plaintext: &[u8]) -> (Vec<u8>, AegisParams) {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let mut ciphertext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))
+        .encrypt(&nonce, plaintext)
+        .expect("Unable to encrypt vault");
+    let tag = ciphertext.split_off(ciphertext.len() - 16);
+
+    let params = AegisParams {
+        nonce: base16ct::lower::encode_string(&nonce),
+        tag: base16ct::lower::encode_string(&tag),
+    };
+    (ciphertext, params)
+}
+
+/// A random version 4 UUID, which Aegis uses to identify entries, groups and slots.
+fn random_uuid() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = base16ct::lower::encode_string(&bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+impl AegisSlot {
+    /// A slot holding the master key, encrypted with a key derived from the password.
+    fn new(master_key: &[u8], password: &str) -> Self {
+        let mut salt = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        let params = scrypt::Params::new(SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P, 32)
+            .expect("Default scrypt parameters are valid");
+        let mut key = [0u8; 32];
+        scrypt::scrypt(password.as_bytes(), &salt, &params, &mut key)
+            .expect("Unable to derive the key of the vault");
+        let (ciphertext, key_params) = encrypt(&key, master_key);
+
+        AegisSlot {
+            kind: PASSWORD_SLOT,
+            uuid: Some(random_uuid()),
+            key: base16ct::lower::encode_string(&ciphertext),
+            key_params,
+            n: Some(1 << SCRYPT_LOG_N),
+            r: Some(SCRYPT_R),
+            p: Some(SCRYPT_P),
+            salt: Some(base16ct::lower::encode_string(&salt)),
+        }
+    }
+
+    /// Decrypts the master key, or returns `None` if the password doesn't belong to this slot.
+    fn master_key(&self, password: &str) -> Result<Option<Vec<u8>>, ExportError> {
+        let (Some(n), Some(r), Some(p), Some(salt)) = (self.n, self.r, self.p, &self.salt) else {
+            return Err(ExportError::Malformed);
+        };
+        let memory = 128u64
+            .saturating_mul(n)
+            .saturating_mul(r as u64)
+            .saturating_mul(p as u64);
+        if !n.is_power_of_two() || memory > MAX_SCRYPT_MEMORY {
+            return Err(ExportError::Malformed);
+        }
+
+        let params = scrypt::Params::new(n.trailing_zeros() as u8, r, p, 32)
+            .map_err(|_| ExportError::Malformed)?;
+        let mut key = [0u8; 32];
+        scrypt::scrypt(password.as_bytes(), &decode_hex(salt)?, &params, &mut key)
+            .map_err(|_| ExportError::Malformed)?;
+
+        Ok(decrypt(&key, &self.key_params, &decode_hex(&self.key)?))
+    }
+}
+
+impl AegisVault {
+    /// A vault of the database, encrypted when a password is given.
+    pub fn new(db: &AegisDb, password: Option<&str>) -> Self {
+        let Some(password) = password else {
+            return AegisVault {
+                version: VAULT_VERSION,
+                header: AegisHeader {
+                    slots: None,
+                    params: None,
+                },
+                db: serde_json::to_value(db).expect("Unable to serialize vault"),
+            };
+        };
+
+        let mut master_key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut master_key);
+        let plaintext = serde_json::to_vec(db).expect("Unable to serialize vault");
+        let (ciphertext, params) = encrypt(&master_key, &plaintext);
+
+        AegisVault {
+            version: VAULT_VERSION,
+            header: AegisHeader {
+                slots: Some(vec![AegisSlot::new(&master_key, password)]),
+                params: Some(params),
+            },
+            db: serde_json::Value::String(STANDARD.encode(ciphertext)),
+        }
+    }
+
+    /// Returns the database of the vault, decrypting it with the password if needed.
+    pub fn open(self, password: Option<&str>) -> Result<AegisDb, ExportError> {
+        let db = match self.db {
+            serde_json::Value::String(ciphertext) => {
+                let password = password.ok_or(ExportError::PassphraseRequired)?;
+                let params = self.header.params.ok_or(ExportError::Malformed)?;
+                let ciphertext = STANDARD
+                    .decode(ciphertext)
+                    .map_err(|_| ExportError::Malformed)?;
+
+                let mut master_key = None;
+                for slot in self
+                    .header
+                    .slots
+                    .iter()
+                    .flatten()
+                    .filter(|slot| slot.kind == PASSWORD_SLOT)
+                    .take(MAX_PASSWORD_SLOTS)
+                {
+                    if let Some(key) = slot.master_key(password)? {
+                        master_key = Some(key);
+                        break;
+                    }
+                }
+                let master_key = master_key.ok_or(ExportError::WrongPassphrase)?;
+
+                let plaintext =
+                    decrypt(&master_key, &params, &ciphertext).ok_or(ExportError::Malformed)?;
+                serde_json::from_slice(&plaintext).map_err(|_| ExportError::Malformed)?
+            }
+            db => serde_json::from_value::<AegisDb>(db).map_err(|_| ExportError::Malformed)?,
+        };
+
+        Ok(db)
+    }
+}
+
+/// Reads the TOTP entries of a vault, decrypting it with the password if needed.
+pub fn read(vault: AegisVault, password: Option<&str>) -> Result<Backup, ExportError> {
+    let db = vault.open(password)?;
+
+    let mut backup = Backup::default();
+    for entry in &db.entries {
+        let Some(params) = entry.params() else {
+            backup.skipped.push(entry.name.clone());
+            continue;
+        };
+
+        let issuer = Some(entry.issuer.clone()).filter(|issuer| !issuer.is_empty());
+        backup.codes.push(ForeignCode {
+            display_name: entry.name.clone(),
+            secret: entry.info.secret.clone(),
+            website_url: issuer.clone(),
+            issuer,
+            account: Some(entry.name.clone()),
+            icon_url: entry.icon_url(),
+            params,
+            favorite: entry.favorite,
+            group: db.group_of(entry).map(str::to_string),
+        });
+    }
+
+    Ok(backup)
+}
+
+/// Vault file of the codes, with a group for every folder. Encrypted when a password is given.
+pub fn write(codes: Vec<Code>, folders: Vec<Folder>, password: Option<&str>) -> String {
+    serde_json::to_string(&AegisVault::new(&AegisDb::new(codes, folders), password))
+        .expect("Unable to serialize vault")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    const PLAIN: &str = include_str!("../../tests/fixtures/aegis_plain.json");
+    const ENCRYPTED: &str = include_str!("../../tests/fixtures/aegis_encrypted.json");
+    const GROUPS: &str = include_str!("../../tests/fixtures/aegis_groups.json");
+
+    fn vault(json: &str) -> AegisVault {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn names(entries: &[AegisEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[gtest]
+    fn plain_vault() {
+        let entries = vault(PLAIN).open(None).unwrap().entries;
+
+        expect_that!(
+            names(&entries),
+            elements_are![eq("alice@example.com"), eq("bob")]
+        );
+        expect_that!(entries[0].issuer, eq("GitHub"));
+        expect_that!(entries[0].info.secret, eq("JBSWY3DPEHPK3PXP"));
+        expect_that!(entries[0].params(), some(eq(totp::Params::default())));
+        // HOTP codes can't be generated
+        expect_that!(entries[1].params(), none());
+    }
+
+    #[gtest]
+    fn groups_and_icons() {
+        let db = vault(GROUPS).open(None).unwrap();
+
+        assert_that!(db.entries.len(), eq(4));
+        expect_that!(db.group_of(&db.entries[0]), some(eq("Work")));
+        expect_that!(db.group_of(&db.entries[1]), none());
+        expect_that!(db.group_of(&db.entries[2]), some(eq("Legacy")));
+        expect_that!(
+            db.entries[0].icon_url(),
+            some(eq("data:image/svg+xml;base64,PHN2Zy8+"))
+        );
+        expect_that!(db.entries[1].icon_url(), none());
+        expect_that!(db.entries[0].favorite, is_true());
+        expect_that!(
+            db.entries[1].params(),
+            some(eq(totp::Params {
+                kind: Kind::Totp,
+                algorithm: Algorithm::Sha256,
+                digits: 8,
+                period: 60,
+            }))
+        );
+        // MD5 isn't supported
+        expect_that!(db.entries[3].params(), none());
+    }
+
+    #[gtest]
+    fn encrypted_vault() {
+        let entries = vault(ENCRYPTED).open(Some("test")).unwrap().entries;
+
+        expect_that!(
+            names(&entries),
+            elements_are![eq("alice@example.com"), eq("bob")]
+        );
+        expect_that!(entries[0].info.secret, eq("JBSWY3DPEHPK3PXP"));
+    }
+
+    #[gtest]
+    fn encrypted_vault_wrong_password() {
+        expect_that!(
+            vault(ENCRYPTED).open(Some("wrong")).map(|_| ()),
+            err(eq(&ExportError::WrongPassphrase))
+        );
+        expect_that!(
+            vault(ENCRYPTED).open(None).map(|_| ()),
+            err(eq(&ExportError::PassphraseRequired))
+        );
+    }
+
+    #[gtest]
+    fn unreasonable_scrypt_parameters() {
+        let mut vault = vault(ENCRYPTED);
+        vault.header.slots.as_mut().unwrap()[0].n = Some(1 << 30);
+
+        expect_that!(
+            vault.open(Some("test")).map(|_| ()),
+            err(eq(&ExportError::Malformed))
+        );
+    }
+
+    #[gtest]
+    fn password_slots_are_limited() {
+        let mut vault = vault(ENCRYPTED);
+        let slots = vault.header.slots.as_mut().unwrap();
+        let slot = serde_json::to_value(&slots[0]).unwrap();
+        // Slots of other passwords, whose keys don't decrypt
+        for salt in ["00", "01", "02", "03"] {
+            let mut decoy: AegisSlot = serde_json::from_value(slot.clone()).unwrap();
+            decoy.salt = Some(salt.into());
+            slots.insert(0, decoy);
+        }
+
+        expect_that!(
+            vault.open(Some("test")).map(|_| ()),
+            err(eq(&ExportError::WrongPassphrase))
+        );
+    }
+
+    fn example_codes() -> (Vec<Code>, Vec<Folder>) {
+        let folder = Folder {
+            id: "z8GJ2kdPq0w8Vn1c".into(),
+            owner_id: "k0d8WrkRjK6gkc3C".into(),
+            name: "Work".into(),
+            parent_id: None,
+        };
+        let code = Code {
+            id: "Ckpt4eFi1pw9fxI3".into(),
+            owner_id: "k0d8WrkRjK6gkc3C".into(),
+            content: "JBSWY3DPEHPK3PXP".into(),
+            display_name: "Work email".into(),
+            icon_url: Some("data:image/svg+xml;base64,PHN2Zy8+".into()),
+            website_url: Some("google.com".into()),
+            issuer: None,
+            account: None,
+            kind: "totp".into(),
+            digits: 8,
+            period: 60,
+            algorithm: "SHA256".into(),
+            expires_at: None,
+            sort_index: 0,
+            favorite: true,
+            folder_id: Some(folder.id.clone()),
+            created_at: 0,
+            updated_at: 0,
+            revision: 0,
+            deleted_at: None,
+        };
+        let favicon = Code {
+            id: "a1Vq7sLm3Xc9Rt2b".into(),
+            display_name: "Personal".into(),
+            icon_url: Some("data:image/x-icon;base64,AAABAA==".into()),
+            folder_id: None,
+            favorite: false,
+            ..code.clone()
+        };
+
+        (vec![code, favicon], vec![folder])
+    }
+
+    /// Serializes and parses the vault again, as if it was written to a file and imported.
+    fn reimport(vault: &AegisVault) -> AegisVault {
+        serde_json::from_str(&serde_json::to_string(vault).unwrap()).unwrap()
+    }
+
+    #[gtest]
+    fn export_round_trip() {
+        let (codes, folders) = example_codes();
+        let db = reimport(&AegisVault::new(&AegisDb::new(codes, folders), None))
+            .open(None)
+            .unwrap();
+
+        assert_that!(db.entries.len(), eq(2));
+        expect_that!(db.version, eq(DB_VERSION));
+        expect_that!(db.entries[0].name, eq("Work email"));
+        expect_that!(db.entries[0].issuer, eq("google.com"));
+        expect_that!(db.entries[0].favorite, is_true());
+        expect_that!(
+            db.entries[0].params(),
+            some(eq(totp::Params {
+                kind: Kind::Totp,
+                algorithm: Algorithm::Sha256,
+                digits: 8,
+                period: 60,
+            }))
+        );
+        expect_that!(
+            db.entries[0].icon_url(),
+            some(eq("data:image/svg+xml;base64,PHN2Zy8+"))
+        );
+        expect_that!(db.group_of(&db.entries[0]), some(eq("Work")));
+        // Aegis can't display `.ico` files
+        expect_that!(db.entries[1].icon, none());
+        expect_that!(db.group_of(&db.entries[1]), none());
+    }
+
+    #[gtest]
+    fn encrypted_export_round_trip() {
+        let (codes, folders) = example_codes();
+        let vault = AegisVault::new(&AegisDb::new(codes, folders), Some("test"));
+
+        expect_that!(
+            serde_json::to_string(&vault).unwrap(),
+            not(contains_substring("JBSWY3DPEHPK3PXP"))
+        );
+        expect_that!(
+            reimport(&vault).open(Some("wrong")).map(|_| ()),
+            err(eq(&ExportError::WrongPassphrase))
+        );
+        let db = reimport(&vault).open(Some("test")).unwrap();
+        expect_that!(db.entries[0].info.secret, eq("JBSWY3DPEHPK3PXP"));
+        expect_that!(db.group_of(&db.entries[0]), some(eq("Work")));
+    }
+}
@@ -0,0 +1,253 @@
+use super::{Backup, ForeignCode};
+use crate::{
+    export::ExportError,
+    otpauth,
+    totp::{self, Algorithm, Kind},
+};
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Key, Nonce,
+};
+use serde::Deserialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+/// Highest PBKDF2 iteration count accepted when decrypting, to keep imports from hogging the CPU.
+/// andOTP picks between 140000 and 160000.
+const MAX_PBKDF2_ROUNDS: u32 = 1_000_000;
+const SALT_LENGTH: usize = 12;
+const NONCE_LENGTH: usize = 12;
+
+/// An entry of an andOTP backup.
+#[derive(Deserialize, Debug)]
+struct AndOtpEntry {
+    /// Base32 encoded secret.
+    secret: String,
+    #[serde(default)]
+    issuer: String,
+    #[serde(default)]
+    label: String,
+    /// `TOTP`, `HOTP` or `STEAM`.
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default = "default_algorithm")]
+    algorithm: String,
+    #[serde(default = "default_digits")]
+    digits: u32,
+    #[serde(default = "default_period")]
+    period: i64,
+}
+
+fn default_algorithm() -> String {
+    Algorithm::default().as_str().into()
+}
+
+fn default_digits() -> u32 {
+    totp::DIGITS
+}
+
+fn default_period() -> i64 {
+    totp::PERIOD
+}
+
+impl AndOtpEntry {
+    /// Parameters of the code, or `None` if Iceblink can't generate it, like HOTP codes.
+    fn params(&self) -> Option<totp::Params> {
+        if self.kind.eq_ignore_ascii_case("steam") {
+            return Some(totp::Params::STEAM);
+        }
+        if !self.kind.eq_ignore_ascii_case("totp")
+            || !otpauth::DIGITS.contains(&self.digits)
+            || !otpauth::PERIODS.contains(&self.period)
+        {
+            return None;
+        }
+
+        Some(totp::Params {
+            kind: Kind::Totp,
+            algorithm: Algorithm::parse(&self.algorithm)?,
+            digits: self.digits,
+            period: self.period,
+        })
+    }
+
+    /// Name of the entry, for listing skipped entries.
+    fn name(&self) -> String {
+        match self.issuer.is_empty() {
+            true => self.label.clone(),
+            false => format!("{}: {}", self.issuer, self.label),
+        }
+    }
+}
+
+fn open(key: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .ok()
+}
+
+/// Decrypts a `.json.aes` backup. Since version 0.6.3, andOTP prefixes the ciphertext with the
+/// PBKDF2 iteration count, salt and nonce. Older backups are just the nonce and ciphertext, with
+/// the SHA-256 hash of the password as key.
+fn decrypt(password: &str, data: &[u8]) -> Result<Vec<u8>, ExportError> {
+    if let Some((rounds, rest)) = data.split_first_chunk::<4>() {
+        let rounds = u32::from_be_bytes(*rounds);
+        if (1..=MAX_PBKDF2_ROUNDS).contains(&rounds) && rest.len() > SALT_LENGTH + NONCE_LENGTH {
+            let (salt, rest) = rest.split_at(SALT_LENGTH);
+            let (nonce, ciphertext) = rest.split_at(NONCE_LENGTH);
+            let mut key = [0u8; 32];
+            pbkdf2::pbkdf2_hmac::<Sha1>(password.as_bytes(), salt, rounds, &mut key);
+
+            if let Some(plaintext) = open(&key, nonce, ciphertext) {
+                return Ok(plaintext);
+            }
+        }
+    }
+
+    if data.len() <= NONCE_LENGTH {
+        return Err(ExportError::Malformed);
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+    open(&Sha256::digest(password.as_bytes()), nonce, ciphertext)
+        .ok_or(ExportError::WrongPassphrase)
+}
+
+/// Reads the TOTP entries of an andOTP backup, decrypting it with the password if it isn't a
+/// plaintext `.json` backup.
+pub fn read(data: &[u8], password: Option<&str>) -> Result<Backup, ExportError> {
+    let entries: Vec<AndOtpEntry> = match serde_json::from_slice(data) {
+        Ok(entries) => entries,
+        Err(_) => {
+            let password = password.ok_or(ExportError::PassphraseRequired)?;
+            serde_json::from_slice(&decrypt(password, data)?).map_err(|_| ExportError::Malformed)?
+        }
+    };
+
+    let mut backup = Backup::default();
+    for entry in entries {
+        let Some(params) = entry.params() else {
+            backup.skipped.push(entry.name());
+            continue;
+        };
+
+        let issuer = Some(entry.issuer).filter(|issuer| !issuer.is_empty());
+        backup.codes.push(ForeignCode {
+            display_name: entry.label.clone(),
+            secret: entry.secret,
+            website_url: issuer.clone(),
+            issuer,
+            account: Some(entry.label).filter(|label| !label.is_empty()),
+            icon_url: None,
+            params,
+            favorite: false,
+            group: None,
+        });
+    }
+
+    Ok(backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{interop::Format, models::codes::Code};
+    use googletest::prelude::*;
+
+    const PLAIN: &[u8] = include_bytes!("../../tests/fixtures/andotp_plain.json");
+    const ENCRYPTED: &[u8] = include_bytes!("../../tests/fixtures/andotp_encrypted.json.aes");
+    const LEGACY: &[u8] = include_bytes!("../../tests/fixtures/andotp_legacy.json.aes");
+
+    fn names(backup: &Backup) -> Vec<&str> {
+        backup
+            .codes
+            .iter()
+            .map(|code| code.display_name.as_str())
+            .collect()
+    }
+
+    #[gtest]
+    fn plain_backup() {
+        let backup = read(PLAIN, None).unwrap();
+
+        expect_that!(
+            names(&backup),
+            elements_are![eq("alice@example.com"), eq("carol")]
+        );
+        expect_that!(backup.skipped, elements_are![eq("Bank: bob")]);
+        expect_that!(
+            backup.codes[0],
+            eq(&ForeignCode {
+                display_name: "alice@example.com".into(),
+                secret: "JBSWY3DPEHPK3PXP".into(),
+                issuer: Some("GitHub".into()),
+                account: Some("alice@example.com".into()),
+                website_url: Some("GitHub".into()),
+                icon_url: None,
+                params: totp::Params::default(),
+                favorite: false,
+                group: None,
+            })
+        );
+        expect_that!(
+            backup.codes[1].params,
+            eq(totp::Params {
+                kind: Kind::Totp,
+                algorithm: Algorithm::Sha512,
+                digits: 8,
+                period: 60,
+            })
+        );
+    }
+
+    #[gtest]
+    fn steam_entries() {
+        let backup = read(
+            br#"[{"secret": "JBSWY3DPEHPK3PXP", "issuer": "Steam", "label": "gaben", "type": "STEAM", "digits": 5}]"#,
+            None,
+        )
+        .unwrap();
+
+        expect_that!(backup.skipped, empty());
+        expect_that!(backup.codes[0].params, eq(totp::Params::STEAM));
+    }
+
+    #[gtest]
+    fn oversized_entries() {
+        let data = format!(
+            r#"[{{"secret": "JBSWY3DPEHPK3PXP", "issuer": "{}", "type": "TOTP"}}, {{"secret": "JBSWY3DPEHPK3PXP", "label": "carol", "type": "TOTP"}}]"#,
+            "a".repeat(Code::MAX_LABEL_LENGTH + 1)
+        );
+        let backup = Format::AndOtp.read(data.as_bytes(), None).unwrap();
+
+        expect_that!(names(&backup), elements_are![eq("carol")]);
+        expect_that!(backup.skipped, len(eq(1)));
+    }
+
+    #[gtest]
+    fn encrypted_backup() {
+        for data in [ENCRYPTED, LEGACY] {
+            let backup = read(data, Some("test")).unwrap();
+
+            expect_that!(
+                names(&backup),
+                elements_are![eq("alice@example.com"), eq("carol")]
+            );
+            expect_that!(
+                read(data, Some("wrong")).map(|_| ()),
+                err(eq(&ExportError::WrongPassphrase))
+            );
+            expect_that!(
+                read(data, None).map(|_| ()),
+                err(eq(&ExportError::PassphraseRequired))
+            );
+        }
+    }
+
+    #[gtest]
+    fn truncated_backup() {
+        expect_that!(
+            read(&ENCRYPTED[..8], Some("test")).map(|_| ()),
+            err(eq(&ExportError::Malformed))
+        );
+    }
+}
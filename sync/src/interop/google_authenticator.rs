@@ -0,0 +1,255 @@
+use super::{Backup, ForeignCode};
+use crate::{
+    export::ExportError,
+    totp::{self, Algorithm},
+};
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use data_encoding::BASE32_NOPAD;
+use reqwest::Url;
+
+/// An account exported by Google Authenticator's "Transfer accounts".
+#[derive(Clone, Debug, PartialEq)]
+pub struct MigrationEntry {
+    /// The label of the account, like `Google:me@example.com`.
+    pub name: String,
+    pub issuer: Option<String>,
+    /// The label without the issuer prefix.
+    pub account: String,
+    /// Base32 secret, without padding.
+    pub secret: String,
+    pub params: totp::Params,
+    /// Whether the entry is a TOTP code with a supported algorithm. HOTP counters can't be synced
+    /// safely, and MD5 isn't supported by Iceblink.
+    pub supported: bool,
+}
+
+/// Field of a protobuf message. Fixed size fields are skipped, as the payload has none.
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+/// Reads the fields of a protobuf message. Just enough of the wire format for the migration
+/// payload, which isn't worth a code generator.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn varint(&mut self) -> Option<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.data.split_first()?;
+            self.data = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.data.len() {
+            return None;
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(taken)
+    }
+
+    /// Number and value of the next field, or `None` at the end of the message.
+    fn field(&mut self) -> Result<Option<(u64, Value<'a>)>, ExportError> {
+        if self.data.is_empty() {
+            return Ok(None);
+        }
+
+        let key = self.varint().ok_or(ExportError::Malformed)?;
+        let value = match key & 0x07 {
+            0 => self.varint().map(Value::Varint),
+            1 => self.take(8).map(|_| Value::Fixed),
+            2 => self
+                .varint()
+                .and_then(|len| self.take(usize::try_from(len).ok()?))
+                .map(Value::Bytes),
+            5 => self.take(4).map(|_| Value::Fixed),
+            _ => None,
+        };
+
+        Ok(Some((key >> 3, value.ok_or(ExportError::Malformed)?)))
+    }
+}
+
+fn string(bytes: &[u8]) -> Result<String, ExportError> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| ExportError::Malformed)
+}
+
+fn entry(data: &[u8]) -> Result<MigrationEntry, ExportError> {
+    let mut reader = Reader { data };
+    let mut secret = vec![];
+    let mut name = String::new();
+    let mut issuer = None;
+    let mut params = totp::Params::default();
+    let mut supported = true;
+
+    // Unspecified enum values are left at the defaults, as older versions of the app did
+    while let Some((field, value)) = reader.field()? {
+        match (field, value) {
+            (1, Value::Bytes(bytes)) => secret = bytes.to_vec(),
+            (2, Value::Bytes(bytes)) => name = string(bytes)?.trim().to_string(),
+            (3, Value::Bytes(bytes)) => {
+                issuer = Some(string(bytes)?.trim().to_string()).filter(|issuer| !issuer.is_empty())
+            }
+            (4, Value::Varint(algorithm)) => match algorithm {
+                2 => params.algorithm = Algorithm::Sha256,
+                3 => params.algorithm = Algorithm::Sha512,
+                4 => supported = false,
+                _ => {}
+            },
+            (5, Value::Varint(2)) => params.digits = 8,
+            (6, Value::Varint(1)) => supported = false,
+            _ => {}
+        }
+    }
+
+    if secret.is_empty() || name.is_empty() {
+        return Err(ExportError::Malformed);
+    }
+    let account = match name.split_once(':') {
+        Some((_, account)) => account.trim().to_string(),
+        None => name.clone(),
+    };
+
+    Ok(MigrationEntry {
+        secret: BASE32_NOPAD.encode(&secret),
+        name,
+        issuer,
+        account,
+        params,
+        supported,
+    })
+}
+
+/// Parses an `otpauth-migration://offline?data=` URI, as shown in the QR codes of Google
+/// Authenticator. Large exports are split across several QR codes, each a URI of its own.
+pub fn parse(uri: &str) -> Result<Vec<MigrationEntry>, ExportError> {
+    let uri = Url::parse(uri.trim()).map_err(|_| ExportError::Malformed)?;
+    if uri.scheme() != "otpauth-migration" {
+        return Err(ExportError::Malformed);
+    }
+    let data = uri
+        .query_pairs()
+        .find(|(key, _)| key == "data")
+        .ok_or(ExportError::Malformed)?
+        .1
+        // Query decoding turns unescaped `+` of the base64 into spaces
+        .replace(' ', "+");
+    let payload = STANDARD_NO_PAD
+        .decode(data.trim_end_matches('='))
+        .map_err(|_| ExportError::Malformed)?;
+
+    let mut reader = Reader { data: &payload };
+    let mut entries = vec![];
+    while let Some((field, value)) = reader.field()? {
+        if let (1, Value::Bytes(bytes)) = (field, value) {
+            entries.push(entry(bytes)?);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Reads the accounts of one or more migration URIs, skipping those Iceblink can't generate codes
+/// for.
+pub fn read<'a>(uris: impl IntoIterator<Item = &'a str>) -> Result<Backup, ExportError> {
+    let mut backup = Backup::default();
+    for uri in uris {
+        for entry in parse(uri)? {
+            if !entry.supported {
+                backup.skipped.push(entry.name);
+                continue;
+            }
+
+            backup.codes.push(ForeignCode {
+                display_name: entry.name,
+                secret: entry.secret,
+                website_url: entry.issuer.clone(),
+                issuer: entry.issuer,
+                account: Some(entry.account),
+                icon_url: None,
+                params: entry.params,
+                favorite: false,
+                group: None,
+            });
+        }
+    }
+
+    Ok(backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    /// A TOTP entry with an issuer, an HOTP entry, and a TOTP entry with 8 digit SHA256 codes.
+    const EXPORT: &str = "otpauth-migration://offline?data=CjQKCkhlbGxvId6tvu8SGEdpdEh1YjphbGljZUBleGFtcGxlLmNvbRoGR2l0SHViIAEoATACChkKCkhlbGxvId6tvu8SA2JvYiABKAEwATgFCiIKCkhlbGxvId6tvu8SBWNhcm9sGgdFeGFtcGxlIAIoAjACEAEYASAAKHs%3D";
+
+    #[gtest]
+    fn parse_export() {
+        let entries = parse(EXPORT).unwrap();
+
+        assert_that!(entries.len(), eq(3));
+        expect_that!(
+            entries[0],
+            eq(&MigrationEntry {
+                name: "GitHub:alice@example.com".into(),
+                issuer: Some("GitHub".into()),
+                account: "alice@example.com".into(),
+                secret: "JBSWY3DPEHPK3PXP".into(),
+                params: totp::Params::default(),
+                supported: true,
+            })
+        );
+        expect_that!(entries[1].name, eq("bob"));
+        expect_that!(entries[1].supported, is_false());
+        expect_that!(
+            entries[2].params,
+            eq(totp::Params {
+                kind: totp::Kind::Totp,
+                algorithm: Algorithm::Sha256,
+                digits: 8,
+                period: 30,
+            })
+        );
+    }
+
+    #[gtest]
+    fn parse_unescaped_data() {
+        let unescaped = EXPORT.replace("%3D", "=");
+
+        expect_that!(parse(&unescaped).map(|entries| entries.len()), ok(eq(3)));
+    }
+
+    #[gtest]
+    fn parse_rejects_malformed() {
+        expect_that!(
+            parse("otpauth://totp/Work?secret=JBSWY3DPEHPK3PXP"),
+            err(eq(&ExportError::Malformed))
+        );
+        expect_that!(
+            parse("otpauth-migration://offline"),
+            err(eq(&ExportError::Malformed))
+        );
+        expect_that!(
+            parse("otpauth-migration://offline?data=not%20base64!"),
+            err(eq(&ExportError::Malformed))
+        );
+        // Truncated in the middle of the first entry
+        expect_that!(
+            parse("otpauth-migration://offline?data=CjQKCkhlbGxvId6tvu8SGEdpdEh1"),
+            err(eq(&ExportError::Malformed))
+        );
+    }
+}
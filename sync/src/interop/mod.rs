@@ -0,0 +1,219 @@
+use crate::{
+    export::ExportError,
+    models::{codes::Code, folders::Folder},
+    totp, utils,
+};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+
+pub mod aegis;
+pub mod andotp;
+pub mod google_authenticator;
+pub mod twofas;
+
+/// A code read from the backup of another authenticator app.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForeignCode {
+    pub display_name: String,
+    /// Base32 encoded secret.
+    pub secret: String,
+    pub issuer: Option<String>,
+    pub account: Option<String>,
+    pub website_url: Option<String>,
+    pub icon_url: Option<String>,
+    pub params: totp::Params,
+    pub favorite: bool,
+    /// Name of the group the code is in, which becomes a folder.
+    pub group: Option<String>,
+}
+
+impl ForeignCode {
+    /// Whether every field fits in those of Iceblink codes, see [`Code::limited_fields`].
+    fn fits(&self) -> bool {
+        [
+            (Some(self.secret.as_str()), Code::MAX_CONTENT_LENGTH),
+            (
+                Some(self.display_name.as_str()),
+                Code::MAX_DISPLAY_NAME_LENGTH,
+            ),
+            (self.issuer.as_deref(), Code::MAX_LABEL_LENGTH),
+            (self.account.as_deref(), Code::MAX_LABEL_LENGTH),
+            (self.website_url.as_deref(), Code::MAX_WEBSITE_URL_LENGTH),
+        ]
+        .into_iter()
+        .all(|(value, max_len)| !value.is_some_and(|value| value.len() > max_len))
+    }
+}
+
+/// The codes of a backup, and names of entries which can't be imported, like HOTP codes.
+#[derive(Debug, Default)]
+pub struct Backup {
+    pub codes: Vec<ForeignCode>,
+    pub skipped: Vec<String>,
+}
+
+impl Backup {
+    /// Skips codes with fields longer than Iceblink codes may have.
+    fn skip_oversized(mut self) -> Self {
+        let (codes, oversized): (Vec<_>, Vec<_>) =
+            self.codes.into_iter().partition(ForeignCode::fits);
+        self.codes = codes;
+        self.skipped.extend(oversized.into_iter().map(|code| {
+            code.display_name
+                .chars()
+                .take(Code::MAX_DISPLAY_NAME_LENGTH)
+                .collect()
+        }));
+        self
+    }
+}
+
+/// Backup formats of other apps, shared by the import and export routes and the CLI.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Aegis,
+    /// Import only.
+    AndOtp,
+    TwoFas,
+    /// `otpauth-migration://` URIs of "Transfer accounts", one per line. Import only.
+    GoogleAuthenticator,
+}
+
+impl Format {
+    /// Reads a backup file, decrypting it with the password if needed.
+    pub fn read(self, data: &[u8], password: Option<&str>) -> Result<Backup, ExportError> {
+        let backup = match self {
+            Format::Aegis => aegis::read(
+                serde_json::from_slice(data).map_err(|_| ExportError::Malformed)?,
+                password,
+            ),
+            Format::AndOtp => andotp::read(data, password),
+            Format::TwoFas => twofas::read(
+                serde_json::from_slice(data).map_err(|_| ExportError::Malformed)?,
+                password,
+            ),
+            Format::GoogleAuthenticator => google_authenticator::read(
+                std::str::from_utf8(data)
+                    .map_err(|_| ExportError::Malformed)?
+                    .lines()
+                    .filter(|line| !line.trim().is_empty()),
+            ),
+        };
+
+        backup.map(Backup::skip_oversized)
+    }
+
+    /// Writes a backup file of the codes, encrypted when a password is given. Returns `None` for
+    /// formats which can't be written.
+    pub fn write(
+        self,
+        codes: Vec<Code>,
+        folders: Vec<Folder>,
+        password: Option<&str>,
+    ) -> Option<String> {
+        match self {
+            Format::Aegis => Some(aegis::write(codes, folders, password)),
+            Format::TwoFas => Some(twofas::write(codes, folders, password)),
+            Format::AndOtp | Format::GoogleAuthenticator => None,
+        }
+    }
+}
+
+/// Top level folders of a user by name, for putting imported codes in the folder named like
+/// their group.
+pub struct FolderNames {
+    owner_id: String,
+    ids: HashMap<String, String>,
+    /// Folders created for names without one, which still have to be inserted.
+    pub created: Vec<Folder>,
+}
+
+impl FolderNames {
+    pub async fn load(pool: &SqlitePool, owner_id: &str) -> Result<Self, sqlx::Error> {
+        let ids = Folder::get_many(pool, owner_id)
+            .await?
+            .into_iter()
+            .filter(|folder| folder.parent_id.is_none())
+            .map(|folder| (folder.name, folder.id))
+            .collect();
+
+        Ok(FolderNames {
+            owner_id: owner_id.to_string(),
+            ids,
+            created: vec![],
+        })
+    }
+
+    /// Id of the top level folder with the name, creating it when missing. Returns `None` for
+    /// names which are blank or too long for a folder.
+    pub fn resolve(&mut self, name: Option<&str>) -> Option<String> {
+        let name = name
+            .map(str::trim)
+            .filter(|name| !name.is_empty() && name.len() <= Folder::MAX_NAME_LENGTH)?;
+        if let Some(id) = self.ids.get(name) {
+            return Some(id.clone());
+        }
+
+        let folder = Folder {
+            id: utils::generate_id(16),
+            owner_id: self.owner_id.clone(),
+            name: name.to_string(),
+            parent_id: None,
+        };
+        self.ids.insert(folder.name.clone(), folder.id.clone());
+        let id = folder.id.clone();
+        self.created.push(folder);
+        Some(id)
+    }
+
+    /// Inserts the created folders, returning them.
+    pub async fn insert(self, pool: &SqlitePool) -> Result<Vec<Folder>, sqlx::Error> {
+        for folder in &self.created {
+            folder.insert(pool).await?;
+        }
+        Ok(self.created)
+    }
+}
+
+/// Adds the codes of a backup to those of the user, after the existing ones. Groups are put in
+/// top level folders of the same name, which are created when missing. Returns the new codes and
+/// folders.
+pub async fn store(
+    pool: &SqlitePool,
+    owner_id: &str,
+    codes: Vec<ForeignCode>,
+) -> Result<(Vec<Code>, Vec<Folder>), sqlx::Error> {
+    let first_index = Code::next_sort_index(pool, owner_id).await?;
+    let mut folders = FolderNames::load(pool, owner_id).await?;
+
+    let mut imported = vec![];
+    for (index, code) in (first_index..).zip(codes) {
+        imported.push(Code {
+            id: utils::generate_id(16),
+            owner_id: owner_id.to_string(),
+            content: code.secret,
+            display_name: code.display_name,
+            icon_url: code.icon_url,
+            website_url: code.website_url,
+            expires_at: None,
+            sort_index: index,
+            issuer: code.issuer,
+            account: code.account,
+            kind: code.params.kind.as_str().into(),
+            digits: code.params.digits as i64,
+            period: code.params.period,
+            algorithm: code.params.algorithm.as_str().into(),
+            favorite: code.favorite,
+            folder_id: folders.resolve(code.group.as_deref()),
+            created_at: 0,
+            updated_at: 0,
+            revision: 0,
+            deleted_at: None,
+        });
+    }
+
+    let folders = folders.insert(pool).await?;
+    Code::insert_many(pool, &mut imported).await?;
+
+    Ok((imported, folders))
+}
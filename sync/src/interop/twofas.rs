@@ -0,0 +1,404 @@
+use super::{Backup, ForeignCode};
+use crate::{
+    export::ExportError,
+    models::{codes::Code, folders::Folder},
+    otpauth,
+    totp::{self, Algorithm, Kind},
+};
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+
+/// PBKDF2 iterations the key of encrypted backups is derived with, as 2FAS does.
+const PBKDF2_ROUNDS: u32 = 10_000;
+const SALT_LENGTH: usize = 256;
+/// Version of the backup format written by exports.
+const SCHEMA_VERSION: u32 = 4;
+
+/// A `.2fas` backup of the 2FAS Auth app, plaintext or encrypted.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TwoFasBackup {
+    /// Empty when the backup is encrypted.
+    #[serde(default)]
+    services: Vec<TwoFasService>,
+    /// Base64 encoded ciphertext, salt and IV of the services, separated by colons.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    services_encrypted: Option<String>,
+    #[serde(default)]
+    groups: Vec<TwoFasGroup>,
+    #[serde(default)]
+    updated_at: i64,
+    #[serde(default = "schema_version")]
+    schema_version: u32,
+    #[serde(default)]
+    app_version_code: u32,
+    #[serde(default)]
+    app_version_name: String,
+    #[serde(default)]
+    app_origin: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TwoFasGroup {
+    id: String,
+    name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TwoFasService {
+    name: String,
+    /// Base32 encoded secret.
+    secret: String,
+    /// Milliseconds since the epoch.
+    #[serde(default)]
+    updated_at: i64,
+    #[serde(default)]
+    otp: TwoFasOtp,
+    order: Option<TwoFasOrder>,
+    group_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct TwoFasOtp {
+    label: Option<String>,
+    account: Option<String>,
+    issuer: Option<String>,
+    digits: Option<u32>,
+    period: Option<i64>,
+    algorithm: Option<String>,
+    /// `TOTP`, `HOTP` or `STEAM`.
+    token_type: Option<String>,
+    source: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TwoFasOrder {
+    position: i64,
+}
+
+fn schema_version() -> u32 {
+    SCHEMA_VERSION
+}
+
+fn cipher(password: &str, salt: &[u8]) -> Aes256Gcm {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+}
+
+fn encrypt(password: &str, plaintext: &[u8]) -> String {
+    let mut salt = [0u8; SALT_LENGTH];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher(password, &salt)
+        .encrypt(&nonce, plaintext)
+        .expect("Unable to encrypt backup");
+
+    format!(
+        "{}:{}:{}",
+        STANDARD.encode(ciphertext),
+        STANDARD.encode(salt),
+        STANDARD.encode(nonce)
+    )
+}
+
+fn decrypt(password: &str, encrypted: &str) -> Result<Vec<u8>, ExportError> {
+    let parts = encrypted
+        .split(':')
+        .map(|part| STANDARD.decode(part))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| ExportError::Malformed)?;
+    let [ciphertext, salt, nonce] = parts.as_slice() else {
+        return Err(ExportError::Malformed);
+    };
+    if nonce.len() != 12 {
+        return Err(ExportError::Malformed);
+    }
+
+    cipher(password, salt)
+        .decrypt(Nonce::from_slice(nonce), ciphertext.as_slice())
+        .map_err(|_| ExportError::WrongPassphrase)
+}
+
+impl TwoFasService {
+    /// Parameters of the code, or `None` if Iceblink can't generate it, like HOTP codes.
+    fn params(&self) -> Option<totp::Params> {
+        let kind = self.otp.token_type.as_deref().unwrap_or("TOTP");
+        if kind.eq_ignore_ascii_case("steam") {
+            return Some(totp::Params::STEAM);
+        }
+
+        let defaults = totp::Params::default();
+        let params = totp::Params {
+            kind: Kind::Totp,
+            algorithm: match &self.otp.algorithm {
+                Some(algorithm) => Algorithm::parse(algorithm)?,
+                None => defaults.algorithm,
+            },
+            digits: self.otp.digits.unwrap_or(defaults.digits),
+            period: self.otp.period.unwrap_or(defaults.period),
+        };
+
+        (kind.eq_ignore_ascii_case("totp")
+            && otpauth::DIGITS.contains(&params.digits)
+            && otpauth::PERIODS.contains(&params.period))
+        .then_some(params)
+    }
+}
+
+impl TwoFasBackup {
+    /// Returns the services of the backup, decrypting them with the password if needed.
+    fn services(&mut self, password: Option<&str>) -> Result<Vec<TwoFasService>, ExportError> {
+        match &self.services_encrypted {
+            Some(encrypted) => {
+                let password = password.ok_or(ExportError::PassphraseRequired)?;
+                serde_json::from_slice(&decrypt(password, encrypted)?)
+                    .map_err(|_| ExportError::Malformed)
+            }
+            None => Ok(std::mem::take(&mut self.services)),
+        }
+    }
+}
+
+/// Reads the TOTP services of a backup, in the order shown by 2FAS, decrypting them with the
+/// password if needed. Icons aren't imported, as 2FAS only refers to icons of its own collection.
+pub fn read(mut backup: TwoFasBackup, password: Option<&str>) -> Result<Backup, ExportError> {
+    let mut services = backup.services(password)?;
+    services.sort_by_key(|service| {
+        service
+            .order
+            .as_ref()
+            .map_or(i64::MAX, |order| order.position)
+    });
+    let groups: HashMap<&str, &str> = backup
+        .groups
+        .iter()
+        .map(|group| (group.id.as_str(), group.name.as_str()))
+        .collect();
+
+    let mut imported = Backup::default();
+    for service in services {
+        let Some(params) = service.params() else {
+            imported.skipped.push(service.name);
+            continue;
+        };
+
+        let issuer = service.otp.issuer.filter(|issuer| !issuer.is_empty());
+        imported.codes.push(ForeignCode {
+            group: service
+                .group_id
+                .and_then(|id| groups.get(id.as_str()).map(|name| name.to_string())),
+            display_name: service.name,
+            secret: service.secret,
+            website_url: issuer.clone(),
+            issuer,
+            account: service
+                .otp
+                .account
+                .or(service.otp.label)
+                .filter(|account| !account.is_empty()),
+            icon_url: None,
+            params,
+            favorite: false,
+        });
+    }
+
+    Ok(imported)
+}
+
+/// Backup file of the codes, with a group for every folder. Encrypted when a password is given.
+/// The `reference` 2FAS uses to check passwords isn't written, the services themselves are
+/// authenticated by AES-GCM.
+pub fn write(codes: Vec<Code>, folders: Vec<Folder>, password: Option<&str>) -> String {
+    let services: Vec<TwoFasService> = codes
+        .into_iter()
+        .enumerate()
+        .map(|(position, code)| TwoFasService {
+            updated_at: code.updated_at * 1000,
+            otp: TwoFasOtp {
+                label: Some(code.account.clone().unwrap_or(code.display_name.clone())),
+                account: code.account,
+                issuer: code.issuer.or(code.website_url),
+                digits: Some(code.digits as u32),
+                period: Some(code.period),
+                algorithm: Some(code.algorithm),
+                token_type: Some(code.kind.to_ascii_uppercase()),
+                source: Some("Manual".into()),
+            },
+            name: code.display_name,
+            secret: code.content,
+            order: Some(TwoFasOrder {
+                position: position as i64,
+            }),
+            group_id: code.folder_id,
+        })
+        .collect();
+
+    let (services, services_encrypted) = match password {
+        Some(password) => {
+            let plaintext = serde_json::to_vec(&services).expect("Unable to serialize backup");
+            (vec![], Some(encrypt(password, &plaintext)))
+        }
+        None => (services, None),
+    };
+    let backup = TwoFasBackup {
+        services,
+        services_encrypted,
+        groups: folders
+            .into_iter()
+            .map(|folder| TwoFasGroup {
+                id: folder.id,
+                name: folder.name,
+            })
+            .collect(),
+        updated_at: chrono::Utc::now().timestamp_millis(),
+        schema_version: SCHEMA_VERSION,
+        app_version_code: 0,
+        app_version_name: env!("CARGO_PKG_VERSION").into(),
+        app_origin: "iceblink".into(),
+    };
+
+    serde_json::to_string(&backup).expect("Unable to serialize backup")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    const PLAIN: &str = include_str!("../../tests/fixtures/twofas_plain.2fas");
+    const ENCRYPTED: &str = include_str!("../../tests/fixtures/twofas_encrypted.2fas");
+
+    fn backup(json: &str) -> TwoFasBackup {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn names(backup: &Backup) -> Vec<&str> {
+        backup
+            .codes
+            .iter()
+            .map(|code| code.display_name.as_str())
+            .collect()
+    }
+
+    #[gtest]
+    fn plain_backup() {
+        let imported = read(backup(PLAIN), None).unwrap();
+
+        // Sorted by position
+        expect_that!(names(&imported), elements_are![eq("Example"), eq("GitHub")]);
+        expect_that!(imported.skipped, elements_are![eq("Bank")]);
+        expect_that!(
+            imported.codes[0].params,
+            eq(totp::Params {
+                kind: Kind::Totp,
+                algorithm: Algorithm::Sha256,
+                digits: 8,
+                period: 60,
+            })
+        );
+        expect_that!(
+            imported.codes[1],
+            eq(&ForeignCode {
+                display_name: "GitHub".into(),
+                secret: "JBSWY3DPEHPK3PXP".into(),
+                issuer: Some("GitHub".into()),
+                account: Some("alice@example.com".into()),
+                website_url: Some("GitHub".into()),
+                icon_url: None,
+                params: totp::Params::default(),
+                favorite: false,
+                group: Some("Work".into()),
+            })
+        );
+    }
+
+    #[gtest]
+    fn steam_services() {
+        let imported = read(
+            backup(
+                r#"{"services": [{"name": "Steam", "secret": "JBSWY3DPEHPK3PXP", "otp": {"digits": 5, "tokenType": "STEAM"}}]}"#,
+            ),
+            None,
+        )
+        .unwrap();
+
+        expect_that!(imported.skipped, empty());
+        expect_that!(imported.codes[0].params, eq(totp::Params::STEAM));
+    }
+
+    #[gtest]
+    fn encrypted_backup() {
+        let imported = read(backup(ENCRYPTED), Some("test")).unwrap();
+
+        expect_that!(names(&imported), elements_are![eq("Example"), eq("GitHub")]);
+        expect_that!(imported.codes[1].group.as_deref(), some(eq("Work")));
+        expect_that!(
+            read(backup(ENCRYPTED), Some("wrong")).map(|_| ()),
+            err(eq(&ExportError::WrongPassphrase))
+        );
+        expect_that!(
+            read(backup(ENCRYPTED), None).map(|_| ()),
+            err(eq(&ExportError::PassphraseRequired))
+        );
+    }
+
+    #[gtest]
+    fn export_round_trip() {
+        let exported = read(backup(PLAIN), None).unwrap();
+        let folder = Folder {
+            id: "z8GJ2kdPq0w8Vn1c".into(),
+            owner_id: "k0d8WrkRjK6gkc3C".into(),
+            name: "Work".into(),
+            parent_id: None,
+        };
+        let codes = exported
+            .codes
+            .iter()
+            .map(|code| Code {
+                id: "Ckpt4eFi1pw9fxI3".into(),
+                owner_id: "k0d8WrkRjK6gkc3C".into(),
+                content: code.secret.clone(),
+                display_name: code.display_name.clone(),
+                icon_url: None,
+                website_url: code.website_url.clone(),
+                issuer: code.issuer.clone(),
+                account: code.account.clone(),
+                kind: code.params.kind.as_str().into(),
+                digits: code.params.digits as i64,
+                period: code.params.period,
+                algorithm: code.params.algorithm.as_str().into(),
+                expires_at: None,
+                sort_index: 0,
+                favorite: false,
+                folder_id: code.group.as_ref().map(|_| folder.id.clone()),
+                created_at: 0,
+                updated_at: 0,
+                revision: 0,
+                deleted_at: None,
+            })
+            .collect::<Vec<_>>();
+
+        let plain = write(codes.clone(), vec![folder.clone()], None);
+        expect_that!(
+            read(backup(&plain), None).unwrap().codes,
+            eq(&exported.codes)
+        );
+
+        let encrypted = write(codes, vec![folder], Some("test"));
+        expect_that!(encrypted, not(contains_substring("JBSWY3DPEHPK3PXP")));
+        expect_that!(
+            read(backup(&encrypted), Some("test")).unwrap().codes,
+            eq(&exported.codes)
+        );
+    }
+}
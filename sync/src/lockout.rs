@@ -0,0 +1,152 @@
+use crate::routes::v1::ApiError;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// Failed attempts a key may make before it is locked out.
+pub const FREE_ATTEMPTS: u32 = 10;
+
+/// Lockout after the first failure beyond the free attempts, doubling with every further one.
+const BASE_LOCKOUT: Duration = Duration::from_secs(1);
+
+/// Longest a key is locked out at once.
+pub const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Failures of a key are forgotten once it had none for this long.
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Copy, Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Key of the failures of a client address.
+pub fn address_key(ip: IpAddr) -> String {
+    format!("ip:{ip}")
+}
+
+/// Failed authentication attempts per key, like `ip:192.0.2.1` or `email:alice@example.com`. Keys
+/// failing too often are locked out for exponentially growing periods, so credentials can't be
+/// guessed, even by spreading the attempts out.
+///
+/// Lockouts are logged with the `audit` target, and counted in the `auth_lockouts_total` metric.
+#[derive(Clone, Debug, Default)]
+pub struct Lockouts(Arc<Mutex<HashMap<String, Failures>>>);
+
+impl Lockouts {
+    /// Fails with the time left of the longest lockout, if any of the keys is locked out.
+    pub fn check(&self, keys: &[String]) -> Result<(), ApiError> {
+        let now = Instant::now();
+        let failures = self.0.lock().unwrap();
+        let locked_until = keys
+            .iter()
+            .filter_map(|key| failures.get(key)?.locked_until)
+            .filter(|until| *until > now)
+            .max();
+
+        match locked_until {
+            Some(until) => Err(ApiError::TooManyFailedAttempts(until - now)),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts a failed attempt with the authentication method, e.g. `password`, for every key.
+    pub fn record_failure(&self, method: &'static str, keys: &[String]) {
+        let now = Instant::now();
+        metrics::counter!("auth_failures_total", "method" => method).increment(1);
+        info!(target: "audit", method, keys = ?keys, "Authentication failed");
+
+        let mut failures = self.0.lock().unwrap();
+        failures.retain(|_, failures| now.duration_since(failures.last) < FORGET_AFTER);
+        for key in keys {
+            let failures = failures.entry(key.clone()).or_insert(Failures {
+                count: 0,
+                last: now,
+                locked_until: None,
+            });
+            failures.count += 1;
+            failures.last = now;
+            if failures.count <= FREE_ATTEMPTS {
+                continue;
+            }
+
+            let lockout = lockout_after(failures.count);
+            failures.locked_until = Some(now + lockout);
+            metrics::counter!("auth_lockouts_total", "method" => method).increment(1);
+            warn!(
+                target: "audit",
+                method,
+                key = %key,
+                failures = failures.count,
+                seconds = lockout.as_secs(),
+                "Locked out after repeated authentication failures"
+            );
+        }
+    }
+
+    /// Forgets the failures of the key, after it authenticated successfully.
+    pub fn record_success(&self, key: &str) {
+        self.0.lock().unwrap().remove(key);
+    }
+}
+
+/// Lockout after the given amount of failures, beyond the free attempts.
+fn lockout_after(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(FREE_ATTEMPTS + 1).min(31);
+    BASE_LOCKOUT.saturating_mul(1 << doublings).min(MAX_LOCKOUT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    #[gtest]
+    fn locks_out_after_free_attempts() {
+        let lockouts = Lockouts::default();
+        let keys = vec!["ip:192.0.2.1".to_string()];
+
+        for _ in 0..FREE_ATTEMPTS {
+            lockouts.record_failure("password", &keys);
+        }
+        expect_that!(lockouts.check(&keys), ok(anything()));
+
+        lockouts.record_failure("password", &keys);
+        expect_that!(lockouts.check(&keys), err(anything()));
+        // Other keys are unaffected, but any locked key is enough
+        expect_that!(lockouts.check(&["ip:192.0.2.2".into()]), ok(anything()));
+        expect_that!(
+            lockouts.check(&["ip:192.0.2.2".into(), keys[0].clone()]),
+            err(anything())
+        );
+    }
+
+    #[gtest]
+    fn lockouts_grow_exponentially() {
+        expect_that!(lockout_after(FREE_ATTEMPTS + 1), eq(Duration::from_secs(1)));
+        expect_that!(lockout_after(FREE_ATTEMPTS + 2), eq(Duration::from_secs(2)));
+        expect_that!(
+            lockout_after(FREE_ATTEMPTS + 5),
+            eq(Duration::from_secs(16))
+        );
+        expect_that!(lockout_after(u32::MAX), eq(MAX_LOCKOUT));
+    }
+
+    #[gtest]
+    fn success_forgets_failures() {
+        let lockouts = Lockouts::default();
+        let keys = vec!["email:alice@example.com".to_string()];
+
+        for _ in 0..=FREE_ATTEMPTS {
+            lockouts.record_failure("password", &keys);
+        }
+        lockouts.record_success(&keys[0]);
+        expect_that!(lockouts.check(&keys), ok(anything()));
+    }
+}
@@ -0,0 +1,450 @@
+use crate::{
+    models::{sessions::Session, user::User},
+    AppState, ServerOptions,
+};
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tracing::{debug, warn};
+
+/// Variables every template can use.
+const COMMON_VARIABLES: [&str; 3] = ["username", "display_name", "instance"];
+
+/// Built-in templates by name, with the variables they can use next to [`COMMON_VARIABLES`].
+/// Operators can replace them with `{name}.txt` files in the template directory.
+const TEMPLATES: [(&str, &str, &[&str]); 3] = [
+    (
+        "new_login",
+        include_str!("mail/new_login.txt"),
+        &["device", "ip", "time"],
+    ),
+    (
+        "deletion_scheduled",
+        include_str!("mail/deletion_scheduled.txt"),
+        &["delete_at"],
+    ),
+    (
+        "large_export",
+        include_str!("mail/large_export.txt"),
+        &["format", "codes", "time"],
+    ),
+];
+
+#[derive(Debug)]
+pub enum MailError {
+    InvalidSmtpUrl(lettre::transport::smtp::Error),
+    /// The sender isn't an address, optionally with a name like `Iceblink <iceblink@example.com>`.
+    InvalidSender(lettre::address::AddressError),
+    MissingSender,
+    InvalidRecipient(lettre::address::AddressError),
+    UnableToReadTemplate(PathBuf, std::io::Error),
+    /// The template doesn't start with a `Subject:` line followed by an empty line, or uses an
+    /// unknown variable.
+    InvalidTemplate(&'static str, String),
+    Message(lettre::error::Error),
+    Smtp(lettre::transport::smtp::Error),
+}
+
+impl std::fmt::Display for MailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MailError::InvalidSmtpUrl(err) => write!(f, "The SMTP URL is invalid: {err}"),
+            MailError::InvalidSender(err) => write!(f, "The email sender is invalid: {err}"),
+            MailError::MissingSender => write!(f, "Sending emails requires a sender"),
+            MailError::InvalidRecipient(err) => write!(f, "The email recipient is invalid: {err}"),
+            MailError::UnableToReadTemplate(path, err) => {
+                write!(f, "Unable to read email template {}: {err}", path.display())
+            }
+            MailError::InvalidTemplate(name, reason) => {
+                write!(f, "The email template {name} is invalid: {reason}")
+            }
+            MailError::Message(err) => write!(f, "Unable to build email: {err}"),
+            MailError::Smtp(err) => write!(f, "Unable to send email: {err}"),
+        }
+    }
+}
+
+/// A security event worth emailing the user about.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Notification {
+    /// Logged in from a device none of the other sessions of the user is on.
+    NewLogin {
+        /// Label of the device, see [`crate::auth::device_label`].
+        device: String,
+        ip: Option<String>,
+        at: i64,
+    },
+    /// The user scheduled their account for deletion at the timestamp.
+    DeletionScheduled { delete_at: i64 },
+    /// Exported at least [`ServerOptions::export_notification_threshold`] codes.
+    LargeExport {
+        /// Name of the format, like `Aegis`.
+        format: &'static str,
+        codes: usize,
+        at: i64,
+    },
+}
+
+impl Notification {
+    fn template(&self) -> &'static str {
+        match self {
+            Notification::NewLogin { .. } => "new_login",
+            Notification::DeletionScheduled { .. } => "deletion_scheduled",
+            Notification::LargeExport { .. } => "large_export",
+        }
+    }
+
+    fn variables(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Notification::NewLogin { device, ip, at } => vec![
+                ("device", device.clone()),
+                ("ip", ip.clone().unwrap_or_else(|| "Unknown address".into())),
+                ("time", format_time(*at)),
+            ],
+            Notification::DeletionScheduled { delete_at } => {
+                vec![("delete_at", format_time(*delete_at))]
+            }
+            Notification::LargeExport { format, codes, at } => vec![
+                ("format", format.to_string()),
+                ("codes", codes.to_string()),
+                ("time", format_time(*at)),
+            ],
+        }
+    }
+}
+
+fn format_time(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_default()
+}
+
+/// Names of the variables used in the text, like `username` for `{{username}}`.
+fn placeholders(text: &str) -> impl Iterator<Item = &str> {
+    text.split("{{")
+        .skip(1)
+        .filter_map(|part| part.split_once("}}"))
+        .map(|(name, _)| name.trim())
+}
+
+/// Replaces `{{variable}}` with its value. Unknown variables are left as they are.
+fn render(text: &str, variables: &[(&str, String)]) -> String {
+    let mut rendered = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let Some(length) = rest[start..].find("}}") else {
+            break;
+        };
+        let placeholder = &rest[start..start + length + 2];
+        let name = placeholder[2..placeholder.len() - 2].trim();
+
+        rendered.push_str(&rest[..start]);
+        match variables.iter().find(|(variable, _)| *variable == name) {
+            Some((_, value)) => rendered.push_str(value),
+            None => rendered.push_str(placeholder),
+        }
+        rest = &rest[start + length + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// A plain text email, with a subject line.
+#[derive(Clone, Debug, PartialEq)]
+struct Template {
+    subject: String,
+    body: String,
+}
+
+impl Template {
+    /// Parses a `Subject:` line, an empty line and the body, checking it only uses the variables.
+    fn parse(name: &'static str, text: &str, variables: &[&str]) -> Result<Self, MailError> {
+        let text = text.replace("\r\n", "\n");
+        let (subject, body) = text
+            .split_once('\n')
+            .and_then(|(first, rest)| Some((first.strip_prefix("Subject:")?, rest)))
+            .and_then(|(subject, rest)| Some((subject, rest.strip_prefix('\n')?)))
+            .ok_or_else(|| {
+                MailError::InvalidTemplate(
+                    name,
+                    "It has to start with a Subject: line, followed by an empty line".into(),
+                )
+            })?;
+
+        for placeholder in placeholders(subject).chain(placeholders(body)) {
+            if !COMMON_VARIABLES.contains(&placeholder) && !variables.contains(&placeholder) {
+                return Err(MailError::InvalidTemplate(
+                    name,
+                    format!("There is no variable {placeholder:?}"),
+                ));
+            }
+        }
+
+        Ok(Template {
+            subject: subject.trim().to_string(),
+            body: body.to_string(),
+        })
+    }
+}
+
+/// The email templates, built-in or replaced by the operator.
+#[derive(Debug)]
+struct Templates {
+    by_name: HashMap<&'static str, Template>,
+}
+
+impl Templates {
+    /// Loads the templates, preferring `{name}.txt` files in the directory over the built-in ones.
+    fn load(directory: Option<&Path>) -> Result<Self, MailError> {
+        let mut by_name = HashMap::new();
+        for (name, builtin, variables) in TEMPLATES {
+            let custom = directory
+                .map(|directory| directory.join(format!("{name}.txt")))
+                .filter(|path| path.exists());
+            let text = match custom {
+                Some(path) => std::fs::read_to_string(&path)
+                    .map_err(|err| MailError::UnableToReadTemplate(path, err))?,
+                None => builtin.to_string(),
+            };
+            by_name.insert(name, Template::parse(name, &text, variables)?);
+        }
+
+        Ok(Templates { by_name })
+    }
+}
+
+/// An email ready to be sent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Clone)]
+enum Transport {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    /// Keeps the emails instead of sending them, see [`Mailer::outbox`].
+    Capture(Arc<Mutex<Vec<Email>>>),
+}
+
+/// Emails users about security events through an SMTP server.
+#[derive(Clone)]
+pub struct Mailer {
+    transport: Transport,
+    from: Mailbox,
+    templates: Arc<Templates>,
+    /// Frontfacing URL, where users can review what happened.
+    instance: String,
+}
+
+impl Mailer {
+    /// The mailer as configured, if an SMTP server is set.
+    pub fn from_options(opts: &ServerOptions) -> Result<Option<Self>, MailError> {
+        let Some(url) = &opts.smtp_url else {
+            return Ok(None);
+        };
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(url)
+            .map_err(MailError::InvalidSmtpUrl)?
+            .build();
+
+        Ok(Some(Mailer::new(Transport::Smtp(transport), opts)?))
+    }
+
+    /// A mailer keeping the emails in its [`Mailer::outbox`] instead of sending them, for tests.
+    /// Only the sender and templates of the options are used.
+    pub fn capturing(opts: &ServerOptions) -> Result<Self, MailError> {
+        Mailer::new(Transport::Capture(Arc::default()), opts)
+    }
+
+    fn new(transport: Transport, opts: &ServerOptions) -> Result<Self, MailError> {
+        Ok(Mailer {
+            transport,
+            from: opts
+                .smtp_from
+                .as_deref()
+                .ok_or(MailError::MissingSender)?
+                .parse()
+                .map_err(MailError::InvalidSender)?,
+            templates: Arc::new(Templates::load(opts.email_templates.as_deref())?),
+            instance: opts.frontfacing.clone(),
+        })
+    }
+
+    /// Emails captured by a [`Mailer::capturing`] mailer, oldest first.
+    pub fn outbox(&self) -> Vec<Email> {
+        match &self.transport {
+            Transport::Smtp(_) => vec![],
+            Transport::Capture(outbox) => outbox.lock().unwrap().clone(),
+        }
+    }
+
+    /// The email about the notification, from its template.
+    pub fn compose(&self, user: &User, to: String, notification: &Notification) -> Email {
+        let template = &self.templates.by_name[notification.template()];
+        let mut variables = notification.variables();
+        variables.extend([
+            ("username", user.username.clone()),
+            ("display_name", user.display_name.clone()),
+            ("instance", self.instance.clone()),
+        ]);
+
+        Email {
+            to,
+            // Values may contain anything, but a subject is a single line
+            subject: render(&template.subject, &variables).replace(['\r', '\n'], " "),
+            body: render(&template.body, &variables),
+        }
+    }
+
+    pub async fn send(&self, email: Email) -> Result<(), MailError> {
+        let transport = match &self.transport {
+            Transport::Smtp(transport) => transport,
+            Transport::Capture(outbox) => {
+                outbox.lock().unwrap().push(email);
+                return Ok(());
+            }
+        };
+
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email.to.parse().map_err(MailError::InvalidRecipient)?)
+            .subject(email.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.body)
+            .map_err(MailError::Message)?;
+        transport.send(message).await.map_err(MailError::Smtp)?;
+        Ok(())
+    }
+}
+
+/// Emails the user about the event in the background, unless the instance doesn't send emails,
+/// the user has no address or they turned the emails off.
+pub fn notify(state: &AppState, user: &User, notification: Notification) {
+    let Some(mailer) = &state.mailer else {
+        return;
+    };
+    let Some(to) = user.email.clone().filter(|_| user.email_notifications) else {
+        return;
+    };
+
+    let email = mailer.compose(user, to, &notification);
+    if let Transport::Capture(outbox) = &mailer.transport {
+        // Kept right away, so tests don't have to wait for the background task
+        outbox.lock().unwrap().push(email);
+        return;
+    }
+    let mailer = mailer.clone();
+    let user_id = user.id.clone();
+    tokio::spawn(async move {
+        match mailer.send(email).await {
+            Ok(()) => debug!("Emailed {user_id} about {}", notification.template()),
+            Err(err) => warn!("Unable to email {user_id}: {err}"),
+        }
+    });
+}
+
+/// Emails the user about the session if it's on a device none of their other sessions is on.
+pub async fn notify_login(
+    state: &AppState,
+    user: &User,
+    session_id: &str,
+) -> Result<(), sqlx::Error> {
+    if state.mailer.is_none() || user.email.is_none() || !user.email_notifications {
+        return Ok(());
+    }
+
+    let sessions = Session::get_for_user(&state.db, &user.id).await?;
+    let Some(session) = sessions.iter().find(|session| session.id == session_id) else {
+        return Ok(());
+    };
+    let known_device = sessions
+        .iter()
+        .any(|other| other.id != session.id && other.device == session.device);
+    if !known_device {
+        notify(
+            state,
+            user,
+            Notification::NewLogin {
+                device: session.device.clone(),
+                ip: session.ip.clone(),
+                at: session.created_at,
+            },
+        );
+    }
+
+    Ok(())
+}
+
+/// Emails the user about exporting the codes, if there are enough of them to be worth it.
+pub fn notify_export(state: &AppState, user: &User, format: &'static str, codes: usize) {
+    let threshold = state.settings.export_notification_threshold;
+    if threshold != 0 && codes as u64 >= threshold {
+        notify(
+            state,
+            user,
+            Notification::LargeExport {
+                format,
+                codes,
+                at: chrono::Utc::now().timestamp(),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    #[gtest]
+    fn builtin_templates_are_valid() {
+        let templates = Templates::load(None).unwrap();
+        expect_that!(templates.by_name.len(), eq(TEMPLATES.len()));
+    }
+
+    #[gtest]
+    fn templates_are_rendered() {
+        let template = Template::parse(
+            "new_login",
+            "Subject: Login from {{device}}\n\nHi {{ username }}, {{device}} at {{time}}. {{ip",
+            &["device", "ip", "time"],
+        )
+        .unwrap();
+        let variables = vec![
+            ("device", "Firefox on Linux".to_string()),
+            ("username", "user1".to_string()),
+        ];
+
+        expect_that!(
+            render(&template.subject, &variables),
+            eq("Login from Firefox on Linux")
+        );
+        expect_that!(
+            render(&template.body, &variables),
+            eq("Hi user1, Firefox on Linux at {{time}}. {{ip")
+        );
+    }
+
+    #[gtest]
+    fn invalid_templates_are_rejected() {
+        expect_that!(
+            Template::parse("new_login", "Hi {{username}}", &[]),
+            err(anything())
+        );
+        expect_that!(
+            Template::parse("new_login", "Subject: Hi\nNo empty line", &[]),
+            err(anything())
+        );
+        expect_that!(
+            Template::parse("new_login", "Subject: Hi\n\n{{password}}", &["device"]),
+            err(anything())
+        );
+    }
+}
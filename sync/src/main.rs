@@ -1,10 +1,20 @@
 use iceblink_sync::cli;
+use iceblink_sync::icons::{self, IconStore};
+use iceblink_sync::interop;
+use iceblink_sync::models::{
+    codes::Code,
+    folders::Folder,
+    passwords::{Password, PasswordReset},
+    user::User,
+};
 use iceblink_sync::ServerOptions;
 use std::error::Error;
-use tracing::info;
+use std::process::ExitCode;
+use std::time::Duration;
+use tracing::{error, info, warn};
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() -> Result<ExitCode, Box<dyn Error>> {
     let _ = dotenvy::dotenv();
     let settings = cli::get_settings();
 
@@ -24,28 +34,270 @@ async fn main() -> Result<(), Box<dyn Error>> {
             client_id,
             client_secret,
             oauth_server,
+            openid_providers,
+            native_redirect_schemes,
             jwt_secret,
+            jwt_algorithm,
+            jwt_private_key,
+            jwt_previous_secret,
+            jwt_previous_private_key,
+            jwt_lifetime,
+            jwt_renewal_window,
+            session_idle_timeout,
+            totp_skew,
             redirect_uri,
             frontfacing,
+            max_connections_per_ip,
+            trusted_proxies,
+            registration,
+            registrations_per_hour,
+            requests_per_minute,
+            login_requests_per_minute,
+            write_requests_per_minute,
+            max_codes_per_user,
+            max_icon_bytes_per_user,
+            captcha_verify_url,
+            captcha_secret,
+            local_auth,
+            http_proxy,
+            vapid_private_key,
+            vapid_subject,
+            smtp_url,
+            smtp_from,
+            email_templates,
+            export_notification_threshold,
+            unauthenticated_redirect,
+            tombstone_retention,
+            trash_retention_days,
+            account_deletion_grace_days,
+            slow_query_threshold,
+            trailing_slash,
+            html_cache_control,
+            backup_directory,
+            icon_directory,
         } => {
             info!("Iceblink Sync Server");
 
-            iceblink_sync::serve(ServerOptions {
+            let result = iceblink_sync::serve(ServerOptions {
                 port: port.unwrap_or(8085),
                 client_id: client_id.to_string(),
                 client_secret: client_secret.to_string(),
                 oauth_server: oauth_server
                     .clone()
                     .unwrap_or("https://pfapi.snowflake.blue".to_string()),
+                openid_providers: openid_providers.clone(),
+                native_redirect_schemes: native_redirect_schemes
+                    .clone()
+                    .unwrap_or(vec!["iceblink".to_string()]),
                 redirect_uri: redirect_uri.to_string(),
                 jwt_secret: jwt_secret.to_string(),
+                jwt_algorithm: jwt_algorithm.unwrap_or(cli::JwtAlgorithm::Hs256),
+                jwt_private_key: jwt_private_key.clone(),
+                jwt_previous_secret: jwt_previous_secret.clone(),
+                jwt_previous_private_key: jwt_previous_private_key.clone(),
+                jwt_lifetime: Duration::from_secs(jwt_lifetime.unwrap_or(90 * 24 * 60 * 60)),
+                jwt_renewal_window: Duration::from_secs(jwt_renewal_window.unwrap_or(0)),
+                session_idle_timeout: Duration::from_secs(
+                    session_idle_timeout.unwrap_or(30 * 24 * 60 * 60),
+                ),
+                totp_skew: totp_skew.unwrap_or(1),
                 frontfacing: frontfacing
                     .clone()
                     .unwrap_or("http://localhost:8085".to_string()),
+                max_connections_per_ip: max_connections_per_ip.unwrap_or(64),
+                trusted_proxies: trusted_proxies.clone(),
+                registration: registration.unwrap_or(cli::RegistrationPolicy::Open),
+                registrations_per_hour: registrations_per_hour.unwrap_or(10),
+                requests_per_minute: requests_per_minute.unwrap_or(600),
+                login_requests_per_minute: login_requests_per_minute.unwrap_or(30),
+                write_requests_per_minute: write_requests_per_minute.unwrap_or(120),
+                max_codes_per_user: max_codes_per_user.unwrap_or(0),
+                max_icon_bytes_per_user: max_icon_bytes_per_user.unwrap_or(0),
+                captcha_verify_url: captcha_verify_url.clone(),
+                captcha_secret: captcha_secret.clone(),
+                local_auth: *local_auth,
+                http_proxy: http_proxy.clone(),
+                vapid_private_key: vapid_private_key.clone(),
+                vapid_subject: vapid_subject.clone(),
+                smtp_url: smtp_url.clone(),
+                smtp_from: smtp_from.clone(),
+                email_templates: email_templates.clone(),
+                export_notification_threshold: export_notification_threshold.unwrap_or(10),
+                unauthenticated_redirect: unauthenticated_redirect
+                    .clone()
+                    .unwrap_or("/".to_string()),
+                tombstone_retention: Duration::from_secs(
+                    tombstone_retention.unwrap_or(90 * 24 * 60 * 60),
+                ),
+                trash_retention: Duration::from_secs(
+                    trash_retention_days.unwrap_or(30) * 24 * 60 * 60,
+                ),
+                account_deletion_grace: Duration::from_secs(
+                    account_deletion_grace_days.unwrap_or(7) * 24 * 60 * 60,
+                ),
+                slow_query_threshold: Duration::from_millis(slow_query_threshold.unwrap_or(250)),
+                trailing_slash: trailing_slash.unwrap_or(cli::TrailingSlash::Rewrite),
+                html_cache_control: html_cache_control.unwrap_or(cli::HtmlCacheControl::NoCache),
+                backup_directory: backup_directory.clone().unwrap_or("backups".to_string()),
+                icon_directory: icon_directory
+                    .clone()
+                    .unwrap_or(iceblink_sync::ICON_DIRECTORY.to_string()),
             })
             .await;
+
+            if let Err(err) = result {
+                error!("{err}");
+                return Ok(ExitCode::from(err.exit_code()));
+            }
+        }
+        cli::Commands::Icons {
+            command:
+                cli::IconCommands::Refresh {
+                    owner,
+                    icon_directory,
+                },
+        } => {
+            let pool = iceblink_sync::connect_database().await?;
+            let store = IconStore::new_with_custom_base(
+                icon_directory
+                    .as_deref()
+                    .unwrap_or(iceblink_sync::ICON_DIRECTORY)
+                    .into(),
+            );
+            store
+                .init()
+                .await
+                .map_err(|err| format!("Unable to create icon directory: {err:?}"))?;
+
+            let report =
+                icons::refresh_icons(&pool, &store, owner.clone(), Duration::from_millis(250))
+                    .await?;
+
+            for (domain, err) in &report.failed {
+                warn!("Unable to refresh icon for {domain}: {err:?}");
+            }
+
+            info!(
+                "Refreshed {} icons, {} failed",
+                report.refreshed.len(),
+                report.failed.len()
+            );
+        }
+        cli::Commands::Codes {
+            command:
+                cli::CodeCommands::Import {
+                    format,
+                    owner,
+                    password,
+                    file,
+                },
+        } => {
+            let backup = interop::Format::from(*format)
+                .read(&std::fs::read(file)?, password.as_deref())
+                .map_err(|err| format!("Unable to read the backup: {err:?}"))?;
+
+            let pool = iceblink_sync::connect_database().await?;
+            match User::get_by_id(&pool, owner.clone()).await? {
+                None => return Err(format!("No user has the id {owner}").into()),
+                Some(user) if user.encryption_enabled => {
+                    return Err(format!(
+                        "{owner} uses end-to-end encryption, so plaintext backups can't be imported"
+                    )
+                    .into())
+                }
+                Some(_) => {}
+            }
+            let (codes, folders) = interop::store(&pool, owner, backup.codes).await?;
+
+            for name in &backup.skipped {
+                warn!("Skipped {name}, which isn't a TOTP code");
+            }
+
+            info!(
+                "Imported {} codes, created {} folders, skipped {}",
+                codes.len(),
+                folders.len(),
+                backup.skipped.len()
+            );
+        }
+        cli::Commands::Codes {
+            command:
+                cli::CodeCommands::Export {
+                    format,
+                    owner,
+                    password,
+                    file,
+                },
+        } => {
+            if password.as_deref() == Some("") {
+                return Err("The password can't be empty".into());
+            }
+
+            let pool = iceblink_sync::connect_database().await?;
+            if let Some(user) = User::get_by_id(&pool, owner.clone()).await? {
+                if user.encryption_enabled {
+                    return Err(format!(
+                        "{owner} uses end-to-end encryption, so their secrets can't be exported"
+                    )
+                    .into());
+                }
+            }
+            let codes = Code::get_many(&pool, owner.clone()).await?;
+            let count = codes.len();
+            let backup = interop::Format::from(*format)
+                .write(
+                    codes,
+                    Folder::get_many(&pool, owner).await?,
+                    password.as_deref(),
+                )
+                .ok_or(format!("Backups can't be exported as {format:?}"))?;
+            std::fs::write(file, backup)?;
+
+            info!("Exported {count} codes to {}", file.display());
+        }
+        cli::Commands::Users { command } => {
+            let user = match command {
+                cli::UserCommands::Promote { user }
+                | cli::UserCommands::Demote { user }
+                | cli::UserCommands::ResetPassword { user } => user,
+            };
+
+            let pool = iceblink_sync::connect_database().await?;
+            let found = match User::get_by_id(&pool, user.clone()).await? {
+                Some(found) => Some(found),
+                None => User::get_by_username(&pool, user.clone()).await?,
+            };
+            let Some(mut found) = found else {
+                return Err(format!("No user has the id or username {user}").into());
+            };
+
+            match command {
+                cli::UserCommands::Promote { .. } => {
+                    found.set_admin(&pool, true).await?;
+                    info!("{} ({}) is an admin now", found.username, found.id);
+                }
+                cli::UserCommands::Demote { .. } => {
+                    found.set_admin(&pool, false).await?;
+                    info!("{} ({}) is no admin anymore", found.username, found.id);
+                }
+                cli::UserCommands::ResetPassword { .. } => {
+                    if Password::get_for_user(&pool, &found.id).await?.is_none() {
+                        return Err(format!("{} logs in without a password", found.username).into());
+                    }
+                    let (reset, token) =
+                        PasswordReset::generate(found.id, chrono::Utc::now().timestamp());
+                    reset.insert(&pool).await?;
+                    info!(
+                        "{} can set a new password with this token until {}:",
+                        found.username,
+                        chrono::DateTime::from_timestamp(reset.expires_at, 0)
+                            .expect("Expiry is a valid timestamp")
+                    );
+                    println!("{token}");
+                }
+            }
         }
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
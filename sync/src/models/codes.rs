@@ -1,5 +1,7 @@
+use super::{revisions, tags, timed};
+use crate::totp;
 use serde::{Deserialize, Serialize};
-use sqlx::SqlitePool;
+use sqlx::{QueryBuilder, Sqlite, SqliteConnection, SqliteExecutor, SqlitePool, Transaction};
 
 #[derive(Serialize, Deserialize, Clone, Debug, sqlx::FromRow, utoipa::ToSchema, PartialEq)]
 pub struct Code {
@@ -9,121 +11,1009 @@ pub struct Code {
     pub display_name: String,
     pub icon_url: Option<String>,
     pub website_url: Option<String>,
+    /// Service the code is for, as named by its otpauth URI.
+    pub issuer: Option<String>,
+    /// Account at the issuer, as named by its otpauth URI.
+    pub account: Option<String>,
+    /// Format of the generated codes: `totp`, or `steam` for Steam Guard codes.
+    pub kind: String,
+    pub digits: i64,
+    /// Seconds every code is valid for.
+    pub period: i64,
+    /// Hash function used to generate codes: `SHA1`, `SHA256` or `SHA512`.
+    pub algorithm: String,
+    /// Unix timestamp (seconds) after which the code is no longer served, and gets removed.
+    pub expires_at: Option<i64>,
+    /// Position of the code in the owner's listing, ascending.
+    pub sort_index: i64,
+    /// Whether the owner marked the code as a favorite.
+    pub favorite: bool,
+    /// Folder the code is in. Codes without a folder are at the top level.
+    pub folder_id: Option<String>,
+    /// Unix timestamp (seconds) at which the code was added.
+    pub created_at: i64,
+    /// Unix timestamp (seconds) of the last change to the code.
+    pub updated_at: i64,
+    /// Sync revision of the last change to the code, see [`Code::changes_since`].
+    pub revision: i64,
+    /// Unix timestamp (seconds) at which the code was deleted. Deleted codes are kept around as
+    /// tombstones for syncing clients, and are only served from the trash.
+    #[serde(skip)]
+    pub deleted_at: Option<i64>,
+}
+
+/// Claims the next sync revision. Has to run in the transaction making the change, so changes
+/// become visible in the order of their revisions, as SQLite only allows a single writer.
+async fn next_revision(conn: &mut SqliteConnection) -> Result<i64, sqlx::error::Error> {
+    sqlx::query_scalar!(
+        r#"UPDATE sync_revision SET value = value + 1 RETURNING value as "value!: i64""#
+    )
+    .fetch_one(conn)
+    .await
+}
+
+/// Direction to move a code in the owner's listing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Move {
+    Up,
+    Down,
+}
+
+/// Order of the codes in a listing.
+#[derive(Deserialize, utoipa::ToSchema, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CodeSort {
+    /// As arranged by the owner.
+    #[default]
+    Position,
+    /// Alphabetically by name, ignoring case.
+    Name,
+    /// Most recently changed first.
+    Updated,
+    /// Most recently added first.
+    Created,
+}
+
+impl CodeSort {
+    fn order_by(self) -> &'static str {
+        match self {
+            CodeSort::Position => "sort_index, rowid",
+            CodeSort::Name => "display_name COLLATE NOCASE, sort_index, rowid",
+            CodeSort::Updated => "updated_at DESC, sort_index, rowid",
+            CodeSort::Created => "created_at DESC, sort_index, rowid",
+        }
+    }
 }
 
 #[bon::bon]
 impl Code {
+    pub const MAX_IDS_PER_QUERY: usize = 500;
+    /// Longest secret, leaving room for secrets encrypted end-to-end.
+    pub const MAX_CONTENT_LENGTH: usize = 1024;
+    /// Longest name, leaving room for names encrypted end-to-end.
+    pub const MAX_DISPLAY_NAME_LENGTH: usize = 512;
+    /// Longest issuer and account.
+    pub const MAX_LABEL_LENGTH: usize = 256;
+    pub const MAX_WEBSITE_URL_LENGTH: usize = 2048;
+
+    /// Text fields of the code with their longest allowed length. Keeps the otpauth URIs of codes
+    /// short enough for QR codes in most cases.
+    pub fn limited_fields(&self) -> [(&'static str, Option<&str>, usize); 5] {
+        [
+            (
+                "content",
+                Some(self.content.as_str()),
+                Code::MAX_CONTENT_LENGTH,
+            ),
+            (
+                "display_name",
+                Some(self.display_name.as_str()),
+                Code::MAX_DISPLAY_NAME_LENGTH,
+            ),
+            ("issuer", self.issuer.as_deref(), Code::MAX_LABEL_LENGTH),
+            ("account", self.account.as_deref(), Code::MAX_LABEL_LENGTH),
+            (
+                "website_url",
+                self.website_url.as_deref(),
+                Code::MAX_WEBSITE_URL_LENGTH,
+            ),
+        ]
+    }
+
     pub async fn get(
-        pool: &SqlitePool,
+        executor: impl SqliteExecutor<'_>,
         id: String,
         owner_id: String,
     ) -> Result<Option<Code>, sqlx::error::Error> {
-        sqlx::query_as!(
-            Code,
-            "SELECT * FROM codes WHERE id = ? AND owner_id = ?",
-            id,
-            owner_id
+        let now = chrono::Utc::now().timestamp();
+
+        timed(
+            "codes.get",
+            sqlx::query_as!(
+                Code,
+                "SELECT * FROM codes WHERE id = ? AND owner_id = ? AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?)",
+                id,
+                owner_id,
+                now
+            )
+            .fetch_optional(executor),
         )
-        .fetch_optional(pool)
         .await
     }
 
     pub async fn get_many(
+        executor: impl SqliteExecutor<'_>,
+        owner_id: String,
+    ) -> Result<Vec<Code>, sqlx::error::Error> {
+        let now = chrono::Utc::now().timestamp();
+
+        timed(
+            "codes.get_many",
+            sqlx::query_as!(
+                Code,
+                "SELECT * FROM codes WHERE owner_id = ? AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?) ORDER BY sort_index, rowid",
+                owner_id,
+                now
+            )
+            .fetch_all(executor),
+        )
+        .await
+    }
+
+    /// A page of the owner's codes in the given order, alongside the amount of codes in total.
+    /// Without a limit, every code after the offset is returned. With a tag, only codes carrying
+    /// it are included, in the page as well as the total. Read within a transaction, the total
+    /// matches the page, as does anything else read in it.
+    pub async fn get_page(
+        conn: &mut SqliteConnection,
+        owner_id: String,
+        sort: CodeSort,
+        tag: Option<String>,
+        limit: Option<i64>,
+        offset: i64,
+    ) -> Result<(Vec<Code>, i64), sqlx::error::Error> {
+        let now = chrono::Utc::now().timestamp();
+
+        timed("codes.get_page", async {
+            let total = sqlx::query_scalar!(
+                "SELECT COUNT(*) FROM codes WHERE owner_id = $1 AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > $2) AND ($3 IS NULL OR id IN (SELECT code_tags.code_id FROM code_tags JOIN tags ON tags.id = code_tags.tag_id WHERE tags.owner_id = $1 AND tags.name = $3))",
+                owner_id,
+                now,
+                tag
+            )
+            .fetch_one(&mut *conn)
+            .await?;
+
+            let mut query: QueryBuilder<Sqlite> =
+                QueryBuilder::new("SELECT * FROM codes WHERE owner_id = ");
+            query
+                .push_bind(owner_id.clone())
+                .push(" AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ")
+                .push_bind(now)
+                .push(")");
+            if let Some(tag) = tag {
+                query
+                    .push(" AND id IN (SELECT code_tags.code_id FROM code_tags JOIN tags ON tags.id = code_tags.tag_id WHERE tags.owner_id = ")
+                    .push_bind(owner_id)
+                    .push(" AND tags.name = ")
+                    .push_bind(tag)
+                    .push(")");
+            }
+            query
+                .push(" ORDER BY ")
+                .push(sort.order_by())
+                // SQLite treats a negative limit as no limit at all
+                .push(" LIMIT ")
+                .push_bind(limit.unwrap_or(-1))
+                .push(" OFFSET ")
+                .push_bind(offset);
+            let codes = query.build_query_as::<Code>().fetch_all(&mut *conn).await?;
+
+            Ok((codes, total))
+        })
+        .await
+    }
+
+    /// Codes with any of the given ids, skipping unknown ids and those owned by someone else.
+    /// Callers should stay below [`Code::MAX_IDS_PER_QUERY`] ids, as every id is a bound parameter.
+    pub async fn get_many_by_ids(
         pool: &SqlitePool,
         owner_id: String,
+        ids: &[String],
     ) -> Result<Vec<Code>, sqlx::error::Error> {
-        sqlx::query_as!(Code, "SELECT * FROM codes WHERE owner_id = ?", owner_id)
-            .fetch_all(pool)
-            .await
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        let mut query: QueryBuilder<Sqlite> =
+            QueryBuilder::new("SELECT * FROM codes WHERE owner_id = ");
+        query
+            .push_bind(owner_id)
+            .push(" AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ")
+            .push_bind(now)
+            .push(") AND id IN (");
+
+        let mut separated = query.separated(", ");
+        for id in ids {
+            separated.push_bind(id.clone());
+        }
+        separated.push_unseparated(") ORDER BY sort_index, rowid");
+
+        timed(
+            "codes.get_many_by_ids",
+            query.build_query_as::<Code>().fetch_all(pool),
+        )
+        .await
     }
 
-    pub async fn insert(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
-        sqlx::query!(
-			"INSERT INTO codes (id, owner_id, content, display_name, icon_url, website_url) VALUES ($1, $2, $3, $4, $5, $6)",
-			self.id, self.owner_id, self.content, self.display_name, self.icon_url, self.website_url).execute(pool).await?;
+    /// The owner's codes matching every word of the search, best match first. Words match the
+    /// start of any word in the name or website, so `git` finds `GitHub`.
+    pub async fn search(
+        pool: &SqlitePool,
+        owner_id: String,
+        search: &str,
+        limit: i64,
+    ) -> Result<Vec<Code>, sqlx::error::Error> {
+        let now = chrono::Utc::now().timestamp();
+        // Quoting every word keeps FTS5 operators like `OR` and `-` in the search literal
+        let terms = search
+            .split_whitespace()
+            .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(" ");
 
-        Ok(())
+        // Not checked at compile time, as sqlx can't describe queries on virtual tables
+        timed(
+            "codes.search",
+            sqlx::query_as::<_, Code>(
+                "SELECT codes.* FROM codes_search JOIN codes ON codes.rowid = codes_search.rowid WHERE codes_search MATCH $1 AND codes.owner_id = $2 AND codes.deleted_at IS NULL AND (codes.expires_at IS NULL OR codes.expires_at > $3) ORDER BY codes_search.rank, codes.sort_index LIMIT $4",
+            )
+            .bind(terms)
+            .bind(owner_id)
+            .bind(now)
+            .bind(limit)
+            .fetch_all(pool),
+        )
+        .await
     }
 
-    pub async fn delete(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
-        sqlx::query!("DELETE FROM codes WHERE id = $1", self.id)
-            .execute(pool)
-            .await?;
+    /// Distinct websites of all codes, optionally limited to a single owner.
+    pub async fn website_urls(
+        pool: &SqlitePool,
+        owner_id: Option<String>,
+    ) -> Result<Vec<String>, sqlx::error::Error> {
+        timed(
+            "codes.website_urls",
+            sqlx::query_scalar!(
+                r#"SELECT DISTINCT website_url as "website_url!" FROM codes WHERE website_url IS NOT NULL AND deleted_at IS NULL AND ($1 IS NULL OR owner_id = $1)"#,
+                owner_id
+            )
+            .fetch_all(pool),
+        )
+        .await
+    }
 
-        Ok(())
+    /// Codes with an icon or website whose icon isn't stored yet, of every owner.
+    pub async fn missing_icons(pool: &SqlitePool) -> Result<Vec<Code>, sqlx::error::Error> {
+        let now = chrono::Utc::now().timestamp();
+
+        timed(
+            "codes.missing_icons",
+            sqlx::query_as!(
+                Code,
+                "SELECT * FROM codes WHERE (icon_url IS NOT NULL OR website_url IS NOT NULL) AND id NOT IN (SELECT code_id FROM code_icons) AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?)",
+                now
+            )
+            .fetch_all(pool),
+        )
+        .await
     }
 
-    #[builder]
-    pub async fn edit(
-        &mut self,
+    /// Stores the code, setting its revision and time of the last change.
+    pub async fn insert(&mut self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        Code::insert_many(pool, std::slice::from_mut(self)).await
+    }
+
+    /// Inserts all codes in a single transaction, so either all or none of them are stored.
+    pub async fn insert_many(
         pool: &SqlitePool,
-        content: Option<String>,
-        display_name: Option<String>,
-        icon_url: Option<Option<String>>,
-        website_url: Option<Option<String>>,
-    ) -> Result<&Code, sqlx::error::Error> {
-        let mut tx = pool.begin().await?;
+        codes: &mut [Code],
+    ) -> Result<(), sqlx::error::Error> {
+        let mut batch = CodeBatch::begin(pool).await?;
+        for code in codes {
+            batch.insert(code).await?;
+        }
+        batch.commit().await
+    }
+
+    /// Sort index placing a new code at the end of the owner's listing.
+    pub async fn next_sort_index(
+        executor: impl SqliteExecutor<'_>,
+        owner_id: &str,
+    ) -> Result<i64, sqlx::error::Error> {
+        timed(
+            "codes.next_sort_index",
+            sqlx::query_scalar!(
+                r#"SELECT COALESCE(MAX(sort_index) + 1, 0) as "sort_index!: i64" FROM codes WHERE owner_id = $1"#,
+                owner_id
+            )
+            .fetch_one(executor),
+        )
+        .await
+    }
 
-        if let Some(content_inner) = content {
+    /// Amount of codes of the owner, neither deleted nor expired, and the bytes of the icons
+    /// embedded in them as `data:` URIs.
+    pub async fn usage(
+        executor: impl SqliteExecutor<'_>,
+        owner_id: &str,
+    ) -> Result<(i64, i64), sqlx::error::Error> {
+        let now = chrono::Utc::now().timestamp();
+        let usage = timed(
+            "codes.usage",
             sqlx::query!(
-                "UPDATE codes SET content = $2 WHERE id = $1",
-                self.id,
-                content_inner
+                r#"SELECT COUNT(*) as "codes!: i64", COALESCE(SUM(CASE WHEN icon_url LIKE 'data:%' THEN LENGTH(icon_url) ELSE 0 END), 0) as "icon_bytes!: i64" FROM codes WHERE owner_id = $1 AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > $2)"#,
+                owner_id,
+                now
             )
-            .execute(&mut *tx)
-            .await?;
+            .fetch_one(executor),
+        )
+        .await?;
 
-            self.content = content_inner;
-        }
+        Ok((usage.codes, usage.icon_bytes))
+    }
 
-        if let Some(display_name_inner) = display_name {
+    /// Id of a code of the owner with the same secret. Base32 secrets are compared ignoring case,
+    /// spaces and padding, other contents have to match exactly.
+    pub async fn find_by_secret(
+        executor: impl SqliteExecutor<'_>,
+        owner_id: &str,
+        secret: &str,
+    ) -> Result<Option<String>, sqlx::error::Error> {
+        let now = chrono::Utc::now().timestamp();
+        let codes = timed(
+            "codes.find_by_secret",
             sqlx::query!(
-                "UPDATE codes SET display_name = $2 WHERE id = $1",
+                "SELECT id, content FROM codes WHERE owner_id = $1 AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > $2) ORDER BY sort_index, rowid",
+                owner_id,
+                now
+            )
+            .fetch_all(executor),
+        )
+        .await?;
+
+        let key = totp::decode_secret(secret);
+        Ok(codes
+            .into_iter()
+            .find(|code| match (&key, totp::decode_secret(&code.content)) {
+                (Some(key), Some(other)) => *key == other,
+                _ => code.content == secret,
+            })
+            .map(|code| code.id))
+    }
+
+    /// Whether another code of the owner in the same folder already has the name.
+    pub async fn name_taken(
+        executor: impl SqliteExecutor<'_>,
+        owner_id: &str,
+        folder_id: Option<&str>,
+        display_name: &str,
+        except_id: Option<&str>,
+    ) -> Result<bool, sqlx::error::Error> {
+        timed(
+            "codes.name_taken",
+            sqlx::query_scalar!(
+                r#"SELECT EXISTS(SELECT 1 FROM codes WHERE owner_id = $1 AND folder_id IS $2 AND display_name = $3 AND id IS NOT $4 AND deleted_at IS NULL) as "taken!: bool""#,
+                owner_id,
+                folder_id,
+                display_name,
+                except_id
+            )
+            .fetch_one(executor),
+        )
+        .await
+    }
+
+    /// Codes directly in the folder, expired ones included.
+    pub async fn get_in_folder(
+        executor: impl SqliteExecutor<'_>,
+        folder_id: &str,
+    ) -> Result<Vec<Code>, sqlx::error::Error> {
+        timed(
+            "codes.get_in_folder",
+            sqlx::query_as!(
+                Code,
+                "SELECT * FROM codes WHERE folder_id = $1 AND deleted_at IS NULL ORDER BY sort_index, rowid",
+                folder_id
+            )
+            .fetch_all(executor),
+        )
+        .await
+    }
+
+    /// Swaps the sort index of the code with its neighbour in the given direction, returning the
+    /// neighbour as changed. Does nothing when the code already is at the respective end of the
+    /// listing.
+    pub async fn shift(
+        &mut self,
+        pool: &SqlitePool,
+        direction: Move,
+    ) -> Result<Option<Code>, sqlx::error::Error> {
+        timed("codes.shift", async {
+            let now = chrono::Utc::now().timestamp();
+            let mut tx = pool.begin().await?;
+
+            let sort_index = sqlx::query_scalar!(
+                "SELECT sort_index FROM codes WHERE id = $1 AND owner_id = $2 AND deleted_at IS NULL",
                 self.id,
-                display_name_inner
+                self.owner_id
             )
-            .execute(&mut *tx)
-            .await?;
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)?;
 
-            self.display_name = display_name_inner;
-        }
+            let neighbour = match direction {
+                Move::Up => sqlx::query_as!(
+                    Code,
+                    "SELECT * FROM codes WHERE owner_id = $1 AND sort_index < $2 AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > $3) ORDER BY sort_index DESC LIMIT 1",
+                    self.owner_id,
+                    sort_index,
+                    now
+                )
+                .fetch_optional(&mut *tx)
+                .await?,
+                Move::Down => sqlx::query_as!(
+                    Code,
+                    "SELECT * FROM codes WHERE owner_id = $1 AND sort_index > $2 AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > $3) ORDER BY sort_index ASC LIMIT 1",
+                    self.owner_id,
+                    sort_index,
+                    now
+                )
+                .fetch_optional(&mut *tx)
+                .await?,
+            };
 
-        if let Some(icon_url_inner) = icon_url {
+            let Some(mut neighbour) = neighbour else {
+                self.sort_index = sort_index;
+                return Ok::<_, sqlx::Error>(None);
+            };
+
+            // Both codes moved, so both are changed for syncing clients
+            let revision = next_revision(&mut tx).await?;
             sqlx::query!(
-                "UPDATE codes SET icon_url = $2 WHERE id = $1",
-                self.id,
-                icon_url_inner
+                "UPDATE codes SET sort_index = $1, updated_at = $2, revision = $3 WHERE id = $4",
+                neighbour.sort_index,
+                now,
+                revision,
+                self.id
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "UPDATE codes SET sort_index = $1, updated_at = $2, revision = $3 WHERE id = $4",
+                sort_index,
+                now,
+                revision,
+                neighbour.id
             )
             .execute(&mut *tx)
             .await?;
 
-            self.icon_url = icon_url_inner;
-        }
+            tx.commit().await?;
+            self.sort_index = neighbour.sort_index;
+            self.updated_at = now;
+            self.revision = revision;
+            neighbour.sort_index = sort_index;
+            neighbour.updated_at = now;
+            neighbour.revision = revision;
+
+            Ok::<_, sqlx::Error>(Some(neighbour))
+        })
+        .await
+    }
 
-        if let Some(website_url_inner) = website_url {
+    /// Deletes the code in a transaction of its own, see [`CodeBatch::delete`].
+    pub async fn delete(
+        &self,
+        pool: &SqlitePool,
+        expected_revision: Option<i64>,
+    ) -> Result<(), sqlx::error::Error> {
+        let mut batch = CodeBatch::begin(pool).await?;
+        batch.delete(self, expected_revision).await?;
+        batch.commit().await
+    }
+
+    /// Codes of the owner changed after the given revision, deleted ones included, oldest change
+    /// first. Returns them alongside the current revision, which the next sync continues from.
+    /// Returns `None` if tombstones of deletions after the revision have already been purged.
+    pub async fn changes_since(
+        pool: &SqlitePool,
+        owner_id: &str,
+        since: i64,
+    ) -> Result<Option<(Vec<Code>, i64)>, sqlx::error::Error> {
+        timed("codes.changes_since", async {
+            // Reading both in a transaction sees a single snapshot, so no change falls in between
+            let mut tx = pool.begin().await?;
+            let sync = sqlx::query!("SELECT value, purged FROM sync_revision")
+                .fetch_one(&mut *tx)
+                .await?;
+            // Full syncs don't rely on tombstones, as every code which is left is sent
+            if since >= 0 && since < sync.purged {
+                return Ok(None);
+            }
+
+            let codes = sqlx::query_as!(
+                Code,
+                "SELECT * FROM codes WHERE owner_id = $1 AND revision > $2 AND revision <= $3 ORDER BY revision, sort_index",
+                owner_id,
+                since,
+                sync.value
+            )
+            .fetch_all(&mut *tx)
+            .await?;
+            tx.commit().await?;
+
+            Ok(Some((codes, sync.value)))
+        })
+        .await
+    }
+
+    /// Removes tombstones of codes deleted at or before `before`. Returns the amount of removed
+    /// tombstones.
+    pub async fn purge_tombstones(
+        pool: &SqlitePool,
+        before: i64,
+    ) -> Result<u64, sqlx::error::Error> {
+        timed("codes.purge_tombstones", async {
+            let mut tx = pool.begin().await?;
             sqlx::query!(
-                "UPDATE codes SET website_url = $2, icon_url = NULL WHERE id = $1",
-                self.id,
-                website_url_inner
+                "UPDATE sync_revision SET purged = MAX(purged, (SELECT COALESCE(MAX(revision), 0) FROM codes WHERE deleted_at <= $1))",
+                before
             )
             .execute(&mut *tx)
             .await?;
+            let result = sqlx::query!("DELETE FROM codes WHERE deleted_at <= $1", before)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+
+            Ok(result.rows_affected())
+        })
+        .await
+    }
 
-            self.website_url = website_url_inner;
-            self.icon_url = None;
-        };
+    /// Deleted codes of the owner still in the trash, most recently deleted first. Codes deleted at
+    /// or before `after` have left the trash, as have those whose content was cleared.
+    pub async fn get_trash(
+        executor: impl SqliteExecutor<'_>,
+        owner_id: String,
+        after: i64,
+    ) -> Result<Vec<Code>, sqlx::error::Error> {
+        let now = chrono::Utc::now().timestamp();
+
+        timed(
+            "codes.get_trash",
+            sqlx::query_as!(
+                Code,
+                "SELECT * FROM codes WHERE owner_id = ? AND deleted_at > ? AND content != '' AND (expires_at IS NULL OR expires_at > ?) ORDER BY deleted_at DESC, sort_index, rowid",
+                owner_id,
+                after,
+                now
+            )
+            .fetch_all(executor),
+        )
+        .await
+    }
+
+    /// A single code from the owner's trash, see [`Code::get_trash`].
+    pub async fn get_trashed(
+        executor: impl SqliteExecutor<'_>,
+        id: String,
+        owner_id: String,
+        after: i64,
+    ) -> Result<Option<Code>, sqlx::error::Error> {
+        let now = chrono::Utc::now().timestamp();
+
+        timed(
+            "codes.get_trashed",
+            sqlx::query_as!(
+                Code,
+                "SELECT * FROM codes WHERE id = ? AND owner_id = ? AND deleted_at > ? AND content != '' AND (expires_at IS NULL OR expires_at > ?)",
+                id,
+                owner_id,
+                after,
+                now
+            )
+            .fetch_optional(executor),
+        )
+        .await
+    }
+
+    /// Clears the content of codes deleted at or before `before`, emptying them from the trash.
+    /// Their tombstones are left for syncing clients. Returns the amount of emptied codes.
+    pub async fn empty_trash(pool: &SqlitePool, before: i64) -> Result<u64, sqlx::error::Error> {
+        let result = timed(
+            "codes.empty_trash",
+            sqlx::query!(
+                "UPDATE codes SET content = '' WHERE deleted_at <= $1 AND content != ''",
+                before
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Removes every code which expired at or before `now`. Returns the amount of removed codes.
+    pub async fn delete_expired(pool: &SqlitePool, now: i64) -> Result<u64, sqlx::error::Error> {
+        let result = timed(
+            "codes.delete_expired",
+            sqlx::query!(
+                "DELETE FROM codes WHERE expires_at IS NOT NULL AND expires_at <= $1",
+                now
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Updates the given fields in a transaction of its own, see [`CodeBatch::edit`].
+    #[builder]
+    pub async fn edit(
+        &mut self,
+        pool: &SqlitePool,
+        content: Option<String>,
+        display_name: Option<String>,
+        icon_url: Option<Option<String>>,
+        website_url: Option<Option<String>>,
+        params: Option<totp::Params>,
+        expires_at: Option<Option<i64>>,
+        favorite: Option<bool>,
+        folder_id: Option<Option<String>>,
+        tags: Option<Vec<String>>,
+        expected_revision: Option<i64>,
+    ) -> Result<&Code, sqlx::error::Error> {
+        let mut batch = CodeBatch::begin(pool).await?;
+        batch
+            .edit()
+            .code(&mut *self)
+            .maybe_content(content)
+            .maybe_display_name(display_name)
+            .maybe_icon_url(icon_url)
+            .maybe_website_url(website_url)
+            .maybe_params(params)
+            .maybe_expires_at(expires_at)
+            .maybe_favorite(favorite)
+            .maybe_folder_id(folder_id)
+            .maybe_tags(tags)
+            .maybe_expected_revision(expected_revision)
+            .call()
+            .await?;
+        batch.commit().await?;
 
-        tx.commit().await?;
         Ok(self)
     }
 
+    /// How codes are generated from the secret. Unknown kinds fall back to TOTP, and unknown
+    /// algorithms to SHA1.
+    pub fn totp_params(&self) -> totp::Params {
+        totp::Params {
+            kind: totp::Kind::parse(&self.kind).unwrap_or_default(),
+            algorithm: totp::Algorithm::parse(&self.algorithm).unwrap_or_default(),
+            digits: self.digits as u32,
+            period: self.period,
+        }
+    }
+
     pub fn fmt_for_hasher(&self) -> String {
         format!(
-            "{}{}{}{}",
+            "{}{}{}{}{}{}{}{}{}",
             self.content,
             self.display_name,
             self.icon_url.clone().unwrap_or("".to_string()),
-            self.website_url.clone().unwrap_or("".to_string())
+            self.website_url.clone().unwrap_or("".to_string()),
+            self.expires_at.map(|e| e.to_string()).unwrap_or_default(),
+            self.kind,
+            self.digits,
+            self.period,
+            self.algorithm
+        )
+    }
+}
+
+/// Changes to codes made in a single transaction, so either all or none of them are applied.
+/// All changes share a revision and time of the last change, claimed when the batch begins.
+pub struct CodeBatch {
+    tx: Transaction<'static, Sqlite>,
+    revision: i64,
+    now: i64,
+}
+
+#[bon::bon]
+impl CodeBatch {
+    pub async fn begin(pool: &SqlitePool) -> Result<Self, sqlx::error::Error> {
+        let mut tx = pool.begin().await?;
+        let revision = next_revision(&mut tx).await?;
+
+        Ok(Self {
+            tx,
+            revision,
+            now: chrono::Utc::now().timestamp(),
+        })
+    }
+
+    /// Connection of the transaction, seeing the changes made by the batch so far.
+    pub fn conn(&mut self) -> &mut SqliteConnection {
+        &mut self.tx
+    }
+
+    /// Stores the code, setting its revision and time of the last change.
+    pub async fn insert(&mut self, code: &mut Code) -> Result<(), sqlx::error::Error> {
+        timed(
+            "codes.insert",
+            sqlx::query!(
+                "INSERT INTO codes (id, owner_id, content, display_name, icon_url, website_url, issuer, account, kind, digits, period, algorithm, expires_at, sort_index, favorite, folder_id, created_at, updated_at, revision) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $17, $18)",
+                code.id, code.owner_id, code.content, code.display_name, code.icon_url, code.website_url, code.issuer, code.account, code.kind, code.digits, code.period, code.algorithm, code.expires_at, code.sort_index, code.favorite, code.folder_id, self.now, self.revision
+            )
+            .execute(&mut *self.tx),
+        )
+        .await?;
+        code.created_at = self.now;
+        code.updated_at = self.now;
+        code.revision = self.revision;
+
+        Ok(())
+    }
+
+    /// Updates the given fields using a single statement, scoped to the owner of the code.
+    /// Changing the website also resets the icon, and tags replace those the code had. The kind,
+    /// digits, period and algorithm are changed together, as [`totp::Params`]. With an expected
+    /// revision, the code is only updated if it is still at that revision, failing with
+    /// [`sqlx::Error::RowNotFound`] otherwise. Changing the content, name, website or parameters
+    /// records the previous version first, see [`revisions::record`].
+    #[builder]
+    pub async fn edit(
+        &mut self,
+        code: &mut Code,
+        content: Option<String>,
+        display_name: Option<String>,
+        icon_url: Option<Option<String>>,
+        website_url: Option<Option<String>>,
+        params: Option<totp::Params>,
+        expires_at: Option<Option<i64>>,
+        sort_index: Option<i64>,
+        favorite: Option<bool>,
+        folder_id: Option<Option<String>>,
+        tags: Option<Vec<String>>,
+        expected_revision: Option<i64>,
+    ) -> Result<(), sqlx::error::Error> {
+        if content.is_none()
+            && display_name.is_none()
+            && icon_url.is_none()
+            && website_url.is_none()
+            && params.is_none()
+            && expires_at.is_none()
+            && sort_index.is_none()
+            && favorite.is_none()
+            && folder_id.is_none()
+            && tags.is_none()
+        {
+            return Ok(());
+        }
+        // Arranging codes and refreshing icons don't make a version worth rolling back to
+        if content.is_some() || display_name.is_some() || website_url.is_some() || params.is_some()
+        {
+            revisions::record(
+                &mut self.tx,
+                &code.id,
+                &code.owner_id,
+                expected_revision,
+                self.now,
+            )
+            .await?;
+        }
+
+        let mut query: QueryBuilder<Sqlite> = QueryBuilder::new("UPDATE codes SET ");
+        let mut columns = query.separated(", ");
+
+        if let Some(content) = &content {
+            columns.push("content = ");
+            columns.push_bind_unseparated(content.clone());
+        }
+
+        if let Some(display_name) = &display_name {
+            columns.push("display_name = ");
+            columns.push_bind_unseparated(display_name.clone());
+        }
+
+        if let Some(website_url) = &website_url {
+            columns.push("website_url = ");
+            columns.push_bind_unseparated(website_url.clone());
+            columns.push("icon_url = NULL");
+        } else if let Some(icon_url) = &icon_url {
+            columns.push("icon_url = ");
+            columns.push_bind_unseparated(icon_url.clone());
+        }
+
+        if let Some(params) = params {
+            columns.push("kind = ");
+            columns.push_bind_unseparated(params.kind.as_str());
+            columns.push("digits = ");
+            columns.push_bind_unseparated(params.digits);
+            columns.push("period = ");
+            columns.push_bind_unseparated(params.period);
+            columns.push("algorithm = ");
+            columns.push_bind_unseparated(params.algorithm.as_str());
+        }
+
+        if let Some(expires_at) = expires_at {
+            columns.push("expires_at = ");
+            columns.push_bind_unseparated(expires_at);
+        }
+
+        if let Some(sort_index) = sort_index {
+            columns.push("sort_index = ");
+            columns.push_bind_unseparated(sort_index);
+        }
+
+        if let Some(favorite) = favorite {
+            columns.push("favorite = ");
+            columns.push_bind_unseparated(favorite);
+        }
+
+        if let Some(folder_id) = &folder_id {
+            columns.push("folder_id = ");
+            columns.push_bind_unseparated(folder_id.clone());
+        }
+
+        columns.push("updated_at = ");
+        columns.push_bind_unseparated(self.now);
+        columns.push("revision = ");
+        columns.push_bind_unseparated(self.revision);
+
+        query
+            .push(" WHERE id = ")
+            .push_bind(code.id.clone())
+            .push(" AND owner_id = ")
+            .push_bind(code.owner_id.clone())
+            .push(" AND deleted_at IS NULL");
+        if let Some(expected_revision) = expected_revision {
+            query.push(" AND revision = ").push_bind(expected_revision);
+        }
+
+        if timed("codes.edit", query.build().execute(&mut *self.tx))
+            .await?
+            .rows_affected()
+            == 0
+        {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        if let Some(tags) = &tags {
+            self.set_tags(code, tags).await?;
+        }
+        code.updated_at = self.now;
+        code.revision = self.revision;
+
+        if let Some(content) = content {
+            code.content = content;
+        }
+        if let Some(display_name) = display_name {
+            code.display_name = display_name;
+        }
+        if let Some(website_url) = website_url {
+            code.website_url = website_url;
+            code.icon_url = None;
+        } else if let Some(icon_url) = icon_url {
+            code.icon_url = icon_url;
+        }
+        if let Some(params) = params {
+            code.kind = params.kind.as_str().into();
+            code.digits = params.digits as i64;
+            code.period = params.period;
+            code.algorithm = params.algorithm.as_str().into();
+        }
+        if let Some(expires_at) = expires_at {
+            code.expires_at = expires_at;
+        }
+        if let Some(sort_index) = sort_index {
+            code.sort_index = sort_index;
+        }
+        if let Some(favorite) = favorite {
+            code.favorite = favorite;
+        }
+        if let Some(folder_id) = folder_id {
+            code.folder_id = folder_id;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the tags of the code. Doesn't change its revision, so it is meant for codes
+    /// inserted or edited in the same batch.
+    pub async fn set_tags(
+        &mut self,
+        code: &Code,
+        tags: &[String],
+    ) -> Result<(), sqlx::error::Error> {
+        tags::replace(&mut self.tx, &code.owner_id, &code.id, tags).await
+    }
+
+    /// Deletes the code, leaving a tombstone behind for syncing clients. With an expected
+    /// revision, the code is only deleted if it is still at that revision, failing with
+    /// [`sqlx::Error::RowNotFound`] otherwise.
+    pub async fn delete(
+        &mut self,
+        code: &Code,
+        expected_revision: Option<i64>,
+    ) -> Result<(), sqlx::error::Error> {
+        let result = timed(
+            "codes.delete",
+            sqlx::query!(
+                "UPDATE codes SET deleted_at = $1, updated_at = $1, revision = $2 WHERE id = $3 AND deleted_at IS NULL AND ($4 IS NULL OR revision = $4)",
+                self.now,
+                self.revision,
+                code.id,
+                expected_revision
+            )
+            .execute(&mut *self.tx),
         )
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+
+    /// Brings a deleted code back from the trash at its `sort_index`, so syncing clients see it
+    /// again. Fails with [`sqlx::Error::RowNotFound`] if the code isn't deleted.
+    pub async fn restore(&mut self, code: &mut Code) -> Result<(), sqlx::error::Error> {
+        let result = timed(
+            "codes.restore",
+            sqlx::query!(
+                "UPDATE codes SET deleted_at = NULL, sort_index = $1, updated_at = $2, revision = $3 WHERE id = $4 AND owner_id = $5 AND deleted_at IS NOT NULL",
+                code.sort_index,
+                self.now,
+                self.revision,
+                code.id,
+                code.owner_id
+            )
+            .execute(&mut *self.tx),
+        )
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        code.deleted_at = None;
+        code.updated_at = self.now;
+        code.revision = self.revision;
+        Ok(())
+    }
+
+    /// Clears the content of the owner's deleted and expired codes, which are no longer served but
+    /// linger until purged, and forgets previous versions of their codes. Used when the owner
+    /// changes whether contents are end-to-end encrypted.
+    pub async fn clear_stale_content(&mut self, owner_id: &str) -> Result<(), sqlx::error::Error> {
+        revisions::clear(&mut self.tx, owner_id).await?;
+        timed(
+            "codes.clear_stale_content",
+            sqlx::query!(
+                "UPDATE codes SET content = '' WHERE owner_id = $1 AND (deleted_at IS NOT NULL OR expires_at <= $2)",
+                owner_id,
+                self.now
+            )
+            .execute(&mut *self.tx),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn commit(self) -> Result<(), sqlx::error::Error> {
+        self.tx.commit().await
     }
 }
@@ -0,0 +1,93 @@
+use super::timed;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+/// A WebAuthn credential (passkey) registered to a user.
+/// The credential itself is stored serialized, as it is opaque to everything but the WebAuthn library.
+#[derive(Serialize, Deserialize, Clone, Debug, sqlx::FromRow)]
+pub struct WebauthnCredential {
+    pub id: String,
+    pub user_id: String,
+    /// WebAuthn user handle, shared by all credentials of a user.
+    pub user_handle: String,
+    pub credential: String,
+    pub created_at: i64,
+    /// Tells the credentials of a user apart, e.g. `YubiKey`.
+    pub name: String,
+}
+
+impl WebauthnCredential {
+    pub const MAX_NAME_LENGTH: usize = 64;
+
+    pub async fn get(
+        pool: &SqlitePool,
+        id: &str,
+        user_id: &str,
+    ) -> Result<Option<WebauthnCredential>, sqlx::error::Error> {
+        timed(
+            "webauthn_credentials.get",
+            sqlx::query_as!(
+                WebauthnCredential,
+                "SELECT * FROM webauthn_credentials WHERE id = ? AND user_id = ?",
+                id,
+                user_id
+            )
+            .fetch_optional(pool),
+        )
+        .await
+    }
+
+    pub async fn get_for_user(
+        pool: &SqlitePool,
+        user_id: String,
+    ) -> Result<Vec<WebauthnCredential>, sqlx::error::Error> {
+        timed(
+            "webauthn_credentials.get_for_user",
+            sqlx::query_as!(
+                WebauthnCredential,
+                "SELECT * FROM webauthn_credentials WHERE user_id = ?",
+                user_id
+            )
+            .fetch_all(pool),
+        )
+        .await
+    }
+
+    pub async fn insert(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed("webauthn_credentials.insert", sqlx::query!(
+			"INSERT INTO webauthn_credentials (id, user_id, user_handle, credential, created_at, name) VALUES ($1, $2, $3, $4, $5, $6)",
+			self.id, self.user_id, self.user_handle, self.credential, self.created_at, self.name).execute(pool)).await?;
+
+        Ok(())
+    }
+
+    pub async fn update_credential(
+        &mut self,
+        pool: &SqlitePool,
+        credential: String,
+    ) -> Result<(), sqlx::error::Error> {
+        timed(
+            "webauthn_credentials.update_credential",
+            sqlx::query!(
+                "UPDATE webauthn_credentials SET credential = $2 WHERE id = $1",
+                self.id,
+                credential
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        self.credential = credential;
+        Ok(())
+    }
+
+    pub async fn delete(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "webauthn_credentials.delete",
+            sqlx::query!("DELETE FROM webauthn_credentials WHERE id = $1", self.id).execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use super::timed;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+/// An app or browser the user syncs with, which registered itself. Sessions logging in on it are
+/// linked to it, see [`super::sessions::Session::device_id`].
+#[derive(Serialize, Deserialize, Clone, Debug, sqlx::FromRow, utoipa::ToSchema, PartialEq)]
+pub struct Device {
+    pub id: String,
+    #[serde(skip)]
+    pub user_id: String,
+    /// Named by the user or the app, e.g. `iPhone 14`.
+    pub name: String,
+    /// Operating system or environment of the app, e.g. `ios` or `web`.
+    pub platform: String,
+    pub app_version: String,
+    pub created_at: i64,
+    /// Unix timestamp (seconds) of the last request of one of the device's sessions.
+    pub last_seen_at: Option<i64>,
+    /// Unix timestamp (seconds) of the last change the device made, like adding a code.
+    pub last_push_at: Option<i64>,
+}
+
+impl Device {
+    pub const MAX_NAME_LENGTH: usize = 64;
+    /// Applies to the platform and app version.
+    pub const MAX_LABEL_LENGTH: usize = 32;
+
+    pub async fn get(
+        pool: &SqlitePool,
+        id: &str,
+        user_id: &str,
+    ) -> Result<Option<Device>, sqlx::error::Error> {
+        timed(
+            "devices.get",
+            sqlx::query_as!(
+                Device,
+                r#"SELECT devices.id, devices.user_id, devices.name, devices.platform, devices.app_version, devices.created_at, MAX(sessions.last_activity) AS "last_seen_at?: i64", devices.last_push_at FROM devices LEFT JOIN sessions ON sessions.device_id = devices.id WHERE devices.id = $1 AND devices.user_id = $2 GROUP BY devices.id"#,
+                id,
+                user_id
+            )
+            .fetch_optional(pool),
+        )
+        .await
+    }
+
+    /// Devices of the user, most recently seen first.
+    pub async fn get_for_user(
+        pool: &SqlitePool,
+        user_id: &str,
+    ) -> Result<Vec<Device>, sqlx::error::Error> {
+        timed(
+            "devices.get_for_user",
+            sqlx::query_as!(
+                Device,
+                r#"SELECT devices.id, devices.user_id, devices.name, devices.platform, devices.app_version, devices.created_at, MAX(sessions.last_activity) AS "last_seen_at?: i64", devices.last_push_at FROM devices LEFT JOIN sessions ON sessions.device_id = devices.id WHERE devices.user_id = $1 GROUP BY devices.id ORDER BY COALESCE(MAX(sessions.last_activity), devices.created_at) DESC, devices.created_at DESC"#,
+                user_id
+            )
+            .fetch_all(pool),
+        )
+        .await
+    }
+
+    pub async fn insert(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "devices.insert",
+            sqlx::query!(
+                "INSERT INTO devices (id, user_id, name, platform, app_version, created_at, last_push_at) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                self.id,
+                self.user_id,
+                self.name,
+                self.platform,
+                self.app_version,
+                self.created_at,
+                self.last_push_at
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Stores the name, platform and app version of the device.
+    pub async fn update(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "devices.update",
+            sqlx::query!(
+                "UPDATE devices SET name = $1, platform = $2, app_version = $3 WHERE id = $4",
+                self.name,
+                self.platform,
+                self.app_version,
+                self.id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Marks the device as having made a change at `now`.
+    pub async fn record_push(
+        pool: &SqlitePool,
+        id: &str,
+        now: i64,
+    ) -> Result<(), sqlx::error::Error> {
+        timed(
+            "devices.record_push",
+            sqlx::query!(
+                "UPDATE devices SET last_push_at = $1 WHERE id = $2",
+                now,
+                id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Removes the device, revoking its sessions.
+    pub async fn delete(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "devices.delete",
+            sqlx::query!("DELETE FROM devices WHERE id = $1", self.id).execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
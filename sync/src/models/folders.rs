@@ -0,0 +1,129 @@
+use super::timed;
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqliteExecutor, SqlitePool};
+
+/// A folder codes can be put in, which may itself be in another folder.
+#[derive(Serialize, Deserialize, Clone, Debug, sqlx::FromRow, utoipa::ToSchema, PartialEq)]
+pub struct Folder {
+    pub id: String,
+    pub owner_id: String,
+    pub name: String,
+    /// Folder containing this one. Folders without a parent are at the top level.
+    pub parent_id: Option<String>,
+}
+
+impl Folder {
+    pub const MAX_NAME_LENGTH: usize = 64;
+
+    pub async fn get(
+        executor: impl SqliteExecutor<'_>,
+        id: &str,
+        owner_id: &str,
+    ) -> Result<Option<Folder>, sqlx::error::Error> {
+        timed(
+            "folders.get",
+            sqlx::query_as!(
+                Folder,
+                "SELECT id, owner_id, name, parent_id FROM folders WHERE id = $1 AND owner_id = $2",
+                id,
+                owner_id
+            )
+            .fetch_optional(executor),
+        )
+        .await
+    }
+
+    pub async fn get_many(
+        executor: impl SqliteExecutor<'_>,
+        owner_id: &str,
+    ) -> Result<Vec<Folder>, sqlx::error::Error> {
+        timed(
+            "folders.get_many",
+            sqlx::query_as!(
+                Folder,
+                "SELECT id, owner_id, name, parent_id FROM folders WHERE owner_id = $1 ORDER BY name COLLATE NOCASE, rowid",
+                owner_id
+            )
+            .fetch_all(executor),
+        )
+        .await
+    }
+
+    pub async fn insert(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "folders.insert",
+            sqlx::query!(
+                "INSERT INTO folders (id, owner_id, name, parent_id) VALUES ($1, $2, $3, $4)",
+                self.id,
+                self.owner_id,
+                self.name,
+                self.parent_id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Stores the name and parent of the folder.
+    pub async fn update(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "folders.update",
+            sqlx::query!(
+                "UPDATE folders SET name = $1, parent_id = $2 WHERE id = $3 AND owner_id = $4",
+                self.name,
+                self.parent_id,
+                self.id,
+                self.owner_id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Whether the folder is `ancestor_id` itself, or inside it at any depth.
+    pub async fn is_within(
+        pool: &SqlitePool,
+        id: &str,
+        ancestor_id: &str,
+    ) -> Result<bool, sqlx::error::Error> {
+        timed(
+            "folders.is_within",
+            sqlx::query_scalar!(
+                r#"WITH RECURSIVE ancestors(id, parent_id) AS (
+                    SELECT id, parent_id FROM folders WHERE id = $1
+                    UNION
+                    SELECT folders.id, folders.parent_id FROM folders JOIN ancestors ON folders.id = ancestors.parent_id
+                )
+                SELECT EXISTS(SELECT 1 FROM ancestors WHERE id = $2) as "within!: bool""#,
+                id,
+                ancestor_id
+            )
+            .fetch_one(pool),
+        )
+        .await
+    }
+
+    /// Deletes the folder, moving the folders in it up to its parent. Codes in it have to be moved
+    /// beforehand, so syncing clients learn about it.
+    pub async fn delete(&self, conn: &mut SqliteConnection) -> Result<(), sqlx::error::Error> {
+        timed("folders.delete", async {
+            sqlx::query!(
+                "UPDATE folders SET parent_id = $1 WHERE parent_id = $2",
+                self.parent_id,
+                self.id
+            )
+            .execute(&mut *conn)
+            .await?;
+            sqlx::query!("DELETE FROM folders WHERE id = $1", self.id)
+                .execute(&mut *conn)
+                .await?;
+
+            Ok(())
+        })
+        .await
+    }
+}
@@ -0,0 +1,131 @@
+use super::timed;
+use sqlx::SqlitePool;
+
+/// An icon stored once by the hash of its bytes, see [`crate::icons::store_icon`]. Codes are linked
+/// to the icon they're shown with, and the links are counted, so icons no code shows anymore can be
+/// removed.
+#[derive(Clone, Debug, sqlx::FromRow, PartialEq)]
+pub struct Icon {
+    /// Hex encoded SHA-256 hash of the bytes, which are stored under it.
+    pub hash: String,
+    pub content_type: String,
+    /// Bytes of the icon.
+    pub size: i64,
+    /// Codes linked to the icon, including those in the trash.
+    pub refs: i64,
+    /// Unix timestamp (seconds) of the last time the icon was stored.
+    pub stored_at: i64,
+}
+
+impl Icon {
+    /// Records the icon, or refreshes when it was stored if it is known already.
+    pub async fn record(
+        pool: &SqlitePool,
+        hash: &str,
+        content_type: &str,
+        size: i64,
+        now: i64,
+    ) -> Result<(), sqlx::error::Error> {
+        timed(
+            "icons.record",
+            sqlx::query!(
+                "INSERT INTO icons (hash, content_type, size, stored_at) VALUES ($1, $2, $3, $4) ON CONFLICT (hash) DO UPDATE SET stored_at = excluded.stored_at",
+                hash,
+                content_type,
+                size,
+                now
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get(pool: &SqlitePool, hash: &str) -> Result<Option<Icon>, sqlx::error::Error> {
+        timed(
+            "icons.get",
+            sqlx::query_as!(Icon, "SELECT * FROM icons WHERE hash = $1", hash).fetch_optional(pool),
+        )
+        .await
+    }
+
+    /// The icon the code is shown with.
+    pub async fn of_code(
+        pool: &SqlitePool,
+        code_id: &str,
+    ) -> Result<Option<Icon>, sqlx::error::Error> {
+        timed(
+            "icons.of_code",
+            sqlx::query_as!(
+                Icon,
+                "SELECT icons.hash, icons.content_type, icons.size, icons.refs, icons.stored_at FROM icons INNER JOIN code_icons ON code_icons.hash = icons.hash WHERE code_icons.code_id = $1",
+                code_id
+            )
+            .fetch_optional(pool),
+        )
+        .await
+    }
+
+    /// Shows the code with the icon, as long as the code still has the `icon_url` the icon was
+    /// found with. Returns whether it was linked.
+    pub async fn link(
+        pool: &SqlitePool,
+        code_id: &str,
+        icon_url: Option<&str>,
+        hash: &str,
+    ) -> Result<bool, sqlx::error::Error> {
+        let result = timed(
+            "icons.link",
+            sqlx::query!(
+                "INSERT INTO code_icons (code_id, hash) SELECT id, $1 FROM codes WHERE id = $2 AND icon_url IS $3 AND deleted_at IS NULL ON CONFLICT (code_id) DO UPDATE SET hash = excluded.hash",
+                hash,
+                code_id,
+                icon_url
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Shows the codes of the website which use the icon at `icon_url` with the icon, optionally
+    /// only those of a single owner. Returns how many codes were linked.
+    pub async fn link_website(
+        pool: &SqlitePool,
+        website_url: &str,
+        icon_url: &str,
+        owner_id: Option<&str>,
+        hash: &str,
+    ) -> Result<u64, sqlx::error::Error> {
+        let result = timed(
+            "icons.link_website",
+            sqlx::query!(
+                "INSERT INTO code_icons (code_id, hash) SELECT id, $1 FROM codes WHERE website_url = $2 AND icon_url = $3 AND deleted_at IS NULL AND ($4 IS NULL OR owner_id = $4) ON CONFLICT (code_id) DO UPDATE SET hash = excluded.hash",
+                hash,
+                website_url,
+                icon_url,
+                owner_id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Forgets icons no code is linked to, which were last stored before `before`. Returns the
+    /// hashes of the forgotten icons, so their bytes can be removed.
+    pub async fn prune(pool: &SqlitePool, before: i64) -> Result<Vec<String>, sqlx::error::Error> {
+        timed(
+            "icons.prune",
+            sqlx::query_scalar!(
+                "DELETE FROM icons WHERE refs <= 0 AND stored_at < $1 RETURNING hash",
+                before
+            )
+            .fetch_all(pool),
+        )
+        .await
+    }
+}
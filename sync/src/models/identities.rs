@@ -0,0 +1,71 @@
+use super::timed;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+/// Identity at an OpenID provider which the user linked to their account, so they can log in with
+/// it next to the one the account was created with.
+#[derive(Serialize, Deserialize, Clone, Debug, sqlx::FromRow, PartialEq)]
+pub struct Identity {
+    pub id: String,
+    pub user_id: String,
+    /// Issuer of the OpenID provider `subject` is the subject at.
+    pub issuer: String,
+    pub subject: String,
+    pub created_at: i64,
+}
+
+impl Identity {
+    pub async fn get(pool: &SqlitePool, id: &str) -> Result<Option<Identity>, sqlx::error::Error> {
+        timed(
+            "identities.get",
+            sqlx::query_as!(Identity, "SELECT * FROM identities WHERE id = ?", id)
+                .fetch_optional(pool),
+        )
+        .await
+    }
+
+    /// Identities the user linked, oldest first.
+    pub async fn get_for_user(
+        pool: &SqlitePool,
+        user_id: &str,
+    ) -> Result<Vec<Identity>, sqlx::error::Error> {
+        timed(
+            "identities.get_for_user",
+            sqlx::query_as!(
+                Identity,
+                "SELECT * FROM identities WHERE user_id = ? ORDER BY created_at, id",
+                user_id
+            )
+            .fetch_all(pool),
+        )
+        .await
+    }
+
+    pub async fn insert(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "identities.insert",
+            sqlx::query!(
+                "INSERT INTO identities (id, user_id, issuer, subject, created_at) VALUES ($1, $2, $3, $4, $5)",
+                self.id,
+                self.user_id,
+                self.issuer,
+                self.subject,
+                self.created_at
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "identities.delete",
+            sqlx::query!("DELETE FROM identities WHERE id = $1", self.id).execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
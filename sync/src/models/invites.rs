@@ -0,0 +1,134 @@
+use super::timed;
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use utoipa::ToSchema;
+
+/// Single use invite to create an account, for instances where registration is invite-only. Minted
+/// by admins, who hand the token out themselves.
+#[derive(Serialize, Deserialize, Clone, Debug, sqlx::FromRow, PartialEq, ToSchema)]
+pub struct Invite {
+    pub id: String,
+    /// Invite tokens are random, so unlike passwords they don't need a slow hash.
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    /// Id of the admin who minted the invite.
+    pub created_by: String,
+    pub created_at: i64,
+    /// Unix timestamp (seconds) after which the invite can't be used anymore.
+    pub expires_at: i64,
+    /// Id of the user who registered with the invite.
+    pub used_by: Option<String>,
+    pub used_at: Option<i64>,
+}
+
+impl Invite {
+    /// Days an invite can be used for, unless the admin picks otherwise.
+    pub const DEFAULT_LIFETIME_DAYS: u32 = 7;
+    pub const MAX_LIFETIME_DAYS: u32 = 365;
+
+    fn hash_token(token: &str) -> String {
+        base16ct::lower::encode_string(&Sha256::digest(token))
+    }
+
+    /// Generates an invite valid for the amount of seconds, returning it next to its token.
+    pub fn generate(created_by: String, now: i64, lifetime: i64) -> (Invite, String) {
+        let token = utils::generate_id(32);
+        let invite = Invite {
+            id: utils::generate_id(16),
+            token_hash: Invite::hash_token(&token),
+            created_by,
+            created_at: now,
+            expires_at: now + lifetime,
+            used_by: None,
+            used_at: None,
+        };
+        (invite, token)
+    }
+
+    pub async fn insert(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "invites.insert",
+            sqlx::query!(
+                "INSERT INTO invites (id, token_hash, created_by, created_at, expires_at) VALUES ($1, $2, $3, $4, $5)",
+                self.id,
+                self.token_hash,
+                self.created_by,
+                self.created_at,
+                self.expires_at
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Every invite, including used and expired ones, most recently minted first.
+    pub async fn list(pool: &SqlitePool) -> Result<Vec<Invite>, sqlx::error::Error> {
+        timed(
+            "invites.list",
+            sqlx::query_as!(
+                Invite,
+                "SELECT * FROM invites ORDER BY created_at DESC, rowid DESC"
+            )
+            .fetch_all(pool),
+        )
+        .await
+    }
+
+    /// Deletes the invite, returning whether it existed.
+    pub async fn delete(pool: &SqlitePool, id: &str) -> Result<bool, sqlx::error::Error> {
+        let result = timed(
+            "invites.delete",
+            sqlx::query!("DELETE FROM invites WHERE id = ?", id).execute(pool),
+        )
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Whether the token belongs to an invite which is neither used nor expired.
+    pub async fn is_valid(
+        pool: &SqlitePool,
+        token: &str,
+        now: i64,
+    ) -> Result<bool, sqlx::error::Error> {
+        let token_hash = Invite::hash_token(token);
+        timed(
+            "invites.is_valid",
+            sqlx::query_scalar!(
+                r#"SELECT EXISTS(SELECT 1 FROM invites WHERE token_hash = $1 AND used_at IS NULL AND expires_at > $2) as "valid!: bool""#,
+                token_hash,
+                now
+            )
+            .fetch_one(pool),
+        )
+        .await
+    }
+
+    /// Uses up the invite for the user, returning whether it was still valid. Only one of several
+    /// concurrent redemptions succeeds.
+    pub async fn redeem(
+        pool: &SqlitePool,
+        token: &str,
+        user_id: &str,
+        now: i64,
+    ) -> Result<bool, sqlx::error::Error> {
+        let token_hash = Invite::hash_token(token);
+        let result = timed(
+            "invites.redeem",
+            sqlx::query!(
+                "UPDATE invites SET used_by = $1, used_at = $2 WHERE token_hash = $3 AND used_at IS NULL AND expires_at > $2",
+                user_id,
+                now,
+                token_hash
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
@@ -1,2 +1,50 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
 pub mod codes;
+pub mod credentials;
+pub mod devices;
+pub mod folders;
+pub mod icons;
+pub mod identities;
+pub mod invites;
+pub mod passwords;
+pub mod preferences;
+pub mod push_subscriptions;
+pub mod revisions;
+pub mod sessions;
+pub mod stats;
+pub mod tags;
+pub mod tokens;
 pub mod user;
+pub mod webhooks;
+
+/// Queries taking at least this many milliseconds are logged. Zero disables the logging.
+static SLOW_QUERY_THRESHOLD_MS: AtomicU64 = AtomicU64::new(250);
+
+pub fn set_slow_query_threshold(threshold: Duration) {
+    SLOW_QUERY_THRESHOLD_MS.store(threshold.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Runs a query, warning if it exceeds the slow query threshold.
+/// Only the operation name is logged, as parameters may contain secrets.
+pub async fn timed<T>(operation: &'static str, query: impl Future<Output = T>) -> T {
+    let start = Instant::now();
+    let result = query.await;
+    let elapsed = start.elapsed();
+
+    let threshold = SLOW_QUERY_THRESHOLD_MS.load(Ordering::Relaxed);
+    if threshold != 0 && elapsed >= Duration::from_millis(threshold) {
+        warn!(
+            operation,
+            elapsed_ms = elapsed.as_millis() as u64,
+            "Slow query"
+        );
+    }
+
+    result
+}
@@ -0,0 +1,205 @@
+use super::timed;
+use crate::utils;
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::sync::LazyLock;
+
+/// Hash of a random password, verified against when logging in with an unknown email, so the
+/// response time doesn't tell which emails have an account.
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| Password::hash(&utils::generate_id(32)));
+
+/// Email and password of a user who registered without an OpenID provider, see
+/// [`crate::routes::v1::local`].
+#[derive(Serialize, Deserialize, Clone, Debug, sqlx::FromRow, PartialEq)]
+pub struct Password {
+    pub user_id: String,
+    /// Lowercase, see [`Password::normalize_email`].
+    pub email: String,
+    /// Argon2id hash of the password, in the PHC string format.
+    pub password_hash: String,
+    pub updated_at: i64,
+}
+
+impl Password {
+    /// Issuer of users with a password, in place of an OpenID provider's.
+    pub const ISSUER: &'static str = "local";
+    pub const MIN_LENGTH: usize = 10;
+    /// Argon2 takes any length, but hashing megabytes on every login attempt would be a DoS.
+    pub const MAX_LENGTH: usize = 1024;
+    pub const MAX_EMAIL_LENGTH: usize = 254;
+
+    pub fn normalize_email(email: &str) -> String {
+        email.trim().to_lowercase()
+    }
+
+    pub fn hash(password: &str) -> String {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let salt = SaltString::encode_b64(&salt).expect("16 bytes are a valid salt");
+
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .expect("Unable to hash password")
+            .to_string()
+    }
+
+    /// Checks the password against the stored hash. The comparison is constant-time.
+    pub fn verify(&self, password: &str) -> bool {
+        verify_hash(&self.password_hash, password)
+    }
+
+    /// Takes as long as [`Password::verify`], for logins with an unknown email.
+    pub fn verify_dummy(password: &str) {
+        verify_hash(&DUMMY_HASH, password);
+    }
+
+    pub async fn get_by_email(
+        pool: &SqlitePool,
+        email: &str,
+    ) -> Result<Option<Password>, sqlx::error::Error> {
+        timed(
+            "passwords.get_by_email",
+            sqlx::query_as!(Password, "SELECT * FROM passwords WHERE email = ?", email)
+                .fetch_optional(pool),
+        )
+        .await
+    }
+
+    pub async fn get_for_user(
+        pool: &SqlitePool,
+        user_id: &str,
+    ) -> Result<Option<Password>, sqlx::error::Error> {
+        timed(
+            "passwords.get_for_user",
+            sqlx::query_as!(
+                Password,
+                "SELECT * FROM passwords WHERE user_id = ?",
+                user_id
+            )
+            .fetch_optional(pool),
+        )
+        .await
+    }
+
+    pub async fn insert(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "passwords.insert",
+            sqlx::query!(
+                "INSERT INTO passwords (user_id, email, password_hash, updated_at) VALUES ($1, $2, $3, $4)",
+                self.user_id,
+                self.email,
+                self.password_hash,
+                self.updated_at
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_password(
+        &mut self,
+        pool: &SqlitePool,
+        password: &str,
+        now: i64,
+    ) -> Result<(), sqlx::error::Error> {
+        let password_hash = Password::hash(password);
+        timed(
+            "passwords.set_password",
+            sqlx::query!(
+                "UPDATE passwords SET password_hash = $1, updated_at = $2 WHERE user_id = $3",
+                password_hash,
+                now,
+                self.user_id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        self.password_hash = password_hash;
+        self.updated_at = now;
+        Ok(())
+    }
+}
+
+fn verify_hash(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Single use token to set a new password, for users who forgot theirs. Handed out by admins, as
+/// the instance can't send emails.
+#[derive(Serialize, Deserialize, Clone, Debug, sqlx::FromRow, PartialEq)]
+pub struct PasswordReset {
+    /// Reset tokens are random, so unlike passwords they don't need a slow hash.
+    pub token_hash: String,
+    pub user_id: String,
+    pub expires_at: i64,
+}
+
+impl PasswordReset {
+    /// Seconds a reset token can be used for.
+    pub const LIFETIME: i64 = 24 * 60 * 60;
+
+    fn hash_token(token: &str) -> String {
+        base16ct::lower::encode_string(&Sha256::digest(token))
+    }
+
+    /// Generates a reset token for the user, returning it next to the reset to store.
+    pub fn generate(user_id: String, now: i64) -> (PasswordReset, String) {
+        let token = utils::generate_id(40);
+        let reset = PasswordReset {
+            token_hash: PasswordReset::hash_token(&token),
+            user_id,
+            expires_at: now + PasswordReset::LIFETIME,
+        };
+        (reset, token)
+    }
+
+    pub async fn insert(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "password_resets.insert",
+            sqlx::query!(
+                "INSERT INTO password_resets (token_hash, user_id, expires_at) VALUES ($1, $2, $3)",
+                self.token_hash,
+                self.user_id,
+                self.expires_at
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Uses up the token, returning its reset unless it expired.
+    pub async fn take(
+        pool: &SqlitePool,
+        token: &str,
+        now: i64,
+    ) -> Result<Option<PasswordReset>, sqlx::error::Error> {
+        let token_hash = PasswordReset::hash_token(token);
+        let reset = timed(
+            "password_resets.take",
+            sqlx::query_as!(
+                PasswordReset,
+                "DELETE FROM password_resets WHERE token_hash = ? RETURNING *",
+                token_hash
+            )
+            .fetch_optional(pool),
+        )
+        .await?;
+
+        Ok(reset.filter(|reset| reset.expires_at > now))
+    }
+}
@@ -0,0 +1,65 @@
+use super::timed;
+use serde_json::Value;
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+
+/// Preferences clients stored for the user, by key. Values which aren't valid JSON are skipped.
+pub async fn of_user(
+    pool: &SqlitePool,
+    user_id: &str,
+) -> Result<BTreeMap<String, Value>, sqlx::error::Error> {
+    let rows = timed(
+        "preferences.of_user",
+        sqlx::query!(
+            "SELECT key, value FROM preferences WHERE user_id = ?",
+            user_id
+        )
+        .fetch_all(pool),
+    )
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| Some((row.key, serde_json::from_str(&row.value).ok()?)))
+        .collect())
+}
+
+/// Sets the preferences with a value, and removes those with `None`, all at once.
+pub async fn update(
+    pool: &SqlitePool,
+    user_id: &str,
+    changes: &BTreeMap<String, Option<Value>>,
+) -> Result<(), sqlx::error::Error> {
+    let now = chrono::Utc::now().timestamp();
+
+    timed("preferences.update", async {
+        let mut tx = pool.begin().await?;
+        for (key, value) in changes {
+            match value {
+                Some(value) => {
+                    let value = value.to_string();
+                    sqlx::query!(
+                        "INSERT INTO preferences (user_id, key, value, updated_at) VALUES ($1, $2, $3, $4) ON CONFLICT (user_id, key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at",
+                        user_id,
+                        key,
+                        value,
+                        now
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                None => {
+                    sqlx::query!(
+                        "DELETE FROM preferences WHERE user_id = $1 AND key = $2",
+                        user_id,
+                        key
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+            }
+        }
+        tx.commit().await
+    })
+    .await
+}
@@ -0,0 +1,89 @@
+use super::timed;
+use sqlx::SqlitePool;
+
+/// Where to send Web Push messages for a device, as handed out by the push service of its browser
+/// or platform. Every device has at most one.
+#[derive(Clone, Debug, sqlx::FromRow, PartialEq)]
+pub struct PushSubscription {
+    pub device_id: String,
+    pub user_id: String,
+    pub endpoint: String,
+    /// Public key of the device pushes are encrypted for, base64url encoded.
+    pub p256dh: String,
+    /// Authentication secret of the subscription, base64url encoded.
+    pub auth: String,
+    pub created_at: i64,
+}
+
+impl PushSubscription {
+    pub async fn get_for_user(
+        pool: &SqlitePool,
+        user_id: &str,
+    ) -> Result<Vec<PushSubscription>, sqlx::error::Error> {
+        timed(
+            "push_subscriptions.get_for_user",
+            sqlx::query_as!(
+                PushSubscription,
+                "SELECT device_id, user_id, endpoint, p256dh, auth, created_at FROM push_subscriptions WHERE user_id = $1",
+                user_id
+            )
+            .fetch_all(pool),
+        )
+        .await
+    }
+
+    /// Stores the subscription, replacing the previous one of the device.
+    pub async fn upsert(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "push_subscriptions.upsert",
+            sqlx::query!(
+                "INSERT INTO push_subscriptions (device_id, user_id, endpoint, p256dh, auth, created_at) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (device_id) DO UPDATE SET endpoint = excluded.endpoint, p256dh = excluded.p256dh, auth = excluded.auth, created_at = excluded.created_at",
+                self.device_id,
+                self.user_id,
+                self.endpoint,
+                self.p256dh,
+                self.auth,
+                self.created_at
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Removes the subscription of the device. Returns whether it had one.
+    pub async fn delete_for_device(
+        pool: &SqlitePool,
+        device_id: &str,
+    ) -> Result<bool, sqlx::error::Error> {
+        let result = timed(
+            "push_subscriptions.delete_for_device",
+            sqlx::query!(
+                "DELETE FROM push_subscriptions WHERE device_id = $1",
+                device_id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Removes the subscription after the push service forgot it, unless the device subscribed
+    /// again in the meantime.
+    pub async fn delete_expired(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "push_subscriptions.delete_expired",
+            sqlx::query!(
+                "DELETE FROM push_subscriptions WHERE device_id = $1 AND endpoint = $2",
+                self.device_id,
+                self.endpoint
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
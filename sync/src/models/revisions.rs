@@ -0,0 +1,128 @@
+use super::timed;
+use crate::totp;
+use serde::Serialize;
+use sqlx::{SqliteConnection, SqliteExecutor};
+use utoipa::ToSchema;
+
+/// Most previous versions kept of a single code. Older ones are dropped as new ones are recorded.
+pub const MAX_PER_CODE: i64 = 20;
+
+/// A previous version of a code, recorded when it was edited.
+#[derive(Serialize, Clone, Debug, sqlx::FromRow, ToSchema, PartialEq)]
+pub struct CodeRevision {
+    #[serde(skip)]
+    pub code_id: String,
+    /// Sync revision of the code while it was at this version.
+    pub revision: i64,
+    pub content: String,
+    pub display_name: String,
+    pub icon_url: Option<String>,
+    pub website_url: Option<String>,
+    pub kind: String,
+    pub digits: i64,
+    pub period: i64,
+    pub algorithm: String,
+    /// Unix timestamp (seconds) at which the code got to this version.
+    pub updated_at: i64,
+    /// Unix timestamp (seconds) at which an edit replaced this version.
+    pub replaced_at: i64,
+}
+
+impl CodeRevision {
+    /// How codes were generated at this version, see [`crate::models::codes::Code::totp_params`].
+    pub fn totp_params(&self) -> totp::Params {
+        totp::Params {
+            kind: totp::Kind::parse(&self.kind).unwrap_or_default(),
+            algorithm: totp::Algorithm::parse(&self.algorithm).unwrap_or_default(),
+            digits: self.digits as u32,
+            period: self.period,
+        }
+    }
+}
+
+/// Previous versions of the code, most recent first.
+pub async fn of_code(
+    executor: impl SqliteExecutor<'_>,
+    code_id: &str,
+) -> Result<Vec<CodeRevision>, sqlx::error::Error> {
+    timed(
+        "revisions.of_code",
+        sqlx::query_as!(
+            CodeRevision,
+            "SELECT * FROM code_revisions WHERE code_id = $1 ORDER BY revision DESC",
+            code_id
+        )
+        .fetch_all(executor),
+    )
+    .await
+}
+
+/// A single previous version of the code.
+pub async fn get(
+    executor: impl SqliteExecutor<'_>,
+    code_id: &str,
+    revision: i64,
+) -> Result<Option<CodeRevision>, sqlx::error::Error> {
+    timed(
+        "revisions.get",
+        sqlx::query_as!(
+            CodeRevision,
+            "SELECT * FROM code_revisions WHERE code_id = $1 AND revision = $2",
+            code_id,
+            revision
+        )
+        .fetch_optional(executor),
+    )
+    .await
+}
+
+/// Records the current version of the code before it is edited, dropping the oldest versions
+/// beyond [`MAX_PER_CODE`]. Has to run in the transaction editing the code, with the same
+/// expected revision, see [`crate::models::codes::CodeBatch::edit`]. Edits in the same batch
+/// replace the version recorded by the first, as they share a revision.
+pub(crate) async fn record(
+    conn: &mut SqliteConnection,
+    code_id: &str,
+    owner_id: &str,
+    expected_revision: Option<i64>,
+    now: i64,
+) -> Result<(), sqlx::error::Error> {
+    timed("revisions.record", async {
+        sqlx::query!(
+            "INSERT OR REPLACE INTO code_revisions (code_id, revision, content, display_name, icon_url, website_url, kind, digits, period, algorithm, updated_at, replaced_at) SELECT id, revision, content, display_name, icon_url, website_url, kind, digits, period, algorithm, updated_at, $1 FROM codes WHERE id = $2 AND owner_id = $3 AND deleted_at IS NULL AND ($4 IS NULL OR revision = $4)",
+            now,
+            code_id,
+            owner_id,
+            expected_revision
+        )
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM code_revisions WHERE code_id = $1 AND revision NOT IN (SELECT revision FROM code_revisions WHERE code_id = $1 ORDER BY revision DESC LIMIT $2)",
+            code_id,
+            MAX_PER_CODE
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+/// Forgets every previous version of the owner's codes.
+pub(crate) async fn clear(
+    conn: &mut SqliteConnection,
+    owner_id: &str,
+) -> Result<(), sqlx::error::Error> {
+    timed(
+        "revisions.clear",
+        sqlx::query!(
+            "DELETE FROM code_revisions WHERE code_id IN (SELECT id FROM codes WHERE owner_id = $1)",
+            owner_id
+        )
+        .execute(conn),
+    )
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,239 @@
+use super::timed;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+
+/// A login, referenced by the `sid` claim of its JWT.
+#[derive(Serialize, Deserialize, Clone, Debug, sqlx::FromRow, PartialEq)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    pub created_at: i64,
+    /// Unix timestamp (seconds) of the last request authenticated by the session.
+    pub last_activity: i64,
+    /// Coarse label of the browser or app which logged in, e.g. `Firefox on Linux`.
+    pub device: String,
+    /// Hash of the secret of the refresh token, see [`Session::hash_refresh_secret`].
+    pub refresh_token_hash: Option<String>,
+    /// Hash of the refresh token replaced by the current one. Using it again revokes the session,
+    /// as either the client or someone who stole it already used it.
+    pub previous_refresh_token_hash: Option<String>,
+    /// User agent of the login request, truncated to [`Session::MAX_USER_AGENT_LENGTH`].
+    pub user_agent: String,
+    /// Address of the client which last used the session, see [`crate::connections::client_ip`].
+    pub ip: Option<String>,
+    /// Issuer of the OpenID provider the session logged in at, if it did.
+    pub upstream_issuer: Option<String>,
+    /// Id of the session at the OpenID provider, the `sid` of its ID token. Only sent by providers
+    /// supporting back-channel logout.
+    pub upstream_sid: Option<String>,
+    /// Access token of the OpenID provider from the login, to fetch the user's profile with again.
+    #[serde(skip_serializing)]
+    pub upstream_access_token: Option<String>,
+    /// Device the session belongs to, once the app registered itself, see
+    /// [`super::devices::Device`].
+    pub device_id: Option<String>,
+}
+
+impl Session {
+    pub const MAX_USER_AGENT_LENGTH: usize = 512;
+
+    /// Refresh tokens are random, so unlike passwords they don't need a slow hash.
+    pub fn hash_refresh_secret(secret: &str) -> String {
+        base16ct::lower::encode_string(&Sha256::digest(secret))
+    }
+
+    pub async fn get(pool: &SqlitePool, id: &str) -> Result<Option<Session>, sqlx::error::Error> {
+        timed(
+            "sessions.get",
+            sqlx::query_as!(Session, "SELECT * FROM sessions WHERE id = ?", id)
+                .fetch_optional(pool),
+        )
+        .await
+    }
+
+    /// Sessions of the user, most recently active first.
+    pub async fn get_for_user(
+        pool: &SqlitePool,
+        user_id: &str,
+    ) -> Result<Vec<Session>, sqlx::error::Error> {
+        timed(
+            "sessions.get_for_user",
+            sqlx::query_as!(
+                Session,
+                "SELECT * FROM sessions WHERE user_id = ? ORDER BY last_activity DESC, created_at DESC",
+                user_id
+            )
+            .fetch_all(pool),
+        )
+        .await
+    }
+
+    pub async fn insert(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "sessions.insert",
+            sqlx::query!(
+                "INSERT INTO sessions (id, user_id, created_at, last_activity, device, refresh_token_hash, user_agent, ip) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                self.id,
+                self.user_id,
+                self.created_at,
+                self.last_activity,
+                self.device,
+                self.refresh_token_hash,
+                self.user_agent,
+                self.ip
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Marks the session as used at `now`, from the address if it is known.
+    pub async fn touch(
+        &mut self,
+        pool: &SqlitePool,
+        now: i64,
+        ip: Option<String>,
+    ) -> Result<(), sqlx::error::Error> {
+        let ip = ip.or_else(|| self.ip.clone());
+        timed(
+            "sessions.touch",
+            sqlx::query!(
+                "UPDATE sessions SET last_activity = $1, ip = $2 WHERE id = $3",
+                now,
+                ip,
+                self.id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        self.last_activity = now;
+        self.ip = ip;
+        Ok(())
+    }
+
+    /// Replaces the refresh token, marking the session as active. Fails with
+    /// [`sqlx::Error::RowNotFound`] if the refresh token was replaced in the meantime.
+    pub async fn rotate_refresh_token(
+        &mut self,
+        pool: &SqlitePool,
+        refresh_token_hash: String,
+        now: i64,
+    ) -> Result<(), sqlx::error::Error> {
+        let result = timed(
+            "sessions.rotate_refresh_token",
+            sqlx::query!(
+                "UPDATE sessions SET refresh_token_hash = $1, previous_refresh_token_hash = refresh_token_hash, last_activity = $2 WHERE id = $3 AND refresh_token_hash = $4",
+                refresh_token_hash,
+                now,
+                self.id,
+                self.refresh_token_hash
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        self.previous_refresh_token_hash = self.refresh_token_hash.replace(refresh_token_hash);
+        self.last_activity = now;
+        Ok(())
+    }
+
+    /// Records the OpenID provider session the session logged in with, see
+    /// [`Session::delete_upstream`], and the provider's access token to fetch the profile with.
+    pub async fn set_upstream(
+        pool: &SqlitePool,
+        id: &str,
+        issuer: &str,
+        sid: Option<&str>,
+        access_token: &str,
+    ) -> Result<(), sqlx::error::Error> {
+        timed(
+            "sessions.set_upstream",
+            sqlx::query!(
+                "UPDATE sessions SET upstream_issuer = $1, upstream_sid = $2, upstream_access_token = $3 WHERE id = $4",
+                issuer,
+                sid,
+                access_token,
+                id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Revokes the sessions which logged in at the provider, of the user and with the provider's
+    /// session id if given. Returns how many there were.
+    pub async fn delete_upstream(
+        pool: &SqlitePool,
+        issuer: &str,
+        user_id: Option<&str>,
+        sid: Option<&str>,
+    ) -> Result<u64, sqlx::error::Error> {
+        let result = timed(
+            "sessions.delete_upstream",
+            sqlx::query!(
+                "DELETE FROM sessions WHERE upstream_issuer = $1 AND ($2 IS NULL OR user_id = $2) AND ($3 IS NULL OR upstream_sid = $3)",
+                issuer,
+                user_id,
+                sid
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Links the session to the device.
+    pub async fn set_device(
+        &mut self,
+        pool: &SqlitePool,
+        device_id: &str,
+    ) -> Result<(), sqlx::error::Error> {
+        timed(
+            "sessions.set_device",
+            sqlx::query!(
+                "UPDATE sessions SET device_id = $1 WHERE id = $2",
+                device_id,
+                self.id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        self.device_id = Some(device_id.to_string());
+        Ok(())
+    }
+
+    pub async fn delete(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "sessions.delete",
+            sqlx::query!("DELETE FROM sessions WHERE id = $1", self.id).execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Revokes every session of the user, e.g. after their password was reset.
+    pub async fn delete_for_user(
+        pool: &SqlitePool,
+        user_id: &str,
+    ) -> Result<(), sqlx::error::Error> {
+        timed(
+            "sessions.delete_for_user",
+            sqlx::query!("DELETE FROM sessions WHERE user_id = $1", user_id).execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+use super::timed;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use utoipa::ToSchema;
+
+/// Totals of the instance, for admins.
+#[derive(Serialize, Debug, PartialEq, ToSchema)]
+pub struct InstanceStats {
+    pub users: i64,
+    pub admins: i64,
+    pub suspended_users: i64,
+    /// Codes which aren't in the trash.
+    pub codes: i64,
+    /// Codes in the trash, until they are purged.
+    pub trashed_codes: i64,
+    pub folders: i64,
+    pub sessions: i64,
+    pub api_tokens: i64,
+}
+
+impl InstanceStats {
+    pub async fn get(pool: &SqlitePool) -> Result<InstanceStats, sqlx::error::Error> {
+        timed(
+            "stats.get",
+            sqlx::query_as!(
+                InstanceStats,
+                r#"SELECT
+                    (SELECT COUNT(*) FROM users) AS "users!: i64",
+                    (SELECT COUNT(*) FROM users WHERE is_admin) AS "admins!: i64",
+                    (SELECT COUNT(*) FROM users WHERE suspended_at IS NOT NULL) AS "suspended_users!: i64",
+                    (SELECT COUNT(*) FROM codes WHERE deleted_at IS NULL) AS "codes!: i64",
+                    (SELECT COUNT(*) FROM codes WHERE deleted_at IS NOT NULL) AS "trashed_codes!: i64",
+                    (SELECT COUNT(*) FROM folders) AS "folders!: i64",
+                    (SELECT COUNT(*) FROM sessions) AS "sessions!: i64",
+                    (SELECT COUNT(*) FROM api_tokens) AS "api_tokens!: i64""#
+            )
+            .fetch_one(pool),
+        )
+        .await
+    }
+}
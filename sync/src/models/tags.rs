@@ -0,0 +1,102 @@
+use super::timed;
+use crate::utils;
+use serde::Serialize;
+use sqlx::{SqliteConnection, SqliteExecutor, SqlitePool};
+use utoipa::ToSchema;
+
+/// A tag of the user, with the codes carrying it.
+#[derive(Serialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct TagUsage {
+    pub name: String,
+    pub code_ids: Vec<String>,
+}
+
+/// Every tag of the owner in use by a code, sorted by name.
+pub async fn usage(pool: &SqlitePool, owner_id: &str) -> Result<Vec<TagUsage>, sqlx::error::Error> {
+    let now = chrono::Utc::now().timestamp();
+    let rows = timed(
+        "tags.usage",
+        sqlx::query!(
+            "SELECT tags.name, code_tags.code_id FROM tags JOIN code_tags ON code_tags.tag_id = tags.id JOIN codes ON codes.id = code_tags.code_id WHERE tags.owner_id = $1 AND codes.deleted_at IS NULL AND (codes.expires_at IS NULL OR codes.expires_at > $2) ORDER BY tags.name, codes.sort_index, codes.rowid",
+            owner_id,
+            now
+        )
+        .fetch_all(pool),
+    )
+    .await?;
+
+    let mut usage: Vec<TagUsage> = vec![];
+    for row in rows {
+        match usage.last_mut() {
+            Some(tag) if tag.name == row.name => tag.code_ids.push(row.code_id),
+            _ => usage.push(TagUsage {
+                name: row.name,
+                code_ids: vec![row.code_id],
+            }),
+        }
+    }
+
+    Ok(usage)
+}
+
+/// Names of the tags of the code, sorted.
+pub async fn of_code(
+    executor: impl SqliteExecutor<'_>,
+    code_id: &str,
+) -> Result<Vec<String>, sqlx::error::Error> {
+    timed(
+        "tags.of_code",
+        sqlx::query_scalar!(
+            "SELECT tags.name FROM tags JOIN code_tags ON code_tags.tag_id = tags.id WHERE code_tags.code_id = $1 ORDER BY tags.name",
+            code_id
+        )
+        .fetch_all(executor),
+    )
+    .await
+}
+
+/// Replaces the tags of the code, creating tags which don't exist yet, and removing those no
+/// longer in use. Has to run in the transaction changing the code, see
+/// [`crate::models::codes::CodeBatch::set_tags`].
+pub(crate) async fn replace(
+    conn: &mut SqliteConnection,
+    owner_id: &str,
+    code_id: &str,
+    names: &[String],
+) -> Result<(), sqlx::error::Error> {
+    timed("tags.replace", async {
+        sqlx::query!("DELETE FROM code_tags WHERE code_id = $1", code_id)
+            .execute(&mut *conn)
+            .await?;
+
+        for name in names {
+            let id = utils::generate_id(16);
+            sqlx::query!(
+                "INSERT INTO tags (id, owner_id, name) VALUES ($1, $2, $3) ON CONFLICT (owner_id, name) DO NOTHING",
+                id,
+                owner_id,
+                name
+            )
+            .execute(&mut *conn)
+            .await?;
+            sqlx::query!(
+                "INSERT INTO code_tags (code_id, tag_id) SELECT $1, id FROM tags WHERE owner_id = $2 AND name = $3",
+                code_id,
+                owner_id,
+                name
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+
+        sqlx::query!(
+            "DELETE FROM tags WHERE owner_id = $1 AND id NOT IN (SELECT tag_id FROM code_tags)",
+            owner_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    })
+    .await
+}
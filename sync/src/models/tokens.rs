@@ -0,0 +1,422 @@
+use super::timed;
+use crate::utils;
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use subtle::ConstantTimeEq;
+use utoipa::ToSchema;
+
+/// How long a verified token is remembered, skipping Argon2 for its following requests.
+const VERIFIED_TOKEN_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Hex digits of the SHA-256 hash of legacy tokens kept to find them, see [`ApiToken::legacy_lookup`].
+const LEGACY_LOOKUP_LENGTH: usize = 16;
+
+/// What a request is allowed to do. Sessions (JWTs) always have full access.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+pub enum TokenScope {
+    #[serde(rename = "full")]
+    Full,
+    /// List codes without their secret content. Can't modify anything. Every token may do this.
+    #[serde(rename = "read:metadata")]
+    ReadMetadata,
+    /// Read codes including their secrets, and export them. Can't modify anything.
+    #[serde(rename = "codes:read")]
+    CodesRead,
+    /// Add, edit, delete and import codes and folders. Includes `codes:read`.
+    #[serde(rename = "codes:write")]
+    CodesWrite,
+    /// Delete the account.
+    #[serde(rename = "account:delete")]
+    AccountDelete,
+}
+
+impl TokenScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenScope::Full => "full",
+            TokenScope::ReadMetadata => "read:metadata",
+            TokenScope::CodesRead => "codes:read",
+            TokenScope::CodesWrite => "codes:write",
+            TokenScope::AccountDelete => "account:delete",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "full" => Some(TokenScope::Full),
+            "read:metadata" => Some(TokenScope::ReadMetadata),
+            "codes:read" => Some(TokenScope::CodesRead),
+            "codes:write" => Some(TokenScope::CodesWrite),
+            "account:delete" => Some(TokenScope::AccountDelete),
+            _ => None,
+        }
+    }
+
+    /// Whether a token granted this scope may do what the other scope allows.
+    pub fn implies(&self, other: TokenScope) -> bool {
+        match (self, other) {
+            (TokenScope::Full, _) => true,
+            (_, TokenScope::ReadMetadata) => true,
+            (TokenScope::CodesWrite, TokenScope::CodesRead) => true,
+            _ => *self == other,
+        }
+    }
+}
+
+/// Scopes granted to a request. Stored and sent space separated, like OAuth scopes, e.g.
+/// `codes:read account:delete`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct TokenScopes(Vec<TokenScope>);
+
+impl TokenScopes {
+    pub fn full() -> Self {
+        TokenScopes(vec![TokenScope::Full])
+    }
+
+    /// Returns `None` if there are no scopes, or any of them is unknown.
+    pub fn parse(scopes: &str) -> Option<Self> {
+        let mut parsed = Vec::new();
+        for scope in scopes.split_whitespace() {
+            let scope = TokenScope::parse(scope)?;
+            if !parsed.contains(&scope) {
+                parsed.push(scope);
+            }
+        }
+
+        (!parsed.is_empty()).then_some(TokenScopes(parsed))
+    }
+
+    pub fn allows(&self, scope: TokenScope) -> bool {
+        self.0.iter().any(|granted| granted.implies(scope))
+    }
+}
+
+impl fmt::Display for TokenScopes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scopes: Vec<&str> = self.0.iter().map(TokenScope::as_str).collect();
+        f.write_str(&scopes.join(" "))
+    }
+}
+
+impl TryFrom<String> for TokenScopes {
+    type Error = String;
+
+    fn try_from(scopes: String) -> Result<Self, Self::Error> {
+        TokenScopes::parse(&scopes).ok_or_else(|| format!("Invalid token scopes: {scopes}"))
+    }
+}
+
+impl From<TokenScopes> for String {
+    fn from(scopes: TokenScopes) -> Self {
+        scopes.to_string()
+    }
+}
+
+/// A long-lived API token. Only an Argon2id hash of the token is stored.
+#[derive(Serialize, Deserialize, Clone, Debug, sqlx::FromRow)]
+pub struct ApiToken {
+    pub id: String,
+    pub user_id: String,
+    pub token_hash: String,
+    /// Space separated, see [`TokenScopes`].
+    pub scope: String,
+    pub created_at: i64,
+    /// Given by the user to tell their tokens apart, e.g. `Backup script`.
+    pub name: String,
+    /// Unix timestamp (seconds) of the last request authenticated by the token.
+    pub last_used_at: Option<i64>,
+    /// Start of the SHA-256 hash of tokens created before hashing with Argon2, which have no id to
+    /// be found by. Only set once they're rehashed.
+    pub legacy_lookup: Option<String>,
+}
+
+impl ApiToken {
+    /// Prefix of every API token, telling them apart from JWTs.
+    pub const PREFIX: &'static str = "ibt_";
+
+    pub const MAX_NAME_LENGTH: usize = 100;
+
+    /// Generates a token of the form `ibt_{id}_{secret}`, returning it next to its row.
+    /// The token itself can't be recovered from the row.
+    pub fn generate(user_id: String, name: String, scopes: &TokenScopes) -> (ApiToken, String) {
+        let id = utils::generate_id(16);
+        let secret = utils::generate_id(40);
+        let token = format!("{}{id}_{secret}", ApiToken::PREFIX);
+
+        let api_token = ApiToken {
+            id,
+            user_id,
+            token_hash: ApiToken::hash(&secret),
+            scope: scopes.to_string(),
+            created_at: chrono::Utc::now().timestamp(),
+            name,
+            last_used_at: None,
+            legacy_lookup: None,
+        };
+        (api_token, token)
+    }
+
+    /// Argon2id hash of the secret part of a token, in the PHC string format.
+    pub fn hash(secret: &str) -> String {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let salt = SaltString::encode_b64(&salt).expect("16 bytes are a valid salt");
+
+        Argon2::default()
+            .hash_password(secret.as_bytes(), &salt)
+            .expect("Unable to hash API token")
+            .to_string()
+    }
+
+    /// Checks the secret against the stored hash. The comparison is constant-time.
+    pub fn verify(&self, secret: &str) -> bool {
+        PasswordHash::new(&self.token_hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(secret.as_bytes(), &hash)
+                .is_ok()
+        })
+    }
+
+    /// Tokens created before hashing with Argon2 have no id, and are stored as a SHA-256 hash.
+    fn legacy_hash(token: &str) -> String {
+        base16ct::lower::encode_string(&Sha256::digest(token))
+    }
+
+    pub fn scopes(&self) -> Option<TokenScopes> {
+        TokenScopes::parse(&self.scope)
+    }
+
+    pub async fn get_by_token(
+        pool: &SqlitePool,
+        token: &str,
+        verified: &VerifiedTokens,
+    ) -> Result<Option<ApiToken>, sqlx::error::Error> {
+        let Some((id, secret)) = token
+            .strip_prefix(ApiToken::PREFIX)
+            .and_then(|token| token.split_once('_'))
+        else {
+            return ApiToken::get_by_legacy_token(pool, token, verified).await;
+        };
+
+        let api_token = timed(
+            "api_tokens.get_by_token",
+            sqlx::query_as!(ApiToken, "SELECT * FROM api_tokens WHERE id = ?", id)
+                .fetch_optional(pool),
+        )
+        .await?;
+
+        Ok(match api_token {
+            Some(api_token) => api_token.check(secret, verified).await,
+            None => None,
+        })
+    }
+
+    /// Tokens created before hashing with Argon2, found by their SHA-256 hash. It's replaced by an
+    /// Argon2id hash once they're used, after which they're verified like other tokens.
+    async fn get_by_legacy_token(
+        pool: &SqlitePool,
+        token: &str,
+        verified: &VerifiedTokens,
+    ) -> Result<Option<ApiToken>, sqlx::error::Error> {
+        let token_hash = ApiToken::legacy_hash(token);
+        let lookup = &token_hash[..LEGACY_LOOKUP_LENGTH];
+
+        let api_token = timed(
+            "api_tokens.get_by_legacy_token",
+            sqlx::query_as!(
+                ApiToken,
+                "SELECT * FROM api_tokens WHERE token_hash = $1 OR legacy_lookup = $2",
+                token_hash,
+                lookup
+            )
+            .fetch_optional(pool),
+        )
+        .await?;
+        let Some(mut api_token) = api_token else {
+            return Ok(None);
+        };
+        if api_token.legacy_lookup.is_some() {
+            return Ok(api_token.check(token, verified).await);
+        }
+
+        let rehashed = {
+            let token = token.to_string();
+            tokio::task::spawn_blocking(move || ApiToken::hash(&token))
+                .await
+                .expect("Unable to hash API token")
+        };
+        timed(
+            "api_tokens.rehash_legacy",
+            sqlx::query!(
+                "UPDATE api_tokens SET token_hash = $1, legacy_lookup = $2 WHERE id = $3",
+                rehashed,
+                lookup,
+                api_token.id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        api_token.token_hash = rehashed;
+        api_token.legacy_lookup = Some(lookup.to_string());
+        verified.insert(&api_token, token);
+        Ok(Some(api_token))
+    }
+
+    /// The token, if the secret matches it. Argon2 runs on a blocking thread, as it's too slow on
+    /// purpose to hold up other requests, and only once in a while for the same secret.
+    async fn check(self, secret: &str, verified: &VerifiedTokens) -> Option<ApiToken> {
+        if verified.contains(&self, secret) {
+            return Some(self);
+        }
+
+        let (api_token, valid) = {
+            let secret = secret.to_string();
+            tokio::task::spawn_blocking(move || {
+                let valid = self.verify(&secret);
+                (self, valid)
+            })
+            .await
+            .expect("Unable to verify API token")
+        };
+        if !valid {
+            return None;
+        }
+        verified.insert(&api_token, secret);
+        Some(api_token)
+    }
+
+    pub async fn get(pool: &SqlitePool, id: &str) -> Result<Option<ApiToken>, sqlx::error::Error> {
+        timed(
+            "api_tokens.get",
+            sqlx::query_as!(ApiToken, "SELECT * FROM api_tokens WHERE id = ?", id)
+                .fetch_optional(pool),
+        )
+        .await
+    }
+
+    /// Tokens of the user, most recently created first.
+    pub async fn get_for_user(
+        pool: &SqlitePool,
+        user_id: &str,
+    ) -> Result<Vec<ApiToken>, sqlx::error::Error> {
+        timed(
+            "api_tokens.get_for_user",
+            sqlx::query_as!(
+                ApiToken,
+                "SELECT * FROM api_tokens WHERE user_id = ? ORDER BY created_at DESC, id",
+                user_id
+            )
+            .fetch_all(pool),
+        )
+        .await
+    }
+
+    pub async fn insert(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed("api_tokens.insert", sqlx::query!(
+			"INSERT INTO api_tokens (id, user_id, token_hash, scope, created_at, name, last_used_at) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+			self.id, self.user_id, self.token_hash, self.scope, self.created_at, self.name, self.last_used_at).execute(pool)).await?;
+
+        Ok(())
+    }
+
+    pub async fn touch(&mut self, pool: &SqlitePool, now: i64) -> Result<(), sqlx::error::Error> {
+        timed(
+            "api_tokens.touch",
+            sqlx::query!(
+                "UPDATE api_tokens SET last_used_at = $1 WHERE id = $2",
+                now,
+                self.id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        self.last_used_at = Some(now);
+        Ok(())
+    }
+
+    pub async fn rename(
+        &mut self,
+        pool: &SqlitePool,
+        name: String,
+    ) -> Result<(), sqlx::error::Error> {
+        timed(
+            "api_tokens.rename",
+            sqlx::query!(
+                "UPDATE api_tokens SET name = $1 WHERE id = $2",
+                name,
+                self.id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        self.name = name;
+        Ok(())
+    }
+
+    pub async fn delete(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "api_tokens.delete",
+            sqlx::query!("DELETE FROM api_tokens WHERE id = $1", self.id).execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Tokens which were verified recently, as Argon2 is too slow on purpose to run on every request.
+#[derive(Clone, Debug, Default)]
+pub struct VerifiedTokens {
+    /// Time of verification, and a fingerprint of the secret and the hash it was verified against, per token id.
+    verified: Arc<Mutex<HashMap<String, (Instant, [u8; 32])>>>,
+}
+
+impl VerifiedTokens {
+    /// Changes with the stored hash, so a replaced token isn't accepted from the cache.
+    fn fingerprint(api_token: &ApiToken, secret: &str) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(&api_token.token_hash)
+            .chain_update([0u8])
+            .chain_update(secret)
+            .finalize()
+            .into()
+    }
+
+    pub fn contains(&self, api_token: &ApiToken, secret: &str) -> bool {
+        let now = Instant::now();
+        let mut verified = self.verified.lock().unwrap();
+        verified.retain(|_, (at, _)| now.duration_since(*at) < VERIFIED_TOKEN_TTL);
+
+        verified.get(&api_token.id).is_some_and(|(_, fingerprint)| {
+            fingerprint
+                .ct_eq(&VerifiedTokens::fingerprint(api_token, secret))
+                .into()
+        })
+    }
+
+    pub fn insert(&self, api_token: &ApiToken, secret: &str) {
+        self.verified.lock().unwrap().insert(
+            api_token.id.clone(),
+            (
+                Instant::now(),
+                VerifiedTokens::fingerprint(api_token, secret),
+            ),
+        );
+    }
+}
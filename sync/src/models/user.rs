@@ -1,5 +1,6 @@
+use super::timed;
 use serde::{Deserialize, Serialize};
-use sqlx::SqlitePool;
+use sqlx::{SqliteExecutor, SqlitePool};
 
 #[derive(Serialize, Deserialize, Clone, Debug, sqlx::FromRow)]
 pub struct User {
@@ -8,6 +9,27 @@ pub struct User {
     pub display_name: String,
     pub avatar_url: String,
     pub upstream_userid: String,
+    /// Issuer of the OpenID provider `upstream_userid` is the subject at. Empty for users from
+    /// before multiple providers were supported, see [`User::claim_legacy_identity`].
+    pub upstream_issuer: String,
+    /// Admins can manage the instance, e.g. create backups.
+    pub is_admin: bool,
+    /// Reject adding or renaming a code to a name which another code of the user already has.
+    pub enforce_unique_names: bool,
+    /// Only store code contents encrypted by the clients, see [`crate::e2e`].
+    pub encryption_enabled: bool,
+    /// Opaque value derived from the end-to-end encryption key, for clients to verify theirs with.
+    pub encryption_key_check: Option<String>,
+    /// Unix timestamp (seconds) at which an admin suspended the user. Suspended users can't log in
+    /// or use their sessions and tokens, but keep their codes.
+    pub suspended_at: Option<i64>,
+    /// Unix timestamp (seconds) at which the account is deleted, if the user asked for it. The
+    /// user can cancel the deletion until then.
+    pub delete_at: Option<i64>,
+    /// Address security notifications are sent to, see [`crate::mail`].
+    pub email: Option<String>,
+    /// Whether the user wants to be emailed about security events.
+    pub email_notifications: bool,
 }
 
 impl User {
@@ -15,33 +37,271 @@ impl User {
         pool: &SqlitePool,
         id: String,
     ) -> Result<Option<User>, sqlx::error::Error> {
-        sqlx::query_as!(User, "SELECT * FROM users WHERE id = ?", id)
-            .fetch_optional(pool)
-            .await
+        timed(
+            "users.get_by_id",
+            sqlx::query_as!(User, "SELECT * FROM users WHERE id = ?", id).fetch_optional(pool),
+        )
+        .await
     }
 
+    /// The user with the identity, either the one the account was created with or a linked one, see
+    /// [`super::identities::Identity`].
     pub async fn get_by_upstream_id(
         pool: &SqlitePool,
-        id: String,
+        issuer: &str,
+        id: &str,
+    ) -> Result<Option<User>, sqlx::error::Error> {
+        timed(
+            "users.get_by_upstream_id",
+            sqlx::query_as!(
+                User,
+                "SELECT * FROM users WHERE (upstream_issuer = $1 AND upstream_userid = $2) OR id = (SELECT user_id FROM identities WHERE issuer = $1 AND subject = $2)",
+                issuer,
+                id
+            )
+            .fetch_optional(pool),
+        )
+        .await
+    }
+
+    /// Assigns the user without an issuer with the subject to the issuer. Those users were created
+    /// by the default provider, before identities were keyed by issuer.
+    pub async fn claim_legacy_identity(
+        pool: &SqlitePool,
+        issuer: &str,
+        id: &str,
+    ) -> Result<Option<User>, sqlx::error::Error> {
+        timed(
+            "users.claim_legacy_identity",
+            sqlx::query_as!(
+                User,
+                "UPDATE users SET upstream_issuer = $1 WHERE upstream_issuer = '' AND upstream_userid = $2 RETURNING *",
+                issuer,
+                id
+            )
+            .fetch_optional(pool),
+        )
+        .await
+    }
+
+    pub async fn get_by_username(
+        pool: &SqlitePool,
+        username: String,
     ) -> Result<Option<User>, sqlx::error::Error> {
-        sqlx::query_as!(User, "SELECT * FROM users WHERE upstream_userid = ?", id)
-            .fetch_optional(pool)
-            .await
+        timed(
+            "users.get_by_username",
+            sqlx::query_as!(User, "SELECT * FROM users WHERE username = ?", username)
+                .fetch_optional(pool),
+        )
+        .await
+    }
+
+    /// Every user of the instance, ordered by username.
+    pub async fn get_all(pool: &SqlitePool) -> Result<Vec<User>, sqlx::error::Error> {
+        timed(
+            "users.get_all",
+            sqlx::query_as!(User, "SELECT * FROM users ORDER BY username, id").fetch_all(pool),
+        )
+        .await
     }
 
     pub async fn insert(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
-        sqlx::query!(
-			"INSERT INTO users (id, username, display_name, avatar_url, upstream_userid) VALUES ($1, $2, $3, $4, $5)",
-			self.id, self.username, self.display_name, self.avatar_url, self.upstream_userid).execute(pool).await?;
+        timed("users.insert", sqlx::query!(
+			"INSERT INTO users (id, username, display_name, avatar_url, upstream_userid, upstream_issuer, is_admin, enforce_unique_names, encryption_enabled, encryption_key_check, suspended_at, delete_at, email, email_notifications) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)",
+			self.id, self.username, self.display_name, self.avatar_url, self.upstream_userid, self.upstream_issuer, self.is_admin, self.enforce_unique_names, self.encryption_enabled, self.encryption_key_check, self.suspended_at, self.delete_at, self.email, self.email_notifications).execute(pool)).await?;
 
         Ok(())
     }
 
+    pub async fn set_enforce_unique_names(
+        &mut self,
+        pool: &SqlitePool,
+        enforce: bool,
+    ) -> Result<(), sqlx::error::Error> {
+        timed(
+            "users.set_enforce_unique_names",
+            sqlx::query!(
+                "UPDATE users SET enforce_unique_names = $1 WHERE id = $2",
+                enforce,
+                self.id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        self.enforce_unique_names = enforce;
+        Ok(())
+    }
+
+    pub async fn set_admin(
+        &mut self,
+        pool: &SqlitePool,
+        is_admin: bool,
+    ) -> Result<(), sqlx::error::Error> {
+        timed(
+            "users.set_admin",
+            sqlx::query!(
+                "UPDATE users SET is_admin = $1 WHERE id = $2",
+                is_admin,
+                self.id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        self.is_admin = is_admin;
+        Ok(())
+    }
+
+    /// Suspends the user since the timestamp, or lifts the suspension with `None`.
+    pub async fn set_suspended(
+        &mut self,
+        pool: &SqlitePool,
+        suspended_at: Option<i64>,
+    ) -> Result<(), sqlx::error::Error> {
+        timed(
+            "users.set_suspended",
+            sqlx::query!(
+                "UPDATE users SET suspended_at = $1 WHERE id = $2",
+                suspended_at,
+                self.id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        self.suspended_at = suspended_at;
+        Ok(())
+    }
+
+    /// Schedules the account to be deleted at the timestamp, or cancels the deletion with `None`.
+    pub async fn set_delete_at(
+        &mut self,
+        pool: &SqlitePool,
+        delete_at: Option<i64>,
+    ) -> Result<(), sqlx::error::Error> {
+        timed(
+            "users.set_delete_at",
+            sqlx::query!(
+                "UPDATE users SET delete_at = $1 WHERE id = $2",
+                delete_at,
+                self.id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        self.delete_at = delete_at;
+        Ok(())
+    }
+
+    /// Updates the display name and avatar to those at the OpenID provider.
+    pub async fn set_profile(
+        &mut self,
+        pool: &SqlitePool,
+        display_name: String,
+        avatar_url: String,
+    ) -> Result<(), sqlx::error::Error> {
+        timed(
+            "users.set_profile",
+            sqlx::query!(
+                "UPDATE users SET display_name = $1, avatar_url = $2 WHERE id = $3",
+                display_name,
+                avatar_url,
+                self.id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        self.display_name = display_name;
+        self.avatar_url = avatar_url;
+        Ok(())
+    }
+
+    /// Updates the address notifications are emailed to, e.g. to the one at the OpenID provider.
+    pub async fn set_email(
+        &mut self,
+        pool: &SqlitePool,
+        email: Option<String>,
+    ) -> Result<(), sqlx::error::Error> {
+        timed(
+            "users.set_email",
+            sqlx::query!("UPDATE users SET email = $1 WHERE id = $2", email, self.id).execute(pool),
+        )
+        .await?;
+
+        self.email = email;
+        Ok(())
+    }
+
+    pub async fn set_email_notifications(
+        &mut self,
+        pool: &SqlitePool,
+        enabled: bool,
+    ) -> Result<(), sqlx::error::Error> {
+        timed(
+            "users.set_email_notifications",
+            sqlx::query!(
+                "UPDATE users SET email_notifications = $1 WHERE id = $2",
+                enabled,
+                self.id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        self.email_notifications = enabled;
+        Ok(())
+    }
+
+    /// Enables end-to-end encryption with the key check, or disables it without one.
+    pub async fn set_encryption(
+        &mut self,
+        executor: impl SqliteExecutor<'_>,
+        key_check: Option<String>,
+    ) -> Result<(), sqlx::error::Error> {
+        let enabled = key_check.is_some();
+        timed(
+            "users.set_encryption",
+            sqlx::query!(
+                "UPDATE users SET encryption_enabled = $1, encryption_key_check = $2 WHERE id = $3",
+                enabled,
+                key_check,
+                self.id
+            )
+            .execute(executor),
+        )
+        .await?;
+
+        self.encryption_enabled = enabled;
+        self.encryption_key_check = key_check;
+        Ok(())
+    }
+
     pub async fn delete(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
-        sqlx::query!("DELETE from users WHERE id = $1", self.id)
-            .execute(pool)
-            .await?;
+        timed(
+            "users.delete",
+            sqlx::query!("DELETE from users WHERE id = $1", self.id).execute(pool),
+        )
+        .await?;
 
         Ok(())
     }
+
+    /// Deletes the accounts scheduled for deletion at or before the timestamp, returning their ids.
+    pub async fn delete_scheduled(
+        pool: &SqlitePool,
+        now: i64,
+    ) -> Result<Vec<String>, sqlx::error::Error> {
+        timed(
+            "users.delete_scheduled",
+            sqlx::query_scalar!(
+                r#"DELETE FROM users WHERE delete_at <= $1 RETURNING id as "id!: String""#,
+                now
+            )
+            .fetch_all(pool),
+        )
+        .await
+    }
 }
@@ -0,0 +1,281 @@
+use super::timed;
+use crate::events::EventKind;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use utoipa::ToSchema;
+
+/// URL receiving the events of a user as signed JSON, or of every user if registered by an admin.
+#[derive(Clone, Debug, sqlx::FromRow, PartialEq)]
+pub struct Webhook {
+    pub id: String,
+    /// Unset for webhooks of the instance, which receive the events of every user.
+    pub user_id: Option<String>,
+    pub url: String,
+    /// Payloads are signed with it, so receivers can tell they come from the instance.
+    pub secret: String,
+    /// Space separated kinds of events the webhook receives, empty for every kind.
+    pub events: String,
+    pub created_at: i64,
+}
+
+impl Webhook {
+    pub fn receives(&self, kind: EventKind) -> bool {
+        self.events.is_empty() || self.events.split(' ').any(|name| name == kind.name())
+    }
+
+    pub async fn insert(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "webhooks.insert",
+            sqlx::query!(
+                "INSERT INTO webhooks (id, user_id, url, secret, events, created_at) VALUES ($1, $2, $3, $4, $5, $6)",
+                self.id,
+                self.user_id,
+                self.url,
+                self.secret,
+                self.events,
+                self.created_at
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// The webhook of the user, or of the instance if `user_id` is unset.
+    pub async fn get(
+        pool: &SqlitePool,
+        id: &str,
+        user_id: Option<&str>,
+    ) -> Result<Option<Webhook>, sqlx::error::Error> {
+        timed(
+            "webhooks.get",
+            sqlx::query_as!(
+                Webhook,
+                "SELECT * FROM webhooks WHERE id = $1 AND user_id IS $2",
+                id,
+                user_id
+            )
+            .fetch_optional(pool),
+        )
+        .await
+    }
+
+    /// Webhooks of the user, or of the instance if `user_id` is unset, oldest first.
+    pub async fn list(
+        pool: &SqlitePool,
+        user_id: Option<&str>,
+    ) -> Result<Vec<Webhook>, sqlx::error::Error> {
+        timed(
+            "webhooks.list",
+            sqlx::query_as!(
+                Webhook,
+                "SELECT * FROM webhooks WHERE user_id IS $1 ORDER BY created_at, rowid",
+                user_id
+            )
+            .fetch_all(pool),
+        )
+        .await
+    }
+
+    /// Webhooks receiving the events of the user: their own, and those of the instance.
+    pub async fn for_user(
+        pool: &SqlitePool,
+        user_id: &str,
+    ) -> Result<Vec<Webhook>, sqlx::error::Error> {
+        timed(
+            "webhooks.for_user",
+            sqlx::query_as!(
+                Webhook,
+                "SELECT * FROM webhooks WHERE user_id = $1 OR user_id IS NULL",
+                user_id
+            )
+            .fetch_all(pool),
+        )
+        .await
+    }
+
+    pub async fn count_for_user(
+        pool: &SqlitePool,
+        user_id: &str,
+    ) -> Result<i64, sqlx::error::Error> {
+        timed(
+            "webhooks.count_for_user",
+            sqlx::query_scalar!(
+                r#"SELECT COUNT(*) as "count!: i64" FROM webhooks WHERE user_id = $1"#,
+                user_id
+            )
+            .fetch_one(pool),
+        )
+        .await
+    }
+
+    /// Removes the webhook with its delivery log.
+    pub async fn delete(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "webhooks.delete",
+            sqlx::query!("DELETE FROM webhooks WHERE id = $1", self.id).execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryState {
+    /// Waiting for its first or next attempt.
+    Pending,
+    Delivered,
+    /// Every attempt failed, it won't be retried.
+    Failed,
+}
+
+/// An event sent, or to be sent, to a webhook.
+#[derive(Serialize, Deserialize, Clone, Debug, sqlx::FromRow, PartialEq, ToSchema)]
+pub struct WebhookDelivery {
+    pub id: String,
+    #[serde(skip)]
+    pub webhook_id: String,
+    /// Kind of the event, e.g. `code.created`.
+    pub event: String,
+    /// The JSON sent as body.
+    pub payload: String,
+    pub attempts: i64,
+    /// HTTP status the receiver responded to the last attempt with.
+    pub response_status: Option<i64>,
+    /// Why the last attempt failed, if it did.
+    pub error: Option<String>,
+    pub created_at: i64,
+    /// Unix timestamp (seconds) of the next attempt, unset once delivered or given up on.
+    pub next_attempt_at: Option<i64>,
+    pub delivered_at: Option<i64>,
+}
+
+/// A delivery due for an attempt, with where to send it.
+#[derive(Clone, Debug, sqlx::FromRow, PartialEq)]
+pub struct DueDelivery {
+    pub id: String,
+    pub event: String,
+    pub payload: String,
+    pub attempts: i64,
+    pub url: String,
+    pub secret: String,
+    /// Unset for webhooks of the instance, which may post to internal hosts.
+    pub user_id: Option<String>,
+}
+
+impl WebhookDelivery {
+    pub fn state(&self) -> DeliveryState {
+        match (self.delivered_at, self.next_attempt_at) {
+            (Some(_), _) => DeliveryState::Delivered,
+            (None, Some(_)) => DeliveryState::Pending,
+            (None, None) => DeliveryState::Failed,
+        }
+    }
+
+    pub async fn insert(&self, pool: &SqlitePool) -> Result<(), sqlx::error::Error> {
+        timed(
+            "webhook_deliveries.insert",
+            sqlx::query!(
+                "INSERT INTO webhook_deliveries (id, webhook_id, event, payload, attempts, response_status, error, created_at, next_attempt_at, delivered_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+                self.id,
+                self.webhook_id,
+                self.event,
+                self.payload,
+                self.attempts,
+                self.response_status,
+                self.error,
+                self.created_at,
+                self.next_attempt_at,
+                self.delivered_at
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// The most recent deliveries to the webhook, newest first.
+    pub async fn list(
+        pool: &SqlitePool,
+        webhook_id: &str,
+        limit: i64,
+    ) -> Result<Vec<WebhookDelivery>, sqlx::error::Error> {
+        timed(
+            "webhook_deliveries.list",
+            sqlx::query_as!(
+                WebhookDelivery,
+                "SELECT * FROM webhook_deliveries WHERE webhook_id = $1 ORDER BY created_at DESC, rowid DESC LIMIT $2",
+                webhook_id,
+                limit
+            )
+            .fetch_all(pool),
+        )
+        .await
+    }
+
+    /// Deliveries whose next attempt is due at `now`, oldest first.
+    pub async fn due(
+        pool: &SqlitePool,
+        now: i64,
+        limit: i64,
+    ) -> Result<Vec<DueDelivery>, sqlx::error::Error> {
+        timed(
+            "webhook_deliveries.due",
+            sqlx::query_as!(
+                DueDelivery,
+                "SELECT webhook_deliveries.id, webhook_deliveries.event, webhook_deliveries.payload, webhook_deliveries.attempts, webhooks.url, webhooks.secret, webhooks.user_id FROM webhook_deliveries INNER JOIN webhooks ON webhooks.id = webhook_deliveries.webhook_id WHERE webhook_deliveries.next_attempt_at <= $1 ORDER BY webhook_deliveries.next_attempt_at, webhook_deliveries.rowid LIMIT $2",
+                now,
+                limit
+            )
+            .fetch_all(pool),
+        )
+        .await
+    }
+
+    /// Stores the outcome of an attempt. `next_attempt_at` is unset once delivered or given up on.
+    pub async fn record_attempt(
+        pool: &SqlitePool,
+        id: &str,
+        attempts: i64,
+        response_status: Option<i64>,
+        error: Option<String>,
+        next_attempt_at: Option<i64>,
+        delivered_at: Option<i64>,
+    ) -> Result<(), sqlx::error::Error> {
+        timed(
+            "webhook_deliveries.record_attempt",
+            sqlx::query!(
+                "UPDATE webhook_deliveries SET attempts = $1, response_status = $2, error = $3, next_attempt_at = $4, delivered_at = $5 WHERE id = $6",
+                attempts,
+                response_status,
+                error,
+                next_attempt_at,
+                delivered_at,
+                id
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Removes deliveries made before `before` which are delivered or given up on.
+    pub async fn prune(pool: &SqlitePool, before: i64) -> Result<u64, sqlx::error::Error> {
+        let result = timed(
+            "webhook_deliveries.prune",
+            sqlx::query!(
+                "DELETE FROM webhook_deliveries WHERE created_at < $1 AND next_attempt_at IS NULL",
+                before
+            )
+            .execute(pool),
+        )
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
@@ -0,0 +1,99 @@
+use crate::utils;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long a user has to log in at the OpenID provider.
+pub const LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// Pending logins at once, so unauthenticated requests can't grow the map forever.
+const MAX_PENDING: usize = 10_000;
+
+/// A login which was sent to the OpenID provider, waiting for the user to come back.
+#[derive(Clone, Debug)]
+pub struct PendingLogin {
+    /// Name of the provider the login was started with.
+    pub provider: String,
+    /// Sent to the provider, which has to put it in the ID token.
+    pub nonce: String,
+    /// Redirect URI of a native app the login was started by, see [`crate::auth::native_redirect_uri`].
+    /// `None` for logins of the web frontend, which come back to the OAuth redirect URI.
+    pub redirect_uri: Option<String>,
+    created: Instant,
+}
+
+/// The `state` of logins started at `/v1/oauth/authorize`. Logging in only accepts a state which
+/// was handed out here, once, so a login can't be forced onto someone else (CSRF) or replayed.
+#[derive(Clone, Debug, Default)]
+pub struct OauthStates(Arc<Mutex<HashMap<String, PendingLogin>>>);
+
+impl OauthStates {
+    /// Starts a login with the provider, returning its state and nonce. Returns `None` if too many
+    /// logins are pending.
+    pub fn start(&self, provider: &str, redirect_uri: Option<&str>) -> Option<(String, String)> {
+        let mut pending = self.0.lock().unwrap();
+        pending.retain(|_, login| login.created.elapsed() < LIFETIME);
+        if pending.len() >= MAX_PENDING {
+            return None;
+        }
+
+        let state = utils::generate_id(32);
+        let nonce = utils::generate_id(32);
+        pending.insert(
+            state.clone(),
+            PendingLogin {
+                provider: provider.to_string(),
+                nonce: nonce.clone(),
+                redirect_uri: redirect_uri.map(str::to_string),
+                created: Instant::now(),
+            },
+        );
+
+        Some((state, nonce))
+    }
+
+    /// The pending login with the state, without finishing it.
+    pub fn get(&self, state: &str) -> Option<PendingLogin> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(state)
+            .filter(|login| login.created.elapsed() < LIFETIME)
+            .cloned()
+    }
+
+    /// Finishes the login with the state. Returns `None` if the state expired, was already used,
+    /// or never existed.
+    pub fn finish(&self, state: &str) -> Option<PendingLogin> {
+        self.0
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|login| login.created.elapsed() < LIFETIME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    #[gtest]
+    fn state_works_once() {
+        let states = OauthStates::default();
+        let (state, nonce) = states.start("default", None).unwrap();
+
+        expect_that!(states.get(&state), some(anything()));
+        let login = states.finish(&state);
+        assert_that!(login, some(anything()));
+        let login = login.unwrap();
+        expect_that!(login.provider, eq("default"));
+        expect_that!(login.nonce, eq(&nonce));
+        expect_that!(login.redirect_uri, none());
+
+        expect_that!(states.finish(&state), none());
+        expect_that!(states.finish("made up"), none());
+    }
+}
@@ -0,0 +1,512 @@
+use crate::{
+    models::codes::Code,
+    totp::{self, Algorithm, Kind},
+};
+use percent_encoding::percent_decode_str;
+use qrcode::{render::svg, types::QrError, QrCode};
+use reqwest::Url;
+
+/// Contents of an `otpauth://totp/` URI, or an `otpauth://steam/` URI of a Steam Guard code.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OtpAuth {
+    /// The label of the URI, like `Work email` or `Google:me@example.com`.
+    pub display_name: String,
+    /// Base32 secret, uppercased and without padding or spaces.
+    pub secret: String,
+    /// The `issuer` parameter, or the prefix of the label if the parameter is missing.
+    pub issuer: Option<String>,
+    /// The label without the issuer prefix, like `me@example.com`.
+    pub account: String,
+    /// The `algorithm`, `digits` and `period` parameters, defaulting to those of RFC 6238. Those
+    /// of Steam Guard codes are fixed.
+    pub params: totp::Params,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OtpAuthError {
+    /// Not an `otpauth://` URI.
+    InvalidUri,
+    /// Only TOTP is supported, HOTP counters can't be synced safely.
+    UnsupportedType,
+    MissingLabel,
+    MissingSecret,
+    /// The secret isn't valid base32.
+    InvalidSecret,
+    InvalidDigits,
+    InvalidPeriod,
+    UnsupportedAlgorithm,
+    /// The kind of a code is neither `totp` nor `steam`.
+    UnsupportedKind,
+    /// Steam Guard codes always use the same parameters.
+    SteamParams,
+}
+
+/// Amounts of digits codes may have.
+pub const DIGITS: std::ops::RangeInclusive<u32> = 6..=8;
+/// Periods (seconds) codes may be valid for.
+pub const PERIODS: std::ops::RangeInclusive<i64> = 1..=300;
+
+impl OtpAuthError {
+    /// Part of the URI which is invalid.
+    pub fn field(&self) -> &'static str {
+        match self {
+            OtpAuthError::InvalidUri => "uri",
+            OtpAuthError::UnsupportedType => "type",
+            OtpAuthError::MissingLabel => "label",
+            OtpAuthError::MissingSecret | OtpAuthError::InvalidSecret => "secret",
+            OtpAuthError::InvalidDigits => "digits",
+            OtpAuthError::InvalidPeriod => "period",
+            OtpAuthError::UnsupportedAlgorithm => "algorithm",
+            OtpAuthError::UnsupportedKind | OtpAuthError::SteamParams => "kind",
+        }
+    }
+}
+
+impl std::fmt::Display for OtpAuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OtpAuthError::InvalidUri => write!(f, "Not an otpauth:// URI"),
+            OtpAuthError::UnsupportedType => write!(f, "Only totp and steam URIs are supported"),
+            OtpAuthError::MissingLabel => write!(f, "The URI has no label"),
+            OtpAuthError::MissingSecret => write!(f, "The URI has no secret"),
+            OtpAuthError::InvalidSecret => write!(f, "The secret is not valid base32"),
+            OtpAuthError::InvalidDigits => write!(
+                f,
+                "Codes must have between {} and {} digits",
+                DIGITS.start(),
+                DIGITS.end()
+            ),
+            OtpAuthError::InvalidPeriod => write!(
+                f,
+                "The period must be between {} and {} seconds",
+                PERIODS.start(),
+                PERIODS.end()
+            ),
+            OtpAuthError::UnsupportedAlgorithm => {
+                write!(
+                    f,
+                    "Only the SHA1, SHA256 and SHA512 algorithms are supported"
+                )
+            }
+            OtpAuthError::UnsupportedKind => write!(f, "Only totp and steam codes are supported"),
+            OtpAuthError::SteamParams => write!(
+                f,
+                "Steam codes always have 5 characters, and use SHA1 with a period of 30 seconds"
+            ),
+        }
+    }
+}
+
+/// Parses an `otpauth://totp/` URI, as produced by [`to_uri`] and most authenticator apps.
+pub fn parse(uri: &str) -> Result<OtpAuth, OtpAuthError> {
+    let uri = Url::parse(uri.trim()).map_err(|_| OtpAuthError::InvalidUri)?;
+    if uri.scheme() != "otpauth" {
+        return Err(OtpAuthError::InvalidUri);
+    }
+    let kind = match uri.host_str() {
+        Some(kind) if kind.eq_ignore_ascii_case("hotp") => {
+            return Err(OtpAuthError::UnsupportedType)
+        }
+        Some(kind) => Kind::parse(kind).ok_or(OtpAuthError::InvalidUri)?,
+        None => return Err(OtpAuthError::InvalidUri),
+    };
+
+    let display_name = percent_decode_str(uri.path().trim_start_matches('/'))
+        .decode_utf8()
+        .map_err(|_| OtpAuthError::InvalidUri)?
+        .trim()
+        .to_string();
+    if display_name.is_empty() {
+        return Err(OtpAuthError::MissingLabel);
+    }
+
+    let mut secret = None;
+    let mut issuer = None;
+    let mut params = totp::Params::default();
+    for (key, value) in uri.query_pairs() {
+        match key.as_ref() {
+            "secret" => secret = Some(value.to_string()),
+            "issuer" if !value.trim().is_empty() => issuer = Some(value.trim().to_string()),
+            "algorithm" => {
+                params.algorithm =
+                    Algorithm::parse(value.trim()).ok_or(OtpAuthError::UnsupportedAlgorithm)?
+            }
+            "digits" => {
+                params.digits = value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|digits| DIGITS.contains(digits))
+                    .ok_or(OtpAuthError::InvalidDigits)?
+            }
+            "period" => {
+                params.period = value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|period| PERIODS.contains(period))
+                    .ok_or(OtpAuthError::InvalidPeriod)?
+            }
+            _ => {}
+        }
+    }
+
+    let secret: String = secret
+        .ok_or(OtpAuthError::MissingSecret)?
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '=')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if secret.is_empty() {
+        return Err(OtpAuthError::MissingSecret);
+    }
+    if !secret.chars().all(|c| matches!(c, 'A'..='Z' | '2'..='7')) {
+        return Err(OtpAuthError::InvalidSecret);
+    }
+
+    if kind == Kind::Steam {
+        params = totp::Params::STEAM;
+    }
+
+    let (prefix, account) = match display_name.split_once(':') {
+        Some((prefix, account)) if !prefix.trim().is_empty() => {
+            (Some(prefix.trim().to_string()), account.trim().to_string())
+        }
+        _ => (None, display_name.clone()),
+    };
+
+    Ok(OtpAuth {
+        issuer: issuer.or(prefix),
+        display_name,
+        secret,
+        account,
+        params,
+    })
+}
+
+/// Replaces the parameters with those given as separate fields, like in the payload of a code.
+/// They are validated as they would be in a URI. Changing the kind starts over from its defaults.
+pub fn override_params(
+    mut params: totp::Params,
+    kind: Option<&str>,
+    algorithm: Option<&str>,
+    digits: Option<u32>,
+    period: Option<i64>,
+) -> Result<totp::Params, OtpAuthError> {
+    if let Some(kind) = kind {
+        let kind = Kind::parse(kind.trim()).ok_or(OtpAuthError::UnsupportedKind)?;
+        if kind != params.kind {
+            params = match kind {
+                Kind::Totp => totp::Params::default(),
+                Kind::Steam => totp::Params::STEAM,
+            };
+        }
+    }
+    if params.kind == Kind::Steam {
+        if algorithm.is_some() || digits.is_some() || period.is_some() {
+            return Err(OtpAuthError::SteamParams);
+        }
+        return Ok(params);
+    }
+
+    if let Some(algorithm) = algorithm {
+        params.algorithm =
+            Algorithm::parse(algorithm.trim()).ok_or(OtpAuthError::UnsupportedAlgorithm)?;
+    }
+    if let Some(digits) = digits {
+        if !DIGITS.contains(&digits) {
+            return Err(OtpAuthError::InvalidDigits);
+        }
+        params.digits = digits;
+    }
+    if let Some(period) = period {
+        if !PERIODS.contains(&period) {
+            return Err(OtpAuthError::InvalidPeriod);
+        }
+        params.period = period;
+    }
+
+    Ok(params)
+}
+
+/// Serializes a code as an `otpauth://totp/` URI, as understood by most authenticator apps.
+/// Steam Guard codes become `otpauth://steam/` URIs, as written by Aegis.
+pub fn to_uri(code: &Code) -> String {
+    let params = code.totp_params();
+    let mut uri = Url::parse(&format!("otpauth://{}/", params.kind.as_str()))
+        .expect("otpauth URIs of every kind are valid");
+    uri.path_segments_mut()
+        .expect("otpauth URIs have a path")
+        .clear()
+        .push(&code.display_name);
+
+    {
+        let mut query = uri.query_pairs_mut();
+        query.append_pair("secret", &code.content);
+        if let Some(issuer) = code.issuer.as_ref().or(code.website_url.as_ref()) {
+            query.append_pair("issuer", issuer);
+        }
+
+        // Only spelled out when they differ from the defaults, which some apps don't understand.
+        // Those of Steam Guard codes are implied by the kind
+        if params.kind == Kind::Totp {
+            if params.algorithm != Algorithm::default() {
+                query.append_pair("algorithm", params.algorithm.as_str());
+            }
+            if params.digits != totp::DIGITS {
+                query.append_pair("digits", &params.digits.to_string());
+            }
+            if params.period != totp::PERIOD {
+                query.append_pair("period", &params.period.to_string());
+            }
+        }
+    }
+
+    uri.to_string()
+}
+
+/// Renders the data as a QR code in SVG format. Fails if the data is too long for a QR code.
+pub fn to_qr_svg(data: &str) -> Result<String, QrError> {
+    Ok(QrCode::new(data.as_bytes())?
+        .render::<svg::Color>()
+        .min_dimensions(160, 160)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    fn example_code() -> Code {
+        Code {
+            id: "Ckpt4eFi1pw9fxI3".into(),
+            owner_id: "k0d8WrkRjK6gkc3C".into(),
+            content: "JBSWY3DPEHPK3PXP".into(),
+            display_name: "Work email".into(),
+            icon_url: None,
+            website_url: Some("google.com".into()),
+            issuer: None,
+            account: None,
+            kind: "totp".into(),
+            digits: 6,
+            period: 30,
+            algorithm: "SHA1".into(),
+            expires_at: None,
+            sort_index: 0,
+            favorite: false,
+            folder_id: None,
+            created_at: 0,
+            updated_at: 0,
+            revision: 0,
+            deleted_at: None,
+        }
+    }
+
+    #[gtest]
+    fn uri_contains_secret_and_issuer() {
+        assert_that!(
+            to_uri(&example_code()),
+            eq("otpauth://totp/Work%20email?secret=JBSWY3DPEHPK3PXP&issuer=google.com")
+        );
+    }
+
+    #[gtest]
+    fn uri_without_website() {
+        let code = Code {
+            website_url: None,
+            ..example_code()
+        };
+
+        assert_that!(
+            to_uri(&code),
+            eq("otpauth://totp/Work%20email?secret=JBSWY3DPEHPK3PXP")
+        );
+    }
+
+    #[gtest]
+    fn parse_round_trip() {
+        assert_that!(
+            parse(&to_uri(&example_code())),
+            ok(eq(&OtpAuth {
+                display_name: "Work email".into(),
+                secret: "JBSWY3DPEHPK3PXP".into(),
+                issuer: Some("google.com".into()),
+                account: "Work email".into(),
+                params: totp::Params::default(),
+            }))
+        );
+    }
+
+    #[gtest]
+    fn parse_normalizes_secret_and_issuer() {
+        assert_that!(
+            parse("otpauth://TOTP/GitHub:octocat?secret=jbsw%20y3dp%20ehpk%203pxp%3D%3D&digits=6"),
+            ok(eq(&OtpAuth {
+                display_name: "GitHub:octocat".into(),
+                secret: "JBSWY3DPEHPK3PXP".into(),
+                issuer: Some("GitHub".into()),
+                account: "octocat".into(),
+                params: totp::Params::default(),
+            }))
+        );
+    }
+
+    #[gtest]
+    fn parse_params() {
+        assert_that!(
+            parse(
+                "otpauth://totp/Work?secret=JBSWY3DPEHPK3PXP&algorithm=sha256&digits=8&period=60"
+            )
+            .map(|parsed| parsed.params),
+            ok(eq(&totp::Params {
+                kind: Kind::Totp,
+                algorithm: Algorithm::Sha256,
+                digits: 8,
+                period: 60,
+            }))
+        );
+    }
+
+    #[gtest]
+    fn uri_round_trips_params() {
+        let code = Code {
+            issuer: Some("GitHub".into()),
+            digits: 8,
+            algorithm: "SHA512".into(),
+            ..example_code()
+        };
+
+        let parsed = parse(&to_uri(&code)).unwrap();
+        expect_that!(parsed.issuer.as_deref(), some(eq("GitHub")));
+        expect_that!(
+            parsed.params,
+            eq(totp::Params {
+                kind: Kind::Totp,
+                algorithm: Algorithm::Sha512,
+                digits: 8,
+                period: 30,
+            })
+        );
+    }
+
+    #[gtest]
+    fn steam_uri_round_trip() {
+        let code = Code {
+            display_name: "Steam:gaben".into(),
+            kind: "steam".into(),
+            digits: 5,
+            ..example_code()
+        };
+
+        let uri = to_uri(&code);
+        expect_that!(uri, starts_with("otpauth://steam/Steam:gaben?secret="));
+        expect_that!(uri, not(contains_substring("digits")));
+        let parsed = parse(&uri).unwrap();
+        expect_that!(parsed.params, eq(totp::Params::STEAM));
+        expect_that!(parsed.issuer.as_deref(), some(eq("google.com")));
+    }
+
+    #[gtest]
+    fn parse_rejects_invalid_uris() {
+        expect_that!(
+            parse("https://example.com/?secret=JBSWY3DPEHPK3PXP"),
+            err(eq(&OtpAuthError::InvalidUri))
+        );
+        expect_that!(
+            parse("otpauth://hotp/Work?secret=JBSWY3DPEHPK3PXP&counter=1"),
+            err(eq(&OtpAuthError::UnsupportedType))
+        );
+        expect_that!(
+            parse("otpauth://totp/?secret=JBSWY3DPEHPK3PXP"),
+            err(eq(&OtpAuthError::MissingLabel))
+        );
+        expect_that!(
+            parse("otpauth://totp/Work"),
+            err(eq(&OtpAuthError::MissingSecret))
+        );
+        expect_that!(
+            parse("otpauth://totp/Work?secret=not-base32!"),
+            err(eq(&OtpAuthError::InvalidSecret))
+        );
+        expect_that!(
+            parse("otpauth://totp/Work?secret=JBSWY3DPEHPK3PXP&digits=4"),
+            err(eq(&OtpAuthError::InvalidDigits))
+        );
+        expect_that!(
+            parse("otpauth://totp/Work?secret=JBSWY3DPEHPK3PXP&period=0"),
+            err(eq(&OtpAuthError::InvalidPeriod))
+        );
+        expect_that!(
+            parse("otpauth://totp/Work?secret=JBSWY3DPEHPK3PXP&algorithm=MD5"),
+            err(eq(&OtpAuthError::UnsupportedAlgorithm))
+        );
+    }
+
+    #[gtest]
+    fn override_params_validates() {
+        let defaults = totp::Params::default();
+        expect_that!(
+            override_params(defaults, None, Some("sha512"), Some(7), None),
+            ok(eq(&totp::Params {
+                kind: Kind::Totp,
+                algorithm: Algorithm::Sha512,
+                digits: 7,
+                period: 30,
+            }))
+        );
+        expect_that!(
+            override_params(defaults, None, None, None, None),
+            ok(eq(&defaults))
+        );
+        expect_that!(
+            override_params(defaults, None, Some("MD5"), None, None),
+            err(eq(&OtpAuthError::UnsupportedAlgorithm))
+        );
+        expect_that!(
+            override_params(defaults, None, None, Some(9), None),
+            err(eq(&OtpAuthError::InvalidDigits))
+        );
+        expect_that!(
+            override_params(defaults, None, None, None, Some(0)),
+            err(eq(&OtpAuthError::InvalidPeriod))
+        );
+        expect_that!(
+            override_params(defaults, Some("steam"), None, None, None),
+            ok(eq(&totp::Params::STEAM))
+        );
+        expect_that!(
+            override_params(totp::Params::STEAM, Some("totp"), None, Some(8), None),
+            ok(eq(&totp::Params {
+                digits: 8,
+                ..defaults
+            }))
+        );
+        expect_that!(
+            override_params(totp::Params::STEAM, None, None, Some(6), None),
+            err(eq(&OtpAuthError::SteamParams))
+        );
+        expect_that!(
+            override_params(defaults, Some("hotp"), None, None, None),
+            err(eq(&OtpAuthError::UnsupportedKind))
+        );
+    }
+
+    #[gtest]
+    fn qr_is_svg() {
+        let svg = to_qr_svg(&to_uri(&example_code())).unwrap();
+
+        expect_that!(svg, contains_substring("<svg"));
+        expect_that!(svg, ends_with("</svg>"));
+    }
+
+    #[gtest]
+    fn qr_of_long_uri_fails() {
+        let code = Code {
+            display_name: "a".repeat(4096),
+            ..example_code()
+        };
+
+        expect_that!(to_qr_svg(&to_uri(&code)), err(anything()));
+    }
+}
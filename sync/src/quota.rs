@@ -0,0 +1,133 @@
+use crate::{models::codes::Code, routes::v1::ApiError, ServerOptions};
+use serde::Serialize;
+use sqlx::SqliteExecutor;
+use utoipa::ToSchema;
+
+/// Bytes the icon takes up in the code. Only `data:` URIs embed the icon, other URLs merely link
+/// to it, like icons fetched for websites, which are shared between users.
+pub fn icon_bytes(icon_url: Option<&str>) -> u64 {
+    icon_url
+        .filter(|url| url.starts_with("data:"))
+        .map_or(0, |url| url.len() as u64)
+}
+
+/// What a user stores, counted against the [`Quota`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, ToSchema)]
+pub struct Usage {
+    /// Codes which are neither deleted nor expired.
+    pub codes: u64,
+    /// Bytes of the icons embedded in the codes.
+    pub icon_bytes: u64,
+}
+
+impl Usage {
+    pub async fn of(
+        executor: impl SqliteExecutor<'_>,
+        owner_id: &str,
+    ) -> Result<Self, sqlx::Error> {
+        let (codes, icon_bytes) = Code::usage(executor, owner_id).await?;
+        Ok(Usage {
+            codes: codes as u64,
+            icon_bytes: icon_bytes as u64,
+        })
+    }
+}
+
+/// Limits on what a single user may store, so one account can't fill up the disk of an instance.
+/// Missing limits are unlimited.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+pub struct Quota {
+    /// Codes a user may store.
+    pub max_codes: Option<u64>,
+    /// Bytes the icons embedded in the codes of a user may take up.
+    pub max_icon_bytes: Option<u64>,
+}
+
+impl Quota {
+    pub fn new(opts: &ServerOptions) -> Self {
+        Quota {
+            max_codes: (opts.max_codes_per_user > 0).then_some(opts.max_codes_per_user),
+            max_icon_bytes: (opts.max_icon_bytes_per_user > 0)
+                .then_some(opts.max_icon_bytes_per_user),
+        }
+    }
+
+    /// Fails if storing the codes and icon bytes on top of the usage exceeds a limit. Users over a
+    /// limit, e.g. as it was lowered, can still store more of what they have room for.
+    pub fn check(&self, usage: Usage, codes: u64, icon_bytes: u64) -> Result<(), ApiError> {
+        if let Some(max_codes) = self.max_codes {
+            if codes > 0 && usage.codes + codes > max_codes {
+                return Err(ApiError::CodeQuotaExceeded(max_codes));
+            }
+        }
+        if let Some(max_icon_bytes) = self.max_icon_bytes {
+            if icon_bytes > 0 && usage.icon_bytes + icon_bytes > max_icon_bytes {
+                return Err(ApiError::IconQuotaExceeded(max_icon_bytes));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks whether the owner has room for the codes and icon bytes. Run it in the transaction
+    /// storing them, to see codes stored earlier in it.
+    pub async fn ensure_room(
+        &self,
+        executor: impl SqliteExecutor<'_>,
+        owner_id: &str,
+        codes: u64,
+        icon_bytes: u64,
+    ) -> Result<(), ApiError> {
+        if self.max_codes.is_none() && self.max_icon_bytes.is_none() {
+            return Ok(());
+        }
+        let usage = Usage::of(executor, owner_id).await?;
+        self.check(usage, codes, icon_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    #[gtest]
+    fn only_embedded_icons_count() {
+        expect_that!(icon_bytes(None), eq(0));
+        expect_that!(icon_bytes(Some("https://iceblink.example/icon.png")), eq(0));
+        expect_that!(icon_bytes(Some("data:image/png;base64,AAAA")), eq(26));
+    }
+
+    #[gtest]
+    fn checks_what_is_added() {
+        let quota = Quota {
+            max_codes: Some(2),
+            max_icon_bytes: Some(100),
+        };
+        let usage = Usage {
+            codes: 1,
+            icon_bytes: 100,
+        };
+
+        expect_that!(quota.check(usage, 1, 0), ok(anything()));
+        expect_that!(
+            matches!(
+                quota.check(usage, 2, 0),
+                Err(ApiError::CodeQuotaExceeded(2))
+            ),
+            is_true()
+        );
+        expect_that!(
+            matches!(
+                quota.check(usage, 1, 1),
+                Err(ApiError::IconQuotaExceeded(100))
+            ),
+            is_true()
+        );
+
+        let unlimited = Quota {
+            max_codes: None,
+            max_icon_bytes: None,
+        };
+        expect_that!(unlimited.check(usage, 1000, 1000), ok(anything()));
+    }
+}
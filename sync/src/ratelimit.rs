@@ -0,0 +1,334 @@
+use crate::{connections, models::user::User, routes::v1::ApiError, ServerOptions};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Entries per key, with the ones no longer needed pruned at most once per interval, as that walks
+/// the whole map while every request waits for the lock.
+#[derive(Debug)]
+struct Entries<V> {
+    entries: HashMap<String, V>,
+    pruned_at: Instant,
+}
+
+impl<V> Entries<V> {
+    fn new() -> Self {
+        Entries {
+            entries: HashMap::new(),
+            pruned_at: Instant::now(),
+        }
+    }
+
+    /// Keeps only the entries `keep` holds on to, if they weren't pruned within the interval.
+    fn prune(
+        &mut self,
+        now: Instant,
+        interval: Duration,
+        keep: impl FnMut(&String, &mut V) -> bool,
+    ) {
+        if now.duration_since(self.pruned_at) >= interval {
+            self.entries.retain(keep);
+            self.pruned_at = now;
+        }
+    }
+}
+
+/// Allows every key a fixed amount of hits per window.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    max_hits: u32,
+    window: Duration,
+    /// Start of the current window, and the amount of hits within it, per key.
+    hits: Arc<Mutex<Entries<(Instant, u32)>>>,
+}
+
+impl RateLimiter {
+    pub fn new(max_hits: u32, window: Duration) -> Self {
+        RateLimiter {
+            max_hits,
+            window,
+            hits: Arc::new(Mutex::new(Entries::new())),
+        }
+    }
+
+    /// Counts a hit for the key. Returns `false` if the key already used up its hits for this window.
+    pub fn try_hit(&self, key: &str) -> bool {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().unwrap();
+
+        // Forget expired windows, so the map doesn't grow forever
+        hits.prune(now, self.window, |_, (start, _)| {
+            now.duration_since(*start) < self.window
+        });
+
+        let (start, count) = hits.entries.entry(key.to_string()).or_insert((now, 0));
+        if now.duration_since(*start) >= self.window {
+            (*start, *count) = (now, 0);
+        }
+        if *count >= self.max_hits {
+            return false;
+        }
+
+        *count += 1;
+        true
+    }
+}
+
+/// A token bucket per key: every key may make up to `capacity` hits at once, and regains them
+/// evenly over the `period`.
+#[derive(Clone, Debug)]
+pub struct TokenBuckets {
+    capacity: u32,
+    period: Duration,
+    /// Tokens left in the bucket of every key, and when they were counted.
+    buckets: Arc<Mutex<Entries<(f64, Instant)>>>,
+}
+
+/// A bucket after a hit, as reported in the `X-RateLimit-*` headers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BucketStatus {
+    pub limit: u32,
+    pub remaining: u32,
+    /// Time until the bucket is full again.
+    pub reset: Duration,
+    /// Time until the next hit is allowed, if the bucket was empty and the hit was refused.
+    pub retry_after: Option<Duration>,
+}
+
+impl TokenBuckets {
+    pub fn new(capacity: u32, period: Duration) -> Self {
+        TokenBuckets {
+            capacity,
+            period,
+            buckets: Arc::new(Mutex::new(Entries::new())),
+        }
+    }
+
+    /// Takes a token from the bucket of the key, if there is one left.
+    pub fn try_take(&self, key: &str) -> BucketStatus {
+        let now = Instant::now();
+        let capacity = f64::from(self.capacity);
+        let per_second = capacity / self.period.as_secs_f64();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // Full buckets are the same as none, so the map doesn't grow forever
+        buckets.prune(now, self.period, |_, (tokens, counted)| {
+            *tokens + now.duration_since(*counted).as_secs_f64() * per_second < capacity
+        });
+
+        let (tokens, counted) = buckets
+            .entries
+            .entry(key.to_string())
+            .or_insert((capacity, now));
+        *tokens = (*tokens + now.duration_since(*counted).as_secs_f64() * per_second).min(capacity);
+        *counted = now;
+        let allowed = *tokens >= 1.0;
+        if allowed {
+            *tokens -= 1.0;
+        }
+
+        BucketStatus {
+            limit: self.capacity,
+            remaining: *tokens as u32,
+            reset: Duration::from_secs_f64((capacity - *tokens) / per_second),
+            retry_after: (!allowed).then(|| Duration::from_secs_f64((1.0 - *tokens) / per_second)),
+        }
+    }
+}
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("x-ratelimit-reset");
+
+/// Requests per minute of every address and user, as configured in the [`ServerOptions`]. Limits
+/// set to zero are disabled.
+#[derive(Clone, Debug)]
+pub struct RequestLimits {
+    /// Every request, by address and by user.
+    requests: Option<TokenBuckets>,
+    /// Logins at `/v1/oauth` and `/v1/local`, by address.
+    logins: Option<TokenBuckets>,
+    /// Requests changing something, by user.
+    writes: Option<TokenBuckets>,
+    trusted_proxies: Arc<Vec<IpAddr>>,
+}
+
+impl RequestLimits {
+    pub fn new(opts: &ServerOptions) -> Self {
+        let per_minute =
+            |limit: u32| (limit != 0).then(|| TokenBuckets::new(limit, Duration::from_secs(60)));
+        RequestLimits {
+            requests: per_minute(opts.requests_per_minute),
+            logins: per_minute(opts.login_requests_per_minute),
+            writes: per_minute(opts.write_requests_per_minute),
+            trusted_proxies: Arc::new(opts.trusted_proxies.clone()),
+        }
+    }
+}
+
+/// Limits the requests of every address, with stricter limits for logging in.
+pub async fn limit_addresses(
+    State(limits): State<RequestLimits>,
+    req: Request,
+    next: Next,
+) -> Response {
+    // Requests without peer information (e.g. in tests) can not be attributed to anyone
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| peer.ip());
+    let Some(ip) = connections::client_ip(peer, req.headers(), &limits.trusted_proxies) else {
+        return next.run(req).await;
+    };
+
+    let key = format!("ip:{}", connections::client_network(ip));
+    let path = req.uri().path();
+    let login = path.starts_with("/v1/oauth") || path.starts_with("/v1/local/");
+    let statuses = [
+        limits.requests.as_ref(),
+        limits.logins.as_ref().filter(|_| login),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|buckets| buckets.try_take(&key))
+    .collect();
+
+    run_limited(statuses, req, next).await
+}
+
+/// Limits the requests of every authenticated user, with stricter limits for changing something.
+/// Has to run after [`crate::auth::jwt_middleware`].
+pub async fn limit_users(
+    State(limits): State<RequestLimits>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(user) = req.extensions().get::<User>() else {
+        return next.run(req).await;
+    };
+
+    let key = format!("user:{}", user.id);
+    let write = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let statuses = [
+        limits.requests.as_ref(),
+        limits.writes.as_ref().filter(|_| write),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|buckets| buckets.try_take(&key))
+    .collect();
+
+    run_limited(statuses, req, next).await
+}
+
+/// Refuses the request if any of the buckets it took from was empty. Either way, the response
+/// reports the bucket closest to running out.
+async fn run_limited(statuses: Vec<BucketStatus>, req: Request, next: Next) -> Response {
+    let Some(tightest) = statuses
+        .iter()
+        .min_by_key(|status| status.remaining)
+        .copied()
+    else {
+        return next.run(req).await;
+    };
+
+    let mut response = match statuses
+        .iter()
+        .filter_map(|status| status.retry_after)
+        .max()
+    {
+        Some(retry_after) => {
+            let mut response = ApiError::RateLimited.into_response();
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, whole_seconds(retry_after));
+            response
+        }
+        None => next.run(req).await,
+    };
+    insert_status(response.headers_mut(), tightest);
+    response
+}
+
+/// Sets the `X-RateLimit-*` headers, unless an inner limit already reported a tighter bucket.
+fn insert_status(headers: &mut HeaderMap, status: BucketStatus) {
+    let reported = headers
+        .get(RATELIMIT_REMAINING)
+        .and_then(|remaining| remaining.to_str().ok()?.parse::<u32>().ok());
+    if reported.is_some_and(|remaining| remaining <= status.remaining) {
+        return;
+    }
+
+    headers.insert(RATELIMIT_LIMIT, status.limit.into());
+    headers.insert(RATELIMIT_REMAINING, status.remaining.into());
+    headers.insert(RATELIMIT_RESET, whole_seconds(status.reset));
+}
+
+fn whole_seconds(duration: Duration) -> HeaderValue {
+    (duration.as_secs_f64().ceil() as u64).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    #[gtest]
+    fn limits_per_key() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+
+        expect_that!(limiter.try_hit("a"), is_true());
+        expect_that!(limiter.try_hit("a"), is_true());
+        expect_that!(limiter.try_hit("a"), is_false());
+        expect_that!(limiter.try_hit("b"), is_true());
+    }
+
+    #[gtest]
+    fn window_resets() {
+        let limiter = RateLimiter::new(1, Duration::from_millis(10));
+
+        expect_that!(limiter.try_hit("a"), is_true());
+        expect_that!(limiter.try_hit("a"), is_false());
+        std::thread::sleep(Duration::from_millis(20));
+        expect_that!(limiter.try_hit("a"), is_true());
+    }
+
+    #[gtest]
+    fn expired_entries_are_pruned() {
+        let limiter = RateLimiter::new(1, Duration::from_millis(10));
+        for key in ["a", "b", "c"] {
+            limiter.try_hit(key);
+        }
+        expect_that!(limiter.hits.lock().unwrap().entries.len(), eq(3));
+
+        std::thread::sleep(Duration::from_millis(20));
+        expect_that!(limiter.try_hit("d"), is_true());
+        expect_that!(limiter.hits.lock().unwrap().entries.len(), eq(1));
+    }
+
+    #[gtest]
+    fn buckets_refill_gradually() {
+        let buckets = TokenBuckets::new(2, Duration::from_millis(100));
+
+        expect_that!(buckets.try_take("a").remaining, eq(1));
+        expect_that!(buckets.try_take("a").retry_after, none());
+        let refused = buckets.try_take("a");
+        expect_that!(refused.remaining, eq(0));
+        expect_that!(refused.retry_after, some(le(Duration::from_millis(50))));
+        expect_that!(buckets.try_take("b").retry_after, none());
+
+        // Half the period brings back one of the two tokens
+        std::thread::sleep(Duration::from_millis(60));
+        expect_that!(buckets.try_take("a").retry_after, none());
+        expect_that!(buckets.try_take("a").retry_after, some(anything()));
+    }
+}
@@ -0,0 +1,153 @@
+use crate::{
+    cli::RegistrationPolicy, connections, models::invites::Invite, ratelimit::RateLimiter,
+    routes::v1::ApiError, utils,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::{net::IpAddr, time::Duration};
+use tracing::{info, warn};
+
+/// Verifies captcha tokens with a `siteverify` style endpoint, as offered by Cloudflare Turnstile,
+/// hCaptcha and reCAPTCHA.
+#[derive(Clone, Debug)]
+pub struct CaptchaVerifier {
+    url: String,
+    secret: String,
+}
+
+#[derive(Serialize)]
+struct SiteVerifyRequest<'a> {
+    secret: &'a str,
+    response: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remoteip: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+}
+
+impl CaptchaVerifier {
+    pub fn new(url: String, secret: String) -> Self {
+        CaptchaVerifier { url, secret }
+    }
+
+    pub async fn verify(
+        &self,
+        token: &str,
+        remote_ip: Option<IpAddr>,
+    ) -> Result<bool, reqwest::Error> {
+        let response = reqwest::Client::builder()
+            .user_agent(utils::USER_AGENT)
+            .timeout(Duration::from_secs(10))
+            .build()
+            .unwrap()
+            .post(&self.url)
+            .form(&SiteVerifyRequest {
+                secret: &self.secret,
+                response: token,
+                remoteip: remote_ip.map(|ip| ip.to_string()),
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<SiteVerifyResponse>()
+            .await?;
+
+        Ok(response.success)
+    }
+}
+
+/// Decides who may create an account, and protects public instances from abuse.
+#[derive(Clone, Debug)]
+pub struct RegistrationGuard {
+    policy: RegistrationPolicy,
+    /// Accounts each IP address may create per hour. Unlimited if `None`.
+    limiter: Option<RateLimiter>,
+    captcha: Option<CaptchaVerifier>,
+}
+
+impl RegistrationGuard {
+    pub fn new(
+        policy: RegistrationPolicy,
+        per_hour: u32,
+        captcha: Option<CaptchaVerifier>,
+    ) -> Self {
+        RegistrationGuard {
+            policy,
+            limiter: (per_hour != 0)
+                .then(|| RateLimiter::new(per_hour, Duration::from_secs(60 * 60))),
+            captcha,
+        }
+    }
+
+    /// Has to pass before a new user is created. Checks the policy and captcha first, so failed
+    /// attempts don't use up the rate limit of the address. The invite is only looked at, see
+    /// [`RegistrationGuard::redeem`].
+    pub async fn check(
+        &self,
+        pool: &SqlitePool,
+        invite: Option<&str>,
+        captcha_token: Option<&str>,
+        remote_ip: Option<IpAddr>,
+    ) -> Result<(), ApiError> {
+        match self.policy {
+            RegistrationPolicy::Open => {}
+            RegistrationPolicy::Closed => return Err(ApiError::RegistrationClosed),
+            RegistrationPolicy::Invite => {
+                let invite = invite
+                    .filter(|invite| !invite.is_empty())
+                    .ok_or(ApiError::InviteRequired)?;
+                if !Invite::is_valid(pool, invite, chrono::Utc::now().timestamp()).await? {
+                    return Err(ApiError::InvalidInvite);
+                }
+            }
+        }
+
+        if let Some(captcha) = &self.captcha {
+            let token = captcha_token
+                .filter(|token| !token.is_empty())
+                .ok_or(ApiError::CaptchaFailed)?;
+
+            match captcha.verify(token, remote_ip).await {
+                Ok(true) => {}
+                Ok(false) => return Err(ApiError::CaptchaFailed),
+                Err(err) => {
+                    warn!("Unable to verify captcha: {err}");
+                    return Err(ApiError::CaptchaFailed);
+                }
+            }
+        }
+
+        if let Some(limiter) = &self.limiter {
+            let key = remote_ip
+                .map(|ip| connections::client_network(ip).to_string())
+                .unwrap_or_default();
+            if !limiter.try_hit(&key) {
+                return Err(ApiError::TooManyRegistrations);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Uses up the invite for the user about to be created, once everything else about the new
+    /// account checked out. Fails if another registration used the invite in the meantime.
+    pub async fn redeem(
+        &self,
+        pool: &SqlitePool,
+        invite: Option<&str>,
+        user_id: &str,
+    ) -> Result<(), ApiError> {
+        if self.policy != RegistrationPolicy::Invite {
+            return Ok(());
+        }
+        let invite = invite.ok_or(ApiError::InviteRequired)?;
+        if !Invite::redeem(pool, invite, user_id, chrono::Utc::now().timestamp()).await? {
+            return Err(ApiError::InvalidInvite);
+        }
+        info!("The user {user_id} registered with an invite");
+        Ok(())
+    }
+}
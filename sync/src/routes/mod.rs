@@ -1 +1,3 @@
+pub mod openapi;
+pub mod trailing_slash;
 pub mod v1;
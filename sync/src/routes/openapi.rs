@@ -0,0 +1,49 @@
+use crate::utils;
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+
+/// The OpenAPI document only changes between builds, so it is serialized once and served with a strong ETag.
+pub struct OpenApiDocument {
+    json: String,
+    etag: String,
+}
+
+impl OpenApiDocument {
+    pub fn new(api: &utoipa::openapi::OpenApi) -> Self {
+        let json = api
+            .to_json()
+            .expect("Unable to serialize OpenAPI documentation");
+
+        OpenApiDocument {
+            etag: utils::etag(json.as_bytes()),
+            json,
+        }
+    }
+}
+
+const CACHE_CONTROL: &str = "public, max-age=86400";
+
+pub async fn openapi_json(
+    State(document): State<Arc<OpenApiDocument>>,
+    request_headers: HeaderMap,
+) -> Response {
+    let headers = [
+        (header::ETAG, document.etag.clone()),
+        (header::CACHE_CONTROL, CACHE_CONTROL.to_string()),
+    ];
+
+    if utils::etag_matches(&request_headers, &document.etag) {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+
+    (
+        headers,
+        [(header::CONTENT_TYPE, "application/json")],
+        document.json.clone(),
+    )
+        .into_response()
+}
@@ -0,0 +1,81 @@
+use crate::cli::TrailingSlash;
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Canonical form of API paths with trailing slashes, or `None` if the path is already canonical.
+/// The static site is left alone, as it has clean URLs of its own, and so is `/v1/` which is a route by itself.
+fn canonical_path(path: &str) -> Option<&str> {
+    if !path.starts_with("/v1/") || !path.ends_with('/') {
+        return None;
+    }
+
+    match path.trim_end_matches('/') {
+        "/v1" => None,
+        trimmed => Some(trimmed),
+    }
+}
+
+pub async fn normalize(
+    State(mode): State<TrailingSlash>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let Some(path) = canonical_path(req.uri().path()) else {
+        return next.run(req).await;
+    };
+
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+
+    match mode {
+        TrailingSlash::Off => next.run(req).await,
+        // Permanent redirect, keeping the method and body
+        TrailingSlash::Redirect => (
+            StatusCode::PERMANENT_REDIRECT,
+            [(header::LOCATION, path_and_query)],
+        )
+            .into_response(),
+        TrailingSlash::Rewrite => {
+            let mut parts = req.uri().clone().into_parts();
+            parts.path_and_query = Some(
+                path_and_query
+                    .parse()
+                    .expect("Trimmed path is a valid path"),
+            );
+            *req.uri_mut() = Uri::from_parts(parts).expect("Rewritten URI is valid");
+
+            next.run(req).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    #[gtest]
+    fn canonical_paths() {
+        expect_that!(canonical_path("/v1/code/"), some(eq("/v1/code")));
+        expect_that!(canonical_path("/v1/code//"), some(eq("/v1/code")));
+        expect_that!(
+            canonical_path("/v1/code/abc/icon/"),
+            some(eq("/v1/code/abc/icon"))
+        );
+    }
+
+    #[gtest]
+    fn untouched_paths() {
+        expect_that!(canonical_path("/v1/code"), none());
+        expect_that!(canonical_path("/v1/"), none());
+        expect_that!(canonical_path("/v1//"), none());
+        expect_that!(canonical_path("/swagger/"), none());
+        expect_that!(canonical_path("/"), none());
+    }
+}
@@ -0,0 +1,628 @@
+use super::{
+    query::{Validate, ValidatedQuery},
+    webhooks::{
+        self, WebhookCreatePayload, WebhookCreateResponse, WebhookDeliveryResponse, WebhookResponse,
+    },
+    ApiError, JSON,
+};
+use crate::{
+    auth::{self, OpenIdDiscovery},
+    backup,
+    models::{
+        invites::Invite,
+        passwords::{Password, PasswordReset},
+        stats::InstanceStats,
+        tokens::{TokenScope, TokenScopes},
+        user::User,
+        webhooks::Webhook,
+    },
+    utils, AppState,
+};
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use tracing::{info, warn};
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Deserialize, IntoParams)]
+pub struct BackupQuery {
+    /// Respond with the backup file itself, instead of its location on the server.
+    #[serde(default)]
+    pub download: bool,
+}
+
+impl Validate for BackupQuery {
+    fn validate(&mut self) -> Result<(), ApiError> {
+        Ok(())
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BackupResponse {
+    /// Path of the backup on the server.
+    pub file: String,
+    /// Size of the backup in bytes.
+    pub size: u64,
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/admin/backup",
+	tag = "admin",
+	params(BackupQuery),
+	responses(
+		(status = OK, description = "Created a backup. Contains the SQLite database itself when `download=true`, which is removed from the server afterwards", body = BackupResponse),
+		(status = FORBIDDEN, description = "Not an admin")
+	),
+)]
+pub async fn backup(
+    State(state): State<Arc<AppState>>,
+    Extension(scopes): Extension<TokenScopes>,
+    ValidatedQuery(query): ValidatedQuery<BackupQuery>,
+) -> Result<Response, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+
+    let snapshot = backup::create_backup(
+        &state.db,
+        std::path::Path::new(&state.settings.backup_directory),
+    )
+    .await
+    .map_err(|err| {
+        warn!("Unable to create database backup: {err:?}");
+        ApiError::BackupFailed
+    })?;
+    info!("Created database backup at {}", snapshot.path().display());
+
+    if query.download {
+        let read_failed = |err: std::io::Error| {
+            warn!("Unable to read database backup: {err}");
+            ApiError::BackupFailed
+        };
+        let file = tokio::fs::File::open(snapshot.path())
+            .await
+            .map_err(read_failed)?;
+        let size = file.metadata().await.map_err(read_failed)?.len();
+        let filename = snapshot
+            .path()
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        return Ok((
+            [
+                (header::CONTENT_TYPE, "application/vnd.sqlite3".to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{filename}\""),
+                ),
+                (header::CONTENT_LENGTH, size.to_string()),
+            ],
+            Body::from_stream(backup::BackupDownload::new(file, snapshot)),
+        )
+            .into_response());
+    }
+
+    let path = snapshot.keep();
+    let size = tokio::fs::metadata(&path)
+        .await
+        .map(|metadata| metadata.len())
+        .unwrap_or_default();
+
+    Ok(JSON(BackupResponse {
+        file: path.to_string_lossy().to_string(),
+        size,
+    })
+    .into_response())
+}
+
+/// How long a single subsystem check may take. Stays well below the request timeout.
+const CHECK_TIMEOUT: Duration = Duration::from_millis(1500);
+
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubsystemState {
+    Ok,
+    Failing,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SubsystemStatus {
+    /// One of `config`, `database`, `oidc` and `icons`.
+    pub name: String,
+    pub state: SubsystemState,
+    /// Why the subsystem is failing.
+    pub message: Option<String>,
+}
+
+impl SubsystemStatus {
+    fn new(name: &str, result: Result<(), String>) -> Self {
+        let (state, message) = match result {
+            Ok(()) => (SubsystemState::Ok, None),
+            Err(message) => (SubsystemState::Failing, Some(message)),
+        };
+
+        SubsystemStatus {
+            name: name.to_string(),
+            state,
+            message,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ConfigReport {
+    /// Whether every subsystem is ok.
+    pub healthy: bool,
+    pub subsystems: Vec<SubsystemStatus>,
+}
+
+async fn check_database(state: &AppState) -> Result<(), String> {
+    sqlx::query("SELECT 1")
+        .execute(&state.db)
+        .await
+        .map(|_| ())
+        .map_err(|err| format!("Unable to query the database: {err}"))
+}
+
+/// Discovers the OpenId configuration of every provider again, as the one from startup is used
+/// until a restart.
+async fn check_oidc(state: &AppState) -> Result<(), String> {
+    let servers = std::iter::once((auth::DEFAULT_OPENID_PROVIDER, &state.settings.oauth_server))
+        .chain(
+            state
+                .settings
+                .openid_providers
+                .iter()
+                .map(|provider| (provider.name.as_str(), &provider.server)),
+        );
+
+    for (name, server) in servers {
+        let Some(openid) = state.openid.get(Some(name)) else {
+            return Err(format!("The OpenId provider {name:?} isn't set up"));
+        };
+        let client = utils::client_builder(openid.proxy.as_ref())
+            .timeout(CHECK_TIMEOUT)
+            .build()
+            .map_err(|err| err.to_string())?;
+        let discovery = OpenIdDiscovery::fetch(&client, server)
+            .await
+            .map_err(|err| {
+                format!("Unable to discover the OpenId configuration of {name:?}: {err}")
+            })?;
+
+        if discovery.authorization_endpoint != openid.authorization
+            || discovery.token_endpoint != openid.token
+            || discovery.userinfo_endpoint != openid.userinfo
+            || discovery.jwks_uri != openid.jwks_uri
+        {
+            return Err(format!(
+                "The OpenId configuration of {name:?} changed since startup. Restart to apply it"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+async fn check_icons(state: &AppState) -> Result<(), String> {
+    state
+        .icon_store
+        .check(CHECK_TIMEOUT)
+        .await
+        .map_err(|err| format!("Icons are unavailable: {err:?}"))
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/admin/config/validate",
+	tag = "admin",
+	responses(
+		(status = OK, description = "Status of every subsystem. Failing subsystems don't change the status code", body = ConfigReport),
+		(status = FORBIDDEN, description = "Not an admin")
+	),
+)]
+pub async fn validate_config(State(state): State<Arc<AppState>>) -> JSON<ConfigReport> {
+    let (database, oidc, icons) = tokio::join!(
+        check_database(&state),
+        check_oidc(&state),
+        check_icons(&state)
+    );
+
+    let subsystems = vec![
+        SubsystemStatus::new("config", state.settings.validate()),
+        SubsystemStatus::new("database", database),
+        SubsystemStatus::new("oidc", oidc),
+        SubsystemStatus::new("icons", icons),
+    ];
+
+    JSON(ConfigReport {
+        healthy: subsystems
+            .iter()
+            .all(|subsystem| subsystem.state == SubsystemState::Ok),
+        subsystems,
+    })
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct AdminUserResponse {
+    pub id: String,
+    pub username: String,
+    pub display_name: String,
+    /// Issuer of the OpenID provider the user logs in with, `webauthn` for passkeys, or `local` for
+    /// passwords.
+    pub upstream_issuer: String,
+    pub is_admin: bool,
+    /// Unix timestamp (seconds) of the suspension, if the user is suspended.
+    pub suspended_at: Option<i64>,
+}
+
+impl From<User> for AdminUserResponse {
+    fn from(user: User) -> Self {
+        AdminUserResponse {
+            id: user.id,
+            username: user.username,
+            display_name: user.display_name,
+            upstream_issuer: user.upstream_issuer,
+            is_admin: user.is_admin,
+            suspended_at: user.suspended_at,
+        }
+    }
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/admin/users",
+	tag = "admin",
+	responses(
+		(status = OK, description = "Every user of the instance, ordered by username", body = Vec<AdminUserResponse>),
+		(status = FORBIDDEN, description = "Not an admin")
+	),
+)]
+pub async fn list_users(
+    State(state): State<Arc<AppState>>,
+) -> Result<JSON<Vec<AdminUserResponse>>, ApiError> {
+    let users = User::get_all(&state.db).await?;
+    Ok(JSON(users.into_iter().map(Into::into).collect()))
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/admin/users/{id}/suspend",
+	tag = "admin",
+	params(
+		("id", description = "Id of the user to suspend")
+	),
+	responses(
+		(status = OK, description = "Suspended the user. Their sessions and tokens are rejected, and they can't log in until the suspension is lifted", body = AdminUserResponse),
+		(status = BAD_REQUEST, description = "Admins can't suspend themselves"),
+		(status = FORBIDDEN, description = "Not an admin"),
+		(status = NOT_FOUND, description = "Unable to find user")
+	),
+)]
+pub async fn suspend_user(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    Path(id): Path<String>,
+) -> Result<JSON<AdminUserResponse>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+    if id == admin.id {
+        return Err(ApiError::CannotSuspendSelf);
+    }
+
+    let mut user = User::get_by_id(&state.db, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    if user.suspended_at.is_none() {
+        user.set_suspended(&state.db, Some(chrono::Utc::now().timestamp()))
+            .await?;
+        info!("{} suspended the user {}", admin.id, user.id);
+    }
+
+    Ok(JSON(user.into()))
+}
+
+#[utoipa::path(
+	delete,
+	path = "/v1/admin/users/{id}/suspend",
+	tag = "admin",
+	params(
+		("id", description = "Id of the user to lift the suspension of")
+	),
+	responses(
+		(status = OK, description = "Lifted the suspension of the user", body = AdminUserResponse),
+		(status = FORBIDDEN, description = "Not an admin"),
+		(status = NOT_FOUND, description = "Unable to find user")
+	),
+)]
+pub async fn unsuspend_user(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    Path(id): Path<String>,
+) -> Result<JSON<AdminUserResponse>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+
+    let mut user = User::get_by_id(&state.db, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    if user.suspended_at.is_some() {
+        user.set_suspended(&state.db, None).await?;
+        info!("{} lifted the suspension of the user {}", admin.id, user.id);
+    }
+
+    Ok(JSON(user.into()))
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct PasswordResetResponse {
+    /// Passed to `POST /v1/local/password/reset` with the new password. Only works once.
+    pub token: String,
+    /// Unix timestamp (seconds) after which the token can't be used anymore.
+    pub expires_at: i64,
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/admin/users/{id}/password-reset",
+	tag = "admin",
+	params(
+		("id", description = "Id of the user to reset the password of")
+	),
+	responses(
+		(status = CREATED, description = "Token the user can set a new password with. Hand it to them out of band", body = PasswordResetResponse),
+		(status = BAD_REQUEST, description = "The user logs in without a password"),
+		(status = FORBIDDEN, description = "Not an admin"),
+		(status = NOT_FOUND, description = "Unable to find user")
+	),
+)]
+pub async fn create_password_reset(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    Path(id): Path<String>,
+) -> Result<(StatusCode, JSON<PasswordResetResponse>), ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+
+    let user = User::get_by_id(&state.db, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    if Password::get_for_user(&state.db, &user.id).await?.is_none() {
+        return Err(ApiError::BadRequest(
+            "The user logs in without a password.".into(),
+        ));
+    }
+
+    let (reset, token) = PasswordReset::generate(user.id, chrono::Utc::now().timestamp());
+    reset.insert(&state.db).await?;
+    info!(
+        "{} created a password reset for the user {}",
+        admin.id, reset.user_id
+    );
+
+    Ok((
+        StatusCode::CREATED,
+        JSON(PasswordResetResponse {
+            token,
+            expires_at: reset.expires_at,
+        }),
+    ))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct InviteCreatePayload {
+    /// Days the invite can be used for. Defaults to 7, and can be at most 365.
+    pub expires_in_days: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct InviteCreateResponse {
+    pub id: String,
+    /// Passed as `invite` when registering. Only shown once, as just a hash of it is stored.
+    pub token: String,
+    /// Unix timestamp (seconds) after which the invite can't be used anymore.
+    pub expires_at: i64,
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/admin/invites",
+	tag = "admin",
+	request_body = InviteCreatePayload,
+	responses(
+		(status = CREATED, description = "Invite someone can create an account with. Hand it to them out of band", body = InviteCreateResponse),
+		(status = BAD_REQUEST, description = "The invite would be valid for too long, or not at all"),
+		(status = FORBIDDEN, description = "Not an admin")
+	),
+)]
+pub async fn create_invite(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    JSON(payload): JSON<InviteCreatePayload>,
+) -> Result<(StatusCode, JSON<InviteCreateResponse>), ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+
+    let days = payload
+        .expires_in_days
+        .unwrap_or(Invite::DEFAULT_LIFETIME_DAYS);
+    if days == 0 || days > Invite::MAX_LIFETIME_DAYS {
+        return Err(ApiError::BadRequest(format!(
+            "Invites can be valid for 1 to {} days.",
+            Invite::MAX_LIFETIME_DAYS
+        )));
+    }
+
+    let (invite, token) = Invite::generate(
+        admin.id,
+        chrono::Utc::now().timestamp(),
+        i64::from(days) * 24 * 60 * 60,
+    );
+    invite.insert(&state.db).await?;
+    info!("{} created the invite {}", invite.created_by, invite.id);
+
+    Ok((
+        StatusCode::CREATED,
+        JSON(InviteCreateResponse {
+            id: invite.id,
+            token,
+            expires_at: invite.expires_at,
+        }),
+    ))
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/admin/invites",
+	tag = "admin",
+	responses(
+		(status = OK, description = "Every invite, including used and expired ones, most recently created first", body = Vec<Invite>),
+		(status = FORBIDDEN, description = "Not an admin")
+	),
+)]
+pub async fn list_invites(
+    State(state): State<Arc<AppState>>,
+) -> Result<JSON<Vec<Invite>>, ApiError> {
+    Ok(JSON(Invite::list(&state.db).await?))
+}
+
+#[utoipa::path(
+	delete,
+	path = "/v1/admin/invites/{id}",
+	tag = "admin",
+	params(
+		("id", description = "Id of the invite to revoke")
+	),
+	responses(
+		(status = NO_CONTENT, description = "Revoked the invite. Accounts created with it are kept"),
+		(status = FORBIDDEN, description = "Not an admin"),
+		(status = NOT_FOUND, description = "Unable to find invite")
+	),
+)]
+pub async fn revoke_invite(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+
+    if !Invite::delete(&state.db, &id).await? {
+        return Err(ApiError::NotFound);
+    }
+    info!("{} revoked the invite {id}", admin.id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/admin/stats",
+	tag = "admin",
+	responses(
+		(status = OK, description = "Totals of the instance", body = InstanceStats),
+		(status = FORBIDDEN, description = "Not an admin")
+	),
+)]
+pub async fn stats(State(state): State<Arc<AppState>>) -> Result<JSON<InstanceStats>, ApiError> {
+    Ok(JSON(InstanceStats::get(&state.db).await?))
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/admin/webhooks",
+	tag = "admin",
+	responses(
+		(status = OK, description = "Webhooks of the instance, which receive the events of every user, oldest first", body = Vec<WebhookResponse>),
+		(status = FORBIDDEN, description = "Not an admin")
+	),
+)]
+pub async fn list_instance_webhooks(
+    State(state): State<Arc<AppState>>,
+) -> Result<JSON<Vec<WebhookResponse>>, ApiError> {
+    let webhooks = Webhook::list(&state.db, None).await?;
+    Ok(JSON(webhooks.into_iter().map(Into::into).collect()))
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/admin/webhooks",
+	tag = "admin",
+	request_body = WebhookCreatePayload,
+	responses(
+		(status = CREATED, description = "Registered the webhook. The events of every user are posted to it from now on, signed with the secret. It may point at internal hosts", body = WebhookCreateResponse),
+		(status = BAD_REQUEST, description = "The URL isn't an http or https URL, or an event is unknown"),
+		(status = FORBIDDEN, description = "Not an admin")
+	),
+)]
+pub async fn create_instance_webhook(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    JSON(payload): JSON<WebhookCreatePayload>,
+) -> Result<(StatusCode, JSON<WebhookCreateResponse>), ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+
+    let created = webhooks::create(&state.db, None, payload).await?;
+    info!(
+        "{} registered the instance webhook {}",
+        admin.id, created.webhook.id
+    );
+
+    Ok((StatusCode::CREATED, JSON(created)))
+}
+
+#[utoipa::path(
+	delete,
+	path = "/v1/admin/webhooks/{id}",
+	tag = "admin",
+	params(
+		("id", description = "Id of the webhook to remove")
+	),
+	responses(
+		(status = NO_CONTENT, description = "Removed the webhook with its delivery log"),
+		(status = FORBIDDEN, description = "Not an admin"),
+		(status = NOT_FOUND, description = "Unable to find webhook")
+	),
+)]
+pub async fn delete_instance_webhook(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+
+    let webhook = Webhook::get(&state.db, &id, None)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    webhook.delete(&state.db).await?;
+    info!("{} removed the instance webhook {}", admin.id, webhook.id);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/admin/webhooks/{id}/deliveries",
+	tag = "admin",
+	params(
+		("id", description = "Id of the webhook")
+	),
+	responses(
+		(status = OK, description = "The 50 most recent deliveries to the webhook, newest first", body = Vec<WebhookDeliveryResponse>),
+		(status = FORBIDDEN, description = "Not an admin"),
+		(status = NOT_FOUND, description = "Unable to find webhook")
+	),
+)]
+pub async fn list_instance_webhook_deliveries(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<JSON<Vec<WebhookDeliveryResponse>>, ApiError> {
+    Ok(JSON(webhooks::deliveries(&state.db, &id, None).await?))
+}
@@ -1,49 +1,766 @@
-use super::{ApiError, JSON};
+use super::{
+    folders::ensure_folder,
+    icons::ICON_HEADERS,
+    query::{self, Validate, ValidatedQuery},
+    tags::normalize_tags,
+    ApiError, ApiErrorResponse, JSON,
+};
 use crate::{
-    models::{codes::Code, user::User},
-    utils, AppState,
+    auth::{self, IssuedAt},
+    e2e,
+    events::{ClientId, Event, EventKind},
+    icons::{self, IconStoreError},
+    models::{
+        codes::{Code, CodeBatch, CodeSort, Move},
+        folders::Folder,
+        icons::Icon,
+        revisions::{self, CodeRevision},
+        tags,
+        tokens::{TokenScope, TokenScopes},
+        user::User,
+    },
+    otpauth,
+    quota::{self, Quota},
+    totp, utils, AppState,
 };
 use axum::{
     extract::{Path, State},
     http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
     Extension,
 };
 use reqwest::header;
-use serde::Deserialize;
-use std::sync::Arc;
-use utoipa::ToSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqliteExecutor};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+use utoipa::{IntoParams, ToSchema};
+
+/// Serializes codes, leaving out their content if the token may not read secrets.
+/// Going through a [`serde_json::Value`] sorts the keys of every code, so responses are stable
+/// for clients diffing them. Enabling serde_json's `preserve_order` feature would break that.
+fn serialize_codes(codes: Vec<impl Serialize>, scopes: &TokenScopes) -> serde_json::Value {
+    let mut codes = serde_json::to_value(codes).expect("Unable to serialize codes");
+
+    if !scopes.allows(TokenScope::CodesRead) {
+        for code in codes.as_array_mut().into_iter().flatten() {
+            if let Some(fields) = code.as_object_mut() {
+                fields.remove("content");
+            }
+        }
+    }
+
+    codes
+}
+
+/// Fields of [`Code`] which can be selected with `?fields=`.
+const CODE_FIELDS: &[&str] = &[
+    "id",
+    "owner_id",
+    "content",
+    "display_name",
+    "icon_url",
+    "website_url",
+    "issuer",
+    "account",
+    "kind",
+    "digits",
+    "period",
+    "algorithm",
+    "expires_at",
+    "sort_index",
+    "favorite",
+    "folder_id",
+    "created_at",
+    "updated_at",
+    "revision",
+];
+
+#[derive(Deserialize, IntoParams)]
+pub struct CodeFieldsQuery {
+    /// Comma separated fields to respond with, e.g. `id,display_name`. Defaults to every field.
+    /// Selecting `content` doesn't include it for `read:metadata` tokens.
+    pub fields: Option<String>,
+}
+
+impl Validate for CodeFieldsQuery {
+    fn validate(&mut self) -> Result<(), ApiError> {
+        for field in self.fields.iter().flat_map(|fields| fields.split(',')) {
+            query::one_of("fields", field, CODE_FIELDS)?;
+        }
+        Ok(())
+    }
+}
+
+impl CodeFieldsQuery {
+    /// Removes every unselected field from a serialized code, or list of codes.
+    fn select(&self, codes: &mut serde_json::Value) {
+        let Some(fields) = &self.fields else {
+            return;
+        };
+        let fields: Vec<&str> = fields.split(',').collect();
+
+        let codes = match codes {
+            serde_json::Value::Array(codes) => codes.iter_mut().collect(),
+            code => vec![code],
+        };
+        for code in codes.into_iter().filter_map(|code| code.as_object_mut()) {
+            code.retain(|key, _| fields.contains(&key.as_str()));
+        }
+    }
+}
+
+/// Most codes served by a single page of the listing.
+const MAX_PAGE_SIZE: i64 = 500;
+
+#[derive(Deserialize, IntoParams)]
+pub struct CodePageQuery {
+    /// Amount of codes to respond with, up to 500. Defaults to every code.
+    pub limit: Option<i64>,
+    /// Amount of codes to skip. Defaults to 0.
+    #[serde(default)]
+    pub offset: i64,
+    #[serde(default)]
+    #[param(inline)]
+    pub sort: CodeSort,
+    /// Only list codes with this tag.
+    pub tag: Option<String>,
+    /// Groups the codes of the page by folder, into `{ folders: [{ folder, codes }], uncategorized }`.
+    #[param(inline)]
+    pub group_by: Option<CodeGrouping>,
+}
+
+impl Validate for CodePageQuery {
+    fn validate(&mut self) -> Result<(), ApiError> {
+        if let Some(limit) = self.limit {
+            query::in_range("limit", limit, 1, MAX_PAGE_SIZE)?;
+        }
+        query::in_range("offset", self.offset, 0, i64::MAX)?;
+        Ok(())
+    }
+}
+
+/// How the codes of a listing are grouped.
+#[derive(Deserialize, ToSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CodeGrouping {
+    /// By the folder they're in.
+    Folder,
+}
+
+/// Codes of a folder, in the order of the listing.
+#[derive(Serialize, ToSchema)]
+pub struct FolderCodes {
+    pub folder: Folder,
+    #[schema(value_type = Vec<Code>)]
+    pub codes: serde_json::Value,
+}
+
+/// A page of codes grouped by folder, with `?group_by=folder`. Folders are listed in the order of
+/// `GET /v1/folders` when they hold codes of the page, or contain a folder which does, so clients
+/// can rebuild the tree. Empty folders are left out.
+#[derive(Serialize, ToSchema)]
+pub struct GroupedCodes {
+    pub folders: Vec<FolderCodes>,
+    /// Codes outside of any folder.
+    #[schema(value_type = Vec<Code>)]
+    pub uncategorized: serde_json::Value,
+}
+
+impl GroupedCodes {
+    fn new(
+        codes: Vec<Code>,
+        folders: Vec<Folder>,
+        scopes: &TokenScopes,
+        query: &CodeFieldsQuery,
+    ) -> Self {
+        let serialize = |codes: Vec<Code>| {
+            let mut codes = serialize_codes(codes, scopes);
+            query.select(&mut codes);
+            codes
+        };
+        let parents: HashMap<&str, Option<&str>> = folders
+            .iter()
+            .map(|folder| (folder.id.as_str(), folder.parent_id.as_deref()))
+            .collect();
+
+        let mut by_folder: HashMap<String, Vec<Code>> = HashMap::new();
+        let mut uncategorized = vec![];
+        for code in codes {
+            match code.folder_id.clone() {
+                Some(folder_id) if parents.contains_key(folder_id.as_str()) => {
+                    by_folder.entry(folder_id).or_default().push(code)
+                }
+                _ => uncategorized.push(code),
+            }
+        }
+
+        let mut listed: HashSet<&str> = HashSet::new();
+        for folder_id in by_folder.keys() {
+            let mut next = parents.get_key_value(folder_id.as_str()).map(|(id, _)| *id);
+            // Stops at folders already listed, along with their parents
+            while let Some(id) = next.filter(|id| listed.insert(*id)) {
+                next = parents.get(id).copied().flatten();
+            }
+        }
+
+        let listed: HashSet<String> = listed.into_iter().map(str::to_string).collect();
+        let folders = folders
+            .into_iter()
+            .filter(|folder| listed.contains(&folder.id))
+            .map(|folder| FolderCodes {
+                codes: serialize(by_folder.remove(&folder.id).unwrap_or_default()),
+                folder,
+            })
+            .collect();
+
+        GroupedCodes {
+            folders,
+            uncategorized: serialize(uncategorized),
+        }
+    }
+}
 
 #[utoipa::path(
 	get,
 	path = "/v1/code",
+	params(CodeFieldsQuery, CodePageQuery),
 	responses(
-		(status = OK, description = "Successfully fetches codes", body = Vec<Code>)
+		(status = OK, description = "Successfully fetches codes. The content is left out for `read:metadata` tokens. With `?group_by=folder`, the page is grouped into a `GroupedCodes` object instead, still paged and counted by codes", body = Vec<Code>,
+			headers(("X-Total-Count" = i64, description = "Amount of codes across all pages"), ("ETag" = String, description = "Changes whenever the response would"))),
+		(status = NOT_MODIFIED, description = "The codes match the ETag in `If-None-Match`"),
+		(status = BAD_REQUEST, description = "Unknown field selected, or invalid page")
 	),
 	tag = "codes",
 )]
 pub async fn list_all_codes(
     State(state): State<Arc<AppState>>,
     Extension(user): Extension<User>,
-) -> JSON<Vec<Code>> {
-    JSON(
-        Code::get_many(&state.db, user.id)
-            .await
-            .expect("Unable to find codes owned by user"),
+    Extension(scopes): Extension<TokenScopes>,
+    ValidatedQuery(query): ValidatedQuery<CodeFieldsQuery>,
+    ValidatedQuery(page): ValidatedQuery<CodePageQuery>,
+    request_headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    // Read in a single transaction, so the page, the total and the folders are of one snapshot
+    let mut tx = state.db.begin().await?;
+    let (codes, total) = Code::get_page(
+        &mut tx,
+        user.id.clone(),
+        page.sort,
+        page.tag,
+        page.limit,
+        page.offset,
+    )
+    .await?;
+    let codes = match page.group_by {
+        None => {
+            let mut codes = serialize_codes(codes, &scopes);
+            query.select(&mut codes);
+            codes
+        }
+        Some(CodeGrouping::Folder) => {
+            let folders = Folder::get_many(&mut *tx, &user.id).await?;
+            serde_json::to_value(GroupedCodes::new(codes, folders, &scopes, &query))
+                .expect("Unable to serialize codes")
+        }
+    };
+    tx.commit().await?;
+
+    // The ETag covers the body and the total, as either changing has to reach clients
+    let json = serde_json::to_vec(&codes).expect("Unable to serialize codes");
+    let etag = utils::etag(&[&total.to_be_bytes()[..], &json[..]].concat());
+    let headers = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, "private, no-cache".to_string()),
+        (
+            header::HeaderName::from_static("x-total-count"),
+            total.to_string(),
+        ),
+    ];
+
+    if utils::etag_matches(&request_headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    Ok((
+        StatusCode::OK,
+        headers,
+        [(header::CONTENT_TYPE, "application/json".to_string())],
+        json,
     )
+        .into_response())
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/code/{id}",
+	tag = "codes",
+	params(
+		("id", description = "Id of the code to fetch"),
+		CodeFieldsQuery
+	),
+	responses(
+		(status = OK, description = "Successfully fetches the code. The content is left out for `read:metadata` tokens", body = Code),
+		(status = BAD_REQUEST, description = "Unknown field selected"),
+		(status = NOT_FOUND, description = "Unable to find code")
+	),
+)]
+pub async fn get_code(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    Path(id): Path<String>,
+    ValidatedQuery(query): ValidatedQuery<CodeFieldsQuery>,
+) -> Result<JSON<serde_json::Value>, ApiError> {
+    let code = Code::get(&state.db, id, user.id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    let mut code = serialize_codes(vec![code], &scopes)
+        .as_array_mut()
+        .and_then(|codes| codes.pop())
+        .unwrap_or_default();
+    query.select(&mut code);
+
+    Ok(JSON(code))
+}
+
+/// Verifications a single user may attempt per minute, so codes can't be guessed.
+pub const VERIFICATIONS_PER_MINUTE: u32 = 30;
+
+#[derive(Deserialize, ToSchema)]
+pub struct CodeVerifyPayload {
+    /// The TOTP code to check, e.g. `123456`.
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct CodeVerifyResponse {
+    pub valid: bool,
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/code/{id}/verify",
+	tag = "codes",
+	params(
+		("id", description = "Id of the code to verify against")
+	),
+	request_body = CodeVerifyPayload,
+	responses(
+		(status = OK, description = "Whether the code is valid right now, or within the configured amount of time steps around it", body = CodeVerifyResponse),
+		(status = BAD_REQUEST, description = "The content of the code is not a base32 TOTP secret"),
+		(status = FORBIDDEN, description = "Token is not allowed to read secrets"),
+		(status = NOT_FOUND, description = "Unable to find code"),
+		(status = CONFLICT, description = "The user has end-to-end encryption, so the secret can't be read"),
+		(status = TOO_MANY_REQUESTS, description = "Verified too many codes recently")
+	),
+)]
+pub async fn verify_code(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    Path(id): Path<String>,
+    JSON(payload): JSON<CodeVerifyPayload>,
+) -> Result<JSON<CodeVerifyResponse>, ApiError> {
+    if !scopes.allows(TokenScope::CodesRead) {
+        return Err(ApiError::InsufficientScope);
+    }
+    e2e::require_readable(&user)?;
+    if !state.totp_verify_limiter.try_hit(&user.id) {
+        return Err(ApiError::RateLimited);
+    }
+
+    let code = Code::get(&state.db, id, user.id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let key = totp::decode_secret(&code.content).ok_or_else(|| {
+        ApiError::BadRequest("The content of the code is not a base32 TOTP secret.".into())
+    })?;
+
+    Ok(JSON(CodeVerifyResponse {
+        valid: totp::verify_with(
+            &key,
+            payload.code.trim(),
+            chrono::Utc::now().timestamp(),
+            state.settings.totp_skew,
+            code.totp_params(),
+        ),
+    }))
+}
+
+/// How codes are generated from the secret. Unset parameters are left as they are.
+#[derive(Deserialize, ToSchema, Default)]
+pub struct CodeParams {
+    /// `totp`, or `steam` for Steam Guard codes. Steam codes always have 5 characters, and use
+    /// SHA1 with a period of 30 seconds, so the other parameters can't be set for them.
+    pub kind: Option<String>,
+    /// Amount of digits of the generated codes, 6 to 8.
+    pub digits: Option<u32>,
+    /// Seconds every code is valid for, 1 to 300.
+    pub period: Option<i64>,
+    /// `SHA1`, `SHA256` or `SHA512`.
+    pub algorithm: Option<String>,
+}
+
+impl CodeParams {
+    fn is_empty(&self) -> bool {
+        self.kind.is_none()
+            && self.digits.is_none()
+            && self.period.is_none()
+            && self.algorithm.is_none()
+    }
+
+    /// Applies the parameters on top of the current ones.
+    fn resolve(&self, current: totp::Params) -> Result<totp::Params, ApiError> {
+        otpauth::override_params(
+            current,
+            self.kind.as_deref(),
+            self.algorithm.as_deref(),
+            self.digits,
+            self.period,
+        )
+        .map_err(ApiError::InvalidCodeParameter)
+    }
+
+    /// The parameters of the code after an edit, if any were given.
+    fn resolve_for(&self, code: &Code) -> Result<Option<totp::Params>, ApiError> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        self.resolve(code.totp_params()).map(Some)
+    }
 }
 
 #[derive(Deserialize, ToSchema)]
 pub struct CodeAddPayload {
-    pub content: String,
-    pub display_name: String,
+    /// Secret of the code. Required unless `otpauth_uri` is given.
+    pub content: Option<String>,
+    /// Required unless `otpauth_uri` is given, defaulting to its label.
+    pub display_name: Option<String>,
     pub website_url: Option<String>,
+    /// `otpauth://totp/` URI to take the secret, name, issuer, account and code parameters from.
+    /// The other fields take precedence over those of the URI.
+    pub otpauth_uri: Option<String>,
+    /// Defaults to those of the URI, or of RFC 6238: 6 digits every 30 seconds with SHA1.
+    #[serde(flatten)]
+    pub params: CodeParams,
+    /// Unix timestamp (seconds) at which the code expires. Has to be in the future.
+    pub expires_at: Option<i64>,
+    #[serde(default)]
+    pub favorite: bool,
+    /// Folder to put the code in. Defaults to the top level.
+    pub folder_id: Option<String>,
+    /// Tags of the code, see `GET /v1/tags`.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl CodeAddPayload {
+    /// The new code, before it is placed in the listing.
+    fn into_code(self, owner_id: String) -> Result<Code, ApiError> {
+        let parsed = self
+            .otpauth_uri
+            .as_deref()
+            .map(otpauth::parse)
+            .transpose()
+            .map_err(ApiError::InvalidOtpAuthUri)?;
+        let params = self.params.resolve(
+            parsed
+                .as_ref()
+                .map(|parsed| parsed.params)
+                .unwrap_or_default(),
+        )?;
+        let (secret, label, issuer, account) = match parsed {
+            Some(parsed) => (
+                Some(parsed.secret),
+                Some(parsed.display_name),
+                parsed.issuer,
+                Some(parsed.account),
+            ),
+            None => (None, None, None, None),
+        };
+
+        // Without a URI, both are required fields of the payload
+        let (Some(content), Some(display_name)) =
+            (self.content.or(secret), self.display_name.or(label))
+        else {
+            return Err(ApiError::JsonDataError);
+        };
+
+        Ok(Code {
+            id: utils::generate_id(16),
+            owner_id,
+            content,
+            display_name,
+            website_url: self.website_url,
+            icon_url: None,
+            expires_at: self.expires_at,
+            sort_index: 0,
+            issuer,
+            account,
+            kind: params.kind.as_str().into(),
+            digits: params.digits as i64,
+            period: params.period,
+            algorithm: params.algorithm.as_str().into(),
+            favorite: self.favorite,
+            folder_id: self.folder_id,
+            created_at: 0,
+            updated_at: 0,
+            revision: 0,
+            deleted_at: None,
+        })
+    }
+}
+
+/// Rejects a value longer than the field of a code allows, see [`Code::limited_fields`].
+pub(crate) fn validate_length(
+    field: &str,
+    value: Option<&str>,
+    max_len: usize,
+) -> Result<(), ApiError> {
+    if value.is_some_and(|value| value.len() > max_len) {
+        return Err(ApiError::BadRequest(format!(
+            "The {field} of a code can be at most {max_len} characters."
+        )));
+    }
+    Ok(())
+}
+
+fn validate_lengths(code: &Code) -> Result<(), ApiError> {
+    for (field, value, max_len) in code.limited_fields() {
+        validate_length(field, value, max_len)?;
+    }
+    Ok(())
+}
+
+/// Rejects edits making fields of a code longer than they may be.
+fn validate_edit_lengths(payload: &CodeEditPayload) -> Result<(), ApiError> {
+    validate_length(
+        "content",
+        payload.content.as_deref(),
+        Code::MAX_CONTENT_LENGTH,
+    )?;
+    validate_length(
+        "display_name",
+        payload.display_name.as_deref(),
+        Code::MAX_DISPLAY_NAME_LENGTH,
+    )?;
+    validate_length(
+        "website_url",
+        payload.website_url.as_ref().and_then(Option::as_deref),
+        Code::MAX_WEBSITE_URL_LENGTH,
+    )
+}
+
+fn validate_expiry(expires_at: Option<i64>) -> Result<(), ApiError> {
+    match expires_at {
+        Some(expiry) if expiry <= chrono::Utc::now().timestamp() => Err(ApiError::ExpiryInPast),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CodeGetManyPayload {
+    /// Ids of the codes to fetch. At most 500.
+    pub ids: Vec<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CodeGetManyResponse {
+    /// The content is left out for `read:metadata` tokens.
+    #[schema(value_type = Vec<Code>)]
+    pub codes: serde_json::Value,
+    /// Requested ids which don't exist, or aren't owned by the user.
+    pub missing: Vec<String>,
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/codes/get",
+	request_body = CodeGetManyPayload,
+	responses(
+		(status = OK, description = "Successfully fetches the requested codes", body = CodeGetManyResponse),
+		(status = BAD_REQUEST, description = "Too many ids requested")
+	),
+	tag = "codes",
+)]
+pub async fn get_many_codes(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    JSON(payload): JSON<CodeGetManyPayload>,
+) -> Result<JSON<CodeGetManyResponse>, ApiError> {
+    if payload.ids.len() > Code::MAX_IDS_PER_QUERY {
+        return Err(ApiError::BadRequest(format!(
+            "At most {} ids can be requested at once.",
+            Code::MAX_IDS_PER_QUERY
+        )));
+    }
+
+    let codes = Code::get_many_by_ids(&state.db, user.id, &payload.ids).await?;
+    let missing = payload
+        .ids
+        .into_iter()
+        .filter(|id| !codes.iter().any(|code| &code.id == id))
+        .collect();
+
+    Ok(JSON(CodeGetManyResponse {
+        codes: serialize_codes(codes, &scopes),
+        missing,
+    }))
+}
+
+/// Longest search accepted by [`search_codes`].
+const MAX_SEARCH_LENGTH: usize = 256;
+
+#[derive(Deserialize, IntoParams)]
+pub struct CodeSearchQuery {
+    /// Words to search for in the names and websites of codes. Each word matches the start of a
+    /// word, e.g. `git` matches `GitHub`.
+    pub q: String,
+    /// Amount of matches to respond with, up to 500. Defaults to 50.
+    #[serde(default = "default_search_limit")]
+    pub limit: i64,
+}
+
+fn default_search_limit() -> i64 {
+    50
+}
+
+impl Validate for CodeSearchQuery {
+    fn validate(&mut self) -> Result<(), ApiError> {
+        query::non_empty("q", &self.q, MAX_SEARCH_LENGTH)?;
+        query::in_range("limit", self.limit, 1, MAX_PAGE_SIZE)?;
+        Ok(())
+    }
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/codes/search",
+	params(CodeSearchQuery, CodeFieldsQuery),
+	responses(
+		(status = OK, description = "Codes matching the search, best match first. The content is left out for `read:metadata` tokens", body = Vec<Code>),
+		(status = BAD_REQUEST, description = "Empty search, invalid limit or unknown field selected")
+	),
+	tag = "codes",
+)]
+pub async fn search_codes(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    ValidatedQuery(search): ValidatedQuery<CodeSearchQuery>,
+    ValidatedQuery(query): ValidatedQuery<CodeFieldsQuery>,
+) -> Result<JSON<serde_json::Value>, ApiError> {
+    let codes = Code::search(&state.db, user.id, &search.q, search.limit).await?;
+    let mut codes = serialize_codes(codes, &scopes);
+    query.select(&mut codes);
+
+    Ok(JSON(codes))
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct CodeDeltaQuery {
+    /// Cursor returned by the previous sync. Leave out to receive every code.
+    pub since: Option<i64>,
+}
+
+impl Validate for CodeDeltaQuery {
+    fn validate(&mut self) -> Result<(), ApiError> {
+        if let Some(since) = self.since {
+            query::in_range("since", since, 0, i64::MAX)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CodeDeltaResponse {
+    /// Codes created or changed since the cursor, oldest change first. The content is left out for
+    /// `read:metadata` tokens.
+    #[schema(value_type = Vec<Code>)]
+    pub changed: serde_json::Value,
+    /// Ids of codes deleted since the cursor, or which have expired.
+    pub deleted: Vec<String>,
+    /// Pass as `since` to the next sync. Only ever increases.
+    pub cursor: i64,
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/codes/delta",
+	params(CodeDeltaQuery),
+	responses(
+		(status = OK, description = "Changes to the codes since the cursor", body = CodeDeltaResponse),
+		(status = BAD_REQUEST, description = "Invalid cursor"),
+		(status = GONE, description = "The cursor predates purged deletions. Sync in full by leaving out `since`")
+	),
+	tag = "codes",
+)]
+pub async fn delta_codes(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    ValidatedQuery(query): ValidatedQuery<CodeDeltaQuery>,
+) -> Result<JSON<CodeDeltaResponse>, ApiError> {
+    let (codes, cursor) = Code::changes_since(&state.db, &user.id, query.since.unwrap_or(-1))
+        .await?
+        .ok_or(ApiError::CursorExpired)?;
+
+    let now = chrono::Utc::now().timestamp();
+    let (deleted, changed): (Vec<Code>, Vec<Code>) = codes.into_iter().partition(|code| {
+        code.deleted_at.is_some() || code.expires_at.is_some_and(|expiry| expiry <= now)
+    });
+
+    Ok(JSON(CodeDeltaResponse {
+        changed: serialize_codes(changed, &scopes),
+        deleted: deleted.into_iter().map(|code| code.id).collect(),
+        cursor,
+    }))
+}
+
+/// Rejects the name if the user enforces unique names and another code in the folder already has it.
+pub async fn ensure_unique_name(
+    executor: impl SqliteExecutor<'_>,
+    user: &User,
+    folder_id: Option<&str>,
+    display_name: &str,
+    except_id: Option<&str>,
+) -> Result<(), ApiError> {
+    if user.enforce_unique_names
+        && Code::name_taken(executor, &user.id, folder_id, display_name, except_id).await?
+    {
+        return Err(ApiError::DuplicateName);
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct CodeAddQuery {
+    /// Add the code even if another code already has its secret.
+    #[serde(default)]
+    pub allow_duplicate: bool,
+}
+
+impl Validate for CodeAddQuery {
+    fn validate(&mut self) -> Result<(), ApiError> {
+        Ok(())
+    }
 }
 
 #[utoipa::path(
 	method(put),
 	path = "/v1/code",
+	params(CodeAddQuery),
 	responses(
-		(status = OK, description = "Succesfully created code. Response contains contents of the new code", body = Code)
+		(status = OK, description = "Succesfully created code. Response contains contents of the new code", body = Code),
+		(status = BAD_REQUEST, description = "Missing secret or name, invalid otpauth URI, code parameters or tags, or the folder doesn't exist. Errors of the URI and parameters name the invalid part in `field`", body = ApiErrorResponse),
+		(status = FORBIDDEN, description = "The user already stores as many codes as the instance allows", body = ApiErrorResponse),
+		(status = CONFLICT, description = "Another code already has the secret, named in `existing_id`, or the user enforces unique names and another code has the name", body = ApiErrorResponse),
+		(status = UNPROCESSABLE_ENTITY, description = "The user has end-to-end encryption, but the secret isn't encrypted")
 	),
 	request_body = CodeAddPayload,
 	tag = "codes"
@@ -51,18 +768,156 @@ pub struct CodeAddPayload {
 pub async fn add_code(
     State(state): State<Arc<AppState>>,
     Extension(user): Extension<User>,
-    JSON(payload): JSON<CodeAddPayload>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    ValidatedQuery(query): ValidatedQuery<CodeAddQuery>,
+    JSON(mut payload): JSON<CodeAddPayload>,
+) -> Result<JSON<Code>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+    validate_expiry(payload.expires_at)?;
+    let tags = normalize_tags(std::mem::take(&mut payload.tags))?;
+    let mut code = payload.into_code(user.id.clone())?;
+    validate_lengths(&code)?;
+    e2e::require_encrypted(&user, &code.content)?;
+    // Encrypted secrets differ every time they are encrypted, so duplicates can't be recognized
+    if !query.allow_duplicate && !user.encryption_enabled {
+        if let Some(existing_id) = Code::find_by_secret(&state.db, &user.id, &code.content).await? {
+            return Err(ApiError::DuplicateSecret(existing_id));
+        }
+    }
+    ensure_folder(&state.db, &user, code.folder_id.as_deref()).await?;
+    ensure_unique_name(
+        &state.db,
+        &user,
+        code.folder_id.as_deref(),
+        &code.display_name,
+        None,
+    )
+    .await?;
+    code.sort_index = Code::next_sort_index(&state.db, &user.id).await?;
+
+    let mut batch = CodeBatch::begin(&state.db).await?;
+    Quota::new(&state.settings)
+        .ensure_room(batch.conn(), &user.id, 1, 0)
+        .await?;
+    batch.insert(&mut code).await?;
+    batch.set_tags(&code, &tags).await?;
+    batch.commit().await?;
+    state
+        .events
+        .publish(Event::code(EventKind::CodeCreated, &code, client_id));
+
+    Ok(JSON(code))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CodeClonePayload {
+    /// Secret of the clone. Required unless `copy_content` is set.
+    pub content: Option<String>,
+    /// Copy the secret of the original. Usually undesired, as the clone would generate the same codes.
+    #[serde(default)]
+    pub copy_content: bool,
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/code/{id}/clone",
+	tag = "codes",
+	params(
+		("id", description = "Id of the code to clone")
+	),
+	request_body = CodeClonePayload,
+	responses(
+		(status = OK, description = "Successfully cloned the code, appending it to the listing. Response contains the clone", body = Code),
+		(status = BAD_REQUEST, description = "Neither a secret was given, nor asked to copy it"),
+		(status = FORBIDDEN, description = "The clone would take the user over the limit of codes or icon bytes of the instance"),
+		(status = NOT_FOUND, description = "Unable to find code"),
+		(status = CONFLICT, description = "The user enforces unique names, and another code has the name of the clone"),
+		(status = UNPROCESSABLE_ENTITY, description = "The user has end-to-end encryption, but the secret isn't encrypted")
+	),
+)]
+pub async fn clone_code(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    Path(id): Path<String>,
+    JSON(payload): JSON<CodeClonePayload>,
 ) -> Result<JSON<Code>, ApiError> {
-    let code = Code {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+
+    let original = Code::get(&state.db, id, user.id.clone())
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    let content = match (payload.content, payload.copy_content) {
+        (Some(_), true) => {
+            return Err(ApiError::BadRequest(
+                "Either supply the secret of the clone, or copy it. Not both.".into(),
+            ))
+        }
+        (Some(content), false) => content,
+        (None, true) => original.content,
+        (None, false) => {
+            return Err(ApiError::BadRequest(
+                "Supply the secret of the clone, or set copy_content to copy it.".into(),
+            ))
+        }
+    };
+
+    e2e::require_encrypted(&user, &content)?;
+
+    let display_name = format!("{} copy", original.display_name);
+    ensure_unique_name(
+        &state.db,
+        &user,
+        original.folder_id.as_deref(),
+        &display_name,
+        None,
+    )
+    .await?;
+
+    let mut code = Code {
         id: utils::generate_id(16),
-        owner_id: user.id,
-        content: payload.content,
-        display_name: payload.display_name,
-        website_url: payload.website_url,
-        icon_url: None,
+        owner_id: user.id.clone(),
+        content,
+        display_name,
+        website_url: original.website_url,
+        icon_url: original.icon_url,
+        expires_at: None,
+        sort_index: Code::next_sort_index(&state.db, &user.id).await?,
+        issuer: original.issuer,
+        account: original.account,
+        kind: original.kind,
+        digits: original.digits,
+        period: original.period,
+        algorithm: original.algorithm,
+        favorite: original.favorite,
+        folder_id: original.folder_id,
+        created_at: 0,
+        updated_at: 0,
+        revision: 0,
+        deleted_at: None,
     };
+    validate_lengths(&code)?;
+
+    let mut batch = CodeBatch::begin(&state.db).await?;
+    Quota::new(&state.settings)
+        .ensure_room(
+            batch.conn(),
+            &user.id,
+            1,
+            quota::icon_bytes(code.icon_url.as_deref()),
+        )
+        .await?;
+    let tags = tags::of_code(batch.conn(), &original.id).await?;
+    batch.insert(&mut code).await?;
+    batch.set_tags(&code, &tags).await?;
+    batch.commit().await?;
+    state
+        .events
+        .publish(Event::code(EventKind::CodeCreated, &code, client_id));
 
-    code.insert(&state.db).await?;
     Ok(JSON(code))
 }
 
@@ -76,6 +931,152 @@ pub struct CodeEditPayload {
         with = "::serde_with::rust::double_option"
     )]
     pub website_url: Option<Option<String>>,
+    #[serde(flatten)]
+    pub params: CodeParams,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub expires_at: Option<Option<i64>>,
+    pub favorite: Option<bool>,
+    /// Folder to move the code into. `null` moves it to the top level.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub folder_id: Option<Option<String>>,
+    /// Replaces the tags of the code. An empty list removes every tag.
+    pub tags: Option<Vec<String>>,
+    /// Only edit the code if it is still at this revision. Takes precedence over `If-Match`.
+    pub expected_revision: Option<i64>,
+}
+
+/// Revision the client expects the code to be at, from the request itself, or an `If-Match`
+/// header holding the quoted revision. `If-Match: *` matches any revision.
+fn expected_revision(headers: &HeaderMap, explicit: Option<i64>) -> Result<Option<i64>, ApiError> {
+    if explicit.is_some() {
+        return Ok(explicit);
+    }
+
+    let Some(value) = headers.get(header::IF_MATCH) else {
+        return Ok(None);
+    };
+    let value = value.to_str().unwrap_or_default().trim();
+    if value == "*" {
+        return Ok(None);
+    }
+
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .and_then(|revision| revision.parse().ok())
+        .map(Some)
+        .ok_or_else(|| {
+            ApiError::BadRequest(
+                "`If-Match` has to be the quoted revision of the code, e.g. \"12\".".into(),
+            )
+        })
+}
+
+/// Error for a change to a code which matched no row. If the code is still around, it was changed
+/// since the revision the client expected, so the current copy is sent along to merge with.
+async fn change_failed(
+    state: &AppState,
+    err: sqlx::Error,
+    id: String,
+    owner_id: String,
+) -> ApiError {
+    if !matches!(err, sqlx::Error::RowNotFound) {
+        return err.into();
+    }
+
+    match Code::get(&state.db, id, owner_id).await {
+        Ok(Some(current)) => ApiError::RevisionConflict(Box::new(current)),
+        Ok(None) => ApiError::NotFound,
+        Err(err) => err.into(),
+    }
+}
+
+/// Rejects moving the code into an unknown folder, or a name clashing with another code in its
+/// folder after the edit.
+async fn check_placement(
+    conn: &mut SqliteConnection,
+    user: &User,
+    code: &Code,
+    payload: &CodeEditPayload,
+) -> Result<(), ApiError> {
+    if let Some(folder_id) = &payload.folder_id {
+        ensure_folder(&mut *conn, user, folder_id.as_deref()).await?;
+    }
+
+    if payload.display_name.is_some() || payload.folder_id.is_some() {
+        let folder_id = match &payload.folder_id {
+            Some(folder_id) => folder_id.as_deref(),
+            None => code.folder_id.as_deref(),
+        };
+        let display_name = payload.display_name.as_ref().unwrap_or(&code.display_name);
+        ensure_unique_name(conn, user, folder_id, display_name, Some(&code.id)).await?;
+    }
+    Ok(())
+}
+
+impl CodeEditPayload {
+    /// Names of the code fields which are modified by this payload.
+    fn changed_fields(&self) -> Vec<&'static str> {
+        let mut fields = vec![];
+
+        if self.content.is_some() {
+            fields.push("content");
+        }
+        if self.display_name.is_some() {
+            fields.push("display_name");
+        }
+        if self.website_url.is_some() {
+            // Changing the website resets the icon
+            fields.push("website_url");
+            fields.push("icon_url");
+        }
+        if !self.params.is_empty() {
+            // The parameters are stored together
+            fields.extend(["kind", "digits", "period", "algorithm"]);
+        }
+        if self.expires_at.is_some() {
+            fields.push("expires_at");
+        }
+        if self.favorite.is_some() {
+            fields.push("favorite");
+        }
+        if self.folder_id.is_some() {
+            fields.push("folder_id");
+        }
+
+        fields
+    }
+}
+
+#[derive(Deserialize, ToSchema, Default, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum EditResponseFields {
+    /// Respond with the entire code.
+    #[default]
+    Full,
+    /// Respond with the id, and the fields modified by the edit.
+    Changed,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct CodeEditQuery {
+    #[serde(default)]
+    #[param(inline)]
+    pub fields: EditResponseFields,
+}
+
+impl Validate for CodeEditQuery {
+    fn validate(&mut self) -> Result<(), ApiError> {
+        Ok(())
+    }
 }
 
 #[utoipa::path(
@@ -83,32 +1084,169 @@ pub struct CodeEditPayload {
 	path = "/v1/code/{id}",
 	tag = "codes",
 	params(
-		("id", description = "Id of the code to edit")
+		("id", description = "Id of the code to edit"),
+		CodeEditQuery
 	),
 	request_body = CodeEditPayload,
 	responses(
-		(status = OK, description = "Success", body = Vec<Code>)
+		(status = OK, description = "Success. Only contains the id and modified fields when `fields=changed`", body = Code),
+		(status = BAD_REQUEST, description = "Invalid code parameters or tags, or malformed `If-Match` header. Invalid parameters are named in `field`", body = ApiErrorResponse),
+		(status = CONFLICT, description = "The user enforces unique names and another code has the new name, or the code is no longer at the expected revision. The latter includes the current copy", body = ApiErrorResponse),
+		(status = UNPROCESSABLE_ENTITY, description = "The user has end-to-end encryption, but the secret isn't encrypted")
 	),
 )]
 pub async fn edit_code(
     State(state): State<Arc<AppState>>,
     Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    Path(id): Path<String>,
+    ValidatedQuery(query): ValidatedQuery<CodeEditQuery>,
+    headers: HeaderMap,
+    JSON(mut payload): JSON<CodeEditPayload>,
+) -> Result<JSON<serde_json::Value>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+    validate_expiry(payload.expires_at.flatten())?;
+    validate_edit_lengths(&payload)?;
+    if let Some(content) = &payload.content {
+        e2e::require_encrypted(&user, content)?;
+    }
+    let tags = payload.tags.take().map(normalize_tags).transpose()?;
+    let expected_revision = expected_revision(&headers, payload.expected_revision)?;
+    let changed = payload.changed_fields();
+
+    let mut code = Code::get(&state.db, id.clone(), user.id.clone())
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    check_placement(&mut *state.db.acquire().await?, &user, &code, &payload).await?;
+    let params = payload.params.resolve_for(&code)?;
+    if let Err(err) = code
+        .edit()
+        .pool(&state.db)
+        .maybe_content(payload.content)
+        .maybe_display_name(payload.display_name)
+        .maybe_website_url(payload.website_url)
+        .maybe_params(params)
+        .maybe_expires_at(payload.expires_at)
+        .maybe_favorite(payload.favorite)
+        .maybe_folder_id(payload.folder_id)
+        .maybe_tags(tags)
+        .maybe_expected_revision(expected_revision)
+        .call()
+        .await
+    {
+        return Err(change_failed(&state, err, id, user.id).await);
+    }
+    state
+        .events
+        .publish(Event::code(EventKind::CodeUpdated, &code, client_id));
+
+    let mut response = serde_json::to_value(code).expect("Unable to serialize code");
+    if let (EditResponseFields::Changed, Some(fields)) = (query.fields, response.as_object_mut()) {
+        fields.retain(|key, _| key == "id" || changed.contains(&key.as_str()));
+    }
+
+    Ok(JSON(response))
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/code/{id}/history",
+	tag = "codes",
+	params(
+		("id", description = "Id of the code")
+	),
+	responses(
+		(status = OK, description = "Previous versions of the code, most recent first. The content is left out for `read:metadata` tokens", body = Vec<CodeRevision>),
+		(status = NOT_FOUND, description = "Unable to find code")
+	),
+)]
+pub async fn code_history(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
     Path(id): Path<String>,
-    JSON(payload): JSON<CodeEditPayload>,
+) -> Result<JSON<serde_json::Value>, ApiError> {
+    let code = Code::get(&state.db, id, user.id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let history = revisions::of_code(&state.db, &code.id).await?;
+
+    Ok(JSON(serialize_codes(history, &scopes)))
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/code/{id}/history/{revision}/restore",
+	tag = "codes",
+	params(
+		("id", description = "Id of the code"),
+		("revision", description = "Revision of the previous version to roll back to")
+	),
+	responses(
+		(status = OK, description = "Rolled back the content, name, website and parameters of the code. The replaced version is added to the history, so the rollback can be undone", body = Code),
+		(status = NOT_FOUND, description = "Unable to find the code, or the previous version"),
+		(status = CONFLICT, description = "The user enforces unique names and another code has the previous name, or the code changed while rolling back. The latter includes the current copy", body = ApiErrorResponse)
+	),
+)]
+pub async fn restore_code_revision(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    Path((id, revision)): Path<(String, i64)>,
 ) -> Result<JSON<Code>, ApiError> {
-    Ok(JSON(
-        Code::get(&state.db, id, user.id)
-            .await?
-            .ok_or(ApiError::NotFound)?
-            .edit()
-            .pool(&state.db)
-            .maybe_content(payload.content)
-            .maybe_display_name(payload.display_name)
-            .maybe_website_url(payload.website_url)
-            .call()
-            .await?
-            .clone(),
-    ))
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+
+    let mut code = Code::get(&state.db, id.clone(), user.id.clone())
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let previous = revisions::get(&state.db, &code.id, revision)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    ensure_unique_name(
+        &state.db,
+        &user,
+        code.folder_id.as_deref(),
+        &previous.display_name,
+        Some(&code.id),
+    )
+    .await?;
+
+    let params = previous.totp_params();
+    // Setting the website resets the icon, so it is only set if it differs
+    let website_url = (previous.website_url != code.website_url).then_some(previous.website_url);
+    let expected_revision = code.revision;
+    if let Err(err) = code
+        .edit()
+        .pool(&state.db)
+        .content(previous.content)
+        .display_name(previous.display_name)
+        .maybe_website_url(website_url)
+        .params(params)
+        .expected_revision(expected_revision)
+        .call()
+        .await
+    {
+        return Err(change_failed(&state, err, id, user.id).await);
+    }
+    state
+        .events
+        .publish(Event::code(EventKind::CodeUpdated, &code, client_id));
+
+    Ok(JSON(code))
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct CodeDeleteQuery {
+    /// Only delete the code if it is still at this revision. Takes precedence over `If-Match`.
+    pub expected_revision: Option<i64>,
+}
+
+impl Validate for CodeDeleteQuery {
+    fn validate(&mut self) -> Result<(), ApiError> {
+        Ok(())
+    }
 }
 
 #[utoipa::path(
@@ -116,33 +1254,560 @@ pub async fn edit_code(
 	path = "/v1/code/{id}",
 	tag = "codes",
 	responses(
-		(status = NO_CONTENT, description = "Deleted")
+		(status = NO_CONTENT, description = "Deleted"),
+		(status = BAD_REQUEST, description = "Malformed `If-Match` header"),
+		(status = CONFLICT, description = "The code is no longer at the expected revision. Includes the current copy", body = ApiErrorResponse)
 	),
 	params(
-		("id", description = "Id of code to delete")
+		("id", description = "Id of code to delete"),
+		CodeDeleteQuery
 	)
 )]
 pub async fn delete_code(
     State(state): State<Arc<AppState>>,
     Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
     Path(id): Path<String>,
+    ValidatedQuery(query): ValidatedQuery<CodeDeleteQuery>,
+    headers: HeaderMap,
 ) -> Result<StatusCode, ApiError> {
-    Code::get(&state.db, id, user.id)
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+    let expected_revision = expected_revision(&headers, query.expected_revision)?;
+    let code = Code::get(&state.db, id.clone(), user.id.clone())
         .await?
-        .ok_or(ApiError::NotFound)?
-        .delete(&state.db)
-        .await?;
+        .ok_or(ApiError::NotFound)?;
+    if let Err(err) = code.delete(&state.db, expected_revision).await {
+        return Err(change_failed(&state, err, id, user.id).await);
+    }
+    state
+        .events
+        .publish(Event::code(EventKind::CodeDeleted, &code, client_id));
 
     Ok(StatusCode::NO_CONTENT)
 }
 
+/// Codes deleted at or before this have left the trash, see [`crate::ServerOptions::trash_retention`].
+fn trash_cutoff(state: &AppState) -> i64 {
+    if state.settings.trash_retention.is_zero() {
+        return 0;
+    }
+    chrono::Utc::now().timestamp() - state.settings.trash_retention.as_secs() as i64
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TrashedCode {
+    #[serde(flatten)]
+    pub code: Code,
+    /// Unix timestamp (seconds) at which the code was deleted.
+    pub deleted_at: i64,
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/codes/trash",
+	responses(
+		(status = OK, description = "Deleted codes which can still be restored, most recently deleted first. The content is left out for `read:metadata` tokens", body = Vec<TrashedCode>)
+	),
+	tag = "codes",
+)]
+pub async fn list_trash(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+) -> Result<JSON<serde_json::Value>, ApiError> {
+    let codes = Code::get_trash(&state.db, user.id, trash_cutoff(&state)).await?;
+    let trashed = codes
+        .into_iter()
+        .map(|code| TrashedCode {
+            deleted_at: code.deleted_at.unwrap_or_default(),
+            code,
+        })
+        .collect();
+
+    Ok(JSON(serialize_codes(trashed, &scopes)))
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/code/{id}/restore",
+	tag = "codes",
+	params(
+		("id", description = "Id of the deleted code to restore")
+	),
+	responses(
+		(status = OK, description = "Restored the code, appending it to the listing. Response contains the code", body = Code),
+		(status = FORBIDDEN, description = "Restoring the code would take the user over the limit of codes or icon bytes of the instance"),
+		(status = NOT_FOUND, description = "The code isn't in the trash"),
+		(status = CONFLICT, description = "The user enforces unique names, and another code has been given the name since")
+	),
+)]
+pub async fn restore_code(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    Path(id): Path<String>,
+) -> Result<JSON<Code>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+
+    let mut code = Code::get_trashed(&state.db, id, user.id.clone(), trash_cutoff(&state))
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    // Deleting a folder moves its codes to the top level, trashed ones included
+    ensure_unique_name(
+        &state.db,
+        &user,
+        code.folder_id.as_deref(),
+        &code.display_name,
+        Some(&code.id),
+    )
+    .await?;
+    code.sort_index = Code::next_sort_index(&state.db, &user.id).await?;
+
+    let mut batch = CodeBatch::begin(&state.db).await?;
+    Quota::new(&state.settings)
+        .ensure_room(
+            batch.conn(),
+            &user.id,
+            1,
+            quota::icon_bytes(code.icon_url.as_deref()),
+        )
+        .await?;
+    batch.restore(&mut code).await?;
+    batch.commit().await?;
+    state
+        .events
+        .publish(Event::code(EventKind::CodeCreated, &code, client_id));
+
+    Ok(JSON(code))
+}
+
+/// Most operations accepted by a single batch, to keep the transaction short.
+const MAX_BATCH_OPERATIONS: usize = 100;
+
+#[derive(Deserialize, ToSchema)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum CodeBatchOperation {
+    Create(CodeAddPayload),
+    Update {
+        id: String,
+        changes: CodeEditPayload,
+    },
+    Delete {
+        id: String,
+        /// Only delete the code if it is still at this revision.
+        expected_revision: Option<i64>,
+    },
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CodeBatchPayload {
+    /// Applied in order, so later operations see the changes of earlier ones.
+    pub operations: Vec<CodeBatchOperation>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CodeBatchResult {
+    /// Status code the operation would have responded with on its own endpoint.
+    pub status: u16,
+    /// The created or updated code.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<Code>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ApiErrorResponse>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CodeBatchResponse {
+    /// Whether the changes were applied. A single failing operation rolls back the entire batch.
+    pub committed: bool,
+    /// Result of every operation, in the order of the request.
+    pub results: Vec<CodeBatchResult>,
+}
+
+/// Rejects changes to the code if it is no longer at the expected revision. Only race free within
+/// a transaction, like that of a batch.
+fn ensure_revision(code: &Code, expected_revision: Option<i64>) -> Result<(), ApiError> {
+    match expected_revision {
+        Some(revision) if revision != code.revision => {
+            Err(ApiError::RevisionConflict(Box::new(code.clone())))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Applies a single operation of a batch, returning its status and the affected code.
+async fn apply_operation(
+    batch: &mut CodeBatch,
+    user: &User,
+    quota: &Quota,
+    sort_index: &mut i64,
+    operation: CodeBatchOperation,
+) -> Result<(StatusCode, EventKind, Code), ApiError> {
+    match operation {
+        CodeBatchOperation::Create(mut payload) => {
+            validate_expiry(payload.expires_at)?;
+            let tags = normalize_tags(std::mem::take(&mut payload.tags))?;
+            let mut code = payload.into_code(user.id.clone())?;
+            validate_lengths(&code)?;
+            e2e::require_encrypted(user, &code.content)?;
+            ensure_folder(batch.conn(), user, code.folder_id.as_deref()).await?;
+            ensure_unique_name(
+                batch.conn(),
+                user,
+                code.folder_id.as_deref(),
+                &code.display_name,
+                None,
+            )
+            .await?;
+
+            quota.ensure_room(batch.conn(), &user.id, 1, 0).await?;
+            code.sort_index = *sort_index;
+            batch.insert(&mut code).await?;
+            batch.set_tags(&code, &tags).await?;
+            *sort_index += 1;
+
+            Ok((StatusCode::OK, EventKind::CodeCreated, code))
+        }
+        CodeBatchOperation::Update { id, mut changes } => {
+            validate_expiry(changes.expires_at.flatten())?;
+            validate_edit_lengths(&changes)?;
+            if let Some(content) = &changes.content {
+                e2e::require_encrypted(user, content)?;
+            }
+            let tags = changes.tags.take().map(normalize_tags).transpose()?;
+
+            let mut code = Code::get(batch.conn(), id, user.id.clone())
+                .await?
+                .ok_or(ApiError::NotFound)?;
+            ensure_revision(&code, changes.expected_revision)?;
+            check_placement(batch.conn(), user, &code, &changes).await?;
+            let params = changes.params.resolve_for(&code)?;
+            batch
+                .edit()
+                .code(&mut code)
+                .maybe_content(changes.content)
+                .maybe_display_name(changes.display_name)
+                .maybe_website_url(changes.website_url)
+                .maybe_params(params)
+                .maybe_expires_at(changes.expires_at)
+                .maybe_favorite(changes.favorite)
+                .maybe_folder_id(changes.folder_id)
+                .maybe_tags(tags)
+                .call()
+                .await?;
+
+            Ok((StatusCode::OK, EventKind::CodeUpdated, code))
+        }
+        CodeBatchOperation::Delete {
+            id,
+            expected_revision,
+        } => {
+            let code = Code::get(batch.conn(), id, user.id.clone())
+                .await?
+                .ok_or(ApiError::NotFound)?;
+            ensure_revision(&code, expected_revision)?;
+            batch.delete(&code, None).await?;
+
+            Ok((StatusCode::NO_CONTENT, EventKind::CodeDeleted, code))
+        }
+    }
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/codes/batch",
+	request_body = CodeBatchPayload,
+	responses(
+		(status = OK, description = "Every operation succeeded, and the batch was applied", body = CodeBatchResponse),
+		(status = UNPROCESSABLE_ENTITY, description = "An operation failed, so nothing was applied. The results tell which", body = CodeBatchResponse),
+		(status = BAD_REQUEST, description = "Too many operations")
+	),
+	tag = "codes",
+)]
+pub async fn batch_codes(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    JSON(payload): JSON<CodeBatchPayload>,
+) -> Result<(StatusCode, JSON<CodeBatchResponse>), ApiError> {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+    if payload.operations.len() > MAX_BATCH_OPERATIONS {
+        return Err(ApiError::BadRequest(format!(
+            "At most {MAX_BATCH_OPERATIONS} operations can be sent at once."
+        )));
+    }
+
+    let quota = Quota::new(&state.settings);
+    let mut batch = CodeBatch::begin(&state.db).await?;
+    let mut sort_index = Code::next_sort_index(batch.conn(), &user.id).await?;
+    let mut results = vec![];
+    let mut events = vec![];
+
+    // Every operation is attempted even after one failed, so clients learn about all problems
+    for operation in payload.operations {
+        match apply_operation(&mut batch, &user, &quota, &mut sort_index, operation).await {
+            Ok((status, kind, code)) => {
+                events.push(Event::code(kind, &code, client_id.clone()));
+                results.push(CodeBatchResult {
+                    status: status.as_u16(),
+                    code: (kind != EventKind::CodeDeleted).then_some(code),
+                    error: None,
+                });
+            }
+            Err(err @ ApiError::DatabaseError(_)) => return Err(err),
+            Err(err) => {
+                let (status, error) = err.parts();
+                results.push(CodeBatchResult {
+                    status: status.as_u16(),
+                    code: None,
+                    error: Some(error),
+                });
+            }
+        }
+    }
+
+    let committed = results.iter().all(|result| result.error.is_none());
+    if !committed {
+        // Dropping the transaction rolls it back
+        drop(batch);
+        return Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            JSON(CodeBatchResponse { committed, results }),
+        ));
+    }
+
+    batch.commit().await?;
+    for event in events {
+        state.events.publish(event);
+    }
+
+    Ok((
+        StatusCode::OK,
+        JSON(CodeBatchResponse { committed, results }),
+    ))
+}
+
+/// Most codes deleted by a single bulk deletion, to keep the transaction short.
+const MAX_BULK_DELETIONS: usize = 500;
+
+#[derive(Serialize, ToSchema)]
+pub struct CodeBulkDeleteResult {
+    pub id: String,
+    /// Status code deleting the code on its own would have responded with.
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ApiErrorResponse>,
+}
+
+#[utoipa::path(
+	method(delete),
+	path = "/v1/codes",
+	request_body(content = Vec<String>, description = "Ids of the codes to delete"),
+	responses(
+		(status = OK, description = "Deleted every code which could be found, in a single transaction. Contains the result of every id, in the order of the request", body = Vec<CodeBulkDeleteResult>),
+		(status = BAD_REQUEST, description = "Too many ids")
+	),
+	tag = "codes",
+)]
+pub async fn bulk_delete_codes(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    JSON(ids): JSON<Vec<String>>,
+) -> Result<JSON<Vec<CodeBulkDeleteResult>>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+    if ids.len() > MAX_BULK_DELETIONS {
+        return Err(ApiError::BadRequest(format!(
+            "At most {MAX_BULK_DELETIONS} codes can be deleted at once."
+        )));
+    }
+
+    let mut batch = CodeBatch::begin(&state.db).await?;
+    let mut results = vec![];
+    let mut events = vec![];
+
+    // Unlike a batch, codes which can't be deleted don't hold back the others
+    for id in ids {
+        // Listing a code twice finds it deleted the second time
+        let Some(code) = Code::get(batch.conn(), id.clone(), user.id.clone()).await? else {
+            let (status, error) = ApiError::NotFound.parts();
+            results.push(CodeBulkDeleteResult {
+                id,
+                status: status.as_u16(),
+                error: Some(error),
+            });
+            continue;
+        };
+
+        batch.delete(&code, None).await?;
+        events.push(Event::code(
+            EventKind::CodeDeleted,
+            &code,
+            client_id.clone(),
+        ));
+        results.push(CodeBulkDeleteResult {
+            id,
+            status: StatusCode::NO_CONTENT.as_u16(),
+            error: None,
+        });
+    }
+
+    batch.commit().await?;
+    for event in events {
+        state.events.publish(event);
+    }
+
+    Ok(JSON(results))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CodeOrderPayload {
+    /// Ids of codes in their new order. Listing only some codes rearranges them among the
+    /// positions they already take, leaving every other code in place.
+    pub ids: Vec<String>,
+}
+
+#[utoipa::path(
+	method(patch),
+	path = "/v1/codes/order",
+	tag = "codes",
+	request_body = CodeOrderPayload,
+	responses(
+		(status = OK, description = "Successfully reordered the codes. Response contains the new listing", body = Vec<Code>),
+		(status = BAD_REQUEST, description = "A code is listed twice, or doesn't exist")
+	),
+)]
+pub async fn reorder_codes(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    JSON(payload): JSON<CodeOrderPayload>,
+) -> Result<JSON<Vec<Code>>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+
+    let mut batch = CodeBatch::begin(&state.db).await?;
+    let mut codes: Vec<Code> = vec![];
+    for id in payload.ids {
+        if codes.iter().any(|code| code.id == id) {
+            return Err(ApiError::BadRequest(format!(
+                "The code {id} is listed more than once."
+            )));
+        }
+        let code = Code::get(batch.conn(), id.clone(), user.id.clone())
+            .await?
+            .ok_or_else(|| ApiError::BadRequest(format!("The code {id} doesn't exist.")))?;
+        codes.push(code);
+    }
+
+    let mut positions: Vec<i64> = codes.iter().map(|code| code.sort_index).collect();
+    positions.sort_unstable();
+
+    // Only codes which actually moved change, so syncing clients don't refetch the others
+    let mut moved = vec![];
+    for (mut code, sort_index) in codes.into_iter().zip(positions) {
+        if code.sort_index != sort_index {
+            batch
+                .edit()
+                .code(&mut code)
+                .sort_index(sort_index)
+                .call()
+                .await?;
+            moved.push(code);
+        }
+    }
+    batch.commit().await?;
+
+    for code in &moved {
+        state
+            .events
+            .publish(Event::code(EventKind::CodeUpdated, code, client_id.clone()));
+    }
+
+    Ok(JSON(Code::get_many(&state.db, user.id).await?))
+}
+
+async fn move_code(
+    state: &AppState,
+    user: User,
+    scopes: TokenScopes,
+    client_id: ClientId,
+    id: String,
+    direction: Move,
+) -> Result<JSON<Vec<Code>>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+    let mut code = Code::get(&state.db, id, user.id.clone())
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    // Both codes moved, so clients are told about both, like when reordering codes
+    if let Some(neighbour) = code.shift(&state.db, direction).await? {
+        for code in [&code, &neighbour] {
+            state
+                .events
+                .publish(Event::code(EventKind::CodeUpdated, code, client_id.clone()));
+        }
+    }
+
+    Ok(JSON(Code::get_many(&state.db, user.id).await?))
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/code/{id}/move-up",
+	tag = "codes",
+	responses(
+		(status = OK, description = "Swapped the code with the one before it. Nothing changes for the first code. Response contains the new listing", body = Vec<Code>),
+		(status = NOT_FOUND, description = "Unable to find code")
+	),
+	params(
+		("id", description = "Id of the code to move")
+	)
+)]
+pub async fn move_code_up(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    Path(id): Path<String>,
+) -> Result<JSON<Vec<Code>>, ApiError> {
+    move_code(&state, user, scopes, client_id, id, Move::Up).await
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/code/{id}/move-down",
+	tag = "codes",
+	responses(
+		(status = OK, description = "Swapped the code with the one after it. Nothing changes for the last code. Response contains the new listing", body = Vec<Code>),
+		(status = NOT_FOUND, description = "Unable to find code")
+	),
+	params(
+		("id", description = "Id of the code to move")
+	)
+)]
+pub async fn move_code_down(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    Path(id): Path<String>,
+) -> Result<JSON<Vec<Code>>, ApiError> {
+    move_code(&state, user, scopes, client_id, id, Move::Down).await
+}
+
 #[utoipa::path(
 	get,
 	path = "/v1/code/{id}/icon",
 	tag = "codes",
 	responses(
-		(status = OK, description = "Icon found"),
-		(status = NOT_FOUND, description = "Unable to find icon")
+		(status = OK, description = "The icon of the code, or else the favicon of its website, as linked from its page or at /favicon.ico"),
+		(status = NOT_MODIFIED, description = "The icon matches the ETag in If-None-Match"),
+		(status = NO_CONTENT, description = "The code has neither icon nor website, or its icon can't be fetched"),
+		(status = NOT_FOUND, description = "Unable to find code")
 	),
 	params(
 		("id", description = "Id of code to fetch icon for")
@@ -151,22 +1816,102 @@ pub async fn delete_code(
 pub async fn get_code_icon(
     State(state): State<Arc<AppState>>,
     Extension(user): Extension<User>,
+    client_id: ClientId,
     Path(id): Path<String>,
-) -> Result<(HeaderMap, Vec<u8>), ApiError> {
-    let code = Code::get(&state.db, id, user.id)
+    request_headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let mut code = Code::get(&state.db, id, user.id)
         .await?
         .ok_or(ApiError::NotFound)?;
 
+    let mut icon = Icon::of_code(&state.db, &code.id).await?;
+    // Icons of codes changed moments ago may not be stored yet
+    if icon.is_none() {
+        let icon_url = code.icon_url.clone();
+        let linked = icons::populate_icon(&state.db, &state.icon_store, &mut code)
+            .await
+            .map_err(|err| match err {
+                IconStoreError::Database(err) => err.into(),
+                _ => ApiError::NoIcon,
+            })?;
+        if code.icon_url != icon_url {
+            state
+                .events
+                .publish(Event::code(EventKind::CodeUpdated, &code, client_id));
+        }
+        if linked {
+            icon = Icon::of_code(&state.db, &code.id).await?;
+        }
+    }
+    let icon = icon.ok_or(ApiError::NoIcon)?;
+
+    // Icons are served with the hash of their bytes as ETag
+    let etag = format!("\"{}\"", icon.hash);
+    let headers = (ICON_HEADERS, [(header::ETAG, etag.clone())]);
+    if utils::etag_matches(&request_headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    let bytes = state
+        .icon_store
+        .get(&icon.hash)
+        .await
+        .ok_or(ApiError::NoIcon)?;
+    Ok((headers, [(header::CONTENT_TYPE, icon.content_type)], bytes).into_response())
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/codes/recovery-sheet",
+	tag = "codes",
+	responses(
+		(status = OK, description = "Printable HTML page with the secret and QR code of every code", body = String, content_type = "text/html"),
+		(status = UNAUTHORIZED, description = "Not logged in within the last five minutes"),
+		(status = FORBIDDEN, description = "Authenticated with a token which can't read secrets"),
+		(status = CONFLICT, description = "The user has end-to-end encryption, so the secrets can't be read")
+	),
+)]
+pub async fn recovery_sheet(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    issued_at: Option<Extension<IssuedAt>>,
+) -> Result<(HeaderMap, Html<String>), ApiError> {
+    if !scopes.allows(TokenScope::CodesRead) {
+        return Err(ApiError::InsufficientScope);
+    }
+    e2e::require_readable(&user)?;
+    auth::require_recent_login(issued_at.map(|Extension(iat)| iat))?;
+
+    let mut entries = String::new();
+    for code in Code::get_many(&state.db, user.id).await? {
+        let uri = otpauth::to_uri(&code);
+        // Codes with long names and secrets may not fit, rather than failing the whole sheet
+        let qr = otpauth::to_qr_svg(&uri).unwrap_or_else(|_| {
+            "<p><em>Too long for a QR code, enter the secret below instead.</em></p>".into()
+        });
+        entries += &format!(
+            "<section><h2>{}</h2>{}<p><code>{}</code></p><p><small>{}</small></p></section>\n",
+            utils::escape_html(&code.display_name),
+            qr,
+            utils::escape_html(&code.content),
+            utils::escape_html(&uri),
+        );
+    }
+
     let mut headers = HeaderMap::default();
-    headers.append(header::CONTENT_TYPE, "image/x-icon".parse().unwrap());
+    headers.append(header::CACHE_CONTROL, "no-store".parse().unwrap());
 
     Ok((
         headers,
-        state
-            .icon_store
-            .find_or_gather(code.website_url.ok_or(ApiError::NoIcon)?.as_str())
-            .await
-            .map_err(|_| ApiError::NoIcon)?,
+        Html(format!(
+            "<!DOCTYPE html>\n<html lang=\"en\">\n<head><meta charset=\"utf-8\"><title>Iceblink recovery sheet for {}</title>\
+            <style>section {{ break-inside: avoid; margin-bottom: 2em; }}</style></head>\n\
+            <body><h1>Iceblink recovery sheet</h1>\
+            <p>Keep this page somewhere safe. Anyone with access to it can generate your codes.</p>\n{}</body>\n</html>",
+            utils::escape_html(&user.username),
+            entries
+        )),
     ))
 }
 
@@ -0,0 +1,332 @@
+use super::{ApiError, JSON};
+use crate::{
+    auth,
+    models::{
+        devices::Device,
+        push_subscriptions::PushSubscription,
+        sessions::Session,
+        tokens::{TokenScope, TokenScopes},
+        user::User,
+    },
+    ssrf, utils, webpush, AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+use utoipa::ToSchema;
+
+fn validate_label(field: &str, value: &str, max_len: usize) -> Result<(), ApiError> {
+    if value.trim().is_empty() || value.len() > max_len {
+        return Err(ApiError::BadRequest(format!(
+            "The {field} of a device must be between 1 and {max_len} characters."
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct DeviceResponse {
+    #[serde(flatten)]
+    pub device: Device,
+    /// Whether the request was authenticated by a session of this device.
+    pub current: bool,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct DeviceRegisterPayload {
+    /// Id the device was registered with before, like before logging out, to register as the same
+    /// device again. A new device is registered if it was revoked in the meantime.
+    pub id: Option<String>,
+    pub name: String,
+    /// Operating system or environment of the app, e.g. `ios`, `android` or `web`.
+    pub platform: String,
+    pub app_version: String,
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/devices",
+	tag = "devices",
+	request_body = DeviceRegisterPayload,
+	responses(
+		(status = CREATED, description = "Registered a new device, and linked the session to it", body = DeviceResponse),
+		(status = OK, description = "The session or id belongs to a device already, which was updated and linked to the session", body = DeviceResponse),
+		(status = BAD_REQUEST, description = "Invalid name, platform or app version, or authenticated with an API token instead of a session")
+	),
+)]
+pub async fn register_device(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    session: Option<Extension<Session>>,
+    JSON(payload): JSON<DeviceRegisterPayload>,
+) -> Result<(StatusCode, JSON<DeviceResponse>), ApiError> {
+    let Some(Extension(mut session)) = session else {
+        return Err(ApiError::BadRequest(
+            "Only sessions can register as a device, API tokens can't.".into(),
+        ));
+    };
+    validate_label("name", &payload.name, Device::MAX_NAME_LENGTH)?;
+    validate_label("platform", &payload.platform, Device::MAX_LABEL_LENGTH)?;
+    validate_label(
+        "app version",
+        &payload.app_version,
+        Device::MAX_LABEL_LENGTH,
+    )?;
+
+    let existing = match session.device_id.as_deref().or(payload.id.as_deref()) {
+        Some(id) => Device::get(&state.db, id, &user.id).await?,
+        None => None,
+    };
+    let (status, device) = match existing {
+        Some(mut device) => {
+            device.name = payload.name;
+            device.platform = payload.platform;
+            device.app_version = payload.app_version;
+            device.update(&state.db).await?;
+            (StatusCode::OK, device)
+        }
+        None => {
+            let device = Device {
+                id: utils::generate_id(16),
+                user_id: user.id,
+                name: payload.name,
+                platform: payload.platform,
+                app_version: payload.app_version,
+                created_at: chrono::Utc::now().timestamp(),
+                last_seen_at: Some(session.last_activity),
+                last_push_at: None,
+            };
+            device.insert(&state.db).await?;
+            (StatusCode::CREATED, device)
+        }
+    };
+    if session.device_id.as_ref() != Some(&device.id) {
+        session.set_device(&state.db, &device.id).await?;
+    }
+
+    Ok((
+        status,
+        JSON(DeviceResponse {
+            device,
+            current: true,
+        }),
+    ))
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/devices",
+	tag = "devices",
+	responses(
+		(status = OK, description = "Registered devices of the user, most recently seen first", body = Vec<DeviceResponse>)
+	),
+)]
+pub async fn list_devices(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    current: Option<Extension<Session>>,
+) -> Result<JSON<Vec<DeviceResponse>>, ApiError> {
+    let current_id = current.and_then(|Extension(session)| session.device_id);
+    let devices = Device::get_for_user(&state.db, &user.id).await?;
+
+    Ok(JSON(
+        devices
+            .into_iter()
+            .map(|device| DeviceResponse {
+                current: current_id.as_ref() == Some(&device.id),
+                device,
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct DeviceRenamePayload {
+    pub name: String,
+}
+
+#[utoipa::path(
+	patch,
+	path = "/v1/devices/{id}",
+	tag = "devices",
+	params(
+		("id", description = "Id of the device to rename")
+	),
+	request_body = DeviceRenamePayload,
+	responses(
+		(status = OK, description = "Renamed the device", body = Device),
+		(status = BAD_REQUEST, description = "Invalid name"),
+		(status = NOT_FOUND, description = "Unable to find device")
+	),
+)]
+pub async fn rename_device(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    Path(id): Path<String>,
+    JSON(payload): JSON<DeviceRenamePayload>,
+) -> Result<JSON<Device>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+    validate_label("name", &payload.name, Device::MAX_NAME_LENGTH)?;
+
+    let mut device = Device::get(&state.db, &id, &user.id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    device.name = payload.name;
+    device.update(&state.db).await?;
+
+    Ok(JSON(device))
+}
+
+#[utoipa::path(
+	delete,
+	path = "/v1/devices/{id}",
+	tag = "devices",
+	params(
+		("id", description = "Id of the device to revoke")
+	),
+	responses(
+		(status = NO_CONTENT, description = "Revoked the device. Its sessions are rejected from now on"),
+		(status = NOT_FOUND, description = "Unable to find device")
+	),
+)]
+pub async fn revoke_device(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+
+    let device = Device::get(&state.db, &id, &user.id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    device.delete(&state.db).await?;
+    info!("{} revoked their device {}", user.id, device.id);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Longest push endpoint accepted. Those of browsers are well below it.
+const MAX_PUSH_ENDPOINT_LENGTH: usize = 1024;
+
+/// Keys of a push subscription, base64url encoded like `PushSubscription.toJSON()` in browsers.
+#[derive(Deserialize, ToSchema)]
+pub struct PushSubscriptionKeys {
+    /// Uncompressed P-256 public key of the device.
+    pub p256dh: String,
+    pub auth: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct PushSubscriptionPayload {
+    /// URL of the push service to send pushes for the device to.
+    pub endpoint: String,
+    pub keys: PushSubscriptionKeys,
+}
+
+/// Decodes a key of a push subscription, checking its length.
+fn decode_push_key(name: &str, key: &str, len: usize) -> Result<String, ApiError> {
+    // Some clients pad their keys, pushing doesn't
+    let key = key.trim_end_matches('=');
+    match URL_SAFE_NO_PAD.decode(key) {
+        Ok(decoded) if decoded.len() == len => Ok(key.to_string()),
+        _ => Err(ApiError::BadRequest(format!(
+            "The {name} key has to be {len} bytes, base64url encoded."
+        ))),
+    }
+}
+
+#[utoipa::path(
+	put,
+	path = "/v1/devices/{id}/push",
+	tag = "devices",
+	params(
+		("id", description = "Id of the device to send pushes to")
+	),
+	request_body = PushSubscriptionPayload,
+	responses(
+		(status = NO_CONTENT, description = "Subscribed the device, replacing its previous subscription. It's pinged whenever another device changes codes, folders or settings"),
+		(status = BAD_REQUEST, description = "The endpoint isn't a public https URL, or the keys are invalid"),
+		(status = NOT_FOUND, description = "Unable to find device"),
+		(status = NOT_IMPLEMENTED, description = "The instance has no VAPID key to send pushes with")
+	),
+)]
+pub async fn subscribe_push(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    Path(id): Path<String>,
+    JSON(payload): JSON<PushSubscriptionPayload>,
+) -> Result<StatusCode, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+    if state.web_push.is_none() {
+        return Err(ApiError::PushUnavailable);
+    }
+
+    // Pushes are sent from the server, so they mustn't reach into its network
+    let public_https = Url::parse(&payload.endpoint).is_ok_and(|url| {
+        url.scheme() == "https"
+            && url
+                .host_str()
+                .is_some_and(|host| !ssrf::is_internal_host(host))
+    });
+    if !public_https || payload.endpoint.len() > MAX_PUSH_ENDPOINT_LENGTH {
+        return Err(ApiError::BadRequest(
+            "The push endpoint has to be a public https URL.".into(),
+        ));
+    }
+    let p256dh = decode_push_key("p256dh", &payload.keys.p256dh, webpush::PUBLIC_KEY_LENGTH)?;
+    let auth = decode_push_key("auth", &payload.keys.auth, webpush::AUTH_SECRET_LENGTH)?;
+
+    let device = Device::get(&state.db, &id, &user.id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    PushSubscription {
+        device_id: device.id,
+        user_id: user.id,
+        endpoint: payload.endpoint,
+        p256dh,
+        auth,
+        created_at: chrono::Utc::now().timestamp(),
+    }
+    .upsert(&state.db)
+    .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+	delete,
+	path = "/v1/devices/{id}/push",
+	tag = "devices",
+	params(
+		("id", description = "Id of the device to stop sending pushes to")
+	),
+	responses(
+		(status = NO_CONTENT, description = "The device isn't sent pushes anymore"),
+		(status = NOT_FOUND, description = "Unable to find device")
+	),
+)]
+pub async fn unsubscribe_push(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+
+    let device = Device::get(&state.db, &id, &user.id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    PushSubscription::delete_for_device(&state.db, &device.id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,817 @@
+use super::{
+    query::{Validate, ValidatedQuery},
+    tags::normalize_tags,
+    ApiError, JSON,
+};
+use crate::{
+    auth::{self, IssuedAt},
+    e2e,
+    events::{ClientId, Event, EventKind},
+    export::{Export, ExportFile},
+    interop::{self, aegis::AegisVault, twofas::TwoFasBackup, FolderNames},
+    models::{
+        codes::{Code, CodeBatch},
+        folders::Folder,
+        tags,
+        tokens::{TokenScope, TokenScopes},
+        user::User,
+    },
+    otpauth,
+    quota::{self, Quota},
+    utils, AppState,
+};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::{write::GzEncoder, Compression};
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, io::Write, sync::Arc};
+use tracing::warn;
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Deserialize, ToSchema)]
+pub struct ExportPayload {
+    /// Encrypts the export when set. Leave it out for a plaintext export.
+    pub passphrase: Option<String>,
+}
+
+fn validate_passphrase(passphrase: Option<&str>) -> Result<(), ApiError> {
+    match passphrase {
+        Some("") => Err(ApiError::BadRequest(
+            "The passphrase can't be empty.".into(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ExportQuery {
+    /// Respond with a gzipped file download (`iceblink-export.json.gz`) instead of plain JSON.
+    #[serde(default)]
+    pub gzip: bool,
+}
+
+impl Validate for ExportQuery {
+    fn validate(&mut self) -> Result<(), ApiError> {
+        Ok(())
+    }
+}
+
+fn gzip_attachment(file: &ExportFile) -> Response {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    serde_json::to_writer(&mut encoder, file).expect("Unable to serialize export");
+    encoder.flush().expect("Unable to compress export");
+    let compressed = encoder.finish().expect("Unable to compress export");
+
+    (
+        [
+            (header::CONTENT_TYPE, "application/gzip"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"iceblink-export.json.gz\"",
+            ),
+        ],
+        compressed,
+    )
+        .into_response()
+}
+
+/// Export of all codes of the user, encrypted when a passphrase is given.
+async fn export_file(
+    state: &AppState,
+    user: &User,
+    passphrase: Option<&str>,
+) -> Result<ExportFile, ApiError> {
+    let export = Export::new(
+        Code::get_many(&state.db, user.id.clone()).await?,
+        &Folder::get_many(&state.db, &user.id).await?,
+        &tags::usage(&state.db, &user.id).await?,
+    );
+    mail::notify_export(state, user, "Iceblink export", export.codes.len());
+
+    Ok(match passphrase {
+        Some(passphrase) => {
+            // Deriving the key takes a while, which would hold up other requests
+            let passphrase = passphrase.to_string();
+            let encrypted = tokio::task::spawn_blocking(move || export.encrypt(&passphrase))
+                .await
+                .expect("Unable to encrypt export");
+            ExportFile::Encrypted(encrypted)
+        }
+        None => ExportFile::Plain(export),
+    })
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/export",
+	tag = "export",
+	params(
+		ExportQuery,
+		("X-Step-Up-Token" = Option<String>, Header, description = "Step-up token from `POST /v1/user/step-up/finish`, required unless logged in recently")
+	),
+	request_body = ExportPayload,
+	responses(
+		(status = OK, description = "Export of all codes, encrypted when a passphrase was supplied. Gzipped when `gzip=true`", body = ExportFile),
+		(status = UNAUTHORIZED, description = "Neither logged in within the last five minutes, nor confirmed with a security key")
+	),
+)]
+pub async fn export_codes(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    issued_at: Option<Extension<IssuedAt>>,
+    ValidatedQuery(query): ValidatedQuery<ExportQuery>,
+    headers: HeaderMap,
+    JSON(payload): JSON<ExportPayload>,
+) -> Result<Response, ApiError> {
+    if !scopes.allows(TokenScope::CodesRead) {
+        return Err(ApiError::InsufficientScope);
+    }
+    validate_passphrase(payload.passphrase.as_deref())?;
+    auth::require_step_up(&state, &user, issued_at.map(|Extension(iat)| iat), &headers)?;
+
+    let file = export_file(&state, &user, payload.passphrase.as_deref()).await?;
+    Ok(match query.gzip {
+        true => gzip_attachment(&file),
+        false => JSON(file).into_response(),
+    })
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/export",
+	tag = "export",
+	params(
+		ExportQuery,
+		("X-Export-Password" = Option<String>, Header, description = "Encrypts the export with this passphrase when set"),
+		("X-Step-Up-Token" = Option<String>, Header, description = "Step-up token from `POST /v1/user/step-up/finish`, required unless logged in recently")
+	),
+	responses(
+		(status = OK, description = "Export of all codes, like `POST /v1/export`, for clients which can't send a body", body = ExportFile),
+		(status = BAD_REQUEST, description = "The passphrase is empty, or not valid UTF-8"),
+		(status = UNAUTHORIZED, description = "Neither logged in within the last five minutes, nor confirmed with a security key")
+	),
+)]
+pub async fn download_export(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    issued_at: Option<Extension<IssuedAt>>,
+    ValidatedQuery(query): ValidatedQuery<ExportQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    if !scopes.allows(TokenScope::CodesRead) {
+        return Err(ApiError::InsufficientScope);
+    }
+    let passphrase = export_password(&headers)?;
+    auth::require_step_up(&state, &user, issued_at.map(|Extension(iat)| iat), &headers)?;
+
+    let file = export_file(&state, &user, passphrase).await?;
+    Ok(match query.gzip {
+        true => gzip_attachment(&file),
+        false => JSON(file).into_response(),
+    })
+}
+
+/// Header carrying the password of downloaded exports, keeping it out of URLs and access logs.
+const EXPORT_PASSWORD_HEADER: &str = "X-Export-Password";
+
+fn export_password(headers: &HeaderMap) -> Result<Option<&str>, ApiError> {
+    let password = headers
+        .get(EXPORT_PASSWORD_HEADER)
+        .map(|value| value.to_str())
+        .transpose()
+        .map_err(|_| ApiError::BadRequest("The password has to be valid UTF-8.".into()))?;
+    validate_passphrase(password)?;
+    Ok(password)
+}
+
+fn attachment(filename: &str, file: String) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{filename}\""),
+            ),
+        ],
+        file,
+    )
+        .into_response()
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/export/aegis",
+	tag = "export",
+	params(
+		("X-Export-Password" = Option<String>, Header, description = "Encrypts the vault with this password when set"),
+		("X-Step-Up-Token" = Option<String>, Header, description = "Step-up token from `POST /v1/user/step-up/finish`, required unless logged in recently")
+	),
+	responses(
+		(status = OK, description = "Vault file (`iceblink-aegis.json`) of all codes, importable by Aegis. Folders become groups, and icons stored as PNG, JPEG or SVG `data:` URIs are included", content_type = "application/json"),
+		(status = BAD_REQUEST, description = "The password is empty, or not valid UTF-8"),
+		(status = UNAUTHORIZED, description = "Neither logged in within the last five minutes, nor confirmed with a security key"),
+		(status = CONFLICT, description = "The user has end-to-end encryption, so the secrets can't be read")
+	),
+)]
+pub async fn export_aegis(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    issued_at: Option<Extension<IssuedAt>>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    if !scopes.allows(TokenScope::CodesRead) {
+        return Err(ApiError::InsufficientScope);
+    }
+    e2e::require_readable(&user)?;
+    let password = export_password(&headers)?;
+    auth::require_step_up(&state, &user, issued_at.map(|Extension(iat)| iat), &headers)?;
+
+    let codes = Code::get_many(&state.db, user.id.clone()).await?;
+    mail::notify_export(&state, &user, "Aegis vault", codes.len());
+    let vault = interop::aegis::write(
+        codes,
+        Folder::get_many(&state.db, &user.id).await?,
+        password,
+    );
+    Ok(attachment("iceblink-aegis.json", vault))
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/export/2fas",
+	tag = "export",
+	params(
+		("X-Export-Password" = Option<String>, Header, description = "Encrypts the backup with this password when set"),
+		("X-Step-Up-Token" = Option<String>, Header, description = "Step-up token from `POST /v1/user/step-up/finish`, required unless logged in recently")
+	),
+	responses(
+		(status = OK, description = "Backup file (`iceblink.2fas`) of all codes, importable by 2FAS Auth. Folders become groups", content_type = "application/json"),
+		(status = BAD_REQUEST, description = "The password is empty, or not valid UTF-8"),
+		(status = UNAUTHORIZED, description = "Neither logged in within the last five minutes, nor confirmed with a security key"),
+		(status = CONFLICT, description = "The user has end-to-end encryption, so the secrets can't be read")
+	),
+)]
+pub async fn export_twofas(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    issued_at: Option<Extension<IssuedAt>>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    if !scopes.allows(TokenScope::CodesRead) {
+        return Err(ApiError::InsufficientScope);
+    }
+    e2e::require_readable(&user)?;
+    let password = export_password(&headers)?;
+    auth::require_step_up(&state, &user, issued_at.map(|Extension(iat)| iat), &headers)?;
+
+    let codes = Code::get_many(&state.db, user.id.clone()).await?;
+    mail::notify_export(&state, &user, "2FAS backup", codes.len());
+    let backup = interop::twofas::write(
+        codes,
+        Folder::get_many(&state.db, &user.id).await?,
+        password,
+    );
+    Ok(attachment("iceblink.2fas", backup))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ImportPayload {
+    /// Export of any format version. Older versions are migrated when importing.
+    #[schema(value_type = ExportFile)]
+    pub file: serde_json::Value,
+    /// Required when the file is encrypted.
+    pub passphrase: Option<String>,
+}
+
+impl ImportPayload {
+    /// Migrates and decrypts the file, away from the async runtime as deriving the key takes a while.
+    async fn open(self) -> Result<Export, ApiError> {
+        tokio::task::spawn_blocking(move || {
+            Ok(ExportFile::from_value(self.file)?.open(self.passphrase.as_deref())?)
+        })
+        .await
+        .expect("Unable to open export")
+    }
+}
+
+/// Turns the codes of an export into codes of the user, after their existing ones, paired with
+/// their tags. Folders missing for the user are created, and other clients are told about them.
+/// Fails without creating anything if the user lacks room for all of the codes.
+async fn prepare_import(
+    state: &AppState,
+    user: &User,
+    client_id: &ClientId,
+    export: Export,
+) -> Result<Vec<(Code, Vec<String>)>, ApiError> {
+    let icon_bytes = export
+        .codes
+        .iter()
+        .map(|code| quota::icon_bytes(code.icon_url.as_deref()))
+        .sum();
+    Quota::new(&state.settings)
+        .ensure_room(&state.db, &user.id, export.codes.len() as u64, icon_bytes)
+        .await?;
+    let first_index = Code::next_sort_index(&state.db, &user.id).await?;
+    let mut folders = FolderNames::load(&state.db, &user.id).await?;
+
+    let mut codes = vec![];
+    for (index, exported) in (first_index..).zip(export.codes) {
+        e2e::require_encrypted(user, &exported.content)?;
+        let tags = normalize_tags(exported.tags)?;
+        let code = Code {
+            id: utils::generate_id(16),
+            owner_id: user.id.clone(),
+            content: exported.content,
+            display_name: exported.display_name,
+            icon_url: exported.icon_url,
+            website_url: exported.website_url,
+            expires_at: exported.expires_at,
+            sort_index: index,
+            issuer: exported.issuer,
+            account: exported.account,
+            kind: exported.kind,
+            digits: exported.digits,
+            period: exported.period,
+            algorithm: exported.algorithm,
+            favorite: exported.favorite,
+            folder_id: folders.resolve(exported.folder.as_deref()),
+            created_at: 0,
+            updated_at: 0,
+            revision: 0,
+            deleted_at: None,
+        };
+        codes.push((code, tags));
+    }
+
+    for folder in folders.insert(&state.db).await? {
+        state.events.publish(Event::folder(
+            EventKind::FolderCreated,
+            &folder,
+            client_id.clone(),
+        ));
+    }
+    Ok(codes)
+}
+
+/// Inserts the codes with their tags in a single transaction.
+async fn insert_with_tags(
+    state: &AppState,
+    codes: &mut [(Code, Vec<String>)],
+) -> Result<(), sqlx::Error> {
+    let mut batch = CodeBatch::begin(&state.db).await?;
+    for (code, tags) in codes.iter_mut() {
+        batch.insert(code).await?;
+        batch.set_tags(code, tags).await?;
+    }
+    batch.commit().await
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/import",
+	tag = "export",
+	request_body = ImportPayload,
+	responses(
+		(status = OK, description = "Imported all codes of the export, with their tags. Folders are matched by name, and created when missing. Response contains the new codes", body = Vec<Code>),
+		(status = BAD_REQUEST, description = "The export is encrypted, but no passphrase was supplied, or it has invalid tags"),
+		(status = FORBIDDEN, description = "The user lacks room for the codes under the limits of the instance, so none were imported"),
+		(status = UNPROCESSABLE_ENTITY, description = "Unable to decrypt the export, it was made by a newer version of Iceblink, or the user has end-to-end encryption and it holds plaintext secrets")
+	),
+)]
+pub async fn import_codes(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    JSON(payload): JSON<ImportPayload>,
+) -> Result<JSON<Vec<Code>>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+    let export = payload.open().await?;
+
+    let mut codes = prepare_import(&state, &user, &client_id, export).await?;
+    insert_with_tags(&state, &mut codes).await?;
+
+    let codes: Vec<Code> = codes.into_iter().map(|(code, _)| code).collect();
+    for code in &codes {
+        state
+            .events
+            .publish(Event::code(EventKind::CodeCreated, code, client_id.clone()));
+    }
+
+    Ok(JSON(codes))
+}
+
+/// Codes committed per transaction when importing with progress.
+pub const IMPORT_BATCH_SIZE: usize = 50;
+
+/// A line of the progress stream.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ImportProgress {
+    pub processed: usize,
+    pub total: usize,
+    /// Set on the last line if the import failed. The batches processed before it stay imported.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ImportProgress {
+    fn line(&self) -> Result<Vec<u8>, Infallible> {
+        let mut line = serde_json::to_vec(self).expect("Unable to serialize import progress");
+        line.push(b'\n');
+        Ok(line)
+    }
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/codes/import/progress",
+	tag = "export",
+	request_body = ImportPayload,
+	responses(
+		(status = OK, description = "Newline delimited JSON, with a line after every batch of imported codes", body = ImportProgress, content_type = "application/x-ndjson"),
+		(status = BAD_REQUEST, description = "The export is encrypted, but no passphrase was supplied, or it has invalid tags"),
+		(status = FORBIDDEN, description = "The user lacks room for the codes under the limits of the instance, so none were imported"),
+		(status = UNPROCESSABLE_ENTITY, description = "Unable to decrypt the export, it was made by a newer version of Iceblink, or the user has end-to-end encryption and it holds plaintext secrets")
+	),
+)]
+pub async fn import_codes_progress(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    JSON(payload): JSON<ImportPayload>,
+) -> Result<Response, ApiError> {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+    let export = payload.open().await?;
+
+    let codes = prepare_import(&state, &user, &client_id, export).await?;
+    let total = codes.len();
+    let batches: Vec<Vec<(Code, Vec<String>)>> = codes
+        .chunks(IMPORT_BATCH_SIZE)
+        .map(<[(Code, Vec<String>)]>::to_vec)
+        .collect();
+
+    // The response body drives the import. When the client disconnects, the body is dropped, which
+    // stops the import and rolls back the batch in progress
+    let progress = stream::unfold(
+        (batches.into_iter(), 0, false),
+        move |(mut batches, processed, failed)| {
+            let state = state.clone();
+            let client_id = client_id.clone();
+
+            async move {
+                if failed {
+                    return None;
+                }
+                let mut batch = batches.next()?;
+
+                if let Err(err) = insert_with_tags(&state, &mut batch).await {
+                    warn!("Unable to import batch of codes: {err}");
+                    let line = ImportProgress {
+                        processed,
+                        total,
+                        error: Some("Unable to import the codes. Try again later.".into()),
+                    };
+                    return Some((line.line(), (batches, processed, true)));
+                }
+
+                for (code, _) in &batch {
+                    state.events.publish(Event::code(
+                        EventKind::CodeCreated,
+                        code,
+                        client_id.clone(),
+                    ));
+                }
+                let processed = processed + batch.len();
+                let line = ImportProgress {
+                    processed,
+                    total,
+                    error: None,
+                };
+                Some((line.line(), (batches, processed, false)))
+            }
+        },
+    );
+
+    let initial = ImportProgress {
+        processed: 0,
+        total,
+        error: None,
+    };
+    let body = Body::from_stream(stream::once(async move { initial.line() }).chain(progress));
+
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct OtpAuthImportError {
+    /// Line of the URI in the request, starting at 1. For JSON arrays, the position in the array.
+    pub line: usize,
+    /// Part of the URI which is invalid, e.g. `secret` or `digits`.
+    pub field: &'static str,
+    pub message: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct OtpAuthImportResponse {
+    pub imported: Vec<Code>,
+    /// URIs which couldn't be imported. Doesn't prevent the others from being imported.
+    pub errors: Vec<OtpAuthImportError>,
+}
+
+/// Splits the body into URIs, paired with their line number. Blank lines are skipped.
+fn otpauth_uris(headers: &HeaderMap, body: &str) -> Result<Vec<(usize, String)>, ApiError> {
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+
+    let uris = if is_json {
+        serde_json::from_str::<Vec<String>>(body)
+            .map_err(|_| ApiError::BadRequest("Expected a JSON array of URIs.".into()))?
+    } else {
+        body.lines().map(str::to_string).collect()
+    };
+
+    Ok((1..)
+        .zip(uris)
+        .filter(|(_, uri)| !uri.trim().is_empty())
+        .collect())
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/codes/import/otpauth",
+	tag = "export",
+	request_body(
+		description = "Newline delimited `otpauth://totp/` URIs, or a JSON array of them",
+		content(
+			(String = "text/plain"),
+			(Vec<String> = "application/json")
+		)
+	),
+	responses(
+		(status = OK, description = "Imported every valid URI, appending them to the listing", body = OtpAuthImportResponse),
+		(status = BAD_REQUEST, description = "The body is neither a JSON array, nor text"),
+		(status = FORBIDDEN, description = "The user lacks room for the codes under the limits of the instance, so none were imported"),
+		(status = UNPROCESSABLE_ENTITY, description = "The user has end-to-end encryption")
+	),
+)]
+pub async fn import_otpauth(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    headers: HeaderMap,
+    body: String,
+) -> Result<JSON<OtpAuthImportResponse>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+    e2e::reject_plaintext(&user)?;
+    let first_index = Code::next_sort_index(&state.db, &user.id).await?;
+
+    let mut imported = vec![];
+    let mut errors = vec![];
+    for (line, uri) in otpauth_uris(&headers, &body)? {
+        match otpauth::parse(&uri) {
+            Ok(parsed) => imported.push(Code {
+                id: utils::generate_id(16),
+                owner_id: user.id.clone(),
+                content: parsed.secret,
+                display_name: parsed.display_name,
+                icon_url: None,
+                website_url: parsed.issuer.clone(),
+                expires_at: None,
+                sort_index: first_index + imported.len() as i64,
+                issuer: parsed.issuer,
+                account: Some(parsed.account),
+                kind: parsed.params.kind.as_str().into(),
+                digits: parsed.params.digits as i64,
+                period: parsed.params.period,
+                algorithm: parsed.params.algorithm.as_str().into(),
+                favorite: false,
+                folder_id: None,
+                created_at: 0,
+                updated_at: 0,
+                revision: 0,
+                deleted_at: None,
+            }),
+            Err(err) => errors.push(OtpAuthImportError {
+                line,
+                field: err.field(),
+                message: err.to_string(),
+            }),
+        }
+    }
+
+    Quota::new(&state.settings)
+        .ensure_room(&state.db, &user.id, imported.len() as u64, 0)
+        .await?;
+    Code::insert_many(&state.db, &mut imported).await?;
+    for code in &imported {
+        state
+            .events
+            .publish(Event::code(EventKind::CodeCreated, code, client_id.clone()));
+    }
+
+    Ok(JSON(OtpAuthImportResponse { imported, errors }))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BackupImportResponse {
+    pub imported: Vec<Code>,
+    /// Folders created for the groups of the backup. Groups named like an existing top level
+    /// folder are put in that folder instead.
+    pub folders: Vec<Folder>,
+    /// Names of entries which aren't TOTP codes, or use unsupported parameters, and so weren't
+    /// imported.
+    pub skipped: Vec<String>,
+}
+
+/// Stores the codes of a backup from another app, and lets other clients know about them.
+async fn import_backup(
+    state: &AppState,
+    user: &User,
+    client_id: ClientId,
+    backup: interop::Backup,
+) -> Result<JSON<BackupImportResponse>, ApiError> {
+    let icon_bytes = backup
+        .codes
+        .iter()
+        .map(|code| quota::icon_bytes(code.icon_url.as_deref()))
+        .sum();
+    Quota::new(&state.settings)
+        .ensure_room(&state.db, &user.id, backup.codes.len() as u64, icon_bytes)
+        .await?;
+    let (imported, folders) = interop::store(&state.db, &user.id, backup.codes).await?;
+    for folder in &folders {
+        state.events.publish(Event::folder(
+            EventKind::FolderCreated,
+            folder,
+            client_id.clone(),
+        ));
+    }
+    for code in &imported {
+        state
+            .events
+            .publish(Event::code(EventKind::CodeCreated, code, client_id.clone()));
+    }
+
+    Ok(JSON(BackupImportResponse {
+        imported,
+        folders,
+        skipped: backup.skipped,
+    }))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct AegisImportPayload {
+    /// The exported vault file, plaintext or encrypted.
+    #[schema(value_type = Object)]
+    pub vault: AegisVault,
+    /// Required when the vault is encrypted.
+    pub password: Option<String>,
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/import/aegis",
+	tag = "export",
+	request_body = AegisImportPayload,
+	responses(
+		(status = OK, description = "Imported every TOTP entry of the vault, appending them to the listing. Groups become folders, and icons are kept as `data:` URIs in `icon_url`", body = BackupImportResponse),
+		(status = BAD_REQUEST, description = "The vault is encrypted, but no password was supplied"),
+		(status = FORBIDDEN, description = "The user lacks room for the codes under the limits of the instance, so none were imported"),
+		(status = UNPROCESSABLE_ENTITY, description = "Unable to decrypt the vault, or the user has end-to-end encryption")
+	),
+)]
+pub async fn import_aegis(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    JSON(payload): JSON<AegisImportPayload>,
+) -> Result<JSON<BackupImportResponse>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+    e2e::reject_plaintext(&user)?;
+    // Deriving keys from the password takes a while, so it's kept off the async workers
+    let AegisImportPayload { vault, password } = payload;
+    let backup =
+        tokio::task::spawn_blocking(move || interop::aegis::read(vault, password.as_deref()))
+            .await
+            .expect("Unable to read vault")?;
+    import_backup(&state, &user, client_id, backup).await
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct AndOtpImportPayload {
+    /// Base64 encoded backup file, plaintext (`.json`) or encrypted (`.json.aes`).
+    pub backup: String,
+    /// Required when the backup is encrypted.
+    pub password: Option<String>,
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/import/andotp",
+	tag = "export",
+	request_body = AndOtpImportPayload,
+	responses(
+		(status = OK, description = "Imported every TOTP entry of the backup, appending them to the listing", body = BackupImportResponse),
+		(status = BAD_REQUEST, description = "The backup is encrypted, but no password was supplied, or it isn't base64 encoded"),
+		(status = FORBIDDEN, description = "The user lacks room for the codes under the limits of the instance, so none were imported"),
+		(status = UNPROCESSABLE_ENTITY, description = "Unable to decrypt the backup, or the user has end-to-end encryption")
+	),
+)]
+pub async fn import_andotp(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    JSON(payload): JSON<AndOtpImportPayload>,
+) -> Result<JSON<BackupImportResponse>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+    e2e::reject_plaintext(&user)?;
+    let data = STANDARD
+        .decode(payload.backup.trim())
+        .map_err(|_| ApiError::BadRequest("The backup has to be base64 encoded.".into()))?;
+    let backup = interop::andotp::read(&data, payload.password.as_deref())?;
+    import_backup(&state, &user, client_id, backup).await
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TwoFasImportPayload {
+    /// The `.2fas` backup file, plaintext or encrypted.
+    #[schema(value_type = Object)]
+    pub backup: TwoFasBackup,
+    /// Required when the backup is encrypted.
+    pub password: Option<String>,
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/import/2fas",
+	tag = "export",
+	request_body = TwoFasImportPayload,
+	responses(
+		(status = OK, description = "Imported every TOTP service of the backup, appending them to the listing in the order of 2FAS. Groups become folders", body = BackupImportResponse),
+		(status = BAD_REQUEST, description = "The backup is encrypted, but no password was supplied"),
+		(status = FORBIDDEN, description = "The user lacks room for the codes under the limits of the instance, so none were imported"),
+		(status = UNPROCESSABLE_ENTITY, description = "Unable to decrypt the backup, or the user has end-to-end encryption")
+	),
+)]
+pub async fn import_twofas(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    JSON(payload): JSON<TwoFasImportPayload>,
+) -> Result<JSON<BackupImportResponse>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+    e2e::reject_plaintext(&user)?;
+    let backup = interop::twofas::read(payload.backup, payload.password.as_deref())?;
+    import_backup(&state, &user, client_id, backup).await
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/import/google-authenticator",
+	tag = "export",
+	request_body(
+		description = "Newline delimited `otpauth-migration://` URIs from the QR codes of Google Authenticator's \"Transfer accounts\", or a JSON array of them",
+		content(
+			(String = "text/plain"),
+			(Vec<String> = "application/json")
+		)
+	),
+	responses(
+		(status = OK, description = "Imported every TOTP account, appending them to the listing", body = BackupImportResponse),
+		(status = BAD_REQUEST, description = "The body is neither a JSON array, nor text"),
+		(status = FORBIDDEN, description = "The user lacks room for the codes under the limits of the instance, so none were imported"),
+		(status = UNPROCESSABLE_ENTITY, description = "A URI isn't a valid migration payload, so nothing was imported, or the user has end-to-end encryption")
+	),
+)]
+pub async fn import_google_authenticator(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    headers: HeaderMap,
+    body: String,
+) -> Result<JSON<BackupImportResponse>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+    e2e::reject_plaintext(&user)?;
+    let uris = otpauth_uris(&headers, &body)?;
+    let backup = interop::google_authenticator::read(uris.iter().map(|(_, uri)| uri.as_str()))?;
+    import_backup(&state, &user, client_id, backup).await
+}
@@ -0,0 +1,226 @@
+use super::{ApiError, JSON};
+use crate::{
+    auth,
+    events::{ClientId, Event, EventKind},
+    models::{
+        codes::{Code, CodeBatch},
+        folders::Folder,
+        tokens::{TokenScope, TokenScopes},
+        user::User,
+    },
+    utils, AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension,
+};
+use serde::Deserialize;
+use sqlx::SqliteExecutor;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+fn validate_name(name: &str) -> Result<(), ApiError> {
+    if name.trim().is_empty() || name.len() > Folder::MAX_NAME_LENGTH {
+        return Err(ApiError::BadRequest(format!(
+            "Folder names must be between 1 and {} characters.",
+            Folder::MAX_NAME_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+/// Rejects folders which don't exist, or are owned by someone else. `None` is the top level.
+pub async fn ensure_folder(
+    executor: impl SqliteExecutor<'_>,
+    user: &User,
+    folder_id: Option<&str>,
+) -> Result<(), ApiError> {
+    if let Some(folder_id) = folder_id {
+        if Folder::get(executor, folder_id, &user.id).await?.is_none() {
+            return Err(ApiError::BadRequest(format!(
+                "The folder {folder_id} doesn't exist."
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/folders",
+	tag = "folders",
+	responses(
+		(status = OK, description = "Every folder of the user, sorted by name", body = Vec<Folder>)
+	),
+)]
+pub async fn list_folders(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+) -> Result<JSON<Vec<Folder>>, ApiError> {
+    Ok(JSON(Folder::get_many(&state.db, &user.id).await?))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct FolderAddPayload {
+    pub name: String,
+    /// Folder to create the folder in. Defaults to the top level.
+    pub parent_id: Option<String>,
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/folders",
+	tag = "folders",
+	request_body = FolderAddPayload,
+	responses(
+		(status = OK, description = "Successfully created the folder", body = Folder),
+		(status = BAD_REQUEST, description = "Invalid name, or the parent doesn't exist")
+	),
+)]
+pub async fn add_folder(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    JSON(payload): JSON<FolderAddPayload>,
+) -> Result<JSON<Folder>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+    validate_name(&payload.name)?;
+    ensure_folder(&state.db, &user, payload.parent_id.as_deref()).await?;
+
+    let folder = Folder {
+        id: utils::generate_id(16),
+        owner_id: user.id,
+        name: payload.name,
+        parent_id: payload.parent_id,
+    };
+    folder.insert(&state.db).await?;
+    state
+        .events
+        .publish(Event::folder(EventKind::FolderCreated, &folder, client_id));
+
+    Ok(JSON(folder))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct FolderEditPayload {
+    pub name: Option<String>,
+    /// Folder to move the folder into. `null` moves it to the top level.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub parent_id: Option<Option<String>>,
+}
+
+#[utoipa::path(
+	method(patch),
+	path = "/v1/folders/{id}",
+	tag = "folders",
+	params(
+		("id", description = "Id of the folder to edit")
+	),
+	request_body = FolderEditPayload,
+	responses(
+		(status = OK, description = "Successfully edited the folder", body = Folder),
+		(status = BAD_REQUEST, description = "Invalid name, the parent doesn't exist, or is the folder itself or inside it"),
+		(status = NOT_FOUND, description = "Unable to find folder")
+	),
+)]
+pub async fn edit_folder(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    Path(id): Path<String>,
+    JSON(payload): JSON<FolderEditPayload>,
+) -> Result<JSON<Folder>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+    let mut folder = Folder::get(&state.db, &id, &user.id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    if let Some(name) = payload.name {
+        validate_name(&name)?;
+        folder.name = name;
+    }
+    if let Some(parent_id) = payload.parent_id {
+        ensure_folder(&state.db, &user, parent_id.as_deref()).await?;
+        if let Some(parent_id) = &parent_id {
+            if Folder::is_within(&state.db, parent_id, &folder.id).await? {
+                return Err(ApiError::BadRequest(
+                    "A folder can't be moved into itself.".into(),
+                ));
+            }
+        }
+        folder.parent_id = parent_id;
+    }
+
+    folder.update(&state.db).await?;
+    state
+        .events
+        .publish(Event::folder(EventKind::FolderUpdated, &folder, client_id));
+
+    Ok(JSON(folder))
+}
+
+#[utoipa::path(
+	method(delete),
+	path = "/v1/folders/{id}",
+	tag = "folders",
+	params(
+		("id", description = "Id of the folder to delete")
+	),
+	responses(
+		(status = NO_CONTENT, description = "Deleted. Codes and folders in it are moved up to its parent"),
+		(status = NOT_FOUND, description = "Unable to find folder"),
+		(status = CONFLICT, description = "The user enforces unique names, and a code in the folder has the name of one in its parent")
+	),
+)]
+pub async fn delete_folder(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    auth::require_scope(&scopes, TokenScope::CodesWrite)?;
+    let folder = Folder::get(&state.db, &id, &user.id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    // Moving the codes as changes of their own, so syncing clients pick up their new folder
+    let mut batch = CodeBatch::begin(&state.db).await?;
+    let mut codes = Code::get_in_folder(batch.conn(), &folder.id).await?;
+    for code in &mut codes {
+        super::codes::ensure_unique_name(
+            batch.conn(),
+            &user,
+            folder.parent_id.as_deref(),
+            &code.display_name,
+            Some(&code.id),
+        )
+        .await?;
+        batch
+            .edit()
+            .code(code)
+            .folder_id(folder.parent_id.clone())
+            .call()
+            .await?;
+    }
+    folder.delete(batch.conn()).await?;
+    batch.commit().await?;
+
+    for code in &codes {
+        state
+            .events
+            .publish(Event::code(EventKind::CodeUpdated, code, client_id.clone()));
+    }
+    state
+        .events
+        .publish(Event::folder(EventKind::FolderDeleted, &folder, client_id));
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,86 @@
+use super::{
+    query::{self, Validate, ValidatedQuery},
+    ApiError,
+};
+use crate::{icons::IconStoreError, models::user::User, AppState};
+use axum::{
+    extract::State,
+    http::{header, HeaderName},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use utoipa::IntoParams;
+
+/// Icons a single user may preview per minute.
+pub const PREVIEWS_PER_MINUTE: u32 = 30;
+
+/// Headers of every response with an icon. Icons come from websites, and may be SVGs with scripts
+/// in them, so browsers must neither sniff them nor run them as documents of the instance. They're
+/// fresh for a day, and revalidated in the background for a month after that, as the icons of codes
+/// rarely change.
+pub const ICON_HEADERS: [(HeaderName, &str); 4] = [
+    (
+        header::CACHE_CONTROL,
+        "private, max-age=86400, stale-while-revalidate=2592000",
+    ),
+    (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+    (
+        header::CONTENT_SECURITY_POLICY,
+        "default-src 'none'; style-src 'unsafe-inline'; sandbox",
+    ),
+    (header::CONTENT_DISPOSITION, "inline; filename=\"icon\""),
+];
+
+#[derive(Deserialize, IntoParams)]
+pub struct IconPreviewQuery {
+    /// Public http(s) URL of the image.
+    pub url: String,
+}
+
+impl Validate for IconPreviewQuery {
+    fn validate(&mut self) -> Result<(), ApiError> {
+        query::non_empty("url", &self.url, 2048)?;
+        Ok(())
+    }
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/icons/preview",
+	tag = "codes",
+	params(IconPreviewQuery),
+	responses(
+		(status = OK, description = "The image at the URL. It is not stored"),
+		(status = BAD_REQUEST, description = "The URL is not public, too large or not an image"),
+		(status = TOO_MANY_REQUESTS, description = "Previewed too many icons recently")
+	),
+)]
+pub async fn preview_icon(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    ValidatedQuery(query): ValidatedQuery<IconPreviewQuery>,
+) -> Result<Response, ApiError> {
+    if !state.icon_preview_limiter.try_hit(&user.id) {
+        return Err(ApiError::RateLimited);
+    }
+
+    let (content_type, image) =
+        state
+            .icon_store
+            .preview(&query.url)
+            .await
+            .map_err(|err| match err {
+                IconStoreError::BlockedHost => ApiError::BadRequest(
+                    "Icons can only be previewed from public http(s) URLs.".into(),
+                ),
+                IconStoreError::TooLarge => ApiError::BadRequest("The image is too large.".into()),
+                IconStoreError::NotAnImage => {
+                    ApiError::BadRequest("The URL does not point to an image.".into())
+                }
+                _ => ApiError::NoIcon,
+            })?;
+
+    Ok((ICON_HEADERS, [(header::CONTENT_TYPE, content_type)], image).into_response())
+}
@@ -0,0 +1,281 @@
+use super::{users::SessionTokensResponse, ApiError, JSON};
+use crate::{
+    auth, connections, lockout, mail,
+    models::{
+        passwords::{Password, PasswordReset},
+        sessions::Session,
+        user::User,
+    },
+    utils, AppState,
+};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+};
+use serde::Deserialize;
+use std::{net::SocketAddr, sync::Arc};
+use tracing::info;
+use utoipa::ToSchema;
+
+/// Login attempts per minute for a single email, so passwords can't be guessed.
+pub const LOGINS_PER_MINUTE: u32 = 10;
+
+fn require_local_auth(state: &AppState) -> Result<(), ApiError> {
+    match state.settings.local_auth {
+        true => Ok(()),
+        false => Err(ApiError::LocalAuthUnavailable),
+    }
+}
+
+fn validate_password(password: &str) -> Result<(), ApiError> {
+    let length = password.chars().count();
+    if !(Password::MIN_LENGTH..=Password::MAX_LENGTH).contains(&length) {
+        return Err(ApiError::BadRequest(format!(
+            "Passwords must be between {} and {} characters long.",
+            Password::MIN_LENGTH,
+            Password::MAX_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+/// Starts a session for the user. Logins to an existing account, rather than one `registered` by
+/// the request, are emailed about if they're from a new device.
+async fn login(
+    state: &AppState,
+    user: &User,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request_headers: &HeaderMap,
+    registered: bool,
+) -> Result<(HeaderMap, JSON<SessionTokensResponse>), ApiError> {
+    auth::require_active(user)?;
+    let remote_ip = connections::client_ip(
+        peer.map(|ConnectInfo(peer)| peer.ip()),
+        request_headers,
+        &state.settings.trusted_proxies,
+    );
+    let tokens =
+        auth::create_jwt(&state.db, user, &state.jwt_keys, request_headers, remote_ip).await?;
+    if !registered {
+        mail::notify_login(state, user, &tokens.session_id).await?;
+    }
+    Ok((tokens.cookie_headers(), JSON(tokens.into())))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct LocalRegisterPayload {
+    pub email: String,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub password: String,
+    /// Token of a solved captcha. Required when the instance has a captcha set up.
+    pub captcha: Option<String>,
+    /// Invite minted by an admin. Required when registration is invite-only.
+    pub invite: Option<String>,
+}
+
+#[utoipa::path(
+	method(post),
+	path = "/v1/local/register",
+	tag = "user",
+	request_body = LocalRegisterPayload,
+	responses(
+		(status = CREATED, description = "Registered and logged in", body = SessionTokensResponse),
+		(status = BAD_REQUEST, description = "The email or password is invalid"),
+		(status = FORBIDDEN, description = "Creating an account requires a valid captcha, or an invite, or registration is closed"),
+		(status = CONFLICT, description = "Username or email is taken"),
+		(status = TOO_MANY_REQUESTS, description = "Too many accounts were created from the address"),
+		(status = NOT_IMPLEMENTED, description = "Logging in with a password is disabled")
+	),
+	security(())
+)]
+pub async fn register(
+    State(state): State<Arc<AppState>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request_headers: HeaderMap,
+    JSON(payload): JSON<LocalRegisterPayload>,
+) -> Result<(StatusCode, HeaderMap, JSON<SessionTokensResponse>), ApiError> {
+    require_local_auth(&state)?;
+
+    let email = Password::normalize_email(&payload.email);
+    if email.len() > Password::MAX_EMAIL_LENGTH || !email.contains('@') {
+        return Err(ApiError::BadRequest("The email is invalid.".into()));
+    }
+    if payload.username.trim().is_empty() {
+        return Err(ApiError::BadRequest("The username can't be empty.".into()));
+    }
+    validate_password(&payload.password)?;
+
+    if User::get_by_username(&state.db, payload.username.clone())
+        .await?
+        .is_some()
+    {
+        return Err(ApiError::UsernameTaken);
+    }
+    if Password::get_by_email(&state.db, &email).await?.is_some() {
+        return Err(ApiError::EmailTaken);
+    }
+
+    let remote_ip = connections::client_ip(
+        peer.map(|ConnectInfo(peer)| peer.ip()),
+        &request_headers,
+        &state.settings.trusted_proxies,
+    );
+    state
+        .registration
+        .check(
+            &state.db,
+            payload.invite.as_deref(),
+            payload.captcha.as_deref(),
+            remote_ip,
+        )
+        .await?;
+
+    let id = utils::generate_id(16);
+    let user = User {
+        id: id.clone(),
+        display_name: payload
+            .display_name
+            .unwrap_or_else(|| payload.username.clone()),
+        username: payload.username,
+        avatar_url: "".to_string(),
+        upstream_userid: format!("{}:{id}", Password::ISSUER),
+        upstream_issuer: Password::ISSUER.into(),
+        is_admin: false,
+        enforce_unique_names: false,
+        encryption_enabled: false,
+        encryption_key_check: None,
+        suspended_at: None,
+        delete_at: None,
+        email: Some(email.clone()),
+        email_notifications: true,
+    };
+    state
+        .registration
+        .redeem(&state.db, payload.invite.as_deref(), &user.id)
+        .await?;
+    user.insert(&state.db).await?;
+
+    Password {
+        user_id: user.id.clone(),
+        email,
+        password_hash: Password::hash(&payload.password),
+        updated_at: chrono::Utc::now().timestamp(),
+    }
+    .insert(&state.db)
+    .await?;
+
+    let (headers, tokens) = login(&state, &user, peer, &request_headers, true).await?;
+    Ok((StatusCode::CREATED, headers, tokens))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct LocalLoginPayload {
+    pub email: String,
+    pub password: String,
+}
+
+#[utoipa::path(
+	method(post),
+	path = "/v1/local/login",
+	tag = "user",
+	request_body = LocalLoginPayload,
+	responses(
+		(status = OK, description = "Logged in, starting a session", body = SessionTokensResponse),
+		(status = UNAUTHORIZED, description = "Wrong email or password"),
+		(status = FORBIDDEN, description = "The account is suspended"),
+		(status = TOO_MANY_REQUESTS, description = "Too many login attempts for the email, or too many failed ones from the address or for the email recently"),
+		(status = NOT_IMPLEMENTED, description = "Logging in with a password is disabled")
+	),
+	security(())
+)]
+pub async fn login_with_password(
+    State(state): State<Arc<AppState>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request_headers: HeaderMap,
+    JSON(payload): JSON<LocalLoginPayload>,
+) -> Result<(HeaderMap, JSON<SessionTokensResponse>), ApiError> {
+    require_local_auth(&state)?;
+
+    let email = Password::normalize_email(&payload.email);
+    if !state.password_login_limiter.try_hit(&email) {
+        return Err(ApiError::RateLimited);
+    }
+    // Guessing is slowed down per email, and per address for guessing at many emails at once
+    let mut lockout_keys = vec![format!("email:{email}")];
+    lockout_keys.extend(
+        connections::client_ip(
+            peer.map(|ConnectInfo(peer)| peer.ip()),
+            &request_headers,
+            &state.settings.trusted_proxies,
+        )
+        .map(lockout::address_key),
+    );
+    state.lockouts.check(&lockout_keys)?;
+
+    let password = if payload.password.chars().count() > Password::MAX_LENGTH {
+        None
+    } else {
+        match Password::get_by_email(&state.db, &email).await? {
+            Some(password) => Some(password).filter(|password| password.verify(&payload.password)),
+            None => {
+                Password::verify_dummy(&payload.password);
+                None
+            }
+        }
+    };
+    let Some(password) = password else {
+        state.lockouts.record_failure("password", &lockout_keys);
+        return Err(ApiError::InvalidCredentials);
+    };
+    state.lockouts.record_success(&lockout_keys[0]);
+
+    let user = User::get_by_id(&state.db, password.user_id)
+        .await?
+        .ok_or(ApiError::InvalidCredentials)?;
+    login(&state, &user, peer, &request_headers, false).await
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct PasswordResetPayload {
+    /// Token handed out by an admin, see `POST /v1/admin/users/{id}/password-reset`.
+    pub token: String,
+    pub password: String,
+}
+
+#[utoipa::path(
+	method(post),
+	path = "/v1/local/password/reset",
+	tag = "user",
+	request_body = PasswordResetPayload,
+	responses(
+		(status = NO_CONTENT, description = "Set the new password, and logged out every session of the user"),
+		(status = BAD_REQUEST, description = "The token is unknown, expired or already used, or the password is invalid"),
+		(status = NOT_IMPLEMENTED, description = "Logging in with a password is disabled")
+	),
+	security(())
+)]
+pub async fn reset_password(
+    State(state): State<Arc<AppState>>,
+    JSON(payload): JSON<PasswordResetPayload>,
+) -> Result<StatusCode, ApiError> {
+    require_local_auth(&state)?;
+    validate_password(&payload.password)?;
+
+    let now = chrono::Utc::now().timestamp();
+    let reset = PasswordReset::take(&state.db, &payload.token, now)
+        .await?
+        .ok_or(ApiError::InvalidResetToken)?;
+    let mut password = Password::get_for_user(&state.db, &reset.user_id)
+        .await?
+        .ok_or(ApiError::InvalidResetToken)?;
+
+    password
+        .set_password(&state.db, &payload.password, now)
+        .await?;
+    // Whoever knew the old password might still be logged in
+    Session::delete_for_user(&state.db, &reset.user_id).await?;
+    info!("The password of the user {} was reset", reset.user_id);
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,38 +1,118 @@
-use crate::AppState;
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use crate::{
+    auth, cli::RegistrationPolicy, jwt::JwkSet, quota::Quota, routes::v1::ApiError, utils, AppState,
+};
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_extra::extract::cookie::CookieJar;
 use serde::Serialize;
 use std::sync::Arc;
 use utoipa::ToSchema;
 
+/// OpenID provider users can log in with.
+#[derive(Serialize, Debug, ToSchema)]
+pub struct OpenIdProviderMetadata {
+    /// Passed as `provider` to `/v1/oauth`, which the redirect URI already does.
+    name: String,
+    client_id: String,
+    authorize: String,
+    redirect_uri: String,
+}
+
 #[derive(Serialize, Debug, ToSchema)]
 pub struct IceblinkInstanceMetadata {
     version: String,
+    /// Client id, authorization endpoint and redirect URI of the default OpenID provider.
     client_id: String,
     authorize: String,
     redirect_uri: String,
+    /// Every OpenID provider, the default one first.
+    providers: Vec<OpenIdProviderMetadata>,
+    /// PKCE methods `/v1/oauth` takes a `code_verifier` for.
+    code_challenge_methods_supported: Vec<String>,
+    /// Whether users can register and log in with an email and password at `/v1/local`.
+    local_auth: bool,
+    /// Who may create an account. With `invite`, registering takes an `invite` from an admin.
+    registration: RegistrationPolicy,
+    /// Limits on what a single user may store. Current usage is at `/v1/user/usage`.
+    quota: Quota,
+    /// Public VAPID key to pass as `applicationServerKey` when subscribing to Web Push, for
+    /// `/v1/devices/{id}/push`. Left out if the instance doesn't send pushes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vapid_public_key: Option<String>,
+    /// Whether the instance emails users about security events, see the `email_notifications`
+    /// setting.
+    email_notifications: bool,
+    /// Whether the logged in user has end-to-end encryption. Left out for anonymous requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encryption_enabled: Option<bool>,
 }
 
 #[utoipa::path(
 	get,
 	path = "/v1/",
 	responses(
-		(status = OK, description = "Successfully fetched instance metadata", body = IceblinkInstanceMetadata)
+		(status = OK, description = "Successfully fetched instance metadata", body = IceblinkInstanceMetadata),
+		(status = NOT_MODIFIED, description = "The metadata matches the ETag in `If-None-Match`")
 	),
 	tag = "misc",
 	security(())
 )]
 pub async fn instance_metadata(
     State(data): State<Arc<AppState>>,
-) -> (StatusCode, Json<IceblinkInstanceMetadata>) {
+    cookie_jar: CookieJar,
+    mut request: Request,
+) -> Response {
+    let user = auth::optional_user(&cookie_jar, &data, &mut request).await;
+    let metadata = IceblinkInstanceMetadata {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        authorize: data.openid.default_provider().authorization.clone(),
+        client_id: data.openid.default_provider().client_id.clone(),
+        redirect_uri: data.settings.redirect_uri.clone(),
+        providers: data
+            .openid
+            .iter()
+            .map(|provider| OpenIdProviderMetadata {
+                name: provider.name.clone(),
+                client_id: provider.client_id.clone(),
+                authorize: provider.authorization.clone(),
+                redirect_uri: provider.redirect_uri(&data.settings.redirect_uri),
+            })
+            .collect(),
+        code_challenge_methods_supported: auth::CODE_CHALLENGE_METHODS.map(str::to_string).to_vec(),
+        local_auth: data.settings.local_auth,
+        registration: data.settings.registration,
+        quota: Quota::new(&data.settings),
+        vapid_public_key: data
+            .web_push
+            .as_ref()
+            .map(|web_push| web_push.public_key().to_string()),
+        email_notifications: data.mailer.is_some(),
+        encryption_enabled: user.map(|user| user.encryption_enabled),
+    };
+    // The ETag covers every surfaced setting, so changing any of them busts caches
+    let json = serde_json::to_vec(&metadata).expect("Unable to serialize instance metadata");
+    let etag = utils::etag(&json);
+    let headers = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, "no-cache".to_string()),
+        (header::VARY, "Authorization, Cookie".to_string()),
+    ];
+
+    if utils::etag_matches(request.headers(), &etag) {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+
     (
         StatusCode::OK,
-        Json(IceblinkInstanceMetadata {
-            version: env!("CARGO_PKG_VERSION").to_string(),
-            authorize: data.openid.authorization.clone(),
-            client_id: data.openid.client_id.clone(),
-            redirect_uri: data.settings.redirect_uri.clone(),
-        }),
+        headers,
+        [(header::CONTENT_TYPE, "application/json".to_string())],
+        json,
     )
+        .into_response()
 }
 
 #[utoipa::path(
@@ -47,3 +127,22 @@ pub async fn instance_metadata(
 pub async fn metrics(State(data): State<Arc<AppState>>) -> impl IntoResponse {
     data.metrics.render()
 }
+
+#[utoipa::path(
+	get,
+	path = "/.well-known/jwks.json",
+	responses(
+		(status = OK, description = "Public keys session JWTs are signed with", body = JwkSet),
+		(status = NOT_FOUND, description = "JWTs are signed with a shared secret, which isn't published")
+	),
+	tag = "misc",
+	security(())
+)]
+pub async fn jwks(State(data): State<Arc<AppState>>) -> Result<Json<JwkSet>, ApiError> {
+    let jwks = data.jwt_keys.jwks();
+    if jwks.keys.is_empty() {
+        return Err(ApiError::NotFound);
+    }
+
+    Ok(Json(jwks))
+}
@@ -5,15 +5,36 @@ use serde::Serialize;
 use std::fmt::Debug;
 use tracing::warn;
 
+pub mod admin;
 pub mod codes;
+pub mod devices;
+pub mod export;
+pub mod folders;
+pub mod icons;
+pub mod local;
 pub mod misc;
+pub mod push;
+pub mod query;
+pub mod tags;
 pub mod users;
+#[cfg(feature = "webauthn")]
+pub mod webauthn;
+pub mod webhooks;
 
-#[derive(Serialize)]
+#[derive(Serialize, utoipa::ToSchema)]
 pub struct ApiErrorResponse {
     pub message: String,
     #[serde(rename = "errorKind")]
     pub kind: String,
+    /// Current copy of the code, if it was changed since the revision the client expected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<crate::models::codes::Code>,
+    /// Id of the code which already has the secret of a code being added.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub existing_id: Option<String>,
+    /// Part of the request which is invalid, like the `digits` of an otpauth URI.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
 }
 
 #[derive(Debug)]
@@ -28,17 +49,114 @@ pub enum ApiError {
     InvalidAuthentication,
     InvalidJwtSignature,
     JwtUserGone,
+    /// The session of the JWT has been idle for too long, or no longer exists.
+    SessionExpired,
     /// Usually caused by giving Iceblink an invalid authentication token.
     /// Still logging a warning regardless.
     OpenIdTokenExchangeFail(reqwest::Error),
     /// This should generally not happen, since we have received an authenticated token from the IdP.
     OpenIdUserinfoFail(reqwest::Error),
+    /// The keys to verify ID tokens with couldn't be fetched from the IdP.
+    OpenIdKeysFail(reqwest::Error),
+    /// No OpenID provider has the name the client logged in with.
+    UnknownOpenIdProvider,
+    /// The `state` of the login is unknown, expired, already used, or from another browser.
+    InvalidOauthState,
+    /// The ID token of the OpenID provider was rejected, e.g. as its nonce doesn't match the login.
+    InvalidIdToken,
+    /// The back-channel logout token of the OpenID provider was rejected.
+    InvalidLogoutToken,
+    /// The session didn't log in with the account's identity at an OpenID provider, or the
+    /// provider no longer accepts its access token.
+    UpstreamLoginRequired,
+    /// The device polling for its login hasn't been approved yet.
+    AuthorizationPending,
+    /// The device polled for its login too often.
+    SlowDown,
+    /// The user denied the device polling for its login.
+    AccessDenied,
+    /// The device code expired, or the device already logged in with it.
+    DeviceCodeExpired,
+    /// No device is waiting for the user code.
+    UnknownUserCode,
     NoIcon,
+    ExpiryInPast,
+    /// Too many requests to a rate limited endpoint.
+    RateLimited,
+    /// The address or account failed to authenticate too often, and is locked out for the duration.
+    TooManyFailedAttempts(std::time::Duration),
+    /// Request was malformed, with a message describing why.
+    BadRequest(String),
+    UsernameTaken,
+    /// The identity at the OpenID provider already belongs to an account.
+    IdentityInUse,
+    PasskeysUnavailable,
+    /// Logging in with an email and password isn't enabled on the instance.
+    LocalAuthUnavailable,
+    /// Web Push isn't configured on the instance.
+    PushUnavailable,
+    /// Another account already logs in with the email.
+    EmailTaken,
+    /// The email or password of a login is wrong.
+    InvalidCredentials,
+    /// The password reset token is unknown, expired or already used.
+    InvalidResetToken,
+    PassphraseRequired,
+    WrongPassphrase,
+    MalformedExport,
+    /// The export has a format version newer than this server knows.
+    UnsupportedExportVersion,
+    /// The user has end-to-end encryption, but the secret of a code was sent in plaintext.
+    EncryptionRequired,
+    /// The action needs to read secrets, which the user has end-to-end encrypted.
+    EncryptedContent,
+    /// The API token is valid, but its scope doesn't allow the action.
+    InsufficientScope,
+    /// Sensitive actions require having logged in recently, or a step-up with a security key.
+    ReauthenticationRequired,
+    AdminRequired,
+    /// An admin suspended the account.
+    AccountSuspended,
+    /// Admins can't suspend themselves, so the instance isn't left without an admin.
+    CannotSuspendSelf,
+    BackupFailed,
+    /// Creating an account requires a captcha, which was missing or rejected.
+    CaptchaFailed,
+    /// Too many accounts were created from the client's address recently.
+    TooManyRegistrations,
+    /// The instance doesn't accept new accounts.
+    RegistrationClosed,
+    /// Registration is invite-only, and no invite was supplied.
+    InviteRequired,
+    /// The invite is unknown, expired or already used.
+    InvalidInvite,
+    /// Storing the codes would take the user over the contained limit of codes.
+    CodeQuotaExceeded(u64),
+    /// Storing the codes would take the icons of the user over the contained limit of bytes.
+    IconQuotaExceeded(u64),
+    /// The user enforces unique names, and another code already has the name.
+    DuplicateName,
+    /// Another code of the user, with the contained id, already has the secret.
+    DuplicateSecret(String),
+    /// A cookie authenticated WebSocket was opened from another origin than the frontend.
+    CrossOrigin,
+    /// Tombstones of deletions after the sync cursor have been purged, so the client has to sync in full.
+    CursorExpired,
+    /// The code was changed since the revision the client expected, e.g. on another device.
+    RevisionConflict(Box<crate::models::codes::Code>),
+    /// An otpauth URI given in place of the secret is invalid.
+    InvalidOtpAuthUri(crate::otpauth::OtpAuthError),
+    /// The digits, period or algorithm given for a code are invalid.
+    InvalidCodeParameter(crate::otpauth::OtpAuthError),
+    #[cfg(feature = "webauthn")]
+    PasskeyRejected(webauthn_rs::prelude::WebauthnError),
 }
 
-impl IntoResponse for ApiError {
-    fn into_response(self) -> axum::response::Response {
-        let (status, message) = match &self {
+impl ApiError {
+    /// Status code and body sent to clients for the error.
+    pub fn parts(&self) -> (StatusCode, ApiErrorResponse) {
+        let detail: String;
+        let (status, message) = match self {
             ApiError::NotFound => (StatusCode::NOT_FOUND, "Resource not found."),
 			ApiError::MissingContentType => (StatusCode::UNSUPPORTED_MEDIA_TYPE, "Unsupported Content-Type. Did you mean to set it to applicaiton/json?"),
 			ApiError::JsonSyntaxError => (StatusCode::BAD_REQUEST, "Unable to parse JSON request."),
@@ -55,6 +173,7 @@ impl IntoResponse for ApiError {
 			ApiError::InvalidAuthentication => (StatusCode::UNAUTHORIZED, "The supplied authentication is invalid."),
 			ApiError::InvalidJwtSignature => (StatusCode::UNAUTHORIZED, "The supplied authentication has an invalid signature. Try logging in again."),
 			ApiError::JwtUserGone => (StatusCode::UNAUTHORIZED, "Authenticated user does not exist. Has the account been deleted?"),
+			ApiError::SessionExpired => (StatusCode::UNAUTHORIZED, "Your session has expired. Please log in again."),
 			ApiError::OpenIdTokenExchangeFail(err) => {
 				warn!("Failed to exchange from IdP: {err}");
 				(StatusCode::BAD_REQUEST, "Failed to exchange token with authentication provider. Please make sure to not edit the URL. Please try again.")
@@ -63,17 +182,113 @@ impl IntoResponse for ApiError {
 				warn!("Failed to get userinfo from IdP: {err}");
 				(StatusCode::INTERNAL_SERVER_ERROR, "Failed to aquire userinfo from authentication provider. Try again later.")
 			},
-			ApiError::NoIcon => (StatusCode::NO_CONTENT, "Unable to find an icon for this code. Double check your website URL.")
+			ApiError::OpenIdKeysFail(err) => {
+				warn!("Failed to get signing keys from IdP: {err}");
+				(StatusCode::INTERNAL_SERVER_ERROR, "Failed to verify the login with the authentication provider. Try again later.")
+			},
+			ApiError::UnknownOpenIdProvider => (StatusCode::BAD_REQUEST, "Unknown OpenID provider. Pick one listed in the instance metadata at /v1/."),
+			ApiError::InvalidOauthState => (StatusCode::BAD_REQUEST, "The login expired, or was started in another browser. Please log in again."),
+			ApiError::InvalidIdToken => (StatusCode::UNAUTHORIZED, "The authentication provider sent an invalid ID token. Please log in again."),
+			ApiError::InvalidLogoutToken => (StatusCode::BAD_REQUEST, "The logout token is invalid."),
+			ApiError::UpstreamLoginRequired => (StatusCode::CONFLICT, "The profile can only be refreshed by sessions which logged in at the authentication provider the account was created with. Please log in there again."),
+			ApiError::AuthorizationPending => (StatusCode::BAD_REQUEST, "The device hasn't been approved yet. Keep polling."),
+			ApiError::SlowDown => (StatusCode::BAD_REQUEST, "Polling too often. Wait at least the interval between polls."),
+			ApiError::AccessDenied => (StatusCode::BAD_REQUEST, "The login of the device was denied."),
+			ApiError::DeviceCodeExpired => (StatusCode::BAD_REQUEST, "The device code expired, or was already used. Start over to log in."),
+			ApiError::UnknownUserCode => (StatusCode::NOT_FOUND, "No device is waiting for this code. Check the code shown on your device."),
+			ApiError::NoIcon => (StatusCode::NO_CONTENT, "Unable to find an icon for this code. Double check your website URL."),
+			ApiError::ExpiryInPast => (StatusCode::BAD_REQUEST, "The expiry of a code has to be in the future."),
+			ApiError::RateLimited => (StatusCode::TOO_MANY_REQUESTS, "Too many requests. Try again in a minute."),
+			ApiError::TooManyFailedAttempts(_) => (StatusCode::TOO_MANY_REQUESTS, "Too many failed attempts to log in. Try again later."),
+			ApiError::BadRequest(message) => (StatusCode::BAD_REQUEST, message.as_str()),
+			ApiError::UsernameTaken => (StatusCode::CONFLICT, "The username is already taken."),
+			ApiError::IdentityInUse => (StatusCode::CONFLICT, "This account at the authentication provider is already linked to an Iceblink account."),
+			ApiError::PasskeysUnavailable => (StatusCode::NOT_IMPLEMENTED, "Passkeys are not available on this instance."),
+			ApiError::LocalAuthUnavailable => (StatusCode::NOT_IMPLEMENTED, "Logging in with a password is not available on this instance."),
+			ApiError::PushUnavailable => (StatusCode::NOT_IMPLEMENTED, "Push notifications are not available on this instance."),
+			ApiError::EmailTaken => (StatusCode::CONFLICT, "The email is already used by another account."),
+			ApiError::InvalidCredentials => (StatusCode::UNAUTHORIZED, "Wrong email or password."),
+			ApiError::InvalidResetToken => (StatusCode::BAD_REQUEST, "The password reset link expired, or was already used. Ask an admin for a new one."),
+			ApiError::PassphraseRequired => (StatusCode::BAD_REQUEST, "The export is encrypted. Supply the passphrase to import it."),
+			ApiError::WrongPassphrase => (StatusCode::UNPROCESSABLE_ENTITY, "Unable to decrypt the export. Is the passphrase correct?"),
+			ApiError::MalformedExport => (StatusCode::UNPROCESSABLE_ENTITY, "The export is malformed or uses unsupported encryption parameters."),
+			ApiError::UnsupportedExportVersion => (StatusCode::UNPROCESSABLE_ENTITY, "The export was made by a newer version of Iceblink. Update this server to import it."),
+			ApiError::EncryptionRequired => (StatusCode::UNPROCESSABLE_ENTITY, "Your account uses end-to-end encryption. Encrypt secrets before sending them."),
+			ApiError::EncryptedContent => (StatusCode::CONFLICT, "Your account uses end-to-end encryption, so the server can't read your secrets to do this."),
+			ApiError::InsufficientScope => (StatusCode::FORBIDDEN, "The scope of your token does not allow this action."),
+			ApiError::ReauthenticationRequired => (StatusCode::UNAUTHORIZED, "This action requires a recent login. Please log in again, or confirm it with a security key."),
+			ApiError::AdminRequired => (StatusCode::FORBIDDEN, "This action is only available to admins."),
+			ApiError::AccountSuspended => (StatusCode::FORBIDDEN, "This account was suspended by an admin of this instance."),
+			ApiError::CannotSuspendSelf => (StatusCode::BAD_REQUEST, "Admins can't suspend themselves."),
+			ApiError::BackupFailed => (StatusCode::INTERNAL_SERVER_ERROR, "Unable to create a database backup. Check the logs for details."),
+			ApiError::CaptchaFailed => (StatusCode::FORBIDDEN, "Creating an account requires solving the captcha. Please try again."),
+			ApiError::TooManyRegistrations => (StatusCode::TOO_MANY_REQUESTS, "Too many accounts were created from your address. Try again later."),
+			ApiError::RegistrationClosed => (StatusCode::FORBIDDEN, "This instance doesn't accept new accounts. Ask an admin of the instance for one."),
+			ApiError::InviteRequired => (StatusCode::FORBIDDEN, "Creating an account on this instance requires an invite. Ask an admin of the instance for one."),
+			ApiError::InvalidInvite => (StatusCode::FORBIDDEN, "The invite is unknown, expired or was already used. Ask an admin of the instance for a new one."),
+			ApiError::CodeQuotaExceeded(max_codes) => {
+				detail = format!("You can store at most {max_codes} codes on this instance. Delete some to make room.");
+				(StatusCode::FORBIDDEN, detail.as_str())
+			},
+			ApiError::IconQuotaExceeded(max_icon_bytes) => {
+				detail = format!("The icons of your codes can take up at most {max_icon_bytes} bytes on this instance. Remove some to make room.");
+				(StatusCode::FORBIDDEN, detail.as_str())
+			},
+			ApiError::DuplicateName => (StatusCode::CONFLICT, "Another code already has this name."),
+			ApiError::DuplicateSecret(_) => (StatusCode::CONFLICT, "Another code already has this secret. Add it anyway with `allow_duplicate=true`."),
+			ApiError::CrossOrigin => (StatusCode::FORBIDDEN, "Connections from other origins are not allowed."),
+			ApiError::CursorExpired => (StatusCode::GONE, "The cursor is too old to sync from. Sync in full by leaving out `since`."),
+			ApiError::RevisionConflict(_) => (StatusCode::CONFLICT, "The code was changed in the meantime. Merge with the current copy, and try again."),
+			ApiError::InvalidOtpAuthUri(err) => {
+				detail = format!("Invalid otpauth URI: {err}.");
+				(StatusCode::BAD_REQUEST, detail.as_str())
+			},
+			ApiError::InvalidCodeParameter(err) => {
+				detail = format!("Invalid code parameter: {err}.");
+				(StatusCode::BAD_REQUEST, detail.as_str())
+			},
+			#[cfg(feature = "webauthn")]
+			ApiError::PasskeyRejected(err) => {
+				warn!("Passkey ceremony failed: {err}");
+				(StatusCode::UNAUTHORIZED, "The passkey could not be verified. Please try again.")
+			}
         };
 
         (
             status,
-            axum::Json(ApiErrorResponse {
+            ApiErrorResponse {
                 message: message.to_string(),
                 kind: self.kind(),
-            }),
+                current: match self {
+                    ApiError::RevisionConflict(current) => Some(current.as_ref().clone()),
+                    _ => None,
+                },
+                existing_id: match self {
+                    ApiError::DuplicateSecret(id) => Some(id.clone()),
+                    _ => None,
+                },
+                field: match self {
+                    ApiError::InvalidOtpAuthUri(err) | ApiError::InvalidCodeParameter(err) => {
+                        Some(err.field().to_string())
+                    }
+                    _ => None,
+                },
+            },
         )
-            .into_response()
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> axum::response::Response {
+        let (status, body) = self.parts();
+        let mut response = (status, axum::Json(body)).into_response();
+        if let ApiError::TooManyFailedAttempts(locked_for) = self {
+            response.headers_mut().insert(
+                axum::http::header::RETRY_AFTER,
+                (locked_for.as_secs_f64().ceil() as u64).into(),
+            );
+        }
+        response
     }
 }
 
@@ -105,6 +320,17 @@ impl From<sqlx::Error> for ApiError {
     }
 }
 
+impl From<crate::export::ExportError> for ApiError {
+    fn from(value: crate::export::ExportError) -> Self {
+        match value {
+            crate::export::ExportError::PassphraseRequired => ApiError::PassphraseRequired,
+            crate::export::ExportError::WrongPassphrase => ApiError::WrongPassphrase,
+            crate::export::ExportError::Malformed => ApiError::MalformedExport,
+            crate::export::ExportError::UnsupportedVersion => ApiError::UnsupportedExportVersion,
+        }
+    }
+}
+
 impl From<jsonwebtoken::errors::Error> for ApiError {
     fn from(value: jsonwebtoken::errors::Error) -> Self {
         match value.into_kind() {
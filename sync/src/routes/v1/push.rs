@@ -0,0 +1,144 @@
+use super::ApiError;
+use crate::{
+    events::{Event, EventKind},
+    models::user::User,
+    AppState,
+};
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::{header, HeaderMap},
+    response::{
+        sse::{self, KeepAlive, Sse},
+        Response,
+    },
+    Extension,
+};
+use futures_util::{stream, Stream};
+use std::{convert::Infallible, sync::Arc};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Browsers send cookies along with cross-origin WebSocket handshakes, as CORS doesn't apply to
+/// them. Without checking the origin, any website could listen in on the changes of its visitors.
+fn ensure_same_origin(headers: &HeaderMap, frontfacing: &str) -> Result<(), ApiError> {
+    match headers.get(header::ORIGIN) {
+        Some(origin) if origin.as_bytes() != frontfacing.as_bytes() => Err(ApiError::CrossOrigin),
+        _ => Ok(()),
+    }
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/ws",
+	tag = "sync",
+	responses(
+		(status = SWITCHING_PROTOCOLS, description = "Upgraded to a WebSocket. Every change to the data of the user is sent as a JSON text message", body = Event),
+		(status = FORBIDDEN, description = "Connecting from another origin than the frontend")
+	),
+)]
+pub async fn websocket(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    ensure_same_origin(&headers, &state.settings.frontfacing)?;
+
+    // Subscribing before upgrading, so no change after the handshake is missed
+    let events = state.events.subscribe();
+    Ok(upgrade.on_upgrade(move |socket| forward_events(socket, events, user.id)))
+}
+
+/// Sends the events of the user over the socket, until either side goes away.
+async fn forward_events(
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<Event>,
+    user_id: String,
+) {
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if event.user_id == user_id => {
+                    let text = serde_json::to_string(&event).expect("Unable to serialize event");
+                    if socket.send(Message::Text(text)).await.is_err() {
+                        return;
+                    }
+                    if event.kind == EventKind::UserDeleted {
+                        close(socket, close_code::NORMAL, "The account was deleted").await;
+                        return;
+                    }
+                }
+                Ok(_) => {}
+                // Missed events can't be recovered, so have the client reconnect and sync in full
+                Err(RecvError::Lagged(_)) => {
+                    close(socket, close_code::AGAIN, "Missed events, sync again").await;
+                    return;
+                }
+                Err(RecvError::Closed) => {
+                    close(socket, close_code::RESTART, "Shutting down").await;
+                    return;
+                }
+            },
+            message = socket.recv() => match message {
+                // Clients have nothing to say. Pings are answered by axum itself
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+async fn close(mut socket: WebSocket, code: u16, reason: &'static str) {
+    let _ = socket
+        .send(Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        })))
+        .await;
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/events",
+	tag = "sync",
+	responses(
+		(status = OK, description = "Server-sent events for every change to the data of the user, named after their kind, with the event as JSON data. A fallback for networks blocking WebSockets", content_type = "text/event-stream", body = Event)
+	),
+)]
+pub async fn event_stream(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    let events = state.events.subscribe();
+
+    let stream = stream::unfold((events, false), move |(mut events, done)| {
+        let user_id = user.id.clone();
+
+        async move {
+            if done {
+                return None;
+            }
+
+            loop {
+                match events.recv().await {
+                    Ok(event) if event.user_id == user_id => {
+                        let data = sse::Event::default()
+                            .event(event.kind.name())
+                            .json_data(&event)
+                            .expect("Unable to serialize event");
+                        let done = event.kind == EventKind::UserDeleted;
+                        return Some((Ok(data), (events, done)));
+                    }
+                    Ok(_) => {}
+                    // Ending the stream has the client reconnect. Having missed events, it should
+                    // sync in full
+                    Err(RecvError::Lagged(_) | RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
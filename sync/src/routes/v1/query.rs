@@ -0,0 +1,159 @@
+use super::ApiError;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::request::Parts,
+};
+use serde::de::DeserializeOwned;
+
+/// Validation of query parameters, run after they have been deserialized.
+/// Implementations may also normalize values, e.g. clamping a limit.
+pub trait Validate {
+    fn validate(&mut self) -> Result<(), ApiError>;
+}
+
+/// Like [`axum::extract::Query`], but validates the parameters, and rejects with an [`ApiError`].
+pub struct ValidatedQuery<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for ValidatedQuery<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(mut value) = Query::<T>::from_request_parts(parts, state)
+            .await
+            .map_err(|err| ApiError::BadRequest(err.body_text()))?;
+
+        value.validate()?;
+        Ok(ValidatedQuery(value))
+    }
+}
+
+/// Rejects values outside of `min..=max`.
+pub fn in_range<T>(name: &str, value: T, min: T, max: T) -> Result<T, ApiError>
+where
+    T: PartialOrd + std::fmt::Display,
+{
+    if value < min || value > max {
+        return Err(ApiError::BadRequest(format!(
+            "Query parameter `{name}` must be between {min} and {max}."
+        )));
+    }
+
+    Ok(value)
+}
+
+/// Rejects values which are not in the allowlist.
+pub fn one_of<'a>(name: &str, value: &'a str, allowed: &[&str]) -> Result<&'a str, ApiError> {
+    if !allowed.contains(&value) {
+        return Err(ApiError::BadRequest(format!(
+            "Query parameter `{name}` must be one of: {}.",
+            allowed.join(", ")
+        )));
+    }
+
+    Ok(value)
+}
+
+/// Rejects empty or overly long strings.
+pub fn non_empty<'a>(name: &str, value: &'a str, max_len: usize) -> Result<&'a str, ApiError> {
+    if value.trim().is_empty() || value.len() > max_len {
+        return Err(ApiError::BadRequest(format!(
+            "Query parameter `{name}` must be between 1 and {max_len} characters."
+        )));
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+    use googletest::prelude::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug)]
+    struct ListingQuery {
+        #[serde(default = "default_limit")]
+        limit: u32,
+        sort: Option<String>,
+    }
+
+    fn default_limit() -> u32 {
+        50
+    }
+
+    impl Validate for ListingQuery {
+        fn validate(&mut self) -> Result<(), ApiError> {
+            in_range("limit", self.limit, 1, 500)?;
+
+            if let Some(sort) = &self.sort {
+                one_of("sort", sort, &["display_name", "id"])?;
+            }
+
+            Ok(())
+        }
+    }
+
+    async fn extract(uri: &str) -> Result<ListingQuery, ApiError> {
+        let (mut parts, _) = Request::builder().uri(uri).body(()).unwrap().into_parts();
+
+        ValidatedQuery::<ListingQuery>::from_request_parts(&mut parts, &())
+            .await
+            .map(|ValidatedQuery(query)| query)
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn accepts_valid() {
+        let query = extract("/?limit=20&sort=id").await.unwrap();
+        assert_that!(query.limit, eq(20));
+        assert_that!(query.sort, some(eq("id")));
+
+        let query = extract("/").await.unwrap();
+        assert_that!(query.limit, eq(50));
+        assert_that!(query.sort, none());
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn rejects_out_of_range() {
+        for uri in ["/?limit=0", "/?limit=501"] {
+            let err = extract(uri).await.unwrap_err();
+            assert_that!(err.kind(), eq("BadRequest"));
+            assert_that!(
+                err.to_string(),
+                contains_substring("`limit` must be between 1 and 500")
+            );
+        }
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn rejects_unknown_sort() {
+        let err = extract("/?sort=content").await.unwrap_err();
+        assert_that!(
+            err.to_string(),
+            contains_substring("`sort` must be one of: display_name, id")
+        );
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn rejects_unparsable() {
+        let err = extract("/?limit=many").await.unwrap_err();
+        assert_that!(err.kind(), eq("BadRequest"));
+    }
+
+    #[gtest]
+    fn non_empty_strings() {
+        assert_that!(non_empty("q", "google", 64), ok(eq("google")));
+        assert_that!(non_empty("q", "  ", 64), err(anything()));
+        assert_that!(non_empty("q", &"a".repeat(65), 64), err(anything()));
+    }
+}
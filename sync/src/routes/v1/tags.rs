@@ -0,0 +1,51 @@
+use super::{ApiError, JSON};
+use crate::{
+    models::{
+        tags::{self, TagUsage},
+        user::User,
+    },
+    AppState,
+};
+use axum::{extract::State, Extension};
+use std::sync::Arc;
+
+const MAX_TAG_LENGTH: usize = 32;
+const MAX_TAGS_PER_CODE: usize = 20;
+
+/// Trims the tags of a code, dropping duplicates, and rejects empty or overly long ones.
+pub fn normalize_tags(tags: Vec<String>) -> Result<Vec<String>, ApiError> {
+    let mut normalized: Vec<String> = vec![];
+    for tag in tags {
+        let tag = tag.trim();
+        if tag.is_empty() || tag.len() > MAX_TAG_LENGTH {
+            return Err(ApiError::BadRequest(format!(
+                "Tags must be between 1 and {MAX_TAG_LENGTH} characters."
+            )));
+        }
+        if !normalized.iter().any(|existing| existing == tag) {
+            normalized.push(tag.to_string());
+        }
+    }
+
+    if normalized.len() > MAX_TAGS_PER_CODE {
+        return Err(ApiError::BadRequest(format!(
+            "A code can have at most {MAX_TAGS_PER_CODE} tags."
+        )));
+    }
+    Ok(normalized)
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/tags",
+	tag = "tags",
+	responses(
+		(status = OK, description = "Every tag of the user in use, sorted by name, with the ids of the codes carrying it", body = Vec<TagUsage>)
+	),
+)]
+pub async fn list_tags(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+) -> Result<JSON<Vec<TagUsage>>, ApiError> {
+    Ok(JSON(tags::usage(&state.db, &user.id).await?))
+}
@@ -1,22 +1,224 @@
-use super::{ApiError, JSON};
+use super::{
+    codes,
+    query::{self, Validate, ValidatedQuery},
+    ApiError, JSON,
+};
 use crate::{
-    auth,
-    models::{self, codes::Code, user::User},
+    archive::UserArchive,
+    auth::{self, IssuedAt, OpenId, OpenIdUserInfo},
+    connections,
+    device::{self, DevicePoll},
+    e2e,
+    events::{ClientId, Event, EventKind},
+    lockout,
+    mail::{self, Notification},
+    models::{
+        codes::{Code, CodeBatch},
+        identities::Identity,
+        preferences,
+        sessions::Session,
+        tokens::{ApiToken, TokenScope, TokenScopes},
+        user::User,
+    },
+    quota::{Quota, Usage},
     utils, AppState,
 };
 use axum::{
-    extract::{Query, State},
-    http::HeaderMap,
-    Extension,
+    body::Body,
+    extract::{ConnectInfo, Path, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    Extension, Form,
 };
-use reqwest::{header, StatusCode};
+use axum_extra::extract::cookie::CookieJar;
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use utoipa::IntoParams;
+use sqlx::SqlitePool;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::Arc,
+};
+use tracing::info;
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Deserialize, IntoParams)]
+pub struct AuthorizeQueryParams {
+    /// Name of the OpenID provider to log in with, as listed in the instance metadata. Defaults to
+    /// the default provider.
+    provider: Option<String>,
+    /// S256 PKCE code challenge to request the code with. Logging in then needs its verifier.
+    code_challenge: Option<String>,
+    /// Redirect URI of a mobile or desktop app to finish the login at, instead of the web frontend:
+    /// either at one of the instance's app schemes like `iceblink://callback`, or a loopback address
+    /// like `http://127.0.0.1:49152/callback`. Requires a code challenge.
+    redirect_uri: Option<String>,
+}
+
+impl Validate for AuthorizeQueryParams {
+    fn validate(&mut self) -> Result<(), ApiError> {
+        if let Some(provider) = &self.provider {
+            query::non_empty("provider", provider, 64)?;
+        }
+        if let Some(code_challenge) = &self.code_challenge {
+            // Base64url of a SHA-256 hash
+            if code_challenge.len() != 43 || !auth::valid_code_verifier(code_challenge) {
+                return Err(ApiError::BadRequest(
+                    "Query parameter `code_challenge` must be an S256 code challenge.".into(),
+                ));
+            }
+        }
+        if let Some(redirect_uri) = &self.redirect_uri {
+            query::non_empty("redirect_uri", redirect_uri, 512)?;
+            // Apps can't send the cookie of the browser they opened, so PKCE ties the code to them
+            if self.code_challenge.is_none() {
+                return Err(ApiError::BadRequest(
+                    "Query parameter `redirect_uri` requires a `code_challenge`.".into(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[utoipa::path(
+	method(get),
+	path = "/v1/oauth/authorize",
+	tag = "user",
+	responses(
+		(status = SEE_OTHER, description = "Redirects to the OpenID provider to log in. The `state` of the login is bound to the browser with a cookie, and has to be passed to /v1/oauth within 10 minutes"),
+		(status = BAD_REQUEST, description = "No OpenID provider has the given name, or the redirect URI isn't one of an app"),
+		(status = TOO_MANY_REQUESTS, description = "Too many logins are pending")
+	),
+	params(
+		AuthorizeQueryParams
+	),
+	security(())
+)]
+pub async fn authorize(
+    State(state): State<Arc<AppState>>,
+    ValidatedQuery(query): ValidatedQuery<AuthorizeQueryParams>,
+) -> Result<(StatusCode, HeaderMap), ApiError> {
+    let openid = state
+        .openid
+        .get(query.provider.as_deref())
+        .ok_or(ApiError::UnknownOpenIdProvider)?;
+    if let Some(redirect_uri) = &query.redirect_uri {
+        if !auth::native_redirect_uri(redirect_uri, &state.settings.native_redirect_schemes) {
+            return Err(ApiError::BadRequest(
+                "Query parameter `redirect_uri` must use an app scheme of the instance, or be a loopback address.".into(),
+            ));
+        }
+    }
+    let (login_state, nonce) = state
+        .oauth_states
+        .start(&openid.name, query.redirect_uri.as_deref())
+        .ok_or(ApiError::RateLimited)?;
+
+    let redirect_uri = query
+        .redirect_uri
+        .unwrap_or_else(|| openid.redirect_uri(&state.settings.redirect_uri));
+    let url = openid.authorization_url(
+        &redirect_uri,
+        &login_state,
+        &nonce,
+        query.code_challenge.as_deref(),
+    );
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::LOCATION,
+        url.parse()
+            .map_err(|_| ApiError::BadRequest("Unable to redirect to the provider.".into()))?,
+    );
+    headers.insert(
+        header::SET_COOKIE,
+        auth::oauth_state_cookie(&login_state)
+            .to_string()
+            .parse()
+            .unwrap(),
+    );
+
+    Ok((StatusCode::SEE_OTHER, headers))
+}
 
 #[derive(Deserialize, IntoParams)]
 pub struct OauthQueryParams {
     code: String,
+    /// State of the login, as handed out by /v1/oauth/authorize. The browser has to send the cookie
+    /// it received there as well, unless an app started the login with its own redirect URI.
+    state: String,
+    /// Name of the OpenID provider the code is from, as listed in the instance metadata. Defaults
+    /// to the default provider.
+    provider: Option<String>,
+    /// PKCE code verifier, if the client sent a code challenge when requesting the code. Lets
+    /// public clients log in without a client secret.
+    code_verifier: Option<String>,
+    /// Token of a solved captcha. Required to create an account when the instance has a captcha set up.
+    captcha: Option<String>,
+    /// Invite minted by an admin. Required to create an account when registration is invite-only.
+    invite: Option<String>,
+}
+
+impl Validate for OauthQueryParams {
+    fn validate(&mut self) -> Result<(), ApiError> {
+        validate_upstream_login(
+            &self.code,
+            &self.state,
+            self.provider.as_deref(),
+            self.code_verifier.as_deref(),
+        )?;
+        if let Some(captcha) = &self.captcha {
+            query::non_empty("captcha", captcha, 2048)?;
+        }
+        if let Some(invite) = &self.invite {
+            query::non_empty("invite", invite, 256)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the query parameters the OpenID provider and client send to finish a login.
+fn validate_upstream_login(
+    code: &str,
+    state: &str,
+    provider: Option<&str>,
+    code_verifier: Option<&str>,
+) -> Result<(), ApiError> {
+    query::non_empty("code", code, 2048)?;
+    query::non_empty("state", state, 256)?;
+    if let Some(provider) = provider {
+        query::non_empty("provider", provider, 64)?;
+    }
+    if let Some(code_verifier) = code_verifier {
+        if !auth::valid_code_verifier(code_verifier) {
+            return Err(ApiError::BadRequest(
+                "Query parameter `code_verifier` must be 43 to 128 letters, digits, or any of `-._~`."
+                    .into(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Tokens of a session. Browsers receive them as cookies as well.
+#[derive(Serialize, ToSchema)]
+pub struct SessionTokensResponse {
+    /// JWT to authenticate requests with.
+    pub access_token: String,
+    /// Exchanged for new tokens with `POST /v1/oauth/refresh`. Only works once.
+    pub refresh_token: String,
+    /// Unix timestamp (seconds) at which the access token expires.
+    pub expires_at: i64,
+}
+
+impl From<auth::SessionTokens> for SessionTokensResponse {
+    fn from(tokens: auth::SessionTokens) -> Self {
+        SessionTokensResponse {
+            access_token: tokens.jwt,
+            refresh_token: tokens.refresh_token,
+            expires_at: tokens.expires_at,
+        }
+    }
 }
 
 #[utoipa::path(
@@ -24,7 +226,11 @@ pub struct OauthQueryParams {
 	path = "/v1/oauth",
 	tag = "user",
 	responses(
-		(status = OK, description = "Success")
+		(status = OK, description = "Logged in, starting a session", body = SessionTokensResponse),
+		(status = BAD_REQUEST, description = "No OpenID provider has the given name, or the state is unknown, expired or from another browser"),
+		(status = UNAUTHORIZED, description = "The ID token doesn't have the nonce of the login"),
+		(status = FORBIDDEN, description = "Creating an account requires a valid captcha, or an invite, or registration is closed. Existing users can always log in"),
+		(status = TOO_MANY_REQUESTS, description = "Too many accounts were created from the address")
 	),
 	params(
 		OauthQueryParams
@@ -33,48 +239,497 @@ pub struct OauthQueryParams {
 )]
 pub async fn oauth(
     State(state): State<Arc<AppState>>,
-    query: Query<OauthQueryParams>,
-) -> Result<(StatusCode, HeaderMap), ApiError> {
-    let code = query.code.to_string();
-    let mut headers = HeaderMap::default();
+    peer: Option<ConnectInfo<SocketAddr>>,
+    cookie_jar: CookieJar,
+    request_headers: HeaderMap,
+    ValidatedQuery(query): ValidatedQuery<OauthQueryParams>,
+) -> Result<(HeaderMap, JSON<SessionTokensResponse>), ApiError> {
+    let UpstreamLogin {
+        openid,
+        userinfo,
+        sid: upstream_sid,
+        access_token: upstream_access_token,
+    } = upstream_login(
+        &state,
+        &cookie_jar,
+        query.provider.as_deref(),
+        &query.state,
+        query.code,
+        query.code_verifier,
+    )
+    .await?;
+
+    let remote_ip = connections::client_ip(
+        peer.map(|ConnectInfo(peer)| peer.ip()),
+        &request_headers,
+        &state.settings.trusted_proxies,
+    );
+    let mut user_query = User::get_by_upstream_id(&state.db, &openid.issuer, &userinfo.id).await?;
+    if user_query.is_none() && openid.name == auth::DEFAULT_OPENID_PROVIDER {
+        user_query = User::claim_legacy_identity(&state.db, &openid.issuer, &userinfo.id).await?;
+    }
 
-    let access_token = state
+    let (user, registered) = match user_query {
+        None => {
+            state
+                .registration
+                .check(
+                    &state.db,
+                    query.invite.as_deref(),
+                    query.captcha.as_deref(),
+                    remote_ip,
+                )
+                .await?;
+
+            let user = User {
+                avatar_url: userinfo.clone().avatar,
+                display_name: userinfo.display_name_or_username(),
+                id: utils::generate_id(16),
+                upstream_userid: userinfo.clone().id,
+                upstream_issuer: openid.issuer.clone(),
+                username: userinfo.clone().username,
+                is_admin: false,
+                enforce_unique_names: false,
+                encryption_enabled: false,
+                encryption_key_check: None,
+                suspended_at: None,
+                delete_at: None,
+                email: userinfo.verified_email(),
+                email_notifications: true,
+            };
+            state
+                .registration
+                .redeem(&state.db, query.invite.as_deref(), &user.id)
+                .await?;
+            user.insert(&state.db).await?;
+            (user, true)
+        }
+        Some(mut user) => {
+            sync_profile(&state, &mut user, openid, &userinfo).await?;
+            (user, false)
+        }
+    };
+    auth::require_active(&user)?;
+
+    let tokens = auth::create_jwt(
+        &state.db,
+        &user,
+        &state.jwt_keys,
+        &request_headers,
+        remote_ip,
+    )
+    .await?;
+    Session::set_upstream(
+        &state.db,
+        &tokens.session_id,
+        &openid.issuer,
+        upstream_sid.as_deref(),
+        &upstream_access_token,
+    )
+    .await?;
+    if !registered {
+        mail::notify_login(&state, &user, &tokens.session_id).await?;
+    }
+
+    let mut headers = tokens.cookie_headers();
+    headers.append(header::SET_COOKIE, auth::oauth_state_removal_header());
+    Ok((headers, JSON(tokens.into())))
+}
+
+/// Takes over the display name, avatar and email from the OpenID provider, if the user logged in
+/// with the identity the account was created with. Linked identities don't change the profile.
+async fn sync_profile(
+    state: &AppState,
+    user: &mut User,
+    openid: &OpenId,
+    userinfo: &OpenIdUserInfo,
+) -> Result<(), ApiError> {
+    if user.upstream_issuer != openid.issuer || user.upstream_userid != userinfo.id {
+        return Ok(());
+    }
+    let display_name = userinfo.display_name_or_username();
+    if user.display_name != display_name || user.avatar_url != userinfo.avatar {
+        user.set_profile(&state.db, display_name, userinfo.avatar.clone())
+            .await?;
+    }
+    // Kept when the provider sends none, e.g. as it wasn't granted the email scope
+    let email = userinfo.verified_email();
+    if email.is_some() && user.email != email {
+        user.set_email(&state.db, email).await?;
+    }
+    Ok(())
+}
+
+/// A finished login at an OpenID provider.
+struct UpstreamLogin<'a> {
+    openid: &'a OpenId,
+    /// Who logged in at the provider.
+    userinfo: OpenIdUserInfo,
+    /// Id of the session at the provider, if it sent one.
+    sid: Option<String>,
+    /// Access token of the provider, to fetch the userinfo with again later.
+    access_token: String,
+}
+
+/// Finishes a login at the OpenID provider started at /v1/oauth/authorize.
+async fn upstream_login<'a>(
+    state: &'a AppState,
+    cookie_jar: &CookieJar,
+    provider: Option<&str>,
+    login_state: &str,
+    code: String,
+    code_verifier: Option<String>,
+) -> Result<UpstreamLogin<'a>, ApiError> {
+    let openid = state
         .openid
+        .get(provider)
+        .ok_or(ApiError::UnknownOpenIdProvider)?;
+
+    let pending = state
+        .oauth_states
+        .get(login_state)
+        .ok_or(ApiError::InvalidOauthState)?;
+    match pending.redirect_uri {
+        // Apps opened the login in the system browser, so they can't have its cookie. The code
+        // verifier only they know takes its place.
+        Some(_) => {
+            if code_verifier.is_none() {
+                return Err(ApiError::InvalidOauthState);
+            }
+        }
+        // Logins forced onto the browser by someone else don't come with its cookie
+        None => {
+            let cookie_state = cookie_jar
+                .get(auth::OAUTH_STATE_COOKIE)
+                .map(|cookie| cookie.value());
+            if cookie_state != Some(login_state) {
+                return Err(ApiError::InvalidOauthState);
+            }
+        }
+    }
+    let login = state
+        .oauth_states
+        .finish(login_state)
+        .filter(|login| login.provider == openid.name)
+        .ok_or(ApiError::InvalidOauthState)?;
+
+    let redirect_uri = login
+        .redirect_uri
+        .unwrap_or_else(|| openid.redirect_uri(&state.settings.redirect_uri));
+    let upstream_tokens = openid
         .clone()
-        .exchange(code.clone())
+        .exchange(code, redirect_uri, code_verifier)
         .await
         .map_err(ApiError::OpenIdTokenExchangeFail)?;
+    let sid = match &upstream_tokens.id_token {
+        Some(id_token) => openid.verify_id_token(id_token, &login.nonce).await?,
+        None => None,
+    };
 
-    let userinfo = state
-        .openid
+    let userinfo = openid
         .clone()
-        .userinfo(access_token)
+        .userinfo(upstream_tokens.access_token.clone())
         .await
         .map_err(ApiError::OpenIdUserinfoFail)?;
+    Ok(UpstreamLogin {
+        openid,
+        userinfo,
+        sid,
+        access_token: upstream_tokens.access_token,
+    })
+}
 
-    let user_query = models::user::User::get_by_upstream_id(&state.db, userinfo.clone().id).await?;
+#[derive(Deserialize, IntoParams)]
+pub struct BackchannelLogoutQueryParams {
+    /// Name of the OpenID provider sending the logout, as in its redirect URI. Defaults to the
+    /// default provider.
+    provider: Option<String>,
+}
 
-    let user = match user_query {
-        None => {
-            let user = User {
-                avatar_url: userinfo.clone().avatar,
-                display_name: userinfo
-                    .clone()
-                    .display_name
-                    .unwrap_or(userinfo.clone().username),
-                id: utils::generate_id(16),
-                upstream_userid: userinfo.clone().id,
-                username: userinfo.clone().username,
-            };
-            user.insert(&state.db).await?;
-            user
+impl Validate for BackchannelLogoutQueryParams {
+    fn validate(&mut self) -> Result<(), ApiError> {
+        if let Some(provider) = &self.provider {
+            query::non_empty("provider", provider, 64)?;
         }
-        Some(user) => user,
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct BackchannelLogoutPayload {
+    /// JWT signed by the provider, naming the user (`sub`) or session (`sid`) which logged out.
+    pub logout_token: String,
+}
+
+#[utoipa::path(
+	method(post),
+	path = "/v1/oauth/backchannel-logout",
+	tag = "user",
+	params(
+		BackchannelLogoutQueryParams
+	),
+	request_body(content = BackchannelLogoutPayload, content_type = "application/x-www-form-urlencoded"),
+	responses(
+		(status = OK, description = "Revoked the sessions which logged in with the ended session at the provider"),
+		(status = BAD_REQUEST, description = "No OpenID provider has the given name, or the logout token is invalid")
+	),
+	security(())
+)]
+pub async fn backchannel_logout(
+    State(state): State<Arc<AppState>>,
+    ValidatedQuery(query): ValidatedQuery<BackchannelLogoutQueryParams>,
+    Form(payload): Form<BackchannelLogoutPayload>,
+) -> Result<HeaderMap, ApiError> {
+    let openid = state
+        .openid
+        .get(query.provider.as_deref())
+        .ok_or(ApiError::UnknownOpenIdProvider)?;
+    let logout = openid.verify_logout_token(&payload.logout_token).await?;
+
+    // Subjects without an account here have no sessions to end
+    let user_id = match &logout.subject {
+        Some(subject) => {
+            match User::get_by_upstream_id(&state.db, &openid.issuer, subject).await? {
+                Some(user) => Some(user.id),
+                None => return Ok(no_store()),
+            }
+        }
+        None => None,
+    };
+    let revoked = Session::delete_upstream(
+        &state.db,
+        &openid.issuer,
+        user_id.as_deref(),
+        logout.sid.as_deref(),
+    )
+    .await?;
+    info!(
+        "{} ended {revoked} sessions through a back-channel logout",
+        openid.name
+    );
+
+    Ok(no_store())
+}
+
+fn no_store() -> HeaderMap {
+    HeaderMap::from_iter([(header::CACHE_CONTROL, "no-store".parse().unwrap())])
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RefreshPayload {
+    /// Defaults to the `iceblink_refresh` cookie.
+    pub refresh_token: Option<String>,
+}
+
+#[utoipa::path(
+	method(post),
+	path = "/v1/oauth/refresh",
+	tag = "user",
+	request_body(content = Option<RefreshPayload>, description = "Can be left out by browsers, which send the refresh token as a cookie"),
+	responses(
+		(status = OK, description = "New tokens of the session. The refresh token can't be used again", body = SessionTokensResponse),
+		(status = UNAUTHORIZED, description = "The refresh token is invalid, or the session has expired. Using a replaced refresh token again revokes its session"),
+		(status = TOO_MANY_REQUESTS, description = "Too many invalid refresh tokens came from the address recently")
+	),
+	security(())
+)]
+pub async fn refresh(
+    State(state): State<Arc<AppState>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request_headers: HeaderMap,
+    cookie_jar: CookieJar,
+    payload: Option<JSON<RefreshPayload>>,
+) -> Result<(HeaderMap, JSON<SessionTokensResponse>), ApiError> {
+    let refresh_token = payload
+        .and_then(|JSON(payload)| payload.refresh_token)
+        .or_else(|| {
+            cookie_jar
+                .get(auth::REFRESH_COOKIE)
+                .map(|cookie| cookie.value().to_string())
+        })
+        .ok_or(ApiError::MissingAuthentication)?;
+    let lockout_keys: Vec<String> = connections::client_ip(
+        peer.map(|ConnectInfo(peer)| peer.ip()),
+        &request_headers,
+        &state.settings.trusted_proxies,
+    )
+    .map(lockout::address_key)
+    .into_iter()
+    .collect();
+    state.lockouts.check(&lockout_keys)?;
+
+    let tokens = auth::refresh_session(
+        &state.db,
+        &state.jwt_keys,
+        &refresh_token,
+        state.settings.session_idle_timeout,
+    )
+    .await
+    .inspect_err(|err| {
+        if matches!(err, ApiError::InvalidAuthentication) {
+            state
+                .lockouts
+                .record_failure("refresh_token", &lockout_keys);
+        }
+    })?;
+    Ok((tokens.cookie_headers(), JSON(tokens.into())))
+}
+
+/// Attempts a single user may make per minute to approve or deny a device, so user codes can't be
+/// guessed.
+pub const DEVICE_VERIFICATIONS_PER_MINUTE: u32 = 10;
+
+#[derive(Serialize, ToSchema)]
+pub struct DeviceAuthorizationResponse {
+    /// Secret the device polls `POST /v1/oauth/device/token` with.
+    pub device_code: String,
+    /// Shown to the user, who enters it at the verification URI on a device which is logged in.
+    pub user_code: String,
+    pub verification_uri: String,
+    /// Verification URI with the user code filled in, e.g. for a QR code.
+    pub verification_uri_complete: String,
+    /// Seconds until the codes expire.
+    pub expires_in: u64,
+    /// Seconds the device has to wait between polls.
+    pub interval: u64,
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/oauth/device",
+	tag = "user",
+	responses(
+		(status = OK, description = "Started logging in a device without a browser. Show the user code and poll for the login", body = DeviceAuthorizationResponse),
+		(status = TOO_MANY_REQUESTS, description = "Too many devices are logging in at once, in total or from the same network")
+	),
+	security(())
+)]
+pub async fn device_authorization(
+    State(state): State<Arc<AppState>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request_headers: HeaderMap,
+) -> Result<JSON<DeviceAuthorizationResponse>, ApiError> {
+    let client = connections::client_ip(
+        peer.map(|ConnectInfo(peer)| peer.ip()),
+        &request_headers,
+        &state.settings.trusted_proxies,
+    );
+    let (device_code, user_code) = state
+        .device_authorizations
+        .start(client)
+        .ok_or(ApiError::RateLimited)?;
+    let verification_uri = format!("{}/device", state.settings.frontfacing);
+
+    Ok(JSON(DeviceAuthorizationResponse {
+        device_code,
+        verification_uri_complete: format!("{verification_uri}?code={user_code}"),
+        verification_uri,
+        user_code,
+        expires_in: device::LIFETIME.as_secs(),
+        interval: device::POLLING_INTERVAL.as_secs(),
+    }))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct DeviceTokenPayload {
+    pub device_code: String,
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/oauth/device/token",
+	tag = "user",
+	request_body = DeviceTokenPayload,
+	responses(
+		(status = OK, description = "The user approved the device, starting a session", body = SessionTokensResponse),
+		(status = BAD_REQUEST, description = "Not approved yet (`AuthorizationPending`), polled too often (`SlowDown`), denied (`AccessDenied`), or the device code expired (`DeviceCodeExpired`)")
+	),
+	security(())
+)]
+pub async fn device_token(
+    State(state): State<Arc<AppState>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request_headers: HeaderMap,
+    JSON(payload): JSON<DeviceTokenPayload>,
+) -> Result<JSON<SessionTokensResponse>, ApiError> {
+    let user_id = match state.device_authorizations.poll(&payload.device_code) {
+        DevicePoll::Approved(user_id) => user_id,
+        DevicePoll::Pending => return Err(ApiError::AuthorizationPending),
+        DevicePoll::SlowDown => return Err(ApiError::SlowDown),
+        DevicePoll::Denied => return Err(ApiError::AccessDenied),
+        DevicePoll::Expired => return Err(ApiError::DeviceCodeExpired),
+    };
+    let user = User::get_by_id(&state.db, user_id)
+        .await?
+        .ok_or(ApiError::JwtUserGone)?;
+    auth::require_active(&user)?;
+
+    let remote_ip = connections::client_ip(
+        peer.map(|ConnectInfo(peer)| peer.ip()),
+        &request_headers,
+        &state.settings.trusted_proxies,
+    );
+    let tokens = auth::create_jwt(
+        &state.db,
+        &user,
+        &state.jwt_keys,
+        &request_headers,
+        remote_ip,
+    )
+    .await?;
+    mail::notify_login(&state, &user, &tokens.session_id).await?;
+    Ok(JSON(tokens.into()))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct DeviceVerifyPayload {
+    /// As shown on the device. Case, spaces and dashes don't matter.
+    pub user_code: String,
+    /// Denies the device instead of logging it in.
+    #[serde(default)]
+    pub deny: bool,
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/oauth/device/verify",
+	tag = "user",
+	request_body = DeviceVerifyPayload,
+	responses(
+		(status = NO_CONTENT, description = "The device is logged in as the user, or denied"),
+		(status = NOT_FOUND, description = "No device is waiting for the user code"),
+		(status = TOO_MANY_REQUESTS, description = "Too many attempts in the last minute")
+	),
+)]
+pub async fn verify_device(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    JSON(payload): JSON<DeviceVerifyPayload>,
+) -> Result<StatusCode, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+    if !state.device_verify_limiter.try_hit(&user.id) {
+        return Err(ApiError::RateLimited);
+    }
+
+    let decided = if payload.deny {
+        state.device_authorizations.deny(&payload.user_code)
+    } else {
+        state
+            .device_authorizations
+            .approve(&payload.user_code, user.id)
     };
+    if !decided {
+        return Err(ApiError::UnknownUserCode);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
 
-    let (_, cookie) = auth::create_jwt(&user, state.settings.jwt_secret.clone()).await;
-    headers.insert(header::SET_COOKIE, cookie.to_string().parse().unwrap());
-    Ok((StatusCode::OK, headers))
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct AccountDeletionResponse {
+    /// Unix timestamp (seconds) at which the account is deleted, unless the deletion is cancelled.
+    pub delete_at: i64,
 }
 
 #[utoipa::path(
@@ -82,37 +737,1066 @@ pub async fn oauth(
 	path = "/v1/user",
 	tag = "user",
 	responses(
-		(status = NO_CONTENT, description = "Successfully deleted")
+		(status = ACCEPTED, description = "Scheduled the account for deletion after the grace period of the instance. Deleting it again keeps the earlier date", body = AccountDeletionResponse),
+		(status = NO_CONTENT, description = "Successfully deleted, as the instance has no grace period"),
+		(status = UNAUTHORIZED, description = "Neither logged in within the last five minutes, nor confirmed with a security key"),
+		(status = FORBIDDEN, description = "Authenticated with a token without the account:delete scope")
+	),
+	params(
+		("X-Step-Up-Token" = Option<String>, Header, description = "Step-up token from `POST /v1/user/step-up/finish`, required unless logged in recently")
 	),
 )]
 pub async fn delete_account(
     State(state): State<Arc<AppState>>,
-    Extension(user): Extension<User>,
+    Extension(mut user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    issued_at: Option<Extension<IssuedAt>>,
+    client_id: ClientId,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    auth::require_scope(&scopes, TokenScope::AccountDelete)?;
+    auth::require_step_up(&state, &user, issued_at.map(|Extension(iat)| iat), &headers)?;
+
+    let grace = state.settings.account_deletion_grace;
+    if grace.is_zero() {
+        user.delete(&state.db).await?;
+        state
+            .events
+            .publish(Event::user_deleted(user.id, client_id));
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+
+    let delete_at = match user.delete_at {
+        Some(delete_at) => delete_at,
+        None => {
+            let delete_at = chrono::Utc::now().timestamp() + grace.as_secs() as i64;
+            user.set_delete_at(&state.db, Some(delete_at)).await?;
+            info!("{} scheduled their account for deletion", user.id);
+            mail::notify(&state, &user, Notification::DeletionScheduled { delete_at });
+            // Tells the other devices of the user, so a stolen session deleting the account
+            // doesn't go unnoticed
+            state.events.publish(Event::user(
+                EventKind::UserDeletionScheduled,
+                user.id,
+                client_id,
+            ));
+            delete_at
+        }
+    };
+
+    Ok((
+        StatusCode::ACCEPTED,
+        JSON(AccountDeletionResponse { delete_at }),
+    )
+        .into_response())
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/user/delete/cancel",
+	tag = "user",
+	responses(
+		(status = NO_CONTENT, description = "The account is no longer scheduled for deletion, if it was"),
+		(status = FORBIDDEN, description = "Authenticated with a token without the account:delete scope")
+	),
+)]
+pub async fn cancel_account_deletion(
+    State(state): State<Arc<AppState>>,
+    Extension(mut user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
 ) -> Result<StatusCode, ApiError> {
-    user.delete(&state.db).await?;
+    auth::require_scope(&scopes, TokenScope::AccountDelete)?;
+
+    if user.delete_at.is_some() {
+        user.set_delete_at(&state.db, None).await?;
+        info!("{} cancelled the deletion of their account", user.id);
+        state.events.publish(Event::user(
+            EventKind::UserDeletionCancelled,
+            user.id,
+            client_id,
+        ));
+    }
+
     Ok(StatusCode::NO_CONTENT)
 }
 
+#[utoipa::path(
+	get,
+	path = "/v1/user/export",
+	tag = "user",
+	params(
+		("X-Step-Up-Token" = Option<String>, Header, description = "Step-up token from `POST /v1/user/step-up/finish`, required unless logged in recently")
+	),
+	responses(
+		(status = OK, description = "Zip archive (`iceblink-data.zip`) of everything stored about the user: their profile, codes with their history, folders, tags, embedded icons, sessions and API tokens, as JSON files", content_type = "application/zip"),
+		(status = UNAUTHORIZED, description = "Neither logged in within the last five minutes, nor confirmed with a security key"),
+		(status = FORBIDDEN, description = "Authenticated with a token which can't read the whole account")
+	),
+)]
+pub async fn export_data(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    issued_at: Option<Extension<IssuedAt>>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+    auth::require_step_up(&state, &user, issued_at.map(|Extension(iat)| iat), &headers)?;
+
+    let archive = UserArchive::collect(&state.db, user.clone()).await?;
+    mail::notify_export(&state, &user, "data archive", archive.codes());
+    let zip = tokio::task::spawn_blocking(move || archive.into_zip())
+        .await
+        .expect("Unable to write archive");
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"iceblink-data.zip\"",
+            ),
+        ],
+        Body::from(zip),
+    )
+        .into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ProfileResponse {
+    pub id: String,
+    pub username: String,
+    pub display_name: String,
+    pub avatar_url: String,
+    /// Unix timestamp (seconds) at which the account is deleted, if the user scheduled it.
+    pub delete_at: Option<i64>,
+}
+
+impl From<User> for ProfileResponse {
+    fn from(user: User) -> Self {
+        ProfileResponse {
+            id: user.id,
+            username: user.username,
+            display_name: user.display_name,
+            avatar_url: user.avatar_url,
+            delete_at: user.delete_at,
+        }
+    }
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/user/refresh",
+	tag = "user",
+	responses(
+		(status = OK, description = "Fetched the profile from the OpenID provider again, updating the display name and avatar", body = ProfileResponse),
+		(status = CONFLICT, description = "The request wasn't authenticated by a session which logged in with the identity the account was created with, or the provider rejected the session's access token")
+	),
+)]
+pub async fn refresh_profile(
+    State(state): State<Arc<AppState>>,
+    Extension(mut user): Extension<User>,
+    session: Option<Extension<Session>>,
+) -> Result<JSON<ProfileResponse>, ApiError> {
+    // API tokens and password logins have nothing to fetch the profile with
+    let Some(Extension(session)) = session else {
+        return Err(ApiError::UpstreamLoginRequired);
+    };
+    let (Some(issuer), Some(access_token)) =
+        (session.upstream_issuer, session.upstream_access_token)
+    else {
+        return Err(ApiError::UpstreamLoginRequired);
+    };
+    let openid = state
+        .openid
+        .by_issuer(&issuer)
+        .filter(|openid| openid.issuer == user.upstream_issuer)
+        .ok_or(ApiError::UpstreamLoginRequired)?;
+
+    let userinfo =
+        openid
+            .clone()
+            .userinfo(access_token)
+            .await
+            .map_err(|err| match err.status() {
+                Some(StatusCode::UNAUTHORIZED) => ApiError::UpstreamLoginRequired,
+                _ => ApiError::OpenIdUserinfoFail(err),
+            })?;
+    if userinfo.id != user.upstream_userid {
+        return Err(ApiError::UpstreamLoginRequired);
+    }
+    sync_profile(&state, &mut user, openid, &userinfo).await?;
+
+    Ok(JSON(user.into()))
+}
+
 #[derive(Debug, Serialize, Deserialize, utoipa::ToSchema, Clone)]
 pub struct ChecksumResponse {
+    /// Root of the hashes, changing with any code. Compare the buckets when it differs.
     pub checksum: String,
+    /// Hash of the codes in every bucket, by the first character of their ids. Empty buckets are
+    /// left out.
+    pub buckets: BTreeMap<String, String>,
+    /// Hash of every code in the requested bucket, by id. Fetch those which differ with
+    /// `POST /v1/codes/get`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub codes: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ChecksumQuery {
+    /// Bucket to include the hashes of every code of, e.g. `D`.
+    pub bucket: Option<String>,
+}
+
+impl Validate for ChecksumQuery {
+    fn validate(&mut self) -> Result<(), ApiError> {
+        match &self.bucket {
+            Some(bucket)
+                if bucket.chars().count() != 1
+                    || !bucket.chars().all(|c| c.is_ascii_alphanumeric()) =>
+            {
+                Err(ApiError::BadRequest(
+                    "`bucket` has to be a single letter or digit.".into(),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 #[utoipa::path(
 	get,
 	path = "/v1/user/checksum",
 	tag = "user",
+	params(ChecksumQuery),
 	responses(
-		(status = OK, description = "Successfully calculated checksum of user", body = ChecksumResponse)
+		(status = OK, description = "Successfully calculated checksums of the codes of the user", body = ChecksumResponse),
+		(status = BAD_REQUEST, description = "Invalid bucket")
 	),
 )]
 pub async fn checksum(
     State(state): State<Arc<AppState>>,
     Extension(user): Extension<User>,
+    ValidatedQuery(query): ValidatedQuery<ChecksumQuery>,
 ) -> Result<JSON<ChecksumResponse>, ApiError> {
-    let codes = Code::get_many(&state.db, user.clone().id).await?;
+    let codes = Code::get_many(&state.db, user.id).await?;
+    let checksums = utils::checksums(&codes);
 
+    let codes = query.bucket.map(|bucket| {
+        checksums
+            .codes
+            .into_iter()
+            .filter(|(id, _)| id.starts_with(&bucket))
+            .collect()
+    });
     Ok(JSON(ChecksumResponse {
-        checksum: utils::checksum(codes, &user),
+        checksum: checksums.root,
+        buckets: checksums.buckets,
+        codes,
+    }))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UsageResponse {
+    #[serde(flatten)]
+    pub usage: Usage,
+    /// Limits of the instance, which are the same for every user.
+    pub quota: Quota,
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/user/usage",
+	tag = "user",
+	responses(
+		(status = OK, description = "What the user stores, and how much the instance allows", body = UsageResponse)
+	),
+)]
+pub async fn usage(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+) -> Result<JSON<UsageResponse>, ApiError> {
+    Ok(JSON(UsageResponse {
+        usage: Usage::of(&state.db, &user.id).await?,
+        quota: Quota::new(&state.settings),
     }))
 }
+
+fn validate_token_name(name: &str) -> Result<(), ApiError> {
+    if name.len() > ApiToken::MAX_NAME_LENGTH {
+        return Err(ApiError::BadRequest(format!(
+            "Token names can't be longer than {} characters.",
+            ApiToken::MAX_NAME_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TokenCreatePayload {
+    /// Tells the token apart from the others of the user, e.g. `Backup script`.
+    #[serde(default)]
+    pub name: String,
+    /// Space separated scopes, e.g. `codes:read` for a backup script.
+    #[schema(value_type = String, example = "codes:read")]
+    pub scope: TokenScopes,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TokenCreateResponse {
+    pub id: String,
+    pub name: String,
+    /// The token itself. It is only shown once, as just a hash of it is stored.
+    pub token: String,
+    #[schema(value_type = String, example = "codes:read")]
+    pub scope: TokenScopes,
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/user/tokens",
+	tag = "user",
+	request_body = TokenCreatePayload,
+	responses(
+		(status = OK, description = "Successfully created API token", body = TokenCreateResponse),
+		(status = FORBIDDEN, description = "Authenticated with a token which can't create tokens")
+	),
+)]
+pub async fn create_token(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    JSON(payload): JSON<TokenCreatePayload>,
+) -> Result<JSON<TokenCreateResponse>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+
+    validate_token_name(&payload.name)?;
+
+    let (api_token, token) = ApiToken::generate(user.id, payload.name, &payload.scope);
+    api_token.insert(&state.db).await?;
+
+    Ok(JSON(TokenCreateResponse {
+        id: api_token.id,
+        name: api_token.name,
+        token,
+        scope: payload.scope,
+    }))
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct TokenResponse {
+    pub id: String,
+    pub name: String,
+    /// `None` for tokens with a scope this server doesn't know, which are rejected.
+    #[schema(value_type = Option<String>, example = "codes:read")]
+    pub scope: Option<TokenScopes>,
+    pub created_at: i64,
+    /// `None` if the token was never used. Updated at most once a minute.
+    pub last_used_at: Option<i64>,
+    /// Whether the request was authenticated by this token.
+    pub current: bool,
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/user/tokens",
+	tag = "user",
+	responses(
+		(status = OK, description = "API tokens of the user, most recently created first. The tokens themselves are never shown again", body = Vec<TokenResponse>)
+	),
+)]
+pub async fn list_tokens(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    current: Option<Extension<ApiToken>>,
+) -> Result<JSON<Vec<TokenResponse>>, ApiError> {
+    let current_id = current.map(|Extension(api_token)| api_token.id);
+    let api_tokens = ApiToken::get_for_user(&state.db, &user.id).await?;
+
+    Ok(JSON(
+        api_tokens
+            .into_iter()
+            .map(|api_token| TokenResponse {
+                current: current_id.as_ref() == Some(&api_token.id),
+                scope: api_token.scopes(),
+                id: api_token.id,
+                name: api_token.name,
+                created_at: api_token.created_at,
+                last_used_at: api_token.last_used_at,
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TokenRenamePayload {
+    pub name: String,
+}
+
+#[utoipa::path(
+	patch,
+	path = "/v1/user/tokens/{id}",
+	tag = "user",
+	params(
+		("id", description = "Id of the token to rename")
+	),
+	request_body = TokenRenamePayload,
+	responses(
+		(status = NO_CONTENT, description = "Renamed the token"),
+		(status = NOT_FOUND, description = "Unable to find token")
+	),
+)]
+pub async fn rename_token(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    Path(id): Path<String>,
+    JSON(payload): JSON<TokenRenamePayload>,
+) -> Result<StatusCode, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+    validate_token_name(&payload.name)?;
+
+    let mut api_token = ApiToken::get(&state.db, &id)
+        .await?
+        .filter(|api_token| api_token.user_id == user.id)
+        .ok_or(ApiError::NotFound)?;
+    api_token.rename(&state.db, payload.name).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+	delete,
+	path = "/v1/user/tokens/{id}",
+	tag = "user",
+	params(
+		("id", description = "Id of the token to revoke")
+	),
+	responses(
+		(status = NO_CONTENT, description = "Revoked the token. It is rejected from now on"),
+		(status = NOT_FOUND, description = "Unable to find token")
+	),
+)]
+pub async fn revoke_token(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+
+    let api_token = ApiToken::get(&state.db, &id)
+        .await?
+        .filter(|api_token| api_token.user_id == user.id)
+        .ok_or(ApiError::NotFound)?;
+    api_token.delete(&state.db).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct SessionResponse {
+    pub id: String,
+    /// Coarse label of the browser or app which logged in, e.g. `Firefox on Linux`.
+    pub device: String,
+    /// Full user agent of the login, empty if none was sent.
+    pub user_agent: String,
+    /// Address of the client which last used the session, if known.
+    pub ip: Option<String>,
+    pub created_at: i64,
+    pub last_activity: i64,
+    /// Device the session belongs to, once the app registered itself at `POST /v1/devices`.
+    pub device_id: Option<String>,
+    /// Whether the request was authenticated by this session.
+    pub current: bool,
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/user/sessions",
+	tag = "user",
+	responses(
+		(status = OK, description = "Active sessions of the user, most recently active first", body = Vec<SessionResponse>)
+	),
+)]
+pub async fn list_sessions(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    current: Option<Extension<Session>>,
+) -> Result<JSON<Vec<SessionResponse>>, ApiError> {
+    let current_id = current.map(|Extension(session)| session.id);
+    let sessions = Session::get_for_user(&state.db, &user.id).await?;
+
+    Ok(JSON(
+        sessions
+            .into_iter()
+            .map(|session| SessionResponse {
+                current: current_id.as_ref() == Some(&session.id),
+                id: session.id,
+                device: session.device,
+                user_agent: session.user_agent,
+                ip: session.ip,
+                created_at: session.created_at,
+                last_activity: session.last_activity,
+                device_id: session.device_id,
+            })
+            .collect(),
+    ))
+}
+
+#[utoipa::path(
+	delete,
+	path = "/v1/user/sessions/{id}",
+	tag = "user",
+	params(
+		("id", description = "Id of the session to revoke")
+	),
+	responses(
+		(status = NO_CONTENT, description = "Revoked the session. Its JWT is rejected from now on"),
+		(status = NOT_FOUND, description = "Unable to find session")
+	),
+)]
+pub async fn revoke_session(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+
+    let session = Session::get(&state.db, &id)
+        .await?
+        .filter(|session| session.user_id == user.id)
+        .ok_or(ApiError::NotFound)?;
+    session.delete(&state.db).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/logout",
+	tag = "user",
+	responses(
+		(status = NO_CONTENT, description = "Revoked the session or API token authenticating the request. It is rejected from now on, and browsers have their cookies removed")
+	),
+)]
+pub async fn logout(
+    State(state): State<Arc<AppState>>,
+    session: Option<Extension<Session>>,
+    api_token: Option<Extension<ApiToken>>,
+) -> Result<(StatusCode, HeaderMap), ApiError> {
+    // Every JWT belongs to a session, which is checked on every request
+    if let Some(Extension(session)) = session {
+        session.delete(&state.db).await?;
+    }
+    if let Some(Extension(api_token)) = api_token {
+        api_token.delete(&state.db).await?;
+    }
+
+    Ok((StatusCode::NO_CONTENT, auth::removal_cookie_headers()))
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct IdentityLinkQueryParams {
+    code: String,
+    /// State of the login, as handed out by /v1/oauth/authorize. The browser has to send the cookie
+    /// it received there as well.
+    state: String,
+    /// Name of the OpenID provider the code is from, as listed in the instance metadata. Defaults
+    /// to the default provider.
+    provider: Option<String>,
+    /// PKCE code verifier, if the client sent a code challenge when requesting the code.
+    code_verifier: Option<String>,
+}
+
+impl Validate for IdentityLinkQueryParams {
+    fn validate(&mut self) -> Result<(), ApiError> {
+        validate_upstream_login(
+            &self.code,
+            &self.state,
+            self.provider.as_deref(),
+            self.code_verifier.as_deref(),
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct IdentityResponse {
+    /// Absent for the identity the account was created with, which can't be unlinked.
+    pub id: Option<String>,
+    /// Name of the OpenID provider, if it's still configured.
+    pub provider: Option<String>,
+    pub issuer: String,
+    /// Id of the user at the provider.
+    pub subject: String,
+    /// Absent for the identity the account was created with.
+    pub created_at: Option<i64>,
+}
+
+impl IdentityResponse {
+    fn new(
+        state: &AppState,
+        id: Option<String>,
+        issuer: String,
+        subject: String,
+        created_at: Option<i64>,
+    ) -> Self {
+        IdentityResponse {
+            id,
+            provider: state
+                .openid
+                .iter()
+                .find(|openid| openid.issuer == issuer)
+                .map(|openid| openid.name.clone()),
+            issuer,
+            subject,
+            created_at,
+        }
+    }
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/user/identities",
+	tag = "user",
+	responses(
+		(status = OK, description = "Identities at OpenID providers the user can log in with, starting with the one the account was created with", body = Vec<IdentityResponse>)
+	),
+)]
+pub async fn list_identities(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+) -> Result<JSON<Vec<IdentityResponse>>, ApiError> {
+    let linked = Identity::get_for_user(&state.db, &user.id).await?;
+
+    let primary = IdentityResponse::new(
+        &state,
+        None,
+        user.upstream_issuer,
+        user.upstream_userid,
+        None,
+    );
+    Ok(JSON(
+        std::iter::once(primary)
+            .chain(linked.into_iter().map(|identity| {
+                IdentityResponse::new(
+                    &state,
+                    Some(identity.id),
+                    identity.issuer,
+                    identity.subject,
+                    Some(identity.created_at),
+                )
+            }))
+            .collect(),
+    ))
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/user/identities",
+	tag = "user",
+	responses(
+		(status = CREATED, description = "Linked the identity the user logged in with at the provider. They can log in with it from now on", body = IdentityResponse),
+		(status = BAD_REQUEST, description = "No OpenID provider has the given name, or the state is unknown, expired or from another browser"),
+		(status = UNAUTHORIZED, description = "Not logged in within the last five minutes, or the ID token doesn't have the nonce of the login"),
+		(status = CONFLICT, description = "The identity already belongs to an account")
+	),
+	params(
+		IdentityLinkQueryParams
+	),
+)]
+pub async fn link_identity(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    issued_at: Option<Extension<IssuedAt>>,
+    cookie_jar: CookieJar,
+    ValidatedQuery(query): ValidatedQuery<IdentityLinkQueryParams>,
+) -> Result<(StatusCode, HeaderMap, JSON<IdentityResponse>), ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+    // A stolen JWT mustn't be turned into a login of its own
+    auth::require_recent_login(issued_at.map(|Extension(iat)| iat))?;
+
+    let UpstreamLogin {
+        openid, userinfo, ..
+    } = upstream_login(
+        &state,
+        &cookie_jar,
+        query.provider.as_deref(),
+        &query.state,
+        query.code,
+        query.code_verifier,
+    )
+    .await?;
+    if User::get_by_upstream_id(&state.db, &openid.issuer, &userinfo.id)
+        .await?
+        .is_some()
+    {
+        return Err(ApiError::IdentityInUse);
+    }
+
+    let identity = Identity {
+        id: utils::generate_id(16),
+        user_id: user.id,
+        issuer: openid.issuer.clone(),
+        subject: userinfo.id,
+        created_at: chrono::Utc::now().timestamp(),
+    };
+    // Losing a race against linking the same identity to another account
+    identity.insert(&state.db).await.map_err(|err| match err {
+        sqlx::Error::Database(err) if err.is_unique_violation() => ApiError::IdentityInUse,
+        err => err.into(),
+    })?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::SET_COOKIE, auth::oauth_state_removal_header());
+    Ok((
+        StatusCode::CREATED,
+        headers,
+        JSON(IdentityResponse::new(
+            &state,
+            Some(identity.id),
+            identity.issuer,
+            identity.subject,
+            Some(identity.created_at),
+        )),
+    ))
+}
+
+#[utoipa::path(
+	delete,
+	path = "/v1/user/identities/{id}",
+	tag = "user",
+	params(
+		("id", description = "Id of the linked identity to unlink")
+	),
+	responses(
+		(status = NO_CONTENT, description = "Unlinked the identity. Logging in with it creates a new account from now on"),
+		(status = NOT_FOUND, description = "Unable to find a linked identity with the id. The identity the account was created with can't be unlinked")
+	),
+)]
+pub async fn unlink_identity(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+
+    let identity = Identity::get(&state.db, &id)
+        .await?
+        .filter(|identity| identity.user_id == user.id)
+        .ok_or(ApiError::NotFound)?;
+    identity.delete(&state.db).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Preferences a single user may store.
+pub const MAX_PREFERENCES: usize = 64;
+const MAX_PREFERENCE_KEY_LENGTH: usize = 64;
+/// Length of the JSON of a single preference value.
+const MAX_PREFERENCE_VALUE_LENGTH: usize = 4096;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UserSettings {
+    /// Reject adding, cloning or renaming a code to a name another code in its folder already has.
+    pub enforce_unique_names: bool,
+    /// Email the user about security events, like logins from new devices, if the instance sends
+    /// emails and knows their address.
+    pub email_notifications: bool,
+    /// Preferences of the clients, like their theme or auto-lock timeout, synced between the
+    /// devices of the user. The server doesn't interpret them.
+    #[schema(value_type = Object)]
+    pub preferences: BTreeMap<String, serde_json::Value>,
+}
+
+impl UserSettings {
+    async fn of_user(pool: &SqlitePool, user: &User) -> Result<Self, sqlx::Error> {
+        Ok(UserSettings {
+            enforce_unique_names: user.enforce_unique_names,
+            email_notifications: user.email_notifications,
+            preferences: preferences::of_user(pool, &user.id).await?,
+        })
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct UserSettingsPayload {
+    pub enforce_unique_names: Option<bool>,
+    pub email_notifications: Option<bool>,
+    /// Preferences to set, by key. Keys set to `null` are removed, and those left out are kept.
+    #[schema(value_type = Option<Object>)]
+    pub preferences: Option<BTreeMap<String, Option<serde_json::Value>>>,
+}
+
+fn validate_preferences(
+    changes: &BTreeMap<String, Option<serde_json::Value>>,
+) -> Result<(), ApiError> {
+    for (key, value) in changes {
+        if key.is_empty()
+            || key.len() > MAX_PREFERENCE_KEY_LENGTH
+            || !key
+                .chars()
+                .all(|char| char.is_ascii_alphanumeric() || matches!(char, '_' | '-' | '.'))
+        {
+            return Err(ApiError::BadRequest(format!(
+                "The preference {key:?} has to be named with up to {MAX_PREFERENCE_KEY_LENGTH} letters, digits, _, - and ."
+            )));
+        }
+        if value
+            .as_ref()
+            .is_some_and(|value| value.to_string().len() > MAX_PREFERENCE_VALUE_LENGTH)
+        {
+            return Err(ApiError::BadRequest(format!(
+                "The value of the preference {key:?} can be at most {MAX_PREFERENCE_VALUE_LENGTH} bytes of JSON."
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/user/settings",
+	tag = "user",
+	responses(
+		(status = OK, description = "Settings of the user", body = UserSettings)
+	),
+)]
+pub async fn get_settings(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+) -> Result<JSON<UserSettings>, ApiError> {
+    Ok(JSON(UserSettings::of_user(&state.db, &user).await?))
+}
+
+#[utoipa::path(
+	patch,
+	path = "/v1/user/settings",
+	tag = "user",
+	request_body = UserSettingsPayload,
+	responses(
+		(status = OK, description = "Changed the settings. Response contains every setting", body = UserSettings),
+		(status = BAD_REQUEST, description = "A preference has an invalid key or a too large value, or there would be too many")
+	),
+)]
+pub async fn edit_settings(
+    State(state): State<Arc<AppState>>,
+    Extension(mut user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    JSON(payload): JSON<UserSettingsPayload>,
+) -> Result<JSON<UserSettings>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+
+    if let Some(changes) = &payload.preferences {
+        validate_preferences(changes)?;
+        let mut stored = preferences::of_user(&state.db, &user.id).await?;
+        for (key, value) in changes {
+            match value {
+                Some(value) => stored.insert(key.clone(), value.clone()),
+                None => stored.remove(key),
+            };
+        }
+        if stored.len() > MAX_PREFERENCES {
+            return Err(ApiError::BadRequest(format!(
+                "At most {MAX_PREFERENCES} preferences can be stored."
+            )));
+        }
+    }
+
+    if let Some(enforce) = payload.enforce_unique_names {
+        user.set_enforce_unique_names(&state.db, enforce).await?;
+    }
+    if let Some(enabled) = payload.email_notifications {
+        user.set_email_notifications(&state.db, enabled).await?;
+    }
+    if let Some(changes) = &payload.preferences {
+        preferences::update(&state.db, &user.id, changes).await?;
+    }
+    state.events.publish(Event::user(
+        EventKind::SettingsUpdated,
+        user.id.clone(),
+        client_id,
+    ));
+
+    Ok(JSON(UserSettings::of_user(&state.db, &user).await?))
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct EncryptionStatus {
+    /// Whether the content of codes is end-to-end encrypted by the clients.
+    pub enabled: bool,
+    /// Value derived from the key when encryption was enabled. Clients compare theirs against it
+    /// before encrypting anything with their key.
+    pub key_check: Option<String>,
+}
+
+impl From<&User> for EncryptionStatus {
+    fn from(user: &User) -> Self {
+        EncryptionStatus {
+            enabled: user.encryption_enabled,
+            key_check: user.encryption_key_check.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CodeContent {
+    pub id: String,
+    pub content: String,
+    /// New name of the code, which may be encrypted too. Left unchanged when missing.
+    pub display_name: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct EnableEncryptionPayload {
+    /// Value derived from the key, like a hash of it. Opaque to the server.
+    pub key_check: String,
+    /// The encrypted content of every code, prefixed with `e2e:v1:`.
+    pub codes: Vec<CodeContent>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct DisableEncryptionPayload {
+    /// The decrypted content of every code.
+    pub codes: Vec<CodeContent>,
+}
+
+/// Replaces the content of every code of the user, and enables end-to-end encryption with the key
+/// check, or disables it without one. Either every code changes, or none.
+async fn rewrite_contents(
+    state: &AppState,
+    user: &mut User,
+    client_id: ClientId,
+    contents: Vec<CodeContent>,
+    key_check: Option<String>,
+) -> Result<(), ApiError> {
+    let encrypted = key_check.is_some();
+    for code in &contents {
+        codes::validate_length("content", Some(&code.content), Code::MAX_CONTENT_LENGTH)?;
+        codes::validate_length(
+            "display_name",
+            code.display_name.as_deref(),
+            Code::MAX_DISPLAY_NAME_LENGTH,
+        )?;
+    }
+    if contents
+        .iter()
+        .any(|code| e2e::is_encrypted(&code.content) != encrypted)
+    {
+        return Err(match encrypted {
+            true => ApiError::EncryptionRequired,
+            false => ApiError::BadRequest("Send the decrypted content of every code.".into()),
+        });
+    }
+
+    let sent = contents.len();
+    let mut contents: HashMap<String, CodeContent> = contents
+        .into_iter()
+        .map(|code| (code.id.clone(), code))
+        .collect();
+
+    let mut batch = CodeBatch::begin(&state.db).await?;
+    let mut codes = Code::get_many(batch.conn(), user.id.clone()).await?;
+    if sent != codes.len()
+        || contents.len() != codes.len()
+        || codes.iter().any(|code| !contents.contains_key(&code.id))
+    {
+        return Err(ApiError::BadRequest(
+            "Send the content of every code exactly once.".into(),
+        ));
+    }
+
+    for code in &mut codes {
+        let content = contents.remove(&code.id).expect("Every code has a content");
+        batch
+            .edit()
+            .code(code)
+            .content(content.content)
+            .maybe_display_name(content.display_name)
+            .call()
+            .await?;
+    }
+    batch.clear_stale_content(&user.id).await?;
+    user.set_encryption(batch.conn(), key_check).await?;
+    batch.commit().await?;
+
+    for code in &codes {
+        state
+            .events
+            .publish(Event::code(EventKind::CodeUpdated, code, client_id.clone()));
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/user/encryption",
+	tag = "user",
+	responses(
+		(status = OK, description = "Whether the user has end-to-end encryption, and the check of its key", body = EncryptionStatus)
+	),
+)]
+pub async fn get_encryption(Extension(user): Extension<User>) -> JSON<EncryptionStatus> {
+    JSON(EncryptionStatus::from(&user))
+}
+
+#[utoipa::path(
+	put,
+	path = "/v1/user/encryption",
+	tag = "user",
+	request_body = EnableEncryptionPayload,
+	responses(
+		(status = OK, description = "Enabled end-to-end encryption, or changed its key, replacing the content of every code with the encrypted one", body = EncryptionStatus),
+		(status = BAD_REQUEST, description = "The key check is empty or too long, or not every code was sent exactly once"),
+		(status = UNPROCESSABLE_ENTITY, description = "A content isn't encrypted")
+	),
+)]
+pub async fn enable_encryption(
+    State(state): State<Arc<AppState>>,
+    Extension(mut user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    JSON(payload): JSON<EnableEncryptionPayload>,
+) -> Result<JSON<EncryptionStatus>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+    let key_check = payload.key_check.trim();
+    if key_check.is_empty() || key_check.len() > e2e::MAX_KEY_CHECK_LENGTH {
+        return Err(ApiError::BadRequest(format!(
+            "The key check must be between 1 and {} characters.",
+            e2e::MAX_KEY_CHECK_LENGTH
+        )));
+    }
+
+    let key_check = Some(key_check.to_string());
+    rewrite_contents(&state, &mut user, client_id, payload.codes, key_check).await?;
+    Ok(JSON(EncryptionStatus::from(&user)))
+}
+
+#[utoipa::path(
+	delete,
+	path = "/v1/user/encryption",
+	tag = "user",
+	request_body = DisableEncryptionPayload,
+	responses(
+		(status = OK, description = "Disabled end-to-end encryption, replacing the content of every code with the decrypted one", body = EncryptionStatus),
+		(status = BAD_REQUEST, description = "A content is still encrypted, or not every code was sent exactly once")
+	),
+)]
+pub async fn disable_encryption(
+    State(state): State<Arc<AppState>>,
+    Extension(mut user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    client_id: ClientId,
+    JSON(payload): JSON<DisableEncryptionPayload>,
+) -> Result<JSON<EncryptionStatus>, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+    rewrite_contents(&state, &mut user, client_id, payload.codes, None).await?;
+    Ok(JSON(EncryptionStatus::from(&user)))
+}
@@ -0,0 +1,619 @@
+use super::{ApiError, JSON};
+use crate::{
+    auth::{self, IssuedAt},
+    connections, mail,
+    models::{
+        credentials::WebauthnCredential,
+        tokens::{TokenScope, TokenScopes},
+        user::User,
+    },
+    utils,
+    webauthn::{
+        PasskeyAuth, PendingAuthentication, PendingKeyRegistration, PendingRegistration,
+        CHALLENGE_LIFETIME,
+    },
+    AppState,
+};
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, StatusCode},
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, sync::Arc};
+use utoipa::ToSchema;
+use webauthn_rs::prelude::*;
+
+fn passkeys(state: &AppState) -> Result<&PasskeyAuth, ApiError> {
+    state
+        .passkeys
+        .as_deref()
+        .ok_or(ApiError::PasskeysUnavailable)
+}
+
+/// Persists the updated signature counter of the credential used for an authentication.
+async fn update_counters(
+    state: &AppState,
+    user_id: String,
+    result: &AuthenticationResult,
+) -> Result<(), ApiError> {
+    for mut credential in WebauthnCredential::get_for_user(&state.db, user_id).await? {
+        let Ok(mut passkey) = serde_json::from_str::<Passkey>(&credential.credential) else {
+            continue;
+        };
+
+        if passkey.update_credential(result) == Some(true) {
+            credential
+                .update_credential(
+                    &state.db,
+                    serde_json::to_string(&passkey).expect("Unable to serialize passkey"),
+                )
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+fn stored_passkeys(credentials: &[WebauthnCredential]) -> Vec<Passkey> {
+    credentials
+        .iter()
+        .filter_map(|c| serde_json::from_str(&c.credential).ok())
+        .collect()
+}
+
+/// Starts a session for the user. Logins to an existing account, rather than one `registered` by
+/// the request, are emailed about if they're from a new device.
+async fn login(
+    state: &AppState,
+    user: &User,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request_headers: &HeaderMap,
+    registered: bool,
+) -> Result<HeaderMap, ApiError> {
+    auth::require_active(user)?;
+    let remote_ip = connections::client_ip(
+        peer.map(|ConnectInfo(peer)| peer.ip()),
+        request_headers,
+        &state.settings.trusted_proxies,
+    );
+    let tokens =
+        auth::create_jwt(&state.db, user, &state.jwt_keys, request_headers, remote_ip).await?;
+    if !registered {
+        mail::notify_login(state, user, &tokens.session_id).await?;
+    }
+    Ok(tokens.cookie_headers())
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct PasskeyRegisterStartPayload {
+    pub username: String,
+    pub display_name: Option<String>,
+    /// Token of a solved captcha. Required when the instance has a captcha set up.
+    pub captcha: Option<String>,
+    /// Invite minted by an admin. Required when registration is invite-only.
+    pub invite: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct PasskeyRegisterChallenge {
+    pub challenge_id: String,
+    /// Options to pass to `navigator.credentials.create()`.
+    #[schema(value_type = Object)]
+    pub options: CreationChallengeResponse,
+}
+
+#[utoipa::path(
+	method(post),
+	path = "/v1/webauthn/register/start",
+	tag = "user",
+	request_body = PasskeyRegisterStartPayload,
+	responses(
+		(status = OK, description = "Challenge to be answered by the authenticator", body = PasskeyRegisterChallenge),
+		(status = FORBIDDEN, description = "Creating an account requires a valid captcha, or an invite, or registration is closed"),
+		(status = CONFLICT, description = "Username is taken")
+	),
+	security(())
+)]
+pub async fn register_start(
+    State(state): State<Arc<AppState>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request_headers: HeaderMap,
+    JSON(payload): JSON<PasskeyRegisterStartPayload>,
+) -> Result<JSON<PasskeyRegisterChallenge>, ApiError> {
+    let passkeys = passkeys(&state)?;
+
+    if User::get_by_username(&state.db, payload.username.clone())
+        .await?
+        .is_some()
+    {
+        return Err(ApiError::UsernameTaken);
+    }
+
+    let remote_ip = connections::client_ip(
+        peer.map(|ConnectInfo(peer)| peer.ip()),
+        &request_headers,
+        &state.settings.trusted_proxies,
+    );
+    state
+        .registration
+        .check(
+            &state.db,
+            payload.invite.as_deref(),
+            payload.captcha.as_deref(),
+            remote_ip,
+        )
+        .await?;
+
+    let user_handle = Uuid::new_v4();
+    let display_name = payload
+        .display_name
+        .unwrap_or_else(|| payload.username.clone());
+
+    let (options, registration) = passkeys
+        .webauthn
+        .start_passkey_registration(user_handle, &payload.username, &display_name, None)
+        .map_err(ApiError::PasskeyRejected)?;
+
+    let challenge_id = passkeys.begin_registration(PendingRegistration {
+        username: payload.username,
+        display_name,
+        invite: payload.invite,
+        user_handle,
+        state: registration,
+    });
+
+    Ok(JSON(PasskeyRegisterChallenge {
+        challenge_id,
+        options,
+    }))
+}
+
+#[derive(Deserialize, Serialize, ToSchema)]
+pub struct PasskeyRegisterFinishPayload {
+    pub challenge_id: String,
+    #[schema(value_type = Object)]
+    pub credential: RegisterPublicKeyCredential,
+}
+
+#[utoipa::path(
+	method(post),
+	path = "/v1/webauthn/register/finish",
+	tag = "user",
+	request_body = PasskeyRegisterFinishPayload,
+	responses(
+		(status = OK, description = "Registered and logged in"),
+		(status = FORBIDDEN, description = "The invite was used by another registration in the meantime")
+	),
+	security(())
+)]
+pub async fn register_finish(
+    State(state): State<Arc<AppState>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request_headers: HeaderMap,
+    JSON(payload): JSON<PasskeyRegisterFinishPayload>,
+) -> Result<(StatusCode, HeaderMap), ApiError> {
+    let passkeys = passkeys(&state)?;
+    let pending = passkeys
+        .finish_registration(&payload.challenge_id)
+        .ok_or(ApiError::NotFound)?;
+
+    let passkey = passkeys
+        .webauthn
+        .finish_passkey_registration(&payload.credential, &pending.state)
+        .map_err(ApiError::PasskeyRejected)?;
+
+    // The username might have been taken while the challenge was being answered
+    if User::get_by_username(&state.db, pending.username.clone())
+        .await?
+        .is_some()
+    {
+        return Err(ApiError::UsernameTaken);
+    }
+
+    let user = User {
+        id: utils::generate_id(16),
+        username: pending.username,
+        display_name: pending.display_name,
+        avatar_url: "".to_string(),
+        upstream_userid: format!("webauthn:{}", pending.user_handle),
+        upstream_issuer: "webauthn".into(),
+        is_admin: false,
+        enforce_unique_names: false,
+        encryption_enabled: false,
+        encryption_key_check: None,
+        suspended_at: None,
+        delete_at: None,
+        email: None,
+        email_notifications: true,
+    };
+    state
+        .registration
+        .redeem(&state.db, pending.invite.as_deref(), &user.id)
+        .await?;
+    user.insert(&state.db).await?;
+
+    WebauthnCredential {
+        id: utils::generate_id(16),
+        user_id: user.id.clone(),
+        user_handle: pending.user_handle.to_string(),
+        credential: serde_json::to_string(&passkey).expect("Unable to serialize passkey"),
+        created_at: chrono::Utc::now().timestamp(),
+        name: "Passkey".into(),
+    }
+    .insert(&state.db)
+    .await?;
+
+    Ok((
+        StatusCode::OK,
+        login(&state, &user, peer, &request_headers, true).await?,
+    ))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct PasskeyAuthStartPayload {
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct PasskeyAuthChallenge {
+    pub challenge_id: String,
+    /// Options to pass to `navigator.credentials.get()`.
+    #[schema(value_type = Object)]
+    pub options: RequestChallengeResponse,
+}
+
+#[utoipa::path(
+	method(post),
+	path = "/v1/webauthn/auth/start",
+	tag = "user",
+	request_body = PasskeyAuthStartPayload,
+	responses(
+		(status = OK, description = "Challenge to be answered by the authenticator", body = PasskeyAuthChallenge),
+		(status = NOT_FOUND, description = "No user with passkeys by that username")
+	),
+	security(())
+)]
+pub async fn auth_start(
+    State(state): State<Arc<AppState>>,
+    JSON(payload): JSON<PasskeyAuthStartPayload>,
+) -> Result<JSON<PasskeyAuthChallenge>, ApiError> {
+    let passkeys = passkeys(&state)?;
+    let user = User::get_by_username(&state.db, payload.username)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    let credentials =
+        stored_passkeys(&WebauthnCredential::get_for_user(&state.db, user.id.clone()).await?);
+
+    if credentials.is_empty() {
+        return Err(ApiError::NotFound);
+    }
+
+    let (options, authentication) = passkeys
+        .webauthn
+        .start_passkey_authentication(&credentials)
+        .map_err(ApiError::PasskeyRejected)?;
+
+    let challenge_id = passkeys.begin_authentication(PendingAuthentication {
+        user_id: user.id,
+        state: authentication,
+    });
+
+    Ok(JSON(PasskeyAuthChallenge {
+        challenge_id,
+        options,
+    }))
+}
+
+#[derive(Deserialize, Serialize, ToSchema)]
+pub struct PasskeyAuthFinishPayload {
+    pub challenge_id: String,
+    #[schema(value_type = Object)]
+    pub credential: PublicKeyCredential,
+}
+
+#[utoipa::path(
+	method(post),
+	path = "/v1/webauthn/auth/finish",
+	tag = "user",
+	request_body = PasskeyAuthFinishPayload,
+	responses(
+		(status = OK, description = "Logged in")
+	),
+	security(())
+)]
+pub async fn auth_finish(
+    State(state): State<Arc<AppState>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request_headers: HeaderMap,
+    JSON(payload): JSON<PasskeyAuthFinishPayload>,
+) -> Result<(StatusCode, HeaderMap), ApiError> {
+    let passkeys = passkeys(&state)?;
+    let pending = passkeys
+        .finish_authentication(&payload.challenge_id)
+        .ok_or(ApiError::NotFound)?;
+
+    let result = passkeys
+        .webauthn
+        .finish_passkey_authentication(&payload.credential, &pending.state)
+        .map_err(ApiError::PasskeyRejected)?;
+
+    let user = User::get_by_id(&state.db, pending.user_id.clone())
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    update_counters(&state, pending.user_id, &result).await?;
+
+    Ok((
+        StatusCode::OK,
+        login(&state, &user, peer, &request_headers, false).await?,
+    ))
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct SecurityKeyResponse {
+    pub id: String,
+    pub name: String,
+    /// Unix timestamp (seconds) of the registration.
+    pub created_at: i64,
+}
+
+impl From<WebauthnCredential> for SecurityKeyResponse {
+    fn from(credential: WebauthnCredential) -> Self {
+        SecurityKeyResponse {
+            id: credential.id,
+            name: credential.name,
+            created_at: credential.created_at,
+        }
+    }
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/user/security-keys",
+	tag = "user",
+	responses(
+		(status = OK, description = "Security keys and passkeys of the user", body = Vec<SecurityKeyResponse>)
+	),
+)]
+pub async fn list_security_keys(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+) -> Result<JSON<Vec<SecurityKeyResponse>>, ApiError> {
+    let credentials = WebauthnCredential::get_for_user(&state.db, user.id).await?;
+    Ok(JSON(credentials.into_iter().map(Into::into).collect()))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct SecurityKeyRegisterStartPayload {
+    /// Tells the key apart from the others of the user, e.g. `YubiKey`.
+    pub name: String,
+}
+
+#[utoipa::path(
+	method(post),
+	path = "/v1/user/security-keys/register/start",
+	tag = "user",
+	request_body = SecurityKeyRegisterStartPayload,
+	params(
+		("X-Step-Up-Token" = Option<String>, Header, description = "Step-up token from `POST /v1/user/step-up/finish`, required unless logged in recently")
+	),
+	responses(
+		(status = OK, description = "Challenge to be answered by the new security key", body = PasskeyRegisterChallenge),
+		(status = UNAUTHORIZED, description = "Neither logged in within the last five minutes, nor confirmed with a security key"),
+		(status = FORBIDDEN, description = "Authenticated with a token without the full scope")
+	),
+)]
+pub async fn security_key_register_start(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    issued_at: Option<Extension<IssuedAt>>,
+    headers: HeaderMap,
+    JSON(payload): JSON<SecurityKeyRegisterStartPayload>,
+) -> Result<JSON<PasskeyRegisterChallenge>, ApiError> {
+    let passkeys = passkeys(&state)?;
+    auth::require_scope(&scopes, TokenScope::Full)?;
+    let name = payload.name.trim().to_string();
+    if name.is_empty() || name.len() > WebauthnCredential::MAX_NAME_LENGTH {
+        return Err(ApiError::BadRequest(format!(
+            "Security key names must be between 1 and {} characters long.",
+            WebauthnCredential::MAX_NAME_LENGTH
+        )));
+    }
+    // Otherwise a stolen token could add a key of its own, and step up with it
+    auth::require_step_up(&state, &user, issued_at.map(|Extension(iat)| iat), &headers)?;
+
+    let credentials = WebauthnCredential::get_for_user(&state.db, user.id.clone()).await?;
+    // Every credential of a user shares their user handle
+    let user_handle = credentials
+        .first()
+        .and_then(|credential| Uuid::parse_str(&credential.user_handle).ok())
+        .unwrap_or_else(Uuid::new_v4);
+    let exclude = stored_passkeys(&credentials)
+        .iter()
+        .map(|passkey| passkey.cred_id().clone())
+        .collect();
+
+    let (options, registration) = passkeys
+        .webauthn
+        .start_passkey_registration(
+            user_handle,
+            &user.username,
+            &user.display_name,
+            Some(exclude),
+        )
+        .map_err(ApiError::PasskeyRejected)?;
+
+    let challenge_id = passkeys.begin_key_registration(PendingKeyRegistration {
+        user_id: user.id,
+        name,
+        user_handle,
+        state: registration,
+    });
+
+    Ok(JSON(PasskeyRegisterChallenge {
+        challenge_id,
+        options,
+    }))
+}
+
+#[utoipa::path(
+	method(post),
+	path = "/v1/user/security-keys/register/finish",
+	tag = "user",
+	request_body = PasskeyRegisterFinishPayload,
+	responses(
+		(status = CREATED, description = "Registered the security key", body = SecurityKeyResponse),
+		(status = NOT_FOUND, description = "The challenge is unknown, expired or of another user")
+	),
+)]
+pub async fn security_key_register_finish(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    JSON(payload): JSON<PasskeyRegisterFinishPayload>,
+) -> Result<(StatusCode, JSON<SecurityKeyResponse>), ApiError> {
+    let passkeys = passkeys(&state)?;
+    let pending = passkeys
+        .finish_key_registration(&payload.challenge_id)
+        .filter(|pending| pending.user_id == user.id)
+        .ok_or(ApiError::NotFound)?;
+
+    let passkey = passkeys
+        .webauthn
+        .finish_passkey_registration(&payload.credential, &pending.state)
+        .map_err(ApiError::PasskeyRejected)?;
+
+    let credential = WebauthnCredential {
+        id: utils::generate_id(16),
+        user_id: user.id,
+        user_handle: pending.user_handle.to_string(),
+        credential: serde_json::to_string(&passkey).expect("Unable to serialize passkey"),
+        created_at: chrono::Utc::now().timestamp(),
+        name: pending.name,
+    };
+    credential.insert(&state.db).await?;
+
+    Ok((StatusCode::CREATED, JSON(credential.into())))
+}
+
+#[utoipa::path(
+	delete,
+	path = "/v1/user/security-keys/{id}",
+	tag = "user",
+	params(
+		("id", description = "Id of the security key to remove"),
+		("X-Step-Up-Token" = Option<String>, Header, description = "Step-up token from `POST /v1/user/step-up/finish`, required unless logged in recently")
+	),
+	responses(
+		(status = NO_CONTENT, description = "Removed the security key"),
+		(status = BAD_REQUEST, description = "The user logs in with passkeys, and this is the last one"),
+		(status = UNAUTHORIZED, description = "Neither logged in within the last five minutes, nor confirmed with a security key"),
+		(status = FORBIDDEN, description = "Authenticated with a token without the full scope"),
+		(status = NOT_FOUND, description = "The user has no security key with the id")
+	),
+)]
+pub async fn remove_security_key(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    issued_at: Option<Extension<IssuedAt>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+    let credential = WebauthnCredential::get(&state.db, &id, &user.id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    if user.upstream_issuer == "webauthn"
+        && WebauthnCredential::get_for_user(&state.db, user.id.clone())
+            .await?
+            .len()
+            <= 1
+    {
+        return Err(ApiError::BadRequest(
+            "You log in with passkeys, so the last one can't be removed.".into(),
+        ));
+    }
+    auth::require_step_up(&state, &user, issued_at.map(|Extension(iat)| iat), &headers)?;
+
+    credential.delete(&state.db).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+	method(post),
+	path = "/v1/user/step-up/start",
+	tag = "user",
+	responses(
+		(status = OK, description = "Challenge to be answered by one of the security keys of the user", body = PasskeyAuthChallenge),
+		(status = NOT_FOUND, description = "The user has no security keys")
+	),
+)]
+pub async fn step_up_start(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+) -> Result<JSON<PasskeyAuthChallenge>, ApiError> {
+    let passkeys = passkeys(&state)?;
+    let credentials =
+        stored_passkeys(&WebauthnCredential::get_for_user(&state.db, user.id.clone()).await?);
+    if credentials.is_empty() {
+        return Err(ApiError::NotFound);
+    }
+
+    let (options, authentication) = passkeys
+        .webauthn
+        .start_passkey_authentication(&credentials)
+        .map_err(ApiError::PasskeyRejected)?;
+
+    let challenge_id = passkeys.begin_step_up(PendingAuthentication {
+        user_id: user.id,
+        state: authentication,
+    });
+
+    Ok(JSON(PasskeyAuthChallenge {
+        challenge_id,
+        options,
+    }))
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct StepUpResponse {
+    /// Sent in the `X-Step-Up-Token` header of a sensitive action, like deleting the account or
+    /// exporting every code. Only works once.
+    pub token: String,
+    /// Unix timestamp (seconds) after which the token can't be used anymore.
+    pub expires_at: i64,
+}
+
+#[utoipa::path(
+	method(post),
+	path = "/v1/user/step-up/finish",
+	tag = "user",
+	request_body = PasskeyAuthFinishPayload,
+	responses(
+		(status = OK, description = "Confirmed with a security key, allowing a single sensitive action", body = StepUpResponse),
+		(status = NOT_FOUND, description = "The challenge is unknown, expired or of another user")
+	),
+)]
+pub async fn step_up_finish(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    JSON(payload): JSON<PasskeyAuthFinishPayload>,
+) -> Result<JSON<StepUpResponse>, ApiError> {
+    let passkeys = passkeys(&state)?;
+    let pending = passkeys
+        .finish_step_up(&payload.challenge_id)
+        .filter(|pending| pending.user_id == user.id)
+        .ok_or(ApiError::NotFound)?;
+
+    let result = passkeys
+        .webauthn
+        .finish_passkey_authentication(&payload.credential, &pending.state)
+        .map_err(ApiError::PasskeyRejected)?;
+    update_counters(&state, user.id.clone(), &result).await?;
+
+    Ok(JSON(StepUpResponse {
+        token: passkeys.grant_step_up(user.id),
+        expires_at: chrono::Utc::now().timestamp() + CHALLENGE_LIFETIME.as_secs() as i64,
+    }))
+}
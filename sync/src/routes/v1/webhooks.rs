@@ -0,0 +1,240 @@
+use super::{ApiError, JSON};
+use crate::{
+    auth,
+    events::EventKind,
+    models::{
+        tokens::{TokenScope, TokenScopes},
+        user::User,
+        webhooks::{DeliveryState, Webhook, WebhookDelivery},
+    },
+    ssrf, utils, AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension,
+};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::sync::Arc;
+use tracing::info;
+use utoipa::ToSchema;
+
+/// Webhooks a single user may register.
+pub const MAX_WEBHOOKS_PER_USER: i64 = 10;
+const MAX_URL_LENGTH: usize = 2048;
+/// Deliveries listed in the delivery log of a webhook.
+const DELIVERY_LOG_LENGTH: i64 = 50;
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct WebhookResponse {
+    pub id: String,
+    pub url: String,
+    /// Kinds of events the webhook receives, empty for every kind.
+    pub events: Vec<String>,
+    pub created_at: i64,
+}
+
+impl From<Webhook> for WebhookResponse {
+    fn from(webhook: Webhook) -> Self {
+        WebhookResponse {
+            id: webhook.id,
+            url: webhook.url,
+            events: webhook
+                .events
+                .split(' ')
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect(),
+            created_at: webhook.created_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct WebhookCreateResponse {
+    #[serde(flatten)]
+    pub webhook: WebhookResponse,
+    /// Secret the payloads are signed with. Only shown once.
+    pub secret: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct WebhookCreatePayload {
+    /// URL the events are posted to.
+    pub url: String,
+    /// Kinds of events to receive, e.g. `code.created`. Receives every kind if left out or empty.
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct WebhookDeliveryResponse {
+    #[serde(flatten)]
+    pub delivery: WebhookDelivery,
+    pub state: DeliveryState,
+}
+
+/// Registers a webhook for the user, or for the instance if `user_id` is unset. Only webhooks of
+/// the instance may post to internal hosts, like other services on the same network.
+pub(super) async fn create(
+    pool: &SqlitePool,
+    user_id: Option<String>,
+    payload: WebhookCreatePayload,
+) -> Result<WebhookCreateResponse, ApiError> {
+    let allow_internal = user_id.is_none();
+    let valid_url = Url::parse(&payload.url).is_ok_and(|url| {
+        matches!(url.scheme(), "http" | "https")
+            && url
+                .host_str()
+                .is_some_and(|host| allow_internal || !ssrf::is_internal_host(host))
+    });
+    if !valid_url || payload.url.len() > MAX_URL_LENGTH {
+        return Err(ApiError::BadRequest(
+            "The URL of a webhook has to be a public http or https URL.".into(),
+        ));
+    }
+    let mut events = vec![];
+    for name in &payload.events {
+        let kind = EventKind::parse(name)
+            .ok_or_else(|| ApiError::BadRequest(format!("Unknown event {name:?}.")))?;
+        if !events.contains(&kind.name()) {
+            events.push(kind.name());
+        }
+    }
+
+    if let Some(user_id) = &user_id {
+        if Webhook::count_for_user(pool, user_id).await? >= MAX_WEBHOOKS_PER_USER {
+            return Err(ApiError::BadRequest(format!(
+                "You can register up to {MAX_WEBHOOKS_PER_USER} webhooks."
+            )));
+        }
+    }
+
+    let webhook = Webhook {
+        id: utils::generate_id(16),
+        user_id,
+        url: payload.url,
+        secret: format!("whsec_{}", utils::generate_id(32)),
+        events: events.join(" "),
+        created_at: chrono::Utc::now().timestamp(),
+    };
+    webhook.insert(pool).await?;
+
+    Ok(WebhookCreateResponse {
+        secret: webhook.secret.clone(),
+        webhook: webhook.into(),
+    })
+}
+
+/// The delivery log of the webhook of the user, or of the instance if `user_id` is unset.
+pub(super) async fn deliveries(
+    pool: &SqlitePool,
+    id: &str,
+    user_id: Option<&str>,
+) -> Result<Vec<WebhookDeliveryResponse>, ApiError> {
+    let webhook = Webhook::get(pool, id, user_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    Ok(
+        WebhookDelivery::list(pool, &webhook.id, DELIVERY_LOG_LENGTH)
+            .await?
+            .into_iter()
+            .map(|delivery| WebhookDeliveryResponse {
+                state: delivery.state(),
+                delivery,
+            })
+            .collect(),
+    )
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/webhooks",
+	tag = "webhooks",
+	responses(
+		(status = OK, description = "Webhooks of the user, oldest first", body = Vec<WebhookResponse>)
+	),
+)]
+pub async fn list_webhooks(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+) -> Result<JSON<Vec<WebhookResponse>>, ApiError> {
+    let webhooks = Webhook::list(&state.db, Some(&user.id)).await?;
+    Ok(JSON(webhooks.into_iter().map(Into::into).collect()))
+}
+
+#[utoipa::path(
+	post,
+	path = "/v1/webhooks",
+	tag = "webhooks",
+	request_body = WebhookCreatePayload,
+	responses(
+		(status = CREATED, description = "Registered the webhook. Events are posted to it from now on, signed with the secret", body = WebhookCreateResponse),
+		(status = BAD_REQUEST, description = "The URL isn't a public http or https URL, an event is unknown, or the user has too many webhooks")
+	),
+)]
+pub async fn create_webhook(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    JSON(payload): JSON<WebhookCreatePayload>,
+) -> Result<(StatusCode, JSON<WebhookCreateResponse>), ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+
+    let created = create(&state.db, Some(user.id.clone()), payload).await?;
+    info!("{} registered the webhook {}", user.id, created.webhook.id);
+
+    Ok((StatusCode::CREATED, JSON(created)))
+}
+
+#[utoipa::path(
+	delete,
+	path = "/v1/webhooks/{id}",
+	tag = "webhooks",
+	params(
+		("id", description = "Id of the webhook to remove")
+	),
+	responses(
+		(status = NO_CONTENT, description = "Removed the webhook with its delivery log"),
+		(status = NOT_FOUND, description = "Unable to find webhook")
+	),
+)]
+pub async fn delete_webhook(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(scopes): Extension<TokenScopes>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    auth::require_scope(&scopes, TokenScope::Full)?;
+
+    let webhook = Webhook::get(&state.db, &id, Some(&user.id))
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    webhook.delete(&state.db).await?;
+    info!("{} removed the webhook {}", user.id, webhook.id);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/webhooks/{id}/deliveries",
+	tag = "webhooks",
+	params(
+		("id", description = "Id of the webhook")
+	),
+	responses(
+		(status = OK, description = "The 50 most recent deliveries to the webhook, newest first", body = Vec<WebhookDeliveryResponse>),
+		(status = NOT_FOUND, description = "Unable to find webhook")
+	),
+)]
+pub async fn list_webhook_deliveries(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(id): Path<String>,
+) -> Result<JSON<Vec<WebhookDeliveryResponse>>, ApiError> {
+    Ok(JSON(deliveries(&state.db, &id, Some(&user.id)).await?))
+}
@@ -0,0 +1,182 @@
+use reqwest::{ClientBuilder, Url};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Why requests to a URL chosen by a user aren't sent, as they might reach into the network of
+/// the server.
+#[derive(Debug, PartialEq)]
+pub enum GuardError {
+    /// The URL isn't http(s), or its host resolves to an address which isn't public.
+    Blocked,
+    /// Unable to resolve the host.
+    Unresolvable,
+}
+
+/// Resolves the host of the URL, and makes sure none of its addresses are internal. Returns the
+/// host and the address requests should be pinned to, so the host can't be rebound to an internal
+/// address between checking and connecting.
+pub async fn resolve_public(url: &Url) -> Result<(String, SocketAddr), GuardError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(GuardError::Blocked);
+    }
+
+    let host = url.host_str().ok_or(GuardError::Blocked)?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addresses: Vec<SocketAddr> = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|_| GuardError::Unresolvable)?
+            .collect(),
+    };
+
+    match addresses.first() {
+        None => Err(GuardError::Unresolvable),
+        Some(_) if addresses.iter().any(|a| !is_public_address(a.ip())) => Err(GuardError::Blocked),
+        Some(address) => Ok((host.to_string(), *address)),
+    }
+}
+
+/// Checks the host of the URL with [`resolve_public`], and pins the client to the checked address.
+/// Pinning has no effect on proxied requests, but the host is checked regardless.
+pub async fn pin(client: ClientBuilder, url: &Url) -> Result<ClientBuilder, GuardError> {
+    let (host, address) = resolve_public(url).await?;
+    Ok(client.resolve(&host, address))
+}
+
+pub fn is_public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(embedded) => is_public_ipv4(embedded),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7
+                    || (ip.segments()[0] & 0xfe00) == 0xfc00
+                    // Link local, fe80::/10
+                    || (ip.segments()[0] & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [first, second, ..] = ip.octets();
+
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // This network, 0.0.0.0/8
+        || first == 0
+        // Shared address space, 100.64.0.0/10
+        || (first == 100 && (second & 0b1100_0000) == 64)
+        // Reserved, 240.0.0.0/4, including the broadcast address
+        || first >= 240)
+}
+
+/// IPv4 address carried by an IPv6 address, which connects to the IPv4 address: IPv4-mapped
+/// (`::ffff:0:0/96`), IPv4-translated (`::ffff:0:0:0/96`), IPv4-compatible (`::/96`) and NAT64
+/// (`64:ff9b::/96`) addresses. `::` and `::1` count as `0.0.0.0/8`, which isn't public either.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let embedded = Ipv4Addr::from((u32::from(segments[6]) << 16) | u32::from(segments[7]));
+
+    match segments[..6] {
+        [0, 0, 0, 0, 0, 0 | 0xffff] | [0, 0, 0, 0, 0xffff, 0] | [0x64, 0xff9b, 0, 0, 0, 0] => {
+            Some(embedded)
+        }
+        _ => None,
+    }
+}
+
+/// Cheap check of the host alone, for quick feedback on URLs users enter. Requests still have to be
+/// guarded with [`pin`], as names may resolve to internal addresses.
+pub fn is_internal_host(host: &str) -> bool {
+    match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => !is_public_address(ip),
+        Err(_) => host == "localhost" || host.ends_with(".localhost"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    #[gtest]
+    fn public_addresses() {
+        for ip in [
+            "1.1.1.1",
+            "142.250.74.46",
+            "2606:4700:4700::1111",
+            "::ffff:1.1.1.1",
+            "64:ff9b::1.1.1.1",
+        ] {
+            expect_that!(is_public_address(ip.parse().unwrap()), is_true());
+        }
+    }
+
+    #[gtest]
+    fn internal_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "0.1.2.3",
+            "224.0.0.1",
+            "239.255.255.250",
+            "240.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "ff02::1",
+            "::ffff:127.0.0.1",
+            "::ffff:0:10.0.0.1",
+            "::127.0.0.1",
+            "64:ff9b::127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            expect_that!(is_public_address(ip.parse().unwrap()), is_false());
+        }
+    }
+
+    #[gtest]
+    fn internal_hosts() {
+        expect_that!(is_internal_host("localhost"), is_true());
+        expect_that!(is_internal_host("[::1]"), is_true());
+        expect_that!(is_internal_host("192.168.0.1"), is_true());
+        expect_that!(is_internal_host("example.com"), is_false());
+    }
+
+    #[tokio::test]
+    #[gtest]
+    async fn resolved_hosts_are_guarded() {
+        let (host, address) = resolve_public(&Url::parse("https://1.1.1.1/hook").unwrap())
+            .await
+            .unwrap();
+        expect_that!(host, eq("1.1.1.1"));
+        expect_that!(address, eq("1.1.1.1:443".parse::<SocketAddr>().unwrap()));
+
+        for url in [
+            "http://127.0.0.1:8080/",
+            "http://[::ffff:7f00:1]/",
+            // Names are resolved, rather than only compared
+            "http://localhost:8080/",
+            "ftp://1.1.1.1/",
+        ] {
+            expect_that!(
+                resolve_public(&Url::parse(url).unwrap()).await,
+                err(eq(&GuardError::Blocked))
+            );
+        }
+    }
+}
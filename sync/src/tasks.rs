@@ -0,0 +1,470 @@
+use crate::{
+    events::{ClientId, Event, EventKind, Events},
+    icons::{self, IconStore, IconStoreError},
+    models::{
+        codes::Code, devices::Device, push_subscriptions::PushSubscription, user::User,
+        webhooks::WebhookDelivery,
+    },
+    webhooks,
+    webpush::{WebPush, WebPushError},
+};
+use sqlx::SqlitePool;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        broadcast::{error::RecvError, Receiver},
+        Notify,
+    },
+    time::Instant,
+};
+use tracing::{debug, info, warn};
+
+/// Periodically removes codes which have passed their `expires_at`.
+pub async fn expire_codes(pool: SqlitePool, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+
+    loop {
+        interval.tick().await;
+        purge_expired_codes(&pool).await;
+    }
+}
+
+pub async fn purge_expired_codes(pool: &SqlitePool) -> u64 {
+    match Code::delete_expired(pool, chrono::Utc::now().timestamp()).await {
+        Ok(removed) => {
+            debug!("Removed {removed} expired codes");
+            removed
+        }
+        Err(err) => {
+            warn!("Unable to remove expired codes: {err}");
+            0
+        }
+    }
+}
+
+/// Periodically removes tombstones of codes deleted longer than `retention` ago.
+pub async fn expire_tombstones(pool: SqlitePool, retention: Duration, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+
+    loop {
+        interval.tick().await;
+        purge_tombstones(&pool, retention).await;
+    }
+}
+
+pub async fn purge_tombstones(pool: &SqlitePool, retention: Duration) -> u64 {
+    let before = chrono::Utc::now().timestamp() - retention.as_secs() as i64;
+
+    match Code::purge_tombstones(pool, before).await {
+        Ok(removed) => {
+            debug!("Removed {removed} tombstones");
+            removed
+        }
+        Err(err) => {
+            warn!("Unable to remove tombstones: {err}");
+            0
+        }
+    }
+}
+
+/// Periodically empties codes deleted longer than `retention` ago from the trash.
+pub async fn expire_trash(pool: SqlitePool, retention: Duration, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+
+    loop {
+        interval.tick().await;
+        empty_trash(&pool, retention).await;
+    }
+}
+
+pub async fn empty_trash(pool: &SqlitePool, retention: Duration) -> u64 {
+    let before = chrono::Utc::now().timestamp() - retention.as_secs() as i64;
+
+    match Code::empty_trash(pool, before).await {
+        Ok(emptied) => {
+            debug!("Emptied {emptied} codes from the trash");
+            emptied
+        }
+        Err(err) => {
+            warn!("Unable to empty the trash: {err}");
+            0
+        }
+    }
+}
+
+/// Periodically deletes accounts whose grace period after the user deleted them has passed.
+pub async fn expire_accounts(pool: SqlitePool, events: Events, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+
+    loop {
+        interval.tick().await;
+        purge_deleted_accounts(&pool, &events).await;
+    }
+}
+
+pub async fn purge_deleted_accounts(pool: &SqlitePool, events: &Events) -> u64 {
+    match User::delete_scheduled(pool, chrono::Utc::now().timestamp()).await {
+        Ok(deleted) => {
+            for user_id in &deleted {
+                info!("Deleted the account {user_id} after its grace period");
+                events.publish(Event::user_deleted(user_id.clone(), ClientId::default()));
+            }
+            deleted.len() as u64
+        }
+        Err(err) => {
+            warn!("Unable to delete accounts scheduled for deletion: {err}");
+            0
+        }
+    }
+}
+
+/// Changes of a device within this many seconds of its last recorded one aren't recorded again,
+/// so importing many codes doesn't write its `last_push_at` for every one of them.
+const DEVICE_PUSH_PRECISION: i64 = 60;
+
+/// Records the changes registered devices make as they're published, as the `last_push_at` of the
+/// devices. Stops once the bus is gone.
+pub async fn track_device_pushes(pool: SqlitePool, mut receiver: Receiver<Event>) {
+    let mut recorded: HashMap<String, i64> = HashMap::new();
+
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                debug!("Missed {missed} events while tracking device pushes");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let Some(device_id) = &event.device_id else {
+            continue;
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        if recorded
+            .get(device_id)
+            .is_some_and(|last| now - last < DEVICE_PUSH_PRECISION)
+        {
+            continue;
+        }
+        if record_device_push(&pool, &event).await {
+            recorded.insert(device_id.clone(), now);
+        }
+    }
+}
+
+/// Marks the device which caused the event as having pushed a change now. Returns whether it did.
+pub async fn record_device_push(pool: &SqlitePool, event: &Event) -> bool {
+    let Some(device_id) = &event.device_id else {
+        return false;
+    };
+
+    match Device::record_push(pool, device_id, chrono::Utc::now().timestamp()).await {
+        Ok(()) => true,
+        Err(err) => {
+            warn!("Unable to record the push of a device: {err}");
+            false
+        }
+    }
+}
+
+/// Changes of a user following each other within this window are announced with a single push, so
+/// importing many codes doesn't ping every device for every one of them.
+const PUSH_COALESCE_WINDOW: Duration = Duration::from_secs(2);
+/// Pushes replace undelivered ones with the same topic, so offline devices get a single ping.
+const PUSH_TOPIC: &str = "changes";
+const PUSH_PAYLOAD: &[u8] = br#"{"kind":"changes.available"}"#;
+
+/// Pings the subscribed devices of users with Web Push when their codes, folders or settings
+/// change. Stops once the bus is gone.
+pub async fn push_changes(pool: SqlitePool, web_push: WebPush, mut receiver: Receiver<Event>) {
+    // Users with changes to announce, with the devices which made them
+    let mut pending: HashMap<String, HashSet<Option<String>>> = HashMap::new();
+    let mut deadline = Instant::now();
+
+    loop {
+        let received = if pending.is_empty() {
+            receiver.recv().await
+        } else {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(received) => received,
+                Err(_) => {
+                    for (user_id, origins) in pending.drain() {
+                        announce_changes(&pool, &web_push, &user_id, &origins).await;
+                    }
+                    continue;
+                }
+            }
+        };
+        let event = match received {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                debug!("Missed {missed} events while pushing changes");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if !matches!(
+            event.kind,
+            EventKind::CodeCreated
+                | EventKind::CodeUpdated
+                | EventKind::CodeDeleted
+                | EventKind::FolderCreated
+                | EventKind::FolderUpdated
+                | EventKind::FolderDeleted
+                | EventKind::SettingsUpdated
+        ) {
+            continue;
+        }
+
+        if pending.is_empty() {
+            deadline = Instant::now() + PUSH_COALESCE_WINDOW;
+        }
+        pending
+            .entry(event.user_id)
+            .or_default()
+            .insert(event.device_id);
+    }
+}
+
+/// Pings the subscribed devices of the user about changes made by `origins`, with `None` standing
+/// for clients which aren't registered devices. Devices which made all the changes themselves are
+/// left out. Returns how many devices were pinged.
+pub async fn announce_changes(
+    pool: &SqlitePool,
+    web_push: &WebPush,
+    user_id: &str,
+    origins: &HashSet<Option<String>>,
+) -> u64 {
+    let subscriptions = match PushSubscription::get_for_user(pool, user_id).await {
+        Ok(subscriptions) => subscriptions,
+        Err(err) => {
+            warn!("Unable to get the push subscriptions of {user_id}: {err}");
+            return 0;
+        }
+    };
+
+    let mut pinged = 0;
+    for subscription in subscriptions {
+        if origins.len() == 1 && origins.contains(&Some(subscription.device_id.clone())) {
+            continue;
+        }
+
+        match web_push.send(&subscription, PUSH_TOPIC, PUSH_PAYLOAD).await {
+            Ok(()) => pinged += 1,
+            Err(WebPushError::Expired) => {
+                debug!(
+                    "Removing the expired push subscription of {}",
+                    subscription.device_id
+                );
+                if let Err(err) = subscription.delete_expired(pool).await {
+                    warn!("Unable to remove an expired push subscription: {err}");
+                }
+            }
+            Err(err) => warn!(
+                "Unable to push changes to {}: {err}",
+                subscription.device_id
+            ),
+        }
+    }
+    pinged
+}
+
+/// Queues deliveries of events to the webhooks receiving them as they're published, waking up
+/// [`deliver_webhooks`] through `queued`. Stops once the bus is gone.
+pub async fn queue_webhook_deliveries(
+    pool: SqlitePool,
+    mut receiver: Receiver<Event>,
+    queued: Arc<Notify>,
+) {
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Missed {missed} events while queueing webhook deliveries");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        match webhooks::enqueue(&pool, &event).await {
+            Ok(0) => {}
+            Ok(_) => queued.notify_one(),
+            Err(err) => warn!("Unable to queue webhook deliveries: {err}"),
+        }
+    }
+}
+
+/// Attempts deliveries to webhooks once they're queued, and retries failed ones when they're due.
+/// Checks for due retries at least `every` so often.
+pub async fn deliver_webhooks(
+    pool: SqlitePool,
+    proxy: Option<reqwest::Proxy>,
+    queued: Arc<Notify>,
+    every: Duration,
+) {
+    let mut interval = tokio::time::interval(every);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = queued.notified() => {}
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        match webhooks::deliver_due(&pool, proxy.as_ref(), now).await {
+            Ok(delivered) if delivered > 0 => debug!("Delivered {delivered} webhook events"),
+            Ok(_) => {}
+            Err(err) => warn!("Unable to deliver webhook events: {err}"),
+        }
+    }
+}
+
+/// Periodically removes finished webhook deliveries older than `retention` from the delivery log.
+pub async fn expire_webhook_deliveries(pool: SqlitePool, retention: Duration, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+
+    loop {
+        interval.tick().await;
+        purge_webhook_deliveries(&pool, retention).await;
+    }
+}
+
+pub async fn purge_webhook_deliveries(pool: &SqlitePool, retention: Duration) -> u64 {
+    let before = chrono::Utc::now().timestamp() - retention.as_secs() as i64;
+
+    match WebhookDelivery::prune(pool, before).await {
+        Ok(removed) => {
+            debug!("Removed {removed} webhook deliveries");
+            removed
+        }
+        Err(err) => {
+            warn!("Unable to remove webhook deliveries: {err}");
+            0
+        }
+    }
+}
+
+/// Stores the icons of codes as they're created or changed, fetching the favicons of those with a
+/// website but no icon, after catching up on the codes whose icon isn't stored yet. Waits `delay`
+/// between the codes it catches up on, as to not hammer websites. Stops once the bus is gone.
+pub async fn fetch_icons(
+    pool: SqlitePool,
+    store: IconStore,
+    events: Events,
+    mut receiver: Receiver<Event>,
+    delay: Duration,
+) {
+    fetch_missing_icons(&pool, &store, &events, delay).await;
+
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                debug!("Missed {missed} events while fetching icons");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let (EventKind::CodeCreated | EventKind::CodeUpdated, Some(code_id)) =
+            (event.kind, event.code_id)
+        else {
+            continue;
+        };
+
+        match Code::get(&pool, code_id, event.user_id).await {
+            Ok(Some(code)) => {
+                fetch_icon(&pool, &store, &events, code).await;
+            }
+            Ok(None) => {}
+            Err(err) => warn!("Unable to get a code to fetch its icon: {err}"),
+        }
+    }
+}
+
+/// Stores the icons of every code whose icon isn't stored yet. Returns how many codes were linked
+/// to their icon.
+pub async fn fetch_missing_icons(
+    pool: &SqlitePool,
+    store: &IconStore,
+    events: &Events,
+    delay: Duration,
+) -> u64 {
+    let codes = match Code::missing_icons(pool).await {
+        Ok(codes) => codes,
+        Err(err) => {
+            warn!("Unable to find codes missing an icon: {err}");
+            return 0;
+        }
+    };
+
+    let mut fetched = 0;
+    for (i, code) in codes.into_iter().enumerate() {
+        if i != 0 {
+            tokio::time::sleep(delay).await;
+        }
+        if fetch_icon(pool, store, events, code).await {
+            fetched += 1;
+        }
+    }
+    if fetched > 0 {
+        info!("Fetched the icons of {fetched} codes");
+    }
+    fetched
+}
+
+/// Stores the icon of the code, announcing the change if it got the favicon of its website as its
+/// icon. Returns whether the code was linked to its icon.
+async fn fetch_icon(pool: &SqlitePool, store: &IconStore, events: &Events, mut code: Code) -> bool {
+    let icon_url = code.icon_url.clone();
+    let linked = match icons::populate_icon(pool, store, &mut code).await {
+        Ok(linked) => linked,
+        Err(IconStoreError::Database(err)) => {
+            warn!("Unable to store the icon of {}: {err}", code.id);
+            false
+        }
+        // Plenty of websites have no usable icon
+        Err(err) => {
+            debug!("Unable to fetch the icon of {}: {err:?}", code.id);
+            false
+        }
+    };
+
+    if code.icon_url != icon_url {
+        events.publish(Event::code(
+            EventKind::CodeUpdated,
+            &code,
+            ClientId::default(),
+        ));
+    }
+    linked
+}
+
+/// Periodically removes icons no code has been linked to for [`icons::UNUSED_ICON_GRACE`].
+pub async fn expire_icons(pool: SqlitePool, store: IconStore, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+
+    loop {
+        interval.tick().await;
+        purge_unused_icons(&pool, &store).await;
+    }
+}
+
+pub async fn purge_unused_icons(pool: &SqlitePool, store: &IconStore) -> u64 {
+    let before = chrono::Utc::now().timestamp() - icons::UNUSED_ICON_GRACE.as_secs() as i64;
+
+    match icons::prune_icons(pool, store, before).await {
+        Ok(removed) => {
+            debug!("Removed {removed} unused icons");
+            removed
+        }
+        Err(err) => {
+            warn!("Unable to remove unused icons: {err}");
+            0
+        }
+    }
+}
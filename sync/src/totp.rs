@@ -0,0 +1,292 @@
+use data_encoding::BASE32_NOPAD;
+use hmac::{digest::KeyInit, Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use subtle::{Choice, ConstantTimeEq};
+
+/// Seconds every code is valid for, unless the code specifies otherwise.
+pub const PERIOD: i64 = 30;
+pub const DIGITS: u32 = 6;
+/// Upper bound for the configurable skew, beyond which guessing codes becomes too easy.
+pub const MAX_SKEW: u8 = 10;
+
+/// Decodes a base32 secret, ignoring case, spaces and padding.
+pub fn decode_secret(secret: &str) -> Option<Vec<u8>> {
+    let normalized: String = secret
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '=')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+
+    BASE32_NOPAD
+        .decode(normalized.as_bytes())
+        .ok()
+        .filter(|key| !key.is_empty())
+}
+
+/// Hash function of the HMAC, named as in the `algorithm` parameter of otpauth URIs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Algorithm {
+    #[default]
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "SHA1" => Some(Algorithm::Sha1),
+            "SHA256" => Some(Algorithm::Sha256),
+            "SHA512" => Some(Algorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Algorithm::Sha1 => "SHA1",
+            Algorithm::Sha256 => "SHA256",
+            Algorithm::Sha512 => "SHA512",
+        }
+    }
+}
+
+/// Format of the generated codes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Kind {
+    /// Decimal codes, as specified in RFC 6238.
+    #[default]
+    Totp,
+    /// Steam Guard codes: 5 characters of [`STEAM_ALPHABET`], otherwise generated like TOTP codes
+    /// with the defaults of RFC 6238.
+    Steam,
+}
+
+impl Kind {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "totp" => Some(Kind::Totp),
+            "steam" => Some(Kind::Steam),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Totp => "totp",
+            Kind::Steam => "steam",
+        }
+    }
+}
+
+/// Characters of Steam Guard codes, leaving out those which are easily confused.
+pub const STEAM_ALPHABET: &[u8] = b"23456789BCDFGHJKMNPQRTVWXY";
+
+/// How the codes of a secret are generated. Most services use the defaults of RFC 6238.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Params {
+    pub kind: Kind,
+    pub algorithm: Algorithm,
+    pub digits: u32,
+    /// Seconds every code is valid for.
+    pub period: i64,
+}
+
+impl Params {
+    /// Steam Guard codes can't be configured.
+    pub const STEAM: Params = Params {
+        kind: Kind::Steam,
+        algorithm: Algorithm::Sha1,
+        digits: 5,
+        period: PERIOD,
+    };
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            kind: Kind::Totp,
+            algorithm: Algorithm::Sha1,
+            digits: DIGITS,
+            period: PERIOD,
+        }
+    }
+}
+
+fn sign<M: Mac + KeyInit>(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = <M as KeyInit>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The code for the given time step, as specified in RFC 6238 with HMAC-SHA1.
+pub fn generate(key: &[u8], step: i64) -> String {
+    generate_with(key, step, Params::default())
+}
+
+/// The code for the given time step, using the kind, hash function and amount of digits of the
+/// params.
+pub fn generate_with(key: &[u8], step: i64, params: Params) -> String {
+    let message = step.to_be_bytes();
+    let hash = match params.algorithm {
+        Algorithm::Sha1 => sign::<Hmac<Sha1>>(key, &message),
+        Algorithm::Sha256 => sign::<Hmac<Sha256>>(key, &message),
+        Algorithm::Sha512 => sign::<Hmac<Sha512>>(key, &message),
+    };
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    match params.kind {
+        Kind::Totp => format!(
+            "{:0width$}",
+            binary % 10u32.pow(params.digits),
+            width = params.digits as usize
+        ),
+        Kind::Steam => {
+            let mut rest = binary;
+            (0..params.digits)
+                .map(|_| {
+                    let c = STEAM_ALPHABET[(rest % STEAM_ALPHABET.len() as u32) as usize];
+                    rest /= STEAM_ALPHABET.len() as u32;
+                    c as char
+                })
+                .collect()
+        }
+    }
+}
+
+/// Compares the code with every candidate, without returning early, so timing reveals nothing about which matched.
+fn constant_time_any(
+    candidates: &[String],
+    code: &str,
+    eq: impl Fn(&[u8], &[u8]) -> Choice,
+) -> bool {
+    candidates
+        .iter()
+        .fold(Choice::from(0), |matched, candidate| {
+            matched | eq(candidate.as_bytes(), code.as_bytes())
+        })
+        .into()
+}
+
+/// Checks the code against the time step of `now`, and up to `skew` steps before and after it.
+pub fn verify(key: &[u8], code: &str, now: i64, skew: u8) -> bool {
+    verify_with(key, code, now, skew, Params::default())
+}
+
+/// Like [`verify`], for codes generated with other params.
+pub fn verify_with(key: &[u8], code: &str, now: i64, skew: u8, params: Params) -> bool {
+    let step = now.div_euclid(params.period);
+    let candidates: Vec<String> = (-(skew as i64)..=skew as i64)
+        .map(|offset| generate_with(key, step + offset, params))
+        .collect();
+
+    constant_time_any(&candidates, code, |a, b| a.ct_eq(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+    use std::cell::Cell;
+
+    /// The SHA1 key from the test vectors of RFC 6238.
+    const RFC_KEY: &[u8] = b"12345678901234567890";
+
+    #[gtest]
+    fn rfc_test_vectors() {
+        // RFC 6238 uses 8 digits, the last 6 of which are the 6 digit code
+        expect_that!(generate(RFC_KEY, 59 / PERIOD), eq("287082"));
+        expect_that!(generate(RFC_KEY, 1111111109 / PERIOD), eq("081804"));
+        expect_that!(generate(RFC_KEY, 1234567890 / PERIOD), eq("005924"));
+        expect_that!(generate(RFC_KEY, 2000000000 / PERIOD), eq("279037"));
+    }
+
+    #[gtest]
+    fn rfc_test_vectors_sha256_and_sha512() {
+        let params = |algorithm| Params {
+            kind: Kind::Totp,
+            algorithm,
+            digits: 8,
+            period: PERIOD,
+        };
+
+        // RFC 6238 pads the keys to the length of the hash output
+        expect_that!(
+            generate_with(
+                b"12345678901234567890123456789012",
+                59 / PERIOD,
+                params(Algorithm::Sha256)
+            ),
+            eq("46119246")
+        );
+        expect_that!(
+            generate_with(
+                b"1234567890123456789012345678901234567890123456789012345678901234",
+                59 / PERIOD,
+                params(Algorithm::Sha512)
+            ),
+            eq("90693936")
+        );
+    }
+
+    #[gtest]
+    fn steam_codes() {
+        expect_that!(
+            generate_with(RFC_KEY, 59 / PERIOD, Params::STEAM),
+            eq("PV9M4")
+        );
+        expect_that!(
+            generate_with(RFC_KEY, 1111111109 / PERIOD, Params::STEAM),
+            eq("PY4YB")
+        );
+        expect_that!(
+            verify_with(RFC_KEY, "PY4YB", 1111111109, 0, Params::STEAM),
+            is_true()
+        );
+    }
+
+    #[gtest]
+    fn secret_decoding() {
+        expect_that!(
+            decode_secret("gezd gnbv gy3t qojq gezd gnbv gy3t qojq"),
+            some(eq(&RFC_KEY.to_vec()))
+        );
+        expect_that!(decode_secret("not base32!"), none());
+        expect_that!(decode_secret(""), none());
+    }
+
+    #[gtest]
+    fn verify_within_window() {
+        let now = 1111111109;
+        let previous = generate(RFC_KEY, now / PERIOD - 1);
+        let too_old = generate(RFC_KEY, now / PERIOD - 2);
+
+        expect_that!(verify(RFC_KEY, "081804", now, 0), is_true());
+        expect_that!(verify(RFC_KEY, &previous, now, 0), is_false());
+        expect_that!(verify(RFC_KEY, &previous, now, 1), is_true());
+        expect_that!(verify(RFC_KEY, &too_old, now, 1), is_false());
+        expect_that!(verify(RFC_KEY, &too_old, now, 2), is_true());
+    }
+
+    #[gtest]
+    fn comparison_checks_every_candidate() {
+        let candidates = vec!["081804".to_string(), "287082".into(), "005924".into()];
+        let comparisons = Cell::new(0);
+
+        let matched = constant_time_any(&candidates, "081804", |a, b| {
+            comparisons.set(comparisons.get() + 1);
+            a.ct_eq(b)
+        });
+
+        expect_that!(matched, is_true());
+        expect_that!(comparisons.get(), eq(3));
+    }
+}
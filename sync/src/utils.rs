@@ -1,25 +1,123 @@
-use crate::models::{codes::Code, user::User};
+use crate::models::codes::Code;
+use axum::http::{header, HeaderMap};
 use rand::distributions::{Alphanumeric, DistString};
 use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 
 pub fn generate_id(len: usize) -> String {
     Alphanumeric.sample_string(&mut rand::thread_rng(), len)
 }
 
-pub fn checksum(codes: Vec<Code>, _user: &User) -> String {
-    let mut content = "".to_owned();
+/// Hashes of a user's codes, grouped in buckets by the first character of their id. Clients
+/// compare the root first, then the buckets, and finally the codes of differing buckets, to find
+/// out which codes changed without downloading all of them.
+#[derive(Debug, PartialEq)]
+pub struct Checksums {
+    /// Hash of every bucket and its hash.
+    pub root: String,
+    /// Hash of the hashes of every code in the bucket, by the first character of their ids. Empty
+    /// buckets are left out.
+    pub buckets: BTreeMap<String, String>,
+    /// Hash of every code, by its id.
+    pub codes: BTreeMap<String, String>,
+}
+
+fn crc32(content: &str) -> String {
+    crc32fast::hash(content.as_bytes()).to_string()
+}
+
+pub fn checksums(codes: &[Code]) -> Checksums {
+    let codes: BTreeMap<String, String> = codes
+        .iter()
+        .map(|code| {
+            (
+                code.id.clone(),
+                crc32(&(code.id.clone() + &code.fmt_for_hasher())),
+            )
+        })
+        .collect();
 
-    for code in codes {
-        content += &code.fmt_for_hasher()
+    let mut contents: BTreeMap<String, String> = BTreeMap::new();
+    for (id, hash) in &codes {
+        let bucket = id.chars().next().map(String::from).unwrap_or_default();
+        let content = contents.entry(bucket).or_default();
+        content.push_str(hash);
+        content.push('\n');
     }
+    let buckets: BTreeMap<String, String> = contents
+        .into_iter()
+        .map(|(bucket, content)| (bucket, crc32(&content)))
+        .collect();
 
-    crc32fast::hash(content.as_bytes()).to_string()
+    let root = buckets
+        .iter()
+        .map(|(bucket, hash)| format!("{bucket}:{hash}\n"))
+        .collect::<String>();
+
+    Checksums {
+        root: crc32(&root),
+        buckets,
+        codes,
+    }
 }
 
 pub fn hash_domain(domain: &str) -> String {
     base16ct::lower::encode_string(&Sha256::digest(domain))
 }
 
+/// Hex encoded SHA-256 hash of the content, which icons are stored under.
+pub fn content_hash(content: &[u8]) -> String {
+    base16ct::lower::encode_string(&Sha256::digest(content))
+}
+
+/// Escapes text for use in HTML content and quoted attributes.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Strong entity tag for the given content, quoted as required for the `ETag` header.
+pub fn etag(content: &[u8]) -> String {
+    format!("\"{}\"", content_hash(content))
+}
+
+/// Whether the `If-None-Match` header of a request matches the given entity tag.
+pub fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+/// Proxy to route outbound requests through. Hosts listed in the `NO_PROXY` environment variable
+/// are still connected to directly.
+pub fn outbound_proxy(url: &str) -> Result<reqwest::Proxy, reqwest::Error> {
+    Ok(reqwest::Proxy::all(url)?.no_proxy(reqwest::NoProxy::from_env()))
+}
+
+/// Client builder going through the proxy, if any.
+pub fn client_builder(proxy: Option<&reqwest::Proxy>) -> reqwest::ClientBuilder {
+    match proxy {
+        Some(proxy) => reqwest::Client::builder().proxy(proxy.clone()),
+        None => reqwest::Client::builder(),
+    }
+}
+
 pub const USER_AGENT: &str = concat!("Snowcone-Labs/Iceblink/", env!("CARGO_PKG_VERSION"));
 
 #[cfg(test)]
@@ -48,6 +146,98 @@ mod tests {
         }
     }
 
+    fn code(id: &str, display_name: &str) -> Code {
+        Code {
+            id: id.into(),
+            owner_id: "k0d8WrkRjK6gkc3C".into(),
+            content: "JBSWY3DPEHPK3PXP".into(),
+            display_name: display_name.into(),
+            icon_url: None,
+            website_url: None,
+            issuer: None,
+            account: None,
+            kind: "totp".into(),
+            digits: 6,
+            period: 30,
+            algorithm: "SHA1".into(),
+            expires_at: None,
+            sort_index: 0,
+            favorite: false,
+            folder_id: None,
+            created_at: 0,
+            updated_at: 0,
+            revision: 0,
+            deleted_at: None,
+        }
+    }
+
+    #[gtest]
+    fn checksums_narrow_down_changes() {
+        let codes = vec![
+            code("Ckpt4eFi1pw9fxI3", "Google"),
+            code("DxLCqi4ZlHPD8YxA", "google.com"),
+            code("Dummy0000000000a", "Dummy INC"),
+        ];
+        let checksums = checksums(&codes);
+        expect_that!(
+            checksums.buckets.keys().collect::<Vec<_>>(),
+            elements_are![eq("C"), eq("D")]
+        );
+        expect_that!(checksums.codes.len(), eq(3));
+        expect_that!(checksums, eq(&super::checksums(&codes)));
+
+        let mut changed = codes.clone();
+        changed[2].display_name = "Dummy LLC".into();
+        let changed = super::checksums(&changed);
+        expect_that!(changed.root, not(eq(&checksums.root)));
+        expect_that!(changed.buckets["C"], eq(&checksums.buckets["C"]));
+        expect_that!(changed.buckets["D"], not(eq(&checksums.buckets["D"])));
+        expect_that!(
+            changed.codes["DxLCqi4ZlHPD8YxA"],
+            eq(&checksums.codes["DxLCqi4ZlHPD8YxA"])
+        );
+        expect_that!(
+            changed.codes["Dummy0000000000a"],
+            not(eq(&checksums.codes["Dummy0000000000a"]))
+        );
+    }
+
+    #[gtest]
+    fn escape_html_special_characters() {
+        assert_that!(
+            escape_html(r#"<a href="x">Tom & Jerry's</a>"#),
+            eq("&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;")
+        );
+    }
+
+    #[gtest]
+    fn etag_is_quoted_and_stable() {
+        let tag = etag(b"iceblink");
+
+        assert_that!(tag, matches_regex(r#"^"[a-f0-9]{64}"$"#));
+        assert_that!(tag, eq(&etag(b"iceblink")));
+        assert_that!(tag, not(eq(&etag(b"permafrost"))));
+    }
+
+    #[gtest]
+    fn etag_matches_if_none_match() {
+        let tag = etag(b"iceblink");
+        let mut headers = HeaderMap::new();
+        assert_that!(etag_matches(&headers, &tag), is_false());
+
+        headers.insert(header::IF_NONE_MATCH, "\"other\"".parse().unwrap());
+        assert_that!(etag_matches(&headers, &tag), is_false());
+
+        headers.insert(
+            header::IF_NONE_MATCH,
+            format!("\"other\", W/{tag}").parse().unwrap(),
+        );
+        assert_that!(etag_matches(&headers, &tag), is_true());
+
+        headers.insert(header::IF_NONE_MATCH, "*".parse().unwrap());
+        assert_that!(etag_matches(&headers, &tag), is_true());
+    }
+
     #[gtest]
     fn hash_domain_always_returns_same() {
         let hash1 = hash_domain("google.com");
@@ -0,0 +1,137 @@
+use crate::utils;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use webauthn_rs::prelude::*;
+
+/// How long a client has to answer a registration or authentication challenge, and how long a
+/// step-up token can be used.
+pub const CHALLENGE_LIFETIME: Duration = Duration::from_secs(300);
+
+pub struct PendingRegistration {
+    pub username: String,
+    pub display_name: String,
+    /// Checked when the registration started, and redeemed when it finishes.
+    pub invite: Option<String>,
+    pub user_handle: Uuid,
+    pub state: PasskeyRegistration,
+}
+
+/// A security key being added to the account of a logged in user.
+pub struct PendingKeyRegistration {
+    pub user_id: String,
+    pub name: String,
+    pub user_handle: Uuid,
+    pub state: PasskeyRegistration,
+}
+
+pub struct PendingAuthentication {
+    pub user_id: String,
+    pub state: PasskeyAuthentication,
+}
+
+/// Challenges which have been handed out, but not yet answered.
+struct Challenges<T>(Mutex<HashMap<String, (Instant, T)>>);
+
+impl<T> Challenges<T> {
+    fn new() -> Self {
+        Challenges(Mutex::new(HashMap::new()))
+    }
+
+    fn insert(&self, value: T) -> String {
+        let id = utils::generate_id(32);
+        let mut challenges = self.0.lock().unwrap();
+
+        challenges.retain(|_, (created, _)| created.elapsed() < CHALLENGE_LIFETIME);
+        challenges.insert(id.clone(), (Instant::now(), value));
+
+        id
+    }
+
+    fn take(&self, id: &str) -> Option<T> {
+        self.0
+            .lock()
+            .unwrap()
+            .remove(id)
+            .filter(|(created, _)| created.elapsed() < CHALLENGE_LIFETIME)
+            .map(|(_, value)| value)
+    }
+}
+
+pub struct PasskeyAuth {
+    pub webauthn: Webauthn,
+    registrations: Challenges<PendingRegistration>,
+    authentications: Challenges<PendingAuthentication>,
+    key_registrations: Challenges<PendingKeyRegistration>,
+    step_up_challenges: Challenges<PendingAuthentication>,
+    /// Step-up tokens handed out for an answered challenge, by the id of their user.
+    step_ups: Challenges<String>,
+}
+
+impl PasskeyAuth {
+    /// The relying party is derived from the frontfacing URL of the server.
+    pub fn new(frontfacing: &str) -> Result<Self, WebauthnError> {
+        let origin = Url::parse(frontfacing).map_err(|_| WebauthnError::Configuration)?;
+        let rp_id = origin
+            .host_str()
+            .ok_or(WebauthnError::Configuration)?
+            .to_string();
+
+        Ok(PasskeyAuth {
+            webauthn: WebauthnBuilder::new(&rp_id, &origin)?
+                .rp_name("Iceblink")
+                .build()?,
+            registrations: Challenges::new(),
+            authentications: Challenges::new(),
+            key_registrations: Challenges::new(),
+            step_up_challenges: Challenges::new(),
+            step_ups: Challenges::new(),
+        })
+    }
+
+    pub fn begin_registration(&self, pending: PendingRegistration) -> String {
+        self.registrations.insert(pending)
+    }
+
+    pub fn finish_registration(&self, challenge_id: &str) -> Option<PendingRegistration> {
+        self.registrations.take(challenge_id)
+    }
+
+    pub fn begin_authentication(&self, pending: PendingAuthentication) -> String {
+        self.authentications.insert(pending)
+    }
+
+    pub fn finish_authentication(&self, challenge_id: &str) -> Option<PendingAuthentication> {
+        self.authentications.take(challenge_id)
+    }
+
+    pub fn begin_key_registration(&self, pending: PendingKeyRegistration) -> String {
+        self.key_registrations.insert(pending)
+    }
+
+    pub fn finish_key_registration(&self, challenge_id: &str) -> Option<PendingKeyRegistration> {
+        self.key_registrations.take(challenge_id)
+    }
+
+    pub fn begin_step_up(&self, pending: PendingAuthentication) -> String {
+        self.step_up_challenges.insert(pending)
+    }
+
+    pub fn finish_step_up(&self, challenge_id: &str) -> Option<PendingAuthentication> {
+        self.step_up_challenges.take(challenge_id)
+    }
+
+    /// Hands out a token allowing the user a single sensitive action, as long as a challenge lasts.
+    pub fn grant_step_up(&self, user_id: String) -> String {
+        self.step_ups.insert(user_id)
+    }
+
+    /// Uses up the step-up token, if it was granted to the user.
+    pub fn take_step_up(&self, token: &str, user_id: &str) -> bool {
+        self.step_ups
+            .take(token)
+            .is_some_and(|granted| granted == user_id)
+    }
+}
@@ -0,0 +1,233 @@
+use crate::{
+    events::Event,
+    models::webhooks::{DueDelivery, Webhook, WebhookDelivery},
+    ssrf, utils,
+};
+use hmac::{Hmac, Mac};
+use reqwest::{header, Url};
+use serde::Serialize;
+use sha2::Sha256;
+use sqlx::SqlitePool;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Header carrying the kind of the event, e.g. `code.created`.
+pub const EVENT_HEADER: &str = "X-Iceblink-Event";
+/// Header carrying the id of the delivery, which stays the same when it's retried.
+pub const DELIVERY_HEADER: &str = "X-Iceblink-Delivery";
+/// Header carrying the Unix timestamp (seconds) of the attempt, which is part of the signature.
+pub const TIMESTAMP_HEADER: &str = "X-Iceblink-Timestamp";
+/// Header carrying `sha256=` and the hex encoded HMAC of the timestamp, a `.` and the body, keyed
+/// with the secret of the webhook.
+pub const SIGNATURE_HEADER: &str = "X-Iceblink-Signature";
+
+/// Seconds to wait before retrying a failed delivery, by the number of attempts made. Deliveries
+/// failing once more after the last delay are given up on.
+const RETRY_DELAYS: [i64; 5] = [60, 5 * 60, 30 * 60, 2 * 60 * 60, 12 * 60 * 60];
+/// Deliveries attempted in one go, the rest wait for the next round.
+const DELIVERIES_PER_ROUND: i64 = 50;
+/// Finished deliveries are kept in the delivery log this long.
+pub const DELIVERY_LOG_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Longest error of an attempt kept in the delivery log.
+const MAX_ERROR_LENGTH: usize = 256;
+
+/// Body of webhook requests.
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    /// Id of the delivery, to recognize retries of deliveries already processed.
+    id: &'a str,
+    user_id: &'a str,
+    created_at: i64,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Signature of a request at `timestamp` with the body, see [`SIGNATURE_HEADER`].
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{timestamp}.{body}").as_bytes());
+    format!(
+        "sha256={}",
+        base16ct::lower::encode_string(&mac.finalize().into_bytes())
+    )
+}
+
+/// Queues a delivery of the event for every webhook receiving it. Returns how many were queued.
+pub async fn enqueue(pool: &SqlitePool, event: &Event) -> Result<u64, sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+    let mut queued = 0;
+
+    for webhook in Webhook::for_user(pool, &event.user_id).await? {
+        if !webhook.receives(event.kind) {
+            continue;
+        }
+
+        let id = utils::generate_id(16);
+        let payload = serde_json::to_string(&WebhookPayload {
+            id: &id,
+            user_id: &event.user_id,
+            created_at: now,
+            event,
+        })
+        .expect("Unable to serialize webhook payload");
+        WebhookDelivery {
+            id,
+            webhook_id: webhook.id,
+            event: event.kind.name().to_string(),
+            payload,
+            attempts: 0,
+            response_status: None,
+            error: None,
+            created_at: now,
+            next_attempt_at: Some(now),
+            delivered_at: None,
+        }
+        .insert(pool)
+        .await?;
+        queued += 1;
+    }
+
+    Ok(queued)
+}
+
+/// Attempts the deliveries due at `now`, scheduling retries of those failing. Returns how many
+/// were delivered.
+pub async fn deliver_due(
+    pool: &SqlitePool,
+    proxy: Option<&reqwest::Proxy>,
+    now: i64,
+) -> Result<u64, sqlx::Error> {
+    let due = WebhookDelivery::due(pool, now, DELIVERIES_PER_ROUND).await?;
+
+    let mut delivered = 0;
+    for delivery in due {
+        let attempts = delivery.attempts + 1;
+        let (status, error) = attempt(proxy, &delivery, now).await;
+
+        let delivered_at = error.is_none().then_some(now);
+        let next_attempt_at = match (&error, RETRY_DELAYS.get(delivery.attempts as usize)) {
+            (Some(_), Some(delay)) => Some(now + delay),
+            _ => None,
+        };
+        if delivered_at.is_some() {
+            delivered += 1;
+        } else if next_attempt_at.is_none() {
+            warn!(
+                "Giving up on delivering {} after {attempts} attempts",
+                delivery.id
+            );
+        } else {
+            debug!("Delivering {} failed, retrying later", delivery.id);
+        }
+
+        WebhookDelivery::record_attempt(
+            pool,
+            &delivery.id,
+            attempts,
+            status,
+            error,
+            next_attempt_at,
+            delivered_at,
+        )
+        .await?;
+    }
+
+    Ok(delivered)
+}
+
+fn is_success(status: i64) -> bool {
+    (200..300).contains(&status)
+}
+
+/// Sends the delivery, returning the status the receiver responded with, and why it failed if it
+/// did.
+async fn attempt(
+    proxy: Option<&reqwest::Proxy>,
+    delivery: &DueDelivery,
+    now: i64,
+) -> (Option<i64>, Option<String>) {
+    let Ok(url) = Url::parse(&delivery.url) else {
+        return (None, Some("The URL of the webhook is invalid".into()));
+    };
+    let mut client = utils::client_builder(proxy)
+        .user_agent(utils::USER_AGENT)
+        .timeout(Duration::from_secs(10))
+        // Redirects could lead anywhere
+        .redirect(reqwest::redirect::Policy::none());
+    // The host of a user's webhook may resolve to an internal address by now, even if it looked
+    // public when registering
+    if delivery.user_id.is_some() {
+        client = match ssrf::pin(client, &url).await {
+            Ok(client) => client,
+            Err(ssrf::GuardError::Blocked) => {
+                return (None, Some("The URL of the webhook isn't public".into()))
+            }
+            Err(ssrf::GuardError::Unresolvable) => {
+                return (
+                    None,
+                    Some("Unable to resolve the host of the webhook".into()),
+                )
+            }
+        };
+    }
+
+    let response = client
+        .build()
+        .expect("Unable to build HTTP client")
+        .post(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, &delivery.event)
+        .header(DELIVERY_HEADER, &delivery.id)
+        .header(TIMESTAMP_HEADER, now)
+        .header(
+            SIGNATURE_HEADER,
+            sign(&delivery.secret, now, &delivery.payload),
+        )
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+
+    match response {
+        Ok(response) => {
+            let status = i64::from(response.status().as_u16());
+            if is_success(status) {
+                (Some(status), None)
+            } else {
+                (
+                    Some(status),
+                    Some(format!("The receiver responded with {}", response.status())),
+                )
+            }
+        }
+        Err(err) => (
+            None,
+            Some(err.to_string().chars().take(MAX_ERROR_LENGTH).collect()),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    #[gtest]
+    fn signatures_cover_timestamp_and_body() {
+        let signature = sign("whsec_secret", 1700000000, r#"{"kind":"code.created"}"#);
+        expect_that!(signature, starts_with("sha256="));
+        expect_that!(signature.len(), eq(7 + 64));
+        expect_that!(
+            sign("whsec_secret", 1700000000, r#"{"kind":"code.created"}"#),
+            eq(&signature)
+        );
+        expect_that!(
+            sign("whsec_secret", 1700000001, r#"{"kind":"code.created"}"#),
+            not(eq(&signature))
+        );
+        expect_that!(
+            sign("whsec_other", 1700000000, r#"{"kind":"code.created"}"#),
+            not(eq(&signature))
+        );
+    }
+}
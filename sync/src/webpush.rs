@@ -0,0 +1,393 @@
+use crate::{models::push_subscriptions::PushSubscription, ssrf, utils, ServerOptions};
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes128Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use p256::{elliptic_curve::sec1::ToEncodedPoint, pkcs8::DecodePrivateKey};
+use rand::RngCore;
+use reqwest::{header, StatusCode, Url};
+use ring::{
+    agreement::{self, EphemeralPrivateKey, UnparsedPublicKey},
+    rand::SystemRandom,
+};
+use serde::Serialize;
+use sha2::Sha256;
+use std::time::Duration;
+
+/// Size of the single record pushes are encrypted in, as defined by RFC 8188. Push services accept
+/// payloads of up to 4096 bytes.
+const RECORD_SIZE: u32 = 4096;
+/// Length of uncompressed P-256 public keys, as clients send them in their subscriptions.
+pub const PUBLIC_KEY_LENGTH: usize = 65;
+/// Length of the authentication secret of subscriptions.
+pub const AUTH_SECRET_LENGTH: usize = 16;
+/// How long push services keep undelivered pushes around for devices which are offline. Devices
+/// offline for longer sync once they're back anyway.
+const TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Lifetime of the VAPID JWTs identifying the instance, at most 24 hours as per RFC 8292.
+const VAPID_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+
+#[derive(Debug)]
+pub enum WebPushError {
+    UnableToReadPrivateKey(std::io::Error),
+    /// The VAPID key isn't a PKCS#8 PEM encoded P-256 key.
+    InvalidPrivateKey,
+    /// The keys of the subscription aren't usable to encrypt pushes.
+    InvalidSubscription,
+    Request(reqwest::Error),
+    /// The endpoint of the subscription resolves to an internal address, or not at all.
+    BlockedHost,
+    /// The push service forgot the subscription, as the client unsubscribed or was uninstalled.
+    Expired,
+    /// The push service refused the push with the status.
+    Rejected(StatusCode),
+}
+
+impl std::fmt::Display for WebPushError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebPushError::UnableToReadPrivateKey(err) => {
+                write!(f, "Unable to read VAPID private key: {err}")
+            }
+            WebPushError::InvalidPrivateKey => write!(
+                f,
+                "The VAPID private key is not a PKCS#8 PEM encoded P-256 key"
+            ),
+            WebPushError::InvalidSubscription => {
+                write!(f, "The keys of the push subscription are invalid")
+            }
+            WebPushError::Request(err) => write!(f, "Unable to reach the push service: {err}"),
+            WebPushError::BlockedHost => {
+                write!(f, "The push service isn't at a public address")
+            }
+            WebPushError::Expired => write!(f, "The push subscription expired"),
+            WebPushError::Rejected(status) => {
+                write!(f, "The push service rejected the push with {status}")
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct VapidClaims<'a> {
+    aud: String,
+    exp: i64,
+    sub: &'a str,
+}
+
+/// Sends Web Push messages (RFC 8030) to the push services of browsers and apps, identifying the
+/// instance with its VAPID key (RFC 8292).
+#[derive(Clone)]
+pub struct WebPush {
+    encoding: EncodingKey,
+    /// Uncompressed public key, base64url encoded. Clients pass it as `applicationServerKey` when
+    /// subscribing.
+    public_key: String,
+    /// Contact of the operator for push services, a `mailto:` or `https:` URL.
+    subject: String,
+    proxy: Option<reqwest::Proxy>,
+    /// Disables the SSRF guard, allowing pushes to loopback and private addresses.
+    allow_private_networks: bool,
+}
+
+impl WebPush {
+    /// Accepts PKCS#8 PEM encoded P-256 keys, as generated by
+    /// `openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256`.
+    pub fn new(private_key_pem: &str, subject: String) -> Result<Self, WebPushError> {
+        let key = p256::SecretKey::from_pkcs8_pem(private_key_pem)
+            .map_err(|_| WebPushError::InvalidPrivateKey)?;
+
+        Ok(WebPush {
+            encoding: EncodingKey::from_ec_pem(private_key_pem.as_bytes())
+                .map_err(|_| WebPushError::InvalidPrivateKey)?,
+            public_key: URL_SAFE_NO_PAD.encode(key.public_key().to_encoded_point(false)),
+            subject,
+            proxy: None,
+            allow_private_networks: false,
+        })
+    }
+
+    /// Web Push as configured, if a VAPID key is set. Pushes go through the HTTP proxy, if any.
+    pub fn from_options(opts: &ServerOptions) -> Result<Option<Self>, WebPushError> {
+        let Some(path) = &opts.vapid_private_key else {
+            return Ok(None);
+        };
+        let pem = std::fs::read_to_string(path).map_err(WebPushError::UnableToReadPrivateKey)?;
+        let subject = opts
+            .vapid_subject
+            .clone()
+            .unwrap_or_else(|| opts.frontfacing.clone());
+
+        let web_push = WebPush::new(&pem, subject)?;
+        Ok(Some(match &opts.http_proxy {
+            Some(url) => web_push.with_proxy(
+                utils::outbound_proxy(url).expect("Unable to configure the HTTP proxy"),
+            ),
+            None => web_push,
+        }))
+    }
+
+    pub fn with_proxy(mut self, proxy: reqwest::Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    pub fn allow_private_networks(mut self, allow: bool) -> Self {
+        self.allow_private_networks = allow;
+        self
+    }
+
+    /// The `applicationServerKey` of the instance.
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    /// Value of the `Authorization` header for pushes to the push service at `endpoint`.
+    fn authorization(&self, endpoint: &Url) -> String {
+        let claims = VapidClaims {
+            aud: endpoint.origin().ascii_serialization(),
+            exp: chrono::Utc::now().timestamp() + VAPID_LIFETIME.as_secs() as i64,
+            sub: &self.subject,
+        };
+        let jwt = jsonwebtoken::encode(&Header::new(Algorithm::ES256), &claims, &self.encoding)
+            .expect("Unable to sign VAPID JWT");
+
+        format!("vapid t={jwt}, k={}", self.public_key)
+    }
+
+    /// Pushes the payload to the subscription. Pushes with the same `topic` replace each other
+    /// while they wait for the device to come online.
+    pub async fn send(
+        &self,
+        subscription: &PushSubscription,
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<(), WebPushError> {
+        let endpoint =
+            Url::parse(&subscription.endpoint).map_err(|_| WebPushError::InvalidSubscription)?;
+        let decode = |key: &str| {
+            URL_SAFE_NO_PAD
+                .decode(key)
+                .map_err(|_| WebPushError::InvalidSubscription)
+        };
+        let body = encrypt(
+            &decode(&subscription.p256dh)?,
+            &decode(&subscription.auth)?,
+            payload,
+        )?;
+
+        let mut client = utils::client_builder(self.proxy.as_ref())
+            .user_agent(utils::USER_AGENT)
+            .timeout(Duration::from_secs(10))
+            // Redirects could lead anywhere
+            .redirect(reqwest::redirect::Policy::none());
+        // The endpoint may resolve to an internal address by now, even if it looked public when
+        // subscribing
+        if !self.allow_private_networks {
+            client = ssrf::pin(client, &endpoint)
+                .await
+                .map_err(|_| WebPushError::BlockedHost)?;
+        }
+
+        let response = client
+            .build()
+            .map_err(WebPushError::Request)?
+            .post(endpoint.clone())
+            .header(header::AUTHORIZATION, self.authorization(&endpoint))
+            .header(header::CONTENT_ENCODING, "aes128gcm")
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header("TTL", TTL.as_secs())
+            .header("Urgency", "normal")
+            .header("Topic", topic)
+            .body(body)
+            .send()
+            .await
+            .map_err(WebPushError::Request)?;
+
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            StatusCode::NOT_FOUND | StatusCode::GONE => Err(WebPushError::Expired),
+            status => Err(WebPushError::Rejected(status)),
+        }
+    }
+}
+
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// HKDF-SHA256 (RFC 5869), for outputs of up to 32 bytes.
+fn hkdf(salt: &[u8], ikm: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+    let prk = hmac_sha256(salt, &[ikm]);
+    hmac_sha256(&prk, &[info, &[1]])[..len].to_vec()
+}
+
+/// Encrypts the payload for the subscription with the `p256dh` public key and `auth` secret, as
+/// defined by RFC 8291. Returns the body of the push, in the `aes128gcm` content encoding.
+pub fn encrypt(p256dh: &[u8], auth: &[u8], payload: &[u8]) -> Result<Vec<u8>, WebPushError> {
+    if p256dh.len() != PUBLIC_KEY_LENGTH
+        || auth.len() != AUTH_SECRET_LENGTH
+        // The payload, its padding delimiter and the authentication tag have to fit in the record
+        || payload.len() + 17 > RECORD_SIZE as usize
+    {
+        return Err(WebPushError::InvalidSubscription);
+    }
+
+    let rng = SystemRandom::new();
+    let private_key = EphemeralPrivateKey::generate(&agreement::ECDH_P256, &rng)
+        .map_err(|_| WebPushError::InvalidSubscription)?;
+    let public_key = private_key
+        .compute_public_key()
+        .map_err(|_| WebPushError::InvalidSubscription)?;
+    let shared_secret = agreement::agree_ephemeral(
+        private_key,
+        &UnparsedPublicKey::new(&agreement::ECDH_P256, p256dh),
+        |secret| secret.to_vec(),
+    )
+    .map_err(|_| WebPushError::InvalidSubscription)?;
+
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let key_info = [&b"WebPush: info\0"[..], p256dh, public_key.as_ref()].concat();
+    let ikm = hkdf(auth, &shared_secret, &key_info, 32);
+    let cek = hkdf(&salt, &ikm, b"Content-Encoding: aes128gcm\0", 16);
+    let nonce = hkdf(&salt, &ikm, b"Content-Encoding: nonce\0", 12);
+
+    // A single record, which is also the last one
+    let plaintext = [payload, &[2][..]].concat();
+    let ciphertext = Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&cek))
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+        .map_err(|_| WebPushError::InvalidSubscription)?;
+
+    Ok([
+        &salt[..],
+        &RECORD_SIZE.to_be_bytes()[..],
+        &[PUBLIC_KEY_LENGTH as u8][..],
+        public_key.as_ref(),
+        &ciphertext,
+    ]
+    .concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    /// Decrypts the body as the user agent holding `private_key` and `auth` would.
+    fn decrypt(private_key: EphemeralPrivateKey, auth: &[u8], body: &[u8]) -> Vec<u8> {
+        let ua_public = private_key.compute_public_key().unwrap();
+        let (salt, rest) = body.split_at(16);
+        let (record_size, rest) = rest.split_at(4);
+        assert_eq!(record_size, RECORD_SIZE.to_be_bytes());
+        assert_eq!(rest[0] as usize, PUBLIC_KEY_LENGTH);
+        let (as_public, ciphertext) = rest[1..].split_at(PUBLIC_KEY_LENGTH);
+
+        let shared_secret = agreement::agree_ephemeral(
+            private_key,
+            &UnparsedPublicKey::new(&agreement::ECDH_P256, as_public),
+            |secret| secret.to_vec(),
+        )
+        .unwrap();
+        let key_info = [&b"WebPush: info\0"[..], ua_public.as_ref(), as_public].concat();
+        let ikm = hkdf(auth, &shared_secret, &key_info, 32);
+        let cek = hkdf(salt, &ikm, b"Content-Encoding: aes128gcm\0", 16);
+        let nonce = hkdf(salt, &ikm, b"Content-Encoding: nonce\0", 12);
+
+        Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&cek))
+            .decrypt(Nonce::from_slice(&nonce), ciphertext)
+            .unwrap()
+    }
+
+    #[gtest]
+    fn hkdf_matches_rfc_5869() {
+        // Test case 1 of RFC 5869, cut to 32 bytes
+        let okm = hkdf(
+            &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+            &[0x0b; 22],
+            &[0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9],
+            32,
+        );
+        expect_that!(
+            base16ct::lower::encode_string(&okm),
+            eq("3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf")
+        );
+    }
+
+    #[gtest]
+    fn encrypted_pushes_decrypt() {
+        let rng = SystemRandom::new();
+        let private_key = EphemeralPrivateKey::generate(&agreement::ECDH_P256, &rng).unwrap();
+        let p256dh = private_key.compute_public_key().unwrap().as_ref().to_vec();
+        let auth = [7u8; AUTH_SECRET_LENGTH];
+
+        let body = encrypt(&p256dh, &auth, b"{\"kind\":\"changes.available\"}").unwrap();
+        expect_that!(
+            decrypt(private_key, &auth, &body),
+            eq(&b"{\"kind\":\"changes.available\"}\x02".to_vec())
+        );
+    }
+
+    #[gtest]
+    fn encrypt_rejects_invalid_keys() {
+        expect_that!(
+            encrypt(&[4; 64], &[0; AUTH_SECRET_LENGTH], b"payload"),
+            err(anything())
+        );
+        expect_that!(
+            encrypt(
+                &[4; PUBLIC_KEY_LENGTH],
+                &[0; AUTH_SECRET_LENGTH],
+                b"payload"
+            ),
+            err(anything())
+        );
+        expect_that!(
+            encrypt(&[4; PUBLIC_KEY_LENGTH], &[0; 8], b"payload"),
+            err(anything())
+        );
+    }
+
+    #[gtest]
+    fn vapid_authorization() {
+        let web_push = WebPush::new(
+            include_str!("../tests/fixtures/jwt_es256.pem"),
+            "mailto:admin@example.com".into(),
+        )
+        .unwrap();
+        let authorization =
+            web_push.authorization(&Url::parse("https://push.example.com/send/abc?x=1").unwrap());
+
+        let (jwt, key) = authorization
+            .strip_prefix("vapid t=")
+            .unwrap()
+            .split_once(", k=")
+            .unwrap();
+        expect_that!(key, eq(web_push.public_key()));
+        expect_that!(
+            URL_SAFE_NO_PAD.decode(key).unwrap(),
+            len(eq(PUBLIC_KEY_LENGTH))
+        );
+        let claims: serde_json::Value = serde_json::from_slice(
+            &URL_SAFE_NO_PAD
+                .decode(jwt.split('.').nth(1).unwrap())
+                .unwrap(),
+        )
+        .unwrap();
+        expect_that!(
+            claims["aud"],
+            eq(&serde_json::json!("https://push.example.com"))
+        );
+        expect_that!(
+            claims["sub"],
+            eq(&serde_json::json!("mailto:admin@example.com"))
+        );
+    }
+}
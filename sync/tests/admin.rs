@@ -0,0 +1,424 @@
+use axum::{
+    body::Body,
+    http::{Method, Request, StatusCode},
+    response::Response,
+    routing::get,
+    Json, Router,
+};
+use googletest::prelude::*;
+use iceblink_sync::{models, ServerOptions};
+use serde_json::json;
+use sqlx::{sqlite::SqliteConnectOptions, SqlitePool};
+use std::time::Duration;
+use tower::ServiceExt;
+
+pub mod common;
+
+async fn make_admin(db: &SqlitePool, id: &str) {
+    sqlx::query("UPDATE users SET is_admin = TRUE WHERE id = ?")
+        .bind(id)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+async fn request_backup(app: &Router, token: &str, download: bool) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(format!("/v1/admin/backup?download={download}"))
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn backup_requires_admin(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = request_backup(&app, a1.as_str(), false).await;
+    assert_that!(response.status(), eq(StatusCode::FORBIDDEN));
+    assert_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&serde_json::json!("AdminRequired"))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn backup_during_concurrent_writes(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    make_admin(&db, common::USER1_ID).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let writer = tokio::spawn({
+        let db = db.clone();
+        async move {
+            for i in 0..200 {
+                models::codes::Code {
+                    id: format!("concurrent{i:06}"),
+                    owner_id: common::USER2_ID.into(),
+                    content: "garbage".into(),
+                    display_name: "Concurrent".into(),
+                    icon_url: None,
+                    website_url: None,
+                    expires_at: None,
+                    sort_index: i + 1,
+                    issuer: None,
+                    account: None,
+                    kind: "totp".into(),
+                    digits: 6,
+                    period: 30,
+                    algorithm: "SHA1".into(),
+                    favorite: false,
+                    folder_id: None,
+                    created_at: 0,
+                    updated_at: 0,
+                    revision: 0,
+                    deleted_at: None,
+                }
+                .insert(&db)
+                .await
+                .unwrap();
+            }
+        }
+    });
+
+    let response = request_backup(&app, a1.as_str(), false).await;
+    writer.await.unwrap();
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let file = common::convert_response(response).await["file"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let restored = SqlitePool::connect_with(SqliteConnectOptions::new().filename(&file))
+        .await
+        .unwrap();
+
+    let integrity: String = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_one(&restored)
+        .await
+        .unwrap();
+    assert_that!(integrity, eq("ok"));
+
+    // The fixture codes are there, and any concurrent write is either fully present or absent
+    let codes = models::codes::Code::get_many(&restored, common::USER1_ID.into())
+        .await
+        .unwrap();
+    assert_that!(codes, common::matchers::code_fixture());
+    let concurrent: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM codes WHERE display_name = 'Concurrent'")
+            .fetch_one(&restored)
+            .await
+            .unwrap();
+    assert_that!(concurrent, le(200));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn backup_download(db: SqlitePool) {
+    let options = common::testing_options();
+    let backup_directory = std::path::PathBuf::from(&options.backup_directory);
+    let app = common::testing_setup_with_options(&db, options).await;
+    make_admin(&db, common::USER1_ID).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = request_backup(&app, a1.as_str(), true).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(
+        response
+            .headers()
+            .get("Content-Disposition")
+            .unwrap()
+            .to_str()
+            .unwrap(),
+        starts_with("attachment; filename=\"iceblink-")
+    );
+
+    let size: usize = response.headers()["Content-Length"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    let contents = common::convert_response_u8(response).await;
+    assert!(contents.starts_with(b"SQLite format 3\0"));
+    expect_that!(contents.len(), eq(size));
+    // Downloaded backups aren't kept on the server
+    let remaining = std::fs::read_dir(&backup_directory).unwrap().count();
+    expect_that!(remaining, eq(0));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn simultaneous_backups(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    make_admin(&db, common::USER1_ID).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let (first, second) = tokio::join!(
+        request_backup(&app, a1.as_str(), false),
+        request_backup(&app, a1.as_str(), false)
+    );
+    assert_that!(first.status(), eq(StatusCode::OK));
+    assert_that!(second.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::convert_response(first).await["file"],
+        not(eq(&common::convert_response(second).await["file"]))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn abandoned_backups_are_removed(db: SqlitePool) {
+    let options = common::testing_options();
+    let backup_directory = std::path::PathBuf::from(&options.backup_directory);
+    let app = common::testing_setup_with_options(&db, options).await;
+    make_admin(&db, common::USER1_ID).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    // Downloads which are never read, and requests given up on while the backup is written
+    drop(request_backup(&app, a1.as_str(), true).await);
+    let cancelled = tokio::time::timeout(
+        Duration::from_micros(1),
+        request_backup(&app, a1.as_str(), true),
+    )
+    .await;
+    expect_that!(cancelled.is_err(), is_true());
+
+    let mut remaining = usize::MAX;
+    for _ in 0..50 {
+        remaining = std::fs::read_dir(&backup_directory)
+            .map(|entries| entries.count())
+            .unwrap_or_default();
+        if remaining == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    expect_that!(remaining, eq(0));
+}
+
+async fn validate_config(app: &Router, token: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/v1/admin/config/validate")
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+/// Serves the same OpenId configuration the testing router is set up with.
+async fn mock_oauth_server() -> String {
+    common::mock_upstream(Router::new().route(
+        "/.well-known/openid-configuration",
+        get(|| async {
+            Json(json!({
+                "authorization_endpoint": "N/A",
+                "token_endpoint": "N/A",
+                "userinfo_endpoint": "N/A"
+            }))
+        }),
+    ))
+    .await
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn validate_config_healthy(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            oauth_server: mock_oauth_server().await,
+            ..common::testing_options()
+        },
+    )
+    .await;
+    make_admin(&db, common::USER1_ID).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    expect_that!(
+        validate_config(&app, &a2).await.status(),
+        eq(StatusCode::FORBIDDEN)
+    );
+
+    let response = validate_config(&app, &a1).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(
+        common::convert_response(response).await,
+        eq(&json!({
+            "healthy": true,
+            "subsystems": [
+                { "name": "config", "state": "ok", "message": null },
+                { "name": "database", "state": "ok", "message": null },
+                { "name": "oidc", "state": "ok", "message": null },
+                { "name": "icons", "state": "ok", "message": null }
+            ]
+        }))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn validate_config_unreachable_oidc(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            // Nothing listens on port 1
+            oauth_server: "http://127.0.0.1:1".into(),
+            ..common::testing_options()
+        },
+    )
+    .await;
+    make_admin(&db, common::USER1_ID).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = validate_config(&app, &a1).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let report = common::convert_response(response).await;
+    expect_that!(report["healthy"], eq(&json!(false)));
+    for subsystem in report["subsystems"].as_array().unwrap() {
+        if subsystem["name"] == "oidc" {
+            expect_that!(subsystem["state"], eq(&json!("failing")));
+            expect_that!(
+                subsystem["message"].as_str(),
+                some(starts_with("Unable to discover the OpenId configuration"))
+            );
+        } else {
+            expect_that!(subsystem["state"], eq(&json!("ok")));
+        }
+    }
+}
+
+async fn admin_request(app: &Router, token: &str, method: Method, path: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(method)
+                .uri(format!("/v1/admin{path}"))
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn list_users(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    make_admin(&db, common::USER1_ID).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let response = admin_request(&app, &a2, Method::GET, "/users").await;
+    expect_that!(response.status(), eq(StatusCode::FORBIDDEN));
+
+    let response = admin_request(&app, &a1, Method::GET, "/users").await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let users = common::convert_response(response).await;
+    expect_that!(
+        users
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| (user["username"].clone(), user["is_admin"].clone()))
+            .collect::<Vec<_>>(),
+        elements_are![
+            eq(&(json!("user1"), json!(true))),
+            eq(&(json!("user2"), json!(false)))
+        ]
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn suspend_user(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    make_admin(&db, common::USER1_ID).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    let suspend_path = format!("/users/{}/suspend", common::USER2_ID);
+
+    let response = admin_request(&app, &a1, Method::POST, &suspend_path).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::convert_response(response).await["suspended_at"].as_i64(),
+        some(gt(0))
+    );
+
+    let response = common::list_codes(&app, &a2).await;
+    expect_that!(response.status(), eq(StatusCode::FORBIDDEN));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("AccountSuspended"))
+    );
+
+    let response = admin_request(&app, &a1, Method::DELETE, &suspend_path).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::convert_response(response).await["suspended_at"],
+        eq(&json!(null))
+    );
+    expect_that!(
+        common::list_codes(&app, &a2).await.status(),
+        eq(StatusCode::OK)
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn suspend_self_or_unknown_user(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    make_admin(&db, common::USER1_ID).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = admin_request(
+        &app,
+        &a1,
+        Method::POST,
+        &format!("/users/{}/suspend", common::USER1_ID),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+
+    let response = admin_request(&app, &a1, Method::POST, "/users/nobody/suspend").await;
+    expect_that!(response.status(), eq(StatusCode::NOT_FOUND));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn instance_stats(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    make_admin(&db, common::USER1_ID).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    common::delete_code(&app, &a1, common::USER1_CODE1_ID).await;
+
+    let response = admin_request(&app, &a1, Method::GET, "/stats").await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(
+        common::convert_response(response).await,
+        eq(&json!({
+            "users": 2,
+            "admins": 1,
+            "suspended_users": 0,
+            "codes": 2,
+            "trashed_codes": 1,
+            "folders": 0,
+            "sessions": 2,
+            "api_tokens": 0
+        }))
+    );
+}
@@ -1,7 +1,12 @@
-use axum::{body::Body, http::Method, http::Request, http::StatusCode};
+use axum::{
+    body::Body, extract::connect_info::MockConnectInfo, http::Method, http::Request,
+    http::StatusCode,
+};
 use googletest::prelude::*;
+use iceblink_sync::{cli::JwtAlgorithm, jwt::JwtKeys, ServerOptions};
 use serde_json::json;
 use sqlx::SqlitePool;
+use std::{net::SocketAddr, time::Duration};
 use tower::ServiceExt;
 
 pub mod common;
@@ -139,3 +144,691 @@ pub async fn list_codes_invalid_signature(db: SqlitePool) {
         }))
     );
 }
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn list_codes_no_header_api_client(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/v1/code")
+                .header("Accept", "application/json")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+    assert_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("MissingAuthentication"))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn list_codes_no_header_browser(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            unauthenticated_redirect: "https://iceblink.snowflake.blue/login".into(),
+            ..common::testing_options()
+        },
+    )
+    .await;
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/v1/code")
+                .header(
+                    "Accept",
+                    "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+                )
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_that!(response.status(), eq(StatusCode::FOUND));
+    assert_that!(
+        response.headers().get("Location").unwrap(),
+        eq("https://iceblink.snowflake.blue/login")
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn list_codes_garbage_bearer_browser(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/v1/code")
+                .header("Accept", "text/html")
+                .header("Authorization", "Bearer some funny garbage")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_that!(response.status(), eq(StatusCode::FOUND));
+    assert_that!(response.headers().get("Location").unwrap(), eq("/"));
+}
+
+fn asymmetric_options(algorithm: JwtAlgorithm, private_key: &str) -> ServerOptions {
+    ServerOptions {
+        jwt_algorithm: algorithm,
+        jwt_private_key: Some(private_key.into()),
+        ..common::testing_options()
+    }
+}
+
+async fn get_jwks(app: &axum::Router) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/.well-known/jwks.json")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn hs256_tokens(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (token, _) = common::get_access_tokens(&db).await;
+
+    expect_that!(
+        common::list_codes(&app, &token).await.status(),
+        eq(StatusCode::OK)
+    );
+    // The shared secret has nothing to publish
+    expect_that!(get_jwks(&app).await.status(), eq(StatusCode::NOT_FOUND));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn rs256_tokens(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        asymmetric_options(JwtAlgorithm::Rs256, "tests/fixtures/jwt_rs256.pem"),
+    )
+    .await;
+    let keys = JwtKeys::rs256(include_str!("fixtures/jwt_rs256.pem")).unwrap();
+    let (token, _) = common::get_access_tokens_with_keys(&db, &keys).await;
+    let (hs256_token, _) = common::get_access_tokens(&db).await;
+
+    expect_that!(
+        common::list_codes(&app, &token).await.status(),
+        eq(StatusCode::OK)
+    );
+    expect_that!(
+        common::list_codes(&app, &hs256_token).await.status(),
+        eq(StatusCode::UNAUTHORIZED)
+    );
+
+    let response = get_jwks(&app).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(
+        common::convert_response(response).await,
+        eq(&serde_json::to_value(keys.jwks()).unwrap())
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn es256_tokens(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        asymmetric_options(JwtAlgorithm::Es256, "tests/fixtures/jwt_es256.pem"),
+    )
+    .await;
+    let keys = JwtKeys::es256(include_str!("fixtures/jwt_es256.pem")).unwrap();
+    let (token, _) = common::get_access_tokens_with_keys(&db, &keys).await;
+    let (hs256_token, _) = common::get_access_tokens(&db).await;
+
+    expect_that!(
+        common::list_codes(&app, &token).await.status(),
+        eq(StatusCode::OK)
+    );
+    expect_that!(
+        common::list_codes(&app, &hs256_token).await.status(),
+        eq(StatusCode::UNAUTHORIZED)
+    );
+
+    let response = get_jwks(&app).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(
+        common::convert_response(response).await,
+        eq(&json!({
+            "keys": [{
+                "kty": "EC",
+                "use": "sig",
+                "alg": "ES256",
+                "kid": keys.jwks().keys[0].kid,
+                "crv": "P-256",
+                "x": keys.jwks().keys[0].x,
+                "y": keys.jwks().keys[0].y,
+            }]
+        }))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn eddsa_tokens(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        asymmetric_options(JwtAlgorithm::Eddsa, "tests/fixtures/jwt_eddsa.pem"),
+    )
+    .await;
+    let keys = JwtKeys::eddsa(include_str!("fixtures/jwt_eddsa.pem")).unwrap();
+    let (token, _) = common::get_access_tokens_with_keys(&db, &keys).await;
+    let (hs256_token, _) = common::get_access_tokens(&db).await;
+
+    expect_that!(
+        common::list_codes(&app, &token).await.status(),
+        eq(StatusCode::OK)
+    );
+    expect_that!(
+        common::list_codes(&app, &hs256_token).await.status(),
+        eq(StatusCode::UNAUTHORIZED)
+    );
+
+    let response = get_jwks(&app).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(
+        common::convert_response(response).await,
+        eq(&json!({
+            "keys": [{
+                "kty": "OKP",
+                "use": "sig",
+                "alg": "EdDSA",
+                "kid": keys.jwks().keys[0].kid,
+                "crv": "Ed25519",
+                "x": "MGmQoWZQsBz3ABLQJ8N7O_5Fs0dV6s42zZ6tOs7TXWY",
+            }]
+        }))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn previous_jwt_secret(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            jwt_secret: "rotated jwt secret".into(),
+            jwt_previous_secret: Some("my jwt secret".into()),
+            ..common::testing_options()
+        },
+    )
+    .await;
+    let keys = JwtKeys::hs256("rotated jwt secret");
+    let (token, _) = common::get_access_tokens_with_keys(&db, &keys).await;
+    let (previous_token, _) = common::get_access_tokens(&db).await;
+    let (other_token, _) =
+        common::get_access_tokens_with_keys(&db, &JwtKeys::hs256("other jwt secret")).await;
+
+    expect_that!(
+        common::list_codes(&app, &token).await.status(),
+        eq(StatusCode::OK)
+    );
+    expect_that!(
+        common::list_codes(&app, &previous_token).await.status(),
+        eq(StatusCode::OK)
+    );
+    expect_that!(
+        common::list_codes(&app, &other_token).await.status(),
+        eq(StatusCode::UNAUTHORIZED)
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn previous_jwt_private_key(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            jwt_previous_private_key: Some("tests/fixtures/jwt_rs256.pem".into()),
+            ..asymmetric_options(JwtAlgorithm::Es256, "tests/fixtures/jwt_es256.pem")
+        },
+    )
+    .await;
+    let previous = JwtKeys::rs256(include_str!("fixtures/jwt_rs256.pem")).unwrap();
+    let (previous_token, _) = common::get_access_tokens_with_keys(&db, &previous).await;
+
+    expect_that!(
+        common::list_codes(&app, &previous_token).await.status(),
+        eq(StatusCode::OK)
+    );
+
+    // Until it is removed, clients verifying JWTs themselves still need the previous public key
+    let response = get_jwks(&app).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let jwks = common::convert_response(response).await;
+    expect_that!(jwks["keys"].as_array().unwrap(), len(eq(2)));
+    expect_that!(
+        jwks["keys"][1]["kid"],
+        eq(&json!(previous.jwks().keys[0].kid))
+    );
+}
+
+#[gtest]
+fn previous_jwt_secret_and_private_key() {
+    let options = ServerOptions {
+        jwt_previous_secret: Some("my jwt secret".into()),
+        jwt_previous_private_key: Some("tests/fixtures/jwt_rs256.pem".into()),
+        ..common::testing_options()
+    };
+
+    expect_that!(options.validate(), err(anything()));
+}
+
+async fn set_last_activity(db: &SqlitePool, user_id: &str, last_activity: i64) {
+    sqlx::query("UPDATE sessions SET last_activity = ? WHERE user_id = ?")
+        .bind(last_activity)
+        .bind(user_id)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn idle_session_expires(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            session_idle_timeout: std::time::Duration::from_secs(60 * 60),
+            ..common::testing_options()
+        },
+    )
+    .await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    let now = chrono::Utc::now().timestamp();
+
+    set_last_activity(&db, common::USER1_ID, now - 2 * 60 * 60).await;
+    set_last_activity(&db, common::USER2_ID, now - 30 * 60).await;
+
+    let response = common::list_codes(&app, &a1).await;
+    assert_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+    assert_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("SessionExpired"))
+    );
+
+    // The session is gone, so it doesn't come back to life either
+    set_last_activity(&db, common::USER1_ID, now).await;
+    expect_that!(
+        common::list_codes(&app, &a1).await.status(),
+        eq(StatusCode::UNAUTHORIZED)
+    );
+
+    // Active sessions are kept alive by using them
+    expect_that!(
+        common::list_codes(&app, &a2).await.status(),
+        eq(StatusCode::OK)
+    );
+    let last_activity: i64 =
+        sqlx::query_scalar("SELECT last_activity FROM sessions WHERE user_id = ?")
+            .bind(common::USER2_ID)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+    expect_that!(last_activity, ge(now));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn idle_timeout_disabled(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            session_idle_timeout: std::time::Duration::ZERO,
+            ..common::testing_options()
+        },
+    )
+    .await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    set_last_activity(&db, common::USER1_ID, 0).await;
+
+    expect_that!(
+        common::list_codes(&app, &a1).await.status(),
+        eq(StatusCode::OK)
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn session_records_last_address(db: SqlitePool) {
+    let app = common::testing_setup(&db)
+        .await
+        .layer(MockConnectInfo(SocketAddr::from(([198, 51, 100, 4], 4000))));
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let now = chrono::Utc::now().timestamp();
+
+    set_last_activity(&db, common::USER1_ID, now - 5 * 60).await;
+    expect_that!(
+        common::list_codes(&app, &a1).await.status(),
+        eq(StatusCode::OK)
+    );
+
+    let ip: Option<String> = sqlx::query_scalar("SELECT ip FROM sessions WHERE user_id = ?")
+        .bind(common::USER1_ID)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    expect_that!(ip, some(eq("198.51.100.4")));
+}
+
+async fn login(db: &SqlitePool) -> iceblink_sync::auth::SessionTokens {
+    let user = iceblink_sync::models::user::User::get_by_id(db, common::USER1_ID.into())
+        .await
+        .unwrap()
+        .unwrap();
+    iceblink_sync::auth::create_jwt(
+        db,
+        &user,
+        &JwtKeys::hs256("my jwt secret"),
+        &axum::http::HeaderMap::new(),
+        None,
+    )
+    .await
+    .unwrap()
+}
+
+async fn refresh(app: &axum::Router, refresh_token: &str) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v1/oauth/refresh")
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    json!({ "refresh_token": refresh_token }).to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn refresh_session(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let tokens = login(&db).await;
+
+    let response = refresh(&app, &tokens.refresh_token).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let refreshed = common::convert_response(response).await;
+    let access_token = refreshed["access_token"].as_str().unwrap();
+    let refresh_token = refreshed["refresh_token"].as_str().unwrap();
+    expect_that!(refresh_token, not(eq(tokens.refresh_token.as_str())));
+    expect_that!(
+        common::list_codes(&app, access_token).await.status(),
+        eq(StatusCode::OK)
+    );
+
+    // Browsers send the refresh token as a cookie
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v1/oauth/refresh")
+                .header("Cookie", format!("iceblink_refresh={refresh_token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let cookies: Vec<_> = response
+        .headers()
+        .get_all("Set-Cookie")
+        .iter()
+        .map(|cookie| cookie.to_str().unwrap().to_string())
+        .collect();
+    expect_that!(
+        cookies,
+        elements_are![
+            starts_with("iceblink_jwt="),
+            all![
+                starts_with("iceblink_refresh="),
+                contains_substring("Path=/v1/oauth/refresh")
+            ]
+        ]
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn refresh_token_reuse_revokes_session(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let tokens = login(&db).await;
+
+    let response = refresh(&app, &tokens.refresh_token).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let refreshed = common::convert_response(response).await;
+
+    let response = refresh(&app, &tokens.refresh_token).await;
+    assert_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("SessionExpired"))
+    );
+
+    // Neither the thief nor the client can continue the session
+    expect_that!(
+        common::list_codes(&app, refreshed["access_token"].as_str().unwrap())
+            .await
+            .status(),
+        eq(StatusCode::UNAUTHORIZED)
+    );
+    let response = refresh(&app, refreshed["refresh_token"].as_str().unwrap()).await;
+    expect_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn refresh_invalid_token(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let tokens = login(&db).await;
+    let (session_id, _) = tokens
+        .refresh_token
+        .strip_prefix("ibr_")
+        .unwrap()
+        .split_once('_')
+        .unwrap();
+
+    for token in ["garbage", &format!("ibr_{session_id}_wrong")] {
+        let response = refresh(&app, token).await;
+        expect_that!(response.status(), eq(StatusCode::UNAUTHORIZED), "{token}");
+        expect_that!(
+            common::convert_response(response).await["errorKind"],
+            eq(&json!("InvalidAuthentication"))
+        );
+    }
+
+    // Guessing doesn't harm the session
+    let response = refresh(&app, &tokens.refresh_token).await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn logout_revokes_session(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    let tokens = login(&db).await;
+
+    let response = common::logout(&app, &tokens.jwt).await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+    let cookies: Vec<_> = response
+        .headers()
+        .get_all("Set-Cookie")
+        .iter()
+        .map(|cookie| cookie.to_str().unwrap().to_string())
+        .collect();
+    expect_that!(
+        cookies,
+        elements_are![
+            all![
+                starts_with("iceblink_jwt=;"),
+                contains_substring("Max-Age=0")
+            ],
+            all![
+                starts_with("iceblink_refresh=;"),
+                contains_substring("Max-Age=0")
+            ]
+        ]
+    );
+
+    let response = common::list_codes(&app, &tokens.jwt).await;
+    expect_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("SessionExpired"))
+    );
+    let response = refresh(&app, &tokens.refresh_token).await;
+    expect_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+
+    // Other sessions, of the same user or not, are unaffected
+    expect_that!(
+        common::list_codes(&app, &a1).await.status(),
+        eq(StatusCode::OK)
+    );
+    expect_that!(
+        common::list_codes(&app, &a2).await.status(),
+        eq(StatusCode::OK)
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn configured_jwt_lifetime(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            jwt_lifetime: Duration::from_secs(60 * 60),
+            ..common::testing_options()
+        },
+    )
+    .await;
+    let tokens = login(&db).await;
+
+    let response = refresh(&app, &tokens.refresh_token).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let expires_at = common::convert_response(response).await["expires_at"]
+        .as_i64()
+        .unwrap();
+    let now = chrono::Utc::now().timestamp();
+    expect_that!(expires_at, ge(now + 60 * 60 - 5));
+    expect_that!(expires_at, le(now + 60 * 60));
+}
+
+async fn list_codes_with_cookie(app: &axum::Router, token: &str) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/v1/code")
+                .header("Cookie", format!("iceblink_jwt={token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn jwt_renewal(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            jwt_renewal_window: Duration::from_secs(60 * 60),
+            ..common::testing_options()
+        },
+    )
+    .await;
+    let (token, _) = common::get_access_tokens(&db).await;
+    let (expiring, _) = common::get_access_tokens_with_keys(
+        &db,
+        &JwtKeys::hs256("my jwt secret").with_lifetime(Duration::from_secs(10 * 60)),
+    )
+    .await;
+
+    // Far from expiring
+    let response = common::list_codes(&app, &token).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(response.headers().get("X-Renewed-Token"), none());
+
+    let response = common::list_codes(&app, &expiring).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(response.headers().get("Set-Cookie"), none());
+    let renewed = response.headers()["X-Renewed-Token"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let response = common::list_codes(&app, &renewed).await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(response.headers().get("X-Renewed-Token"), none());
+
+    // Browsers get the cookie replaced
+    let response = list_codes_with_cookie(&app, &expiring).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        response.headers()["Set-Cookie"].to_str().unwrap(),
+        starts_with("iceblink_jwt=")
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+pub async fn jwt_renewal_disabled(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (expiring, _) = common::get_access_tokens_with_keys(
+        &db,
+        &JwtKeys::hs256("my jwt secret").with_lifetime(Duration::from_secs(60)),
+    )
+    .await;
+
+    let response = common::list_codes(&app, &expiring).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(response.headers().get("X-Renewed-Token"), none());
+}
+
+#[gtest]
+fn jwt_renewal_window_shorter_than_lifetime() {
+    let options = ServerOptions {
+        jwt_lifetime: Duration::from_secs(60 * 60),
+        jwt_renewal_window: Duration::from_secs(60 * 60),
+        ..common::testing_options()
+    };
+    expect_that!(options.validate(), err(anything()));
+
+    let options = ServerOptions {
+        jwt_lifetime: Duration::ZERO,
+        jwt_renewal_window: Duration::ZERO,
+        ..common::testing_options()
+    };
+    expect_that!(options.validate(), err(anything()));
+}
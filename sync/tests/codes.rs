@@ -4,9 +4,10 @@ use axum::{
 };
 use common::AsExpected;
 use googletest::prelude::*;
-use iceblink_sync::models;
+use iceblink_sync::{models, totp, ServerOptions};
 use serde_json::json;
 use sqlx::SqlitePool;
+use std::time::Duration;
 use tower::ServiceExt;
 
 pub mod common;
@@ -103,6 +104,212 @@ async fn add_code_no_content(db: SqlitePool) {
     assert_that!(listing_request, common::matchers::code_fixture());
 }
 
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn add_code_from_otpauth_uri(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let added = common::add_code(
+        &app,
+        &a1,
+        &json!({
+            "otpauth_uri": "otpauth://totp/GitHub:octocat?secret=jbsw%20y3dp%20ehpk%203pxp&algorithm=SHA256&digits=8&period=60",
+            "website_url": "github.com"
+        }),
+    )
+    .await;
+    assert_that!(added.status(), eq(StatusCode::OK));
+    let added: models::codes::Code =
+        serde_json::from_value(common::convert_response(added).await).unwrap();
+
+    expect_that!(added.content, eq("JBSWY3DPEHPK3PXP"));
+    expect_that!(added.display_name, eq("GitHub:octocat"));
+    expect_that!(added.website_url.as_deref(), some(eq("github.com")));
+    expect_that!(added.issuer.as_deref(), some(eq("GitHub")));
+    expect_that!(added.account.as_deref(), some(eq("octocat")));
+    expect_that!(added.digits, eq(8));
+    expect_that!(added.period, eq(60));
+    expect_that!(added.algorithm, eq("SHA256"));
+
+    // Verification uses the parameters of the URI
+    let key = totp::decode_secret(&added.content).unwrap();
+    let code = totp::generate_with(
+        &key,
+        chrono::Utc::now().timestamp() / 60,
+        added.totp_params(),
+    );
+    let response = common::verify_code(&app, &a1, &added.id, &code).await;
+    expect_that!(
+        common::convert_response(response).await["valid"],
+        eq(&json!(true))
+    );
+
+    // Fields of the payload take precedence over the URI
+    let added = common::add_code(
+        &app,
+        &a1,
+        &json!({
+            "otpauth_uri": "otpauth://totp/Work?secret=JBSWY3DPEHPK3PXP",
+            "display_name": "Work email"
+        }),
+    )
+    .await;
+    assert_that!(added.status(), eq(StatusCode::OK));
+    let added = common::convert_response(added).await;
+    expect_that!(added["display_name"], eq(&json!("Work email")));
+    expect_that!(added["account"], eq(&json!("Work")));
+    expect_that!(added["digits"], eq(&json!(6)));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn add_code_invalid_otpauth_uri(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    for (uri, field) in [
+        ("https://example.com", "uri"),
+        ("otpauth://totp/Work?secret=not-base32!", "secret"),
+        (
+            "otpauth://totp/Work?secret=JBSWY3DPEHPK3PXP&digits=12",
+            "digits",
+        ),
+        (
+            "otpauth://totp/Work?secret=JBSWY3DPEHPK3PXP&period=-30",
+            "period",
+        ),
+        (
+            "otpauth://totp/Work?secret=JBSWY3DPEHPK3PXP&algorithm=MD5",
+            "algorithm",
+        ),
+    ] {
+        let added = common::add_code(&app, &a1, &json!({ "otpauth_uri": uri })).await;
+        assert_that!(added.status(), eq(StatusCode::BAD_REQUEST));
+        let error = common::convert_response(added).await;
+        expect_that!(error["errorKind"], eq(&json!("InvalidOtpAuthUri")));
+        expect_that!(error["field"], eq(&json!(field)), "{uri}");
+    }
+
+    let listing_request = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing_request, common::matchers::code_fixture());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn add_code_with_params(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let added = common::add_code(
+        &app,
+        &a1,
+        &json!({
+            "content": "JBSWY3DPEHPK3PXP",
+            "display_name": "Work email",
+            "digits": 7,
+            "algorithm": "sha512"
+        }),
+    )
+    .await;
+    assert_that!(added.status(), eq(StatusCode::OK));
+    let added = common::convert_response(added).await;
+    expect_that!(added["digits"], eq(&json!(7)));
+    expect_that!(added["period"], eq(&json!(30)));
+    expect_that!(added["algorithm"], eq(&json!("SHA512")));
+
+    // The parameters take precedence over those of the URI
+    let added = common::add_code(
+        &app,
+        &a1,
+        &json!({
+            "otpauth_uri": "otpauth://totp/Work?secret=GEZDGNBVGY3TQOJQ&digits=8&period=60",
+            "digits": 6
+        }),
+    )
+    .await;
+    assert_that!(added.status(), eq(StatusCode::OK));
+    let added = common::convert_response(added).await;
+    expect_that!(added["digits"], eq(&json!(6)));
+    expect_that!(added["period"], eq(&json!(60)));
+
+    for (mut payload, field) in [
+        (json!({ "digits": 5 }), "digits"),
+        (json!({ "period": 0 }), "period"),
+        (json!({ "algorithm": "MD5" }), "algorithm"),
+    ] {
+        payload["content"] = json!("JBSWY3DPEHPK3PXP");
+        payload["display_name"] = json!("Invalid");
+        let added = common::add_code(&app, &a1, &payload).await;
+        assert_that!(added.status(), eq(StatusCode::BAD_REQUEST));
+        let error = common::convert_response(added).await;
+        expect_that!(error["errorKind"], eq(&json!("InvalidCodeParameter")));
+        expect_that!(error["field"], eq(&json!(field)));
+    }
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn add_code_duplicate_secret(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let response = common::add_code(
+        &app,
+        &a1,
+        &json!({ "content": "JBSWY3DPEHPK3PXP", "display_name": "GitHub" }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let existing_id = common::convert_response(response).await["id"].clone();
+
+    // Base32 secrets match regardless of case, spaces and padding
+    let response = common::add_code(
+        &app,
+        &a1,
+        &json!({ "otpauth_uri": "otpauth://totp/GitHub?secret=jbsw%20y3dp%20ehpk%203pxp" }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::CONFLICT));
+    let error = common::convert_response(response).await;
+    expect_that!(error["errorKind"], eq(&json!("DuplicateSecret")));
+    expect_that!(error["existing_id"], eq(&existing_id));
+
+    let response = common::add_code(
+        &app,
+        &a1,
+        &json!({ "content": common::USER1_CODE2_CONTENT, "display_name": "Copy" }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::CONFLICT));
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::PUT)
+                .uri("/v1/code?allow_duplicate=true")
+                .header("Authorization", format!("Bearer {a1}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    json!({ "content": "JBSWY3DPEHPK3PXP", "display_name": "GitHub" }).to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    expect_that!(response.status(), eq(StatusCode::OK));
+
+    // Secrets of other users don't count
+    let response = common::add_code(
+        &app,
+        &a2,
+        &json!({ "content": "JBSWY3DPEHPK3PXP", "display_name": "GitHub" }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+}
+
 //
 // Code edit
 //
@@ -126,14 +333,24 @@ async fn edit_code_remove_website(db: SqlitePool) {
     // Returns updated code
     assert_that!(edit_request.status(), eq(StatusCode::OK));
     assert_that!(
-        common::convert_response(edit_request).await,
+        common::without_sync_fields(common::convert_response(edit_request).await),
         eq(&json!({
             "content": common::USER1_CODE2_CONTENT,
             "id": common::USER1_CODE2_ID,
             "owner_id": common::USER1_ID,
             "display_name": "google.com",
             "icon_url": null,
-            "website_url": null
+            "website_url": null,
+            "expires_at": null,
+            "sort_index": 1,
+            "favorite": false,
+            "issuer": null,
+            "account": null,
+            "kind": "totp",
+            "digits": 6,
+            "period": 30,
+            "algorithm": "SHA1",
+            "folder_id": null
         }))
     );
 
@@ -177,14 +394,24 @@ async fn edit_code_update_website_removes_icon(db: SqlitePool) {
 
     assert_that!(edit_request.status(), eq(StatusCode::OK));
     assert_that!(
-        common::convert_response(edit_request).await,
+        common::without_sync_fields(common::convert_response(edit_request).await),
         eq(&json!({
             "content": common::USER2_CODE1_CONTENT,
             "id": common::USER2_CODE1_ID,
             "owner_id": common::USER2_ID,
             "display_name": "Dummy INC",
             "icon_url": null,
-            "website_url": "example.com"
+            "website_url": "example.com",
+            "expires_at": null,
+            "sort_index": 0,
+            "favorite": false,
+            "issuer": null,
+            "account": null,
+            "kind": "totp",
+            "digits": 6,
+            "period": 30,
+            "algorithm": "SHA1",
+            "folder_id": null
         }))
     );
 
@@ -220,14 +447,24 @@ async fn edit_code_content_and_display_name(db: SqlitePool) {
     // Returns updated code
     assert_that!(edit_request.status(), eq(StatusCode::OK));
     assert_that!(
-        common::convert_response(edit_request).await,
+        common::without_sync_fields(common::convert_response(edit_request).await),
         eq(&json!({
             "content": "yippie",
             "id": common::USER1_CODE2_ID,
             "owner_id": common::USER1_ID,
             "display_name": "Modrinth",
             "icon_url": null,
-            "website_url": "google.com"
+            "website_url": "google.com",
+            "expires_at": null,
+            "sort_index": 1,
+            "favorite": false,
+            "issuer": null,
+            "account": null,
+            "kind": "totp",
+            "digits": 6,
+            "period": 30,
+            "algorithm": "SHA1",
+            "folder_id": null
         }))
     );
 
@@ -254,63 +491,102 @@ async fn edit_code_content_and_display_name(db: SqlitePool) {
 
 #[sqlx::test(fixtures("users", "codes"))]
 #[gtest]
-async fn edit_code_not_found(db: SqlitePool) {
+async fn edit_code_only_changed_fields(db: SqlitePool) {
     let app = common::testing_setup(&db).await;
     let (a1, _) = common::get_access_tokens(&db).await;
 
-    let edit_request = common::edit_code(
-        &app,
-        a1.as_str(),
-        "gibberish",
-        &json!({
-            "website_url": "example.com"
-        }),
-    )
-    .await;
+    let edit_request = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::PATCH)
+                .uri(format!(
+                    "/v1/code/{}?fields=changed",
+                    common::USER1_CODE2_ID
+                ))
+                .header("Authorization", format!("Bearer {a1}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&json!({
+                        "display_name": "Modrinth"
+                    }))
+                    .unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
 
-    assert_that!(edit_request.status(), eq(StatusCode::NOT_FOUND));
+    assert_that!(edit_request.status(), eq(StatusCode::OK));
     assert_that!(
         common::convert_response(edit_request).await,
         eq(&json!({
-            "message": "Resource not found.",
-            "errorKind": "NotFound"
+            "id": common::USER1_CODE2_ID,
+            "display_name": "Modrinth"
         }))
     );
+
+    // The rest of the code is untouched
+    let listing_request = common::list_codes_content(&app, a1.as_str()).await;
+    let modified_code = listing_request
+        .iter()
+        .find(|v| v.id == common::USER1_CODE2_ID)
+        .unwrap();
+    expect_that!(modified_code.display_name, eq("Modrinth"));
+    expect_that!(modified_code.content, eq(common::USER1_CODE2_CONTENT));
+    expect_that!(modified_code.website_url, some(eq("google.com")));
 }
 
 #[sqlx::test(fixtures("users", "codes"))]
 #[gtest]
-async fn edit_code_other_user(db: SqlitePool) {
+async fn edit_code_params(db: SqlitePool) {
     let app = common::testing_setup(&db).await;
-    let (a1, a2) = common::get_access_tokens(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
 
-    let edit_request = common::edit_code(
+    let edited =
+        common::edit_code(&app, &a1, common::USER1_CODE2_ID, &json!({ "period": 60 })).await;
+    assert_that!(edited.status(), eq(StatusCode::OK));
+
+    let edited = common::edit_code(
         &app,
-        a1.as_str(),
-        common::USER2_CODE1_ID,
-        &json!({
-            "display_name": "Hacked."
-        }),
+        &a1,
+        common::USER1_CODE2_ID,
+        &json!({ "digits": 8, "algorithm": "SHA256" }),
     )
     .await;
+    assert_that!(edited.status(), eq(StatusCode::OK));
+    let edited = common::convert_response(edited).await;
+    expect_that!(edited["digits"], eq(&json!(8)));
+    expect_that!(edited["period"], eq(&json!(60)));
+    expect_that!(edited["algorithm"], eq(&json!("SHA256")));
 
-    assert_that!(edit_request.status(), eq(StatusCode::NOT_FOUND));
-    assert_that!(
-        common::convert_response(edit_request).await,
-        eq(&json!({
-            "message": "Resource not found.",
-            "errorKind": "NotFound"
-        }))
+    let rejected = common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE2_ID,
+        &json!({ "digits": 10, "display_name": "Renamed" }),
+    )
+    .await;
+    assert_that!(rejected.status(), eq(StatusCode::BAD_REQUEST));
+    expect_that!(
+        common::convert_response(rejected).await["field"],
+        eq(&json!("digits"))
     );
 
-    // Check that it did indeed not happen
-    let u2 = common::list_codes_content(&app, a2.as_str()).await;
-    assert_that!(u2, common::matchers::code_fixture());
+    let listing_request = common::list_codes_content(&app, a1.as_str()).await;
+    let code = listing_request
+        .iter()
+        .find(|code| code.id == common::USER1_CODE2_ID)
+        .unwrap();
+    expect_that!(code.display_name, eq("google.com"));
+    expect_that!(code.digits, eq(8));
+    expect_that!(code.period, eq(60));
+    expect_that!(code.algorithm, eq("SHA256"));
 }
 
 #[sqlx::test(fixtures("users", "codes"))]
 #[gtest]
-async fn edit_code_other_user_no_auth(db: SqlitePool) {
+async fn edit_code_only_changed_fields_website(db: SqlitePool) {
     let app = common::testing_setup(&db).await;
     let (_, a2) = common::get_access_tokens(&db).await;
 
@@ -319,11 +595,15 @@ async fn edit_code_other_user_no_auth(db: SqlitePool) {
         .oneshot(
             Request::builder()
                 .method(Method::PATCH)
-                .uri(format!("/v1/code/{}", common::USER2_CODE1_ID))
+                .uri(format!(
+                    "/v1/code/{}?fields=changed",
+                    common::USER2_CODE1_ID
+                ))
+                .header("Authorization", format!("Bearer {a2}"))
                 .header("Content-Type", "application/json")
                 .body(Body::from(
                     serde_json::to_vec(&json!({
-                        "display_name": "hacked"
+                        "website_url": "example.com"
                     }))
                     .unwrap(),
                 ))
@@ -332,70 +612,269 @@ async fn edit_code_other_user_no_auth(db: SqlitePool) {
         .await
         .unwrap();
 
-    assert_that!(edit_request.status(), eq(StatusCode::UNAUTHORIZED));
+    // Changing the website also resets the icon
+    assert_that!(edit_request.status(), eq(StatusCode::OK));
     assert_that!(
         common::convert_response(edit_request).await,
         eq(&json!({
-            "message": "Missing authentication. Supply a JWT in the `iceblink_jwt` cookie, or use a bearer in the `Authorization` header.",
-            "errorKind": "MissingAuthentication"
+            "id": common::USER2_CODE1_ID,
+            "website_url": "example.com",
+            "icon_url": null
         }))
     );
-
-    // Check that it did indeed not happen
-    let u2 = common::list_codes_content(&app, a2.as_str()).await;
-    assert_that!(u2, common::matchers::code_fixture());
 }
 
-//
-// Code deletion
-//
-
 #[sqlx::test(fixtures("users", "codes"))]
 #[gtest]
-async fn delete_code(db: SqlitePool) {
+async fn edit_code_invalid_fields_query(db: SqlitePool) {
     let app = common::testing_setup(&db).await;
     let (a1, _) = common::get_access_tokens(&db).await;
 
-    let deletion_request = common::delete_code(&app, a1.as_str(), common::USER1_CODE2_ID).await;
-    assert_that!(deletion_request.status(), eq(StatusCode::NO_CONTENT));
+    let edit_request = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::PATCH)
+                .uri(format!("/v1/code/{}?fields=some", common::USER1_CODE2_ID))
+                .header("Authorization", format!("Bearer {a1}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&json!({
+                        "display_name": "Modrinth"
+                    }))
+                    .unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
 
-    let codes_listing = common::list_codes_content(&app, a1.as_str()).await;
-    assert_that!(codes_listing.len(), eq(1));
+    assert_that!(edit_request.status(), eq(StatusCode::BAD_REQUEST));
+    assert_that!(
+        common::convert_response(edit_request).await["errorKind"],
+        eq(&json!("BadRequest"))
+    );
 
-    let remaining_code = codes_listing.get(0).unwrap();
-    assert_that!(remaining_code.id, eq(common::USER1_CODE1_ID));
-    assert!(remaining_code.is_as_expected());
+    let listing_request = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing_request, common::matchers::code_fixture());
 }
 
 #[sqlx::test(fixtures("users", "codes"))]
 #[gtest]
-async fn delete_code_not_found(db: SqlitePool) {
+async fn edit_code_multiple_fields_single_update(db: SqlitePool) {
     let app = common::testing_setup(&db).await;
     let (a1, _) = common::get_access_tokens(&db).await;
 
-    let deletion_request = common::delete_code(&app, a1.as_str(), "random-id").await;
-    assert_that!(deletion_request.status(), eq(StatusCode::NOT_FOUND));
-    assert_that!(
-        common::convert_response(deletion_request).await,
-        eq(&json!({
-            "message": "Resource not found.",
-            "errorKind": "NotFound"
-        }))
-    );
+    // Keep track of every row update
+    sqlx::query("CREATE TABLE code_updates (id TEXT NOT NULL)")
+        .execute(&db)
+        .await
+        .unwrap();
+    sqlx::query(
+        "CREATE TRIGGER count_code_updates AFTER UPDATE ON codes BEGIN INSERT INTO code_updates VALUES (NEW.id); END",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
 
-    // The user codes should not be affected
-    let user_codes = common::list_codes_content(&app, a1.as_str()).await;
-    assert_that!(user_codes, common::matchers::code_fixture());
+    let edit_request = common::edit_code(
+        &app,
+        a1.as_str(),
+        common::USER1_CODE1_ID,
+        &json!({
+            "content": "yippie",
+            "display_name": "Modrinth",
+            "website_url": "modrinth.com"
+        }),
+    )
+    .await;
+    assert_that!(edit_request.status(), eq(StatusCode::OK));
+
+    let updates: Vec<String> = sqlx::query_scalar("SELECT id FROM code_updates")
+        .fetch_all(&db)
+        .await
+        .unwrap();
+    assert_that!(updates, elements_are![eq(common::USER1_CODE1_ID)]);
+
+    let code =
+        models::codes::Code::get(&db, common::USER1_CODE1_ID.into(), common::USER1_ID.into())
+            .await
+            .unwrap()
+            .unwrap();
+    expect_that!(code.content, eq("yippie"));
+    expect_that!(code.display_name, eq("Modrinth"));
+    expect_that!(code.website_url, some(eq("modrinth.com")));
+    expect_that!(code.icon_url, none());
 }
 
 #[sqlx::test(fixtures("users", "codes"))]
 #[gtest]
-async fn delete_code_other_user(db: SqlitePool) {
-    let app = common::testing_setup(&db).await;
-    let (a1, a2) = common::get_access_tokens(&db).await;
+async fn edit_code_model_is_owner_scoped(db: SqlitePool) {
+    let mut code =
+        models::codes::Code::get(&db, common::USER1_CODE1_ID.into(), common::USER1_ID.into())
+            .await
+            .unwrap()
+            .unwrap();
+    code.owner_id = common::USER2_ID.into();
+
+    let result = code
+        .edit()
+        .pool(&db)
+        .display_name("Hacked.".into())
+        .call()
+        .await;
+    assert!(matches!(result, Err(sqlx::Error::RowNotFound)));
 
-    let deletion_request = common::delete_code(&app, a1.as_str(), common::USER2_CODE1_ID).await;
-    assert_that!(deletion_request.status(), eq(StatusCode::NOT_FOUND));
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let app = common::testing_setup(&db).await;
+    let listing_request = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing_request, common::matchers::code_fixture());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn edit_code_not_found(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let edit_request = common::edit_code(
+        &app,
+        a1.as_str(),
+        "gibberish",
+        &json!({
+            "website_url": "example.com"
+        }),
+    )
+    .await;
+
+    assert_that!(edit_request.status(), eq(StatusCode::NOT_FOUND));
+    assert_that!(
+        common::convert_response(edit_request).await,
+        eq(&json!({
+            "message": "Resource not found.",
+            "errorKind": "NotFound"
+        }))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn edit_code_other_user(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let edit_request = common::edit_code(
+        &app,
+        a1.as_str(),
+        common::USER2_CODE1_ID,
+        &json!({
+            "display_name": "Hacked."
+        }),
+    )
+    .await;
+
+    assert_that!(edit_request.status(), eq(StatusCode::NOT_FOUND));
+    assert_that!(
+        common::convert_response(edit_request).await,
+        eq(&json!({
+            "message": "Resource not found.",
+            "errorKind": "NotFound"
+        }))
+    );
+
+    // Check that it did indeed not happen
+    let u2 = common::list_codes_content(&app, a2.as_str()).await;
+    assert_that!(u2, common::matchers::code_fixture());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn edit_code_other_user_no_auth(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (_, a2) = common::get_access_tokens(&db).await;
+
+    let edit_request = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::PATCH)
+                .uri(format!("/v1/code/{}", common::USER2_CODE1_ID))
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&json!({
+                        "display_name": "hacked"
+                    }))
+                    .unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_that!(edit_request.status(), eq(StatusCode::UNAUTHORIZED));
+    assert_that!(
+        common::convert_response(edit_request).await,
+        eq(&json!({
+            "message": "Missing authentication. Supply a JWT in the `iceblink_jwt` cookie, or use a bearer in the `Authorization` header.",
+            "errorKind": "MissingAuthentication"
+        }))
+    );
+
+    // Check that it did indeed not happen
+    let u2 = common::list_codes_content(&app, a2.as_str()).await;
+    assert_that!(u2, common::matchers::code_fixture());
+}
+
+//
+// Code deletion
+//
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn delete_code(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let deletion_request = common::delete_code(&app, a1.as_str(), common::USER1_CODE2_ID).await;
+    assert_that!(deletion_request.status(), eq(StatusCode::NO_CONTENT));
+
+    let codes_listing = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(codes_listing.len(), eq(1));
+
+    let remaining_code = codes_listing.get(0).unwrap();
+    assert_that!(remaining_code.id, eq(common::USER1_CODE1_ID));
+    assert!(remaining_code.is_as_expected());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn delete_code_not_found(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let deletion_request = common::delete_code(&app, a1.as_str(), "random-id").await;
+    assert_that!(deletion_request.status(), eq(StatusCode::NOT_FOUND));
+    assert_that!(
+        common::convert_response(deletion_request).await,
+        eq(&json!({
+            "message": "Resource not found.",
+            "errorKind": "NotFound"
+        }))
+    );
+
+    // The user codes should not be affected
+    let user_codes = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(user_codes, common::matchers::code_fixture());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn delete_code_other_user(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let deletion_request = common::delete_code(&app, a1.as_str(), common::USER2_CODE1_ID).await;
+    assert_that!(deletion_request.status(), eq(StatusCode::NOT_FOUND));
     assert_that!(
         common::convert_response(deletion_request).await,
         eq(&json!({
@@ -409,6 +888,190 @@ async fn delete_code_other_user(db: SqlitePool) {
     assert_that!(victim_codes, common::matchers::code_fixture());
 }
 
+//
+// Expiry
+//
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn add_code_with_expiry(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let expiry = chrono::Utc::now().timestamp() + 3600;
+
+    let added = common::add_code(
+        &app,
+        &a1,
+        &json!({
+            "content": "garbage",
+            "display_name": "Temporary",
+            "expires_at": expiry,
+        }),
+    )
+    .await;
+
+    assert_that!(added.status(), eq(StatusCode::OK));
+    let added_res: models::codes::Code =
+        serde_json::from_value(common::convert_response(added).await).unwrap();
+    expect_that!(added_res.expires_at, some(eq(expiry)));
+
+    let listing_request = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing_request.len(), eq(3));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn add_code_expiry_in_past(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let added = common::add_code(
+        &app,
+        &a1,
+        &json!({
+            "content": "garbage",
+            "display_name": "Temporary",
+            "expires_at": chrono::Utc::now().timestamp() - 60,
+        }),
+    )
+    .await;
+
+    assert_that!(added.status(), eq(StatusCode::BAD_REQUEST));
+    assert_that!(
+        common::convert_response(added).await,
+        eq(&json!({
+            "message": "The expiry of a code has to be in the future.",
+            "errorKind": "ExpiryInPast"
+        }))
+    );
+
+    let listing_request = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing_request, common::matchers::code_fixture());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn edit_code_expiry_in_past(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let edit_request = common::edit_code(
+        &app,
+        a1.as_str(),
+        common::USER1_CODE1_ID,
+        &json!({
+            "expires_at": chrono::Utc::now().timestamp() - 60
+        }),
+    )
+    .await;
+
+    assert_that!(edit_request.status(), eq(StatusCode::BAD_REQUEST));
+
+    let listing_request = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing_request, common::matchers::code_fixture());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn expired_code_is_excluded(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    models::codes::Code {
+        id: "expiredexpired00".into(),
+        owner_id: common::USER1_ID.into(),
+        content: "garbage".into(),
+        display_name: "Expired".into(),
+        icon_url: None,
+        website_url: None,
+        expires_at: Some(chrono::Utc::now().timestamp() - 60),
+        sort_index: 0,
+        issuer: None,
+        account: None,
+        kind: "totp".into(),
+        digits: 6,
+        period: 30,
+        algorithm: "SHA1".into(),
+        favorite: false,
+        folder_id: None,
+        created_at: 0,
+        updated_at: 0,
+        revision: 0,
+        deleted_at: None,
+    }
+    .insert(&db)
+    .await
+    .unwrap();
+
+    let listing_request = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing_request, common::matchers::code_fixture());
+
+    let edit_request = common::edit_code(
+        &app,
+        a1.as_str(),
+        "expiredexpired00",
+        &json!({
+            "display_name": "Revived"
+        }),
+    )
+    .await;
+    assert_that!(edit_request.status(), eq(StatusCode::NOT_FOUND));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn expiry_task_removes_expired_codes(db: SqlitePool) {
+    let now = chrono::Utc::now().timestamp();
+
+    for (id, expires_at) in [
+        ("expiredexpired00", now - 60),
+        ("notexpiredyet000", now + 3600),
+    ] {
+        models::codes::Code {
+            id: id.into(),
+            owner_id: common::USER1_ID.into(),
+            content: "garbage".into(),
+            display_name: "Temporary".into(),
+            icon_url: None,
+            website_url: None,
+            expires_at: Some(expires_at),
+            sort_index: 0,
+            issuer: None,
+            account: None,
+            kind: "totp".into(),
+            digits: 6,
+            period: 30,
+            algorithm: "SHA1".into(),
+            favorite: false,
+            folder_id: None,
+            created_at: 0,
+            updated_at: 0,
+            revision: 0,
+            deleted_at: None,
+        }
+        .insert(&db)
+        .await
+        .unwrap();
+    }
+
+    let removed = iceblink_sync::tasks::purge_expired_codes(&db).await;
+    assert_that!(removed, eq(1));
+
+    let remaining: Vec<String> = sqlx::query_scalar("SELECT id FROM codes WHERE owner_id = ?")
+        .bind(common::USER1_ID)
+        .fetch_all(&db)
+        .await
+        .unwrap();
+    assert_that!(
+        remaining,
+        unordered_elements_are![
+            eq(common::USER1_CODE1_ID),
+            eq(common::USER1_CODE2_ID),
+            eq("notexpiredyet000")
+        ]
+    );
+}
+
 //
 // Icons
 //
@@ -499,3 +1162,1576 @@ async fn icon_returns_same(db: SqlitePool) {
 // TODO: Icon Test: with invalid website url
 // TODO: Icon Test: with 404 on favicon
 // TODO: Icon Test: what if website returns non-ico?
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn move_code_up_swaps_with_previous(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let move_request = common::move_code(&app, a1.as_str(), common::USER1_CODE2_ID, "up").await;
+    assert_that!(move_request.status(), eq(StatusCode::OK));
+
+    let codes: Vec<models::codes::Code> =
+        serde_json::from_value(common::convert_response(move_request).await).unwrap();
+    let order: Vec<&str> = codes.iter().map(|c| c.id.as_str()).collect();
+    assert_that!(
+        order,
+        elements_are![eq(common::USER1_CODE2_ID), eq(common::USER1_CODE1_ID)]
+    );
+
+    // The new order is persisted
+    let listing_request = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing_request, eq(&codes));
+    expect_that!(listing_request[0].sort_index, eq(0));
+    expect_that!(listing_request[1].sort_index, eq(1));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn move_code_up_first_is_noop(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let move_request = common::move_code(&app, a1.as_str(), common::USER1_CODE1_ID, "up").await;
+    assert_that!(move_request.status(), eq(StatusCode::OK));
+
+    let listing_request = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing_request, common::matchers::code_fixture());
+    assert_that!(listing_request[0].id, eq(common::USER1_CODE1_ID));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn move_code_down_last_is_noop(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let move_request = common::move_code(&app, a1.as_str(), common::USER1_CODE2_ID, "down").await;
+    assert_that!(move_request.status(), eq(StatusCode::OK));
+
+    let listing_request = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing_request, common::matchers::code_fixture());
+    assert_that!(listing_request[1].id, eq(common::USER1_CODE2_ID));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn move_code_other_user(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (_, a2) = common::get_access_tokens(&db).await;
+
+    let move_request = common::move_code(&app, a2.as_str(), common::USER1_CODE2_ID, "up").await;
+    assert_that!(move_request.status(), eq(StatusCode::NOT_FOUND));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn reorder_codes(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let add_request = common::add_code(
+        &app,
+        a1.as_str(),
+        &json!({ "content": "yippie", "display_name": "Modrinth" }),
+    )
+    .await;
+    let added: models::codes::Code =
+        serde_json::from_value(common::convert_response(add_request).await).unwrap();
+
+    // Only the listed codes swap places, the one in between stays put
+    let reorder_request = common::reorder_codes(
+        &app,
+        a1.as_str(),
+        &[added.id.as_str(), common::USER1_CODE1_ID],
+    )
+    .await;
+    assert_that!(reorder_request.status(), eq(StatusCode::OK));
+    let codes: Vec<models::codes::Code> =
+        serde_json::from_value(common::convert_response(reorder_request).await).unwrap();
+    let order: Vec<&str> = codes.iter().map(|c| c.id.as_str()).collect();
+    expect_that!(
+        order,
+        elements_are![
+            eq(added.id.as_str()),
+            eq(common::USER1_CODE2_ID),
+            eq(common::USER1_CODE1_ID)
+        ]
+    );
+    expect_that!(codes[1].revision, eq(0));
+    expect_that!(codes[2].revision, gt(added.revision));
+
+    let listing_request = common::list_codes_content(&app, a1.as_str()).await;
+    expect_that!(listing_request, eq(&codes));
+
+    let reorder_request = common::reorder_codes(
+        &app,
+        a1.as_str(),
+        &[common::USER1_CODE1_ID, common::USER1_CODE1_ID],
+    )
+    .await;
+    expect_that!(reorder_request.status(), eq(StatusCode::BAD_REQUEST));
+    let reorder_request = common::reorder_codes(
+        &app,
+        a1.as_str(),
+        &[common::USER1_CODE1_ID, common::USER2_CODE1_ID],
+    )
+    .await;
+    expect_that!(reorder_request.status(), eq(StatusCode::BAD_REQUEST));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn favorite_code(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let edit_request = common::edit_code(
+        &app,
+        a1.as_str(),
+        common::USER1_CODE2_ID,
+        &json!({ "favorite": true }),
+    )
+    .await;
+    assert_that!(edit_request.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::convert_response(edit_request).await["favorite"],
+        eq(&json!(true))
+    );
+
+    let listing_request = common::list_codes_content(&app, a1.as_str()).await;
+    expect_that!(listing_request[0].favorite, eq(false));
+    expect_that!(listing_request[1].favorite, eq(true));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn add_code_appends_to_listing(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let add_request = common::add_code(
+        &app,
+        a1.as_str(),
+        &json!({
+            "content": "yippie",
+            "display_name": "Modrinth",
+            "website_url": null
+        }),
+    )
+    .await;
+    assert_that!(add_request.status(), eq(StatusCode::OK));
+
+    let listing_request = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing_request.len(), eq(3));
+    expect_that!(listing_request[2].display_name, eq("Modrinth"));
+    expect_that!(listing_request[2].sort_index, eq(2));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn get_many_codes_mixed_ids(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let get_request = common::get_many_codes(
+        &app,
+        a1.as_str(),
+        &json!({
+            "ids": [
+                common::USER1_CODE2_ID,
+                "doesnotexist0000",
+                common::USER2_CODE1_ID,
+                common::USER1_CODE1_ID
+            ]
+        }),
+    )
+    .await;
+    assert_that!(get_request.status(), eq(StatusCode::OK));
+
+    let response = common::convert_response(get_request).await;
+    let codes: Vec<models::codes::Code> =
+        serde_json::from_value(response["codes"].clone()).unwrap();
+    assert_that!(codes, common::matchers::code_fixture());
+    assert_that!(
+        response["missing"],
+        eq(&json!(["doesnotexist0000", common::USER2_CODE1_ID]))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn get_many_codes_empty(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let get_request = common::get_many_codes(&app, a1.as_str(), &json!({ "ids": [] })).await;
+    assert_that!(get_request.status(), eq(StatusCode::OK));
+    assert_that!(
+        common::convert_response(get_request).await,
+        eq(&json!({ "codes": [], "missing": [] }))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn get_many_codes_too_many_ids(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let ids: Vec<String> = (0..=models::codes::Code::MAX_IDS_PER_QUERY)
+        .map(|i| format!("{i:016}"))
+        .collect();
+    let get_request = common::get_many_codes(&app, a1.as_str(), &json!({ "ids": ids })).await;
+    assert_that!(get_request.status(), eq(StatusCode::BAD_REQUEST));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn recovery_sheet_lists_codes(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let sheet_request = common::get_recovery_sheet(&app, a1.as_str()).await;
+    assert_that!(sheet_request.status(), eq(StatusCode::OK));
+    assert_that!(
+        sheet_request.headers().get("Cache-Control").unwrap(),
+        eq("no-store")
+    );
+    assert_that!(
+        sheet_request
+            .headers()
+            .get("Content-Type")
+            .unwrap()
+            .to_str()
+            .unwrap(),
+        starts_with("text/html")
+    );
+
+    let sheet = common::convert_response_str(sheet_request).await;
+    assert_that!(sheet.matches("<section>").count(), eq(2));
+    expect_that!(sheet, contains_substring("<h2>Google</h2>"));
+    expect_that!(sheet, contains_substring("<h2>google.com</h2>"));
+    expect_that!(sheet, contains_substring(common::USER1_CODE1_CONTENT));
+    expect_that!(sheet, contains_substring(common::USER1_CODE2_CONTENT));
+    expect_that!(
+        sheet,
+        contains_substring("otpauth://totp/Google?secret=GK6ZFMqk18fuWnCw&amp;issuer=google.com")
+    );
+    expect_that!(sheet, not(contains_substring(common::USER2_CODE1_CONTENT)));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn recovery_sheet_without_qr_for_long_codes(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    // Codes stored before lengths were limited may not fit into a QR code
+    let display_name = "a".repeat(4096);
+    sqlx::query("UPDATE codes SET display_name = ? WHERE id = ?")
+        .bind(&display_name)
+        .bind(common::USER1_CODE1_ID)
+        .execute(&db)
+        .await
+        .unwrap();
+
+    let sheet_request = common::get_recovery_sheet(&app, a1.as_str()).await;
+    assert_that!(sheet_request.status(), eq(StatusCode::OK));
+
+    let sheet = common::convert_response_str(sheet_request).await;
+    assert_that!(sheet.matches("<section>").count(), eq(2));
+    expect_that!(sheet.matches("<svg").count(), eq(1));
+    expect_that!(sheet, contains_substring("Too long for a QR code"));
+    expect_that!(sheet, contains_substring(common::USER1_CODE1_CONTENT));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn overlong_code_fields_rejected(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let added = common::add_code(
+        &app,
+        &a1,
+        &json!({
+            "content": "JBSWY3DPEHPK3PXP",
+            "display_name": "a".repeat(models::codes::Code::MAX_DISPLAY_NAME_LENGTH + 1),
+        }),
+    )
+    .await;
+    assert_that!(added.status(), eq(StatusCode::BAD_REQUEST));
+    expect_that!(
+        common::convert_response(added).await["message"],
+        eq(&json!(
+            "The display_name of a code can be at most 512 characters."
+        ))
+    );
+
+    let edited = common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({
+            "website_url": format!("https://{}.com", "a".repeat(models::codes::Code::MAX_WEBSITE_URL_LENGTH)),
+        }),
+    )
+    .await;
+    assert_that!(edited.status(), eq(StatusCode::BAD_REQUEST));
+
+    let listing_request = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing_request, common::matchers::code_fixture());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn recovery_sheet_requires_recent_login(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let now = chrono::Utc::now();
+
+    let session = models::sessions::Session {
+        id: "a7Gf0Qk2LmZr9XsPq1WvBn3E".into(),
+        user_id: common::USER1_ID.into(),
+        created_at: (now - chrono::Duration::hours(1)).timestamp(),
+        last_activity: now.timestamp(),
+        device: "".into(),
+        refresh_token_hash: None,
+        previous_refresh_token_hash: None,
+        user_agent: "".into(),
+        ip: None,
+        upstream_issuer: None,
+        upstream_sid: None,
+        upstream_access_token: None,
+        device_id: None,
+    };
+    session.insert(&db).await.unwrap();
+
+    let stale_jwt = jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &iceblink_sync::auth::TokenClaims {
+            iat: (now - chrono::Duration::hours(1)).timestamp() as usize,
+            exp: (now + chrono::Duration::days(1)).timestamp() as usize,
+            sub: common::USER1_ID.into(),
+            username: "user1".into(),
+            display_name: "User 1".into(),
+            avatar_url: "".into(),
+            sid: session.id,
+        },
+        &jsonwebtoken::EncodingKey::from_secret("my jwt secret".as_ref()),
+    )
+    .unwrap();
+
+    let sheet_request = common::get_recovery_sheet(&app, stale_jwt.as_str()).await;
+    assert_that!(sheet_request.status(), eq(StatusCode::UNAUTHORIZED));
+    assert_that!(
+        common::convert_response(sheet_request).await["errorKind"],
+        eq(&json!("ReauthenticationRequired"))
+    );
+
+    // API tokens never count as a recent login
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let token = common::create_token(&app, a1.as_str(), "full").await;
+    let sheet_request = common::get_recovery_sheet(&app, token.as_str()).await;
+    assert_that!(sheet_request.status(), eq(StatusCode::UNAUTHORIZED));
+
+    let token = common::create_token(&app, a1.as_str(), "read:metadata").await;
+    let sheet_request = common::get_recovery_sheet(&app, token.as_str()).await;
+    assert_that!(sheet_request.status(), eq(StatusCode::FORBIDDEN));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn list_codes_selected_fields(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = common::get_codes_path(&app, &a1, "?fields=id,display_name").await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let codes = common::convert_response(response).await;
+    assert_that!(codes.as_array().unwrap().len(), eq(2));
+    for code in codes.as_array().unwrap() {
+        expect_that!(
+            code.as_object().unwrap().keys().collect::<Vec<_>>(),
+            unordered_elements_are![eq(&"id"), eq(&"display_name")]
+        );
+    }
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn get_code_selected_fields(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let response = common::get_codes_path(
+        &app,
+        &a1,
+        &format!("/{}?fields=id,website_url", common::USER1_CODE1_ID),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(
+        common::convert_response(response).await,
+        eq(&json!({
+            "id": common::USER1_CODE1_ID,
+            "website_url": "google.com"
+        }))
+    );
+
+    let response = common::get_codes_path(&app, &a1, &format!("/{}", common::USER1_CODE1_ID)).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(
+        common::convert_response(response).await["content"],
+        eq(&json!(common::USER1_CODE1_CONTENT))
+    );
+
+    let response = common::get_codes_path(&app, &a2, &format!("/{}", common::USER1_CODE1_ID)).await;
+    assert_that!(response.status(), eq(StatusCode::NOT_FOUND));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn unknown_field_selected(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    for path in [
+        "?fields=id,password".to_string(),
+        "?fields=".to_string(),
+        format!("/{}?fields=id,,display_name", common::USER1_CODE1_ID),
+    ] {
+        let response = common::get_codes_path(&app, &a1, &path).await;
+        expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+        expect_that!(
+            common::convert_response(response).await["errorKind"],
+            eq(&json!("BadRequest"))
+        );
+    }
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn verify_totp_within_skew(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            totp_skew: 2,
+            ..common::testing_options()
+        },
+    )
+    .await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let secret = "JBSWY3DPEHPK3PXP";
+    let added = common::add_code(
+        &app,
+        &a1,
+        &json!({
+            "content": secret,
+            "display_name": "Permafrost",
+        }),
+    )
+    .await;
+    let id = common::convert_response(added).await["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let key = totp::decode_secret(secret).unwrap();
+    let step = chrono::Utc::now().timestamp() / totp::PERIOD;
+
+    // Steps are chosen so a step boundary passing mid-test doesn't change the outcome
+    for (offset, valid) in [(0, true), (-1, true), (1, true), (-4, false), (4, false)] {
+        let response =
+            common::verify_code(&app, &a1, &id, &totp::generate(&key, step + offset)).await;
+        assert_that!(response.status(), eq(StatusCode::OK));
+        expect_that!(
+            common::convert_response(response).await["valid"],
+            eq(&json!(valid)),
+            "offset {offset}"
+        );
+    }
+
+    let response = common::verify_code(&app, &a2, &id, &totp::generate(&key, step)).await;
+    expect_that!(response.status(), eq(StatusCode::NOT_FOUND));
+
+    // The fixture content isn't base32
+    let response = common::verify_code(&app, &a1, common::USER1_CODE1_ID, "123456").await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn clone_code(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    let original = common::list_codes_content(&app, &a1).await[0].clone();
+
+    let response = common::clone_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "content": "JBSWY3DPEHPK3PXP" }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let clone: models::codes::Code =
+        serde_json::from_value(common::convert_response(response).await).unwrap();
+
+    expect_that!(clone.id, not(eq(&original.id)));
+    expect_that!(clone.id.len(), eq(16));
+    expect_that!(clone.owner_id, eq(common::USER1_ID));
+    expect_that!(clone.content, eq("JBSWY3DPEHPK3PXP"));
+    expect_that!(
+        clone.display_name,
+        eq(&format!("{} copy", original.display_name))
+    );
+    expect_that!(clone.website_url, eq(&original.website_url));
+    expect_that!(clone.icon_url, eq(&original.icon_url));
+    expect_that!(clone.sort_index, eq(2));
+
+    // The secret is only copied when asked to
+    let response = common::clone_code(&app, &a1, common::USER1_CODE1_ID, &json!({})).await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+
+    let response = common::clone_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "copy_content": true }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::convert_response(response).await["content"],
+        eq(&json!(common::USER1_CODE1_CONTENT))
+    );
+
+    // Only owners can clone codes
+    let response = common::clone_code(
+        &app,
+        &a2,
+        common::USER1_CODE1_ID,
+        &json!({ "copy_content": true }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::NOT_FOUND));
+    expect_that!(common::list_codes_content(&app, &a2).await.len(), eq(1));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn code_quota(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            max_codes_per_user: 3,
+            ..common::testing_options()
+        },
+    )
+    .await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    // Codes created earlier in a batch count, so only the first of two fits next to the fixtures
+    let response = common::batch_codes(
+        &app,
+        &a1,
+        &json!({ "operations": [
+            { "op": "create", "content": "JBSWY3DPEHPK3PXP", "display_name": "Permafrost" },
+            { "op": "create", "content": "KRSXG5CTMVRXEZLU", "display_name": "Glacier" }
+        ]}),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::UNPROCESSABLE_ENTITY));
+    let results = common::convert_response(response).await["results"].clone();
+    expect_that!(results[0]["status"], eq(&json!(200)));
+    expect_that!(results[1]["status"], eq(&json!(403)));
+    expect_that!(
+        results[1]["error"]["errorKind"],
+        eq(&json!("CodeQuotaExceeded"))
+    );
+
+    let permafrost = json!({ "content": "JBSWY3DPEHPK3PXP", "display_name": "Permafrost" });
+    let glacier = json!({ "content": "KRSXG5CTMVRXEZLU", "display_name": "Glacier" });
+    let response = common::add_code(&app, &a1, &permafrost).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let response = common::add_code(&app, &a1, &glacier).await;
+    expect_that!(response.status(), eq(StatusCode::FORBIDDEN));
+    let response = common::clone_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "copy_content": true }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::FORBIDDEN));
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/v1/user/usage")
+                .header("Authorization", format!("Bearer {a1}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::convert_response(response).await,
+        eq(&json!({
+            "codes": 3,
+            "icon_bytes": 0,
+            "quota": { "max_codes": 3, "max_icon_bytes": null },
+        }))
+    );
+
+    // Deleting a code makes room, which restoring it needs again
+    let response = common::delete_code(&app, &a1, common::USER1_CODE1_ID).await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+    let response = common::add_code(&app, &a1, &glacier).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let response = common::restore_code(&app, &a1, common::USER1_CODE1_ID).await;
+    expect_that!(response.status(), eq(StatusCode::FORBIDDEN));
+
+    // Other users have a quota of their own
+    let response = common::add_code(&app, &a2, &glacier).await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn duplicate_names_allowed_by_default(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = common::add_code(
+        &app,
+        &a1,
+        &json!({ "content": "JBSWY3DPEHPK3PXP", "display_name": "Google" }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+
+    let response = common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE2_ID,
+        &json!({ "display_name": "Google" }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn duplicate_names_rejected_when_enforced(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let response = common::edit_settings(&app, &a1, &json!({ "enforce_unique_names": true })).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::convert_response(response).await,
+        eq(&json!({ "enforce_unique_names": true }))
+    );
+
+    let response = common::add_code(
+        &app,
+        &a1,
+        &json!({ "content": "JBSWY3DPEHPK3PXP", "display_name": "Google" }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::CONFLICT));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("DuplicateName"))
+    );
+
+    let response = common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE2_ID,
+        &json!({ "display_name": "Google" }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::CONFLICT));
+    expect_that!(common::list_codes_content(&app, &a1).await.len(), eq(2));
+
+    // Keeping the name of the code itself isn't a collision
+    let response = common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "display_name": "Google" }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+
+    let response = common::clone_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "copy_content": true }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+    let response = common::clone_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "copy_content": true }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::CONFLICT));
+
+    // Names of other users don't count, nor is the setting shared
+    let response = common::add_code(
+        &app,
+        &a2,
+        &json!({ "content": "JBSWY3DPEHPK3PXP", "display_name": "Google" }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+    let response = common::add_code(
+        &app,
+        &a2,
+        &json!({ "content": "GEZDGNBVGY3TQOJQ", "display_name": "Google" }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn delta_sync(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    let ids = |delta: &serde_json::Value, key: &str| -> Vec<String> {
+        delta[key]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|code| code.get("id").unwrap_or(code).as_str().unwrap().to_string())
+            .collect()
+    };
+
+    // Without a cursor, every code is sent
+    let full = common::convert_response(common::delta_codes(&app, &a1, None).await).await;
+    expect_that!(
+        ids(&full, "changed"),
+        unordered_elements_are![eq(common::USER1_CODE1_ID), eq(common::USER1_CODE2_ID)]
+    );
+    expect_that!(ids(&full, "deleted"), empty());
+    let cursor = full["cursor"].as_i64().unwrap();
+
+    let unchanged =
+        common::convert_response(common::delta_codes(&app, &a1, Some(cursor)).await).await;
+    expect_that!(
+        unchanged,
+        eq(&json!({ "changed": [], "deleted": [], "cursor": cursor }))
+    );
+
+    let added = common::convert_response(
+        common::add_code(
+            &app,
+            &a1,
+            &json!({ "content": "JBSWY3DPEHPK3PXP", "display_name": "Added" }),
+        )
+        .await,
+    )
+    .await;
+    let response = common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE2_ID,
+        &json!({ "display_name": "Modrinth" }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let response = common::delete_code(&app, &a1, common::USER1_CODE1_ID).await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+
+    let delta = common::convert_response(common::delta_codes(&app, &a1, Some(cursor)).await).await;
+    expect_that!(
+        ids(&delta, "changed"),
+        elements_are![
+            eq(added["id"].as_str().unwrap()),
+            eq(common::USER1_CODE2_ID)
+        ]
+    );
+    expect_that!(delta["changed"][1]["display_name"], eq(&json!("Modrinth")));
+    expect_that!(
+        ids(&delta, "deleted"),
+        elements_are![eq(common::USER1_CODE1_ID)]
+    );
+    let next_cursor = delta["cursor"].as_i64().unwrap();
+    expect_that!(next_cursor, gt(cursor));
+
+    // Deleted codes are gone from the listing, and changes of other users aren't included
+    expect_that!(common::list_codes_content(&app, &a1).await.len(), eq(2));
+    let response = common::edit_code(
+        &app,
+        &a2,
+        common::USER2_CODE1_ID,
+        &json!({ "display_name": "Other" }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let delta =
+        common::convert_response(common::delta_codes(&app, &a1, Some(next_cursor)).await).await;
+    expect_that!(ids(&delta, "changed"), empty());
+    expect_that!(ids(&delta, "deleted"), empty());
+    expect_that!(delta["cursor"].as_i64().unwrap(), gt(next_cursor));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn delta_sync_invalid_cursor(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = common::delta_codes(&app, &a1, Some(-1)).await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn batch_applies_all_operations(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = common::batch_codes(
+        &app,
+        &a1,
+        &json!({ "operations": [
+            { "op": "create", "content": "JBSWY3DPEHPK3PXP", "display_name": "Batched" },
+            { "op": "update", "id": common::USER1_CODE2_ID, "changes": { "display_name": "Renamed" } },
+            { "op": "delete", "id": common::USER1_CODE1_ID }
+        ]}),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let body = common::convert_response(response).await;
+
+    expect_that!(body["committed"], eq(&json!(true)));
+    let statuses: Vec<i64> = body["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|result| result["status"].as_i64().unwrap())
+        .collect();
+    expect_that!(statuses, elements_are![eq(&200), eq(&200), eq(&204)]);
+    expect_that!(
+        body["results"][0]["code"]["display_name"],
+        eq(&json!("Batched"))
+    );
+    expect_that!(
+        body["results"][1]["code"]["display_name"],
+        eq(&json!("Renamed"))
+    );
+
+    let names: Vec<String> = common::list_codes_content(&app, &a1)
+        .await
+        .into_iter()
+        .map(|code| code.display_name)
+        .collect();
+    expect_that!(names, elements_are![eq("Renamed"), eq("Batched")]);
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn batch_rolls_back_on_failure(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let before = common::list_codes_content(&app, &a1).await;
+
+    // Codes of other users can't be touched, failing the entire batch
+    let response = common::batch_codes(
+        &app,
+        &a1,
+        &json!({ "operations": [
+            { "op": "delete", "id": common::USER1_CODE1_ID },
+            { "op": "delete", "id": common::USER2_CODE1_ID },
+            { "op": "create", "content": "JBSWY3DPEHPK3PXP", "display_name": "Batched" }
+        ]}),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::UNPROCESSABLE_ENTITY));
+    let body = common::convert_response(response).await;
+
+    expect_that!(body["committed"], eq(&json!(false)));
+    expect_that!(body["results"][0]["status"], eq(&json!(204)));
+    expect_that!(body["results"][1]["status"], eq(&json!(404)));
+    expect_that!(
+        body["results"][1]["error"]["errorKind"],
+        eq(&json!("NotFound"))
+    );
+    expect_that!(body["results"][2]["status"], eq(&json!(200)));
+
+    expect_that!(common::list_codes_content(&app, &a1).await, eq(&before));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn batch_sees_earlier_operations(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    common::edit_settings(&app, &a1, &json!({ "enforce_unique_names": true })).await;
+
+    let response = common::batch_codes(
+        &app,
+        &a1,
+        &json!({ "operations": [
+            { "op": "create", "content": "JBSWY3DPEHPK3PXP", "display_name": "Twin" },
+            { "op": "create", "content": "JBSWY3DPEHPK3PXP", "display_name": "Twin" }
+        ]}),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::UNPROCESSABLE_ENTITY));
+    let body = common::convert_response(response).await;
+    expect_that!(body["results"][1]["status"], eq(&json!(409)));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn batch_rejects_too_many_operations(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let operations: Vec<_> = (0..101)
+        .map(|_| json!({ "op": "delete", "id": common::USER1_CODE1_ID }))
+        .collect();
+    let response = common::batch_codes(&app, &a1, &json!({ "operations": operations })).await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn list_codes_paginated(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let response = common::add_code(
+        &app,
+        &a1,
+        &json!({ "content": "JBSWY3DPEHPK3PXP", "display_name": "Amazon" }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let names = |codes: serde_json::Value| -> Vec<String> {
+        codes
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|code| code["display_name"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    let response = common::get_codes_path(&app, &a1, "?limit=2").await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(response.headers()["X-Total-Count"], eq("3"));
+    expect_that!(
+        names(common::convert_response(response).await),
+        elements_are![eq("Google"), eq("google.com")]
+    );
+
+    let response = common::get_codes_path(&app, &a1, "?limit=2&offset=2").await;
+    expect_that!(response.headers()["X-Total-Count"], eq("3"));
+    expect_that!(
+        names(common::convert_response(response).await),
+        elements_are![eq("Amazon")]
+    );
+
+    let response = common::get_codes_path(&app, &a1, "?sort=name").await;
+    expect_that!(
+        names(common::convert_response(response).await),
+        elements_are![eq("Amazon"), eq("Google"), eq("google.com")]
+    );
+
+    let response = common::get_codes_path(&app, &a1, "?sort=updated&limit=1").await;
+    expect_that!(
+        names(common::convert_response(response).await),
+        elements_are![eq("Amazon")]
+    );
+
+    let response = common::get_codes_path(&app, &a1, "?sort=created&limit=1").await;
+    expect_that!(
+        names(common::convert_response(response).await),
+        elements_are![eq("Amazon")]
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn created_at_kept_on_edit(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let before = chrono::Utc::now().timestamp();
+
+    let response = common::add_code(
+        &app,
+        &a1,
+        &json!({ "content": "JBSWY3DPEHPK3PXP", "display_name": "GitHub" }),
+    )
+    .await;
+    let code = common::convert_response(response).await;
+    let created_at = code["created_at"].as_i64().unwrap();
+    expect_that!(created_at, ge(before));
+    expect_that!(code["updated_at"], eq(&json!(created_at)));
+
+    let response = common::edit_code(
+        &app,
+        &a1,
+        code["id"].as_str().unwrap(),
+        &json!({ "display_name": "GitHub (work)" }),
+    )
+    .await;
+    let edited = common::convert_response(response).await;
+    expect_that!(edited["created_at"], eq(&json!(created_at)));
+    expect_that!(edited["updated_at"].as_i64().unwrap(), ge(created_at));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn list_codes_invalid_page(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    for query in ["?limit=0", "?limit=501", "?offset=-1", "?sort=random"] {
+        let response = common::get_codes_path(&app, &a1, query).await;
+        expect_that!(response.status(), eq(StatusCode::BAD_REQUEST), "{query}");
+    }
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn tombstones_purged_after_retention(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let cursor = common::convert_response(common::delta_codes(&app, &a1, None).await).await
+        ["cursor"]
+        .as_i64()
+        .unwrap();
+
+    let response = common::delete_code(&app, &a1, common::USER1_CODE1_ID).await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+
+    // Recent tombstones are kept
+    expect_that!(
+        iceblink_sync::tasks::purge_tombstones(&db, Duration::from_secs(3600)).await,
+        eq(0)
+    );
+    let delta = common::convert_response(common::delta_codes(&app, &a1, Some(cursor)).await).await;
+    expect_that!(delta["deleted"], eq(&json!([common::USER1_CODE1_ID])));
+
+    expect_that!(
+        iceblink_sync::tasks::purge_tombstones(&db, Duration::ZERO).await,
+        eq(1)
+    );
+    let remaining: Vec<String> = sqlx::query_scalar("SELECT id FROM codes WHERE owner_id = ?")
+        .bind(common::USER1_ID)
+        .fetch_all(&db)
+        .await
+        .unwrap();
+    expect_that!(remaining, elements_are![eq(common::USER1_CODE2_ID)]);
+
+    // Having missed the deletion, the client has to sync in full
+    let response = common::delta_codes(&app, &a1, Some(cursor)).await;
+    expect_that!(response.status(), eq(StatusCode::GONE));
+
+    let full = common::convert_response(common::delta_codes(&app, &a1, None).await).await;
+    expect_that!(full["deleted"], eq(&json!([])));
+    let response = common::delta_codes(&app, &a1, Some(full["cursor"].as_i64().unwrap())).await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn bulk_delete_codes(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let response = common::bulk_delete_codes(
+        &app,
+        &a1,
+        &[
+            common::USER1_CODE1_ID,
+            "random-id",
+            common::USER2_CODE1_ID,
+            common::USER1_CODE1_ID,
+        ],
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let results = common::convert_response(response).await;
+    expect_that!(
+        results,
+        eq(&json!([
+            { "id": common::USER1_CODE1_ID, "status": 204 },
+            { "id": "random-id", "status": 404, "error": { "message": "Resource not found.", "errorKind": "NotFound" } },
+            { "id": common::USER2_CODE1_ID, "status": 404, "error": { "message": "Resource not found.", "errorKind": "NotFound" } },
+            { "id": common::USER1_CODE1_ID, "status": 404, "error": { "message": "Resource not found.", "errorKind": "NotFound" } }
+        ]))
+    );
+
+    // Codes which couldn't be deleted don't roll back the others
+    let codes = common::list_codes_content(&app, &a1).await;
+    expect_that!(
+        codes
+            .iter()
+            .map(|code| code.id.as_str())
+            .collect::<Vec<_>>(),
+        elements_are![eq(common::USER1_CODE2_ID)]
+    );
+    let victim_codes = common::list_codes_content(&app, &a2).await;
+    expect_that!(victim_codes, common::matchers::code_fixture());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn bulk_delete_too_many_codes(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let ids = vec![common::USER1_CODE1_ID; 501];
+    let response = common::bulk_delete_codes(&app, &a1, &ids).await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+
+    let codes = common::list_codes_content(&app, &a1).await;
+    expect_that!(codes, common::matchers::code_fixture());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn restore_code_from_trash(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    let cursor = common::convert_response(common::delta_codes(&app, &a1, None).await).await
+        ["cursor"]
+        .as_i64()
+        .unwrap();
+
+    let response = common::delete_code(&app, &a1, common::USER1_CODE1_ID).await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+
+    let response = common::list_trash(&app, &a1).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let trash = common::convert_response(response).await;
+    assert_that!(trash.as_array().unwrap().len(), eq(1));
+    expect_that!(trash[0]["id"], eq(&json!(common::USER1_CODE1_ID)));
+    expect_that!(trash[0]["display_name"], eq(&json!("Google")));
+    expect_that!(trash[0]["deleted_at"].as_i64(), some(gt(0)));
+
+    // The trash of others is out of reach
+    let response = common::restore_code(&app, &a2, common::USER1_CODE1_ID).await;
+    expect_that!(response.status(), eq(StatusCode::NOT_FOUND));
+    let trash = common::convert_response(common::list_trash(&app, &a2).await).await;
+    expect_that!(trash, eq(&json!([])));
+
+    let response = common::restore_code(&app, &a1, common::USER1_CODE1_ID).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let restored = common::convert_response(response).await;
+    expect_that!(restored["id"], eq(&json!(common::USER1_CODE1_ID)));
+
+    // Restored codes are appended to the listing, and reach syncing clients again
+    let codes = common::list_codes_content(&app, &a1).await;
+    expect_that!(
+        codes
+            .iter()
+            .map(|code| code.id.as_str())
+            .collect::<Vec<_>>(),
+        elements_are![eq(common::USER1_CODE2_ID), eq(common::USER1_CODE1_ID)]
+    );
+    let delta = common::convert_response(common::delta_codes(&app, &a1, Some(cursor)).await).await;
+    expect_that!(
+        delta["changed"][0]["id"],
+        eq(&json!(common::USER1_CODE1_ID))
+    );
+    expect_that!(delta["deleted"], eq(&json!([])));
+
+    let trash = common::convert_response(common::list_trash(&app, &a1).await).await;
+    expect_that!(trash, eq(&json!([])));
+    let response = common::restore_code(&app, &a1, common::USER1_CODE1_ID).await;
+    expect_that!(response.status(), eq(StatusCode::NOT_FOUND));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn trash_emptied_after_retention(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = common::delete_code(&app, &a1, common::USER1_CODE1_ID).await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+
+    expect_that!(
+        iceblink_sync::tasks::empty_trash(&db, Duration::from_secs(3600)).await,
+        eq(0)
+    );
+    expect_that!(
+        iceblink_sync::tasks::empty_trash(&db, Duration::ZERO).await,
+        eq(1)
+    );
+
+    // The secret is gone, but the tombstone is left for syncing clients
+    let trash = common::convert_response(common::list_trash(&app, &a1).await).await;
+    expect_that!(trash, eq(&json!([])));
+    let response = common::restore_code(&app, &a1, common::USER1_CODE1_ID).await;
+    expect_that!(response.status(), eq(StatusCode::NOT_FOUND));
+    let content: String = sqlx::query_scalar("SELECT content FROM codes WHERE id = ?")
+        .bind(common::USER1_CODE1_ID)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    expect_that!(content, eq(""));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn restore_code_duplicate_name(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let response = common::edit_settings(&app, &a1, &json!({ "enforce_unique_names": true })).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let response = common::delete_code(&app, &a1, common::USER1_CODE1_ID).await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+    let response = common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE2_ID,
+        &json!({ "display_name": "Google" }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let response = common::restore_code(&app, &a1, common::USER1_CODE1_ID).await;
+    expect_that!(response.status(), eq(StatusCode::CONFLICT));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn code_history_rollback(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    let history = |token: &str| {
+        app.clone().oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri(format!("/v1/code/{}/history", common::USER1_CODE1_ID))
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+    let rollback = |revision: i64| {
+        app.clone().oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(format!(
+                    "/v1/code/{}/history/{revision}/restore",
+                    common::USER1_CODE1_ID
+                ))
+                .header("Authorization", format!("Bearer {a1}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let response = common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "content": "MANGLED", "display_name": "Gogle" }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    // Arranging codes doesn't record a version
+    let response = common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "favorite": true }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let response = history(&a1).await.unwrap();
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let versions = common::convert_response(response).await;
+    assert_that!(versions.as_array().unwrap().len(), eq(1));
+    expect_that!(versions[0]["revision"], eq(&json!(0)));
+    expect_that!(versions[0]["content"], eq(&json!("GK6ZFMqk18fuWnCw")));
+    expect_that!(versions[0]["display_name"], eq(&json!("Google")));
+
+    // The history of others is out of reach
+    let response = history(&a2).await.unwrap();
+    expect_that!(response.status(), eq(StatusCode::NOT_FOUND));
+
+    let response = rollback(12).await.unwrap();
+    expect_that!(response.status(), eq(StatusCode::NOT_FOUND));
+    let response = rollback(0).await.unwrap();
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let restored = common::convert_response(response).await;
+    expect_that!(restored["content"], eq(&json!("GK6ZFMqk18fuWnCw")));
+    expect_that!(restored["display_name"], eq(&json!("Google")));
+    expect_that!(restored["favorite"], eq(&json!(true)));
+
+    // The mangled version is kept, so the rollback can be undone
+    let versions = common::convert_response(history(&a1).await.unwrap()).await;
+    assert_that!(versions.as_array().unwrap().len(), eq(2));
+    expect_that!(versions[0]["content"], eq(&json!("MANGLED")));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn edit_code_expected_revision(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "display_name": "First device", "expected_revision": 0 }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let revision = common::convert_response(response).await["revision"]
+        .as_i64()
+        .unwrap();
+
+    // The second device still has the code at revision 0, so its edit conflicts
+    let response = common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "display_name": "Second device", "expected_revision": 0 }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::CONFLICT));
+    let body = common::convert_response(response).await;
+    expect_that!(body["errorKind"], eq(&json!("RevisionConflict")));
+    expect_that!(body["current"]["display_name"], eq(&json!("First device")));
+    expect_that!(body["current"]["revision"], eq(&json!(revision)));
+
+    // Having merged, it retries with the current revision in `If-Match`
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::PATCH)
+                .uri(format!("/v1/code/{}", common::USER1_CODE1_ID))
+                .header("Authorization", format!("Bearer {a1}"))
+                .header("Content-Type", "application/json")
+                .header("If-Match", format!("\"{revision}\""))
+                .body(Body::from(json!({ "display_name": "Merged" }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    expect_that!(response.status(), eq(StatusCode::OK));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn delete_code_expected_revision(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let delete = |if_match: &'static str| {
+        app.clone().oneshot(
+            Request::builder()
+                .method(Method::DELETE)
+                .uri(format!("/v1/code/{}", common::USER1_CODE1_ID))
+                .header("Authorization", format!("Bearer {a1}"))
+                .header("If-Match", if_match)
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let response = delete("12").await.unwrap();
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+
+    let response = delete("\"12\"").await.unwrap();
+    assert_that!(response.status(), eq(StatusCode::CONFLICT));
+    expect_that!(
+        common::convert_response(response).await["current"]["id"],
+        eq(&json!(common::USER1_CODE1_ID))
+    );
+
+    let response = delete("\"0\"").await.unwrap();
+    expect_that!(response.status(), eq(StatusCode::NO_CONTENT));
+    let response = delete("*").await.unwrap();
+    expect_that!(response.status(), eq(StatusCode::NOT_FOUND));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn batch_expected_revision(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = common::batch_codes(
+        &app,
+        &a1,
+        &json!({ "operations": [
+            { "op": "update", "id": common::USER1_CODE1_ID, "changes": { "display_name": "Renamed", "expected_revision": 0 } },
+            { "op": "delete", "id": common::USER1_CODE2_ID, "expected_revision": 3 }
+        ]}),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::UNPROCESSABLE_ENTITY));
+    let body = common::convert_response(response).await;
+    expect_that!(body["results"][0]["status"], eq(&json!(200)));
+    expect_that!(body["results"][1]["status"], eq(&json!(409)));
+    expect_that!(
+        body["results"][1]["error"]["current"]["id"],
+        eq(&json!(common::USER1_CODE2_ID))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn list_codes_not_modified(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let list = |if_none_match: String| {
+        app.clone().oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/v1/code")
+                .header("Authorization", format!("Bearer {a1}"))
+                .header("If-None-Match", if_none_match)
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let response = common::list_codes(&app, &a1).await;
+    let etag = response.headers()["ETag"].to_str().unwrap().to_string();
+    expect_that!(etag, matches_regex(r#"^"[a-f0-9]{64}"$"#));
+
+    let response = list(etag.clone()).await.unwrap();
+    expect_that!(response.status(), eq(StatusCode::NOT_MODIFIED));
+    expect_that!(response.headers()["ETag"], eq(etag.as_str()));
+
+    // Other selections are other representations
+    let response = common::get_codes_path(&app, &a1, "?fields=id").await;
+    expect_that!(response.headers()["ETag"], not(eq(etag.as_str())));
+
+    let response = common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "display_name": "Renamed" }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let response = list(etag.clone()).await.unwrap();
+    expect_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(response.headers()["ETag"], not(eq(etag.as_str())));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn search_codes(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let add_request = common::add_code(
+        &app,
+        a1.as_str(),
+        &json!({ "content": "yippie", "display_name": "Work", "website_url": "github.com" }),
+    )
+    .await;
+    assert_that!(add_request.status(), eq(StatusCode::OK));
+    let github = common::convert_response(add_request).await["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let ids = |codes: serde_json::Value| -> Vec<String> {
+        codes
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|code| code["id"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    let search_request = common::search_codes(&app, a1.as_str(), "q=git").await;
+    assert_that!(search_request.status(), eq(StatusCode::OK));
+    expect_that!(
+        ids(common::convert_response(search_request).await),
+        elements_are![eq(github.as_str())]
+    );
+
+    // Every word has to match, in the name or website
+    let search_request = common::search_codes(&app, a1.as_str(), "q=goo%20com").await;
+    expect_that!(
+        ids(common::convert_response(search_request).await),
+        unordered_elements_are![eq(common::USER1_CODE1_ID), eq(common::USER1_CODE2_ID)]
+    );
+    let search_request = common::search_codes(&app, a1.as_str(), "q=work%20google").await;
+    expect_that!(
+        common::convert_response(search_request).await,
+        eq(&json!([]))
+    );
+
+    // Search syntax is taken literally
+    let search_request = common::search_codes(&app, a1.as_str(), "q=%22google%20OR").await;
+    assert_that!(search_request.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::convert_response(search_request).await,
+        eq(&json!([]))
+    );
+
+    // Renamed and deleted codes are reflected in the results
+    let edit_request = common::edit_code(
+        &app,
+        a1.as_str(),
+        common::USER1_CODE1_ID,
+        &json!({ "display_name": "Mail", "website_url": "mail.example" }),
+    )
+    .await;
+    assert_that!(edit_request.status(), eq(StatusCode::OK));
+    let delete_request = common::delete_code(&app, a1.as_str(), common::USER1_CODE2_ID).await;
+    assert_that!(delete_request.status(), eq(StatusCode::NO_CONTENT));
+    let search_request = common::search_codes(&app, a1.as_str(), "q=goo").await;
+    expect_that!(
+        common::convert_response(search_request).await,
+        eq(&json!([]))
+    );
+    let search_request = common::search_codes(&app, a1.as_str(), "q=mail&fields=id").await;
+    expect_that!(
+        common::convert_response(search_request).await,
+        eq(&json!([{ "id": common::USER1_CODE1_ID }]))
+    );
+
+    let search_request = common::search_codes(&app, a2.as_str(), "q=mail").await;
+    expect_that!(
+        common::convert_response(search_request).await,
+        eq(&json!([]))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn search_codes_invalid_query(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    for query in ["q=", "q=%20%20", "q=goo&limit=0", "limit=10"] {
+        let search_request = common::search_codes(&app, a1.as_str(), query).await;
+        expect_that!(search_request.status(), eq(StatusCode::BAD_REQUEST));
+    }
+}
@@ -14,6 +14,20 @@ fn get_codes_for_user(userid: &str) -> Vec<models::codes::Code> {
                 display_name: "Google".into(),
                 icon_url: None,
                 website_url: Some("google.com".into()),
+                expires_at: None,
+                sort_index: 0,
+                issuer: None,
+                account: None,
+                kind: "totp".into(),
+                digits: 6,
+                period: 30,
+                algorithm: "SHA1".into(),
+                favorite: false,
+                folder_id: None,
+                created_at: 0,
+                updated_at: 0,
+                revision: 0,
+                deleted_at: None,
             },
             models::codes::Code {
                 id: "DxLCqi4ZlHPD8YxA".into(),
@@ -22,6 +36,20 @@ fn get_codes_for_user(userid: &str) -> Vec<models::codes::Code> {
                 display_name: "google.com".into(),
                 icon_url: None,
                 website_url: Some("google.com".into()),
+                expires_at: None,
+                sort_index: 1,
+                issuer: None,
+                account: None,
+                kind: "totp".into(),
+                digits: 6,
+                period: 30,
+                algorithm: "SHA1".into(),
+                favorite: false,
+                folder_id: None,
+                created_at: 0,
+                updated_at: 0,
+                revision: 0,
+                deleted_at: None,
             },
         ],
         "3Ck0d8WrkRjK6gkc" => vec![models::codes::Code {
@@ -31,6 +59,20 @@ fn get_codes_for_user(userid: &str) -> Vec<models::codes::Code> {
             display_name: "Dummy INC".into(),
             icon_url: Some("https://dummy.com/favicon.ico".into()),
             website_url: Some("dummy.com".into()),
+            expires_at: None,
+            sort_index: 0,
+            issuer: None,
+            account: None,
+            kind: "totp".into(),
+            digits: 6,
+            period: 30,
+            algorithm: "SHA1".into(),
+            favorite: false,
+            folder_id: None,
+            created_at: 0,
+            updated_at: 0,
+            revision: 0,
+            deleted_at: None,
         }],
         _ => panic!("Unexpected UserId in code_is_expected"),
     }
@@ -4,20 +4,23 @@ pub mod matchers;
 
 use axum::{
     body::Body,
-    http::{Method, Request},
+    http::{HeaderMap, Method, Request, StatusCode},
     response::Response,
     Router,
 };
 use iceblink_sync::{
-    auth::{self, OpenId},
-    configure_router,
+    auth::{self, OpenId, OpenIdProviders},
+    cli, configure_router,
+    events::Events,
     icons::IconStore,
+    jwt::JwtKeys,
+    mail::Mailer,
     models,
     routes::v1::users::ChecksumResponse,
     ServerOptions,
 };
 use sqlx::SqlitePool;
-use std::usize;
+use std::{time::Duration, usize};
 use tower::ServiceExt;
 
 pub const USER1_ID: &str = "k0d8WrkRjK6gkc3C";
@@ -29,30 +32,226 @@ pub const USER1_CODE1_CONTENT: &str = "GK6ZFMqk18fuWnCw";
 pub const USER1_CODE2_CONTENT: &str = "XGDi8FlvZ5OGBoxG";
 pub const USER2_CODE1_CONTENT: &str = "djnaW1Pl2WjhWrU6";
 
+pub fn testing_options() -> ServerOptions {
+    ServerOptions {
+        port: 8000,
+        jwt_secret: "my jwt secret".into(),
+        jwt_algorithm: cli::JwtAlgorithm::Hs256,
+        jwt_private_key: None,
+        jwt_previous_secret: None,
+        jwt_previous_private_key: None,
+        jwt_lifetime: Duration::from_secs(90 * 24 * 60 * 60),
+        jwt_renewal_window: Duration::ZERO,
+        session_idle_timeout: Duration::from_secs(30 * 24 * 60 * 60),
+        totp_skew: 1,
+        client_id: "N/A".into(),
+        client_secret: "N/A".into(),
+        oauth_server: "N/A".into(),
+        openid_providers: vec![],
+        native_redirect_schemes: vec!["iceblink".into()],
+        redirect_uri: "N/A".into(),
+        frontfacing: "N/A".into(),
+        max_connections_per_ip: 64,
+        trusted_proxies: vec![],
+        registration: cli::RegistrationPolicy::Open,
+        registrations_per_hour: 0,
+        requests_per_minute: 0,
+        login_requests_per_minute: 0,
+        write_requests_per_minute: 0,
+        max_codes_per_user: 0,
+        max_icon_bytes_per_user: 0,
+        captcha_verify_url: None,
+        captcha_secret: None,
+        local_auth: false,
+        http_proxy: None,
+        vapid_private_key: None,
+        vapid_subject: None,
+        smtp_url: None,
+        smtp_from: None,
+        email_templates: None,
+        export_notification_threshold: 10,
+        unauthenticated_redirect: "/".into(),
+        tombstone_retention: Duration::from_secs(90 * 24 * 60 * 60),
+        trash_retention: Duration::from_secs(30 * 24 * 60 * 60),
+        // Most tests deleting accounts are about what happens once they're gone
+        account_deletion_grace: Duration::ZERO,
+        slow_query_threshold: Duration::from_millis(250),
+        trailing_slash: cli::TrailingSlash::Rewrite,
+        html_cache_control: cli::HtmlCacheControl::Long,
+        backup_directory: std::env::temp_dir()
+            .join("iceblink-backups-".to_string() + &iceblink_sync::utils::generate_id(5))
+            .to_string_lossy()
+            .to_string(),
+        icon_directory: std::env::temp_dir()
+            .join("iceblink-icons-".to_string() + &iceblink_sync::utils::generate_id(5))
+            .to_string_lossy()
+            .to_string(),
+    }
+}
+
 pub async fn testing_setup(pool: &SqlitePool) -> Router {
+    testing_setup_with_options(pool, testing_options()).await
+}
+
+pub async fn testing_setup_with_options(pool: &SqlitePool, opts: ServerOptions) -> Router {
+    setup(
+        pool,
+        opts,
+        None,
+        None,
+        IconStore::new(),
+        testing_openid().into(),
+    )
+    .await
+}
+
+pub async fn testing_setup_with_events(pool: &SqlitePool, events: Events) -> Router {
+    setup(
+        pool,
+        testing_options(),
+        Some(events),
+        None,
+        IconStore::new(),
+        testing_openid().into(),
+    )
+    .await
+}
+
+pub async fn testing_setup_with_icon_store(pool: &SqlitePool, icon_store: IconStore) -> Router {
+    setup(
+        pool,
+        testing_options(),
+        None,
+        None,
+        icon_store,
+        testing_openid().into(),
+    )
+    .await
+}
+
+/// Sets up the router against a (mocked) OAuth server, for tests of logging in.
+pub async fn testing_setup_with_openid(
+    pool: &SqlitePool,
+    opts: ServerOptions,
+    openid: impl Into<OpenIdProviders>,
+) -> Router {
+    setup(pool, opts, None, None, IconStore::new(), openid.into()).await
+}
+
+/// Sets up the router with a mailer capturing the emails, see [`Mailer::outbox`].
+pub async fn testing_setup_with_mailer(pool: &SqlitePool, opts: ServerOptions) -> (Router, Mailer) {
+    let mailer = Mailer::capturing(&opts).unwrap();
+    let router = setup(
+        pool,
+        opts,
+        None,
+        Some(mailer.clone()),
+        IconStore::new(),
+        testing_openid().into(),
+    )
+    .await;
+    (router, mailer)
+}
+
+fn testing_openid() -> OpenId {
+    OpenId {
+        name: auth::DEFAULT_OPENID_PROVIDER.into(),
+        issuer: "N/A".into(),
+        authorization: "N/A".into(),
+        client_id: "N/A".into(),
+        client_secret: "N/A".into(),
+        token: "N/A".into(),
+        userinfo: "N/A".into(),
+        proxy: None,
+        jwks_uri: None,
+        keys: Default::default(),
+    }
+}
+
+async fn setup(
+    pool: &SqlitePool,
+    opts: ServerOptions,
+    events: Option<Events>,
+    mailer: Option<Mailer>,
+    icon_store: IconStore,
+    openid: OpenIdProviders,
+) -> Router {
     configure_router()
         .pool(pool)
-        .openid(OpenId {
-            authorization: "N/A".into(),
-            client_id: "N/A".into(),
-            client_secret: "N/A".into(),
-            token: "N/A".into(),
-            userinfo: "N/A".into(),
-        })
-        .opts(ServerOptions {
-            port: 8000,
-            jwt_secret: "my jwt secret".into(),
-            client_id: "N/A".into(),
-            client_secret: "N/A".into(),
-            oauth_server: "N/A".into(),
-            redirect_uri: "N/A".into(),
-            frontfacing: "N/A".into(),
-        })
-        .icon_store(IconStore::new().init().await.unwrap().clone())
+        .openid(openid)
+        .opts(opts)
+        .maybe_events(events)
+        .maybe_mailer(mailer)
+        .icon_store(icon_store.init().await.unwrap().clone())
         .call()
 }
 
+/// Serves the router on a random local port, returning its base URL.
+pub async fn mock_upstream(router: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+    format!("http://{address}")
+}
+
+/// Starts a login at `/v1/oauth/authorize`, with the given query.
+pub async fn authorize(app: &Router, query: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri(format!("/v1/oauth/authorize?{query}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+/// The state of a login started by [`authorize`], and the cookie which comes with it.
+pub fn login_state(response: &Response) -> (String, String) {
+    let cookie = response.headers()["set-cookie"]
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_string();
+    let state = cookie.split_once('=').unwrap().1.to_string();
+    (state, cookie)
+}
+
+/// Logs in like a browser: starting the login with the provider, and coming back from it with the
+/// state, its cookie and the rest of the query.
+pub async fn oauth_login(app: &Router, provider: Option<&str>, query: &str) -> Response {
+    let provider = provider
+        .map(|provider| format!("provider={provider}"))
+        .unwrap_or_default();
+    let response = authorize(app, &provider).await;
+    if response.status() != StatusCode::SEE_OTHER {
+        return response;
+    }
+    let (state, cookie) = login_state(&response);
+
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri(format!("/v1/oauth?state={state}&{provider}&{query}"))
+                .header("Cookie", cookie)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
 pub async fn get_access_tokens(pool: &SqlitePool) -> (String, String) {
+    get_access_tokens_with_keys(pool, &JwtKeys::hs256("my jwt secret")).await
+}
+
+pub async fn get_access_tokens_with_keys(pool: &SqlitePool, keys: &JwtKeys) -> (String, String) {
     let user1 = iceblink_sync::models::user::User::get_by_id(&pool, USER1_ID.into())
         .await
         .unwrap()
@@ -63,8 +262,14 @@ pub async fn get_access_tokens(pool: &SqlitePool) -> (String, String) {
         .unwrap();
 
     (
-        auth::create_jwt(&user1, "my jwt secret".into()).await.0,
-        auth::create_jwt(&user2, "my jwt secret".into()).await.0,
+        auth::create_jwt(pool, &user1, keys, &HeaderMap::new(), None)
+            .await
+            .unwrap()
+            .jwt,
+        auth::create_jwt(pool, &user2, keys, &HeaderMap::new(), None)
+            .await
+            .unwrap()
+            .jwt,
     )
 }
 
@@ -103,6 +308,102 @@ pub async fn list_codes(app: &Router, token: &str) -> Response {
         .unwrap()
 }
 
+/// Fetches `/v1/code{path}`, e.g. `/{id}?fields=id`.
+pub async fn get_codes_path(app: &Router, token: &str, path: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri(format!("/v1/code{path}"))
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+/// Searches the codes using `/v1/codes/search?{query}`
+pub async fn search_codes(app: &Router, token: &str, query: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri(format!("/v1/codes/search?{query}"))
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+/// Fetches the changes since the cursor using `/v1/codes/delta`
+pub async fn delta_codes(app: &Router, token: &str, since: Option<i64>) -> Response {
+    let uri = match since {
+        Some(since) => format!("/v1/codes/delta?since={since}"),
+        None => "/v1/codes/delta".to_string(),
+    };
+
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+pub async fn batch_codes(app: &Router, token: &str, payload: &serde_json::Value) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v1/codes/batch")
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(payload.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+pub async fn bulk_delete_codes(app: &Router, token: &str, ids: &[&str]) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::DELETE)
+                .uri("/v1/codes")
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_vec(ids).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+/// Removes the fields of a serialized code which depend on when it was added or changed, after
+/// checking they're present.
+pub fn without_sync_fields(mut code: serde_json::Value) -> serde_json::Value {
+    let fields = code.as_object_mut().expect("Not a code");
+    assert!(fields
+        .remove("created_at")
+        .is_some_and(|value| value.is_i64()));
+    assert!(fields
+        .remove("updated_at")
+        .is_some_and(|value| value.is_i64()));
+    assert!(fields
+        .remove("revision")
+        .is_some_and(|value| value.is_i64()));
+    code
+}
+
 pub async fn list_codes_content(app: &Router, token: &str) -> Vec<models::codes::Code> {
     serde_json::from_value(convert_response(list_codes(app, token).await).await).unwrap()
 }
@@ -122,6 +423,78 @@ pub async fn add_code(app: &Router, token: &str, payload: &serde_json::Value) ->
         .unwrap()
 }
 
+pub async fn export_codes(app: &Router, token: &str, payload: &serde_json::Value) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v1/export")
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_vec(payload).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+pub async fn import_codes(app: &Router, token: &str, payload: &serde_json::Value) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v1/import")
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_vec(payload).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+pub async fn get_many_codes(app: &Router, token: &str, payload: &serde_json::Value) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v1/codes/get")
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_vec(payload).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+pub async fn create_token_request(app: &Router, token: &str, scope: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v1/user/tokens")
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&serde_json::json!({ "scope": scope })).unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+/// Creates an API token with the given scope, returning the token itself
+pub async fn create_token(app: &Router, token: &str, scope: &str) -> String {
+    let res = create_token_request(app, token, scope).await;
+
+    convert_response(res).await["token"]
+        .as_str()
+        .unwrap()
+        .to_string()
+}
+
 pub async fn delete_code(app: &Router, token: &str, id: &str) -> Response {
     app.clone()
         .oneshot(
@@ -136,6 +509,138 @@ pub async fn delete_code(app: &Router, token: &str, id: &str) -> Response {
         .unwrap()
 }
 
+/// Lists the deleted codes using `/v1/codes/trash`
+pub async fn list_trash(app: &Router, token: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/v1/codes/trash")
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+/// Restores a deleted code using `/v1/code/{id}/restore`
+pub async fn restore_code(app: &Router, token: &str, id: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(format!("/v1/code/{id}/restore"))
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+/// Revokes the session or API token using `/v1/logout`
+pub async fn logout(app: &Router, token: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v1/logout")
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+/// Deletes the account of the user using `/v1/user`
+pub async fn delete_account(app: &Router, token: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::DELETE)
+                .uri("/v1/user")
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+/// Clones a code using `/v1/code/{id}/clone`
+pub async fn clone_code(
+    app: &Router,
+    token: &str,
+    id: &str,
+    payload: &serde_json::Value,
+) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(format!("/v1/code/{id}/clone"))
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_vec(payload).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+/// Reorders the codes using `/v1/codes/order`
+pub async fn reorder_codes(app: &Router, token: &str, ids: &[&str]) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::PATCH)
+                .uri("/v1/codes/order")
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&serde_json::json!({ "ids": ids })).unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+/// Verifies a TOTP code using `/v1/code/{id}/verify`
+pub async fn verify_code(app: &Router, token: &str, id: &str, code: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(format!("/v1/code/{id}/verify"))
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&serde_json::json!({ "code": code })).unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+/// Moves a code using `/v1/code/{id}/move-up` or `/v1/code/{id}/move-down`
+pub async fn move_code(app: &Router, token: &str, id: &str, direction: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(format!("/v1/code/{id}/move-{direction}"))
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
 pub async fn edit_code(
     app: &Router,
     token: &str,
@@ -156,6 +661,81 @@ pub async fn edit_code(
         .unwrap()
 }
 
+/// Sends a request to `/v1/folders{path}`, with the payload as JSON body if any.
+pub async fn folders_request(
+    app: &Router,
+    token: &str,
+    method: Method,
+    path: &str,
+    payload: Option<&serde_json::Value>,
+) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(format!("/v1/folders{path}"))
+        .header("Authorization", format!("Bearer {token}"));
+    let request = match payload {
+        Some(payload) => request
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_vec(payload).unwrap())),
+        None => request.body(Body::empty()),
+    };
+
+    app.clone().oneshot(request.unwrap()).await.unwrap()
+}
+
+/// Creates a folder using `/v1/folders`, returning its id
+pub async fn add_folder(app: &Router, token: &str, name: &str, parent_id: Option<&str>) -> String {
+    let response = folders_request(
+        app,
+        token,
+        Method::POST,
+        "",
+        Some(&serde_json::json!({ "name": name, "parent_id": parent_id })),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    convert_response(response).await["id"]
+        .as_str()
+        .unwrap()
+        .to_string()
+}
+
+/// Fetches the tags of the user, and the codes carrying them, using `/v1/tags`
+pub async fn list_tags(app: &Router, token: &str) -> serde_json::Value {
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/v1/tags")
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    convert_response(response).await
+}
+
+/// Changes the settings of the user using `/v1/user/settings`
+pub async fn edit_settings(app: &Router, token: &str, payload: &serde_json::Value) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::PATCH)
+                .uri("/v1/user/settings")
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_vec(payload).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
 pub async fn user_checksum(app: &Router, token: &str) -> String {
     let res = app
         .clone()
@@ -174,6 +754,20 @@ pub async fn user_checksum(app: &Router, token: &str) -> String {
     parsed.checksum
 }
 
+pub async fn get_recovery_sheet(app: &Router, token: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/v1/codes/recovery-sheet")
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
 pub async fn get_icon(app: &Router, token: &str, id: &str) -> Response {
     app.clone()
         .oneshot(
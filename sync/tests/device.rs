@@ -0,0 +1,157 @@
+use axum::{
+    body::Body,
+    http::{Method, Request, StatusCode},
+    response::Response,
+    Router,
+};
+use googletest::prelude::*;
+use serde_json::json;
+use sqlx::SqlitePool;
+use tower::ServiceExt;
+
+pub mod common;
+
+async fn post(app: &Router, path: &str, token: Option<&str>, body: serde_json::Value) -> Response {
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(path)
+        .header("Content-Type", "application/json");
+    if let Some(token) = token {
+        request = request.header("Authorization", format!("Bearer {token}"));
+    }
+
+    app.clone()
+        .oneshot(request.body(Body::from(body.to_string())).unwrap())
+        .await
+        .unwrap()
+}
+
+/// Starts logging in a device, returning its device code and user code.
+async fn start(app: &Router) -> (String, String) {
+    let response = post(app, "/v1/oauth/device", None, json!({})).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let body = common::convert_response(response).await;
+    expect_that!(body["interval"], eq(&json!(5)));
+    expect_that!(
+        body["verification_uri_complete"],
+        eq(&json!(format!(
+            "N/A/device?code={}",
+            body["user_code"].as_str().unwrap()
+        )))
+    );
+    (
+        body["device_code"].as_str().unwrap().to_string(),
+        body["user_code"].as_str().unwrap().to_string(),
+    )
+}
+
+async fn poll(app: &Router, device_code: &str) -> Response {
+    post(
+        app,
+        "/v1/oauth/device/token",
+        None,
+        json!({ "device_code": device_code }),
+    )
+    .await
+}
+
+async fn error_kind(response: Response) -> serde_json::Value {
+    common::convert_response(response).await["errorKind"].clone()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn device_login(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let (device_code, user_code) = start(&app).await;
+
+    let response = poll(&app, &device_code).await;
+    assert_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    expect_that!(
+        error_kind(response).await,
+        eq(&json!("AuthorizationPending"))
+    );
+    let response = poll(&app, &device_code).await;
+    expect_that!(error_kind(response).await, eq(&json!("SlowDown")));
+
+    let response = post(
+        &app,
+        "/v1/oauth/device/verify",
+        Some(&a1),
+        json!({ "user_code": user_code.to_lowercase() }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+
+    let response = poll(&app, &device_code).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let token = common::convert_response(response).await["access_token"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let codes = common::convert_response(common::list_codes(&app, &token).await).await;
+    expect_that!(codes.as_array().unwrap(), len(eq(2)));
+
+    // The device code only logs in once
+    let response = poll(&app, &device_code).await;
+    expect_that!(error_kind(response).await, eq(&json!("DeviceCodeExpired")));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn device_denied(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let (device_code, user_code) = start(&app).await;
+
+    let response = post(
+        &app,
+        "/v1/oauth/device/verify",
+        Some(&a1),
+        json!({ "user_code": user_code, "deny": true }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+
+    let response = poll(&app, &device_code).await;
+    expect_that!(error_kind(response).await, eq(&json!("AccessDenied")));
+
+    let response = post(
+        &app,
+        "/v1/oauth/device/verify",
+        Some(&a1),
+        json!({ "user_code": user_code }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::NOT_FOUND));
+    expect_that!(error_kind(response).await, eq(&json!("UnknownUserCode")));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn device_verify_requires_write_scope(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let token = common::create_token(&app, &a1, "read:metadata").await;
+    let (_, user_code) = start(&app).await;
+
+    let response = post(
+        &app,
+        "/v1/oauth/device/verify",
+        None,
+        json!({ "user_code": user_code }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+
+    let response = post(
+        &app,
+        "/v1/oauth/device/verify",
+        Some(&token),
+        json!({ "user_code": user_code }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::FORBIDDEN));
+}
@@ -0,0 +1,543 @@
+use axum::{
+    body::Body,
+    extract::Path,
+    http::{HeaderMap, Method, Request, StatusCode},
+    response::Response,
+    routing::post,
+    Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use googletest::prelude::*;
+use iceblink_sync::{
+    events::Events,
+    models::{devices::Device, push_subscriptions::PushSubscription},
+    tasks,
+    webpush::{WebPush, WebPushError},
+    ServerOptions,
+};
+use ring::{agreement, rand::SystemRandom};
+use serde_json::json;
+use sqlx::SqlitePool;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+use tower::ServiceExt;
+
+pub mod common;
+
+async fn device_request(
+    app: &Router,
+    token: &str,
+    method: Method,
+    uri: &str,
+    payload: Option<serde_json::Value>,
+) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Authorization", format!("Bearer {token}"));
+    let request = match payload {
+        Some(payload) => request
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap())),
+        None => request.body(Body::empty()),
+    };
+
+    app.clone().oneshot(request.unwrap()).await.unwrap()
+}
+
+async fn register(app: &Router, token: &str, payload: serde_json::Value) -> Response {
+    device_request(app, token, Method::POST, "/v1/devices", Some(payload)).await
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn register_device(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let response = register(
+        &app,
+        &a1,
+        json!({ "name": "iPhone 14", "platform": "ios", "app_version": "1.4.0" }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::CREATED));
+    let device = common::convert_response(response).await;
+    expect_that!(device["name"], eq(&json!("iPhone 14")));
+    expect_that!(device["current"], eq(&json!(true)));
+    expect_that!(device["last_push_at"], eq(&json!(null)));
+    let id = device["id"].as_str().unwrap().to_string();
+
+    // Registering the session again updates its device
+    let response = register(
+        &app,
+        &a1,
+        json!({ "name": "iPhone 14", "platform": "ios", "app_version": "1.5.0" }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let device = common::convert_response(response).await;
+    expect_that!(device["id"], eq(&json!(id)));
+    expect_that!(device["app_version"], eq(&json!("1.5.0")));
+
+    let response = device_request(&app, &a1, Method::GET, "/v1/devices", None).await;
+    let devices = common::convert_response(response).await;
+    expect_that!(devices.as_array().unwrap(), len(eq(1)));
+    expect_that!(devices[0]["id"], eq(&json!(id)));
+    expect_that!(devices[0]["last_seen_at"].is_i64(), is_true());
+    let response = device_request(&app, &a1, Method::GET, "/v1/user/sessions", None).await;
+    expect_that!(
+        common::convert_response(response).await[0]["device_id"],
+        eq(&json!(id))
+    );
+
+    // Devices are private to their user
+    let response = device_request(&app, &a2, Method::GET, "/v1/devices", None).await;
+    expect_that!(common::convert_response(response).await, eq(&json!([])));
+    let response = device_request(
+        &app,
+        &a2,
+        Method::PATCH,
+        &format!("/v1/devices/{id}"),
+        Some(json!({ "name": "Mine now" })),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::NOT_FOUND));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn register_device_again_after_login(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let response = register(
+        &app,
+        &a1,
+        json!({ "name": "Laptop", "platform": "web", "app_version": "1.4.0" }),
+    )
+    .await;
+    let id = common::convert_response(response).await["id"].clone();
+
+    let (relogin, _) = common::get_access_tokens(&db).await;
+    let response = register(
+        &app,
+        &relogin,
+        json!({ "id": id, "name": "Laptop", "platform": "web", "app_version": "1.4.0" }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(common::convert_response(response).await["id"], eq(&id));
+
+    let response = device_request(&app, &a1, Method::GET, "/v1/devices", None).await;
+    expect_that!(
+        common::convert_response(response).await.as_array().unwrap(),
+        len(eq(1))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn register_device_rejected(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    for payload in [
+        json!({ "name": "", "platform": "ios", "app_version": "1.4.0" }),
+        json!({ "name": "x".repeat(65), "platform": "ios", "app_version": "1.4.0" }),
+        json!({ "name": "iPhone", "platform": " ", "app_version": "1.4.0" }),
+        json!({ "name": "iPhone", "platform": "ios", "app_version": "1".repeat(33) }),
+    ] {
+        let response = register(&app, &a1, payload).await;
+        expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    }
+
+    // API tokens have no session to link
+    let token = common::create_token(&app, &a1, "full").await;
+    let response = register(
+        &app,
+        &token,
+        json!({ "name": "Script", "platform": "linux", "app_version": "1.0.0" }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn rename_and_revoke_device(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, other) = common::get_access_tokens(&db).await;
+    let (phone, _) = common::get_access_tokens(&db).await;
+    let response = register(
+        &app,
+        &phone,
+        json!({ "name": "Pixel", "platform": "android", "app_version": "1.4.0" }),
+    )
+    .await;
+    let id = common::convert_response(response).await["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let response = device_request(
+        &app,
+        &a1,
+        Method::PATCH,
+        &format!("/v1/devices/{id}"),
+        Some(json!({ "name": "Old Pixel" })),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::convert_response(response).await["name"],
+        eq(&json!("Old Pixel"))
+    );
+
+    let response = device_request(
+        &app,
+        &a1,
+        Method::DELETE,
+        &format!("/v1/devices/{id}"),
+        None,
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+
+    // The session of the device is revoked with it, others keep working
+    let response = device_request(&app, &phone, Method::GET, "/v1/devices", None).await;
+    expect_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+    let response = device_request(&app, &a1, Method::GET, "/v1/devices", None).await;
+    expect_that!(common::convert_response(response).await, eq(&json!([])));
+    let response = device_request(&app, &other, Method::GET, "/v1/devices", None).await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn changes_are_attributed_to_device(db: SqlitePool) {
+    let events = Events::default();
+    let mut receiver = events.subscribe();
+    let app = common::testing_setup_with_events(&db, events).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let response = register(
+        &app,
+        &a1,
+        json!({ "name": "iPhone 14", "platform": "ios", "app_version": "1.4.0" }),
+    )
+    .await;
+    let id = common::convert_response(response).await["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "display_name": "Renamed" }),
+    )
+    .await;
+    let event = receiver.try_recv().unwrap();
+    expect_that!(event.device_id, some(eq(&id)));
+    expect_that!(
+        serde_json::to_value(&event).unwrap()["device_id"],
+        eq(&json!(id))
+    );
+
+    expect_that!(tasks::record_device_push(&db, &event).await, is_true());
+    let device = Device::get(&db, &id, common::USER1_ID)
+        .await
+        .unwrap()
+        .unwrap();
+    expect_that!(device.last_push_at, some(anything()));
+}
+
+fn push_options() -> ServerOptions {
+    ServerOptions {
+        vapid_private_key: Some("tests/fixtures/jwt_es256.pem".into()),
+        vapid_subject: Some("mailto:admin@example.com".into()),
+        ..common::testing_options()
+    }
+}
+
+/// Keys of a push subscription, as a browser would generate them.
+fn subscription_keys() -> serde_json::Value {
+    let private_key =
+        agreement::EphemeralPrivateKey::generate(&agreement::ECDH_P256, &SystemRandom::new())
+            .unwrap();
+    json!({
+        "p256dh": URL_SAFE_NO_PAD.encode(private_key.compute_public_key().unwrap()),
+        "auth": URL_SAFE_NO_PAD.encode([7; 16]),
+    })
+}
+
+async fn register_device_id(app: &Router, token: &str, name: &str) -> String {
+    let response = register(
+        app,
+        token,
+        json!({ "name": name, "platform": "web", "app_version": "1.4.0" }),
+    )
+    .await;
+    common::convert_response(response).await["id"]
+        .as_str()
+        .unwrap()
+        .to_string()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn subscribe_push(db: SqlitePool) {
+    let app = common::testing_setup_with_options(&db, push_options()).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    let id = register_device_id(&app, &a1, "Firefox").await;
+    let uri = format!("/v1/devices/{id}/push");
+
+    let response = device_request(&app, &a1, Method::GET, "/v1/", None).await;
+    expect_that!(
+        common::convert_response(response).await["vapid_public_key"].is_string(),
+        is_true()
+    );
+
+    let subscription = json!({
+        "endpoint": "https://updates.push.services.mozilla.com/wpush/v2/abc",
+        "keys": subscription_keys(),
+    });
+    let response = device_request(&app, &a1, Method::PUT, &uri, Some(subscription.clone())).await;
+    expect_that!(response.status(), eq(StatusCode::NO_CONTENT));
+    let subscriptions = PushSubscription::get_for_user(&db, common::USER1_ID)
+        .await
+        .unwrap();
+    assert_that!(subscriptions, len(eq(1)));
+    expect_that!(subscriptions[0].device_id, eq(&id));
+
+    // Subscribing again replaces the subscription
+    let response = device_request(
+        &app,
+        &a1,
+        Method::PUT,
+        &uri,
+        Some(json!({
+            "endpoint": "https://fcm.googleapis.com/fcm/send/def",
+            "keys": subscription_keys(),
+        })),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::NO_CONTENT));
+    let subscriptions = PushSubscription::get_for_user(&db, common::USER1_ID)
+        .await
+        .unwrap();
+    assert_that!(subscriptions, len(eq(1)));
+    expect_that!(
+        subscriptions[0].endpoint,
+        eq("https://fcm.googleapis.com/fcm/send/def")
+    );
+
+    // Devices of other users can't be subscribed
+    let response = device_request(&app, &a2, Method::PUT, &uri, Some(subscription)).await;
+    expect_that!(response.status(), eq(StatusCode::NOT_FOUND));
+
+    let response = device_request(&app, &a1, Method::DELETE, &uri, None).await;
+    expect_that!(response.status(), eq(StatusCode::NO_CONTENT));
+    expect_that!(
+        PushSubscription::get_for_user(&db, common::USER1_ID)
+            .await
+            .unwrap(),
+        is_empty()
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn subscribe_push_rejected(db: SqlitePool) {
+    let app = common::testing_setup_with_options(&db, push_options()).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let id = register_device_id(&app, &a1, "Firefox").await;
+    let uri = format!("/v1/devices/{id}/push");
+    let keys = subscription_keys();
+
+    for subscription in [
+        json!({ "endpoint": "http://push.example.com/abc", "keys": keys }),
+        json!({ "endpoint": "https://127.0.0.1/abc", "keys": keys }),
+        json!({ "endpoint": "https://localhost/abc", "keys": keys }),
+        json!({ "endpoint": "not a url", "keys": keys }),
+        json!({
+            "endpoint": "https://push.example.com/abc",
+            "keys": { "p256dh": URL_SAFE_NO_PAD.encode([4; 33]), "auth": keys["auth"] },
+        }),
+        json!({
+            "endpoint": "https://push.example.com/abc",
+            "keys": { "p256dh": keys["p256dh"], "auth": "not base64!" },
+        }),
+    ] {
+        let response = device_request(&app, &a1, Method::PUT, &uri, Some(subscription)).await;
+        expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    }
+
+    // Without a VAPID key, there's nothing to push with
+    let app = common::testing_setup(&db).await;
+    let response = device_request(
+        &app,
+        &a1,
+        Method::PUT,
+        &uri,
+        Some(json!({ "endpoint": "https://push.example.com/abc", "keys": keys })),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::NOT_IMPLEMENTED));
+}
+
+#[gtest]
+fn vapid_options_are_validated() {
+    let options = ServerOptions {
+        vapid_subject: Some("admin@example.com".into()),
+        ..push_options()
+    };
+    expect_that!(options.validate(), err(anything()));
+
+    let options = ServerOptions {
+        vapid_private_key: Some("tests/fixtures/jwt_rs256.pem".into()),
+        ..push_options()
+    };
+    expect_that!(options.validate(), err(anything()));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn changes_are_pushed_to_other_devices(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (laptop, _) = common::get_access_tokens(&db).await;
+    let (phone, _) = common::get_access_tokens(&db).await;
+    let (tablet, _) = common::get_access_tokens(&db).await;
+    let laptop_id = register_device_id(&app, &laptop, "Laptop").await;
+    let phone_id = register_device_id(&app, &phone, "Phone").await;
+    let tablet_id = register_device_id(&app, &tablet, "Tablet").await;
+
+    let pushes = Arc::new(Mutex::new(vec![]));
+    let recorded = pushes.clone();
+    let push_service = common::mock_upstream(Router::new().route(
+        "/push/:device",
+        post(
+            move |Path(device): Path<String>, headers: HeaderMap| async move {
+                if device == "uninstalled" {
+                    return StatusCode::GONE;
+                }
+                recorded.lock().unwrap().push((device, headers));
+                StatusCode::CREATED
+            },
+        ),
+    ))
+    .await;
+
+    // The mock service is local, which is only allowed as the guard is turned off below
+    let keys = subscription_keys();
+    for (device_id, path) in [
+        (&laptop_id, "laptop"),
+        (&phone_id, "phone"),
+        (&tablet_id, "uninstalled"),
+    ] {
+        PushSubscription {
+            device_id: device_id.clone(),
+            user_id: common::USER1_ID.into(),
+            endpoint: format!("{push_service}/push/{path}"),
+            p256dh: keys["p256dh"].as_str().unwrap().into(),
+            auth: keys["auth"].as_str().unwrap().into(),
+            created_at: 0,
+        }
+        .upsert(&db)
+        .await
+        .unwrap();
+    }
+
+    let web_push = WebPush::new(
+        include_str!("fixtures/jwt_es256.pem"),
+        "mailto:admin@example.com".into(),
+    )
+    .unwrap()
+    .allow_private_networks(true);
+    let pinged = tasks::announce_changes(
+        &db,
+        &web_push,
+        common::USER1_ID,
+        &HashSet::from([Some(phone_id.clone())]),
+    )
+    .await;
+    expect_that!(pinged, eq(1));
+
+    // The phone made the changes, and the tablet's subscription is gone
+    let pushes = pushes.lock().unwrap().clone();
+    assert_that!(pushes, len(eq(1)));
+    let (device, headers) = &pushes[0];
+    expect_that!(device, eq("laptop"));
+    expect_that!(headers["content-encoding"], eq("aes128gcm"));
+    expect_that!(headers["topic"], eq("changes"));
+    expect_that!(headers.contains_key("ttl"), is_true());
+    expect_that!(
+        headers["authorization"].to_str().unwrap(),
+        starts_with("vapid t=")
+    );
+
+    let remaining: Vec<String> = PushSubscription::get_for_user(&db, common::USER1_ID)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|subscription| subscription.device_id)
+        .collect();
+    expect_that!(
+        remaining,
+        unordered_elements_are![eq(&laptop_id), eq(&phone_id)]
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn pushes_never_reach_internal_hosts(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (laptop, _) = common::get_access_tokens(&db).await;
+    let laptop_id = register_device_id(&app, &laptop, "Laptop").await;
+
+    let pushes = Arc::new(Mutex::new(0));
+    let recorded = pushes.clone();
+    let push_service = common::mock_upstream(Router::new().route(
+        "/push",
+        post(move || async move {
+            *recorded.lock().unwrap() += 1;
+            StatusCode::CREATED
+        }),
+    ))
+    .await;
+
+    // As if the endpoint resolved to a public address when subscribing, and was rebound since
+    let keys = subscription_keys();
+    let subscription = PushSubscription {
+        device_id: laptop_id,
+        user_id: common::USER1_ID.into(),
+        endpoint: format!("{push_service}/push"),
+        p256dh: keys["p256dh"].as_str().unwrap().into(),
+        auth: keys["auth"].as_str().unwrap().into(),
+        created_at: 0,
+    };
+    subscription.upsert(&db).await.unwrap();
+
+    let web_push = WebPush::new(
+        include_str!("fixtures/jwt_es256.pem"),
+        "mailto:admin@example.com".into(),
+    )
+    .unwrap();
+    assert!(matches!(
+        web_push.send(&subscription, "changes", b"{}").await,
+        Err(WebPushError::BlockedHost)
+    ));
+    let pinged = tasks::announce_changes(&db, &web_push, common::USER1_ID, &HashSet::new()).await;
+    expect_that!(pinged, eq(0));
+    expect_that!(*pushes.lock().unwrap(), eq(0));
+
+    // The subscription isn't expired, the endpoint may resolve to a public address again
+    expect_that!(
+        PushSubscription::get_for_user(&db, common::USER1_ID)
+            .await
+            .unwrap(),
+        len(eq(1))
+    );
+}
@@ -0,0 +1,105 @@
+use axum::{
+    body::Body,
+    http::{Method, Request, StatusCode},
+};
+use googletest::prelude::*;
+use iceblink_sync::events::{EventKind, Events};
+use serde_json::json;
+use sqlx::SqlitePool;
+use tower::ServiceExt;
+
+pub mod common;
+
+fn add_code_request(token: &str, client_id: &str) -> Request<Body> {
+    Request::builder()
+        .method(Method::PUT)
+        .uri("/v1/code")
+        .header("Authorization", format!("Bearer {token}"))
+        .header("Content-Type", "application/json")
+        .header("X-Client-Id", client_id)
+        .body(Body::from(
+            serde_json::to_vec(&json!({
+                "content": "AAAAAAAAAAAAAAAA",
+                "display_name": "Iceblink"
+            }))
+            .unwrap(),
+        ))
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn event_carries_client_id(db: SqlitePool) {
+    let events = Events::default();
+    let mut receiver = events.subscribe();
+    let app = common::testing_setup_with_events(&db, events).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = app
+        .clone()
+        .oneshot(add_code_request(&a1, "phone-8f3c"))
+        .await
+        .unwrap();
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let code = common::convert_response(response).await;
+
+    let event = receiver.try_recv().unwrap();
+    expect_that!(event.kind, eq(EventKind::CodeCreated));
+    expect_that!(event.user_id, eq(common::USER1_ID));
+    expect_that!(event.code_id, some(eq(code["id"].as_str().unwrap())));
+    expect_that!(event.client_id, some(eq("phone-8f3c")));
+
+    // Requests without the header are still published, just without a client id
+    common::delete_code(&app, &a1, common::USER1_CODE1_ID).await;
+
+    let event = receiver.try_recv().unwrap();
+    expect_that!(event.kind, eq(EventKind::CodeDeleted));
+    expect_that!(event.code_id, some(eq(common::USER1_CODE1_ID)));
+    expect_that!(event.client_id, none());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn invalid_client_id(db: SqlitePool) {
+    let events = Events::default();
+    let mut receiver = events.subscribe();
+    let app = common::testing_setup_with_events(&db, events).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = app
+        .oneshot(add_code_request(&a1, &"a".repeat(65)))
+        .await
+        .unwrap();
+
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    expect_that!(receiver.try_recv(), err(anything()));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn moving_code_publishes_both_codes(db: SqlitePool) {
+    let events = Events::default();
+    let mut receiver = events.subscribe();
+    let app = common::testing_setup_with_events(&db, events).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = common::move_code(&app, &a1, common::USER1_CODE2_ID, "up").await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let moved: Vec<_> = [receiver.try_recv().unwrap(), receiver.try_recv().unwrap()]
+        .into_iter()
+        .map(|event| (event.kind, event.code_id.unwrap()))
+        .collect();
+    expect_that!(
+        moved,
+        unordered_elements_are![
+            eq(&(EventKind::CodeUpdated, common::USER1_CODE1_ID.to_string())),
+            eq(&(EventKind::CodeUpdated, common::USER1_CODE2_ID.to_string()))
+        ]
+    );
+
+    // Nothing changes when moving the first code up
+    let response = common::move_code(&app, &a1, common::USER1_CODE2_ID, "up").await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(receiver.try_recv(), err(anything()));
+}
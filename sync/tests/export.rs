@@ -0,0 +1,1094 @@
+use axum::{
+    body::Body,
+    http::{Method, Request, StatusCode},
+    response::Response,
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::read::GzDecoder;
+use futures_util::StreamExt;
+use googletest::prelude::*;
+use iceblink_sync::ServerOptions;
+use serde_json::json;
+use sqlx::SqlitePool;
+use std::io::Read;
+use tower::ServiceExt;
+
+pub mod common;
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn plaintext_export_round_trip(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let export_request = common::export_codes(&app, a1.as_str(), &json!({})).await;
+    assert_that!(export_request.status(), eq(StatusCode::OK));
+    let file = common::convert_response(export_request).await;
+    assert_that!(file["codes"].as_array().unwrap().len(), eq(2));
+
+    let import_request = common::import_codes(&app, a2.as_str(), &json!({ "file": file })).await;
+    assert_that!(import_request.status(), eq(StatusCode::OK));
+
+    let listing = common::list_codes_content(&app, a2.as_str()).await;
+    let names: Vec<&str> = listing.iter().map(|c| c.display_name.as_str()).collect();
+    assert_that!(
+        names,
+        elements_are![eq("Dummy INC"), eq("Google"), eq("google.com")]
+    );
+    expect_that!(listing[1].content, eq(common::USER1_CODE1_CONTENT));
+    expect_that!(listing[1].owner_id, eq(common::USER2_ID));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn encrypted_export_round_trip(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let export_request = common::export_codes(
+        &app,
+        a1.as_str(),
+        &json!({ "passphrase": "correct horse battery staple" }),
+    )
+    .await;
+    assert_that!(export_request.status(), eq(StatusCode::OK));
+    let file = common::convert_response(export_request).await;
+    assert_that!(file.get("codes"), none());
+    assert_that!(
+        file.to_string(),
+        not(contains_substring(common::USER1_CODE1_CONTENT))
+    );
+
+    let import_request = common::import_codes(
+        &app,
+        a2.as_str(),
+        &json!({ "file": file, "passphrase": "correct horse battery staple" }),
+    )
+    .await;
+    assert_that!(import_request.status(), eq(StatusCode::OK));
+
+    let listing = common::list_codes_content(&app, a2.as_str()).await;
+    assert_that!(listing.len(), eq(3));
+    expect_that!(listing[1].content, eq(common::USER1_CODE1_CONTENT));
+    expect_that!(listing[2].content, eq(common::USER1_CODE2_CONTENT));
+}
+
+async fn download_export(app: &Router, token: &str, password: Option<&str>) -> Response {
+    let mut request = Request::builder()
+        .method(Method::GET)
+        .uri("/v1/export")
+        .header("Authorization", format!("Bearer {token}"));
+    if let Some(password) = password {
+        request = request.header("X-Export-Password", password);
+    }
+
+    app.clone()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn export_folders_and_tags_round_trip(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    let work = common::add_folder(&app, a1.as_str(), "Work", None).await;
+    let edit_request = common::edit_code(
+        &app,
+        a1.as_str(),
+        common::USER1_CODE1_ID,
+        &json!({ "folder_id": work, "tags": ["email", "personal"], "favorite": true }),
+    )
+    .await;
+    assert_that!(edit_request.status(), eq(StatusCode::OK));
+
+    let export_request = download_export(&app, a1.as_str(), Some("hunter2")).await;
+    assert_that!(export_request.status(), eq(StatusCode::OK));
+    let file = common::convert_response(export_request).await;
+
+    let import_request = common::import_codes(
+        &app,
+        a2.as_str(),
+        &json!({ "file": file, "passphrase": "hunter2" }),
+    )
+    .await;
+    assert_that!(import_request.status(), eq(StatusCode::OK));
+
+    let listing = common::list_codes_content(&app, a2.as_str()).await;
+    assert_that!(listing.len(), eq(3));
+    expect_that!(listing[1].content, eq(common::USER1_CODE1_CONTENT));
+    expect_that!(listing[1].favorite, is_true());
+    expect_that!(listing[1].folder_id, some(not(eq(work.as_str()))));
+    expect_that!(listing[2].folder_id, none());
+
+    let tags = common::list_tags(&app, a2.as_str()).await;
+    expect_that!(
+        tags,
+        eq(&json!([
+            { "name": "email", "code_ids": [listing[1].id] },
+            { "name": "personal", "code_ids": [listing[1].id] },
+        ]))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn plaintext_download_export(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let export_request = download_export(&app, a1.as_str(), None).await;
+    assert_that!(export_request.status(), eq(StatusCode::OK));
+    let file = common::convert_response(export_request).await;
+    expect_that!(file["format_version"], eq(&json!(3)));
+    expect_that!(
+        file["codes"][0],
+        eq(&json!({
+            "content": common::USER1_CODE1_CONTENT,
+            "display_name": "Google",
+            "website_url": "google.com",
+            "expires_at": null,
+            "issuer": null,
+            "account": null,
+            "kind": "totp",
+            "digits": 6,
+            "period": 30,
+            "algorithm": "SHA1",
+            "icon_url": null,
+            "favorite": false,
+            "folder": null,
+            "tags": [],
+        }))
+    );
+
+    let empty_request = download_export(&app, a1.as_str(), Some("")).await;
+    expect_that!(empty_request.status(), eq(StatusCode::BAD_REQUEST));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn import_unversioned_export(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (_, a2) = common::get_access_tokens(&db).await;
+
+    // Exports made before the format was versioned
+    let file = json!({
+        "codes": [{
+            "content": "JBSWY3DPEHPK3PXP",
+            "display_name": "GitHub",
+            "website_url": "github.com",
+            "expires_at": null,
+        }],
+    });
+    let import_request = common::import_codes(&app, a2.as_str(), &json!({ "file": file })).await;
+    assert_that!(import_request.status(), eq(StatusCode::OK));
+
+    let listing = common::list_codes_content(&app, a2.as_str()).await;
+    assert_that!(listing.len(), eq(2));
+    expect_that!(listing[1].display_name, eq("GitHub"));
+    expect_that!(listing[1].digits, eq(6));
+    expect_that!(listing[1].period, eq(30));
+    expect_that!(listing[1].algorithm, eq("SHA1"));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn import_newer_export(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (_, a2) = common::get_access_tokens(&db).await;
+
+    let file = json!({ "format_version": 99, "codes": [] });
+    let import_request = common::import_codes(&app, a2.as_str(), &json!({ "file": file })).await;
+    assert_that!(
+        import_request.status(),
+        eq(StatusCode::UNPROCESSABLE_ENTITY)
+    );
+    assert_that!(
+        common::convert_response(import_request).await["errorKind"],
+        eq(&json!("UnsupportedExportVersion"))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn encrypted_import_wrong_passphrase(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let export_request = common::export_codes(
+        &app,
+        a1.as_str(),
+        &json!({ "passphrase": "correct horse battery staple" }),
+    )
+    .await;
+    let file = common::convert_response(export_request).await;
+
+    let import_request = common::import_codes(
+        &app,
+        a2.as_str(),
+        &json!({ "file": file, "passphrase": "incorrect horse battery staple" }),
+    )
+    .await;
+    assert_that!(
+        import_request.status(),
+        eq(StatusCode::UNPROCESSABLE_ENTITY)
+    );
+    assert_that!(
+        common::convert_response(import_request).await["errorKind"],
+        eq(&json!("WrongPassphrase"))
+    );
+
+    let missing_request = common::import_codes(&app, a2.as_str(), &json!({ "file": file })).await;
+    assert_that!(missing_request.status(), eq(StatusCode::BAD_REQUEST));
+
+    // Nothing got imported
+    let listing = common::list_codes_content(&app, a2.as_str()).await;
+    assert_that!(listing, common::matchers::code_fixture());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn export_empty_passphrase(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let export_request =
+        common::export_codes(&app, a1.as_str(), &json!({ "passphrase": "" })).await;
+    assert_that!(export_request.status(), eq(StatusCode::BAD_REQUEST));
+}
+
+async fn export_with(app: &Router, token: &str, uri: &str, accept_encoding: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "application/json")
+                .header("Accept-Encoding", accept_encoding)
+                .body(Body::from("{}"))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn gzip_export_download(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = export_with(&app, a1.as_str(), "/v1/export?gzip=true", "gzip, br").await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(
+        response.headers().get("Content-Type").unwrap(),
+        eq("application/gzip")
+    );
+    assert_that!(
+        response.headers().get("Content-Disposition").unwrap(),
+        eq("attachment; filename=\"iceblink-export.json.gz\"")
+    );
+    // Not compressed a second time
+    assert_that!(response.headers().get("Content-Encoding"), none());
+
+    let compressed = common::convert_response_u8(response).await;
+    let mut decompressed = String::new();
+    GzDecoder::new(compressed.as_slice())
+        .read_to_string(&mut decompressed)
+        .unwrap();
+
+    let file: serde_json::Value = serde_json::from_str(&decompressed).unwrap();
+    assert_that!(file["codes"].as_array().unwrap().len(), eq(2));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn plain_export_is_compressed_in_transit(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = export_with(&app, a1.as_str(), "/v1/export", "gzip").await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(
+        response.headers().get("Content-Encoding").unwrap(),
+        eq("gzip")
+    );
+}
+
+async fn import_otpauth(app: &Router, token: &str, content_type: &str, body: String) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v1/codes/import/otpauth")
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", content_type)
+                .body(Body::from(body))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn otpauth_import_list(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = import_otpauth(
+        &app,
+        a1.as_str(),
+        "text/plain",
+        "otpauth://totp/GitHub:octocat?secret=JBSWY3DPEHPK3PXP&issuer=github.com\n\n\
+         otpauth://totp/Work%20email?secret=GEZDGNBVGY3TQOJQ\n"
+            .to_string(),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let report = common::convert_response(response).await;
+    expect_that!(report["errors"], eq(&json!([])));
+    assert_that!(report["imported"].as_array().unwrap().len(), eq(2));
+
+    let listing = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing.len(), eq(4));
+    expect_that!(listing[2].content, eq("JBSWY3DPEHPK3PXP"));
+    expect_that!(listing[2].display_name, eq("GitHub:octocat"));
+    expect_that!(listing[2].website_url, some(eq("github.com")));
+    expect_that!(listing[2].sort_index, eq(2));
+    expect_that!(listing[3].content, eq("GEZDGNBVGY3TQOJQ"));
+    expect_that!(listing[3].display_name, eq("Work email"));
+    expect_that!(listing[3].website_url, none());
+    expect_that!(listing[3].sort_index, eq(3));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn otpauth_import_with_invalid_uri(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = import_otpauth(
+        &app,
+        a1.as_str(),
+        "application/json",
+        json!([
+            "otpauth://totp/GitHub?secret=JBSWY3DPEHPK3PXP",
+            "otpauth://hotp/Bank?secret=JBSWY3DPEHPK3PXP&counter=0",
+            "not a uri"
+        ])
+        .to_string(),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let report = common::convert_response(response).await;
+    expect_that!(
+        report["errors"],
+        eq(&json!([
+            { "line": 2, "field": "type", "message": "Only totp URIs are supported" },
+            { "line": 3, "field": "uri", "message": "Not an otpauth:// URI" }
+        ]))
+    );
+    expect_that!(report["imported"].as_array().unwrap().len(), eq(1));
+    expect_that!(
+        common::list_codes_content(&app, a1.as_str()).await.len(),
+        eq(3)
+    );
+
+    let response = import_otpauth(&app, a1.as_str(), "application/json", "{}".into()).await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+}
+
+async fn import_aegis(app: &Router, token: &str, payload: &serde_json::Value) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v1/import/aegis")
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_vec(payload).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+fn json_fixture(name: &str) -> serde_json::Value {
+    let path = format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"));
+    serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn aegis_import(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let encrypted = import_aegis(
+        &app,
+        a1.as_str(),
+        &json!({ "vault": json_fixture("aegis_encrypted.json"), "password": "test" }),
+    )
+    .await;
+    assert_that!(encrypted.status(), eq(StatusCode::OK));
+    let report = common::convert_response(encrypted).await;
+    expect_that!(report["skipped"], eq(&json!(["bob"])));
+    assert_that!(report["imported"].as_array().unwrap().len(), eq(1));
+
+    let listing = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing.len(), eq(3));
+    expect_that!(listing[2].content, eq("JBSWY3DPEHPK3PXP"));
+    expect_that!(listing[2].display_name, eq("alice@example.com"));
+    expect_that!(listing[2].website_url, some(eq("GitHub")));
+
+    // Plaintext vaults don't need a password
+    let plain = import_aegis(
+        &app,
+        a2.as_str(),
+        &json!({ "vault": json_fixture("aegis_plain.json") }),
+    )
+    .await;
+    assert_that!(plain.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::list_codes_content(&app, a2.as_str()).await.len(),
+        eq(3)
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn aegis_import_groups_and_icons(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let work = common::add_folder(&app, a1.as_str(), "Work", None).await;
+
+    let response = import_aegis(
+        &app,
+        a1.as_str(),
+        &json!({ "vault": json_fixture("aegis_groups.json") }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let report = common::convert_response(response).await;
+    expect_that!(report["skipped"], eq(&json!(["erin"])));
+    // The existing "Work" folder is reused
+    assert_that!(report["folders"].as_array().unwrap().len(), eq(1));
+    expect_that!(report["folders"][0]["name"], eq(&json!("Legacy")));
+    let legacy = report["folders"][0]["id"].as_str().unwrap();
+
+    let listing = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing.len(), eq(5));
+    expect_that!(listing[2].display_name, eq("alice@example.com"));
+    expect_that!(listing[2].folder_id, some(eq(work.as_str())));
+    expect_that!(
+        listing[2].icon_url,
+        some(eq("data:image/svg+xml;base64,PHN2Zy8+"))
+    );
+    expect_that!(listing[2].issuer, some(eq("GitHub")));
+    expect_that!(listing[2].account, some(eq("alice@example.com")));
+    expect_that!(listing[2].favorite, is_true());
+
+    expect_that!(listing[3].display_name, eq("carol"));
+    expect_that!(listing[3].folder_id, none());
+    expect_that!(listing[3].icon_url, none());
+    expect_that!(listing[3].algorithm, eq("SHA256"));
+    expect_that!(listing[3].digits, eq(8));
+    expect_that!(listing[3].period, eq(60));
+
+    expect_that!(listing[4].display_name, eq("dave"));
+    expect_that!(listing[4].folder_id, some(eq(legacy)));
+    expect_that!(listing[4].website_url, none());
+}
+
+async fn export_aegis(app: &Router, token: &str, password: Option<&str>) -> Response {
+    let mut request = Request::builder()
+        .method(Method::GET)
+        .uri("/v1/export/aegis")
+        .header("Authorization", format!("Bearer {token}"));
+    if let Some(password) = password {
+        request = request.header("X-Export-Password", password);
+    }
+
+    app.clone()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn aegis_import_icon_quota(db: SqlitePool) {
+    // The only icon of the vault is embedded as a data URI of 34 bytes
+    let icon_quota = |max_icon_bytes_per_user| ServerOptions {
+        max_icon_bytes_per_user,
+        ..common::testing_options()
+    };
+    let app = common::testing_setup_with_options(&db, icon_quota(33)).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let payload = json!({ "vault": json_fixture("aegis_groups.json") });
+
+    let response = import_aegis(&app, a1.as_str(), &payload).await;
+    assert_that!(response.status(), eq(StatusCode::FORBIDDEN));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("IconQuotaExceeded"))
+    );
+    expect_that!(
+        common::list_codes_content(&app, a1.as_str()).await.len(),
+        eq(2)
+    );
+
+    let app = common::testing_setup_with_options(&db, icon_quota(34)).await;
+    let response = import_aegis(&app, a1.as_str(), &payload).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::list_codes_content(&app, a1.as_str()).await.len(),
+        eq(5)
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn aegis_export_round_trip(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    let response = import_aegis(
+        &app,
+        a1.as_str(),
+        &json!({ "vault": json_fixture("aegis_groups.json") }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let response = export_aegis(&app, a1.as_str(), Some("hunter2")).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        response.headers().get("Content-Disposition").unwrap(),
+        eq("attachment; filename=\"iceblink-aegis.json\"")
+    );
+    let vault = common::convert_response(response).await;
+    expect_that!(vault["db"].is_string(), is_true());
+
+    // Moving every code over to another account
+    let response = import_aegis(
+        &app,
+        a2.as_str(),
+        &json!({ "vault": vault, "password": "hunter2" }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let report = common::convert_response(response).await;
+    expect_that!(report["skipped"], eq(&json!([])));
+    expect_that!(
+        report["folders"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|folder| folder["name"].as_str().unwrap())
+            .collect::<Vec<_>>(),
+        unordered_elements_are![eq("Legacy"), eq("Work")]
+    );
+
+    let exported = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(
+        report["imported"].as_array().unwrap().len(),
+        eq(exported.len())
+    );
+    let imported = common::list_codes_content(&app, a2.as_str()).await;
+    let carol = imported
+        .iter()
+        .find(|code| code.display_name == "carol")
+        .unwrap();
+    expect_that!(carol.algorithm, eq("SHA256"));
+    expect_that!(carol.digits, eq(8));
+    expect_that!(carol.period, eq(60));
+    let alice = imported
+        .iter()
+        .find(|code| code.display_name == "alice@example.com")
+        .unwrap();
+    expect_that!(alice.favorite, is_true());
+    expect_that!(
+        alice.icon_url,
+        some(eq("data:image/svg+xml;base64,PHN2Zy8+"))
+    );
+    expect_that!(alice.folder_id, some(anything()));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn aegis_export_plain(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = export_aegis(&app, a1.as_str(), None).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let vault = common::convert_response(response).await;
+    expect_that!(vault["header"]["slots"], eq(&json!(null)));
+    expect_that!(vault["db"]["version"], eq(&json!(3)));
+    expect_that!(vault["db"]["entries"].as_array().unwrap().len(), eq(2));
+
+    let response = export_aegis(&app, a1.as_str(), Some("")).await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn aegis_import_wrong_password(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = import_aegis(
+        &app,
+        a1.as_str(),
+        &json!({ "vault": json_fixture("aegis_encrypted.json"), "password": "wrong" }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::UNPROCESSABLE_ENTITY));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("WrongPassphrase"))
+    );
+
+    let response = import_aegis(
+        &app,
+        a1.as_str(),
+        &json!({ "vault": json_fixture("aegis_encrypted.json") }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+
+    // Nothing got imported
+    let listing = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing, common::matchers::code_fixture());
+}
+
+async fn import_andotp(app: &Router, token: &str, payload: &serde_json::Value) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v1/import/andotp")
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_vec(payload).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+fn base64_fixture(name: &str) -> String {
+    let path = format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"));
+    STANDARD.encode(std::fs::read(path).unwrap())
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn andotp_import(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let response = import_andotp(
+        &app,
+        a1.as_str(),
+        &json!({ "backup": base64_fixture("andotp_encrypted.json.aes"), "password": "test" }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let report = common::convert_response(response).await;
+    expect_that!(report["skipped"], eq(&json!(["Bank: bob"])));
+
+    let listing = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing.len(), eq(4));
+    expect_that!(listing[2].display_name, eq("alice@example.com"));
+    expect_that!(listing[2].issuer, some(eq("GitHub")));
+    expect_that!(listing[3].display_name, eq("carol"));
+    expect_that!(listing[3].algorithm, eq("SHA512"));
+    expect_that!(listing[3].digits, eq(8));
+    expect_that!(listing[3].period, eq(60));
+
+    // Plaintext backups don't need a password
+    let response = import_andotp(
+        &app,
+        a2.as_str(),
+        &json!({ "backup": base64_fixture("andotp_plain.json") }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::list_codes_content(&app, a2.as_str()).await.len(),
+        eq(4)
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn andotp_import_rejected(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = import_andotp(
+        &app,
+        a1.as_str(),
+        &json!({ "backup": base64_fixture("andotp_encrypted.json.aes"), "password": "wrong" }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::UNPROCESSABLE_ENTITY));
+
+    let response = import_andotp(
+        &app,
+        a1.as_str(),
+        &json!({ "backup": base64_fixture("andotp_encrypted.json.aes") }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("PassphraseRequired"))
+    );
+
+    let response = import_andotp(&app, a1.as_str(), &json!({ "backup": "not base64!" })).await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+
+    // Nothing got imported
+    let listing = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing, common::matchers::code_fixture());
+}
+
+async fn import_twofas(app: &Router, token: &str, payload: &serde_json::Value) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v1/import/2fas")
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_vec(payload).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+async fn export_twofas(app: &Router, token: &str, password: Option<&str>) -> Response {
+    let mut request = Request::builder()
+        .method(Method::GET)
+        .uri("/v1/export/2fas")
+        .header("Authorization", format!("Bearer {token}"));
+    if let Some(password) = password {
+        request = request.header("X-Export-Password", password);
+    }
+
+    app.clone()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn twofas_import(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = import_twofas(
+        &app,
+        a1.as_str(),
+        &json!({ "backup": json_fixture("twofas_encrypted.2fas"), "password": "test" }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let report = common::convert_response(response).await;
+    expect_that!(report["skipped"], eq(&json!(["Bank"])));
+    expect_that!(report["folders"][0]["name"], eq(&json!("Work")));
+
+    let listing = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing.len(), eq(4));
+    expect_that!(listing[2].display_name, eq("Example"));
+    expect_that!(listing[2].digits, eq(8));
+    expect_that!(listing[3].display_name, eq("GitHub"));
+    expect_that!(listing[3].account, some(eq("alice@example.com")));
+    expect_that!(
+        listing[3].folder_id.as_deref(),
+        some(eq(report["folders"][0]["id"].as_str().unwrap()))
+    );
+
+    let response = import_twofas(
+        &app,
+        a1.as_str(),
+        &json!({ "backup": json_fixture("twofas_encrypted.2fas"), "password": "wrong" }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::UNPROCESSABLE_ENTITY));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn twofas_export_round_trip(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    let response = import_twofas(
+        &app,
+        a1.as_str(),
+        &json!({ "backup": json_fixture("twofas_plain.2fas") }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let response = export_twofas(&app, a1.as_str(), Some("hunter2")).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        response.headers().get("Content-Disposition").unwrap(),
+        eq("attachment; filename=\"iceblink.2fas\"")
+    );
+    let backup = common::convert_response(response).await;
+    expect_that!(backup["services"], eq(&json!([])));
+
+    let response = import_twofas(
+        &app,
+        a2.as_str(),
+        &json!({ "backup": backup, "password": "hunter2" }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let report = common::convert_response(response).await;
+    expect_that!(report["skipped"], eq(&json!([])));
+
+    let exported = common::list_codes_content(&app, a1.as_str()).await;
+    let imported: Vec<_> = report["imported"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|code| code["content"].as_str().unwrap())
+        .collect();
+    expect_that!(
+        imported,
+        elements_are![
+            eq(exported[0].content.as_str()),
+            eq(exported[1].content.as_str()),
+            eq("KRSXG5CTMVRXEZLU"),
+            eq("JBSWY3DPEHPK3PXP")
+        ]
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn steam_codes_round_trip(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let vault = json!({
+        "version": 1,
+        "header": { "slots": null, "params": null },
+        "db": {
+            "version": 3,
+            "entries": [{
+                "type": "steam",
+                "name": "gaben",
+                "issuer": "Steam",
+                "info": { "secret": "JBSWY3DPEHPK3PXP", "algo": "SHA1", "digits": 5, "period": 30 }
+            }]
+        }
+    });
+    let response = import_aegis(&app, a1.as_str(), &json!({ "vault": vault })).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::convert_response(response).await["skipped"],
+        eq(&json!([]))
+    );
+
+    let listing = common::list_codes_content(&app, a1.as_str()).await;
+    let steam = listing.last().unwrap();
+    expect_that!(steam.kind, eq("steam"));
+    expect_that!(steam.digits, eq(5));
+
+    let response = export_aegis(&app, a1.as_str(), None).await;
+    let vault = common::convert_response(response).await;
+    expect_that!(vault["db"]["entries"][2]["type"], eq(&json!("steam")));
+
+    let response = export_twofas(&app, a1.as_str(), None).await;
+    let backup = common::convert_response(response).await;
+    expect_that!(
+        backup["services"][2]["otp"]["tokenType"],
+        eq(&json!("STEAM"))
+    );
+
+    let response = common::export_codes(&app, a1.as_str(), &json!({})).await;
+    let file = common::convert_response(response).await;
+    expect_that!(file["codes"][2]["kind"], eq(&json!("steam")));
+    let response = common::import_codes(&app, a2.as_str(), &json!({ "file": file })).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let imported = common::list_codes_content(&app, a2.as_str()).await;
+    expect_that!(imported.last().unwrap().kind, eq("steam"));
+}
+
+async fn import_google_authenticator(app: &Router, token: &str, body: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v1/import/google-authenticator")
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "text/plain")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+/// Exports of a TOTP entry with an issuer, an HOTP entry, and a TOTP entry with 8 digit SHA256
+/// codes. The second QR code holds only the first entry again.
+const GOOGLE_AUTHENTICATOR_EXPORT: &str = "otpauth-migration://offline?data=CjQKCkhlbGxvId6tvu8SGEdpdEh1YjphbGljZUBleGFtcGxlLmNvbRoGR2l0SHViIAEoATACChkKCkhlbGxvId6tvu8SA2JvYiABKAEwATgFCiIKCkhlbGxvId6tvu8SBWNhcm9sGgdFeGFtcGxlIAIoAjACEAEYASAAKHs%3D
+otpauth-migration://offline?data=CjQKCkhlbGxvId6tvu8SGEdpdEh1YjphbGljZUBleGFtcGxlLmNvbRoGR2l0SHViIAEoATAC";
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn google_authenticator_import(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response =
+        import_google_authenticator(&app, a1.as_str(), GOOGLE_AUTHENTICATOR_EXPORT).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let report = common::convert_response(response).await;
+    expect_that!(report["skipped"], eq(&json!(["bob"])));
+    assert_that!(report["imported"].as_array().unwrap().len(), eq(3));
+
+    let listing = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing.len(), eq(5));
+    expect_that!(listing[2].content, eq("JBSWY3DPEHPK3PXP"));
+    expect_that!(listing[2].display_name, eq("GitHub:alice@example.com"));
+    expect_that!(listing[2].issuer, some(eq("GitHub")));
+    expect_that!(listing[2].account, some(eq("alice@example.com")));
+    expect_that!(listing[3].display_name, eq("carol"));
+    expect_that!(listing[3].digits, eq(8));
+    expect_that!(listing[3].algorithm, eq("SHA256"));
+    expect_that!(listing[4].display_name, eq("GitHub:alice@example.com"));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn google_authenticator_import_malformed(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    // A single broken QR code fails the whole import
+    let body = format!("{GOOGLE_AUTHENTICATOR_EXPORT}\notpauth-migration://offline?data=broken");
+    let response = import_google_authenticator(&app, a1.as_str(), &body).await;
+    expect_that!(response.status(), eq(StatusCode::UNPROCESSABLE_ENTITY));
+    expect_that!(
+        common::list_codes_content(&app, a1.as_str()).await,
+        common::matchers::code_fixture()
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn import_with_progress(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let codes: Vec<serde_json::Value> = (0..120)
+        .map(|i| {
+            json!({
+                "content": "JBSWY3DPEHPK3PXP",
+                "display_name": format!("Code {i}"),
+                "website_url": null,
+                "expires_at": null
+            })
+        })
+        .collect();
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v1/codes/import/progress")
+                .header("Authorization", format!("Bearer {a1}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&json!({ "file": { "codes": codes } })).unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(
+        response.headers()["Content-Type"],
+        eq("application/x-ndjson")
+    );
+
+    let mut body = response.into_body().into_data_stream();
+    let mut buffered = String::new();
+    let mut progress = vec![];
+    while let Some(chunk) = body.next().await {
+        buffered.push_str(std::str::from_utf8(&chunk.unwrap()).unwrap());
+        while let Some(newline) = buffered.find('\n') {
+            let line: String = buffered.drain(..=newline).collect();
+            progress.push(serde_json::from_str::<serde_json::Value>(line.trim_end()).unwrap());
+        }
+    }
+
+    expect_that!(
+        progress,
+        elements_are![
+            eq(&json!({ "processed": 0, "total": 120 })),
+            eq(&json!({ "processed": 50, "total": 120 })),
+            eq(&json!({ "processed": 100, "total": 120 })),
+            eq(&json!({ "processed": 120, "total": 120 }))
+        ]
+    );
+
+    let listing = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing.len(), eq(122));
+    expect_that!(listing[121].display_name, eq("Code 119"));
+    expect_that!(listing[121].sort_index, eq(121));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn export_requires_recent_login(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let token = common::create_token(&app, a1.as_str(), "codes:read").await;
+
+    sqlx::query("UPDATE sessions SET created_at = created_at - 3600")
+        .execute(&db)
+        .await
+        .unwrap();
+
+    for token in [a1.as_str(), token.as_str()] {
+        let response = common::export_codes(&app, token, &json!({})).await;
+        assert_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+        assert_that!(
+            common::convert_response(response).await["errorKind"],
+            eq(&json!("ReauthenticationRequired"))
+        );
+    }
+
+    // A made up step-up token doesn't help either
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/v1/export/aegis")
+                .header("Authorization", format!("Bearer {a1}"))
+                .header("X-Step-Up-Token", "made up")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+}
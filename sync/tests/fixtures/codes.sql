@@ -1,5 +1,5 @@
 -- Inserts dummy codes for our dummy users
 
-INSERT INTO codes (id, owner_id, content, display_name, website_url) VALUES ("Ckpt4eFi1pw9fxI3", "k0d8WrkRjK6gkc3C", "GK6ZFMqk18fuWnCw", "Google", "google.com");
-INSERT INTO codes (id, owner_id, content, display_name, website_url) VALUES ("DxLCqi4ZlHPD8YxA", "k0d8WrkRjK6gkc3C", "XGDi8FlvZ5OGBoxG", "google.com", "google.com");
-INSERT INTO codes (id, owner_id, content, display_name, icon_url, website_url) VALUES ("fUJveqJaNpPhTUkR", "3Ck0d8WrkRjK6gkc", "djnaW1Pl2WjhWrU6", "Dummy INC", "https://dummy.com/favicon.ico", "dummy.com");
+INSERT INTO codes (id, owner_id, content, display_name, website_url, sort_index) VALUES ("Ckpt4eFi1pw9fxI3", "k0d8WrkRjK6gkc3C", "GK6ZFMqk18fuWnCw", "Google", "google.com", 0);
+INSERT INTO codes (id, owner_id, content, display_name, website_url, sort_index) VALUES ("DxLCqi4ZlHPD8YxA", "k0d8WrkRjK6gkc3C", "XGDi8FlvZ5OGBoxG", "google.com", "google.com", 1);
+INSERT INTO codes (id, owner_id, content, display_name, icon_url, website_url, sort_index) VALUES ("fUJveqJaNpPhTUkR", "3Ck0d8WrkRjK6gkc", "djnaW1Pl2WjhWrU6", "Dummy INC", "https://dummy.com/favicon.ico", "dummy.com", 0);
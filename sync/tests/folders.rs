@@ -0,0 +1,257 @@
+use axum::http::{Method, StatusCode};
+use googletest::prelude::*;
+use serde_json::json;
+use sqlx::SqlitePool;
+
+pub mod common;
+
+fn names(folders: &serde_json::Value) -> Vec<String> {
+    folders
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|folder| folder["name"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn manage_folders(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let work = common::add_folder(&app, &a1, "Work", None).await;
+    let servers = common::add_folder(&app, &a1, "servers", Some(&work)).await;
+
+    let response = common::folders_request(&app, &a1, Method::GET, "", None).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let folders = common::convert_response(response).await;
+    expect_that!(names(&folders), elements_are![eq("servers"), eq("Work")]);
+    expect_that!(folders[0]["parent_id"], eq(&json!(work)));
+
+    // Folders of other users are neither listed, nor editable
+    let response = common::folders_request(&app, &a2, Method::GET, "", None).await;
+    expect_that!(common::convert_response(response).await, eq(&json!([])));
+    let response = common::folders_request(
+        &app,
+        &a2,
+        Method::PATCH,
+        &format!("/{work}"),
+        Some(&json!({ "name": "Mine" })),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::NOT_FOUND));
+
+    let response = common::folders_request(
+        &app,
+        &a1,
+        Method::PATCH,
+        &format!("/{servers}"),
+        Some(&json!({ "name": "Servers", "parent_id": null })),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::convert_response(response).await,
+        eq(&json!({
+            "id": servers,
+            "owner_id": common::USER1_ID,
+            "name": "Servers",
+            "parent_id": null
+        }))
+    );
+
+    let response =
+        common::folders_request(&app, &a1, Method::DELETE, &format!("/{work}"), None).await;
+    expect_that!(response.status(), eq(StatusCode::NO_CONTENT));
+    let response = common::folders_request(&app, &a1, Method::GET, "", None).await;
+    expect_that!(
+        names(&common::convert_response(response).await),
+        elements_are![eq("Servers")]
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn folder_rejects_invalid_placement(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let work = common::add_folder(&app, &a1, "Work", None).await;
+    let servers = common::add_folder(&app, &a1, "Servers", Some(&work)).await;
+
+    for (id, payload) in [
+        (&work, json!({ "parent_id": work })),
+        (&work, json!({ "parent_id": servers })),
+        (&work, json!({ "parent_id": "doesnotexist0000" })),
+        (&work, json!({ "name": "" })),
+    ] {
+        let response =
+            common::folders_request(&app, &a1, Method::PATCH, &format!("/{id}"), Some(&payload))
+                .await;
+        expect_that!(response.status(), eq(StatusCode::BAD_REQUEST), "{payload}");
+    }
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn codes_in_folders(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    let work = common::add_folder(&app, &a1, "Work", None).await;
+    let servers = common::add_folder(&app, &a1, "Servers", Some(&work)).await;
+    let other = common::add_folder(&app, &a2, "Other", None).await;
+
+    let response = common::add_code(
+        &app,
+        &a1,
+        &json!({ "content": "JBSWY3DPEHPK3PXP", "display_name": "Router", "folder_id": servers }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let router = common::convert_response(response).await;
+    expect_that!(router["folder_id"], eq(&json!(servers)));
+
+    // Folders of other users can't be used
+    let response = common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "folder_id": other }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+
+    let response = common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "folder_id": work }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let cursor = common::convert_response(common::delta_codes(&app, &a1, None).await).await
+        ["cursor"]
+        .as_i64()
+        .unwrap();
+
+    // Deleting a folder moves its codes up to its parent, as a change for syncing clients
+    let response =
+        common::folders_request(&app, &a1, Method::DELETE, &format!("/{servers}"), None).await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+
+    let delta = common::convert_response(common::delta_codes(&app, &a1, Some(cursor)).await).await;
+    expect_that!(delta["changed"][0]["id"], eq(&router["id"]));
+    expect_that!(delta["changed"][0]["folder_id"], eq(&json!(work)));
+
+    let response =
+        common::folders_request(&app, &a1, Method::DELETE, &format!("/{work}"), None).await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+    let codes = common::list_codes_content(&app, &a1).await;
+    expect_that!(codes.iter().all(|code| code.folder_id.is_none()), is_true());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn unique_names_per_folder(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    common::edit_settings(&app, &a1, &json!({ "enforce_unique_names": true })).await;
+    let work = common::add_folder(&app, &a1, "Work", None).await;
+
+    // The fixture has a "Google" at the top level, but not in the folder
+    let response = common::add_code(
+        &app,
+        &a1,
+        &json!({ "content": "JBSWY3DPEHPK3PXP", "display_name": "Google", "folder_id": work }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    // Moving either into the folder of the other clashes
+    let response = common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "folder_id": work }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::CONFLICT));
+
+    let response =
+        common::folders_request(&app, &a1, Method::DELETE, &format!("/{work}"), None).await;
+    expect_that!(response.status(), eq(StatusCode::CONFLICT));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn codes_grouped_by_folder(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let work = common::add_folder(&app, &a1, "Work", None).await;
+    let servers = common::add_folder(&app, &a1, "Servers", Some(&work)).await;
+    common::add_folder(&app, &a1, "Empty", None).await;
+    let response = common::add_code(
+        &app,
+        &a1,
+        &json!({ "content": "JBSWY3DPEHPK3PXP", "display_name": "Router", "folder_id": servers }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let code_names = |codes: &serde_json::Value| -> Vec<String> {
+        codes
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|code| code["display_name"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    let response = common::get_codes_path(&app, &a1, "?group_by=folder").await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(response.headers()["X-Total-Count"], eq("3"));
+    let grouped = common::convert_response(response).await;
+    let folders = grouped["folders"].as_array().unwrap();
+    // Work holds no codes itself, but contains the folder of the router
+    let folder_names: Vec<_> = folders
+        .iter()
+        .map(|group| group["folder"]["name"].as_str().unwrap())
+        .collect();
+    expect_that!(folder_names, elements_are![eq("Servers"), eq("Work")]);
+    expect_that!(folders[0]["folder"]["parent_id"], eq(&json!(work)));
+    expect_that!(
+        code_names(&folders[0]["codes"]),
+        elements_are![eq("Router")]
+    );
+    expect_that!(code_names(&folders[1]["codes"]), empty());
+    expect_that!(
+        code_names(&grouped["uncategorized"]),
+        elements_are![eq("Google"), eq("google.com")]
+    );
+
+    // Pages are cut from the listing before grouping
+    let response = common::get_codes_path(&app, &a1, "?group_by=folder&limit=2").await;
+    expect_that!(response.headers()["X-Total-Count"], eq("3"));
+    let grouped = common::convert_response(response).await;
+    expect_that!(grouped["folders"], eq(&json!([])));
+    expect_that!(code_names(&grouped["uncategorized"]), len(eq(2)));
+
+    let response = common::get_codes_path(
+        &app,
+        &a1,
+        "?group_by=folder&limit=2&offset=2&fields=display_name",
+    )
+    .await;
+    let grouped = common::convert_response(response).await;
+    expect_that!(grouped["folders"].as_array().unwrap(), len(eq(2)));
+    expect_that!(
+        grouped["folders"][0]["codes"],
+        eq(&json!([{ "display_name": "Router" }]))
+    );
+    expect_that!(grouped["uncategorized"], eq(&json!([])));
+
+    let response = common::get_codes_path(&app, &a1, "?group_by=tag").await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+}
@@ -0,0 +1,532 @@
+use axum::{
+    body::Body,
+    extract::Path,
+    http::{header, Method, Request, StatusCode},
+    response::{Html, Redirect, Response},
+    routing::get,
+    Router,
+};
+use futures_util::stream;
+use googletest::prelude::*;
+use iceblink_sync::{
+    events::{EventKind, Events},
+    icons::{self, IconStore, IconStoreError},
+    models::icons::Icon,
+    tasks, utils,
+};
+use sqlx::SqlitePool;
+use std::time::Duration;
+use tower::ServiceExt;
+
+pub mod common;
+
+async fn favicon_upstream() -> String {
+    common::mock_upstream(Router::new().route(
+        "/:domain/favicon.ico",
+        get(|Path(domain): Path<String>| async move { domain.into_bytes() }),
+    ))
+    .await
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn refresh_populates_missing_icons(db: SqlitePool) {
+    let store = IconStore::new()
+        .with_upstream(favicon_upstream().await)
+        .allow_private_networks(true);
+    store.init().await.unwrap();
+
+    assert_that!(store.get(&utils::content_hash(b"google.com")).await, none());
+    assert_that!(store.get(&utils::content_hash(b"dummy.com")).await, none());
+
+    let report = icons::refresh_icons(&db, &store, None, Duration::from_millis(1))
+        .await
+        .unwrap();
+
+    assert_that!(
+        report.refreshed,
+        unordered_elements_are![eq("google.com"), eq("dummy.com")]
+    );
+    assert_that!(report.failed, empty());
+
+    assert_that!(
+        store.get(&utils::content_hash(b"google.com")).await,
+        some(eq(&b"google.com".to_vec()))
+    );
+    assert_that!(
+        store.get(&utils::content_hash(b"dummy.com")).await,
+        some(eq(&b"dummy.com".to_vec()))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn refresh_single_owner(db: SqlitePool) {
+    let store = IconStore::new()
+        .with_upstream(favicon_upstream().await)
+        .allow_private_networks(true);
+    store.init().await.unwrap();
+
+    let report = icons::refresh_icons(
+        &db,
+        &store,
+        Some(common::USER2_ID.into()),
+        Duration::from_millis(1),
+    )
+    .await
+    .unwrap();
+
+    assert_that!(report.refreshed, elements_are![eq("dummy.com")]);
+    assert_that!(store.get(&utils::content_hash(b"google.com")).await, none());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn refresh_respects_ssrf_guard(db: SqlitePool) {
+    let store = IconStore::new().with_upstream(favicon_upstream().await);
+    store.init().await.unwrap();
+
+    let report = icons::refresh_icons(&db, &store, None, Duration::from_millis(1))
+        .await
+        .unwrap();
+
+    assert_that!(report.refreshed, empty());
+    assert_that!(report.failed.len(), eq(2));
+    assert!(report
+        .failed
+        .iter()
+        .all(|(_, err)| matches!(err, IconStoreError::BlockedHost)));
+}
+
+const PNG: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+async fn image_upstream() -> String {
+    common::mock_upstream(
+        Router::new()
+            .route(
+                "/icon.png",
+                get(|| async { ([(header::CONTENT_TYPE, "image/png")], PNG) }),
+            )
+            .route(
+                "/icon.svg",
+                get(|| async {
+                    (
+                        [(header::CONTENT_TYPE, "image/svg+xml")],
+                        r#"<svg xmlns="http://www.w3.org/2000/svg"><script>alert(document.cookie)</script></svg>"#,
+                    )
+                }),
+            )
+            .route(
+                "/page.html",
+                get(|| async { ([(header::CONTENT_TYPE, "text/html")], "<html></html>") }),
+            )
+            .route(
+                "/huge.png",
+                get(|| async {
+                    (
+                        [(header::CONTENT_TYPE, "image/png")],
+                        vec![0u8; icons::MAX_ICON_SIZE + 1],
+                    )
+                }),
+            ),
+    )
+    .await
+}
+
+async fn preview(app: &Router, token: &str, url: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri(format!("/v1/icons/preview?url={url}"))
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn preview_returns_image(db: SqlitePool) {
+    let upstream = image_upstream().await;
+    let store = IconStore::new().allow_private_networks(true);
+    let app = common::testing_setup_with_icon_store(&db, store).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = preview(&app, &a1, &format!("{upstream}/icon.png")).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(
+        response.headers().get(header::CONTENT_TYPE).unwrap(),
+        eq("image/png")
+    );
+    assert_that!(
+        common::convert_response_u8(response).await,
+        eq(&PNG.to_vec())
+    );
+
+    for path in ["/page.html", "/huge.png"] {
+        let response = preview(&app, &a1, &format!("{upstream}{path}")).await;
+        expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    }
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn preview_never_runs_as_document(db: SqlitePool) {
+    let upstream = image_upstream().await;
+    let store = IconStore::new().allow_private_networks(true);
+    let app = common::testing_setup_with_icon_store(&db, store).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = preview(&app, &a1, &format!("{upstream}/icon.svg")).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let headers = response.headers();
+    expect_that!(headers[header::CONTENT_TYPE], eq("image/svg+xml"));
+    expect_that!(headers[header::X_CONTENT_TYPE_OPTIONS], eq("nosniff"));
+    expect_that!(
+        headers[header::CONTENT_SECURITY_POLICY],
+        eq("default-src 'none'; style-src 'unsafe-inline'; sandbox")
+    );
+    expect_that!(
+        headers[header::CONTENT_DISPOSITION],
+        eq("inline; filename=\"icon\"")
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn preview_respects_ssrf_guard(db: SqlitePool) {
+    let upstream = image_upstream().await;
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    for url in [
+        format!("{upstream}/icon.png"),
+        "http://169.254.169.254/latest/meta-data".to_string(),
+        "file:///etc/passwd".to_string(),
+    ] {
+        let response = preview(&app, &a1, &url).await;
+        expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+        expect_that!(
+            common::convert_response(response).await["message"],
+            eq(&serde_json::json!(
+                "Icons can only be previewed from public http(s) URLs."
+            ))
+        );
+    }
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn preview_is_rate_limited(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    for _ in 0..iceblink_sync::routes::v1::icons::PREVIEWS_PER_MINUTE {
+        preview(&app, &a1, "file:///etc/passwd").await;
+    }
+
+    let response = preview(&app, &a1, "file:///etc/passwd").await;
+    expect_that!(response.status(), eq(StatusCode::TOO_MANY_REQUESTS));
+
+    // Other users have their own limit
+    let response = preview(&app, &a2, "file:///etc/passwd").await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+}
+
+const ICO: &[u8] = b"\0\0\x01\0\x01\0";
+
+/// Website serving `html` as its page, with an icon at `/assets/icon.png` and `/favicon.ico`, and
+/// `/moved` redirecting to the former.
+async fn website(html: &'static str) -> String {
+    common::mock_upstream(
+        Router::new()
+            .route("/", get(move || async move { Html(html) }))
+            .route(
+                "/assets/icon.png",
+                get(|| async { ([(header::CONTENT_TYPE, "image/png")], PNG) }),
+            )
+            .route(
+                "/assets/page.png",
+                get(|| async { Html("<html>Not found</html>") }),
+            )
+            .route(
+                "/moved",
+                get(|| async { Redirect::temporary("assets/icon.png") }),
+            )
+            .route("/loop", get(|| async { Redirect::temporary("/loop") }))
+            // Trickles out a byte every 100ms, forever
+            .route(
+                "/trickle",
+                get(|| async {
+                    let bytes = stream::unfold((), |_| async {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        Some((Ok::<_, std::io::Error>(vec![0u8]), ()))
+                    });
+                    (
+                        [(header::CONTENT_TYPE, "image/png")],
+                        Body::from_stream(bytes),
+                    )
+                }),
+            )
+            // Served without a useful content type, as plenty of websites do
+            .route(
+                "/favicon.ico",
+                get(|| async { ([(header::CONTENT_TYPE, "application/octet-stream")], ICO) }),
+            ),
+    )
+    .await
+}
+
+#[tokio::test]
+#[gtest]
+async fn gather_discovers_linked_icons() {
+    let site = website(
+        r#"<html><head><link rel="icon" href="/assets/page.png" sizes="64x64"><link rel="icon" href="assets/icon.png"></head></html>"#,
+    )
+    .await;
+    let store = IconStore::new().allow_private_networks(true);
+    store.init().await.unwrap();
+
+    // Links which aren't images are skipped
+    let gathered = store.gather(&site).await.unwrap();
+    expect_that!(gathered.source, eq(&format!("{site}/assets/icon.png")));
+    expect_that!(gathered.content_type, eq("image/png"));
+    expect_that!(gathered.icon, eq(&PNG.to_vec()));
+}
+
+#[tokio::test]
+#[gtest]
+async fn gather_falls_back_to_favicon_ico() {
+    let site = website(r#"<html><head><link rel="icon" href="/missing.png"></head></html>"#).await;
+    let store = IconStore::new().allow_private_networks(true);
+    store.init().await.unwrap();
+
+    let gathered = store.gather(&site).await.unwrap();
+    expect_that!(gathered.source, eq(&format!("{site}/favicon.ico")));
+    expect_that!(gathered.content_type, eq("image/x-icon"));
+    expect_that!(gathered.icon, eq(&ICO.to_vec()));
+}
+
+#[tokio::test]
+#[gtest]
+async fn redirects_are_followed_hop_by_hop() {
+    let site = website("<html></html>").await;
+    let store = IconStore::new().allow_private_networks(true);
+
+    let (content_type, icon) = store.preview(&format!("{site}/moved")).await.unwrap();
+    expect_that!(content_type, eq("image/png"));
+    expect_that!(icon, eq(&PNG.to_vec()));
+
+    assert!(matches!(
+        store.preview(&format!("{site}/loop")).await,
+        Err(IconStoreError::UnableToSendRequest)
+    ));
+}
+
+#[tokio::test]
+#[gtest]
+async fn slow_websites_time_out() {
+    let site = website("<html></html>").await;
+    let store = IconStore::new()
+        .allow_private_networks(true)
+        .with_timeout(Duration::from_millis(500));
+
+    let preview = tokio::time::timeout(
+        Duration::from_secs(5),
+        store.preview(&format!("{site}/trickle")),
+    )
+    .await
+    .expect("The request never timed out");
+    expect_that!(preview.is_err(), is_true());
+}
+
+#[tokio::test]
+#[gtest]
+async fn linked_icons_are_limited() {
+    let site = website(
+        r#"<html><head><link rel="icon" href="/a.png"><link rel="icon" href="/b.png"><link rel="icon" href="/c.png"><link rel="icon" href="/assets/icon.png"></head></html>"#,
+    )
+    .await;
+    let store = IconStore::new().allow_private_networks(true);
+    store.init().await.unwrap();
+
+    // The fourth link isn't tried, but the favicon still is
+    let gathered = store.gather(&site).await.unwrap();
+    expect_that!(gathered.source, eq(&format!("{site}/favicon.ico")));
+}
+
+/// Points the codes of google.com at a mock website, and the code of user two at the icon of that
+/// website. Returns the website.
+async fn link_codes_to_website(db: &SqlitePool) -> String {
+    let site =
+        website(r#"<html><head><link rel="icon" href="/assets/icon.png"></head></html>"#).await;
+    sqlx::query("UPDATE codes SET website_url = ? WHERE website_url = 'google.com'")
+        .bind(&site)
+        .execute(db)
+        .await
+        .unwrap();
+    sqlx::query("UPDATE codes SET icon_url = ? WHERE id = ?")
+        .bind(format!("{site}/assets/icon.png"))
+        .bind(common::USER2_CODE1_ID)
+        .execute(db)
+        .await
+        .unwrap();
+
+    site
+}
+
+async fn get_icon_if_none_match(app: &Router, token: &str, id: &str, etag: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri(format!("/v1/code/{id}/icon"))
+                .header("Authorization", format!("Bearer {token}"))
+                .header(header::IF_NONE_MATCH, etag)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn missing_icons_are_fetched(db: SqlitePool) {
+    let site = link_codes_to_website(&db).await;
+    let store = IconStore::new().allow_private_networks(true);
+    store.init().await.unwrap();
+    let app = common::testing_setup_with_icon_store(&db, store.clone()).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    let events = Events::default();
+    let mut receiver = events.subscribe();
+
+    expect_that!(
+        tasks::fetch_missing_icons(&db, &store, &events, Duration::ZERO).await,
+        eq(3)
+    );
+    // Only the codes which got the favicon of their website as icon changed
+    for _ in 0..2 {
+        expect_that!(
+            receiver.try_recv().unwrap().kind,
+            eq(EventKind::CodeUpdated)
+        );
+    }
+    expect_that!(receiver.try_recv(), err(anything()));
+
+    let codes = common::list_codes_content(&app, &a1).await;
+    assert_that!(codes, len(eq(2)));
+    for code in &codes {
+        expect_that!(code.icon_url, some(eq(&format!("{site}/assets/icon.png"))));
+    }
+    // Every code shows the same icon, so it's stored once
+    let icon = Icon::get(&db, &utils::content_hash(PNG))
+        .await
+        .unwrap()
+        .unwrap();
+    expect_that!(icon.refs, eq(3));
+    expect_that!(icon.content_type, eq("image/png"));
+    for (token, id) in [(&a1, common::USER1_CODE1_ID), (&a2, common::USER2_CODE1_ID)] {
+        let response = common::get_icon(&app, token, id).await;
+        assert_that!(response.status(), eq(StatusCode::OK));
+        expect_that!(response.headers()[header::CONTENT_TYPE], eq("image/png"));
+        expect_that!(
+            common::convert_response_u8(response).await,
+            eq(&PNG.to_vec())
+        );
+    }
+
+    // Codes which have their icon stored are left alone
+    expect_that!(
+        tasks::fetch_missing_icons(&db, &store, &events, Duration::ZERO).await,
+        eq(0)
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn icons_are_revalidated(db: SqlitePool) {
+    link_codes_to_website(&db).await;
+    let store = IconStore::new().allow_private_networks(true);
+    store.init().await.unwrap();
+    let app = common::testing_setup_with_icon_store(&db, store).await;
+    let (_, a2) = common::get_access_tokens(&db).await;
+
+    // Stored when it's asked for, if the background task didn't get to it yet
+    let response = common::get_icon(&app, &a2, common::USER2_CODE1_ID).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let etag = response.headers()[header::ETAG]
+        .to_str()
+        .unwrap()
+        .to_string();
+    expect_that!(etag, eq(&format!("\"{}\"", utils::content_hash(PNG))));
+    expect_that!(
+        response.headers()[header::CACHE_CONTROL].to_str().unwrap(),
+        contains_substring("max-age=")
+    );
+    expect_that!(
+        response.headers()[header::X_CONTENT_TYPE_OPTIONS],
+        eq("nosniff")
+    );
+    expect_that!(
+        response.headers()[header::CONTENT_SECURITY_POLICY]
+            .to_str()
+            .unwrap(),
+        contains_substring("sandbox")
+    );
+
+    let response = get_icon_if_none_match(&app, &a2, common::USER2_CODE1_ID, &etag).await;
+    expect_that!(response.status(), eq(StatusCode::NOT_MODIFIED));
+    expect_that!(response.headers()[header::ETAG], eq(etag.as_str()));
+    expect_that!(common::convert_response_u8(response).await, empty());
+
+    let response = get_icon_if_none_match(&app, &a2, common::USER2_CODE1_ID, "\"outdated\"").await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn unused_icons_are_removed(db: SqlitePool) {
+    link_codes_to_website(&db).await;
+    let store = IconStore::new().allow_private_networks(true);
+    store.init().await.unwrap();
+    let app = common::testing_setup_with_icon_store(&db, store.clone()).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    tasks::fetch_missing_icons(&db, &store, &Events::default(), Duration::ZERO).await;
+    let hash = utils::content_hash(PNG);
+
+    // Removing the website or icon of a code, or deleting it, unlinks its icon
+    common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &serde_json::json!({ "website_url": null }),
+    )
+    .await;
+    common::edit_code(
+        &app,
+        &a2,
+        common::USER2_CODE1_ID,
+        &serde_json::json!({ "icon_url": null }),
+    )
+    .await;
+    expect_that!(Icon::get(&db, &hash).await.unwrap().unwrap().refs, eq(1));
+    sqlx::query("DELETE FROM codes WHERE id = ?")
+        .bind(common::USER1_CODE2_ID)
+        .execute(&db)
+        .await
+        .unwrap();
+    expect_that!(Icon::get(&db, &hash).await.unwrap().unwrap().refs, eq(0));
+
+    // Kept for a while, in case it's about to be linked again
+    expect_that!(tasks::purge_unused_icons(&db, &store).await, eq(0));
+    expect_that!(store.get(&hash).await, some(anything()));
+
+    let later = chrono::Utc::now().timestamp() + 1;
+    expect_that!(icons::prune_icons(&db, &store, later).await.unwrap(), eq(1));
+    expect_that!(Icon::get(&db, &hash).await.unwrap(), none());
+    expect_that!(store.get(&hash).await, none());
+}
@@ -0,0 +1,253 @@
+use axum::{
+    body::Body,
+    extract::connect_info::MockConnectInfo,
+    http::{Method, Request, StatusCode},
+    response::Response,
+    Router,
+};
+use googletest::prelude::*;
+use iceblink_sync::{lockout, ServerOptions};
+use serde_json::json;
+use sqlx::SqlitePool;
+use std::net::SocketAddr;
+use tower::ServiceExt;
+
+pub mod common;
+
+async fn local_auth_setup(db: &SqlitePool) -> Router {
+    common::testing_setup_with_options(
+        db,
+        ServerOptions {
+            local_auth: true,
+            ..common::testing_options()
+        },
+    )
+    .await
+}
+
+async fn post(
+    app: &Router,
+    uri: &str,
+    token: Option<&str>,
+    payload: serde_json::Value,
+) -> Response {
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header("Content-Type", "application/json");
+    if let Some(token) = token {
+        request = request.header("Authorization", format!("Bearer {token}"));
+    }
+    app.clone()
+        .oneshot(request.body(Body::from(payload.to_string())).unwrap())
+        .await
+        .unwrap()
+}
+
+async fn register(app: &Router, email: &str, username: &str, password: &str) -> Response {
+    post(
+        app,
+        "/v1/local/register",
+        None,
+        json!({"email": email, "username": username, "password": password}),
+    )
+    .await
+}
+
+async fn login(app: &Router, email: &str, password: &str) -> Response {
+    post(
+        app,
+        "/v1/local/login",
+        None,
+        json!({"email": email, "password": password}),
+    )
+    .await
+}
+
+async fn error_kind(response: Response) -> serde_json::Value {
+    common::convert_response(response).await["errorKind"].clone()
+}
+
+#[sqlx::test(fixtures("users"))]
+#[gtest]
+async fn local_auth_disabled(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+
+    let response = register(&app, "alice@example.com", "alice", "correct horse").await;
+    assert_that!(response.status(), eq(StatusCode::NOT_IMPLEMENTED));
+    assert_that!(
+        error_kind(response).await,
+        eq(&json!("LocalAuthUnavailable"))
+    );
+
+    let response = login(&app, "alice@example.com", "correct horse").await;
+    assert_that!(response.status(), eq(StatusCode::NOT_IMPLEMENTED));
+}
+
+#[sqlx::test(fixtures("users"))]
+#[gtest]
+async fn register_and_login(db: SqlitePool) {
+    let app = local_auth_setup(&db).await;
+
+    let response = register(&app, "Alice@Example.com ", "alice", "correct horse").await;
+    assert_that!(response.status(), eq(StatusCode::CREATED));
+    let access_token = common::convert_response(response).await["access_token"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert_that!(
+        common::list_codes(&app, &access_token).await.status(),
+        eq(StatusCode::OK)
+    );
+
+    let stored: (String, String) = sqlx::query_as("SELECT email, password_hash FROM passwords")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_that!(stored.0, eq("alice@example.com"));
+    assert_that!(stored.1, starts_with("$argon2id$"));
+
+    let response = register(&app, "alice@example.com", "alice2", "correct horse").await;
+    assert_that!(response.status(), eq(StatusCode::CONFLICT));
+    assert_that!(error_kind(response).await, eq(&json!("EmailTaken")));
+
+    let response = register(&app, "bob@example.com", "user1", "correct horse").await;
+    assert_that!(response.status(), eq(StatusCode::CONFLICT));
+    assert_that!(error_kind(response).await, eq(&json!("UsernameTaken")));
+
+    let response = register(&app, "bob@example.com", "bob", "short").await;
+    assert_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+
+    let response = login(&app, "alice@example.com", "wrong horse").await;
+    assert_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+    assert_that!(error_kind(response).await, eq(&json!("InvalidCredentials")));
+
+    let response = login(&app, "nobody@example.com", "correct horse").await;
+    assert_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+    assert_that!(error_kind(response).await, eq(&json!("InvalidCredentials")));
+
+    let response = login(&app, "ALICE@example.com", "correct horse").await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(
+        response.headers().get_all("Set-Cookie").iter().count(),
+        eq(2)
+    );
+}
+
+#[sqlx::test(fixtures("users"))]
+#[gtest]
+async fn login_rate_limit(db: SqlitePool) {
+    let app = local_auth_setup(&db).await;
+    register(&app, "alice@example.com", "alice", "correct horse").await;
+
+    for _ in 0..10 {
+        let response = login(&app, "alice@example.com", "wrong horse").await;
+        assert_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+    }
+
+    // Even the right password is rejected until the minute is over
+    let response = login(&app, "alice@example.com", "correct horse").await;
+    assert_that!(response.status(), eq(StatusCode::TOO_MANY_REQUESTS));
+}
+
+#[sqlx::test(fixtures("users"))]
+#[gtest]
+async fn failed_logins_lock_out_the_address(db: SqlitePool) {
+    let app = local_auth_setup(&db).await;
+    let from = |ip: [u8; 4]| {
+        app.clone()
+            .layer(MockConnectInfo(SocketAddr::from((ip, 4000))))
+    };
+    let attacker = from([192, 0, 2, 1]);
+    register(&app, "alice@example.com", "alice", "correct horse").await;
+
+    // Guessing at a different email every time gets around the limit per email, but not the one
+    // per address
+    for attempt in 0..=lockout::FREE_ATTEMPTS {
+        let email = format!("guess{attempt}@example.com");
+        let response = login(&attacker, &email, "correct horse").await;
+        assert_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+    }
+
+    let response = login(&attacker, "alice@example.com", "correct horse").await;
+    assert_that!(response.status(), eq(StatusCode::TOO_MANY_REQUESTS));
+    expect_that!(response.headers().get("Retry-After"), some(anything()));
+    assert_that!(
+        error_kind(response).await,
+        eq(&json!("TooManyFailedAttempts"))
+    );
+
+    let response = login(&from([192, 0, 2, 2]), "alice@example.com", "correct horse").await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+}
+
+#[sqlx::test(fixtures("users"))]
+#[gtest]
+async fn password_reset(db: SqlitePool) {
+    let app = local_auth_setup(&db).await;
+    sqlx::query("UPDATE users SET is_admin = TRUE WHERE id = ?")
+        .bind(common::USER1_ID)
+        .execute(&db)
+        .await
+        .unwrap();
+    let (admin_token, _) = common::get_access_tokens(&db).await;
+
+    let response = register(&app, "alice@example.com", "alice", "correct horse").await;
+    let old_token = common::convert_response(response).await["access_token"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let (alice_id,): (String,) = sqlx::query_as("SELECT id FROM users WHERE username = 'alice'")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+
+    // Users of OpenID providers have no password to reset
+    let response = post(
+        &app,
+        &format!("/v1/admin/users/{}/password-reset", common::USER2_ID),
+        Some(&admin_token),
+        json!({}),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+
+    let response = post(
+        &app,
+        &format!("/v1/admin/users/{alice_id}/password-reset"),
+        Some(&admin_token),
+        json!({}),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::CREATED));
+    let reset_token = common::convert_response(response).await["token"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let reset = json!({"token": reset_token, "password": "battery staple"});
+    let response = post(&app, "/v1/local/password/reset", None, reset.clone()).await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+
+    // Sessions started with the old password are logged out
+    assert_that!(
+        common::list_codes(&app, &old_token).await.status(),
+        eq(StatusCode::UNAUTHORIZED)
+    );
+    assert_that!(
+        login(&app, "alice@example.com", "correct horse")
+            .await
+            .status(),
+        eq(StatusCode::UNAUTHORIZED)
+    );
+    assert_that!(
+        login(&app, "alice@example.com", "battery staple")
+            .await
+            .status(),
+        eq(StatusCode::OK)
+    );
+
+    let response = post(&app, "/v1/local/password/reset", None, reset).await;
+    assert_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    assert_that!(error_kind(response).await, eq(&json!("InvalidResetToken")));
+}
@@ -0,0 +1,217 @@
+use axum::{
+    body::Body,
+    http::{Method, Request, StatusCode},
+    response::Response,
+    Router,
+};
+use googletest::prelude::*;
+use iceblink_sync::ServerOptions;
+use serde_json::json;
+use sqlx::SqlitePool;
+use std::time::Duration;
+use tower::ServiceExt;
+
+pub mod common;
+
+const FIREFOX_ON_LINUX: &str =
+    "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0";
+const CHROME_ON_ANDROID: &str = "Mozilla/5.0 (Linux; Android 14) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/129.0.0.0 Mobile Safari/537.36";
+
+fn mail_options() -> ServerOptions {
+    ServerOptions {
+        smtp_url: Some("smtp://localhost".into()),
+        smtp_from: Some("Iceblink <iceblink@example.com>".into()),
+        frontfacing: "https://iceblink.example.com".into(),
+        ..common::testing_options()
+    }
+}
+
+async fn set_email(db: &SqlitePool, id: &str, email: &str) {
+    sqlx::query("UPDATE users SET email = ? WHERE id = ?")
+        .bind(email)
+        .bind(id)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+async fn local_request(
+    app: &Router,
+    uri: &str,
+    user_agent: &str,
+    payload: serde_json::Value,
+) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(uri)
+                .header("Content-Type", "application/json")
+                .header("User-Agent", user_agent)
+                .body(Body::from(payload.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+async fn export_aegis(app: &Router, token: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/v1/export/aegis")
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users"))]
+#[gtest]
+async fn new_device_logins_are_emailed(db: SqlitePool) {
+    let (app, mailer) = common::testing_setup_with_mailer(
+        &db,
+        ServerOptions {
+            local_auth: true,
+            ..mail_options()
+        },
+    )
+    .await;
+    let credentials = json!({ "email": "alice@example.com", "password": "correct horse battery" });
+
+    let mut payload = credentials.clone();
+    payload["username"] = json!("alice");
+    let response = local_request(&app, "/v1/local/register", FIREFOX_ON_LINUX, payload).await;
+    assert_that!(response.status(), eq(StatusCode::CREATED));
+    // Registering isn't a login to an existing account
+    expect_that!(mailer.outbox(), empty());
+
+    // Nor is logging in again on the same device
+    let response = local_request(
+        &app,
+        "/v1/local/login",
+        FIREFOX_ON_LINUX,
+        credentials.clone(),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(mailer.outbox(), empty());
+
+    let response = local_request(&app, "/v1/local/login", CHROME_ON_ANDROID, credentials).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let outbox = mailer.outbox();
+    assert_that!(outbox, len(eq(1)));
+    expect_that!(outbox[0].to, eq("alice@example.com"));
+    expect_that!(outbox[0].subject, eq("New login to your Iceblink account"));
+    expect_that!(
+        outbox[0].body,
+        all!(
+            contains_substring("Hi alice"),
+            contains_substring("Chrome on Android"),
+            contains_substring("https://iceblink.example.com")
+        )
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn scheduled_deletions_are_emailed(db: SqlitePool) {
+    let (app, mailer) = common::testing_setup_with_mailer(
+        &db,
+        ServerOptions {
+            account_deletion_grace: Duration::from_secs(7 * 24 * 60 * 60),
+            ..mail_options()
+        },
+    )
+    .await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    set_email(&db, common::USER1_ID, "user1@example.com").await;
+
+    // Users without an address can't be emailed
+    let response = common::delete_account(&app, &a2).await;
+    assert_that!(response.status(), eq(StatusCode::ACCEPTED));
+    expect_that!(mailer.outbox(), empty());
+
+    let response = common::delete_account(&app, &a1).await;
+    assert_that!(response.status(), eq(StatusCode::ACCEPTED));
+    let outbox = mailer.outbox();
+    assert_that!(outbox, len(eq(1)));
+    expect_that!(outbox[0].to, eq("user1@example.com"));
+    expect_that!(
+        outbox[0].subject,
+        eq("Your Iceblink account will be deleted")
+    );
+
+    // Deleting it again keeps the date, so there's nothing new to tell
+    common::delete_account(&app, &a1).await;
+    expect_that!(mailer.outbox(), len(eq(1)));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn large_exports_are_emailed(db: SqlitePool) {
+    let (app, mailer) = common::testing_setup_with_mailer(
+        &db,
+        ServerOptions {
+            export_notification_threshold: 2,
+            ..mail_options()
+        },
+    )
+    .await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    set_email(&db, common::USER1_ID, "user1@example.com").await;
+    set_email(&db, common::USER2_ID, "user2@example.com").await;
+
+    // User two only has a single code
+    let response = export_aegis(&app, &a2).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(mailer.outbox(), empty());
+
+    let response = export_aegis(&app, &a1).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let outbox = mailer.outbox();
+    assert_that!(outbox, len(eq(1)));
+    expect_that!(outbox[0].to, eq("user1@example.com"));
+    expect_that!(
+        outbox[0].body,
+        all!(
+            contains_substring("2 codes"),
+            contains_substring("Aegis vault")
+        )
+    );
+
+    // Unless the user turned the emails off
+    let response = common::edit_settings(&app, &a1, &json!({ "email_notifications": false })).await;
+    expect_that!(
+        common::convert_response(response).await["email_notifications"],
+        eq(&json!(false))
+    );
+    export_aegis(&app, &a1).await;
+    expect_that!(mailer.outbox(), len(eq(1)));
+}
+
+#[gtest]
+fn mail_options_are_validated() {
+    expect_that!(mail_options().validate(), ok(anything()));
+
+    let options = ServerOptions {
+        smtp_from: None,
+        ..mail_options()
+    };
+    expect_that!(options.validate(), err(anything()));
+
+    let options = ServerOptions {
+        smtp_url: Some("imap://localhost".into()),
+        ..mail_options()
+    };
+    expect_that!(options.validate(), err(anything()));
+
+    let options = ServerOptions {
+        smtp_from: Some("not an address".into()),
+        ..mail_options()
+    };
+    expect_that!(options.validate(), err(anything()));
+}
@@ -1,13 +1,28 @@
 use axum::{
     body::Body,
+    extract::connect_info::MockConnectInfo,
     http::{Method, Request, StatusCode},
 };
 use chrono::{DateTime, Utc};
 use googletest::prelude::*;
-use iceblink_sync::models;
+use iceblink_sync::{
+    cli::{self, TrailingSlash},
+    connections::{self, ConnectionLimiter},
+    models, ServeError, ServerOptions,
+};
 use serde_json::json;
 use sqlx::SqlitePool;
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    io::Write,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
 use tower::ServiceExt;
 
 pub mod common;
@@ -38,6 +53,20 @@ async fn api_metadata(db: SqlitePool) {
             "authorize": "N/A",
             "client_id": "N/A",
             "redirect_uri": "N/A",
+            "providers": [{
+                "name": "default",
+                "authorize": "N/A",
+                "client_id": "N/A",
+                "redirect_uri": "N/A",
+            }],
+            "code_challenge_methods_supported": ["S256"],
+            "local_auth": false,
+            "registration": "open",
+            "quota": {
+                "max_codes": null,
+                "max_icon_bytes": null,
+            },
+            "email_notifications": false,
         }))
     );
 
@@ -47,6 +76,50 @@ async fn api_metadata(db: SqlitePool) {
     );
 }
 
+async fn get_metadata(app: &axum::Router, etag: Option<&str>) -> axum::response::Response {
+    let mut request = Request::builder().method(Method::GET).uri("/v1/");
+    if let Some(etag) = etag {
+        request = request.header("If-None-Match", etag);
+    }
+
+    app.clone()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+#[sqlx::test]
+#[gtest]
+async fn api_metadata_not_modified(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+
+    let response = get_metadata(&app, None).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let etag = response.headers()["ETag"].to_str().unwrap().to_string();
+
+    let response = get_metadata(&app, Some(&etag)).await;
+    assert_that!(response.status(), eq(StatusCode::NOT_MODIFIED));
+    expect_that!(response.headers()["ETag"], eq(&etag));
+    expect_that!(common::convert_response_str(response).await, eq(""));
+
+    // Changing a surfaced setting busts the cache
+    let reconfigured = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            redirect_uri: "https://iceblink.example/callback".into(),
+            ..common::testing_options()
+        },
+    )
+    .await;
+    let response = get_metadata(&reconfigured, Some(&etag)).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(response.headers()["ETag"], not(eq(&etag)));
+    expect_that!(
+        common::convert_response(response).await["redirect_uri"],
+        eq(&json!("https://iceblink.example/callback"))
+    );
+}
+
 #[sqlx::test]
 #[gtest]
 async fn landing_page(db: SqlitePool) {
@@ -74,6 +147,36 @@ async fn landing_page(db: SqlitePool) {
     );
 }
 
+#[sqlx::test]
+#[gtest]
+async fn landing_page_configured_cache_control(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            html_cache_control: cli::HtmlCacheControl::NoCache,
+            ..common::testing_options()
+        },
+    )
+    .await;
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(
+        response.headers().get("Cache-Control").unwrap(),
+        eq("no-cache")
+    );
+}
+
 #[sqlx::test]
 #[gtest]
 async fn security_policy_serves(db: SqlitePool) {
@@ -195,6 +298,47 @@ async fn openapi_spec(db: SqlitePool) {
     common::convert_response(response).await;
 }
 
+#[sqlx::test]
+#[gtest]
+async fn openapi_spec_not_modified(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/openapi.json")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(
+        response.headers().get("Cache-Control").unwrap(),
+        eq("public, max-age=86400")
+    );
+    let etag = response.headers().get("ETag").unwrap().clone();
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/openapi.json")
+                .header("If-None-Match", etag.clone())
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_that!(response.status(), eq(StatusCode::NOT_MODIFIED));
+    assert_that!(response.headers().get("ETag").unwrap(), eq(&etag));
+    assert_that!(common::convert_response_str(response).await, eq(""));
+}
+
 #[sqlx::test]
 #[gtest]
 async fn cors_headers(db: SqlitePool) {
@@ -235,6 +379,190 @@ async fn cors_headers(db: SqlitePool) {
     );
 }
 
+/// Serves the app on a random local port, limiting connections as [`iceblink_sync::serve`] does.
+async fn serve_limited(app: axum::Router, limiter: ConnectionLimiter) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(connections::serve(
+        listener,
+        app,
+        limiter,
+        std::future::pending(),
+    ));
+
+    address
+}
+
+/// Sends a request on an open connection, returning the head and start of the response.
+async fn get_on(connection: &mut TcpStream) -> String {
+    connection
+        .write_all(b"GET /v1/ HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = vec![0; 4096];
+    let read = connection.read(&mut response).await.unwrap();
+
+    String::from_utf8_lossy(&response[..read]).into_owned()
+}
+
+/// Waits for the server to notice connections opening or closing.
+async fn wait_for_active(limiter: &ConnectionLimiter, active: usize) {
+    for _ in 0..100 {
+        if limiter.active("127.0.0.1".parse().unwrap()) == active {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("The server never had {active} connections open");
+}
+
+#[sqlx::test]
+#[gtest]
+async fn connection_limit_refuses_excess(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let limiter = ConnectionLimiter::new(2, vec![]);
+    let address = serve_limited(app, limiter.clone()).await;
+
+    // Idle connections take their slots too
+    let mut first = TcpStream::connect(address).await.unwrap();
+    let mut second = TcpStream::connect(address).await.unwrap();
+    wait_for_active(&limiter, 2).await;
+
+    // Connections over the limit are closed right away, without sending anything
+    for _ in 0..3 {
+        let mut idle = TcpStream::connect(address).await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(5), idle.read(&mut [0; 1]))
+            .await
+            .expect("Connection over the limit stayed open");
+        assert!(matches!(closed, Ok(0) | Err(_)));
+    }
+    expect_that!(limiter.active("127.0.0.1".parse().unwrap()), eq(2));
+
+    expect_that!(get_on(&mut first).await, starts_with("HTTP/1.1 200"));
+    expect_that!(get_on(&mut second).await, starts_with("HTTP/1.1 200"));
+
+    // Closing a connection frees its slot, once the server notices
+    drop(first);
+    wait_for_active(&limiter, 1).await;
+    let mut third = TcpStream::connect(address).await.unwrap();
+    expect_that!(get_on(&mut third).await, starts_with("HTTP/1.1 200"));
+}
+
+#[sqlx::test]
+#[gtest]
+async fn connection_limit_exempts_trusted_proxies(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let limiter = ConnectionLimiter::new(1, vec!["127.0.0.1".parse().unwrap()]);
+    let address = serve_limited(app, limiter).await;
+
+    let mut connections = vec![];
+    for _ in 0..3 {
+        connections.push(TcpStream::connect(address).await.unwrap());
+    }
+    for connection in &mut connections {
+        expect_that!(get_on(connection).await, starts_with("HTTP/1.1 200"));
+    }
+}
+
+async fn get_from(app: &axum::Router, uri: &str) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[sqlx::test]
+#[gtest]
+async fn request_limit_per_address(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            requests_per_minute: 2,
+            ..common::testing_options()
+        },
+    )
+    .await
+    .layer(MockConnectInfo(SocketAddr::from(([192, 0, 2, 1], 4000))));
+
+    for remaining in ["1", "0"] {
+        let response = get_from(&app, "/v1/").await;
+        assert_that!(response.status(), eq(StatusCode::OK));
+        expect_that!(response.headers()["X-RateLimit-Limit"], eq("2"));
+        expect_that!(response.headers()["X-RateLimit-Remaining"], eq(remaining));
+    }
+
+    let response = get_from(&app, "/v1/").await;
+    assert_that!(response.status(), eq(StatusCode::TOO_MANY_REQUESTS));
+    // A token comes back every 30 seconds
+    let retry_after: u64 = response.headers()["Retry-After"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    expect_that!(retry_after, all!(gt(0), le(30)));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("RateLimited"))
+    );
+}
+
+#[sqlx::test]
+#[gtest]
+async fn login_limit_is_stricter(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            requests_per_minute: 100,
+            login_requests_per_minute: 1,
+            ..common::testing_options()
+        },
+    )
+    .await
+    .layer(MockConnectInfo(SocketAddr::from(([192, 0, 2, 1], 4000))));
+
+    let response = get_from(&app, "/v1/oauth/authorize").await;
+    assert_that!(response.status(), eq(StatusCode::SEE_OTHER));
+    expect_that!(response.headers()["X-RateLimit-Limit"], eq("1"));
+
+    let response = get_from(&app, "/v1/oauth/authorize").await;
+    expect_that!(response.status(), eq(StatusCode::TOO_MANY_REQUESTS));
+    let response = get_from(&app, "/v1/").await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn write_limit_per_user(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            write_requests_per_minute: 1,
+            ..common::testing_options()
+        },
+    )
+    .await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    let code = json!({ "content": "JBSWY3DPEHPK3PXP", "display_name": "GitHub" });
+
+    let response = common::add_code(&app, &a1, &code).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let response = common::add_code(&app, &a1, &code).await;
+    assert_that!(response.status(), eq(StatusCode::TOO_MANY_REQUESTS));
+    expect_that!(response.headers().get("Retry-After"), some(anything()));
+
+    // Reading is still fine, and other users have limits of their own
+    let response = common::list_codes(&app, &a1).await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+    let response = common::add_code(&app, &a2, &code).await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+}
+
 #[sqlx::test]
 #[gtest]
 async fn export_prometheus_metrics(db: SqlitePool) {
@@ -292,6 +620,134 @@ async fn export_prometheus_metrics(db: SqlitePool) {
     assert_that!(has_found_line, is_true());
 }
 
+#[tokio::test]
+#[gtest]
+async fn bind_port_in_use() {
+    let occupied = tokio::net::TcpListener::bind("0.0.0.0:0").await.unwrap();
+    let port = occupied.local_addr().unwrap().port() as u32;
+
+    let err = iceblink_sync::bind(port).await.unwrap_err();
+    assert!(matches!(err, ServeError::Bind(_)));
+    assert_that!(err.exit_code(), eq(4));
+    assert_that!(err.to_string(), starts_with("Unable to bind HTTP listener"));
+}
+
+async fn get_with_token(app: &axum::Router, uri: &str, token: &str) -> axum::response::Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn trailing_slash_rewrite(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let canonical = get_with_token(&app, "/v1/code", a1.as_str()).await;
+    let slashed = get_with_token(&app, "/v1/code/", a1.as_str()).await;
+    assert_that!(slashed.status(), eq(StatusCode::OK));
+    assert_that!(
+        common::convert_response(slashed).await,
+        eq(&common::convert_response(canonical).await)
+    );
+
+    // The instance metadata lives at /v1/ itself
+    let metadata = get_with_token(&app, "/v1/", a1.as_str()).await;
+    assert_that!(metadata.status(), eq(StatusCode::OK));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn trailing_slash_redirect(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            trailing_slash: TrailingSlash::Redirect,
+            ..common::testing_options()
+        },
+    )
+    .await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = get_with_token(&app, "/v1/codes/recovery-sheet/?print=1", a1.as_str()).await;
+    assert_that!(response.status(), eq(StatusCode::PERMANENT_REDIRECT));
+    assert_that!(
+        response.headers().get("Location").unwrap(),
+        eq("/v1/codes/recovery-sheet?print=1")
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn trailing_slash_off(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            trailing_slash: TrailingSlash::Off,
+            ..common::testing_options()
+        },
+    )
+    .await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = get_with_token(&app, "/v1/code/", a1.as_str()).await;
+    assert_that!(response.status(), eq(StatusCode::NOT_FOUND));
+}
+
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[sqlx::test]
+#[gtest]
+async fn slow_query_is_logged(db: SqlitePool) {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let _subscriber = tracing::subscriber::set_default(
+        tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish(),
+    );
+    models::set_slow_query_threshold(Duration::from_millis(10));
+
+    // Counting to a few million takes SQLite well above the threshold
+    models::timed(
+        "test.slow_query",
+        sqlx::query("WITH RECURSIVE counter(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM counter WHERE x < 3000000) SELECT COUNT(*) FROM counter")
+            .fetch_one(&db),
+    )
+    .await
+    .unwrap();
+    models::timed("test.fast_query", sqlx::query("SELECT 1").fetch_one(&db))
+        .await
+        .unwrap();
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    expect_that!(logs, contains_substring("Slow query"));
+    expect_that!(logs, contains_substring("test.slow_query"));
+    expect_that!(logs, not(contains_substring("test.fast_query")));
+}
+
 #[test]
 fn common_code_is_expected_user1_code2() {
     assert_that!(
@@ -304,6 +760,20 @@ fn common_code_is_expected_user1_code2() {
                 display_name: "google.com".into(),
                 icon_url: None,
                 website_url: Some("google.com".into()),
+                expires_at: None,
+                sort_index: 1,
+                issuer: None,
+                account: None,
+                kind: "totp".into(),
+                digits: 6,
+                period: 30,
+                algorithm: "SHA1".into(),
+                favorite: false,
+                folder_id: None,
+                created_at: 0,
+                updated_at: 0,
+                revision: 0,
+                deleted_at: None,
             }
         ),
         is_true()
@@ -322,6 +792,20 @@ fn common_code_is_expected_user2_code1() {
                 display_name: "Dummy INC".into(),
                 icon_url: Some("https://dummy.com/favicon.ico".into()),
                 website_url: Some("dummy.com".into()),
+                expires_at: None,
+                sort_index: 0,
+                issuer: None,
+                account: None,
+                kind: "totp".into(),
+                digits: 6,
+                period: 30,
+                algorithm: "SHA1".into(),
+                favorite: false,
+                folder_id: None,
+                created_at: 0,
+                updated_at: 0,
+                revision: 0,
+                deleted_at: None,
             }
         ),
         is_true()
@@ -340,8 +824,49 @@ fn common_code_is_expected_wrong_user() {
                 display_name: "Dummy INC".into(),
                 icon_url: Some("https://dummy.com/favicon.ico".into()),
                 website_url: Some("dummy.com".into()),
+                expires_at: None,
+                sort_index: 0,
+                issuer: None,
+                account: None,
+                kind: "totp".into(),
+                digits: 6,
+                period: 30,
+                algorithm: "SHA1".into(),
+                favorite: false,
+                folder_id: None,
+                created_at: 0,
+                updated_at: 0,
+                revision: 0,
+                deleted_at: None,
             }
         ),
         is_false()
     );
 }
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn responses_are_byte_identical(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    for uri in [
+        "/v1/".to_string(),
+        "/v1/code".to_string(),
+        format!("/v1/code/{}", common::USER1_CODE1_ID),
+        "/openapi.json".to_string(),
+    ] {
+        let first = common::convert_response_u8(get_with_token(&app, &uri, &a1).await).await;
+        let second = common::convert_response_u8(get_with_token(&app, &uri, &a1).await).await;
+        expect_that!(first, eq(&second), "{uri}");
+    }
+
+    // Codes are serialized through a map, whose keys are sorted
+    let response = get_with_token(&app, "/v1/code", &a1).await;
+    let body = common::convert_response_str(response).await;
+    let positions: Vec<usize> = ["\"content\"", "\"display_name\"", "\"id\"", "\"owner_id\""]
+        .iter()
+        .map(|key| body.find(key).unwrap())
+        .collect();
+    expect_that!(positions.is_sorted(), is_true());
+}
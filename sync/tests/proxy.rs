@@ -0,0 +1,138 @@
+use axum::{
+    http::{StatusCode, Uri},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use googletest::prelude::*;
+use iceblink_sync::{
+    auth::OpenId,
+    icons::{self, IconStore, IconStoreError},
+    utils, ServerOptions,
+};
+use serde_json::json;
+use sqlx::SqlitePool;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+pub mod common;
+
+/// A public address, which is never connected to, as the proxy answers in its place.
+const UPSTREAM: &str = "http://1.1.1.1";
+
+/// Forward proxy answering every request itself, recording which URLs it was asked for.
+async fn mock_proxy() -> (String, Arc<Mutex<Vec<String>>>) {
+    let requested = Arc::new(Mutex::new(vec![]));
+    let proxy = common::mock_upstream(Router::new().fallback({
+        let requested = requested.clone();
+        move |uri: Uri| async move {
+            requested.lock().unwrap().push(uri.to_string());
+            match uri.path() {
+                "/token" => Json(json!({ "access_token": "proxied" })).into_response(),
+                "/userinfo" => Json(json!({
+                    "sub": "upstream-proxied",
+                    "preferred_username": "proxied",
+                    "name": null,
+                    "picture": ""
+                }))
+                .into_response(),
+                path => path.to_string().into_response(),
+            }
+        }
+    }))
+    .await;
+
+    (proxy, requested)
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn icons_fetched_through_proxy(db: SqlitePool) {
+    let (proxy, requested) = mock_proxy().await;
+    let store = IconStore::new()
+        .with_upstream(UPSTREAM.into())
+        .with_proxy(utils::outbound_proxy(&proxy).unwrap());
+    store.init().await.unwrap();
+
+    let report = icons::refresh_icons(
+        &db,
+        &store,
+        Some(common::USER2_ID.into()),
+        Duration::from_millis(1),
+    )
+    .await
+    .unwrap();
+
+    assert_that!(report.refreshed, elements_are![eq("dummy.com")]);
+    expect_that!(
+        store
+            .get(&utils::content_hash(b"/dummy.com/favicon.ico"))
+            .await,
+        some(eq(&b"/dummy.com/favicon.ico".to_vec()))
+    );
+    expect_that!(
+        *requested.lock().unwrap(),
+        elements_are![eq(&format!("{UPSTREAM}/dummy.com/favicon.ico"))]
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn proxy_respects_ssrf_guard(db: SqlitePool) {
+    let (proxy, requested) = mock_proxy().await;
+    let upstream = common::mock_upstream(Router::new().route("/", get(|| async { "" }))).await;
+    let store = IconStore::new()
+        .with_upstream(upstream)
+        .with_proxy(utils::outbound_proxy(&proxy).unwrap());
+    store.init().await.unwrap();
+
+    let report = icons::refresh_icons(&db, &store, None, Duration::from_millis(1))
+        .await
+        .unwrap();
+
+    assert_that!(report.refreshed, empty());
+    assert!(report
+        .failed
+        .iter()
+        .all(|(_, err)| matches!(err, IconStoreError::BlockedHost)));
+    expect_that!(*requested.lock().unwrap(), empty());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn oauth_through_proxy(db: SqlitePool) {
+    let (proxy, requested) = mock_proxy().await;
+    let app = common::testing_setup_with_openid(
+        &db,
+        ServerOptions {
+            http_proxy: Some(proxy),
+            ..common::testing_options()
+        },
+        OpenId {
+            name: "default".into(),
+            issuer: UPSTREAM.into(),
+            authorization: "N/A".into(),
+            client_id: "N/A".into(),
+            client_secret: "N/A".into(),
+            token: format!("{UPSTREAM}/token"),
+            userinfo: format!("{UPSTREAM}/userinfo"),
+            proxy: None,
+            jwks_uri: None,
+            keys: Default::default(),
+        },
+    )
+    .await;
+
+    let response = common::oauth_login(&app, None, "code=abc").await;
+
+    expect_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        *requested.lock().unwrap(),
+        elements_are![
+            eq(&format!("{UPSTREAM}/token")),
+            eq(&format!("{UPSTREAM}/userinfo"))
+        ]
+    );
+}
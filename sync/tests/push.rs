@@ -0,0 +1,177 @@
+use axum::{
+    body::Body,
+    http::{Method, Request, StatusCode},
+    Router,
+};
+use futures_util::StreamExt;
+use googletest::prelude::*;
+use serde_json::json;
+use sqlx::SqlitePool;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{self, client::IntoClientRequest, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+use tower::ServiceExt;
+
+pub mod common;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn connect(
+    base: &str,
+    token: &str,
+    origin: Option<&str>,
+) -> std::result::Result<Socket, tungstenite::Error> {
+    let mut request = format!("{}/v1/ws", base.replacen("http", "ws", 1))
+        .into_client_request()
+        .unwrap();
+    request
+        .headers_mut()
+        .insert("Authorization", format!("Bearer {token}").parse().unwrap());
+    if let Some(origin) = origin {
+        request
+            .headers_mut()
+            .insert("Origin", origin.parse().unwrap());
+    }
+
+    connect_async(request).await.map(|(socket, _)| socket)
+}
+
+async fn next_event(socket: &mut Socket) -> serde_json::Value {
+    let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+        .await
+        .expect("No event received")
+        .unwrap()
+        .unwrap();
+
+    match message {
+        Message::Text(text) => serde_json::from_str(&text).unwrap(),
+        message => panic!("Unexpected message {message:?}"),
+    }
+}
+
+async fn serve(app: &Router) -> String {
+    common::mock_upstream(app.clone()).await
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn websocket_receives_own_changes(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    let mut socket = connect(&serve(&app).await, &a1, None).await.unwrap();
+
+    // Changes of other users aren't sent
+    common::delete_code(&app, &a2, common::USER2_CODE1_ID).await;
+    common::delete_code(&app, &a1, common::USER1_CODE1_ID).await;
+
+    expect_that!(
+        next_event(&mut socket).await,
+        eq(&json!({
+            "kind": "code.deleted",
+            "code_id": common::USER1_CODE1_ID,
+            "client_id": null
+        }))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn websocket_closes_when_account_is_deleted(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let mut socket = connect(&serve(&app).await, &a1, None).await.unwrap();
+
+    common::delete_account(&app, &a1).await;
+
+    expect_that!(
+        next_event(&mut socket).await["kind"],
+        eq(&json!("user.deleted"))
+    );
+    let message = socket.next().await.unwrap().unwrap();
+    expect_that!(matches!(message, Message::Close(_)), is_true());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn websocket_rejects_other_origins(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let base = serve(&app).await;
+
+    let result = connect(&base, &a1, Some("https://evil.example")).await;
+    let Err(tungstenite::Error::Http(response)) = result else {
+        panic!("Connected from another origin");
+    };
+    expect_that!(response.status().as_u16(), eq(403));
+
+    // The frontend itself may connect
+    let frontfacing = common::testing_options().frontfacing;
+    expect_that!(
+        connect(&base, &a1, Some(&frontfacing)).await.is_ok(),
+        is_true()
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn websocket_requires_authentication(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let base = serve(&app).await;
+
+    let result = connect(&base, "garbage", None).await;
+    let Err(tungstenite::Error::Http(response)) = result else {
+        panic!("Connected without authentication");
+    };
+    expect_that!(response.status().as_u16(), eq(401));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn event_stream_receives_own_changes(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/v1/events")
+                .header("Authorization", format!("Bearer {a1}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(response.headers()["Content-Type"], eq("text/event-stream"));
+
+    common::delete_code(&app, &a2, common::USER2_CODE1_ID).await;
+    common::delete_code(&app, &a1, common::USER1_CODE1_ID).await;
+    common::delete_account(&app, &a1).await;
+
+    // The stream ends once the account is deleted
+    let mut body = response.into_body().into_data_stream();
+    let mut received = String::new();
+    while let Some(chunk) = tokio::time::timeout(Duration::from_secs(5), body.next())
+        .await
+        .expect("The stream didn't end")
+    {
+        received.push_str(std::str::from_utf8(&chunk.unwrap()).unwrap());
+    }
+
+    expect_that!(
+        received,
+        eq(&format!(
+            "event: code.deleted\n\
+             data: {{\"kind\":\"code.deleted\",\"code_id\":\"{}\",\"client_id\":null}}\n\n\
+             event: user.deleted\n\
+             data: {{\"kind\":\"user.deleted\",\"code_id\":null,\"client_id\":null}}\n\n",
+            common::USER1_CODE1_ID
+        ))
+    );
+}
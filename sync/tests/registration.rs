@@ -0,0 +1,1137 @@
+use axum::{
+    body::Body,
+    extract::connect_info::MockConnectInfo,
+    http::{HeaderMap, Method, Request, StatusCode},
+    response::Response,
+    routing::{get, post},
+    Form, Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use googletest::prelude::*;
+use iceblink_sync::{
+    auth::{self, OpenId, OpenIdKeys, OpenIdProviders},
+    cli::RegistrationPolicy,
+    jwt::{Jwk, JwkSet, JwtKeys},
+    models::user::User,
+    ServerOptions,
+};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde_json::json;
+use sqlx::SqlitePool;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tower::ServiceExt;
+
+pub mod common;
+
+/// OAuth server whose users are named after the code they log in with, next to a captcha service
+/// which only accepts the token `solved`. Codes starting with `pkce-` are followed by the code
+/// challenge they were requested with, like a public client would. Codes starting with `nonce-`
+/// come with an ID token holding the nonce after it.
+async fn mock_services() -> (OpenId, String) {
+    let base = common::mock_upstream(
+        Router::new()
+            .route(
+                "/token",
+                post(|Json(body): Json<serde_json::Value>| async move {
+                    if let Some(challenge) = body["code"].as_str().unwrap().strip_prefix("pkce-") {
+                        let verifier = body["code_verifier"].as_str();
+                        if verifier.map(auth::pkce_challenge).as_deref() != Some(challenge) {
+                            return Err(StatusCode::BAD_REQUEST);
+                        }
+                    }
+                    if let Some(nonce) = body["code"].as_str().unwrap().strip_prefix("nonce-") {
+                        let claims = URL_SAFE_NO_PAD.encode(json!({ "nonce": nonce }).to_string());
+                        return Ok(Json(json!({
+                            "access_token": body["code"],
+                            "id_token": format!("e30.{claims}.signature")
+                        })));
+                    }
+                    Ok(Json(json!({ "access_token": body["code"] })))
+                }),
+            )
+            .route(
+                "/userinfo",
+                get(|headers: HeaderMap| async move {
+                    let token = headers["authorization"]
+                        .to_str()
+                        .unwrap()
+                        .trim_start_matches("Bearer ")
+                        .to_string();
+                    Json(json!({
+                        "sub": format!("upstream-{token}"),
+                        "preferred_username": token,
+                        "name": null,
+                        "picture": ""
+                    }))
+                }),
+            )
+            .route(
+                "/siteverify",
+                post(|Form(form): Form<HashMap<String, String>>| async move {
+                    Json(json!({
+                        "success": form["secret"] == "captcha secret" && form["response"] == "solved"
+                    }))
+                }),
+            ),
+    )
+    .await;
+
+    let openid = OpenId {
+        name: "default".into(),
+        issuer: base.clone(),
+        authorization: format!("{base}/authorize"),
+        client_id: "N/A".into(),
+        client_secret: "N/A".into(),
+        token: format!("{base}/token"),
+        userinfo: format!("{base}/userinfo"),
+        proxy: None,
+        jwks_uri: None,
+        keys: Default::default(),
+    };
+    (openid, format!("{base}/siteverify"))
+}
+
+async fn login(app: &Router, code: &str, captcha: Option<&str>) -> Response {
+    match captcha {
+        Some(captcha) => {
+            common::oauth_login(app, None, &format!("code={code}&captcha={captcha}")).await
+        }
+        None => common::oauth_login(app, None, &format!("code={code}")).await,
+    }
+}
+
+async fn login_with_provider(app: &Router, provider: &str, code: &str) -> Response {
+    common::oauth_login(app, Some(provider), &format!("code={code}")).await
+}
+
+async fn get(app: &Router, uri: String) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+async fn user_exists(db: &SqlitePool, username: &str) -> bool {
+    User::get_by_username(db, username.into())
+        .await
+        .unwrap()
+        .is_some()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn registration_requires_captcha(db: SqlitePool) {
+    let (openid, siteverify) = mock_services().await;
+    let app = common::testing_setup_with_openid(
+        &db,
+        ServerOptions {
+            captcha_verify_url: Some(siteverify),
+            captcha_secret: Some("captcha secret".into()),
+            ..common::testing_options()
+        },
+        openid,
+    )
+    .await;
+
+    for captcha in [None, Some("wrong")] {
+        let response = login(&app, "newcomer", captcha).await;
+        assert_that!(response.status(), eq(StatusCode::FORBIDDEN));
+        expect_that!(
+            common::convert_response(response).await["errorKind"],
+            eq(&json!("CaptchaFailed"))
+        );
+    }
+    expect_that!(user_exists(&db, "newcomer").await, is_false());
+
+    let response = login(&app, "newcomer", Some("solved")).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(user_exists(&db, "newcomer").await, is_true());
+
+    // Existing users log in without one
+    let response = login(&app, "newcomer", None).await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::convert_response(response).await["refresh_token"].as_str(),
+        some(starts_with("ibr_"))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn registration_rate_limit(db: SqlitePool) {
+    let (openid, _) = mock_services().await;
+    let app = common::testing_setup_with_openid(
+        &db,
+        ServerOptions {
+            registrations_per_hour: 1,
+            ..common::testing_options()
+        },
+        openid,
+    )
+    .await;
+    let from = |ip: [u8; 4]| {
+        app.clone()
+            .layer(MockConnectInfo(SocketAddr::from((ip, 4000))))
+    };
+
+    let response = login(&from([192, 0, 2, 1]), "first", None).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let response = login(&from([192, 0, 2, 1]), "second", None).await;
+    assert_that!(response.status(), eq(StatusCode::TOO_MANY_REQUESTS));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("TooManyRegistrations"))
+    );
+    expect_that!(user_exists(&db, "second").await, is_false());
+
+    // Logging in isn't limited, nor are other addresses
+    let response = login(&from([192, 0, 2, 1]), "first", None).await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+    let response = login(&from([192, 0, 2, 2]), "second", None).await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+}
+
+async fn create_invite(app: &Router, token: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v1/admin/invites")
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"expires_in_days":1}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+async fn get_admin(app: &Router, token: &str, uri: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn invite_only_registration(db: SqlitePool) {
+    let (openid, _) = mock_services().await;
+    let app = common::testing_setup_with_openid(
+        &db,
+        ServerOptions {
+            registration: RegistrationPolicy::Invite,
+            ..common::testing_options()
+        },
+        openid,
+    )
+    .await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    sqlx::query("UPDATE users SET is_admin = TRUE WHERE id = ?")
+        .bind(common::USER1_ID)
+        .execute(&db)
+        .await
+        .unwrap();
+
+    // Only admins mint invites
+    let response = create_invite(&app, &a2).await;
+    expect_that!(response.status(), eq(StatusCode::FORBIDDEN));
+    let response = create_invite(&app, &a1).await;
+    assert_that!(response.status(), eq(StatusCode::CREATED));
+    let invite = common::convert_response(response).await;
+    let token = invite["token"].as_str().unwrap();
+
+    for (query, kind) in [
+        ("code=newcomer".to_string(), "InviteRequired"),
+        ("code=newcomer&invite=wrong".to_string(), "InvalidInvite"),
+    ] {
+        let response = common::oauth_login(&app, None, &query).await;
+        assert_that!(response.status(), eq(StatusCode::FORBIDDEN));
+        expect_that!(
+            common::convert_response(response).await["errorKind"],
+            eq(&json!(kind))
+        );
+    }
+    expect_that!(user_exists(&db, "newcomer").await, is_false());
+
+    let query = format!("code=newcomer&invite={token}");
+    let response = common::oauth_login(&app, None, &query).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(user_exists(&db, "newcomer").await, is_true());
+
+    // Invites are single use, but existing users log in without one
+    let query = format!("code=latecomer&invite={token}");
+    let response = common::oauth_login(&app, None, &query).await;
+    expect_that!(response.status(), eq(StatusCode::FORBIDDEN));
+    expect_that!(user_exists(&db, "latecomer").await, is_false());
+    let response = login(&app, "newcomer", None).await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+
+    let newcomer = User::get_by_username(&db, "newcomer".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let response = get_admin(&app, &a1, "/v1/admin/invites").await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let invites = common::convert_response(response).await;
+    expect_that!(invites[0]["id"], eq(&invite["id"]));
+    expect_that!(invites[0]["used_by"], eq(&json!(newcomer.id)));
+    expect_that!(invites[0].get("token_hash"), none());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn closed_registration(db: SqlitePool) {
+    let (openid, _) = mock_services().await;
+    let open =
+        common::testing_setup_with_openid(&db, common::testing_options(), openid.clone()).await;
+    let response = login(&open, "member", None).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    let closed = common::testing_setup_with_openid(
+        &db,
+        ServerOptions {
+            registration: RegistrationPolicy::Closed,
+            ..common::testing_options()
+        },
+        openid,
+    )
+    .await;
+    let response = login(&closed, "stranger", None).await;
+    assert_that!(response.status(), eq(StatusCode::FORBIDDEN));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("RegistrationClosed"))
+    );
+    expect_that!(user_exists(&db, "stranger").await, is_false());
+
+    let response = login(&closed, "member", None).await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn multiple_openid_providers(db: SqlitePool) {
+    let (openid, _) = mock_services().await;
+    let authentik = OpenId {
+        name: "authentik".into(),
+        issuer: format!("{}/authentik", openid.issuer),
+        ..openid.clone()
+    };
+    let app = common::testing_setup_with_openid(
+        &db,
+        common::testing_options(),
+        OpenIdProviders::new(openid.clone(), vec![authentik.clone()]),
+    )
+    .await;
+
+    let metadata = common::convert_response(get(&app, "/v1/".into()).await).await;
+    expect_that!(
+        metadata["providers"][1]["redirect_uri"],
+        eq(&json!("N/A?provider=authentik"))
+    );
+
+    // Users from before identities were keyed by issuer belong to the default provider
+    sqlx::query("INSERT INTO users (id, username, display_name, avatar_url, upstream_userid) VALUES ('legacyUser000000', 'legacy', 'Legacy', '', 'upstream-legacy')")
+        .execute(&db)
+        .await
+        .unwrap();
+
+    let response = login(&app, "legacy", None).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let user = User::get_by_upstream_id(&db, &openid.issuer, "upstream-legacy")
+        .await
+        .unwrap();
+    expect_that!(user.map(|user| user.id), some(eq("legacyUser000000")));
+
+    // The same subject at another provider is someone else
+    let response = login_with_provider(&app, "authentik", "legacy").await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let user = User::get_by_upstream_id(&db, &authentik.issuer, "upstream-legacy")
+        .await
+        .unwrap();
+    expect_that!(user.map(|user| user.id), some(not(eq("legacyUser000000"))));
+
+    let response = login_with_provider(&app, "unknown", "legacy").await;
+    assert_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("UnknownOpenIdProvider"))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn pkce_login(db: SqlitePool) {
+    let (openid, _) = mock_services().await;
+    let app = common::testing_setup_with_openid(&db, common::testing_options(), openid).await;
+
+    // Example from RFC 7636
+    let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    let challenge = auth::pkce_challenge(verifier);
+    assert_that!(challenge, eq("E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGEm0-cM"));
+
+    let code = format!("pkce-{challenge}");
+    let response =
+        common::oauth_login(&app, None, &format!("code={code}&code_verifier={verifier}")).await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+
+    // Without the verifier, or with another one, the code is worthless
+    let response = common::oauth_login(&app, None, &format!("code={code}")).await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    let other = "M25iVXpKU3puUjFaYWg3T1NDTDQtcW1ROUY5YXlwalNoc0hhakxifmZHag";
+    let response =
+        common::oauth_login(&app, None, &format!("code={code}&code_verifier={other}")).await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+
+    let response =
+        common::oauth_login(&app, None, &format!("code={code}&code_verifier=short")).await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("BadRequest"))
+    );
+}
+
+async fn callback(app: &Router, query: &str, cookie: Option<&str>) -> Response {
+    let mut request = Request::builder()
+        .method(Method::GET)
+        .uri(format!("/v1/oauth?{query}"));
+    if let Some(cookie) = cookie {
+        request = request.header("Cookie", cookie);
+    }
+    app.clone()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+/// Nonce the provider is asked to put into the ID token of the login.
+fn login_nonce(response: &Response) -> String {
+    response.headers()["location"]
+        .to_str()
+        .unwrap()
+        .split(['?', '&'])
+        .find_map(|param| param.strip_prefix("nonce="))
+        .unwrap()
+        .to_string()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn authorize_redirects_to_provider(db: SqlitePool) {
+    let (openid, _) = mock_services().await;
+    let authorization = openid.authorization.clone();
+    let app = common::testing_setup_with_openid(&db, common::testing_options(), openid).await;
+
+    let challenge = auth::pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk");
+    let response = common::authorize(&app, &format!("code_challenge={challenge}")).await;
+    assert_that!(response.status(), eq(StatusCode::SEE_OTHER));
+    let (state, cookie) = common::login_state(&response);
+    expect_that!(cookie, starts_with("iceblink_oauth_state="));
+
+    let location = response.headers()["location"].to_str().unwrap();
+    expect_that!(location, starts_with(format!("{authorization}?")));
+    expect_that!(location, contains_substring("response_type=code"));
+    expect_that!(location, contains_substring(format!("state={state}")));
+    expect_that!(location, contains_substring("nonce="));
+    expect_that!(
+        location,
+        contains_substring(format!("code_challenge={challenge}"))
+    );
+    expect_that!(location, contains_substring("code_challenge_method=S256"));
+
+    let response = common::authorize(&app, "code_challenge=short").await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    let response = common::authorize(&app, "provider=unknown").await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn login_requires_state(db: SqlitePool) {
+    let (openid, _) = mock_services().await;
+    let app = common::testing_setup_with_openid(&db, common::testing_options(), openid).await;
+
+    let response = callback(&app, "code=newcomer", None).await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+
+    // Without the cookie of the browser which started the login, or with the one of another login
+    let (state, cookie) = common::login_state(&common::authorize(&app, "").await);
+    let (_, other_cookie) = common::login_state(&common::authorize(&app, "").await);
+    for cookie in [None, Some(other_cookie.as_str())] {
+        let response = callback(&app, &format!("code=newcomer&state={state}"), cookie).await;
+        assert_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+        expect_that!(
+            common::convert_response(response).await["errorKind"],
+            eq(&json!("InvalidOauthState"))
+        );
+    }
+
+    // A made up state, even with a matching cookie
+    let response = callback(
+        &app,
+        "code=newcomer&state=madeup",
+        Some("iceblink_oauth_state=madeup"),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+
+    let response = callback(&app, &format!("code=newcomer&state={state}"), Some(&cookie)).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        response
+            .headers()
+            .get_all("set-cookie")
+            .iter()
+            .any(|value| value
+                .to_str()
+                .unwrap()
+                .starts_with("iceblink_oauth_state=;")),
+        is_true()
+    );
+
+    // States only work once
+    let response = callback(&app, &format!("code=newcomer&state={state}"), Some(&cookie)).await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("InvalidOauthState"))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn native_app_login(db: SqlitePool) {
+    let (openid, _) = mock_services().await;
+    let app = common::testing_setup_with_openid(&db, common::testing_options(), openid).await;
+    let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    let challenge = auth::pkce_challenge(verifier);
+
+    for redirect_uri in [
+        "https%3A%2F%2Fevil.example%2Fcallback",
+        "http%3A%2F%2Flocalhost%3A49152%2Fcallback",
+        "other%3A%2F%2Fcallback",
+    ] {
+        let response = common::authorize(
+            &app,
+            &format!("redirect_uri={redirect_uri}&code_challenge={challenge}"),
+        )
+        .await;
+        expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    }
+    // Apps can't send the state cookie, so they have to use PKCE
+    let response = common::authorize(&app, "redirect_uri=iceblink%3A%2F%2Fcallback").await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+
+    let response = common::authorize(
+        &app,
+        &format!(
+            "redirect_uri=http%3A%2F%2F127.0.0.1%3A49152%2Fcallback&code_challenge={challenge}"
+        ),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::SEE_OTHER));
+    expect_that!(
+        response.headers()["location"].to_str().unwrap(),
+        contains_substring("redirect_uri=http%3A%2F%2F127%2E0%2E0%2E1%3A49152%2Fcallback")
+    );
+
+    let response = common::authorize(
+        &app,
+        &format!("redirect_uri=iceblink%3A%2F%2Fcallback&code_challenge={challenge}"),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::SEE_OTHER));
+    expect_that!(
+        response.headers()["location"].to_str().unwrap(),
+        contains_substring("redirect_uri=iceblink%3A%2F%2Fcallback")
+    );
+    let (state, _) = common::login_state(&response);
+
+    // The code verifier takes the place of the cookie
+    let response = callback(&app, &format!("code=pkce-{challenge}&state={state}"), None).await;
+    assert_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("InvalidOauthState"))
+    );
+
+    let response = callback(
+        &app,
+        &format!("code=pkce-{challenge}&state={state}&code_verifier={verifier}"),
+        None,
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        user_exists(&db, &format!("pkce-{challenge}")).await,
+        is_true()
+    );
+
+    let options = ServerOptions {
+        native_redirect_schemes: vec!["https".into()],
+        ..common::testing_options()
+    };
+    expect_that!(options.validate(), err(anything()));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn login_checks_id_token_nonce(db: SqlitePool) {
+    let (openid, _) = mock_services().await;
+    let app = common::testing_setup_with_openid(&db, common::testing_options(), openid).await;
+
+    let response = common::authorize(&app, "").await;
+    let (state, cookie) = common::login_state(&response);
+    let nonce = login_nonce(&response);
+    let response = callback(
+        &app,
+        &format!("code=nonce-{nonce}&state={state}"),
+        Some(&cookie),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+
+    let response = common::oauth_login(&app, None, "code=nonce-replayed").await;
+    expect_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("InvalidIdToken"))
+    );
+    expect_that!(user_exists(&db, "nonce-replayed").await, is_false());
+}
+
+/// OpenID provider handing out the authorization code as ID token, and publishing the keys in
+/// `published` as its JWKS. Counts how often the JWKS is fetched in `fetches`.
+async fn mock_signing_provider(
+    published: Arc<Mutex<Vec<Jwk>>>,
+    fetches: Arc<AtomicUsize>,
+) -> OpenId {
+    let base = common::mock_upstream(
+        Router::new()
+            .route(
+                "/token",
+                post(|Json(body): Json<serde_json::Value>| async move {
+                    Json(json!({ "access_token": "signed", "id_token": body["code"] }))
+                }),
+            )
+            .route(
+                "/userinfo",
+                get(|| async {
+                    Json(json!({
+                        "sub": "upstream-signed",
+                        "preferred_username": "signed",
+                        "name": null,
+                        "picture": ""
+                    }))
+                }),
+            )
+            .route(
+                "/jwks",
+                get(move || async move {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    Json(JwkSet {
+                        keys: published.lock().unwrap().clone(),
+                    })
+                }),
+            ),
+    )
+    .await;
+
+    OpenId {
+        name: "default".into(),
+        issuer: base.clone(),
+        authorization: format!("{base}/authorize"),
+        client_id: "iceblink".into(),
+        client_secret: "N/A".into(),
+        token: format!("{base}/token"),
+        userinfo: format!("{base}/userinfo"),
+        proxy: None,
+        jwks_uri: Some(format!("{base}/jwks")),
+        keys: Default::default(),
+    }
+}
+
+async fn login_with_id_token(
+    app: &Router,
+    sign: impl Fn(&serde_json::Value) -> String,
+    mut claims: serde_json::Value,
+) -> Response {
+    let response = common::authorize(app, "").await;
+    let (state, cookie) = common::login_state(&response);
+    if claims.get("nonce").is_none() {
+        claims["nonce"] = json!(login_nonce(&response));
+    }
+
+    let id_token = sign(&claims);
+    callback(
+        app,
+        &format!("code={id_token}&state={state}"),
+        Some(&cookie),
+    )
+    .await
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn login_verifies_id_token_signature(db: SqlitePool) {
+    let rs256 = JwtKeys::rs256(include_str!("fixtures/jwt_rs256.pem")).unwrap();
+    let es256 = JwtKeys::es256(include_str!("fixtures/jwt_es256.pem")).unwrap();
+    let published = Arc::new(Mutex::new(rs256.jwks().keys));
+    let mut openid = mock_signing_provider(published.clone(), Default::default()).await;
+    openid.keys = OpenIdKeys::with_refresh_interval(Duration::from_millis(200));
+    let issuer = openid.issuer.clone();
+    let app = common::testing_setup_with_openid(&db, common::testing_options(), openid).await;
+
+    let exp = (chrono::Utc::now() + chrono::Duration::minutes(5)).timestamp();
+    let claims = json!({ "iss": issuer, "aud": "iceblink", "exp": exp, "sub": "upstream-signed" });
+    let with_rs256 = |claims: &serde_json::Value| rs256.encode(claims);
+    let with_es256 = |claims: &serde_json::Value| es256.encode(claims);
+    let response = login_with_id_token(&app, with_rs256, claims.clone()).await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+
+    let mut rejected = vec![];
+    for (key, value) in [
+        ("iss", json!("https://attacker.example")),
+        ("aud", json!("another-client")),
+        ("exp", json!(exp - 3600)),
+        ("nonce", json!("replayed")),
+    ] {
+        let mut claims = claims.clone();
+        claims[key] = value;
+        rejected.push(login_with_id_token(&app, with_rs256, claims).await);
+    }
+    // Signed with a key the provider doesn't publish
+    rejected.push(login_with_id_token(&app, with_es256, claims.clone()).await);
+    // Signed with the published public key as HMAC secret
+    let jwk = rs256.jwks().keys[0].clone();
+    let with_public_key = |claims: &serde_json::Value| {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(jwk.kid.clone());
+        let secret = EncodingKey::from_secret(jwk.n.as_ref().unwrap().as_bytes());
+        jsonwebtoken::encode(&header, claims, &secret).unwrap()
+    };
+    rejected.push(login_with_id_token(&app, with_public_key, claims.clone()).await);
+
+    for response in rejected {
+        expect_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+        expect_that!(
+            common::convert_response(response).await["errorKind"],
+            eq(&json!("InvalidIdToken"))
+        );
+    }
+
+    // Keys the provider rotated to are fetched when first used, once the keys weren't fetched
+    // within the refresh interval
+    published.lock().unwrap().extend(es256.jwks().keys);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let response = login_with_id_token(&app, with_es256, claims).await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+}
+
+async fn backchannel_logout(app: &Router, logout_token: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v1/oauth/backchannel-logout")
+                .header("Content-Type", "application/x-www-form-urlencoded")
+                .body(Body::from(format!("logout_token={logout_token}")))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn backchannel_logout_ends_sessions(db: SqlitePool) {
+    let rs256 = JwtKeys::rs256(include_str!("fixtures/jwt_rs256.pem")).unwrap();
+    let openid =
+        mock_signing_provider(Arc::new(Mutex::new(rs256.jwks().keys)), Default::default()).await;
+    let issuer = openid.issuer.clone();
+    let app = common::testing_setup_with_openid(&db, common::testing_options(), openid).await;
+    let sign = |claims: &serde_json::Value| rs256.encode(claims);
+
+    let exp = (chrono::Utc::now() + chrono::Duration::minutes(5)).timestamp();
+    let mut sessions = vec![];
+    for sid in ["upstream-session-1", "upstream-session-2"] {
+        let claims = json!({
+            "iss": issuer, "aud": "iceblink", "exp": exp, "sub": "upstream-signed", "sid": sid
+        });
+        let response = login_with_id_token(&app, sign, claims).await;
+        assert_that!(response.status(), eq(StatusCode::OK));
+        let tokens = common::convert_response(response).await;
+        sessions.push(tokens["access_token"].as_str().unwrap().to_string());
+    }
+
+    let event = json!({ "http://schemas.openid.net/event/backchannel-logout": {} });
+    let logout = json!({
+        "iss": issuer,
+        "aud": "iceblink",
+        "iat": chrono::Utc::now().timestamp(),
+        "jti": "logout-1",
+        "events": event,
+        "sid": "upstream-session-1",
+    });
+
+    let mut rejected = vec![];
+    for (key, value) in [
+        ("aud", json!("another-client")),
+        ("events", json!({})),
+        ("nonce", json!("an ID token")),
+    ] {
+        let mut claims = logout.clone();
+        claims[key] = value;
+        rejected.push(backchannel_logout(&app, &sign(&claims)).await);
+    }
+    let mut anonymous = logout.clone();
+    anonymous.as_object_mut().unwrap().remove("sid");
+    rejected.push(backchannel_logout(&app, &sign(&anonymous)).await);
+    for response in rejected {
+        expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+        expect_that!(
+            common::convert_response(response).await["errorKind"],
+            eq(&json!("InvalidLogoutToken"))
+        );
+    }
+
+    // Ending one session at the provider only ends the session which logged in with it
+    let response = backchannel_logout(&app, &sign(&logout)).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        response.headers()["cache-control"].to_str().unwrap(),
+        eq("no-store")
+    );
+    expect_that!(
+        common::list_codes(&app, &sessions[0]).await.status(),
+        eq(StatusCode::UNAUTHORIZED)
+    );
+    expect_that!(
+        common::list_codes(&app, &sessions[1]).await.status(),
+        eq(StatusCode::OK)
+    );
+
+    // Logging the user out ends all of their sessions, but leaves other users alone
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let mut claims = logout.clone();
+    claims.as_object_mut().unwrap().remove("sid");
+    claims["sub"] = json!("upstream-signed");
+    let response = backchannel_logout(&app, &sign(&claims)).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::list_codes(&app, &sessions[1]).await.status(),
+        eq(StatusCode::UNAUTHORIZED)
+    );
+    expect_that!(
+        common::list_codes(&app, &a1).await.status(),
+        eq(StatusCode::OK)
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn unknown_keys_are_fetched_at_most_once_per_interval(db: SqlitePool) {
+    let rs256 = JwtKeys::rs256(include_str!("fixtures/jwt_rs256.pem")).unwrap();
+    let es256 = JwtKeys::es256(include_str!("fixtures/jwt_es256.pem")).unwrap();
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let openid =
+        mock_signing_provider(Arc::new(Mutex::new(rs256.jwks().keys)), fetches.clone()).await;
+    let issuer = openid.issuer.clone();
+    let app = common::testing_setup_with_openid(&db, common::testing_options(), openid).await;
+
+    // Logout tokens signed with a key the provider doesn't publish, sent all at once
+    let logout = es256.encode(&json!({
+        "iss": issuer,
+        "aud": "iceblink",
+        "iat": chrono::Utc::now().timestamp(),
+        "jti": "logout-1",
+        "events": { "http://schemas.openid.net/event/backchannel-logout": {} },
+        "sid": "upstream-session-1",
+    }));
+    let responses =
+        futures_util::future::join_all((0..10).map(|_| backchannel_logout(&app, &logout))).await;
+    for response in responses {
+        expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    }
+    expect_that!(
+        backchannel_logout(&app, &logout).await.status(),
+        eq(StatusCode::BAD_REQUEST)
+    );
+    expect_that!(fetches.load(Ordering::SeqCst), eq(1));
+}
+
+async fn identities_request(
+    app: &Router,
+    token: &str,
+    method: Method,
+    path: &str,
+    cookie: Option<&str>,
+) -> Response {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header("Authorization", format!("Bearer {token}"));
+    if let Some(cookie) = cookie {
+        request = request.header("Cookie", cookie);
+    }
+    app.clone()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+/// Links the identity logging in with the code to the account of the token.
+async fn link(app: &Router, token: &str, code: &str) -> Response {
+    let (state, cookie) = common::login_state(&common::authorize(app, "").await);
+    identities_request(
+        app,
+        token,
+        Method::POST,
+        &format!("/v1/user/identities?code={code}&state={state}"),
+        Some(&cookie),
+    )
+    .await
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn link_identities(db: SqlitePool) {
+    let (openid, _) = mock_services().await;
+    let issuer = openid.issuer.clone();
+    let app = common::testing_setup_with_openid(&db, common::testing_options(), openid).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let response = link(&app, &a1, "second").await;
+    assert_that!(response.status(), eq(StatusCode::CREATED));
+    let identity = common::convert_response(response).await;
+    expect_that!(identity["provider"], eq(&json!("default")));
+    expect_that!(identity["issuer"], eq(&json!(issuer)));
+    expect_that!(identity["subject"], eq(&json!("upstream-second")));
+
+    // Logging in with the identity is logging into the account
+    let response = login(&app, "second", None).await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(user_exists(&db, "second").await, is_false());
+    let user = User::get_by_upstream_id(&db, &issuer, "upstream-second")
+        .await
+        .unwrap();
+    expect_that!(user.map(|user| user.id), some(eq(common::USER1_ID)));
+
+    let response = identities_request(&app, &a1, Method::GET, "/v1/user/identities", None).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let identities = common::convert_response(response).await;
+    expect_that!(
+        identities,
+        eq(&json!([
+            {
+                "id": null,
+                "provider": null,
+                "issuer": "",
+                "subject": "8h4ar",
+                "created_at": null
+            },
+            identity.clone()
+        ]))
+    );
+
+    // An identity belongs to one account
+    let response = link(&app, &a2, "second").await;
+    assert_that!(response.status(), eq(StatusCode::CONFLICT));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("IdentityInUse"))
+    );
+
+    // Others can't unlink it
+    let path = format!("/v1/user/identities/{}", identity["id"].as_str().unwrap());
+    let response = identities_request(&app, &a2, Method::DELETE, &path, None).await;
+    expect_that!(response.status(), eq(StatusCode::NOT_FOUND));
+
+    let response = identities_request(&app, &a1, Method::DELETE, &path, None).await;
+    expect_that!(response.status(), eq(StatusCode::NO_CONTENT));
+    let response = identities_request(&app, &a1, Method::DELETE, &path, None).await;
+    expect_that!(response.status(), eq(StatusCode::NOT_FOUND));
+
+    // Now it's someone else
+    let response = login(&app, "second", None).await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(user_exists(&db, "second").await, is_true());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn link_identity_requires_recent_login_and_state(db: SqlitePool) {
+    let (openid, _) = mock_services().await;
+    let app = common::testing_setup_with_openid(&db, common::testing_options(), openid).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    // Without the cookie of the login
+    let (state, _) = common::login_state(&common::authorize(&app, "").await);
+    let response = identities_request(
+        &app,
+        &a1,
+        Method::POST,
+        &format!("/v1/user/identities?code=second&state={state}"),
+        None,
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("InvalidOauthState"))
+    );
+
+    sqlx::query("UPDATE sessions SET created_at = created_at - 3600")
+        .execute(&db)
+        .await
+        .unwrap();
+    let response = link(&app, &a1, "second").await;
+    assert_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("ReauthenticationRequired"))
+    );
+}
+
+/// OAuth server whose users are named after the code they log in with, all with the same profile.
+/// Without a profile, the server rejects every access token.
+async fn mock_profile_provider(profile: Arc<Mutex<Option<serde_json::Value>>>) -> OpenId {
+    let base = common::mock_upstream(
+        Router::new()
+            .route(
+                "/token",
+                post(|Json(body): Json<serde_json::Value>| async move {
+                    Json(json!({ "access_token": body["code"] }))
+                }),
+            )
+            .route(
+                "/userinfo",
+                get(move |headers: HeaderMap| async move {
+                    let token = headers["authorization"]
+                        .to_str()
+                        .unwrap()
+                        .trim_start_matches("Bearer ")
+                        .to_string();
+                    let profile = profile.lock().unwrap().clone();
+                    let profile = profile.ok_or(StatusCode::UNAUTHORIZED)?;
+                    Ok::<_, StatusCode>(Json(json!({
+                        "sub": format!("upstream-{token}"),
+                        "preferred_username": token,
+                        "name": profile["name"],
+                        "picture": profile["picture"]
+                    })))
+                }),
+            ),
+    )
+    .await;
+
+    OpenId {
+        name: "default".into(),
+        issuer: base.clone(),
+        authorization: format!("{base}/authorize"),
+        client_id: "N/A".into(),
+        client_secret: "N/A".into(),
+        token: format!("{base}/token"),
+        userinfo: format!("{base}/userinfo"),
+        proxy: None,
+        jwks_uri: None,
+        keys: Default::default(),
+    }
+}
+
+async fn refresh_profile(app: &Router, token: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/v1/user/refresh")
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn profile_follows_provider(db: SqlitePool) {
+    let profile = Arc::new(Mutex::new(Some(
+        json!({ "name": "Alice", "picture": "https://idp.example/alice.png" }),
+    )));
+    let openid = mock_profile_provider(profile.clone()).await;
+    let app = common::testing_setup_with_openid(&db, common::testing_options(), openid).await;
+
+    let response = login(&app, "alice", None).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let user = User::get_by_username(&db, "alice".into()).await.unwrap();
+    expect_that!(user.unwrap().display_name, eq("Alice"));
+
+    // Logging in again takes over changes at the provider
+    *profile.lock().unwrap() =
+        Some(json!({ "name": "Alice Liddell", "picture": "https://idp.example/new.png" }));
+    let response = login(&app, "alice", None).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let access_token = common::convert_response(response).await["access_token"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let user = User::get_by_username(&db, "alice".into())
+        .await
+        .unwrap()
+        .unwrap();
+    expect_that!(user.display_name, eq("Alice Liddell"));
+    expect_that!(user.avatar_url, eq("https://idp.example/new.png"));
+
+    // As does refreshing without logging in again
+    *profile.lock().unwrap() = Some(json!({ "name": null, "picture": "" }));
+    let response = refresh_profile(&app, &access_token).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::convert_response(response).await,
+        eq(&json!({
+            "id": user.id,
+            "username": "alice",
+            "display_name": "alice",
+            "avatar_url": ""
+        }))
+    );
+
+    // Once the provider no longer accepts the session's access token, the user has to log in again
+    *profile.lock().unwrap() = None;
+    let response = refresh_profile(&app, &access_token).await;
+    assert_that!(response.status(), eq(StatusCode::CONFLICT));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("UpstreamLoginRequired"))
+    );
+
+    // Sessions which didn't log in at the provider have nothing to refresh with
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let response = refresh_profile(&app, &a1).await;
+    assert_that!(response.status(), eq(StatusCode::CONFLICT));
+}
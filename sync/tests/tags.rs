@@ -0,0 +1,113 @@
+use axum::http::StatusCode;
+use googletest::prelude::*;
+use serde_json::json;
+use sqlx::SqlitePool;
+
+pub mod common;
+
+fn ids(codes: &serde_json::Value) -> Vec<String> {
+    codes
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|code| code["id"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn tag_codes(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let response = common::edit_code(
+        &app,
+        &a1,
+        "Ckpt4eFi1pw9fxI3",
+        &json!({ "tags": ["work", " mail ", "work"] }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    // Changing only the tags still counts as a change for syncing clients
+    expect_that!(
+        common::convert_response(response).await["revision"].as_i64(),
+        some(gt(0))
+    );
+
+    let response = common::add_code(
+        &app,
+        &a1,
+        &json!({ "content": "1337", "display_name": "Mailbox", "tags": ["mail"] }),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let mailbox = common::convert_response(response).await["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    expect_that!(
+        common::list_tags(&app, &a1).await,
+        eq(&json!([
+            { "name": "mail", "code_ids": ["Ckpt4eFi1pw9fxI3", mailbox] },
+            { "name": "work", "code_ids": ["Ckpt4eFi1pw9fxI3"] }
+        ]))
+    );
+    expect_that!(common::list_tags(&app, &a2).await, eq(&json!([])));
+
+    let response = common::get_codes_path(&app, &a1, "?tag=mail").await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        response.headers()["x-total-count"].to_str().unwrap(),
+        eq("2")
+    );
+    expect_that!(
+        ids(&common::convert_response(response).await),
+        elements_are![eq("Ckpt4eFi1pw9fxI3"), eq(mailbox.as_str())]
+    );
+
+    // Clones keep the tags of the original
+    let response =
+        common::clone_code(&app, &a1, "Ckpt4eFi1pw9fxI3", &json!({ "content": "42" })).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let clone = common::convert_response(response).await["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let response = common::get_codes_path(&app, &a1, "?tag=work").await;
+    expect_that!(
+        ids(&common::convert_response(response).await),
+        elements_are![eq("Ckpt4eFi1pw9fxI3"), eq(clone.as_str())]
+    );
+
+    // Tags no code carries anymore disappear, as do deleted codes
+    let response = common::edit_code(&app, &a1, "Ckpt4eFi1pw9fxI3", &json!({ "tags": [] })).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let response = common::delete_code(&app, &a1, &clone).await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+    expect_that!(
+        common::list_tags(&app, &a1).await,
+        eq(&json!([{ "name": "mail", "code_ids": [mailbox] }]))
+    );
+
+    let response = common::get_codes_path(&app, &a1, "?tag=work").await;
+    expect_that!(common::convert_response(response).await, eq(&json!([])));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn tags_rejects_invalid(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    for tags in [
+        json!([""]),
+        json!(["a".repeat(33)]),
+        json!((0..21).map(|i| i.to_string()).collect::<Vec<_>>()),
+    ] {
+        let response =
+            common::edit_code(&app, &a1, "Ckpt4eFi1pw9fxI3", &json!({ "tags": tags })).await;
+        expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    }
+    expect_that!(common::list_tags(&app, &a1).await, eq(&json!([])));
+}
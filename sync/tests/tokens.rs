@@ -0,0 +1,458 @@
+use axum::{
+    body::Body,
+    http::{Method, Request, StatusCode},
+    response::Response,
+    Router,
+};
+use googletest::prelude::*;
+use serde_json::json;
+use sha2::Digest;
+use sqlx::SqlitePool;
+use tower::ServiceExt;
+
+pub mod common;
+
+/// Sends a request to `/v1/user/tokens{path}`, with the payload as JSON body if any.
+async fn tokens_request(
+    app: &Router,
+    token: &str,
+    method: Method,
+    path: &str,
+    payload: Option<&serde_json::Value>,
+) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(format!("/v1/user/tokens{path}"))
+        .header("Authorization", format!("Bearer {token}"));
+    let request = match payload {
+        Some(payload) => request
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_vec(payload).unwrap())),
+        None => request.body(Body::empty()),
+    };
+
+    app.clone().oneshot(request.unwrap()).await.unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn read_only_token_lists_without_content(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let token = common::create_token(&app, a1.as_str(), "read:metadata").await;
+
+    let listing_request = common::list_codes(&app, token.as_str()).await;
+    assert_that!(listing_request.status(), eq(StatusCode::OK));
+
+    let listing = common::convert_response(listing_request).await;
+    let codes = listing.as_array().unwrap();
+    assert_that!(codes.len(), eq(2));
+    for code in codes {
+        expect_that!(code.get("content"), none());
+        expect_that!(code.get("display_name"), some(anything()));
+    }
+    expect_that!(
+        listing.to_string(),
+        not(contains_substring(common::USER1_CODE1_CONTENT))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn read_only_token_cannot_select_content(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let token = common::create_token(&app, a1.as_str(), "read:metadata").await;
+
+    let response = common::get_codes_path(
+        &app,
+        token.as_str(),
+        &format!("/{}?fields=id,content", common::USER1_CODE1_ID),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(
+        common::convert_response(response).await,
+        eq(&json!({ "id": common::USER1_CODE1_ID }))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn read_only_token_cannot_mutate(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let token = common::create_token(&app, a1.as_str(), "read:metadata").await;
+
+    let add_request = common::add_code(
+        &app,
+        token.as_str(),
+        &json!({
+            "content": "yippie",
+            "display_name": "Modrinth",
+            "website_url": null
+        }),
+    )
+    .await;
+    assert_that!(add_request.status(), eq(StatusCode::FORBIDDEN));
+    assert_that!(
+        common::convert_response(add_request).await["errorKind"],
+        eq(&json!("InsufficientScope"))
+    );
+
+    let edit_request = common::edit_code(
+        &app,
+        token.as_str(),
+        common::USER1_CODE1_ID,
+        &json!({ "display_name": "Hacked." }),
+    )
+    .await;
+    assert_that!(edit_request.status(), eq(StatusCode::FORBIDDEN));
+
+    let delete_request = common::delete_code(&app, token.as_str(), common::USER1_CODE1_ID).await;
+    assert_that!(delete_request.status(), eq(StatusCode::FORBIDDEN));
+
+    let export_request = common::export_codes(&app, token.as_str(), &json!({})).await;
+    assert_that!(export_request.status(), eq(StatusCode::FORBIDDEN));
+
+    let listing = common::list_codes_content(&app, a1.as_str()).await;
+    assert_that!(listing, common::matchers::code_fixture());
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn full_token_can_mutate(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let token = common::create_token(&app, a1.as_str(), "full").await;
+
+    let listing = common::list_codes_content(&app, token.as_str()).await;
+    assert_that!(listing, common::matchers::code_fixture());
+
+    let edit_request = common::edit_code(
+        &app,
+        token.as_str(),
+        common::USER1_CODE1_ID,
+        &json!({ "display_name": "Modrinth" }),
+    )
+    .await;
+    assert_that!(edit_request.status(), eq(StatusCode::OK));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn tokens_are_stored_hashed(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let token = common::create_token(&app, a1.as_str(), "read:metadata").await;
+    assert_that!(token, starts_with("ibt_"));
+    let (id, secret) = token[4..].split_once('_').unwrap();
+
+    let rows: Vec<(String, String, String)> =
+        sqlx::query_as("SELECT id, token_hash, scope FROM api_tokens")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+    assert_that!(rows.len(), eq(1));
+    let (stored_id, hash, scope) = &rows[0];
+    expect_that!(stored_id, eq(id));
+    expect_that!(hash, starts_with("$argon2id$"));
+    for column in [stored_id, hash, scope] {
+        expect_that!(column, not(contains_substring(secret)));
+    }
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn token_with_wrong_secret_is_rejected(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let token = common::create_token(&app, a1.as_str(), "full").await;
+    let (id, _) = token[4..].split_once('_').unwrap();
+
+    // Once verified, the token is cached. That must not let other secrets through
+    expect_that!(
+        common::list_codes(&app, &token).await.status(),
+        eq(StatusCode::OK)
+    );
+    let forged = format!("ibt_{id}_{}", "a".repeat(40));
+    expect_that!(
+        common::list_codes(&app, &forged).await.status(),
+        eq(StatusCode::UNAUTHORIZED)
+    );
+    expect_that!(
+        common::list_codes(&app, &token).await.status(),
+        eq(StatusCode::OK)
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn legacy_sha256_token_still_works(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let token = "ibt_Xq3vT9mLr2Kp8WzN5cYb1HdJf6GsA0eUo4iQ7tRn";
+
+    sqlx::query(
+        "INSERT INTO api_tokens (id, user_id, token_hash, scope, created_at) VALUES (?, ?, ?, ?, 0)",
+    )
+    .bind("legacytoken00001")
+    .bind(common::USER1_ID)
+    .bind(base16ct::lower::encode_string(&sha2::Sha256::digest(token)))
+    .bind("full")
+    .execute(&db)
+    .await
+    .unwrap();
+
+    expect_that!(
+        common::list_codes(&app, token).await.status(),
+        eq(StatusCode::OK)
+    );
+
+    // Rehashed with Argon2 once used, and still found without the cache of verified tokens
+    let token_hash: String =
+        sqlx::query_scalar("SELECT token_hash FROM api_tokens WHERE id = 'legacytoken00001'")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+    expect_that!(token_hash, starts_with("$argon2id$"));
+    let app = common::testing_setup(&db).await;
+    expect_that!(
+        common::list_codes(&app, token).await.status(),
+        eq(StatusCode::OK)
+    );
+    expect_that!(
+        common::list_codes(&app, &format!("{token}x"))
+            .await
+            .status(),
+        eq(StatusCode::UNAUTHORIZED)
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn read_only_token_cannot_create_tokens(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let token = common::create_token(&app, a1.as_str(), "read:metadata").await;
+
+    let create_request = common::create_token_request(&app, token.as_str(), "full").await;
+    assert_that!(create_request.status(), eq(StatusCode::FORBIDDEN));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn unknown_token_is_rejected(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+
+    let listing_request = common::list_codes(&app, "ibt_doesnotexist").await;
+    assert_that!(listing_request.status(), eq(StatusCode::UNAUTHORIZED));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn logout_revokes_token(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let token = common::create_token(&app, a1.as_str(), "read:metadata").await;
+
+    let response = common::logout(&app, &token).await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+
+    let response = common::list_codes(&app, &token).await;
+    expect_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("InvalidAuthentication"))
+    );
+
+    // The session which created it is unaffected
+    expect_that!(
+        common::list_codes(&app, &a1).await.status(),
+        eq(StatusCode::OK)
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn list_rename_and_revoke_tokens(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let response = tokens_request(
+        &app,
+        &a1,
+        Method::POST,
+        "",
+        Some(&json!({ "name": "Backup script", "scope": "read:metadata" })),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let created = common::convert_response(response).await;
+    expect_that!(created["name"], eq(&json!("Backup script")));
+    let id = created["id"].as_str().unwrap().to_string();
+    let token = created["token"].as_str().unwrap().to_string();
+
+    let listing =
+        common::convert_response(tokens_request(&app, &a1, Method::GET, "", None).await).await;
+    assert_that!(listing.as_array().unwrap(), len(eq(1)));
+    expect_that!(listing[0]["id"], eq(&json!(id)));
+    expect_that!(listing[0]["scope"], eq(&json!("read:metadata")));
+    expect_that!(listing[0]["last_used_at"], eq(&json!(null)));
+    expect_that!(listing[0]["current"], eq(&json!(false)));
+
+    // Using the token records it, and it recognizes itself
+    let listing =
+        common::convert_response(tokens_request(&app, &token, Method::GET, "", None).await).await;
+    expect_that!(listing[0]["last_used_at"].as_i64(), some(gt(0)));
+    expect_that!(listing[0]["current"], eq(&json!(true)));
+
+    // Other users can't see or touch it
+    let listing =
+        common::convert_response(tokens_request(&app, &a2, Method::GET, "", None).await).await;
+    expect_that!(listing.as_array().unwrap(), is_empty());
+    let response = tokens_request(&app, &a2, Method::DELETE, &format!("/{id}"), None).await;
+    expect_that!(response.status(), eq(StatusCode::NOT_FOUND));
+
+    let response = tokens_request(
+        &app,
+        &a1,
+        Method::PATCH,
+        &format!("/{id}"),
+        Some(&json!({ "name": "Nightly backup" })),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+    let listing =
+        common::convert_response(tokens_request(&app, &a1, Method::GET, "", None).await).await;
+    expect_that!(listing[0]["name"], eq(&json!("Nightly backup")));
+
+    let response = tokens_request(&app, &a1, Method::DELETE, &format!("/{id}"), None).await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+    expect_that!(
+        common::list_codes(&app, &token).await.status(),
+        eq(StatusCode::UNAUTHORIZED)
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn token_name_too_long(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = tokens_request(
+        &app,
+        &a1,
+        Method::POST,
+        "",
+        Some(&json!({ "name": "a".repeat(101), "scope": "full" })),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn read_only_token_cannot_revoke_tokens(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let token = common::create_token(&app, a1.as_str(), "read:metadata").await;
+
+    let listing =
+        common::convert_response(tokens_request(&app, &a1, Method::GET, "", None).await).await;
+    let id = listing[0]["id"].as_str().unwrap();
+
+    let response = tokens_request(&app, &token, Method::DELETE, &format!("/{id}"), None).await;
+    expect_that!(response.status(), eq(StatusCode::FORBIDDEN));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn codes_read_token_cannot_modify(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let token = common::create_token(&app, a1.as_str(), "codes:read").await;
+
+    let listing = common::list_codes_content(&app, token.as_str()).await;
+    assert_that!(listing, common::matchers::code_fixture());
+
+    let edit_request = common::edit_code(
+        &app,
+        token.as_str(),
+        common::USER1_CODE1_ID,
+        &json!({ "display_name": "Hacked." }),
+    )
+    .await;
+    expect_that!(edit_request.status(), eq(StatusCode::FORBIDDEN));
+    let delete_request = common::delete_code(&app, token.as_str(), common::USER1_CODE1_ID).await;
+    expect_that!(delete_request.status(), eq(StatusCode::FORBIDDEN));
+    let delete_request = common::delete_account(&app, token.as_str()).await;
+    expect_that!(delete_request.status(), eq(StatusCode::FORBIDDEN));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn codes_write_token_cannot_manage_account(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let token = common::create_token(&app, a1.as_str(), "codes:write").await;
+
+    let edit_request = common::edit_code(
+        &app,
+        token.as_str(),
+        common::USER1_CODE1_ID,
+        &json!({ "display_name": "Modrinth" }),
+    )
+    .await;
+    expect_that!(edit_request.status(), eq(StatusCode::OK));
+    // Writing includes reading
+    let listing = common::list_codes_content(&app, token.as_str()).await;
+    expect_that!(listing, len(eq(2)));
+
+    let create_request = common::create_token_request(&app, token.as_str(), "full").await;
+    expect_that!(create_request.status(), eq(StatusCode::FORBIDDEN));
+    let delete_request = common::delete_account(&app, token.as_str()).await;
+    expect_that!(delete_request.status(), eq(StatusCode::FORBIDDEN));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn multiple_scopes(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response =
+        common::create_token_request(&app, a1.as_str(), "codes:read account:delete").await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let created = common::convert_response(response).await;
+    expect_that!(created["scope"], eq(&json!("codes:read account:delete")));
+    let token = created["token"].as_str().unwrap();
+
+    let edit_request = common::edit_code(
+        &app,
+        token,
+        common::USER1_CODE1_ID,
+        &json!({ "display_name": "Hacked." }),
+    )
+    .await;
+    expect_that!(edit_request.status(), eq(StatusCode::FORBIDDEN));
+    // The scope allows it, but a stolen token alone must not delete the vault
+    let delete_request = common::delete_account(&app, token).await;
+    expect_that!(delete_request.status(), eq(StatusCode::UNAUTHORIZED));
+    expect_that!(
+        common::convert_response(delete_request).await["errorKind"],
+        eq(&json!("ReauthenticationRequired"))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn unknown_scope_is_rejected(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    for scope in ["codes:delete", "codes:read codes:delete", " "] {
+        let response = common::create_token_request(&app, a1.as_str(), scope).await;
+        expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    }
+}
@@ -1,11 +1,21 @@
 use axum::{
     body::Body,
-    http::{Method, Request, StatusCode},
+    http::{header, HeaderMap, Method, Request, StatusCode},
+    response::Response,
 };
 use googletest::prelude::*;
-use iceblink_sync::models;
+use iceblink_sync::{
+    auth,
+    events::{EventKind, Events},
+    jwt::JwtKeys,
+    models, tasks, ServerOptions,
+};
 use serde_json::json;
 use sqlx::SqlitePool;
+use std::{
+    io::{Cursor, Read},
+    time::Duration,
+};
 use tower::ServiceExt;
 
 pub mod common;
@@ -119,6 +129,58 @@ async fn checksum_changes_code_content(db: SqlitePool) {
     assert_that!(checksum1, not(eq(&checksum2)));
 }
 
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn checksum_buckets(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = user_request(&app, &a1, Method::GET, "/v1/user/checksum").await;
+    let before = common::convert_response(response).await;
+    expect_that!(
+        before["buckets"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .collect::<Vec<_>>(),
+        elements_are![eq("C"), eq("D")]
+    );
+    expect_that!(before.get("codes"), none());
+
+    common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE2_ID,
+        &json!({ "display_name": "Renamed" }),
+    )
+    .await;
+
+    let response = user_request(&app, &a1, Method::GET, "/v1/user/checksum?bucket=D").await;
+    let after = common::convert_response(response).await;
+    expect_that!(after["checksum"], not(eq(&before["checksum"])));
+    expect_that!(after["buckets"]["C"], eq(&before["buckets"]["C"]));
+    expect_that!(after["buckets"]["D"], not(eq(&before["buckets"]["D"])));
+    expect_that!(
+        after["codes"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .collect::<Vec<_>>(),
+        elements_are![eq(common::USER1_CODE2_ID)]
+    );
+
+    for bucket in ["", "DD", "-"] {
+        let response = user_request(
+            &app,
+            &a1,
+            Method::GET,
+            &format!("/v1/user/checksum?bucket={bucket}"),
+        )
+        .await;
+        expect_that!(response.status(), eq(StatusCode::BAD_REQUEST), "{bucket}");
+    }
+}
+
 #[sqlx::test(fixtures("users", "codes"))]
 #[gtest]
 async fn checksum_equal_edit_and_revert_content(db: SqlitePool) {
@@ -182,3 +244,632 @@ async fn checksum_changes_code_delete(db: SqlitePool) {
 
     assert_that!(checksum1, not(eq(&checksum2)));
 }
+
+async fn user_request(app: &axum::Router, token: &str, method: Method, uri: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+async fn login_with_user_agent(db: &SqlitePool, user_agent: &str) -> String {
+    let user = models::user::User::get_by_id(db, common::USER1_ID.into())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert(header::USER_AGENT, user_agent.parse().unwrap());
+
+    auth::create_jwt(
+        db,
+        &user,
+        &JwtKeys::hs256("my jwt secret"),
+        &headers,
+        Some("203.0.113.7".parse().unwrap()),
+    )
+    .await
+    .unwrap()
+    .jwt
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn list_sessions(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let phone = login_with_user_agent(
+        &db,
+        "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/129.0.0.0 Mobile Safari/537.36",
+    )
+    .await;
+
+    let response = user_request(&app, &phone, Method::GET, "/v1/user/sessions").await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    let sessions = common::convert_response(response).await;
+    let sessions = sessions.as_array().unwrap();
+    assert_that!(sessions.len(), eq(2));
+
+    let current: Vec<_> = sessions
+        .iter()
+        .filter(|session| session["current"] == json!(true))
+        .collect();
+    assert_that!(current.len(), eq(1));
+    expect_that!(current[0]["device"], eq(&json!("Chrome on Android")));
+    expect_that!(
+        current[0]["user_agent"].as_str(),
+        some(starts_with("Mozilla/5.0 (Linux; Android 14; Pixel 8)"))
+    );
+    expect_that!(current[0]["ip"], eq(&json!("203.0.113.7")));
+
+    // Sessions of other users aren't listed
+    let response = user_request(&app, &a1, Method::GET, "/v1/user/sessions").await;
+    let sessions = common::convert_response(response).await;
+    expect_that!(
+        sessions
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|session| session["device"].as_str().unwrap())
+            .collect::<Vec<_>>(),
+        unordered_elements_are![eq("Chrome on Android"), eq("Unknown device")]
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn revoke_session(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    let laptop = login_with_user_agent(
+        &db,
+        "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0",
+    )
+    .await;
+
+    let response = user_request(&app, &laptop, Method::GET, "/v1/user/sessions").await;
+    let laptop_id = common::convert_response(response)
+        .await
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|session| session["current"] == json!(true))
+        .unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    // Other users can't revoke the session
+    let response = user_request(
+        &app,
+        &a2,
+        Method::DELETE,
+        &format!("/v1/user/sessions/{laptop_id}"),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::NOT_FOUND));
+
+    let response = user_request(
+        &app,
+        &a1,
+        Method::DELETE,
+        &format!("/v1/user/sessions/{laptop_id}"),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::NO_CONTENT));
+
+    let response = common::list_codes(&app, &laptop).await;
+    expect_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("SessionExpired"))
+    );
+
+    // The session which revoked it, and those of other users, are unaffected
+    expect_that!(
+        common::list_codes(&app, &a1).await.status(),
+        eq(StatusCode::OK)
+    );
+    expect_that!(
+        common::list_codes(&app, &a2).await.status(),
+        eq(StatusCode::OK)
+    );
+}
+
+const ENCRYPTED_1: &str = "e2e:v1:bm9uY2UxY2lwaGVydGV4dDE=";
+const ENCRYPTED_2: &str = "e2e:v1:bm9uY2UyY2lwaGVydGV4dDI=";
+
+async fn encryption_request(
+    app: &axum::Router,
+    token: &str,
+    method: Method,
+    payload: &serde_json::Value,
+) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(method)
+                .uri("/v1/user/encryption")
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_vec(payload).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+async fn enable_encryption(app: &axum::Router, token: &str) -> Response {
+    encryption_request(
+        app,
+        token,
+        Method::PUT,
+        &json!({
+            "key_check": "a1b2c3",
+            "codes": [
+                { "id": common::USER1_CODE1_ID, "content": ENCRYPTED_1 },
+                { "id": common::USER1_CODE2_ID, "content": ENCRYPTED_2, "display_name": ENCRYPTED_1 },
+            ],
+        }),
+    )
+    .await
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn end_to_end_encryption(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let response = enable_encryption(&app, &a1).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::convert_response(response).await,
+        eq(&json!({ "enabled": true, "key_check": "a1b2c3" }))
+    );
+
+    let listing = common::list_codes_content(&app, &a1).await;
+    expect_that!(listing[0].content, eq(ENCRYPTED_1));
+    expect_that!(listing[0].display_name, eq("Google"));
+    expect_that!(listing[1].content, eq(ENCRYPTED_2));
+    expect_that!(listing[1].display_name, eq(ENCRYPTED_1));
+
+    // Plaintext secrets are refused
+    let response = common::add_code(
+        &app,
+        &a1,
+        &json!({ "content": "JBSWY3DPEHPK3PXP", "display_name": "GitHub" }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::UNPROCESSABLE_ENTITY));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("EncryptionRequired"))
+    );
+    let response = common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "content": "JBSWY3DPEHPK3PXP" }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::UNPROCESSABLE_ENTITY));
+
+    let response = common::add_code(
+        &app,
+        &a1,
+        &json!({ "content": ENCRYPTED_2, "display_name": "GitHub" }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+
+    // The server can't generate codes anymore
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(format!("/v1/code/{}/verify", common::USER1_CODE1_ID))
+                .header("Authorization", format!("Bearer {a1}"))
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"code":"123456"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    expect_that!(response.status(), eq(StatusCode::CONFLICT));
+    expect_that!(
+        common::convert_response(response).await["errorKind"],
+        eq(&json!("EncryptedContent"))
+    );
+
+    let response = user_request(&app, &a1, Method::GET, "/v1/").await;
+    expect_that!(
+        common::convert_response(response).await["encryption_enabled"],
+        eq(&json!(true))
+    );
+    let response = user_request(&app, &a2, Method::GET, "/v1/").await;
+    expect_that!(
+        common::convert_response(response).await["encryption_enabled"],
+        eq(&json!(false))
+    );
+    let response = user_request(&app, &a2, Method::GET, "/v1/user/encryption").await;
+    expect_that!(
+        common::convert_response(response).await,
+        eq(&json!({ "enabled": false, "key_check": null }))
+    );
+
+    // Other users are unaffected
+    let response = common::add_code(
+        &app,
+        &a2,
+        &json!({ "content": "JBSWY3DPEHPK3PXP", "display_name": "GitHub" }),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::OK));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn enable_encryption_rejected(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let missing_code = json!({
+        "key_check": "a1b2c3",
+        "codes": [{ "id": common::USER1_CODE1_ID, "content": ENCRYPTED_1 }],
+    });
+    let duplicate_code = json!({
+        "key_check": "a1b2c3",
+        "codes": [
+            { "id": common::USER1_CODE1_ID, "content": ENCRYPTED_1 },
+            { "id": common::USER1_CODE1_ID, "content": ENCRYPTED_1 },
+            { "id": common::USER1_CODE2_ID, "content": ENCRYPTED_2 },
+        ],
+    });
+    let foreign_code = json!({
+        "key_check": "a1b2c3",
+        "codes": [
+            { "id": common::USER1_CODE1_ID, "content": ENCRYPTED_1 },
+            { "id": common::USER2_CODE1_ID, "content": ENCRYPTED_2 },
+        ],
+    });
+    let empty_key_check = json!({
+        "key_check": " ",
+        "codes": [
+            { "id": common::USER1_CODE1_ID, "content": ENCRYPTED_1 },
+            { "id": common::USER1_CODE2_ID, "content": ENCRYPTED_2 },
+        ],
+    });
+    for payload in [missing_code, duplicate_code, foreign_code, empty_key_check] {
+        let response = encryption_request(&app, &a1, Method::PUT, &payload).await;
+        expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    }
+
+    let plaintext = json!({
+        "key_check": "a1b2c3",
+        "codes": [
+            { "id": common::USER1_CODE1_ID, "content": ENCRYPTED_1 },
+            { "id": common::USER1_CODE2_ID, "content": common::USER1_CODE2_CONTENT },
+        ],
+    });
+    let response = encryption_request(&app, &a1, Method::PUT, &plaintext).await;
+    expect_that!(response.status(), eq(StatusCode::UNPROCESSABLE_ENTITY));
+
+    // Nothing changed
+    let listing = common::list_codes_content(&app, &a1).await;
+    assert_that!(listing, common::matchers::code_fixture());
+    let response = user_request(&app, &a1, Method::GET, "/v1/user/encryption").await;
+    expect_that!(
+        common::convert_response(response).await["enabled"],
+        eq(&json!(false))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn disable_encryption(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    assert_that!(
+        enable_encryption(&app, &a1).await.status(),
+        eq(StatusCode::OK)
+    );
+
+    let still_encrypted = json!({
+        "codes": [
+            { "id": common::USER1_CODE1_ID, "content": common::USER1_CODE1_CONTENT },
+            { "id": common::USER1_CODE2_ID, "content": ENCRYPTED_2 },
+        ],
+    });
+    let response = encryption_request(&app, &a1, Method::DELETE, &still_encrypted).await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+
+    let decrypted = json!({
+        "codes": [
+            { "id": common::USER1_CODE1_ID, "content": common::USER1_CODE1_CONTENT },
+            { "id": common::USER1_CODE2_ID, "content": common::USER1_CODE2_CONTENT, "display_name": "google.com" },
+        ],
+    });
+    let response = encryption_request(&app, &a1, Method::DELETE, &decrypted).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    expect_that!(
+        common::convert_response(response).await,
+        eq(&json!({ "enabled": false, "key_check": null }))
+    );
+
+    let listing = common::list_codes_content(&app, &a1).await;
+    expect_that!(listing[0].content, eq(common::USER1_CODE1_CONTENT));
+    expect_that!(listing[1].content, eq(common::USER1_CODE2_CONTENT));
+    expect_that!(listing[1].display_name, eq("google.com"));
+}
+
+fn archive_file(archive: &mut zip::ZipArchive<Cursor<Vec<u8>>>, name: &str) -> serde_json::Value {
+    let mut contents = String::new();
+    archive
+        .by_name(name)
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    serde_json::from_str(&contents).unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn export_data(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let work = common::add_folder(&app, &a1, "Work", None).await;
+    let edit_request = common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({
+            "display_name": "Google Mail",
+            "folder_id": work,
+            "tags": ["email"],
+        }),
+    )
+    .await;
+    assert_that!(edit_request.status(), eq(StatusCode::OK));
+    // Icons are only embedded by imports from other apps
+    sqlx::query("UPDATE codes SET icon_url = 'data:image/svg+xml;base64,PHN2Zy8+' WHERE id = ?")
+        .bind(common::USER1_CODE1_ID)
+        .execute(&db)
+        .await
+        .unwrap();
+    common::delete_code(&app, &a1, common::USER1_CODE2_ID).await;
+    common::create_token(&app, &a1, "codes:read").await;
+
+    let response = user_request(&app, &a1, Method::GET, "/v1/user/export").await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+    assert_that!(
+        response.headers().get("Content-Type").unwrap(),
+        eq("application/zip")
+    );
+    let mut archive =
+        zip::ZipArchive::new(Cursor::new(common::convert_response_u8(response).await)).unwrap();
+    expect_that!(archive.by_name("README.txt").is_ok(), is_true());
+
+    let profile = archive_file(&mut archive, "profile.json");
+    expect_that!(profile["format_version"], eq(&json!(1)));
+    expect_that!(profile["id"], eq(&json!(common::USER1_ID)));
+    expect_that!(profile["username"], eq(&json!("user1")));
+
+    let codes = archive_file(&mut archive, "codes.json");
+    assert_that!(codes.as_array().unwrap().len(), eq(2));
+    expect_that!(codes[0]["display_name"], eq(&json!("Google Mail")));
+    expect_that!(codes[0]["tags"], eq(&json!(["email"])));
+    expect_that!(codes[0]["deleted_at"], eq(&json!(null)));
+    expect_that!(codes[0]["history"][0]["display_name"], eq(&json!("Google")));
+    // Codes in the trash are still stored
+    expect_that!(codes[1]["id"], eq(&json!(common::USER1_CODE2_ID)));
+    expect_that!(codes[1]["content"], eq(&json!(common::USER1_CODE2_CONTENT)));
+    expect_that!(codes[1]["deleted_at"].is_i64(), is_true());
+
+    let folders = archive_file(&mut archive, "folders.json");
+    expect_that!(folders[0]["name"], eq(&json!("Work")));
+    let sessions = archive_file(&mut archive, "sessions.json");
+    expect_that!(sessions.as_array().unwrap().len(), eq(1));
+    expect_that!(sessions[0].get("refresh_token_hash"), none());
+    let tokens = archive_file(&mut archive, "tokens.json");
+    expect_that!(tokens[0]["scope"], eq(&json!("codes:read")));
+    expect_that!(tokens[0].get("token_hash"), none());
+    let webhooks = archive_file(&mut archive, "webhooks.json");
+    expect_that!(webhooks, eq(&json!([])));
+
+    let mut icon = vec![];
+    archive
+        .by_name(&format!("icons/{}.svg", common::USER1_CODE1_ID))
+        .unwrap()
+        .read_to_end(&mut icon)
+        .unwrap();
+    expect_that!(icon, eq(b"<svg/>"));
+
+    // Nothing of other users
+    let codes = codes.to_string();
+    expect_that!(codes, not(contains_substring(common::USER2_CODE1_CONTENT)));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn export_data_requires_full_scope(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let token = common::create_token(&app, &a1, "codes:read").await;
+
+    let response = user_request(&app, &token, Method::GET, "/v1/user/export").await;
+    expect_that!(response.status(), eq(StatusCode::FORBIDDEN));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn scheduled_account_deletion(db: SqlitePool) {
+    let app = common::testing_setup_with_options(
+        &db,
+        ServerOptions {
+            account_deletion_grace: Duration::from_secs(7 * 24 * 60 * 60),
+            ..common::testing_options()
+        },
+    )
+    .await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let response = common::delete_account(&app, &a1).await;
+    assert_that!(response.status(), eq(StatusCode::ACCEPTED));
+    let delete_at = common::convert_response(response).await["delete_at"]
+        .as_i64()
+        .unwrap();
+    let expected = chrono::Utc::now().timestamp() + 7 * 24 * 60 * 60;
+    expect_that!(delete_at, ge(expected - 5));
+    expect_that!(delete_at, le(expected));
+
+    // The account keeps working during the grace period, and deleting it again keeps the date
+    let listing = common::list_codes_content(&app, &a1).await;
+    expect_that!(listing, common::matchers::code_fixture());
+    let response = common::delete_account(&app, &a1).await;
+    expect_that!(
+        common::convert_response(response).await["delete_at"],
+        eq(&json!(delete_at))
+    );
+
+    // Nothing is purged before the grace period has passed
+    let events = Events::default();
+    expect_that!(tasks::purge_deleted_accounts(&db, &events).await, eq(0));
+
+    let response = user_request(&app, &a1, Method::POST, "/v1/user/delete/cancel").await;
+    expect_that!(response.status(), eq(StatusCode::NO_CONTENT));
+    let user = models::user::User::get_by_id(&db, common::USER1_ID.into())
+        .await
+        .unwrap()
+        .unwrap();
+    expect_that!(user.delete_at, none());
+
+    // Once the grace period is over, the account is gone
+    common::delete_account(&app, &a1).await;
+    sqlx::query("UPDATE users SET delete_at = 0 WHERE id = ?")
+        .bind(common::USER1_ID)
+        .execute(&db)
+        .await
+        .unwrap();
+    let mut receiver = events.subscribe();
+    expect_that!(tasks::purge_deleted_accounts(&db, &events).await, eq(1));
+    let event = receiver.try_recv().unwrap();
+    expect_that!(event.kind, eq(EventKind::UserDeleted));
+    expect_that!(event.user_id, eq(common::USER1_ID));
+    expect_that!(
+        models::user::User::get_by_id(&db, common::USER1_ID.into())
+            .await
+            .unwrap(),
+        none()
+    );
+    expect_that!(
+        models::user::User::get_by_id(&db, common::USER2_ID.into())
+            .await
+            .unwrap(),
+        some(anything())
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn cancel_account_deletion_requires_scope(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    let token = common::create_token(&app, &a1, "codes:read").await;
+
+    let response = user_request(&app, &token, Method::POST, "/v1/user/delete/cancel").await;
+    expect_that!(response.status(), eq(StatusCode::FORBIDDEN));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn preferences_roam(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let response = common::edit_settings(
+        &app,
+        &a1,
+        &json!({ "preferences": {
+            "theme": "dark",
+            "auto_lock_seconds": 60,
+            "code_display": { "grouping": 3, "hidden": true }
+        }}),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    // Another device of the user sees them, but other users don't
+    let response = user_request(&app, &a1, Method::GET, "/v1/user/settings").await;
+    expect_that!(
+        common::convert_response(response).await,
+        eq(&json!({
+            "enforce_unique_names": false,
+            "email_notifications": true,
+            "preferences": {
+                "auto_lock_seconds": 60,
+                "code_display": { "grouping": 3, "hidden": true },
+                "theme": "dark"
+            }
+        }))
+    );
+    let response = user_request(&app, &a2, Method::GET, "/v1/user/settings").await;
+    expect_that!(
+        common::convert_response(response).await["preferences"],
+        eq(&json!({}))
+    );
+
+    // Left out preferences are kept, null removes them
+    let response = common::edit_settings(
+        &app,
+        &a1,
+        &json!({ "enforce_unique_names": true, "preferences": { "theme": null, "auto_lock_seconds": 300 } }),
+    )
+    .await;
+    expect_that!(
+        common::convert_response(response).await,
+        eq(&json!({
+            "enforce_unique_names": true,
+            "email_notifications": true,
+            "preferences": {
+                "auto_lock_seconds": 300,
+                "code_display": { "grouping": 3, "hidden": true }
+            }
+        }))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn preferences_rejected(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let too_many: serde_json::Map<_, _> = (0..65)
+        .map(|index| (format!("key{index}"), json!(index)))
+        .collect();
+    for preferences in [
+        json!({ "": 1 }),
+        json!({ "the theme": "dark" }),
+        json!({ "k".repeat(65): 1 }),
+        json!({ "theme": "x".repeat(4096) }),
+        json!(too_many),
+    ] {
+        let response =
+            common::edit_settings(&app, &a1, &json!({ "preferences": preferences })).await;
+        expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    }
+
+    let response = user_request(&app, &a1, Method::GET, "/v1/user/settings").await;
+    expect_that!(
+        common::convert_response(response).await["preferences"],
+        eq(&json!({}))
+    );
+}
@@ -0,0 +1,381 @@
+#![cfg(feature = "webauthn")]
+
+use axum::{
+    body::Body,
+    http::{Method, Request, StatusCode},
+    response::Response,
+    Router,
+};
+use googletest::prelude::*;
+use iceblink_sync::{
+    models::user::User,
+    routes::v1::webauthn::{PasskeyAuthChallenge, PasskeyRegisterChallenge},
+    ServerOptions,
+};
+use serde_json::json;
+use sqlx::SqlitePool;
+use tower::ServiceExt;
+use webauthn_authenticator_rs::{prelude::Url, softpasskey::SoftPasskey, WebauthnAuthenticator};
+
+pub mod common;
+
+const ORIGIN: &str = "http://localhost:8085";
+
+async fn setup(db: &SqlitePool) -> Router {
+    common::testing_setup_with_options(
+        db,
+        ServerOptions {
+            frontfacing: ORIGIN.into(),
+            ..common::testing_options()
+        },
+    )
+    .await
+}
+
+async fn post(app: &Router, uri: &str, payload: &serde_json::Value) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(uri)
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_vec(payload).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+async fn post_authorized(
+    app: &Router,
+    uri: &str,
+    token: &str,
+    payload: &serde_json::Value,
+) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(uri)
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::from(serde_json::to_vec(payload).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+fn session_cookie(response: &Response) -> String {
+    response
+        .headers()
+        .get("Set-Cookie")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_string()
+}
+
+async fn register(
+    app: &Router,
+    authenticator: &mut WebauthnAuthenticator<SoftPasskey>,
+    username: &str,
+) -> Response {
+    let start = post(
+        app,
+        "/v1/webauthn/register/start",
+        &json!({ "username": username }),
+    )
+    .await;
+    assert_that!(start.status(), eq(StatusCode::OK));
+
+    let challenge: PasskeyRegisterChallenge =
+        serde_json::from_value(common::convert_response(start).await).unwrap();
+    let credential = authenticator
+        .do_registration(Url::parse(ORIGIN).unwrap(), challenge.options)
+        .unwrap();
+
+    post(
+        app,
+        "/v1/webauthn/register/finish",
+        &json!({
+            "challenge_id": challenge.challenge_id,
+            "credential": credential,
+        }),
+    )
+    .await
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn register_and_login(db: SqlitePool) {
+    let app = setup(&db).await;
+    let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+
+    // Registration logs in straight away
+    let registered = register(&app, &mut authenticator, "passkeyuser").await;
+    assert_that!(registered.status(), eq(StatusCode::OK));
+
+    let listing = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/v1/code")
+                .header("Cookie", session_cookie(&registered))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_that!(listing.status(), eq(StatusCode::OK));
+    assert_that!(common::convert_response(listing).await, eq(&json!([])));
+
+    let user = User::get_by_username(&db, "passkeyuser".into())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_that!(user.upstream_userid, starts_with("webauthn:"));
+
+    // Logging in with the same authenticator
+    let start = post(
+        &app,
+        "/v1/webauthn/auth/start",
+        &json!({ "username": "passkeyuser" }),
+    )
+    .await;
+    assert_that!(start.status(), eq(StatusCode::OK));
+
+    let challenge: PasskeyAuthChallenge =
+        serde_json::from_value(common::convert_response(start).await).unwrap();
+    let assertion = authenticator
+        .do_authentication(Url::parse(ORIGIN).unwrap(), challenge.options)
+        .unwrap();
+
+    let finished = post(
+        &app,
+        "/v1/webauthn/auth/finish",
+        &json!({
+            "challenge_id": challenge.challenge_id,
+            "credential": assertion,
+        }),
+    )
+    .await;
+    assert_that!(finished.status(), eq(StatusCode::OK));
+    assert_that!(session_cookie(&finished), starts_with("iceblink_jwt="));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn register_taken_username(db: SqlitePool) {
+    let app = setup(&db).await;
+
+    let start = post(
+        &app,
+        "/v1/webauthn/register/start",
+        &json!({ "username": "user1" }),
+    )
+    .await;
+
+    assert_that!(start.status(), eq(StatusCode::CONFLICT));
+    assert_that!(
+        common::convert_response(start).await,
+        eq(&json!({
+            "message": "The username is already taken.",
+            "errorKind": "UsernameTaken"
+        }))
+    );
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn login_without_passkeys(db: SqlitePool) {
+    let app = setup(&db).await;
+
+    // user1 only has an OAuth identity
+    let start = post(
+        &app,
+        "/v1/webauthn/auth/start",
+        &json!({ "username": "user1" }),
+    )
+    .await;
+
+    assert_that!(start.status(), eq(StatusCode::NOT_FOUND));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn finish_unknown_challenge(db: SqlitePool) {
+    let app = setup(&db).await;
+    let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+
+    let start = post(
+        &app,
+        "/v1/webauthn/register/start",
+        &json!({ "username": "passkeyuser" }),
+    )
+    .await;
+    let challenge: PasskeyRegisterChallenge =
+        serde_json::from_value(common::convert_response(start).await).unwrap();
+    let credential = authenticator
+        .do_registration(Url::parse(ORIGIN).unwrap(), challenge.options)
+        .unwrap();
+
+    let finished = post(
+        &app,
+        "/v1/webauthn/register/finish",
+        &json!({
+            "challenge_id": "not a challenge",
+            "credential": credential,
+        }),
+    )
+    .await;
+    assert_that!(finished.status(), eq(StatusCode::NOT_FOUND));
+}
+
+/// Confirms with the authenticator, returning the step-up token.
+async fn step_up(
+    app: &Router,
+    authenticator: &mut WebauthnAuthenticator<SoftPasskey>,
+    token: &str,
+) -> String {
+    let start = post_authorized(app, "/v1/user/step-up/start", token, &json!({})).await;
+    assert_that!(start.status(), eq(StatusCode::OK));
+    let challenge: PasskeyAuthChallenge =
+        serde_json::from_value(common::convert_response(start).await).unwrap();
+    let assertion = authenticator
+        .do_authentication(Url::parse(ORIGIN).unwrap(), challenge.options)
+        .unwrap();
+
+    let finished = post_authorized(
+        app,
+        "/v1/user/step-up/finish",
+        token,
+        &json!({
+            "challenge_id": challenge.challenge_id,
+            "credential": assertion,
+        }),
+    )
+    .await;
+    assert_that!(finished.status(), eq(StatusCode::OK));
+    common::convert_response(finished).await["token"]
+        .as_str()
+        .unwrap()
+        .to_string()
+}
+
+async fn export_aegis(app: &Router, token: &str, step_up: Option<&str>) -> Response {
+    let mut request = Request::builder()
+        .method(Method::GET)
+        .uri("/v1/export/aegis")
+        .header("Authorization", format!("Bearer {token}"));
+    if let Some(step_up) = step_up {
+        request = request.header("X-Step-Up-Token", step_up);
+    }
+    app.clone()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn security_key_step_up(db: SqlitePool) {
+    let app = setup(&db).await;
+    let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    // Adding a key is allowed right after logging in
+    let start = post_authorized(
+        &app,
+        "/v1/user/security-keys/register/start",
+        &a1,
+        &json!({ "name": "YubiKey" }),
+    )
+    .await;
+    assert_that!(start.status(), eq(StatusCode::OK));
+    let challenge: PasskeyRegisterChallenge =
+        serde_json::from_value(common::convert_response(start).await).unwrap();
+    let credential = authenticator
+        .do_registration(Url::parse(ORIGIN).unwrap(), challenge.options)
+        .unwrap();
+    let finished = post_authorized(
+        &app,
+        "/v1/user/security-keys/register/finish",
+        &a1,
+        &json!({
+            "challenge_id": challenge.challenge_id,
+            "credential": credential,
+        }),
+    )
+    .await;
+    assert_that!(finished.status(), eq(StatusCode::CREATED));
+    let key = common::convert_response(finished).await;
+    assert_that!(key["name"], eq(&json!("YubiKey")));
+
+    let listing = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/v1/user/security-keys")
+                .header("Authorization", format!("Bearer {a1}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_that!(common::convert_response(listing).await, eq(&json!([key])));
+
+    // API tokens never count as a recent login, so they need the key
+    let token = common::create_token(&app, a1.as_str(), "full").await;
+    let response = export_aegis(&app, &token, None).await;
+    assert_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+
+    let step_up_token = step_up(&app, &mut authenticator, &token).await;
+    let response = export_aegis(&app, &token, Some(&step_up_token)).await;
+    assert_that!(response.status(), eq(StatusCode::OK));
+
+    // Every step-up allows a single action
+    let response = export_aegis(&app, &token, Some(&step_up_token)).await;
+    assert_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+
+    // Nor can another user's step-up token be used
+    let (_, a2) = common::get_access_tokens(&db).await;
+    sqlx::query("UPDATE sessions SET created_at = created_at - 3600")
+        .execute(&db)
+        .await
+        .unwrap();
+    let step_up_token = step_up(&app, &mut authenticator, &token).await;
+    let response = export_aegis(&app, &a2, Some(&step_up_token)).await;
+    assert_that!(response.status(), eq(StatusCode::UNAUTHORIZED));
+
+    let step_up_token = step_up(&app, &mut authenticator, &token).await;
+    let deleted = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::DELETE)
+                .uri("/v1/user")
+                .header("Authorization", format!("Bearer {token}"))
+                .header("X-Step-Up-Token", step_up_token)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_that!(deleted.status(), eq(StatusCode::NO_CONTENT));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn step_up_without_security_keys(db: SqlitePool) {
+    let app = setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    let start = post_authorized(&app, "/v1/user/step-up/start", &a1, &json!({})).await;
+    assert_that!(start.status(), eq(StatusCode::NOT_FOUND));
+}
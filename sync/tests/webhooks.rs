@@ -0,0 +1,386 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, Method, Request, StatusCode},
+    response::Response,
+    routing::post,
+    Router,
+};
+use googletest::prelude::*;
+use iceblink_sync::{events::Events, models::webhooks::Webhook, webhooks};
+use serde_json::json;
+use sqlx::SqlitePool;
+use std::sync::{Arc, Mutex};
+use tower::ServiceExt;
+
+pub mod common;
+
+async fn webhook_request(
+    app: &Router,
+    token: &str,
+    method: Method,
+    uri: &str,
+    payload: Option<serde_json::Value>,
+) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Authorization", format!("Bearer {token}"));
+    let request = match payload {
+        Some(payload) => request
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap())),
+        None => request.body(Body::empty()),
+    };
+
+    app.clone().oneshot(request.unwrap()).await.unwrap()
+}
+
+async fn make_admin(db: &SqlitePool, id: &str) {
+    sqlx::query("UPDATE users SET is_admin = TRUE WHERE id = ?")
+        .bind(id)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+type Received = Arc<Mutex<Vec<(HeaderMap, String)>>>;
+
+/// Receiver of webhooks responding with `status`, recording what it receives.
+async fn mock_receiver(status: StatusCode) -> (String, Received) {
+    let received: Received = Arc::default();
+    let recorded = received.clone();
+    let base = common::mock_upstream(Router::new().route(
+        "/hook",
+        post(move |headers: HeaderMap, body: Bytes| async move {
+            recorded
+                .lock()
+                .unwrap()
+                .push((headers, String::from_utf8(body.to_vec()).unwrap()));
+            status
+        }),
+    ))
+    .await;
+
+    (format!("{base}/hook"), received)
+}
+
+/// The most recent delivery to the webhook of the instance.
+async fn latest_delivery(app: &Router, token: &str, id: &str) -> serde_json::Value {
+    let response = webhook_request(
+        app,
+        token,
+        Method::GET,
+        &format!("/v1/admin/webhooks/{id}/deliveries"),
+        None,
+    )
+    .await;
+    common::convert_response(response).await[0].clone()
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn register_webhook(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+
+    let response = webhook_request(
+        &app,
+        &a1,
+        Method::POST,
+        "/v1/webhooks",
+        Some(json!({
+            "url": "https://automation.example.com/iceblink",
+            "events": ["code.created", "code.deleted", "code.created"]
+        })),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::CREATED));
+    let webhook = common::convert_response(response).await;
+    expect_that!(webhook["secret"].as_str().unwrap(), starts_with("whsec_"));
+    expect_that!(
+        webhook["events"],
+        eq(&json!(["code.created", "code.deleted"]))
+    );
+    let id = webhook["id"].as_str().unwrap().to_string();
+
+    let response = webhook_request(&app, &a1, Method::GET, "/v1/webhooks", None).await;
+    let webhooks = common::convert_response(response).await;
+    assert_that!(webhooks.as_array().unwrap(), len(eq(1)));
+    expect_that!(webhooks[0]["id"], eq(&json!(id)));
+    expect_that!(webhooks[0].get("secret"), none());
+
+    // Webhooks are private to their user
+    let response = webhook_request(&app, &a2, Method::GET, "/v1/webhooks", None).await;
+    expect_that!(common::convert_response(response).await, eq(&json!([])));
+    let response = webhook_request(
+        &app,
+        &a2,
+        Method::DELETE,
+        &format!("/v1/webhooks/{id}"),
+        None,
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::NOT_FOUND));
+
+    let response = webhook_request(
+        &app,
+        &a1,
+        Method::DELETE,
+        &format!("/v1/webhooks/{id}"),
+        None,
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::NO_CONTENT));
+    let response = webhook_request(&app, &a1, Method::GET, "/v1/webhooks", None).await;
+    expect_that!(common::convert_response(response).await, eq(&json!([])));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn register_webhook_rejected(db: SqlitePool) {
+    let app = common::testing_setup(&db).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    for payload in [
+        json!({ "url": "ftp://automation.example.com/iceblink" }),
+        json!({ "url": "http://127.0.0.1:8123/api/webhook" }),
+        json!({ "url": "http://localhost/hook" }),
+        json!({ "url": "not a url" }),
+        json!({ "url": "https://automation.example.com", "events": ["code.exploded"] }),
+    ] {
+        let response =
+            webhook_request(&app, &a1, Method::POST, "/v1/webhooks", Some(payload)).await;
+        expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+    }
+
+    for _ in 0..10 {
+        let response = webhook_request(
+            &app,
+            &a1,
+            Method::POST,
+            "/v1/webhooks",
+            Some(json!({ "url": "https://automation.example.com" })),
+        )
+        .await;
+        expect_that!(response.status(), eq(StatusCode::CREATED));
+    }
+    let response = webhook_request(
+        &app,
+        &a1,
+        Method::POST,
+        "/v1/webhooks",
+        Some(json!({ "url": "https://automation.example.com" })),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::BAD_REQUEST));
+
+    // Registering takes full access
+    let token = common::create_token(&app, &a1, "codes:write").await;
+    let response = webhook_request(
+        &app,
+        &token,
+        Method::POST,
+        "/v1/webhooks",
+        Some(json!({ "url": "https://automation.example.com" })),
+    )
+    .await;
+    expect_that!(response.status(), eq(StatusCode::FORBIDDEN));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn events_are_delivered_signed(db: SqlitePool) {
+    let events = Events::default();
+    let mut receiver = events.subscribe();
+    let app = common::testing_setup_with_events(&db, events).await;
+    let (a1, a2) = common::get_access_tokens(&db).await;
+    make_admin(&db, common::USER1_ID).await;
+
+    // Webhooks of the instance may post to internal hosts, like the mock receiver
+    let (url, received) = mock_receiver(StatusCode::NO_CONTENT).await;
+    let response = webhook_request(
+        &app,
+        &a1,
+        Method::POST,
+        "/v1/admin/webhooks",
+        Some(json!({ "url": url, "events": ["code.updated"] })),
+    )
+    .await;
+    assert_that!(response.status(), eq(StatusCode::CREATED));
+    let webhook = common::convert_response(response).await;
+    let secret = webhook["secret"].as_str().unwrap().to_string();
+    let id = webhook["id"].as_str().unwrap().to_string();
+
+    // The instance webhook receives the events of every user
+    common::edit_code(
+        &app,
+        &a2,
+        common::USER2_CODE1_ID,
+        &json!({ "display_name": "Renamed" }),
+    )
+    .await;
+    let event = receiver.try_recv().unwrap();
+    expect_that!(webhooks::enqueue(&db, &event).await.unwrap(), eq(1));
+    let now = chrono::Utc::now().timestamp();
+    expect_that!(webhooks::deliver_due(&db, None, now).await.unwrap(), eq(1));
+
+    let received = received.lock().unwrap().clone();
+    assert_that!(received, len(eq(1)));
+    let (headers, body) = &received[0];
+    expect_that!(headers["x-iceblink-event"], eq("code.updated"));
+    let timestamp: i64 = headers["x-iceblink-timestamp"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    expect_that!(
+        headers["x-iceblink-signature"].to_str().unwrap(),
+        eq(webhooks::sign(&secret, timestamp, body))
+    );
+    let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+    expect_that!(payload["kind"], eq(&json!("code.updated")));
+    expect_that!(payload["user_id"], eq(&json!(common::USER2_ID)));
+    expect_that!(payload["code_id"], eq(&json!(common::USER2_CODE1_ID)));
+    expect_that!(
+        headers["x-iceblink-delivery"],
+        eq(payload["id"].as_str().unwrap())
+    );
+
+    let response = webhook_request(
+        &app,
+        &a1,
+        Method::GET,
+        &format!("/v1/admin/webhooks/{id}/deliveries"),
+        None,
+    )
+    .await;
+    let deliveries = common::convert_response(response).await;
+    assert_that!(deliveries.as_array().unwrap(), len(eq(1)));
+    expect_that!(deliveries[0]["state"], eq(&json!("delivered")));
+    expect_that!(deliveries[0]["response_status"], eq(&json!(204)));
+
+    // Events the webhook didn't ask for aren't queued
+    common::delete_account(&app, &a2).await;
+    let event = receiver.try_recv().unwrap();
+    expect_that!(webhooks::enqueue(&db, &event).await.unwrap(), eq(0));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn failed_deliveries_are_retried(db: SqlitePool) {
+    let events = Events::default();
+    let mut receiver = events.subscribe();
+    let app = common::testing_setup_with_events(&db, events).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+    make_admin(&db, common::USER1_ID).await;
+
+    let (url, received) = mock_receiver(StatusCode::INTERNAL_SERVER_ERROR).await;
+    let response = webhook_request(
+        &app,
+        &a1,
+        Method::POST,
+        "/v1/admin/webhooks",
+        Some(json!({ "url": url })),
+    )
+    .await;
+    let id = common::convert_response(response).await["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "display_name": "Renamed" }),
+    )
+    .await;
+    webhooks::enqueue(&db, &receiver.try_recv().unwrap())
+        .await
+        .unwrap();
+    let mut now = chrono::Utc::now().timestamp();
+    expect_that!(webhooks::deliver_due(&db, None, now).await.unwrap(), eq(0));
+
+    let delivery = latest_delivery(&app, &a1, &id).await;
+    expect_that!(delivery["state"], eq(&json!("pending")));
+    expect_that!(delivery["attempts"], eq(&json!(1)));
+    expect_that!(delivery["response_status"], eq(&json!(500)));
+    expect_that!(delivery["next_attempt_at"], eq(&json!(now + 60)));
+
+    // Not retried before it's due
+    webhooks::deliver_due(&db, None, now + 59).await.unwrap();
+    expect_that!(received.lock().unwrap().len(), eq(1));
+
+    // Given up on after the last retry
+    while let Some(next_attempt_at) =
+        latest_delivery(&app, &a1, &id).await["next_attempt_at"].as_i64()
+    {
+        now = next_attempt_at;
+        webhooks::deliver_due(&db, None, now).await.unwrap();
+    }
+    let delivery = latest_delivery(&app, &a1, &id).await;
+    expect_that!(delivery["state"], eq(&json!("failed")));
+    expect_that!(delivery["attempts"], eq(&json!(6)));
+    expect_that!(received.lock().unwrap().len(), eq(6));
+    let ids: std::collections::HashSet<String> = received
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(headers, _)| headers["x-iceblink-delivery"].to_str().unwrap().to_string())
+        .collect();
+    expect_that!(ids, len(eq(1)));
+}
+
+#[sqlx::test(fixtures("users", "codes"))]
+#[gtest]
+async fn user_webhooks_never_reach_internal_hosts(db: SqlitePool) {
+    let events = Events::default();
+    let mut receiver = events.subscribe();
+    let app = common::testing_setup_with_events(&db, events).await;
+    let (a1, _) = common::get_access_tokens(&db).await;
+
+    // As if the host resolved to a public address when registering, and was rebound since
+    let (url, received) = mock_receiver(StatusCode::NO_CONTENT).await;
+    Webhook {
+        id: "rebound".into(),
+        user_id: Some(common::USER1_ID.into()),
+        url,
+        secret: "whsec_secret".into(),
+        events: String::new(),
+        created_at: 0,
+    }
+    .insert(&db)
+    .await
+    .unwrap();
+
+    common::edit_code(
+        &app,
+        &a1,
+        common::USER1_CODE1_ID,
+        &json!({ "display_name": "Renamed" }),
+    )
+    .await;
+    webhooks::enqueue(&db, &receiver.try_recv().unwrap())
+        .await
+        .unwrap();
+    let now = chrono::Utc::now().timestamp();
+    expect_that!(webhooks::deliver_due(&db, None, now).await.unwrap(), eq(0));
+    expect_that!(received.lock().unwrap().clone(), empty());
+
+    // Nothing about the internal host leaks through the delivery log
+    let response = webhook_request(
+        &app,
+        &a1,
+        Method::GET,
+        "/v1/webhooks/rebound/deliveries",
+        None,
+    )
+    .await;
+    let delivery = common::convert_response(response).await[0].clone();
+    expect_that!(delivery["state"], eq(&json!("pending")));
+    expect_that!(delivery["response_status"], eq(&json!(null)));
+    expect_that!(
+        delivery["error"],
+        eq(&json!("The URL of the webhook isn't public"))
+    );
+}